@@ -101,7 +101,10 @@ pub enum StreamEffect {
 }
 
 pub mod capture;
+#[cfg(feature = "pulseaudio")]
+pub mod pulse;
 pub mod shm_streams;
+pub mod wav;
 
 impl Default for StreamEffect {
     fn default() -> Self {
@@ -0,0 +1,644 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Audio backend for desktop Linux hosts that plays and captures through a PulseAudio server using
+//! the blocking libpulse-simple API. PipeWire hosts are served through pipewire-pulse.
+//!
+//! The server is picked by libpulse the same way as for any other client, so `PULSE_SERVER` can
+//! be used to point crosvm at a specific server.
+//!
+//! The blocking calls of async streams are made on a thread of their own so that they don't stall
+//! the executor.
+
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::ptr::null;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cros_async::{BlockingPool, Executor};
+use remain::sorted;
+use sys_util::{clone_fd, SharedMemory};
+use thiserror::Error;
+
+use crate::capture::{
+    AsyncCaptureBuffer, AsyncCaptureBufferStream, CaptureBuffer, CaptureBufferStream,
+};
+use crate::shm_streams::{BufferSet, ServerRequest, ShmStream, ShmStreamSource};
+use crate::{
+    AsyncBufferCommit, AsyncPlaybackBuffer, AsyncPlaybackBufferStream, BoxError, BufferCommit,
+    NoopBufferCommit, NoopStreamControl, PlaybackBuffer, PlaybackBufferStream, SampleFormat,
+    StreamControl, StreamDirection, StreamEffect, StreamSource,
+};
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct pa_simple {
+    _private: [u8; 0],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct pa_sample_spec {
+    format: c_int,
+    rate: u32,
+    channels: u8,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct pa_buffer_attr {
+    maxlength: u32,
+    tlength: u32,
+    prebuf: u32,
+    minreq: u32,
+    fragsize: u32,
+}
+
+const PA_STREAM_PLAYBACK: c_int = 1;
+const PA_STREAM_RECORD: c_int = 2;
+
+const PA_SAMPLE_U8: c_int = 0;
+const PA_SAMPLE_S16LE: c_int = 3;
+const PA_SAMPLE_S32LE: c_int = 7;
+const PA_SAMPLE_S24_32LE: c_int = 11;
+
+// Lets the server pick the value of a `pa_buffer_attr` field.
+const PA_BUFFER_ATTR_DEFAULT: u32 = u32::MAX;
+
+#[link(name = "pulse-simple")]
+extern "C" {
+    fn pa_simple_new(
+        server: *const c_char,
+        name: *const c_char,
+        dir: c_int,
+        dev: *const c_char,
+        stream_name: *const c_char,
+        ss: *const pa_sample_spec,
+        map: *const c_void,
+        attr: *const pa_buffer_attr,
+        error: *mut c_int,
+    ) -> *mut pa_simple;
+    fn pa_simple_free(s: *mut pa_simple);
+    fn pa_simple_write(
+        s: *mut pa_simple,
+        data: *const c_void,
+        bytes: usize,
+        error: *mut c_int,
+    ) -> c_int;
+    fn pa_simple_read(
+        s: *mut pa_simple,
+        data: *mut c_void,
+        bytes: usize,
+        error: *mut c_int,
+    ) -> c_int;
+}
+
+#[link(name = "pulse")]
+extern "C" {
+    fn pa_strerror(error: c_int) -> *const c_char;
+}
+
+fn pulse_error_string(error: c_int) -> String {
+    // Safe because pa_strerror returns either null or a pointer to a static string.
+    let message = unsafe { pa_strerror(error) };
+    if message.is_null() {
+        return format!("error {}", error);
+    }
+    // Safe because the string is static and nul terminated.
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to duplicate the shared memory descriptor: {0}")]
+    CloneShm(sys_util::Error),
+    #[error("failed to connect to the PulseAudio server: {0}")]
+    Connect(String),
+    #[error("the PulseAudio stream was lost by a cancelled transfer")]
+    Lost,
+    #[error("failed to access the client shared memory: {0}")]
+    Shm(std::io::Error),
+    #[error("failed to exchange samples with the PulseAudio server: {0}")]
+    Transfer(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A connection to the PulseAudio server carrying a single stream.
+struct PulseConnection {
+    simple: *mut pa_simple,
+}
+
+// Safe because libpulse-simple connections can be used from any thread as long as they are not
+// used from more than one at a time, which `&mut self` guarantees.
+unsafe impl Send for PulseConnection {}
+
+impl PulseConnection {
+    fn new(
+        direction: StreamDirection,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<Self> {
+        let spec = pa_sample_spec {
+            format: match format {
+                SampleFormat::U8 => PA_SAMPLE_U8,
+                SampleFormat::S16LE => PA_SAMPLE_S16LE,
+                SampleFormat::S24LE => PA_SAMPLE_S24_32LE,
+                SampleFormat::S32LE => PA_SAMPLE_S32LE,
+            },
+            rate: frame_rate,
+            channels: num_channels as u8,
+        };
+        // Keep the latency close to two buffers, the server default is around two seconds.
+        let buffer_bytes = (buffer_size * num_channels * format.sample_bytes()) as u32;
+        let attr = pa_buffer_attr {
+            maxlength: PA_BUFFER_ATTR_DEFAULT,
+            tlength: 2 * buffer_bytes,
+            prebuf: PA_BUFFER_ATTR_DEFAULT,
+            minreq: buffer_bytes,
+            fragsize: buffer_bytes,
+        };
+        let (dir, stream_name) = match direction {
+            StreamDirection::Playback => (PA_STREAM_PLAYBACK, "playback"),
+            StreamDirection::Capture => (PA_STREAM_RECORD, "capture"),
+        };
+        let name = CString::new("crosvm").unwrap();
+        let stream_name = CString::new(stream_name).unwrap();
+
+        let mut error = 0;
+        // Safe because every pointer is either null or points to a live value, and the result is
+        // checked before use.
+        let simple = unsafe {
+            pa_simple_new(
+                null(),
+                name.as_ptr(),
+                dir,
+                null(),
+                stream_name.as_ptr(),
+                &spec,
+                null(),
+                &attr,
+                &mut error,
+            )
+        };
+        if simple.is_null() {
+            return Err(Error::Connect(pulse_error_string(error)));
+        }
+        Ok(PulseConnection { simple })
+    }
+
+    /// Plays `samples`, blocking until the server has room for them.
+    fn write(&mut self, samples: &[u8]) -> Result<()> {
+        let mut error = 0;
+        // Safe because `samples` is valid for reads of its length and `self.simple` is a live
+        // connection.
+        let ret = unsafe {
+            pa_simple_write(
+                self.simple,
+                samples.as_ptr() as *const c_void,
+                samples.len(),
+                &mut error,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Transfer(pulse_error_string(error)));
+        }
+        Ok(())
+    }
+
+    /// Fills `samples`, blocking until the server has captured enough of them.
+    fn read(&mut self, samples: &mut [u8]) -> Result<()> {
+        let mut error = 0;
+        // Safe because `samples` is valid for writes of its length and `self.simple` is a live
+        // connection.
+        let ret = unsafe {
+            pa_simple_read(
+                self.simple,
+                samples.as_mut_ptr() as *mut c_void,
+                samples.len(),
+                &mut error,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Transfer(pulse_error_string(error)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PulseConnection {
+    fn drop(&mut self) {
+        // Safe because `self.simple` was returned by pa_simple_new and isn't used again.
+        unsafe { pa_simple_free(self.simple) };
+    }
+}
+
+/// Number of frames committed to the current playback buffer.
+#[derive(Default)]
+struct PendingFrames(usize);
+
+impl BufferCommit for PendingFrames {
+    fn commit(&mut self, nframes: usize) {
+        self.0 = nframes;
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for PendingFrames {
+    async fn commit(&mut self, nframes: usize) {
+        self.0 = nframes;
+    }
+}
+
+// Keepalive of the thread that makes the blocking calls of an async stream.
+const ASYNC_THREAD_KEEPALIVE: Duration = Duration::from_secs(10);
+
+/// The connection of an async stream along with the buffer of its samples, which are moved to the
+/// stream's thread for each blocking call.
+struct AsyncPulseConnection {
+    pool: BlockingPool,
+    // Taken while a call is in progress.
+    io: Option<(PulseConnection, Vec<u8>)>,
+}
+
+impl AsyncPulseConnection {
+    fn new(connection: PulseConnection, buffer: Vec<u8>) -> Self {
+        AsyncPulseConnection {
+            pool: BlockingPool::new(1, ASYNC_THREAD_KEEPALIVE),
+            io: Some((connection, buffer)),
+        }
+    }
+
+    /// Runs `f` with the connection and the buffer on the stream's thread.
+    async fn call<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut PulseConnection, &mut [u8]) -> Result<()> + Send + 'static,
+    {
+        let (mut connection, mut buffer) = self.io.take().ok_or(Error::Lost)?;
+        let (connection, buffer, result) = self
+            .pool
+            .spawn(move || {
+                let result = f(&mut connection, buffer.as_mut_slice());
+                (connection, buffer, result)
+            })
+            .await;
+        self.io = Some((connection, buffer));
+        result
+    }
+
+    fn buffer(&mut self) -> Result<&mut Vec<u8>> {
+        self.io
+            .as_mut()
+            .map(|(_, buffer)| buffer)
+            .ok_or(Error::Lost)
+    }
+}
+
+/// Playback stream that sends its samples to the PulseAudio server. Writing blocks while the
+/// server's buffer is full, which paces the stream.
+pub struct PulsePlaybackStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    pending_frames: PendingFrames,
+    connection: PulseConnection,
+}
+
+impl PlaybackBufferStream for PulsePlaybackStream {
+    fn next_playback_buffer<'b, 's: 'b>(
+        &'s mut self,
+    ) -> std::result::Result<PlaybackBuffer<'b>, BoxError> {
+        // The previous buffer borrowed the stream, so its samples are only sent now.
+        let len = self.pending_frames.0 * self.frame_size;
+        self.pending_frames.0 = 0;
+        if len > 0 {
+            self.connection.write(&self.buffer[..len])?;
+        }
+        Ok(PlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.pending_frames,
+        )?)
+    }
+}
+
+/// Async version of `PulsePlaybackStream`.
+pub struct AsyncPulsePlaybackStream {
+    frame_size: usize,
+    pending_frames: PendingFrames,
+    connection: AsyncPulseConnection,
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for AsyncPulsePlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        _ex: &Executor,
+    ) -> std::result::Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        let len = mem::take(&mut self.pending_frames.0) * self.frame_size;
+        if len > 0 {
+            self.connection
+                .call(move |connection, buffer| connection.write(&buffer[..len]))
+                .await?;
+        }
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            self.connection.buffer()?,
+            &mut self.pending_frames,
+        )?)
+    }
+}
+
+/// Capture stream that receives its samples from the PulseAudio server.
+pub struct PulseCaptureStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    buffer_drop: NoopBufferCommit,
+    connection: PulseConnection,
+}
+
+impl CaptureBufferStream for PulseCaptureStream {
+    fn next_capture_buffer<'b, 's: 'b>(
+        &'s mut self,
+    ) -> std::result::Result<CaptureBuffer<'b>, BoxError> {
+        self.connection.read(&mut self.buffer)?;
+        Ok(CaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+/// Async version of `PulseCaptureStream`.
+pub struct AsyncPulseCaptureStream {
+    frame_size: usize,
+    buffer_drop: NoopBufferCommit,
+    connection: AsyncPulseConnection,
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for AsyncPulseCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        _ex: &Executor,
+    ) -> std::result::Result<AsyncCaptureBuffer<'a>, BoxError> {
+        self.connection
+            .call(|connection, buffer| connection.read(buffer))
+            .await?;
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            self.connection.buffer()?,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+/// `ShmStream` that copies samples between the client's shared memory and the PulseAudio server.
+pub struct PulseShmStream {
+    direction: StreamDirection,
+    num_channels: usize,
+    frame_rate: u32,
+    buffer_size: usize,
+    frame_size: usize,
+    client_shm: File,
+    samples: Vec<u8>,
+    // Offsets and lengths in frames of the capture buffers that still need to be filled.
+    capture_buffers: VecDeque<(usize, usize)>,
+    connection: PulseConnection,
+}
+
+impl BufferSet for PulseShmStream {
+    fn callback(&mut self, offset: usize, frames: usize) -> std::result::Result<(), BoxError> {
+        match self.direction {
+            StreamDirection::Playback => {
+                let samples = &mut self.samples[..frames * self.frame_size];
+                self.client_shm
+                    .read_exact_at(samples, offset as u64)
+                    .map_err(Error::Shm)?;
+                self.connection.write(samples)?;
+            }
+            StreamDirection::Capture => self.capture_buffers.push_back((offset, frames)),
+        }
+        Ok(())
+    }
+
+    fn ignore(&mut self) -> std::result::Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+impl ShmStream for PulseShmStream {
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    // Playback is paced by the blocking write in `callback` and capture by the blocking read
+    // here, both of which return within a buffer period, well under any sensible `timeout`.
+    fn wait_for_next_action_with_timeout(
+        &mut self,
+        _timeout: Duration,
+    ) -> std::result::Result<Option<ServerRequest>, BoxError> {
+        if let Some((offset, frames)) = self.capture_buffers.pop_front() {
+            let samples = &mut self.samples[..frames * self.frame_size];
+            self.connection.read(samples)?;
+            self.client_shm
+                .write_all_at(samples, offset as u64)
+                .map_err(Error::Shm)?;
+        }
+        Ok(Some(ServerRequest::new(self.buffer_size, self)))
+    }
+}
+
+/// Source of streams played and captured by the PulseAudio server.
+#[derive(Default)]
+pub struct PulseStreamSource;
+
+impl PulseStreamSource {
+    pub fn new() -> Self {
+        PulseStreamSource {}
+    }
+}
+
+impl StreamSource for PulseStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+    {
+        let connection = PulseConnection::new(
+            StreamDirection::Playback,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = num_channels * format.sample_bytes();
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(PulsePlaybackStream {
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                pending_frames: Default::default(),
+                connection,
+            }),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &Executor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError>
+    {
+        let connection = PulseConnection::new(
+            StreamDirection::Playback,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = num_channels * format.sample_bytes();
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AsyncPulsePlaybackStream {
+                frame_size,
+                pending_frames: Default::default(),
+                connection: AsyncPulseConnection::new(
+                    connection,
+                    vec![0; buffer_size * frame_size],
+                ),
+            }),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        let connection = PulseConnection::new(
+            StreamDirection::Capture,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = num_channels * format.sample_bytes();
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(PulseCaptureStream {
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                buffer_drop: NoopBufferCommit {
+                    which_buffer: false,
+                },
+                connection,
+            }),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &Executor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError>
+    {
+        let connection = PulseConnection::new(
+            StreamDirection::Capture,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = num_channels * format.sample_bytes();
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AsyncPulseCaptureStream {
+                frame_size,
+                buffer_drop: NoopBufferCommit {
+                    which_buffer: false,
+                },
+                connection: AsyncPulseConnection::new(
+                    connection,
+                    vec![0; buffer_size * frame_size],
+                ),
+            }),
+        ))
+    }
+}
+
+impl ShmStreamSource for PulseStreamSource {
+    fn new_stream(
+        &mut self,
+        direction: StreamDirection,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        client_shm: &SharedMemory,
+        buffer_offsets: [u64; 2],
+    ) -> std::result::Result<Box<dyn ShmStream>, BoxError> {
+        let connection =
+            PulseConnection::new(direction, num_channels, format, frame_rate, buffer_size)?;
+        let fd = clone_fd(client_shm).map_err(Error::CloneShm)?;
+        // Safe because `fd` is a freshly duplicated descriptor that nothing else owns.
+        let client_shm = unsafe { File::from_raw_fd(fd) };
+        let capture_buffers = match direction {
+            StreamDirection::Playback => VecDeque::new(),
+            StreamDirection::Capture => buffer_offsets
+                .iter()
+                .map(|&offset| (offset as usize, buffer_size))
+                .collect(),
+        };
+        let frame_size = num_channels * format.sample_bytes();
+        Ok(Box::new(PulseShmStream {
+            direction,
+            num_channels,
+            frame_rate,
+            buffer_size,
+            frame_size,
+            client_shm,
+            samples: vec![0; buffer_size * frame_size],
+            capture_buffers,
+            connection,
+        }))
+    }
+}
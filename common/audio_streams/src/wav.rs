@@ -0,0 +1,940 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Audio backend that records playback into a WAV file and plays capture back from one.
+//!
+//! The files are opened when the `WavStreamSource` is created so streams can still be created
+//! after the device has entered its jail. Each new playback stream truncates the playback file and
+//! each new capture stream starts again at the beginning of the capture file, reading silence once
+//! the file is exhausted.
+//!
+//! ```
+//! use audio_streams::{BoxError, PlaybackBuffer, SampleFormat, StreamSource};
+//! use audio_streams::wav::WavStreamSource;
+//! use std::io::Write;
+//!
+//! # fn main() -> std::result::Result<(), BoxError> {
+//! # let playback_file = tempfile::tempfile()?;
+//! let mut stream_source = WavStreamSource::new(Some(playback_file), None);
+//! let (_, mut stream) =
+//!     stream_source.new_playback_stream(2, SampleFormat::S16LE, 48000, 480)?;
+//! let mut copy_cb = |stream_buffer: &mut PlaybackBuffer| {
+//!     stream_buffer.write_all(&[0u8; 480 * 4])?;
+//!     Ok(())
+//! };
+//! stream.write_playback_buffer(&mut copy_cb)?;
+//! # Ok (())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use cros_async::{Executor, TimerAsync};
+use remain::sorted;
+use sys_util::{clone_fd, SharedMemory};
+use thiserror::Error;
+
+use crate::capture::{
+    AsyncCaptureBuffer, AsyncCaptureBufferStream, CaptureBuffer, CaptureBufferStream,
+};
+use crate::shm_streams::{BufferSet, ServerRequest, ShmStream, ShmStreamSource};
+use crate::{
+    AsyncBufferCommit, AsyncPlaybackBuffer, AsyncPlaybackBufferStream, BoxError, BufferCommit,
+    NoopBufferCommit, NoopStreamControl, PlaybackBuffer, PlaybackBufferStream, SampleFormat,
+    StreamControl, StreamDirection, StreamEffect, StreamSource,
+};
+
+/// Size of the header written by `WavFormat::header`.
+pub const WAV_HEADER_SIZE: usize = 44;
+const WAVE_FORMAT_PCM: u16 = 1;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to duplicate the shared memory descriptor: {0}")]
+    CloneShm(sys_util::Error),
+    #[error("WAV file is limited to 4 GiB of samples")]
+    FileTooLarge,
+    #[error("capture file has {0} but the stream requested {1}")]
+    FormatMismatch(WavFormat, WavFormat),
+    #[error("invalid WAV file: {0}")]
+    InvalidFile(&'static str),
+    #[error("I/O error on WAV file: {0}")]
+    Io(io::Error),
+    #[error("no {0} file was given")]
+    MissingFile(&'static str),
+    #[error("{0} samples can't be stored in a WAV file")]
+    UnsupportedSampleFormat(SampleFormat),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Layout of the samples in a WAV file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WavFormat {
+    pub num_channels: usize,
+    pub format: SampleFormat,
+    pub frame_rate: u32,
+}
+
+impl Display for WavFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} channels of {} at {} Hz",
+            self.num_channels, self.format, self.frame_rate
+        )
+    }
+}
+
+impl WavFormat {
+    pub fn frame_size(&self) -> usize {
+        self.num_channels * self.format.sample_bytes()
+    }
+
+    fn bits_per_sample(&self) -> Result<u16> {
+        match self.format {
+            SampleFormat::U8 => Ok(8),
+            SampleFormat::S16LE => Ok(16),
+            SampleFormat::S32LE => Ok(32),
+            // S24LE samples sit in 4 byte containers, which plain PCM WAV can't describe.
+            SampleFormat::S24LE => Err(Error::UnsupportedSampleFormat(self.format)),
+        }
+    }
+
+    /// Returns the header of a PCM WAV file holding `data_len` bytes of samples.
+    pub fn header(&self, data_len: u32) -> Result<[u8; WAV_HEADER_SIZE]> {
+        let bits_per_sample = self.bits_per_sample()?;
+        let block_align = self.frame_size() as u16;
+
+        let mut header = [0u8; WAV_HEADER_SIZE];
+        let fields: [(usize, &[u8]); 13] = [
+            (0, b"RIFF"),
+            (4, &(36 + data_len).to_le_bytes()),
+            (8, b"WAVE"),
+            (12, b"fmt "),
+            (16, &16u32.to_le_bytes()),
+            (20, &WAVE_FORMAT_PCM.to_le_bytes()),
+            (22, &(self.num_channels as u16).to_le_bytes()),
+            (24, &self.frame_rate.to_le_bytes()),
+            (
+                28,
+                &(self.frame_rate * u32::from(block_align)).to_le_bytes(),
+            ),
+            (32, &block_align.to_le_bytes()),
+            (34, &bits_per_sample.to_le_bytes()),
+            (36, b"data"),
+            (40, &data_len.to_le_bytes()),
+        ];
+        for (offset, bytes) in fields.iter() {
+            header[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        Ok(header)
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Parses the header of the PCM WAV file `file`.
+///
+/// Returns the format of the samples along with their offset and length in bytes.
+pub fn read_header(file: &File) -> Result<(WavFormat, u64, u64)> {
+    let file_len = file.metadata().map_err(Error::Io)?.len();
+    let mut riff = [0u8; 12];
+    file.read_exact_at(&mut riff, 0).map_err(Error::Io)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(Error::InvalidFile("not a RIFF WAVE file"));
+    }
+
+    let mut format = None;
+    let mut offset = riff.len() as u64;
+    loop {
+        let mut chunk = [0u8; 8];
+        file.read_exact_at(&mut chunk, offset)
+            .map_err(|_| Error::InvalidFile("missing data chunk"))?;
+        let chunk_len = u64::from(read_u32(&chunk[4..8]));
+        offset += chunk.len() as u64;
+
+        match &chunk[0..4] {
+            b"fmt " => {
+                let mut fmt = [0u8; 16];
+                file.read_exact_at(&mut fmt, offset).map_err(Error::Io)?;
+                if read_u16(&fmt[0..2]) != WAVE_FORMAT_PCM {
+                    return Err(Error::InvalidFile("samples are not PCM"));
+                }
+                let sample_format = match read_u16(&fmt[14..16]) {
+                    8 => SampleFormat::U8,
+                    16 => SampleFormat::S16LE,
+                    32 => SampleFormat::S32LE,
+                    _ => return Err(Error::InvalidFile("unsupported bits per sample")),
+                };
+                format = Some(WavFormat {
+                    num_channels: read_u16(&fmt[2..4]) as usize,
+                    format: sample_format,
+                    frame_rate: read_u32(&fmt[4..8]),
+                });
+            }
+            b"data" => {
+                let format = format.ok_or(Error::InvalidFile("data chunk before fmt chunk"))?;
+                let data_len = std::cmp::min(chunk_len, file_len.saturating_sub(offset));
+                return Ok((format, offset, data_len));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        offset += chunk_len + (chunk_len & 1);
+    }
+}
+
+/// Appends samples to a WAV file, keeping its header up to date so the file stays playable even
+/// if crosvm exits without closing the stream.
+struct WavWriter {
+    file: Arc<File>,
+    format: WavFormat,
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn new(file: Arc<File>, format: WavFormat) -> Result<Self> {
+        let header = format.header(0)?;
+        file.set_len(0).map_err(Error::Io)?;
+        file.write_all_at(&header, 0).map_err(Error::Io)?;
+        Ok(WavWriter {
+            file,
+            format,
+            data_len: 0,
+        })
+    }
+
+    fn write(&mut self, samples: &[u8]) -> Result<()> {
+        let data_len = (samples.len() as u64)
+            .checked_add(u64::from(self.data_len))
+            .filter(|&len| len <= u64::from(u32::MAX - 36))
+            .ok_or(Error::FileTooLarge)?;
+        self.file
+            .write_all_at(samples, WAV_HEADER_SIZE as u64 + u64::from(self.data_len))
+            .map_err(Error::Io)?;
+        self.data_len = data_len as u32;
+        self.file
+            .write_all_at(&self.format.header(self.data_len)?, 0)
+            .map_err(Error::Io)
+    }
+}
+
+/// Reads samples from a WAV file, returning silence past its end.
+struct WavReader {
+    file: Arc<File>,
+    format: WavFormat,
+    data_offset: u64,
+    data_len: u64,
+    position: u64,
+}
+
+impl WavReader {
+    fn new(file: Arc<File>, format: WavFormat) -> Result<Self> {
+        let (file_format, data_offset, data_len) = read_header(&file)?;
+        if file_format != format {
+            return Err(Error::FormatMismatch(file_format, format));
+        }
+        Ok(WavReader {
+            file,
+            format,
+            data_offset,
+            data_len,
+            position: 0,
+        })
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let len = std::cmp::min(buffer.len() as u64, self.data_len - self.position) as usize;
+        let (samples, silence) = buffer.split_at_mut(len);
+        self.file
+            .read_exact_at(samples, self.data_offset + self.position)
+            .map_err(Error::Io)?;
+        self.position += len as u64;
+
+        // Unsigned 8 bit samples are centered around 0x80.
+        let silent_sample = match self.format.format {
+            SampleFormat::U8 => 0x80,
+            _ => 0,
+        };
+        for sample in silence {
+            *sample = silent_sample;
+        }
+        Ok(())
+    }
+}
+
+/// Returns how long to wait before handing out the next buffer so that buffers are consumed at
+/// the stream's frame rate.
+struct BufferPacer {
+    interval: Duration,
+    next_buffer: Duration,
+    start_time: Option<Instant>,
+}
+
+impl BufferPacer {
+    fn new(buffer_size: usize, frame_rate: u32) -> Self {
+        BufferPacer {
+            interval: Duration::from_millis(buffer_size as u64 * 1000 / frame_rate as u64),
+            next_buffer: Duration::from_secs(0),
+            start_time: None,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        match self.start_time {
+            Some(start_time) => {
+                let delay = self
+                    .next_buffer
+                    .checked_sub(start_time.elapsed())
+                    .unwrap_or_else(|| Duration::from_secs(0));
+                self.next_buffer += self.interval;
+                delay
+            }
+            None => {
+                self.start_time = Some(Instant::now());
+                self.next_buffer = self.interval;
+                Duration::from_secs(0)
+            }
+        }
+    }
+}
+
+/// Number of frames committed to the current playback buffer.
+#[derive(Default)]
+struct PendingFrames(usize);
+
+impl BufferCommit for PendingFrames {
+    fn commit(&mut self, nframes: usize) {
+        self.0 = nframes;
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for PendingFrames {
+    async fn commit(&mut self, nframes: usize) {
+        self.0 = nframes;
+    }
+}
+
+/// Playback stream that appends the samples it is given to a WAV file.
+pub struct WavPlaybackStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    pacer: BufferPacer,
+    pending_frames: PendingFrames,
+    writer: WavWriter,
+}
+
+impl WavPlaybackStream {
+    fn new(file: Arc<File>, format: WavFormat, buffer_size: usize) -> Result<Self> {
+        Ok(WavPlaybackStream {
+            buffer: vec![0; buffer_size * format.frame_size()],
+            frame_size: format.frame_size(),
+            pacer: BufferPacer::new(buffer_size, format.frame_rate),
+            pending_frames: Default::default(),
+            writer: WavWriter::new(file, format)?,
+        })
+    }
+
+    // The buffer handed out by `next_playback_buffer` borrows the stream, so its samples are
+    // written out when the next buffer is requested or the stream is dropped.
+    fn flush(&mut self) -> Result<()> {
+        let len = self.pending_frames.0 * self.frame_size;
+        self.pending_frames.0 = 0;
+        if len > 0 {
+            self.writer.write(&self.buffer[..len])?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WavPlaybackStream {
+    fn drop(&mut self) {
+        // There's nobody to report a failure to at this point.
+        let _ = self.flush();
+    }
+}
+
+impl PlaybackBufferStream for WavPlaybackStream {
+    fn next_playback_buffer<'b, 's: 'b>(
+        &'s mut self,
+    ) -> std::result::Result<PlaybackBuffer<'b>, BoxError> {
+        self.flush()?;
+        std::thread::sleep(self.pacer.next_delay());
+        Ok(PlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.pending_frames,
+        )?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for WavPlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &Executor,
+    ) -> std::result::Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        self.flush()?;
+        let delay = self.pacer.next_delay();
+        if delay > Duration::from_secs(0) {
+            TimerAsync::sleep(ex, delay).await?;
+        }
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.pending_frames,
+        )?)
+    }
+}
+
+/// Capture stream that provides the samples of a WAV file.
+pub struct WavCaptureStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    pacer: BufferPacer,
+    buffer_drop: NoopBufferCommit,
+    reader: WavReader,
+}
+
+impl WavCaptureStream {
+    fn new(file: Arc<File>, format: WavFormat, buffer_size: usize) -> Result<Self> {
+        Ok(WavCaptureStream {
+            buffer: vec![0; buffer_size * format.frame_size()],
+            frame_size: format.frame_size(),
+            pacer: BufferPacer::new(buffer_size, format.frame_rate),
+            buffer_drop: NoopBufferCommit {
+                which_buffer: false,
+            },
+            reader: WavReader::new(file, format)?,
+        })
+    }
+}
+
+impl CaptureBufferStream for WavCaptureStream {
+    fn next_capture_buffer<'b, 's: 'b>(
+        &'s mut self,
+    ) -> std::result::Result<CaptureBuffer<'b>, BoxError> {
+        std::thread::sleep(self.pacer.next_delay());
+        self.reader.read(&mut self.buffer)?;
+        Ok(CaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for WavCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &Executor,
+    ) -> std::result::Result<AsyncCaptureBuffer<'a>, BoxError> {
+        let delay = self.pacer.next_delay();
+        if delay > Duration::from_secs(0) {
+            TimerAsync::sleep(ex, delay).await?;
+        }
+        self.reader.read(&mut self.buffer)?;
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+enum WavShmFile {
+    Playback(WavWriter),
+    Capture(WavReader),
+}
+
+/// `ShmStream` that copies samples between the client's shared memory and a WAV file.
+pub struct WavShmStream {
+    num_channels: usize,
+    frame_rate: u32,
+    buffer_size: usize,
+    frame_size: usize,
+    interval: Duration,
+    next_frame: Duration,
+    start_time: Instant,
+    client_shm: File,
+    samples: Vec<u8>,
+    // Offsets and lengths in frames of the capture buffers that still need to be filled.
+    capture_buffers: VecDeque<(usize, usize)>,
+    file: WavShmFile,
+}
+
+impl WavShmStream {
+    fn new(
+        file: WavShmFile,
+        format: WavFormat,
+        buffer_size: usize,
+        client_shm: &SharedMemory,
+        buffer_offsets: [u64; 2],
+    ) -> Result<Self> {
+        let fd = clone_fd(client_shm).map_err(Error::CloneShm)?;
+        // Safe because `fd` is a freshly duplicated descriptor that nothing else owns.
+        let client_shm = unsafe { File::from_raw_fd(fd) };
+        let capture_buffers = match file {
+            WavShmFile::Playback(_) => VecDeque::new(),
+            WavShmFile::Capture(_) => buffer_offsets
+                .iter()
+                .map(|&offset| (offset as usize, buffer_size))
+                .collect(),
+        };
+        let interval = Duration::from_millis(buffer_size as u64 * 1000 / format.frame_rate as u64);
+        Ok(WavShmStream {
+            num_channels: format.num_channels,
+            frame_rate: format.frame_rate,
+            buffer_size,
+            frame_size: format.frame_size(),
+            interval,
+            next_frame: interval,
+            start_time: Instant::now(),
+            client_shm,
+            samples: vec![0; buffer_size * format.frame_size()],
+            capture_buffers,
+            file,
+        })
+    }
+}
+
+impl BufferSet for WavShmStream {
+    fn callback(&mut self, offset: usize, frames: usize) -> std::result::Result<(), BoxError> {
+        let samples = &mut self.samples[..frames * self.frame_size];
+        match &mut self.file {
+            WavShmFile::Playback(writer) => {
+                self.client_shm
+                    .read_exact_at(samples, offset as u64)
+                    .map_err(Error::Io)?;
+                writer.write(samples)?;
+            }
+            WavShmFile::Capture(_) => self.capture_buffers.push_back((offset, frames)),
+        }
+        Ok(())
+    }
+
+    fn ignore(&mut self) -> std::result::Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+impl ShmStream for WavShmStream {
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    fn wait_for_next_action_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<Option<ServerRequest>, BoxError> {
+        let elapsed = self.start_time.elapsed();
+        if elapsed < self.next_frame {
+            if timeout < self.next_frame - elapsed {
+                std::thread::sleep(timeout);
+                return Ok(None);
+            } else {
+                std::thread::sleep(self.next_frame - elapsed);
+            }
+        }
+        self.next_frame += self.interval;
+
+        // A period has elapsed, so the oldest capture buffer is now full.
+        if let WavShmFile::Capture(reader) = &mut self.file {
+            if let Some((offset, frames)) = self.capture_buffers.pop_front() {
+                let samples = &mut self.samples[..frames * self.frame_size];
+                reader.read(samples)?;
+                self.client_shm
+                    .write_all_at(samples, offset as u64)
+                    .map_err(Error::Io)?;
+            }
+        }
+        Ok(Some(ServerRequest::new(self.buffer_size, self)))
+    }
+}
+
+/// Source of WAV file backed playback and capture streams.
+#[derive(Clone, Default)]
+pub struct WavStreamSource {
+    playback_file: Option<Arc<File>>,
+    capture_file: Option<Arc<File>>,
+}
+
+impl WavStreamSource {
+    /// Creates a source that writes playback to `playback_file` and reads capture from
+    /// `capture_file`. Creating a stream for a direction without a file fails.
+    pub fn new(playback_file: Option<File>, capture_file: Option<File>) -> Self {
+        WavStreamSource {
+            playback_file: playback_file.map(Arc::new),
+            capture_file: capture_file.map(Arc::new),
+        }
+    }
+
+    fn playback_file(&self) -> Result<Arc<File>> {
+        self.playback_file
+            .clone()
+            .ok_or(Error::MissingFile("playback"))
+    }
+
+    fn capture_file(&self) -> Result<Arc<File>> {
+        self.capture_file
+            .clone()
+            .ok_or(Error::MissingFile("capture"))
+    }
+
+    /// Returns the descriptors of the WAV files, which need to stay open inside a jail.
+    pub fn keep_fds(&self) -> Vec<RawFd> {
+        self.playback_file
+            .iter()
+            .chain(self.capture_file.iter())
+            .map(|f| f.as_raw_fd())
+            .collect()
+    }
+}
+
+impl StreamSource for WavStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+    {
+        let format = WavFormat {
+            num_channels,
+            format,
+            frame_rate,
+        };
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(WavPlaybackStream::new(
+                self.playback_file()?,
+                format,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &Executor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError>
+    {
+        let format = WavFormat {
+            num_channels,
+            format,
+            frame_rate,
+        };
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(WavPlaybackStream::new(
+                self.playback_file()?,
+                format,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        let format = WavFormat {
+            num_channels,
+            format,
+            frame_rate,
+        };
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(WavCaptureStream::new(
+                self.capture_file()?,
+                format,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &Executor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError>
+    {
+        let format = WavFormat {
+            num_channels,
+            format,
+            frame_rate,
+        };
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(WavCaptureStream::new(
+                self.capture_file()?,
+                format,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    fn keep_fds(&self) -> Option<Vec<RawFd>> {
+        Some(WavStreamSource::keep_fds(self))
+    }
+}
+
+impl ShmStreamSource for WavStreamSource {
+    fn new_stream(
+        &mut self,
+        direction: StreamDirection,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        client_shm: &SharedMemory,
+        buffer_offsets: [u64; 2],
+    ) -> std::result::Result<Box<dyn ShmStream>, BoxError> {
+        let format = WavFormat {
+            num_channels,
+            format,
+            frame_rate,
+        };
+        let file = match direction {
+            StreamDirection::Playback => {
+                WavShmFile::Playback(WavWriter::new(self.playback_file()?, format)?)
+            }
+            StreamDirection::Capture => {
+                WavShmFile::Capture(WavReader::new(self.capture_file()?, format)?)
+            }
+        };
+        Ok(Box::new(WavShmStream::new(
+            file,
+            format,
+            buffer_size,
+            client_shm,
+            buffer_offsets,
+        )?))
+    }
+
+    fn keep_fds(&self) -> Vec<RawFd> {
+        WavStreamSource::keep_fds(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    use tempfile::tempfile;
+
+    const STEREO_S16: WavFormat = WavFormat {
+        num_channels: 2,
+        format: SampleFormat::S16LE,
+        frame_rate: 48000,
+    };
+
+    #[test]
+    fn header_round_trip() {
+        let file = tempfile().unwrap();
+        let mut writer = WavWriter::new(Arc::new(file.try_clone().unwrap()), STEREO_S16).unwrap();
+        writer.write(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        writer.write(&[9, 10, 11, 12]).unwrap();
+
+        assert_eq!(file.metadata().unwrap().len(), WAV_HEADER_SIZE as u64 + 12);
+        let (format, data_offset, data_len) = read_header(&file).unwrap();
+        assert_eq!(format, STEREO_S16);
+        assert_eq!(data_offset, WAV_HEADER_SIZE as u64);
+        assert_eq!(data_len, 12);
+    }
+
+    #[test]
+    fn header_skips_unknown_chunks() {
+        let mut file = tempfile().unwrap();
+        let header = STEREO_S16.header(4).unwrap();
+        file.write_all(&header[..36]).unwrap();
+        // An odd sized LIST chunk, which is padded to an even length.
+        file.write_all(b"LIST").unwrap();
+        file.write_all(&3u32.to_le_bytes()).unwrap();
+        file.write_all(&[0, 0, 0, 0]).unwrap();
+        file.write_all(&header[36..]).unwrap();
+        file.write_all(&[1, 2, 3, 4]).unwrap();
+
+        let (format, data_offset, data_len) = read_header(&file).unwrap();
+        assert_eq!(format, STEREO_S16);
+        assert_eq!(data_offset, WAV_HEADER_SIZE as u64 + 12);
+        assert_eq!(data_len, 4);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let mut file = tempfile().unwrap();
+        file.write_all(b"RIFF\0\0\0\0AVI ").unwrap();
+        assert!(read_header(&file).is_err());
+
+        let mut header = STEREO_S16.header(0).unwrap();
+        // Not PCM.
+        header[20] = 3;
+        let mut file = tempfile().unwrap();
+        file.write_all(&header).unwrap();
+        assert!(read_header(&file).is_err());
+
+        let s24 = WavFormat {
+            format: SampleFormat::S24LE,
+            ..STEREO_S16
+        };
+        assert!(s24.header(0).is_err());
+    }
+
+    #[test]
+    fn playback_to_file() {
+        let file = tempfile().unwrap();
+        let mut source = WavStreamSource::new(Some(file.try_clone().unwrap()), None);
+        let (_, mut stream) = source
+            .new_playback_stream(2, SampleFormat::S16LE, 48000, 480)
+            .unwrap();
+        for i in 0..3 {
+            let mut copy_cb = |buf: &mut PlaybackBuffer| {
+                assert_eq!(buf.write(&[i as u8; 480 * 4])?, 480 * 4);
+                Ok(())
+            };
+            stream.write_playback_buffer(&mut copy_cb).unwrap();
+        }
+        drop(stream);
+
+        let (format, data_offset, data_len) = read_header(&file).unwrap();
+        assert_eq!(format, STEREO_S16);
+        assert_eq!(data_len, 3 * 480 * 4);
+        let mut samples = vec![0u8; data_len as usize];
+        file.read_exact_at(&mut samples, data_offset).unwrap();
+        for (i, buffer) in samples.chunks(480 * 4).enumerate() {
+            assert!(buffer.iter().all(|&s| s == i as u8));
+        }
+
+        // A new stream starts a new recording.
+        source
+            .new_playback_stream(2, SampleFormat::S16LE, 48000, 480)
+            .unwrap();
+        assert_eq!(read_header(&file).unwrap().2, 0);
+    }
+
+    #[test]
+    fn capture_from_file() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&STEREO_S16.header(480 * 4 + 8).unwrap())
+            .unwrap();
+        file.write_all(&[0xa5; 480 * 4 + 8]).unwrap();
+
+        let mut source = WavStreamSource::new(None, Some(file));
+        assert!(source
+            .new_capture_stream(1, SampleFormat::S16LE, 48000, 480, &[])
+            .is_err());
+        let (_, mut stream) = source
+            .new_capture_stream(2, SampleFormat::S16LE, 48000, 480, &[])
+            .unwrap();
+
+        let mut buf = vec![0u8; 480 * 4];
+        let mut copy_cb = |stream_buffer: &mut CaptureBuffer| {
+            assert_eq!(stream_buffer.read(&mut buf)?, 480 * 4);
+            Ok(())
+        };
+        stream.read_capture_buffer(&mut copy_cb).unwrap();
+        assert!(buf.iter().all(|&s| s == 0xa5));
+
+        // Two frames are left in the file, followed by silence.
+        let mut copy_cb = |stream_buffer: &mut CaptureBuffer| {
+            assert_eq!(stream_buffer.read(&mut buf)?, 480 * 4);
+            Ok(())
+        };
+        stream.read_capture_buffer(&mut copy_cb).unwrap();
+        assert!(buf[..8].iter().all(|&s| s == 0xa5));
+        assert!(buf[8..].iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn missing_files() {
+        let mut source = WavStreamSource::new(None, None);
+        assert!(source
+            .new_playback_stream(2, SampleFormat::S16LE, 48000, 480)
+            .is_err());
+        assert!(source
+            .new_capture_stream(2, SampleFormat::S16LE, 48000, 480, &[])
+            .is_err());
+        assert!(source.keep_fds().is_empty());
+    }
+
+    #[test]
+    fn shm_playback_to_file() {
+        let file = tempfile().unwrap();
+        let mut source = WavStreamSource::new(Some(file.try_clone().unwrap()), None);
+        let mut shm = SharedMemory::anon().unwrap();
+        shm.set_size(4096).unwrap();
+        (&shm).write_all(&[0x11; 4096]).unwrap();
+
+        let mut stream = source
+            .new_stream(
+                StreamDirection::Playback,
+                2,
+                SampleFormat::S16LE,
+                48000,
+                480,
+                &[],
+                &shm,
+                [0, 0],
+            )
+            .unwrap();
+        let request = stream
+            .wait_for_next_action_with_timeout(Duration::from_secs(1))
+            .unwrap()
+            .expect("no request before the timeout");
+        request.set_buffer_offset_and_frames(64, 100).unwrap();
+
+        let (_, data_offset, data_len) = read_header(&file).unwrap();
+        assert_eq!(data_len, 100 * 4);
+        let mut samples = vec![0u8; data_len as usize];
+        file.read_exact_at(&mut samples, data_offset).unwrap();
+        assert!(samples.iter().all(|&s| s == 0x11));
+    }
+}
@@ -33,6 +33,7 @@ macro_rules! generate {
 
         impl<$($Fut: Future + Unpin),*> $Select<$($Fut),*> {
             paste::item! {
+                #[allow(clippy::too_many_arguments)]
                 pub(crate) fn new($($Fut: $Fut),*) -> $Select<$($Fut),*> {
                     $Select {
                         $($Fut: maybe_done($Fut),)*
@@ -84,4 +85,10 @@ generate! {
 
     /// _Future for the [`select6`] function.
     (Select6, <_Fut1, _Fut2, _Fut3, _Fut4, _Fut5, _Fut6>),
+
+    /// _Future for the [`select7`] function.
+    (Select7, <_Fut1, _Fut2, _Fut3, _Fut4, _Fut5, _Fut6, _Fut7>),
+
+    /// _Future for the [`select8`] function.
+    (Select8, <_Fut1, _Fut2, _Fut3, _Fut4, _Fut5, _Fut6, _Fut7, _Fut8>),
 }
@@ -13,7 +13,11 @@ use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
+use sync::Mutex;
 use sys_util::{read_dir::read_dir, syscall};
 
 use crate::protocol::*;
@@ -101,11 +105,26 @@ const P9_SETATTR_CTIME: u32 = 0x00000040;
 const P9_SETATTR_ATIME_SET: u32 = 0x00000080;
 const P9_SETATTR_MTIME_SET: u32 = 0x00000100;
 
+// Tlock types, flags, and Rlock status values.  Taken from "include/net/9p/9p.h" in the linux
+// tree.
+const P9_LOCK_TYPE_RDLCK: u8 = 0;
+const P9_LOCK_TYPE_WRLCK: u8 = 1;
+const P9_LOCK_TYPE_UNLCK: u8 = 2;
+const _P9_LOCK_FLAGS_BLOCK: u32 = 1;
+const _P9_LOCK_FLAGS_RECLAIM: u32 = 2;
+const P9_LOCK_SUCCESS: u8 = 0;
+const P9_LOCK_BLOCKED: u8 = 1;
+const _P9_LOCK_ERROR: u8 = 2;
+const _P9_LOCK_GRACE: u8 = 3;
+
+// Largest extended attribute value that the kernel supports.
+const XATTR_SIZE_MAX: u64 = 1 << 16;
+
 // Minimum and maximum message size that we'll expect from the client.
 const MIN_MESSAGE_SIZE: u32 = 256;
 const MAX_MESSAGE_SIZE: u32 = ::std::u16::MAX as u32;
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum FileType {
     Regular,
     Directory,
@@ -131,6 +150,20 @@ struct Fid {
     path: File,
     file: Option<File>,
     filetype: FileType,
+    xattr: Option<Xattr>,
+}
+
+// Extended attribute state held by fids created with Txattrwalk or converted by Txattrcreate.
+enum Xattr {
+    // The value of an attribute, or the list of attribute names, returned by Tread.
+    Read(Vec<u8>),
+    // An attribute value built up by Twrite that is set on the file when the fid is clunked.
+    Write {
+        name: CString,
+        size: usize,
+        flags: libc::c_int,
+        value: Mutex<Vec<u8>>,
+    },
 }
 
 impl From<libc::stat64> for Qid {
@@ -328,6 +361,86 @@ fn open_fid(proc: &File, path: &File, p9_flags: u32) -> io::Result<File> {
     Ok(unsafe { File::from_raw_fd(fd) })
 }
 
+// Returns a file for `fid` that can be passed to the f*xattr family of syscalls, which don't
+// accept O_PATH fds.
+fn xattr_file<'a>(proc: &File, fid: &'a Fid) -> io::Result<MaybeOwned<'a, File>> {
+    if let Some(ref file) = fid.file {
+        return Ok(MaybeOwned::Borrowed(file));
+    }
+
+    let flags = match fid.filetype {
+        FileType::Directory => P9_RDONLY | P9_DIRECTORY,
+        FileType::Regular | FileType::Other => P9_RDONLY,
+    };
+    open_fid(proc, &fid.path, P9_NONBLOCK | flags).map(MaybeOwned::Owned)
+}
+
+// Reads the value of the extended attribute `name` from `file` or, if `name` is `None`, the list
+// of extended attribute names.
+fn read_xattr(file: &File, name: Option<&CStr>) -> io::Result<Vec<u8>> {
+    let getxattr = |buf: &mut [u8]| {
+        let value = if buf.is_empty() {
+            ptr::null_mut()
+        } else {
+            buf.as_mut_ptr()
+        };
+
+        // Safe because the kernel will only write up to `buf.len()` bytes into `buf` and we check
+        // the return value.
+        syscall!(unsafe {
+            match name {
+                Some(name) => libc::fgetxattr(
+                    file.as_raw_fd(),
+                    name.as_ptr(),
+                    value as *mut libc::c_void,
+                    buf.len(),
+                ),
+                None => libc::flistxattr(file.as_raw_fd(), value as *mut libc::c_char, buf.len()),
+            }
+        })
+    };
+
+    loop {
+        // Get the current size first. The attribute may change in between so retry if the buffer
+        // turns out to be too small.
+        let size = getxattr(&mut [][..])?;
+        let mut buf = vec![0u8; size as usize];
+        match getxattr(&mut buf) {
+            Ok(count) => {
+                buf.truncate(count as usize);
+                return Ok(buf);
+            }
+            Err(e) if e.errno() == libc::ERANGE => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Converts the type and range of a Tlock or Tgetlock into a `flock64` for an OFD lock.
+fn lock_to_flock(ty: u8, start: u64, length: u64) -> io::Result<libc::flock64> {
+    let l_type = match ty {
+        P9_LOCK_TYPE_RDLCK => libc::F_RDLCK,
+        P9_LOCK_TYPE_WRLCK => libc::F_WRLCK,
+        P9_LOCK_TYPE_UNLCK => libc::F_UNLCK,
+        _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+
+    if start > libc::off64_t::max_value() as u64 || length > libc::off64_t::max_value() as u64 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // Safe because this is a plain C struct and all zeroes is a valid value. OFD locks also
+    // require `l_pid` to be 0.
+    let mut flock: libc::flock64 = unsafe { mem::zeroed() };
+    flock.l_type = l_type as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = start as libc::off64_t;
+    // Like `flock64`, a length of 0 covers everything up to the end of the file.
+    flock.l_len = length as libc::off64_t;
+
+    Ok(flock)
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub root: Box<Path>,
@@ -351,9 +464,10 @@ impl Default for Config {
     }
 }
 pub struct Server {
-    fids: BTreeMap<u32, Fid>,
+    fids: Mutex<BTreeMap<u32, Arc<Fid>>>,
     proc: File,
     cfg: Config,
+    msize: AtomicU32,
 }
 
 impl Server {
@@ -387,8 +501,9 @@ impl Server {
         // Safe because we just opened this fd and we know it is valid.
         let proc = unsafe { File::from_raw_fd(fd) };
         Ok(Server {
-            fids: BTreeMap::new(),
+            fids: Mutex::new(BTreeMap::new()),
             proc,
+            msize: AtomicU32::new(cfg.msize),
             cfg,
         })
     }
@@ -397,12 +512,17 @@ impl Server {
         vec![self.proc.as_raw_fd()]
     }
 
+    /// Handles a single request read from `reader` and writes the response to `writer`.
+    ///
+    /// Requests may be handled concurrently from multiple threads. Requests that change a fid, like
+    /// Tlopen or Tclunk, take effect for requests on that fid that start after they complete.
     pub fn handle_message<R: Read, W: Write>(
-        &mut self,
+        &self,
         reader: &mut R,
         writer: &mut W,
     ) -> io::Result<()> {
-        let Tframe { tag, msg } = WireFormat::decode(&mut reader.take(self.cfg.msize as u64))?;
+        let msize = self.msize.load(Ordering::Relaxed);
+        let Tframe { tag, msg } = WireFormat::decode(&mut reader.take(msize as u64))?;
 
         let rmsg = match msg {
             Tmessage::Version(ref version) => self.version(version).map(Rmessage::Version),
@@ -449,15 +569,28 @@ impl Server {
         writer.flush()
     }
 
-    fn auth(&mut self, _auth: &Tauth) -> io::Result<Rauth> {
+    fn get_fid(&self, fid: u32) -> io::Result<Arc<Fid>> {
+        self.fids.lock().get(&fid).cloned().ok_or_else(ebadf)
+    }
+
+    // Replaces the state held for an existing fid. Requests that are already using the old state
+    // keep their own reference to it.
+    fn replace_fid(&self, fid: u32, new: Fid) -> io::Result<()> {
+        let mut fids = self.fids.lock();
+        let entry = fids.get_mut(&fid).ok_or_else(ebadf)?;
+        *entry = Arc::new(new);
+        Ok(())
+    }
+
+    fn auth(&self, _auth: &Tauth) -> io::Result<Rauth> {
         // Returning an error for the auth message means that the server does not require
         // authentication.
         Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
     }
 
-    fn attach(&mut self, attach: &Tattach) -> io::Result<Rattach> {
+    fn attach(&self, attach: &Tattach) -> io::Result<Rattach> {
         // TODO: Check attach parameters
-        match self.fids.entry(attach.fid) {
+        match self.fids.lock().entry(attach.fid) {
             btree_map::Entry::Vacant(entry) => {
                 let root = CString::new(self.cfg.root.as_os_str().as_bytes())
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -479,26 +612,28 @@ impl Server {
                     path: root_path,
                     file: None,
                     filetype: st.st_mode.into(),
+                    xattr: None,
                 };
                 let response = Rattach { qid: st.into() };
-                entry.insert(fid);
+                entry.insert(Arc::new(fid));
                 Ok(response)
             }
             btree_map::Entry::Occupied(_) => Err(io::Error::from_raw_os_error(libc::EBADF)),
         }
     }
 
-    fn version(&mut self, version: &Tversion) -> io::Result<Rversion> {
+    fn version(&self, version: &Tversion) -> io::Result<Rversion> {
         if version.msize < MIN_MESSAGE_SIZE {
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
 
         // A Tversion request clunks all open fids and terminates any pending I/O.
-        self.fids.clear();
-        self.cfg.msize = min(self.cfg.msize, version.msize);
+        self.fids.lock().clear();
+        let msize = min(self.msize.load(Ordering::Relaxed), version.msize);
+        self.msize.store(msize, Ordering::Relaxed);
 
         Ok(Rversion {
-            msize: self.cfg.msize,
+            msize,
             version: if version.version == "9P2000.L" {
                 String::from("9P2000.L")
             } else {
@@ -508,23 +643,20 @@ impl Server {
     }
 
     #[allow(clippy::unnecessary_wraps)]
-    fn flush(&mut self, _flush: &Tflush) -> io::Result<()> {
-        // TODO: Since everything is synchronous we can't actually flush requests.
+    fn flush(&self, _flush: &Tflush) -> io::Result<()> {
+        // TODO: We can't actually flush requests. None of them wait indefinitely (lock requests
+        // return P9_LOCK_BLOCKED instead) so the flushed request will still get its reply.
         Ok(())
     }
 
-    fn walk(&mut self, walk: Twalk) -> io::Result<Rwalk> {
+    fn walk(&self, walk: Twalk) -> io::Result<Rwalk> {
         // `newfid` must not currently be in use unless it is the same as `fid`.
-        if walk.fid != walk.newfid && self.fids.contains_key(&walk.newfid) {
+        if walk.fid != walk.newfid && self.fids.lock().contains_key(&walk.newfid) {
             return Err(io::Error::from_raw_os_error(libc::EBADF));
         }
 
         // We need to walk the tree.  First get the starting path.
-        let start = self
-            .fids
-            .get(&walk.fid)
-            .ok_or_else(ebadf)
-            .and_then(|fid| fid.path.try_clone())?;
+        let start = self.get_fid(walk.fid)?.path.try_clone()?;
 
         // Now walk the tree and break on the first error, if any.
         let expected_len = walk.wnames.len();
@@ -540,13 +672,14 @@ impl Server {
                 // Store the new fid if the full walk succeeded.
                 if mds.len() == expected_len {
                     let st = mds.last().copied().map(Ok).unwrap_or_else(|| stat(&end))?;
-                    self.fids.insert(
+                    self.fids.lock().insert(
                         walk.newfid,
-                        Fid {
+                        Arc::new(Fid {
                             path: end,
                             file: None,
                             filetype: st.st_mode.into(),
-                        },
+                            xattr: None,
+                        }),
                     );
                 }
             }
@@ -563,13 +696,8 @@ impl Server {
         })
     }
 
-    fn read(&mut self, read: &Tread) -> io::Result<Rread> {
-        // Thankfully, `read` cannot be used to read directories in 9P2000.L.
-        let file = self
-            .fids
-            .get_mut(&read.fid)
-            .and_then(|fid| fid.file.as_mut())
-            .ok_or_else(ebadf)?;
+    fn read(&self, read: &Tread) -> io::Result<Rread> {
+        let fid = self.get_fid(read.fid)?;
 
         // Use an empty Rread struct to figure out the overhead of the header.
         let header_size = Rframe {
@@ -580,7 +708,20 @@ impl Server {
         }
         .byte_size();
 
-        let capacity = min(self.cfg.msize - header_size, read.count);
+        let capacity = min(self.msize.load(Ordering::Relaxed) - header_size, read.count);
+
+        // Reading an xattr fid returns the value fetched by Txattrwalk.
+        if let Some(Xattr::Read(value)) = &fid.xattr {
+            let start = min(read.offset, value.len() as u64) as usize;
+            let end = min(start + capacity as usize, value.len());
+            return Ok(Rread {
+                data: Data(value[start..end].to_vec()),
+            });
+        }
+
+        // Thankfully, `read` cannot be used to read directories in 9P2000.L.
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
+
         let mut buf = Data(vec![0u8; capacity as usize]);
 
         let count = file.read_at(&mut buf, read.offset)?;
@@ -589,12 +730,25 @@ impl Server {
         Ok(Rread { data: buf })
     }
 
-    fn write(&mut self, write: &Twrite) -> io::Result<Rwrite> {
-        let file = self
-            .fids
-            .get_mut(&write.fid)
-            .and_then(|fid| fid.file.as_mut())
-            .ok_or_else(ebadf)?;
+    fn write(&self, write: &Twrite) -> io::Result<Rwrite> {
+        let fid = self.get_fid(write.fid)?;
+
+        // Writing an xattr fid builds up the value announced by Txattrcreate.
+        if let Some(Xattr::Write { size, value, .. }) = &fid.xattr {
+            let mut value = value.lock();
+
+            // The value must be written in order and can't grow beyond the announced size.
+            if write.offset != value.len() as u64 || value.len() + write.data.len() > *size {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            value.extend_from_slice(&write.data);
+            return Ok(Rwrite {
+                count: write.data.len() as u32,
+            });
+        }
+
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
 
         let count = file.write_at(&write.data, write.offset)?;
         Ok(Rwrite {
@@ -602,25 +756,55 @@ impl Server {
         })
     }
 
-    fn clunk(&mut self, clunk: &Tclunk) -> io::Result<()> {
-        match self.fids.entry(clunk.fid) {
-            btree_map::Entry::Vacant(_) => Err(io::Error::from_raw_os_error(libc::EBADF)),
-            btree_map::Entry::Occupied(entry) => {
-                entry.remove();
-                Ok(())
+    fn clunk(&self, clunk: &Tclunk) -> io::Result<()> {
+        let fid = self.fids.lock().remove(&clunk.fid).ok_or_else(ebadf)?;
+
+        // Attributes from Txattrcreate are only set once the whole value has been written.
+        if let Some(Xattr::Write {
+            name,
+            size,
+            flags,
+            value,
+        }) = &fid.xattr
+        {
+            let value = value.lock();
+            if value.len() != *size {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            let file = xattr_file(&self.proc, &fid)?;
+
+            // Linux removes an attribute by replacing it with an empty value.
+            if *size == 0 && *flags == libc::XATTR_REPLACE {
+                // Safe because this doesn't modify any memory and we check the return value.
+                syscall!(unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) })?;
+            } else {
+                // Safe because this only reads `value.len()` bytes from `value` and we check the
+                // return value.
+                syscall!(unsafe {
+                    libc::fsetxattr(
+                        file.as_raw_fd(),
+                        name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void,
+                        value.len(),
+                        *flags,
+                    )
+                })?;
             }
         }
+
+        Ok(())
     }
 
-    fn remove(&mut self, _remove: &Tremove) -> io::Result<()> {
+    fn remove(&self, _remove: &Tremove) -> io::Result<()> {
         // Since a file could be linked into multiple locations, there is no way to know exactly
         // which path we are supposed to unlink. Linux uses unlink_at anyway, so we can just return
         // an error here.
         Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
     }
 
-    fn statfs(&mut self, statfs: &Tstatfs) -> io::Result<Rstatfs> {
-        let fid = self.fids.get(&statfs.fid).ok_or_else(ebadf)?;
+    fn statfs(&self, statfs: &Tstatfs) -> io::Result<Rstatfs> {
+        let fid = self.get_fid(statfs.fid)?;
         let mut buf = MaybeUninit::zeroed();
 
         // Safe because this will only modify `out` and we check the return value.
@@ -643,13 +827,21 @@ impl Server {
         })
     }
 
-    fn lopen(&mut self, lopen: &Tlopen) -> io::Result<Rlopen> {
-        let fid = self.fids.get_mut(&lopen.fid).ok_or_else(ebadf)?;
+    fn lopen(&self, lopen: &Tlopen) -> io::Result<Rlopen> {
+        let fid = self.get_fid(lopen.fid)?;
 
         let file = open_fid(&self.proc, &fid.path, lopen.flags)?;
         let st = stat(&file)?;
 
-        fid.file = Some(file);
+        self.replace_fid(
+            lopen.fid,
+            Fid {
+                path: fid.path.try_clone()?,
+                file: Some(file),
+                filetype: fid.filetype,
+                xattr: None,
+            },
+        )?;
         let iounit = st.st_blksize as u32;
         Ok(Rlopen {
             qid: st.into(),
@@ -657,8 +849,8 @@ impl Server {
         })
     }
 
-    fn lcreate(&mut self, lcreate: Tlcreate) -> io::Result<Rlcreate> {
-        let fid = self.fids.get_mut(&lcreate.fid).ok_or_else(ebadf)?;
+    fn lcreate(&self, lcreate: Tlcreate) -> io::Result<Rlcreate> {
+        let fid = self.get_fid(lcreate.fid)?;
 
         if fid.filetype != FileType::Directory {
             return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
@@ -686,11 +878,17 @@ impl Server {
         let st = stat(&file)?;
         let iounit = st.st_blksize as u32;
 
-        fid.file = Some(file);
-
         // This fid now refers to the newly created file so we need to update the O_PATH fd for it
         // as well.
-        fid.path = lookup(&fid.path, &name)?;
+        self.replace_fid(
+            lcreate.fid,
+            Fid {
+                path: lookup(&fid.path, &name)?,
+                file: Some(file),
+                filetype: st.st_mode.into(),
+                xattr: None,
+            },
+        )?;
 
         Ok(Rlcreate {
             qid: st.into(),
@@ -698,30 +896,30 @@ impl Server {
         })
     }
 
-    fn symlink(&mut self, _symlink: &Tsymlink) -> io::Result<Rsymlink> {
+    fn symlink(&self, _symlink: &Tsymlink) -> io::Result<Rsymlink> {
         // symlinks are not allowed.
         Err(io::Error::from_raw_os_error(libc::EACCES))
     }
 
-    fn mknod(&mut self, _mknod: &Tmknod) -> io::Result<Rmknod> {
+    fn mknod(&self, _mknod: &Tmknod) -> io::Result<Rmknod> {
         // No nodes either.
         Err(io::Error::from_raw_os_error(libc::EACCES))
     }
 
-    fn rename(&mut self, _rename: &Trename) -> io::Result<()> {
+    fn rename(&self, _rename: &Trename) -> io::Result<()> {
         // We cannot support this as an inode may be linked into multiple directories but we don't
         // know which one the client wants us to rename. Linux uses rename_at anyway, so we don't
         // need to worry about this.
         Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
     }
 
-    fn readlink(&mut self, _readlink: &Treadlink) -> io::Result<Rreadlink> {
+    fn readlink(&self, _readlink: &Treadlink) -> io::Result<Rreadlink> {
         // symlinks are not allowed
         Err(io::Error::from_raw_os_error(libc::EACCES))
     }
 
-    fn get_attr(&mut self, get_attr: &Tgetattr) -> io::Result<Rgetattr> {
-        let fid = self.fids.get_mut(&get_attr.fid).ok_or_else(ebadf)?;
+    fn get_attr(&self, get_attr: &Tgetattr) -> io::Result<Rgetattr> {
+        let fid = self.get_fid(get_attr.fid)?;
 
         let st = stat(&fid.path)?;
 
@@ -749,8 +947,8 @@ impl Server {
         })
     }
 
-    fn set_attr(&mut self, set_attr: &Tsetattr) -> io::Result<()> {
-        let fid = self.fids.get(&set_attr.fid).ok_or_else(ebadf)?;
+    fn set_attr(&self, set_attr: &Tsetattr) -> io::Result<()> {
+        let fid = self.get_fid(set_attr.fid)?;
 
         let file = if let Some(ref file) = fid.file {
             MaybeOwned::Borrowed(file)
@@ -839,16 +1037,74 @@ impl Server {
         Ok(())
     }
 
-    fn xattr_walk(&mut self, _xattr_walk: &Txattrwalk) -> io::Result<Rxattrwalk> {
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    fn xattr_walk(&self, xattr_walk: &Txattrwalk) -> io::Result<Rxattrwalk> {
+        // `newfid` must not currently be in use unless it is the same as `fid`.
+        if xattr_walk.fid != xattr_walk.newfid && self.fids.lock().contains_key(&xattr_walk.newfid)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        let fid = self.get_fid(xattr_walk.fid)?;
+        let file = xattr_file(&self.proc, &fid)?;
+
+        // An empty name asks for the list of attribute names rather than a single value.
+        let value = if xattr_walk.name.is_empty() {
+            read_xattr(&file, None)?
+        } else {
+            let name = string_to_cstring(xattr_walk.name.clone())?;
+            read_xattr(&file, Some(&name))?
+        };
+
+        let size = value.len() as u64;
+        let path = fid.path.try_clone()?;
+        self.fids.lock().insert(
+            xattr_walk.newfid,
+            Arc::new(Fid {
+                path,
+                file: None,
+                filetype: fid.filetype,
+                xattr: Some(Xattr::Read(value)),
+            }),
+        );
+
+        Ok(Rxattrwalk { size })
     }
 
-    fn xattr_create(&mut self, _xattr_create: &Txattrcreate) -> io::Result<()> {
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    fn xattr_create(&self, xattr_create: &Txattrcreate) -> io::Result<()> {
+        let fid = self.get_fid(xattr_create.fid)?;
+
+        if xattr_create.attr_size > XATTR_SIZE_MAX {
+            return Err(io::Error::from_raw_os_error(libc::E2BIG));
+        }
+
+        let flags = xattr_create.flags as libc::c_int;
+        if flags & !(libc::XATTR_CREATE | libc::XATTR_REPLACE) != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let name = string_to_cstring(xattr_create.name.clone())?;
+        let size = xattr_create.attr_size as usize;
+
+        // The fid now refers to the new attribute value. It gets written with Twrite and is set
+        // on the file when the fid is clunked.
+        self.replace_fid(
+            xattr_create.fid,
+            Fid {
+                path: fid.path.try_clone()?,
+                file: None,
+                filetype: fid.filetype,
+                xattr: Some(Xattr::Write {
+                    name,
+                    size,
+                    flags,
+                    value: Mutex::new(Vec::with_capacity(size)),
+                }),
+            },
+        )
     }
 
-    fn readdir(&mut self, readdir: &Treaddir) -> io::Result<Rreaddir> {
-        let fid = self.fids.get_mut(&readdir.fid).ok_or_else(ebadf)?;
+    fn readdir(&self, readdir: &Treaddir) -> io::Result<Rreaddir> {
+        let fid = self.get_fid(readdir.fid)?;
 
         if fid.filetype != FileType::Directory {
             return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
@@ -863,11 +1119,16 @@ impl Server {
             }),
         }
         .byte_size();
-        let count = min(self.cfg.msize - header_size, readdir.count);
+        let count = min(
+            self.msize.load(Ordering::Relaxed) - header_size,
+            readdir.count,
+        );
         let mut cursor = Cursor::new(Vec::with_capacity(count as usize));
 
-        let dir = fid.file.as_mut().ok_or_else(ebadf)?;
-        let mut dirents = read_dir(dir, readdir.offset as libc::off64_t)?;
+        // `read_dir` needs exclusive access to the directory to seek it so use a duplicate fd.
+        // It still shares the file offset but clients never read the same fid concurrently.
+        let mut dir = fid.file.as_ref().ok_or_else(ebadf)?.try_clone()?;
+        let mut dirents = read_dir(&mut dir, readdir.offset as libc::off64_t)?;
         while let Some(dirent) = dirents.next().transpose()? {
             let st = statat(&fid.path, &dirent.name, 0)?;
 
@@ -899,12 +1160,9 @@ impl Server {
         })
     }
 
-    fn fsync(&mut self, fsync: &Tfsync) -> io::Result<()> {
-        let file = self
-            .fids
-            .get(&fsync.fid)
-            .and_then(|fid| fid.file.as_ref())
-            .ok_or_else(ebadf)?;
+    fn fsync(&self, fsync: &Tfsync) -> io::Result<()> {
+        let fid = self.get_fid(fsync.fid)?;
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
 
         if fsync.datasync == 0 {
             file.sync_all()?;
@@ -914,20 +1172,81 @@ impl Server {
         Ok(())
     }
 
-    fn lock(&mut self, _lock: &Tlock) -> io::Result<Rlock> {
-        // File locking is not supported.
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    fn lock(&self, lock: &Tlock) -> io::Result<Rlock> {
+        let fid = self.get_fid(lock.fid)?;
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
+        let flock = lock_to_flock(lock.type_, lock.start, lock.length)?;
+
+        // Locks are OFD locks so that every open fid is a separate lock owner. We never wait for a
+        // conflicting lock to go away: the client sleeps and retries blocking requests when it gets
+        // P9_LOCK_BLOCKED back.
+        // Safe because this only reads `flock` and we check the return value.
+        let res = syscall!(unsafe {
+            libc::fcntl(
+                file.as_raw_fd(),
+                libc::F_OFD_SETLK,
+                &flock as *const libc::flock64,
+            )
+        });
+
+        match res {
+            Ok(_) => Ok(Rlock {
+                status: P9_LOCK_SUCCESS,
+            }),
+            Err(e) if e.errno() == libc::EAGAIN || e.errno() == libc::EACCES => Ok(Rlock {
+                status: P9_LOCK_BLOCKED,
+            }),
+            Err(e) => Err(e.into()),
+        }
     }
-    fn get_lock(&mut self, _get_lock: &Tgetlock) -> io::Result<Rgetlock> {
-        // File locking is not supported.
-        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+
+    fn get_lock(&self, get_lock: &Tgetlock) -> io::Result<Rgetlock> {
+        let fid = self.get_fid(get_lock.fid)?;
+        let file = fid.file.as_ref().ok_or_else(ebadf)?;
+        let mut flock = lock_to_flock(get_lock.type_, get_lock.start, get_lock.length)?;
+
+        // Safe because the kernel will only write to `flock` and we check the return value.
+        syscall!(unsafe {
+            libc::fcntl(
+                file.as_raw_fd(),
+                libc::F_OFD_GETLK,
+                &mut flock as *mut libc::flock64,
+            )
+        })?;
+
+        let ty = match flock.l_type as libc::c_int {
+            libc::F_RDLCK => P9_LOCK_TYPE_RDLCK,
+            libc::F_WRLCK => P9_LOCK_TYPE_WRLCK,
+            _ => P9_LOCK_TYPE_UNLCK,
+        };
+
+        if ty == P9_LOCK_TYPE_UNLCK {
+            // Nothing conflicts with the requested lock.
+            return Ok(Rgetlock {
+                ty,
+                start: get_lock.start,
+                length: get_lock.length,
+                proc_id: get_lock.proc_id,
+                client_id: get_lock.client_id.clone(),
+            });
+        }
+
+        Ok(Rgetlock {
+            ty,
+            start: flock.l_start as u64,
+            length: flock.l_len as u64,
+            // The conflicting lock may be held through another fid or by a process on the host so
+            // there is no meaningful process id to report.
+            proc_id: 0,
+            client_id: get_lock.client_id.clone(),
+        })
     }
 
-    fn link(&mut self, link: Tlink) -> io::Result<()> {
-        let target = self.fids.get(&link.fid).ok_or_else(ebadf)?;
+    fn link(&self, link: Tlink) -> io::Result<()> {
+        let target = self.get_fid(link.fid)?;
         let path = string_to_cstring(format!("self/fd/{}", target.path.as_raw_fd()))?;
 
-        let dir = self.fids.get(&link.dfid).ok_or_else(ebadf)?;
+        let dir = self.get_fid(link.dfid)?;
         let name = string_to_cstring(link.name)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -943,8 +1262,8 @@ impl Server {
         Ok(())
     }
 
-    fn mkdir(&mut self, mkdir: Tmkdir) -> io::Result<Rmkdir> {
-        let fid = self.fids.get(&mkdir.dfid).ok_or_else(ebadf)?;
+    fn mkdir(&self, mkdir: Tmkdir) -> io::Result<Rmkdir> {
+        let fid = self.get_fid(mkdir.dfid)?;
         let name = string_to_cstring(mkdir.name)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -954,11 +1273,11 @@ impl Server {
         })
     }
 
-    fn rename_at(&mut self, rename_at: Trenameat) -> io::Result<()> {
-        let olddir = self.fids.get(&rename_at.olddirfid).ok_or_else(ebadf)?;
+    fn rename_at(&self, rename_at: Trenameat) -> io::Result<()> {
+        let olddir = self.get_fid(rename_at.olddirfid)?;
         let oldname = string_to_cstring(rename_at.oldname)?;
 
-        let newdir = self.fids.get(&rename_at.newdirfid).ok_or_else(ebadf)?;
+        let newdir = self.get_fid(rename_at.newdirfid)?;
         let newname = string_to_cstring(rename_at.newname)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -974,8 +1293,8 @@ impl Server {
         Ok(())
     }
 
-    fn unlink_at(&mut self, unlink_at: Tunlinkat) -> io::Result<()> {
-        let dir = self.fids.get(&unlink_at.dirfd).ok_or_else(ebadf)?;
+    fn unlink_at(&self, unlink_at: Tunlinkat) -> io::Result<()> {
+        let dir = self.get_fid(unlink_at.dirfd)?;
         let name = string_to_cstring(unlink_at.name)?;
 
         syscall!(unsafe {
@@ -15,6 +15,8 @@ use std::ops::Deref;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 use std::u32;
 
 // Used to indicate that there is no fid associated with this message.
@@ -157,7 +159,7 @@ fn check_qid(qid: &Qid, md: &fs::Metadata) {
     assert_eq!(qid.path, md.ino());
 }
 
-fn check_attr(server: &mut Server, fid: u32, md: &fs::Metadata) {
+fn check_attr(server: &Server, fid: u32, md: &fs::Metadata) {
     let tgetattr = Tgetattr {
         fid,
         request_mask: P9_GETATTR_BASIC,
@@ -196,7 +198,7 @@ fn check_attr(server: &mut Server, fid: u32, md: &fs::Metadata) {
     assert_eq!(rgetattr.data_version, 0);
 }
 
-fn check_content(server: &mut Server, content: &[u8], fid: u32) {
+fn check_content(server: &Server, content: &[u8], fid: u32) {
     for offset in 0..content.len() {
         let tread = Tread {
             fid,
@@ -209,13 +211,7 @@ fn check_content(server: &mut Server, content: &[u8], fid: u32) {
     }
 }
 
-fn walk<P: Into<PathBuf>>(
-    server: &mut Server,
-    start: P,
-    fid: u32,
-    newfid: u32,
-    names: Vec<String>,
-) {
+fn walk<P: Into<PathBuf>>(server: &Server, start: P, fid: u32, newfid: u32, names: Vec<String>) {
     let mut mds = Vec::with_capacity(names.len());
     let mut buf = start.into();
     for name in &names {
@@ -240,7 +236,7 @@ fn walk<P: Into<PathBuf>>(
 }
 
 fn open<P: Into<PathBuf>>(
-    server: &mut Server,
+    server: &Server,
     dir: P,
     dir_fid: u32,
     name: &str,
@@ -259,7 +255,7 @@ fn open<P: Into<PathBuf>>(
     server.lopen(&tlopen)
 }
 
-fn write<P: AsRef<Path>>(server: &mut Server, dir: P, name: &str, fid: u32, flags: u32) {
+fn write<P: AsRef<Path>>(server: &Server, dir: P, name: &str, fid: u32, flags: u32) {
     let file_path = dir.as_ref().join(name);
     let file_len = if file_path.exists() {
         fs::symlink_metadata(&file_path)
@@ -294,7 +290,7 @@ fn write<P: AsRef<Path>>(server: &mut Server, dir: P, name: &str, fid: u32, flag
 }
 
 fn create<P: Into<PathBuf>>(
-    server: &mut Server,
+    server: &Server,
     dir: P,
     dir_fid: u32,
     fid: u32,
@@ -320,7 +316,7 @@ fn create<P: Into<PathBuf>>(
 }
 
 struct Readdir<'a> {
-    server: &'a mut Server,
+    server: &'a Server,
     fid: u32,
     offset: u64,
     cursor: Cursor<Vec<u8>>,
@@ -356,7 +352,7 @@ impl<'a> Iterator for Readdir<'a> {
     }
 }
 
-fn readdir(server: &mut Server, fid: u32) -> Readdir {
+fn readdir(server: &Server, fid: u32) -> Readdir {
     Readdir {
         server,
         fid,
@@ -435,7 +431,7 @@ fn setup<P: AsRef<Path>>(name: P) -> (ScopedPath<OsString>, Server) {
         .symlink_metadata()
         .expect("failed to get metadata for root dir");
 
-    let mut server = Server::new(&*test_dir, Default::default(), Default::default())
+    let server = Server::new(&*test_dir, Default::default(), Default::default())
         .expect("Failed to create server");
 
     let tversion = Tversion {
@@ -498,7 +494,7 @@ fn invalid_joins() {
 
 #[test]
 fn clunk() {
-    let (_test_dir, mut server) = setup("clunk");
+    let (_test_dir, server) = setup("clunk");
 
     let tclunk = Tclunk { fid: ROOT_FID };
     server.clunk(&tclunk).expect("failed to clunk root fid");
@@ -506,18 +502,18 @@ fn clunk() {
 
 #[test]
 fn get_attr() {
-    let (test_dir, mut server) = setup("get_attr");
+    let (test_dir, server) = setup("get_attr");
 
     let md = test_dir
         .symlink_metadata()
         .expect("failed to get metadata for test dir");
 
-    check_attr(&mut server, ROOT_FID, &md);
+    check_attr(&server, ROOT_FID, &md);
 }
 
 #[test]
 fn tree_walk() {
-    let (test_dir, mut server) = setup("readdir");
+    let (test_dir, server) = setup("readdir");
 
     let mut next_fid = ROOT_FID + 1;
 
@@ -534,16 +530,16 @@ fn tree_walk() {
             .components()
             .map(|c| Path::new(&c).to_string_lossy().to_string())
             .collect();
-        walk(&mut server, &*test_dir, ROOT_FID, dfid, wnames);
+        walk(&server, &*test_dir, ROOT_FID, dfid, wnames);
 
         let md = dir.symlink_metadata().expect("failed to get metadata");
 
-        check_attr(&mut server, dfid, &md);
+        check_attr(&server, dfid, &md);
 
         let fid = next_fid;
         next_fid += 1;
-        open(&mut server, &dir, dfid, "", fid, P9_DIRECTORY).expect("Failed to open directory");
-        for dirent in readdir(&mut server, fid) {
+        open(&server, &dir, dfid, "", fid, P9_DIRECTORY).expect("Failed to open directory");
+        for dirent in readdir(&server, fid) {
             if dirent.name == "." || dirent.name == ".." {
                 continue;
             }
@@ -578,22 +574,14 @@ fn tree_walk() {
 
 #[test]
 fn create_existing_file() {
-    let (test_dir, mut server) = setup("create_existing");
+    let (test_dir, server) = setup("create_existing");
 
     let name = "existing";
     create_local_file(&test_dir, name);
 
     let fid = ROOT_FID + 1;
-    create(
-        &mut server,
-        &*test_dir,
-        ROOT_FID,
-        fid,
-        name,
-        P9_APPEND,
-        0o644,
-    )
-    .expect_err("successfully created existing file");
+    create(&server, &*test_dir, ROOT_FID, fid, name, P9_APPEND, 0o644)
+        .expect_err("successfully created existing file");
 }
 
 enum SetAttrKind {
@@ -605,7 +593,7 @@ fn set_attr_test<F>(kind: SetAttrKind, set_fields: F) -> io::Result<fs::Metadata
 where
     F: FnOnce(&mut Tsetattr),
 {
-    let (test_dir, mut server) = setup("set_attr");
+    let (test_dir, server) = setup("set_attr");
 
     let name = "existing";
     match kind {
@@ -630,13 +618,7 @@ where
     };
 
     let fid = ROOT_FID + 1;
-    walk(
-        &mut server,
-        &*test_dir,
-        ROOT_FID,
-        fid,
-        vec![String::from(name)],
-    );
+    walk(&server, &*test_dir, ROOT_FID, fid, vec![String::from(name)]);
 
     let mut tsetattr = Tsetattr {
         fid,
@@ -751,7 +733,7 @@ fn set_dir_atime() {
 
 #[test]
 fn huge_directory() {
-    let (test_dir, mut server) = setup("huge_directory");
+    let (test_dir, server) = setup("huge_directory");
 
     let name = "newdir";
     let newdir = test_dir.join(name);
@@ -759,7 +741,7 @@ fn huge_directory() {
 
     let dfid = ROOT_FID + 1;
     walk(
-        &mut server,
+        &server,
         &*test_dir,
         ROOT_FID,
         dfid,
@@ -775,8 +757,8 @@ fn huge_directory() {
     }
 
     let fid = dfid + 1;
-    open(&mut server, &newdir, dfid, "", fid, P9_DIRECTORY).expect("Failed to open directory");
-    for f in readdir(&mut server, fid) {
+    open(&server, &newdir, dfid, "", fid, P9_DIRECTORY).expect("Failed to open directory");
+    for f in readdir(&server, fid) {
         let path = newdir.join(&f.name);
 
         let md = fs::symlink_metadata(path).expect("failed to get metadata for path");
@@ -795,7 +777,7 @@ fn huge_directory() {
 
 #[test]
 fn mkdir() {
-    let (test_dir, mut server) = setup("mkdir");
+    let (test_dir, server) = setup("mkdir");
 
     let name = "conan";
     let tmkdir = Tmkdir {
@@ -815,7 +797,7 @@ fn mkdir() {
 
 #[test]
 fn unlink_all() {
-    let (test_dir, mut server) = setup("readdir");
+    let (test_dir, server) = setup("readdir");
 
     let mut next_fid = ROOT_FID + 1;
 
@@ -842,7 +824,7 @@ fn unlink_all() {
                     .components()
                     .map(|c| Path::new(&c).to_string_lossy().to_string())
                     .collect();
-                walk(&mut server, &*test_dir, ROOT_FID, fid, wnames);
+                walk(&server, &*test_dir, ROOT_FID, fid, wnames);
                 dirs.push_back((fid, entry.path()));
             }
 
@@ -878,7 +860,7 @@ fn unlink_all() {
 
 #[test]
 fn rename_at() {
-    let (test_dir, mut server) = setup("rename");
+    let (test_dir, server) = setup("rename");
 
     let name = "oldfile";
     let content = create_local_file(&test_dir, name);
@@ -908,13 +890,13 @@ macro_rules! open_test {
     ($name:ident, $flags:expr) => {
         #[test]
         fn $name() {
-            let (test_dir, mut server) = setup("open");
+            let (test_dir, server) = setup("open");
 
             let fid = ROOT_FID + 1;
             let name = "test.txt";
             let content = create_local_file(&test_dir, name);
 
-            let rlopen = open(&mut server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
+            let rlopen = open(&server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
                 .expect("failed to open file");
 
             let md =
@@ -922,17 +904,17 @@ macro_rules! open_test {
             check_qid(&rlopen.qid, &md);
             assert_eq!(rlopen.iounit, md.blksize() as u32);
 
-            check_attr(&mut server, fid, &md);
+            check_attr(&server, fid, &md);
 
             // Check that the file has the proper contents as long as we didn't
             // truncate it first.
             if $flags & P9_TRUNC == 0 && $flags & P9_WRONLY == 0 {
-                check_content(&mut server, &content, fid);
+                check_content(&server, &content, fid);
             }
 
             // Check that we can write to the file.
             if $flags & P9_RDWR != 0 || $flags & P9_WRONLY != 0 {
-                write(&mut server, &test_dir, name, fid, $flags);
+                write(&server, &test_dir, name, fid, $flags);
             }
 
             let tclunk = Tclunk { fid };
@@ -942,13 +924,13 @@ macro_rules! open_test {
     ($name:ident, $flags:expr, $expected_err:expr) => {
         #[test]
         fn $name() {
-            let (test_dir, mut server) = setup("open_fail");
+            let (test_dir, server) = setup("open_fail");
 
             let fid = ROOT_FID + 1;
             let name = "test.txt";
             create_local_file(&test_dir, name);
 
-            let err = open(&mut server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
+            let err = open(&server, &*test_dir, ROOT_FID, name, fid, $flags as u32)
                 .expect_err("successfully opened file");
             assert_eq!(err.kind(), $expected_err);
 
@@ -1046,22 +1028,22 @@ macro_rules! create_test {
     ($name:ident, $flags:expr, $mode:expr) => {
         #[test]
         fn $name() {
-            let (test_dir, mut server) = setup("create");
+            let (test_dir, server) = setup("create");
 
             let name = "foo.txt";
             let fid = ROOT_FID + 1;
-            let rlcreate = create(&mut server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
+            let rlcreate = create(&server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
                 .expect("failed to create file");
 
             let md =
                 fs::symlink_metadata(test_dir.join(name)).expect("failed to get metadata for file");
             assert_eq!(rlcreate.iounit, md.blksize() as u32);
             check_qid(&rlcreate.qid, &md);
-            check_attr(&mut server, fid, &md);
+            check_attr(&server, fid, &md);
 
             // Check that we can write to the file.
             if $flags & P9_RDWR != 0 || $flags & P9_WRONLY != 0 {
-                write(&mut server, &test_dir, name, fid, $flags);
+                write(&server, &test_dir, name, fid, $flags);
             }
 
             let tclunk = Tclunk { fid };
@@ -1071,7 +1053,7 @@ macro_rules! create_test {
     ($name:ident, $flags:expr, $mode:expr, $expected_err:expr) => {
         #[test]
         fn $name() {
-            let (test_dir, mut server) = setup("create_fail");
+            let (test_dir, server) = setup("create_fail");
 
             let name = "foo.txt";
             // The `fid` in the lcreate call initially points to the directory
@@ -1079,7 +1061,7 @@ macro_rules! create_test {
             // completes.  Duplicate the fid so that we don't end up consuming the
             // root fid.
             let fid = ROOT_FID + 1;
-            let err = create(&mut server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
+            let err = create(&server, &*test_dir, ROOT_FID, fid, name, $flags, $mode)
                 .expect_err("successfully created file");
             assert_eq!(err.kind(), $expected_err);
         }
@@ -1097,3 +1079,174 @@ create_test!(
 );
 create_test!(append_read_write_file_create, P9_APPEND | P9_RDWR, 0o600u32);
 create_test!(append_wronly_file_create, P9_APPEND | P9_WRONLY, 0o600u32);
+
+#[test]
+fn concurrent_reads() {
+    let (test_dir, server) = setup("concurrent_reads");
+    let server = Arc::new(server);
+
+    let name = "test.txt";
+    let content = create_local_file(&test_dir, name);
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let server = Arc::clone(&server);
+            let dir = test_dir.to_path_buf();
+            let content = content.clone();
+            thread::spawn(move || {
+                let fid = ROOT_FID + 1 + i;
+                open(&server, &dir, ROOT_FID, name, fid, P9_RDONLY).expect("failed to open file");
+                check_content(&server, &content, fid);
+
+                let tclunk = Tclunk { fid };
+                server.clunk(&tclunk).expect("failed to clunk file");
+            })
+        })
+        .collect();
+
+    for t in threads {
+        t.join().expect("reader thread panicked");
+    }
+}
+
+#[test]
+fn locks() {
+    let (test_dir, server) = setup("locks");
+
+    let name = "lock.txt";
+    create_local_file(&test_dir, name);
+
+    // Every open fid is a separate lock owner.
+    let fid1 = ROOT_FID + 1;
+    let fid2 = ROOT_FID + 2;
+    open(&server, &*test_dir, ROOT_FID, name, fid1, P9_RDWR).expect("failed to open file");
+    open(&server, &*test_dir, ROOT_FID, name, fid2, P9_RDWR).expect("failed to open file");
+
+    let lock = |fid, type_| {
+        let tlock = Tlock {
+            fid,
+            type_,
+            flags: 0,
+            start: 0,
+            length: 0,
+            proc_id: 1,
+            client_id: String::from("unittest"),
+        };
+        server.lock(&tlock).expect("failed to lock file").status
+    };
+
+    let tgetlock = Tgetlock {
+        fid: fid2,
+        type_: P9_LOCK_TYPE_RDLCK,
+        start: 0,
+        length: 10,
+        proc_id: 2,
+        client_id: String::from("unittest"),
+    };
+
+    assert_eq!(lock(fid1, P9_LOCK_TYPE_WRLCK), P9_LOCK_SUCCESS);
+    assert_eq!(lock(fid2, P9_LOCK_TYPE_RDLCK), P9_LOCK_BLOCKED);
+    let rgetlock = server.get_lock(&tgetlock).expect("failed to get lock");
+    assert_eq!(rgetlock.ty, P9_LOCK_TYPE_WRLCK);
+
+    assert_eq!(lock(fid1, P9_LOCK_TYPE_UNLCK), P9_LOCK_SUCCESS);
+    let rgetlock = server.get_lock(&tgetlock).expect("failed to get lock");
+    assert_eq!(rgetlock.ty, P9_LOCK_TYPE_UNLCK);
+    assert_eq!(lock(fid2, P9_LOCK_TYPE_RDLCK), P9_LOCK_SUCCESS);
+}
+
+#[test]
+fn xattrs() {
+    let (test_dir, server) = setup("xattrs");
+
+    let name = "xattr.txt";
+    create_local_file(&test_dir, name);
+
+    let fid = ROOT_FID + 1;
+    let xattr_fid = ROOT_FID + 2;
+    walk(&server, &*test_dir, ROOT_FID, fid, vec![String::from(name)]);
+
+    let attr = "user.p9_test";
+    let value = b"some attribute value";
+
+    // Setting an attribute converts a fid with Txattrcreate and then writes the value to it.
+    walk(&server, &*test_dir, fid, xattr_fid, Vec::new());
+    let txattrcreate = Txattrcreate {
+        fid: xattr_fid,
+        name: String::from(attr),
+        attr_size: value.len() as u64,
+        flags: 0,
+    };
+    server
+        .xattr_create(&txattrcreate)
+        .expect("failed to create xattr");
+
+    let twrite = Twrite {
+        fid: xattr_fid,
+        offset: 0,
+        data: Data(value.to_vec()),
+    };
+    let rwrite = server.write(&twrite).expect("failed to write xattr");
+    assert_eq!(rwrite.count, value.len() as u32);
+
+    let tclunk = Tclunk { fid: xattr_fid };
+    match server.clunk(&tclunk) {
+        Ok(()) => {}
+        // The file system holding the test directory may not support user xattrs.
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+        Err(e) => panic!("failed to set xattr: {}", e),
+    }
+
+    let txattrwalk = Txattrwalk {
+        fid,
+        newfid: xattr_fid,
+        name: String::from(attr),
+    };
+    let rxattrwalk = server
+        .xattr_walk(&txattrwalk)
+        .expect("failed to walk xattr");
+    assert_eq!(rxattrwalk.size, value.len() as u64);
+    check_content(&server, value, xattr_fid);
+    server.clunk(&tclunk).expect("failed to clunk xattr fid");
+
+    // An empty name lists the attribute names instead.
+    let txattrwalk = Txattrwalk {
+        fid,
+        newfid: xattr_fid,
+        name: String::new(),
+    };
+    server
+        .xattr_walk(&txattrwalk)
+        .expect("failed to list xattrs");
+    let tread = Tread {
+        fid: xattr_fid,
+        offset: 0,
+        count: DEFAULT_BUFFER_SIZE,
+    };
+    let rread = server.read(&tread).expect("failed to read xattr list");
+    assert!(rread.data.split(|&b| b == 0).any(|n| n == attr.as_bytes()));
+    server.clunk(&tclunk).expect("failed to clunk xattr fid");
+
+    // Replacing the attribute with an empty value removes it.
+    walk(&server, &*test_dir, fid, xattr_fid, Vec::new());
+    let txattrcreate = Txattrcreate {
+        fid: xattr_fid,
+        name: String::from(attr),
+        attr_size: 0,
+        flags: libc::XATTR_REPLACE as u32,
+    };
+    server
+        .xattr_create(&txattrcreate)
+        .expect("failed to create xattr");
+    server.clunk(&tclunk).expect("failed to remove xattr");
+
+    let txattrwalk = Txattrwalk {
+        fid,
+        newfid: xattr_fid,
+        name: String::from(attr),
+    };
+    let err = server
+        .xattr_walk(&txattrwalk)
+        .expect_err("xattr was not removed");
+    assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+}
@@ -6,7 +6,7 @@ use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{Stderr, Stdin, Stdout};
 use std::mem;
-use std::net::UdpSocket;
+use std::net::{TcpListener, TcpStream, UdpSocket};
 use std::ops::Drop;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::{UnixDatagram, UnixStream};
@@ -235,6 +235,8 @@ macro_rules! IntoRawDescriptor {
 // relevant container type.
 AsRawDescriptor!(File);
 AsRawDescriptor!(UnlinkUnixSeqpacketListener);
+AsRawDescriptor!(TcpListener);
+AsRawDescriptor!(TcpStream);
 AsRawDescriptor!(UdpSocket);
 AsRawDescriptor!(UnixDatagram);
 AsRawDescriptor!(UnixStream);
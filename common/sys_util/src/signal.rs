@@ -343,7 +343,36 @@ pub unsafe fn register_rt_signal_handler(
     register_signal_handler(num, handler)
 }
 
-/// Creates `sigset` from an array of signal numbers.
+/// Registers `handler` as the signal handler for the real-time signal with signum `num`, without
+/// restarting the system calls that the signal interrupts. Blocking calls like `F_OFD_SETLKW`
+/// fail with `EINTR` instead.
+///
+/// The value of `num` must be within [`SIGRTMIN`, `SIGRTMAX`] range.
+///
+/// # Safety
+///
+/// This is considered unsafe because the given handler will be called asynchronously, interrupting
+/// whatever the thread was doing and therefore must only do async-signal-safe operations.
+pub unsafe fn register_interrupting_rt_signal_handler(
+    num: c_int,
+    handler: extern "C" fn(c_int),
+) -> errno::Result<()> {
+    if !valid_rt_signal_num(num) {
+        return Err(errno::Error::new(EINVAL));
+    }
+
+    let mut sigact: sigaction = mem::zeroed();
+    sigact.sa_sigaction = handler as *const () as usize;
+
+    let ret = sigaction(num, &sigact, null_mut());
+    if ret < 0 {
+        return errno_result();
+    }
+
+    Ok(())
+}
+
+/// Creates `sigset`from an array of signal numbers.
 ///
 /// This is a helper function used when we want to manipulate signals.
 pub fn create_sigset(signals: &[c_int]) -> errno::Result<sigset_t> {
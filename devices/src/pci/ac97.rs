@@ -3,10 +3,14 @@
 // found in the LICENSE file.
 
 use std::default::Default;
+use std::fs::{File, OpenOptions};
 use std::path::PathBuf;
 use std::str::FromStr;
 
+#[cfg(feature = "audio_pulse")]
+use audio_streams::pulse::PulseStreamSource;
 use audio_streams::shm_streams::{NullShmStreamSource, ShmStreamSource};
+use audio_streams::wav::WavStreamSource;
 use base::{error, AsRawDescriptor, Event, RawDescriptor};
 #[cfg(feature = "audio_cras")]
 use libcras::{CrasClient, CrasClientType, CrasSocketType, CrasSysError};
@@ -43,6 +47,9 @@ pub enum Ac97Backend {
     #[cfg(feature = "audio_cras")]
     CRAS,
     VIOS,
+    WAV,
+    #[cfg(feature = "audio_pulse")]
+    PULSE,
 }
 
 impl Default for Ac97Backend {
@@ -55,7 +62,7 @@ impl Default for Ac97Backend {
 #[sorted]
 #[derive(Error, Debug)]
 pub enum Ac97Error {
-    #[error("Must be cras, vios or null")]
+    #[error("Must be cras, vios, wav, pulse or null")]
     InvalidBackend,
     #[error("server must be provided for vios backend")]
     MissingServerPath,
@@ -68,6 +75,9 @@ impl FromStr for Ac97Backend {
             #[cfg(feature = "audio_cras")]
             "cras" => Ok(Ac97Backend::CRAS),
             "vios" => Ok(Ac97Backend::VIOS),
+            "wav" => Ok(Ac97Backend::WAV),
+            #[cfg(feature = "audio_pulse")]
+            "pulse" => Ok(Ac97Backend::PULSE),
             "null" => Ok(Ac97Backend::NULL),
             _ => Err(Ac97Error::InvalidBackend),
         }
@@ -80,6 +90,8 @@ pub struct Ac97Parameters {
     pub backend: Ac97Backend,
     pub capture: bool,
     pub vios_server_path: Option<PathBuf>,
+    pub playback_file: Option<PathBuf>,
+    pub capture_file: Option<PathBuf>,
     #[cfg(feature = "audio_cras")]
     client_type: Option<CrasClientType>,
     #[cfg(feature = "audio_cras")]
@@ -166,6 +178,9 @@ impl Ac97Dev {
                 Ok(Self::create_null_audio_device(mem))
             }),
             Ac97Backend::VIOS => Self::create_vios_audio_device(mem, param),
+            Ac97Backend::WAV => Self::create_wav_audio_device(mem, param),
+            #[cfg(feature = "audio_pulse")]
+            Ac97Backend::PULSE => Ok(Self::create_pulse_audio_device(mem)),
             Ac97Backend::NULL => Ok(Self::create_null_audio_device(mem)),
         }
     }
@@ -176,6 +191,9 @@ impl Ac97Dev {
             #[cfg(feature = "audio_cras")]
             Ac97Backend::CRAS => "cras_audio_device",
             Ac97Backend::VIOS => "vios_audio_device",
+            Ac97Backend::WAV => "wav_audio_device",
+            #[cfg(feature = "audio_pulse")]
+            Ac97Backend::PULSE => "pulse_audio_device",
             Ac97Backend::NULL => "null_audio_device",
         }
     }
@@ -216,6 +234,30 @@ impl Ac97Dev {
         ))
     }
 
+    fn create_wav_audio_device(mem: GuestMemory, param: Ac97Parameters) -> Result<Self> {
+        // The files are opened now because the device can't reach them once it is jailed.
+        // The presence of playback_file is checked during argument parsing.
+        let playback_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(param.playback_file.expect("Missing playback file"))
+            .map_err(pci_device::Error::OpenWavFileFailed)?;
+        let capture_file = param
+            .capture_file
+            .map(File::open)
+            .transpose()
+            .map_err(pci_device::Error::OpenWavFileFailed)?;
+        let server = Box::new(WavStreamSource::new(Some(playback_file), capture_file));
+        Ok(Self::new(mem, Ac97Backend::WAV, server))
+    }
+
+    #[cfg(feature = "audio_pulse")]
+    fn create_pulse_audio_device(mem: GuestMemory) -> Self {
+        let server = Box::new(PulseStreamSource::new());
+        Self::new(mem, Ac97Backend::PULSE, server)
+    }
+
     fn create_null_audio_device(mem: GuestMemory) -> Self {
         let server = Box::new(NullShmStreamSource::new());
         Self::new(mem, Ac97Backend::NULL, server)
@@ -49,6 +49,10 @@ pub enum Error {
     /// MSIX Allocator encounters size of zero
     #[error("Size of zero detected in MSIX Allocator")]
     MsixAllocatorSizeZero,
+    /// Opening a WAV file for an audio device failed.
+    #[cfg(feature = "audio")]
+    #[error("failed to open WAV file: {0}")]
+    OpenWavFileFailed(std::io::Error),
     /// PCI Address is not allocated.
     #[error("PCI address is not allocated")]
     PciAddressMissing,
@@ -0,0 +1,616 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Weak};
+
+use super::{descriptor_bytes, string_descriptor_bytes, DESCRIPTOR_TYPE_STRING};
+use crate::usb::host_backend::error::*;
+use crate::usb::host_backend::host_device::ControlEndpointState;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::{BackendType, UsbDeviceAddress, XhciBackendDevice};
+use crate::usb::xhci::xhci_transfer::{
+    TransferDirection, XhciTransfer, XhciTransferState, XhciTransferType,
+};
+use crate::utils::{AsyncJobQueue, EventHandler, EventLoop, FailHandle};
+use base::{error, Descriptor, RawDescriptor};
+use sync::Mutex;
+use usb_util::{
+    ControlRequestDataPhaseTransferDirection, ControlRequestRecipient, DescriptorType,
+    DeviceDescriptor, StandardControlRequest, TransferStatus, UsbRequestSetup,
+};
+
+/// US English, the only language of the string descriptors.
+const LANGUAGE_ID_EN_US: u16 = 0x0409;
+
+/// The part of an emulated USB device that differs from one kind of device to another: its
+/// descriptors, class requests and data endpoints. `EmulatedDevice` takes care of the rest.
+pub trait UsbFunction: Send {
+    /// Returns the device descriptor.
+    fn device_descriptor(&self) -> DeviceDescriptor;
+    /// Returns the descriptor of configuration 1 followed by all its interface, class-specific
+    /// and endpoint descriptors.
+    fn config_descriptor(&self) -> Vec<u8>;
+    /// Returns the strings of the device, which string descriptor indices start at 1.
+    fn strings(&self) -> &[&'static str];
+    /// Returns a class-specific descriptor, such as a HID report descriptor, requested with a
+    /// standard GET_DESCRIPTOR request.
+    fn class_descriptor(&self, _setup: &UsbRequestSetup) -> Option<Vec<u8>> {
+        None
+    }
+    /// Handles a class or vendor control request. `data` holds the data stage of host to device
+    /// requests. Returns the data stage of device to host requests, which is empty for host to
+    /// device requests, or None if the request isn't supported.
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &[u8]) -> Option<Vec<u8>>;
+    /// Handles data written by the host to OUT `endpoint`.
+    fn write_endpoint(&mut self, endpoint: u8, data: &[u8]);
+    /// Returns up to `max_len` bytes the device has for IN `endpoint`, or None if it has nothing
+    /// to send yet, in which case the transfer is retried when the event source gets readable.
+    fn read_endpoint(&mut self, endpoint: u8, max_len: usize) -> Option<Vec<u8>>;
+    /// Resets the device to its state right after it is attached.
+    fn reset(&mut self);
+    /// Returns the descriptor of the file that feeds the IN endpoints, if any.
+    fn event_source(&self) -> Option<RawDescriptor> {
+        None
+    }
+    /// Consumes what is readable from the event source. Returns false once the source is closed.
+    fn read_event_source(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Handles a control request addressed to an emulated device whose function is `function` and
+/// current configuration is `configuration`. Returns the same as `UsbFunction::control_request`.
+pub fn handle_control_request<F: UsbFunction>(
+    function: &mut F,
+    configuration: &mut u8,
+    setup: &UsbRequestSetup,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    let standard_request = match setup.get_standard_request() {
+        Some(req) => req,
+        None => return function.control_request(setup, data),
+    };
+    match (standard_request, setup.get_recipient()) {
+        (StandardControlRequest::GetStatus, _) => Some(vec![0, 0]),
+        (StandardControlRequest::ClearFeature, _)
+        | (StandardControlRequest::SetFeature, _)
+        | (StandardControlRequest::SetAddress, ControlRequestRecipient::Device)
+        | (StandardControlRequest::SetInterface, ControlRequestRecipient::Interface) => {
+            Some(Vec::new())
+        }
+        (StandardControlRequest::GetDescriptor, ControlRequestRecipient::Device) => {
+            let descriptor_type = (setup.value >> 8) as u8;
+            let index = setup.value as u8;
+            if descriptor_type == DescriptorType::Device as u8 {
+                Some(descriptor_bytes(&function.device_descriptor()))
+            } else if descriptor_type == DescriptorType::Configuration as u8 && index == 0 {
+                Some(function.config_descriptor())
+            } else if descriptor_type == DESCRIPTOR_TYPE_STRING && index == 0 {
+                let mut bytes = vec![4, DESCRIPTOR_TYPE_STRING];
+                bytes.extend_from_slice(&LANGUAGE_ID_EN_US.to_le_bytes());
+                Some(bytes)
+            } else if descriptor_type == DESCRIPTOR_TYPE_STRING {
+                function
+                    .strings()
+                    .get(index as usize - 1)
+                    .map(|s| string_descriptor_bytes(s))
+            } else {
+                usb_debug!("unsupported descriptor type {}", descriptor_type);
+                None
+            }
+        }
+        (StandardControlRequest::GetDescriptor, _) => function.class_descriptor(setup),
+        (StandardControlRequest::GetConfiguration, ControlRequestRecipient::Device) => {
+            Some(vec![*configuration])
+        }
+        (StandardControlRequest::SetConfiguration, ControlRequestRecipient::Device) => {
+            match setup.value {
+                0 | 1 => {
+                    *configuration = setup.value as u8;
+                    Some(Vec::new())
+                }
+                _ => None,
+            }
+        }
+        (StandardControlRequest::GetInterface, ControlRequestRecipient::Interface) => Some(vec![0]),
+        _ => None,
+    }
+}
+
+// A transfer to an IN endpoint that waits for the device to have something to send.
+struct PendingTransfer {
+    transfer: Arc<XhciTransfer>,
+    endpoint: u8,
+    buffer: ScatterGatherBuffer,
+}
+
+/// State of an emulated device shared between the device and the handler of its event source.
+struct FunctionState<F: UsbFunction> {
+    function: F,
+    pending: VecDeque<PendingTransfer>,
+}
+
+impl<F: UsbFunction> FunctionState<F> {
+    // Completes the pending transfers for which the function now has data.
+    fn complete_pending(&mut self) -> Result<()> {
+        let pending = mem::take(&mut self.pending);
+        for p in pending {
+            let max_len = p.buffer.len().map_err(Error::BufferLen)?;
+            let data = {
+                // Hold the lock so that the transfer can't be cancelled once data is consumed.
+                let mut state = p.transfer.state().lock();
+                if !matches!(*state, XhciTransferState::Submitted { .. }) {
+                    // The transfer was cancelled, its cancel callback completes it.
+                    continue;
+                }
+                match self.function.read_endpoint(p.endpoint, max_len) {
+                    Some(data) => {
+                        *state = XhciTransferState::Completed;
+                        data
+                    }
+                    None => {
+                        drop(state);
+                        self.pending.push_back(p);
+                        continue;
+                    }
+                }
+            };
+            let bytes_transferred = p.buffer.write(&data).map_err(Error::WriteBuffer)?;
+            usb_debug!(
+                "endpoint {} in transfer completed with {} bytes",
+                p.endpoint,
+                bytes_transferred
+            );
+            p.transfer
+                .on_transfer_complete(&TransferStatus::Completed, bytes_transferred as u32)
+                .map_err(Error::TransferComplete)?;
+        }
+        Ok(())
+    }
+}
+
+// Completes a pending transfer that has been cancelled by the xHCI controller.
+fn complete_cancelled_transfer(transfer: &XhciTransfer) -> Result<()> {
+    let mut state = transfer.state().lock();
+    if !matches!(*state, XhciTransferState::Cancelling) {
+        return Ok(());
+    }
+    *state = XhciTransferState::Cancelled;
+    drop(state);
+    transfer
+        .on_transfer_complete(&TransferStatus::Cancelled, 0)
+        .map_err(Error::TransferComplete)
+}
+
+/// An emulated USB device, made of a generic control endpoint and a `UsbFunction` for everything
+/// specific to the kind of device.
+pub struct EmulatedDevice<F: UsbFunction> {
+    fail_handle: Arc<dyn FailHandle>,
+    job_queue: Arc<AsyncJobQueue>,
+    state: Arc<Mutex<FunctionState<F>>>,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+    configuration: u8,
+}
+
+impl<F: UsbFunction + 'static> EmulatedDevice<F> {
+    /// Create a new emulated device for `function`.
+    pub fn new(
+        fail_handle: Arc<dyn FailHandle>,
+        job_queue: Arc<AsyncJobQueue>,
+        function: F,
+    ) -> EmulatedDevice<F> {
+        EmulatedDevice {
+            fail_handle,
+            job_queue,
+            state: Arc::new(Mutex::new(FunctionState {
+                function,
+                pending: VecDeque::new(),
+            })),
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+            configuration: 0,
+        }
+    }
+
+    /// Returns a handler that feeds the event source of the function to its IN endpoints, along
+    /// with the descriptor to poll it on, if the function has an event source.
+    pub fn event_handler(
+        &self,
+        event_loop: Arc<EventLoop>,
+    ) -> Option<(Arc<dyn EventHandler>, Descriptor)> {
+        let source = Descriptor(self.state.lock().function.event_source()?);
+        let handler: Arc<dyn EventHandler> = Arc::new(FunctionEventHandler {
+            state: self.state.clone(),
+            event_loop,
+            source,
+        });
+        Some((handler, source))
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let setup = self.control_request_setup;
+        let direction = setup.get_direction();
+        let mut data = Vec::new();
+        if direction == ControlRequestDataPhaseTransferDirection::HostToDevice {
+            if let Some(buffer) = &buffer {
+                data.resize(buffer.len().map_err(Error::BufferLen)?, 0);
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+            }
+        }
+
+        let mut state = self.state.lock();
+        let response =
+            handle_control_request(&mut state.function, &mut self.configuration, &setup, &data);
+        let (status, bytes_transferred) = match response {
+            Some(response) => {
+                let bytes_transferred = match (&buffer, direction) {
+                    (Some(buffer), ControlRequestDataPhaseTransferDirection::DeviceToHost) => {
+                        let len = min(response.len(), setup.length as usize);
+                        buffer.write(&response[..len]).map_err(Error::WriteBuffer)?
+                    }
+                    _ => data.len(),
+                };
+                (TransferStatus::Completed, bytes_transferred)
+            }
+            None => {
+                usb_debug!("unsupported control request: {:?}", setup);
+                (TransferStatus::Error, 0)
+            }
+        };
+        xhci_transfer
+            .on_transfer_complete(&status, bytes_transferred as u32)
+            .map_err(Error::TransferComplete)?;
+        // Class requests such as resets can change what the IN endpoints have to send.
+        state.complete_pending()
+    }
+
+    fn handle_control_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage(setup) => {
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_debug!("setup stage setup buffer: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage(buffer) => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                self.execute_control_transfer(xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Queues an IN transfer until the function has data for it.
+    fn queue_in_transfer(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        endpoint: u8,
+        buffer: ScatterGatherBuffer,
+    ) -> Result<()> {
+        let xhci_transfer = Arc::new(xhci_transfer);
+        {
+            let mut state = xhci_transfer.state().lock();
+            match mem::replace(&mut *state, XhciTransferState::Cancelled) {
+                XhciTransferState::Created => {
+                    // The callback runs with the transfer state locked, so the transfer is
+                    // completed later on the job queue.
+                    let weak_transfer: Weak<XhciTransfer> = Arc::downgrade(&xhci_transfer);
+                    let job_queue = self.job_queue.clone();
+                    let fail_handle = self.fail_handle.clone();
+                    let cancel_callback = Box::new(move || {
+                        let weak_transfer = weak_transfer.clone();
+                        let fail_handle = fail_handle.clone();
+                        let job = move || {
+                            if let Some(transfer) = weak_transfer.upgrade() {
+                                if let Err(e) = complete_cancelled_transfer(&transfer) {
+                                    error!("failed to complete cancelled transfer: {}", e);
+                                    fail_handle.fail();
+                                }
+                            }
+                        };
+                        if let Err(e) = job_queue.queue_job(job) {
+                            error!("failed to queue transfer cancellation: {}", e);
+                        }
+                    });
+                    *state = XhciTransferState::Submitted { cancel_callback };
+                }
+                XhciTransferState::Cancelled => {
+                    drop(state);
+                    return xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Cancelled, 0)
+                        .map_err(Error::TransferComplete);
+                }
+                _ => {
+                    error!("xhci trasfer state is invalid");
+                    return Err(Error::BadXhciTransferState);
+                }
+            }
+        }
+
+        let mut state = self.state.lock();
+        state.pending.push_back(PendingTransfer {
+            transfer: xhci_transfer,
+            endpoint,
+            buffer,
+        });
+        state.complete_pending()
+    }
+
+    fn handle_data_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let endpoint = xhci_transfer.get_endpoint_number();
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        let buffer = match transfer_type {
+            XhciTransferType::Normal(buffer) => buffer,
+            XhciTransferType::Noop => {
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete);
+            }
+            _ => {
+                error!(
+                    "unsupported {} transfer sent to endpoint {}",
+                    transfer_type, endpoint
+                );
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+
+        match xhci_transfer.get_transfer_dir() {
+            TransferDirection::In => self.queue_in_transfer(xhci_transfer, endpoint, buffer),
+            TransferDirection::Out => {
+                let mut data = vec![0u8; buffer.len().map_err(Error::BufferLen)?];
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+                let mut state = self.state.lock();
+                state.function.write_endpoint(endpoint, &data);
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, data.len() as u32)
+                    .map_err(Error::TransferComplete)?;
+                state.complete_pending()
+            }
+            TransferDirection::Control => {
+                error!("control transfer sent to endpoint {}", endpoint);
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete)
+            }
+        }
+    }
+}
+
+impl<F: UsbFunction + 'static> XhciBackendDevice for EmulatedDevice<F> {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.state.lock().function.device_descriptor().idVendor
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.state.lock().function.device_descriptor().idProduct
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            self.handle_control_transfer(transfer)
+        } else {
+            self.handle_data_transfer(transfer)
+        }
+    }
+
+    fn set_address(&mut self, _address: UsbDeviceAddress) {
+        usb_debug!(
+            "Set address control transfer is received with address: {}",
+            _address
+        );
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        usb_debug!("resetting emulated device");
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        self.configuration = 0;
+        self.state.lock().function.reset();
+        Ok(())
+    }
+}
+
+// Feeds the event source of a function to the pending transfers of its IN endpoints.
+struct FunctionEventHandler<F: UsbFunction> {
+    state: Arc<Mutex<FunctionState<F>>>,
+    event_loop: Arc<EventLoop>,
+    source: Descriptor,
+}
+
+impl<F: UsbFunction> EventHandler for FunctionEventHandler<F> {
+    fn on_event(&self) -> std::result::Result<(), ()> {
+        let mut state = self.state.lock();
+        let open = state.function.read_event_source().unwrap_or_else(|e| {
+            error!("failed to read event source of emulated device: {}", e);
+            false
+        });
+        if !open {
+            usb_debug!("event source of emulated device closed");
+            if let Err(e) = self.event_loop.remove_event_for_fd(&self.source) {
+                error!("failed to remove event source from event loop: {}", e);
+            }
+        }
+        state.complete_pending().map_err(|e| {
+            error!("failed to complete pending transfers: {}", e);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::emulated::serial::Serial;
+    use tempfile::tempfile;
+    use usb_util::{control_request_type, ControlRequestType};
+
+    fn standard_request(
+        dir: ControlRequestDataPhaseTransferDirection,
+        request: StandardControlRequest,
+        value: u16,
+        length: u16,
+    ) -> UsbRequestSetup {
+        UsbRequestSetup::new(
+            control_request_type(
+                ControlRequestType::Standard,
+                dir,
+                ControlRequestRecipient::Device,
+            ),
+            request as u8,
+            value,
+            0,
+            length,
+        )
+    }
+
+    fn get_descriptor(function: &mut Serial, descriptor_type: u8, index: u8) -> Option<Vec<u8>> {
+        let setup = standard_request(
+            ControlRequestDataPhaseTransferDirection::DeviceToHost,
+            StandardControlRequest::GetDescriptor,
+            (descriptor_type as u16) << 8 | index as u16,
+            255,
+        );
+        handle_control_request(function, &mut 0, &setup, &[])
+    }
+
+    #[test]
+    fn descriptors() {
+        let mut serial = Serial::new(tempfile().unwrap());
+
+        let device = get_descriptor(&mut serial, DescriptorType::Device as u8, 0).unwrap();
+        assert_eq!(device.len(), 18);
+        assert_eq!(device[0], 18);
+        assert_eq!(u16::from_le_bytes([device[8], device[9]]), 0x0525);
+
+        let config = get_descriptor(&mut serial, DescriptorType::Configuration as u8, 0).unwrap();
+        assert_eq!(
+            u16::from_le_bytes([config[2], config[3]]) as usize,
+            config.len()
+        );
+        assert_eq!(
+            get_descriptor(&mut serial, DescriptorType::Configuration as u8, 1),
+            None
+        );
+
+        assert_eq!(
+            get_descriptor(&mut serial, DESCRIPTOR_TYPE_STRING, 0),
+            Some(vec![4, DESCRIPTOR_TYPE_STRING, 0x09, 0x04])
+        );
+        assert_eq!(
+            get_descriptor(&mut serial, DESCRIPTOR_TYPE_STRING, 1),
+            Some(vec![
+                14,
+                DESCRIPTOR_TYPE_STRING,
+                b'c',
+                0,
+                b'r',
+                0,
+                b'o',
+                0,
+                b's',
+                0,
+                b'v',
+                0,
+                b'm',
+                0
+            ])
+        );
+        assert_eq!(get_descriptor(&mut serial, DESCRIPTOR_TYPE_STRING, 4), None);
+    }
+
+    #[test]
+    fn configuration() {
+        let mut serial = Serial::new(tempfile().unwrap());
+        let mut configuration = 0;
+        let get = standard_request(
+            ControlRequestDataPhaseTransferDirection::DeviceToHost,
+            StandardControlRequest::GetConfiguration,
+            0,
+            1,
+        );
+        let set = |value| {
+            standard_request(
+                ControlRequestDataPhaseTransferDirection::HostToDevice,
+                StandardControlRequest::SetConfiguration,
+                value,
+                0,
+            )
+        };
+
+        assert_eq!(
+            handle_control_request(&mut serial, &mut configuration, &get, &[]),
+            Some(vec![0])
+        );
+        assert_eq!(
+            handle_control_request(&mut serial, &mut configuration, &set(1), &[]),
+            Some(Vec::new())
+        );
+        assert_eq!(
+            handle_control_request(&mut serial, &mut configuration, &get, &[]),
+            Some(vec![1])
+        );
+        assert_eq!(
+            handle_control_request(&mut serial, &mut configuration, &set(2), &[]),
+            None
+        );
+        assert_eq!(configuration, 1);
+    }
+}
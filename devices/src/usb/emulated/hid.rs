@@ -0,0 +1,566 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB HID keyboard and tablet, fed with `virtio_input_event`s read from a file or socket.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+
+use super::{
+    config_descriptor_bytes, descriptor_bytes, device_descriptor, UsbFunction,
+    ENDPOINT_ATTRIBUTES_INTERRUPT, ENDPOINT_DIRECTION_IN,
+};
+use base::{AsRawDescriptor, RawDescriptor};
+use linux_input_sys::{virtio_input_event, InputEventDecoder};
+use usb_util::{
+    ControlRequestType, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor, UsbRequestSetup,
+};
+
+const PRODUCT_ID: u16 = 0xa4ac;
+const KEYBOARD_STRINGS: &[&str] = &["crosvm", "crosvm USB keyboard", "000000000001"];
+const TABLET_STRINGS: &[&str] = &["crosvm", "crosvm USB tablet", "000000000001"];
+
+const INTERFACE_CLASS_HID: u8 = 0x03;
+const INTERFACE_SUBCLASS_BOOT: u8 = 0x01;
+const INTERFACE_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+const INTERRUPT_IN_ENDPOINT: u8 = 1;
+// Poll every 8 ms.
+const INTERRUPT_INTERVAL: u8 = 7;
+
+// HID class requests.
+const REQUEST_GET_REPORT: u8 = 0x01;
+const REQUEST_GET_IDLE: u8 = 0x02;
+const REQUEST_GET_PROTOCOL: u8 = 0x03;
+const REQUEST_SET_REPORT: u8 = 0x09;
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+const PROTOCOL_REPORT: u8 = 1;
+
+// Reports that weren't picked up by the guest are dropped beyond this many.
+const MAX_QUEUED_REPORTS: usize = 64;
+
+// Event types and codes of the input events, from linux/input-event-codes.h.
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+
+// Linux key codes of the modifier keys, in the order of the modifier bits of keyboard reports.
+const MODIFIER_KEYS: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+// Linux key codes of the keyboard usages 0x00 to 0x65 of the HID usage tables, 0 when unused.
+const USAGE_KEYS: [u16; 0x66] = [
+    0, 0, 0, 0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, // 0x00
+    50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44, 2, 3, // 0x10
+    4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26, // 0x20
+    27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64, // 0x30
+    65, 66, 67, 68, 87, 88, 99, 70, 119, 110, 102, 104, 111, 107, 109, 106, // 0x40
+    105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71, // 0x50
+    72, 73, 82, 83, 86, 127, // 0x60
+];
+
+// Usage reported in every key slot when more than 6 keys are pressed.
+const USAGE_ROLLOVER: u8 = 0x01;
+
+/// Report descriptor of the boot keyboard, from appendix B.1 of the HID specification.
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant)
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array)
+    0xc0, // End Collection
+];
+
+/// Report descriptor of the tablet: 3 buttons, absolute X and Y from 0 to 0x7fff, and a wheel.
+const TABLET_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xff, 0x7f, //     Logical Maximum (0x7fff)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+const TABLET_AXIS_MAX: u32 = 0x7fff;
+
+enum HidState {
+    Keyboard {
+        modifiers: u8,
+        // Usages of the pressed keys, in the order they were pressed.
+        keys: Vec<u8>,
+    },
+    Tablet {
+        width: u32,
+        height: u32,
+        buttons: u8,
+        x: u16,
+        y: u16,
+        wheel: i32,
+    },
+}
+
+/// A HID keyboard or tablet with an interrupt IN endpoint sending input reports.
+pub struct Hid {
+    source: File,
+    // Bytes of an event that was partially read from `source`.
+    partial: Vec<u8>,
+    state: HidState,
+    reports: VecDeque<Vec<u8>>,
+    last_report: Vec<u8>,
+    idle: u8,
+    protocol: u8,
+}
+
+impl Hid {
+    /// Create a boot protocol keyboard fed with the input events read from `source`.
+    pub fn new_keyboard(source: File) -> Hid {
+        Hid::new(
+            source,
+            HidState::Keyboard {
+                modifiers: 0,
+                keys: Vec::new(),
+            },
+        )
+    }
+
+    /// Create a tablet fed with the input events read from `source`, whose absolute axes range
+    /// from 0 to `width` and `height`.
+    pub fn new_tablet(source: File, width: u32, height: u32) -> Hid {
+        Hid::new(
+            source,
+            HidState::Tablet {
+                width,
+                height,
+                buttons: 0,
+                x: 0,
+                y: 0,
+                wheel: 0,
+            },
+        )
+    }
+
+    fn new(source: File, state: HidState) -> Hid {
+        let mut hid = Hid {
+            source,
+            partial: Vec::new(),
+            state,
+            reports: VecDeque::new(),
+            last_report: Vec::new(),
+            idle: 0,
+            protocol: PROTOCOL_REPORT,
+        };
+        hid.last_report = hid.report();
+        hid
+    }
+
+    fn report_descriptor(&self) -> &'static [u8] {
+        match self.state {
+            HidState::Keyboard { .. } => KEYBOARD_REPORT_DESCRIPTOR,
+            HidState::Tablet { .. } => TABLET_REPORT_DESCRIPTOR,
+        }
+    }
+
+    fn hid_descriptor(&self) -> Vec<u8> {
+        let len = self.report_descriptor().len() as u16;
+        let mut bytes = vec![
+            9,
+            DESCRIPTOR_TYPE_HID,
+            0x11, // HID 1.11.
+            0x01,
+            0, // Not localized.
+            1, // One report descriptor.
+            DESCRIPTOR_TYPE_REPORT,
+        ];
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes
+    }
+
+    // Returns the input report for the current state.
+    fn report(&self) -> Vec<u8> {
+        match &self.state {
+            HidState::Keyboard { modifiers, keys } => {
+                let mut report = vec![*modifiers, 0, 0, 0, 0, 0, 0, 0];
+                if keys.len() > 6 {
+                    report[2..].copy_from_slice(&[USAGE_ROLLOVER; 6]);
+                } else {
+                    report[2..2 + keys.len()].copy_from_slice(keys);
+                }
+                report
+            }
+            HidState::Tablet {
+                buttons,
+                x,
+                y,
+                wheel,
+                ..
+            } => {
+                let mut report = vec![*buttons];
+                report.extend_from_slice(&x.to_le_bytes());
+                report.extend_from_slice(&y.to_le_bytes());
+                report.push((*wheel).clamp(-127, 127) as i8 as u8);
+                report
+            }
+        }
+    }
+
+    /// Updates the state of the device with `event`, queuing a report at the end of each batch
+    /// of events that changes it.
+    pub fn process_event(&mut self, event: &virtio_input_event) {
+        let type_: u16 = event.type_.into();
+        let code: u16 = event.code.into();
+        let value: i32 = event.value.into();
+        if type_ == EV_SYN {
+            if code == SYN_REPORT {
+                self.queue_report();
+            }
+            return;
+        }
+        match (&mut self.state, type_) {
+            (HidState::Keyboard { modifiers, keys }, EV_KEY) => {
+                // Ignore auto-repeat, the guest repeats held keys by itself.
+                if value == 2 {
+                    return;
+                }
+                if let Some(bit) = MODIFIER_KEYS.iter().position(|&k| k == code) {
+                    if value != 0 {
+                        *modifiers |= 1 << bit;
+                    } else {
+                        *modifiers &= !(1 << bit);
+                    }
+                } else if let Some(usage) = USAGE_KEYS.iter().position(|&k| k != 0 && k == code) {
+                    let usage = usage as u8;
+                    keys.retain(|&k| k != usage);
+                    if value != 0 {
+                        keys.push(usage);
+                    }
+                } else {
+                    usb_debug!("key {} has no keyboard usage", code);
+                }
+            }
+            (HidState::Tablet { buttons, .. }, EV_KEY) => {
+                let bit = match code {
+                    BTN_LEFT => 0,
+                    BTN_RIGHT => 1,
+                    BTN_MIDDLE => 2,
+                    _ => return,
+                };
+                if value != 0 {
+                    *buttons |= 1 << bit;
+                } else {
+                    *buttons &= !(1 << bit);
+                }
+            }
+            (
+                HidState::Tablet {
+                    width,
+                    height,
+                    x,
+                    y,
+                    ..
+                },
+                EV_ABS,
+            ) => {
+                let scale = |v: i32, max: u32| {
+                    let v = v.max(0) as u64;
+                    (v.min(max as u64) * TABLET_AXIS_MAX as u64 / max.max(1) as u64) as u16
+                };
+                match code {
+                    ABS_X => *x = scale(value, *width),
+                    ABS_Y => *y = scale(value, *height),
+                    _ => {}
+                }
+            }
+            (HidState::Tablet { wheel, .. }, EV_REL) if code == REL_WHEEL => {
+                *wheel = wheel.saturating_add(value);
+            }
+            _ => {}
+        }
+    }
+
+    fn queue_report(&mut self) {
+        let report = self.report();
+        let moved_wheel = matches!(self.state, HidState::Tablet { wheel, .. } if wheel != 0);
+        if report == self.last_report && !moved_wheel {
+            return;
+        }
+        if let HidState::Tablet { wheel, .. } = &mut self.state {
+            // What doesn't fit in a report is sent with the next one.
+            *wheel -= (*wheel).clamp(-127, 127);
+        }
+        if self.reports.len() == MAX_QUEUED_REPORTS {
+            usb_debug!("dropping HID report the guest didn't read");
+            self.reports.pop_front();
+        }
+        self.last_report = self.report();
+        self.reports.push_back(report);
+    }
+}
+
+impl UsbFunction for Hid {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        device_descriptor(0, PRODUCT_ID)
+    }
+
+    fn config_descriptor(&self) -> Vec<u8> {
+        let (subclass, protocol) = match self.state {
+            HidState::Keyboard { .. } => (INTERFACE_SUBCLASS_BOOT, INTERFACE_PROTOCOL_KEYBOARD),
+            HidState::Tablet { .. } => (0, 0),
+        };
+        let mut body = descriptor_bytes(&InterfaceDescriptor {
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 1,
+            bInterfaceClass: INTERFACE_CLASS_HID,
+            bInterfaceSubClass: subclass,
+            bInterfaceProtocol: protocol,
+            iInterface: 0,
+        });
+        body.extend(self.hid_descriptor());
+        body.extend(descriptor_bytes(&EndpointDescriptor {
+            bEndpointAddress: ENDPOINT_DIRECTION_IN | INTERRUPT_IN_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_INTERRUPT,
+            wMaxPacketSize: 8,
+            bInterval: INTERRUPT_INTERVAL,
+        }));
+        config_descriptor_bytes(1, &body)
+    }
+
+    fn strings(&self) -> &[&'static str] {
+        match self.state {
+            HidState::Keyboard { .. } => KEYBOARD_STRINGS,
+            HidState::Tablet { .. } => TABLET_STRINGS,
+        }
+    }
+
+    fn class_descriptor(&self, setup: &UsbRequestSetup) -> Option<Vec<u8>> {
+        match (setup.value >> 8) as u8 {
+            DESCRIPTOR_TYPE_HID => Some(self.hid_descriptor()),
+            DESCRIPTOR_TYPE_REPORT => Some(self.report_descriptor().to_vec()),
+            _ => None,
+        }
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, _data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_type() != ControlRequestType::Class {
+            return None;
+        }
+        match setup.request {
+            REQUEST_GET_REPORT => Some(self.report()),
+            REQUEST_GET_IDLE => Some(vec![self.idle]),
+            REQUEST_GET_PROTOCOL => Some(vec![self.protocol]),
+            // Keyboard LEDs aren't shown anywhere.
+            REQUEST_SET_REPORT => Some(Vec::new()),
+            REQUEST_SET_IDLE => {
+                self.idle = (setup.value >> 8) as u8;
+                Some(Vec::new())
+            }
+            REQUEST_SET_PROTOCOL => {
+                // Boot and report protocols share the same reports.
+                self.protocol = setup.value as u8;
+                Some(Vec::new())
+            }
+            _ => None,
+        }
+    }
+
+    fn write_endpoint(&mut self, endpoint: u8, _data: &[u8]) {
+        usb_debug!("HID: data written to unknown endpoint {}", endpoint);
+    }
+
+    fn read_endpoint(&mut self, endpoint: u8, _max_len: usize) -> Option<Vec<u8>> {
+        if endpoint != INTERRUPT_IN_ENDPOINT {
+            return None;
+        }
+        self.reports.pop_front()
+    }
+
+    fn reset(&mut self) {
+        self.reports.clear();
+        self.idle = 0;
+        self.protocol = PROTOCOL_REPORT;
+    }
+
+    fn event_source(&self) -> Option<RawDescriptor> {
+        Some(self.source.as_raw_descriptor())
+    }
+
+    fn read_event_source(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let len = match self.source.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        self.partial.extend_from_slice(&buf[..len]);
+        let whole_events = self.partial.len() - self.partial.len() % virtio_input_event::SIZE;
+        let data: Vec<u8> = self.partial.drain(..whole_events).collect();
+        for event_data in data.chunks(virtio_input_event::SIZE) {
+            self.process_event(&virtio_input_event::decode(event_data));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_model::DataInit;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::tempfile;
+
+    fn event(type_: u16, code: u16, value: i32) -> virtio_input_event {
+        virtio_input_event {
+            type_: type_.into(),
+            code: code.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn keyboard_reports() {
+        let mut hid = Hid::new_keyboard(tempfile().unwrap());
+        // KEY_LEFTSHIFT then KEY_A.
+        hid.process_event(&event(EV_KEY, 42, 1));
+        hid.process_event(&event(EV_KEY, 30, 1));
+        hid.process_event(&virtio_input_event::syn());
+        hid.process_event(&event(EV_KEY, 30, 2));
+        hid.process_event(&virtio_input_event::syn());
+        hid.process_event(&event(EV_KEY, 30, 0));
+        hid.process_event(&event(EV_KEY, 42, 0));
+        hid.process_event(&virtio_input_event::syn());
+
+        assert_eq!(
+            hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8),
+            Some(vec![0x02, 0, 0x04, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8),
+            Some(vec![0; 8])
+        );
+        // Auto-repeat doesn't change the state, so it doesn't make a report.
+        assert_eq!(hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8), None);
+    }
+
+    #[test]
+    fn keyboard_rollover() {
+        let mut hid = Hid::new_keyboard(tempfile().unwrap());
+        // KEY_1 to KEY_7.
+        for code in 2..9 {
+            hid.process_event(&event(EV_KEY, code, 1));
+        }
+        hid.process_event(&virtio_input_event::syn());
+        assert_eq!(
+            hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8),
+            Some(vec![0, 0, 1, 1, 1, 1, 1, 1])
+        );
+    }
+
+    #[test]
+    fn tablet_reports() {
+        let mut hid = Hid::new_tablet(tempfile().unwrap(), 1000, 500);
+        hid.process_event(&event(EV_ABS, ABS_X, 500));
+        hid.process_event(&event(EV_ABS, ABS_Y, 600));
+        hid.process_event(&event(EV_KEY, BTN_LEFT, 1));
+        hid.process_event(&virtio_input_event::syn());
+        hid.process_event(&event(EV_REL, REL_WHEEL, -1));
+        hid.process_event(&virtio_input_event::syn());
+
+        assert_eq!(
+            hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8),
+            Some(vec![0x01, 0xff, 0x3f, 0xff, 0x7f, 0])
+        );
+        assert_eq!(
+            hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8),
+            Some(vec![0x01, 0xff, 0x3f, 0xff, 0x7f, 0xff])
+        );
+        assert_eq!(hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8), None);
+    }
+
+    #[test]
+    fn events_from_source() {
+        let mut source = tempfile().unwrap();
+        let events = [event(EV_KEY, 30, 1), virtio_input_event::syn()];
+        for e in events.iter() {
+            source.write_all(e.as_slice()).unwrap();
+        }
+        source.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut hid = Hid::new_keyboard(source);
+        assert!(hid.read_event_source().unwrap());
+        assert_eq!(
+            hid.read_endpoint(INTERRUPT_IN_ENDPOINT, 8),
+            Some(vec![0, 0, 0x04, 0, 0, 0, 0, 0])
+        );
+        // The end of the file closes the source.
+        assert!(!hid.read_event_source().unwrap());
+    }
+}
@@ -0,0 +1,649 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A USB mass-storage device using the Bulk-Only Transport and the SCSI transparent command set,
+//! backed by a disk image.
+
+use std::cmp::min;
+use std::convert::TryInto;
+
+use super::{
+    config_descriptor_bytes, descriptor_bytes, device_descriptor, UsbFunction,
+    BULK_MAX_PACKET_SIZE, ENDPOINT_ATTRIBUTES_BULK, ENDPOINT_DIRECTION_IN,
+};
+use base::{error, warn};
+use data_model::VolatileSlice;
+use disk::DiskFile;
+use usb_util::{
+    ControlRequestType, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor, UsbRequestSetup,
+};
+
+const PRODUCT_ID: u16 = 0xa4a5;
+const STRINGS: &[&str] = &["crosvm", "crosvm USB mass storage", "000000000001"];
+
+const INTERFACE_CLASS_MASS_STORAGE: u8 = 0x08;
+const INTERFACE_SUBCLASS_SCSI: u8 = 0x06;
+const INTERFACE_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const BULK_IN_ENDPOINT: u8 = 1;
+const BULK_OUT_ENDPOINT: u8 = 2;
+
+// Class requests of the Bulk-Only Transport.
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+const REQUEST_BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+const CSW_STATUS_PHASE_ERROR: u8 = 2;
+
+/// Size of the logical blocks of the device.
+const SECTOR_SIZE: u64 = 512;
+
+// SCSI operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+
+/// SCSI sense key and additional sense code of a failed command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+}
+
+const SENSE_NONE: Sense = Sense {
+    key: 0x00,
+    asc: 0x00,
+};
+const SENSE_INVALID_OPCODE: Sense = Sense {
+    key: 0x05,
+    asc: 0x20,
+};
+const SENSE_LBA_OUT_OF_RANGE: Sense = Sense {
+    key: 0x05,
+    asc: 0x21,
+};
+const SENSE_INVALID_FIELD_IN_CDB: Sense = Sense {
+    key: 0x05,
+    asc: 0x24,
+};
+const SENSE_WRITE_PROTECTED: Sense = Sense {
+    key: 0x07,
+    asc: 0x27,
+};
+const SENSE_READ_ERROR: Sense = Sense {
+    key: 0x03,
+    asc: 0x11,
+};
+const SENSE_WRITE_ERROR: Sense = Sense {
+    key: 0x03,
+    asc: 0x0c,
+};
+
+/// The command block wrapper, which starts each command of the Bulk-Only Transport.
+#[derive(Copy, Clone, Debug)]
+struct CommandBlockWrapper {
+    tag: u32,
+    data_transfer_length: u32,
+    data_in: bool,
+    cb: [u8; 16],
+}
+
+impl CommandBlockWrapper {
+    fn parse(data: &[u8]) -> Option<CommandBlockWrapper> {
+        if data.len() != CBW_LEN
+            || u32::from_le_bytes(data[0..4].try_into().unwrap()) != CBW_SIGNATURE
+        {
+            return None;
+        }
+        let cb_len = data[14] as usize;
+        if cb_len == 0 || cb_len > 16 {
+            return None;
+        }
+        let mut cb = [0u8; 16];
+        cb[..cb_len].copy_from_slice(&data[15..15 + cb_len]);
+        Some(CommandBlockWrapper {
+            tag: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            data_transfer_length: u32::from_le_bytes(data[8..12].try_into().unwrap()),
+            data_in: data[12] & CBW_FLAG_DATA_IN != 0,
+            cb,
+        })
+    }
+}
+
+// Where the device is in the command/data/status sequence of the Bulk-Only Transport.
+enum TransportState {
+    // Waiting for a command block wrapper on the bulk OUT endpoint.
+    Command,
+    // Sending the data of an IN command, starting at `offset`.
+    DataIn {
+        cbw: CommandBlockWrapper,
+        data: Vec<u8>,
+        offset: usize,
+        status: u8,
+    },
+    // Receiving the data of a WRITE command. `lba` is the next block to write, or None if the
+    // data is discarded because the command failed. Data past the `sectors_left` blocks the
+    // command asked for is discarded too.
+    DataOut {
+        cbw: CommandBlockWrapper,
+        lba: Option<u64>,
+        sectors_left: u64,
+        partial: Vec<u8>,
+        received: u32,
+        status: u8,
+    },
+    // Sending the command status wrapper.
+    Status {
+        cbw: CommandBlockWrapper,
+        residue: u32,
+        status: u8,
+    },
+}
+
+/// Result of a SCSI command that doesn't receive data from the host.
+type CommandResult = std::result::Result<Vec<u8>, Sense>;
+
+/// A SCSI disk on a bulk-only USB mass-storage interface.
+pub struct MassStorage {
+    disk: Box<dyn DiskFile>,
+    read_only: bool,
+    state: TransportState,
+    sense: Sense,
+}
+
+impl MassStorage {
+    /// Create a mass-storage device for `disk`.
+    pub fn new(disk: Box<dyn DiskFile>, read_only: bool) -> MassStorage {
+        MassStorage {
+            disk,
+            read_only,
+            state: TransportState::Command,
+            sense: SENSE_NONE,
+        }
+    }
+
+    fn num_sectors(&self) -> u64 {
+        match self.disk.get_len() {
+            Ok(len) => len / SECTOR_SIZE,
+            Err(e) => {
+                error!("failed to get disk length: {}", e);
+                0
+            }
+        }
+    }
+
+    // Returns the first block and the block count of a READ(10), WRITE(10) or VERIFY(10) command
+    // if the blocks are all on the disk.
+    fn block_range(&self, cb: &[u8; 16]) -> std::result::Result<(u64, u64), Sense> {
+        let lba = u32::from_be_bytes(cb[2..6].try_into().unwrap()) as u64;
+        let count = u16::from_be_bytes(cb[7..9].try_into().unwrap()) as u64;
+        if lba + count > self.num_sectors() {
+            return Err(SENSE_LBA_OUT_OF_RANGE);
+        }
+        Ok((lba, count))
+    }
+
+    fn inquiry(&self, cb: &[u8; 16]) -> CommandResult {
+        // Vital product data pages aren't supported.
+        if cb[1] & 0x01 != 0 {
+            return Err(SENSE_INVALID_FIELD_IN_CDB);
+        }
+        let mut data = vec![
+            0x00, // Direct access block device.
+            0x80, // Removable medium.
+            0x04, // SPC-2.
+            0x02, // Response data format.
+            31,   // Additional length.
+            0x00, 0x00, 0x00,
+        ];
+        data.extend_from_slice(b"crosvm  ");
+        data.extend_from_slice(b"USB disk        ");
+        data.extend_from_slice(b"1.0 ");
+        Ok(data)
+    }
+
+    fn request_sense(&mut self) -> CommandResult {
+        let sense = self.sense;
+        self.sense = SENSE_NONE;
+        let mut data = vec![0u8; 18];
+        // Current error in fixed format.
+        data[0] = 0x70;
+        data[2] = sense.key;
+        data[7] = 10;
+        data[12] = sense.asc;
+        Ok(data)
+    }
+
+    fn mode_sense(&self) -> CommandResult {
+        let device_specific = if self.read_only { 0x80 } else { 0x00 };
+        Ok(vec![3, 0x00, device_specific, 0])
+    }
+
+    fn read_capacity(&self) -> CommandResult {
+        let last_lba = self.num_sectors().saturating_sub(1);
+        let last_lba = min(last_lba, u32::MAX as u64) as u32;
+        let mut data = last_lba.to_be_bytes().to_vec();
+        data.extend_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        Ok(data)
+    }
+
+    fn read(&mut self, cb: &[u8; 16]) -> CommandResult {
+        let (lba, count) = self.block_range(cb)?;
+        let mut data = vec![0u8; (count * SECTOR_SIZE) as usize];
+        self.disk
+            .read_exact_at_volatile(VolatileSlice::new(&mut data), lba * SECTOR_SIZE)
+            .map_err(|e| {
+                error!("failed to read from disk: {}", e);
+                SENSE_READ_ERROR
+            })?;
+        Ok(data)
+    }
+
+    fn synchronize_cache(&mut self) -> CommandResult {
+        self.disk.fsync().map_err(|e| {
+            error!("failed to flush disk: {}", e);
+            SENSE_WRITE_ERROR
+        })?;
+        Ok(Vec::new())
+    }
+
+    // Executes a command that doesn't receive data from the host.
+    fn execute(&mut self, cb: &[u8; 16]) -> CommandResult {
+        match cb[0] {
+            TEST_UNIT_READY | START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL => Ok(Vec::new()),
+            REQUEST_SENSE => self.request_sense(),
+            INQUIRY => self.inquiry(cb),
+            MODE_SENSE_6 => self.mode_sense(),
+            READ_CAPACITY_10 => self.read_capacity(),
+            READ_10 => self.read(cb),
+            VERIFY_10 => self.block_range(cb).map(|_| Vec::new()),
+            SYNCHRONIZE_CACHE_10 => self.synchronize_cache(),
+            opcode => {
+                usb_debug!("unsupported SCSI command {:#x}", opcode);
+                Err(SENSE_INVALID_OPCODE)
+            }
+        }
+    }
+
+    fn handle_command(&mut self, cbw: CommandBlockWrapper) {
+        if cbw.cb[0] == WRITE_10 {
+            let (lba, sectors_left, status) = if self.read_only {
+                self.sense = SENSE_WRITE_PROTECTED;
+                (None, 0, CSW_STATUS_FAILED)
+            } else {
+                match self.block_range(&cbw.cb) {
+                    // The host sends more data than the command writes (case 13 of the
+                    // Bulk-Only Transport): only the blocks of the command are written.
+                    Ok((lba, count)) if cbw.data_transfer_length as u64 > count * SECTOR_SIZE => {
+                        (Some(lba), count, CSW_STATUS_PHASE_ERROR)
+                    }
+                    Ok((lba, count)) => (Some(lba), count, CSW_STATUS_PASSED),
+                    Err(sense) => {
+                        self.sense = sense;
+                        (None, 0, CSW_STATUS_FAILED)
+                    }
+                }
+            };
+            self.state = if cbw.data_transfer_length == 0 {
+                TransportState::Status {
+                    cbw,
+                    residue: 0,
+                    status,
+                }
+            } else {
+                TransportState::DataOut {
+                    cbw,
+                    lba,
+                    sectors_left,
+                    partial: Vec::new(),
+                    received: 0,
+                    status,
+                }
+            };
+            return;
+        }
+
+        let (data, status) = match self.execute(&cbw.cb) {
+            Ok(data) => (data, CSW_STATUS_PASSED),
+            Err(sense) => {
+                self.sense = sense;
+                (Vec::new(), CSW_STATUS_FAILED)
+            }
+        };
+        self.state = if cbw.data_in && cbw.data_transfer_length > 0 {
+            let mut data = data;
+            data.truncate(cbw.data_transfer_length as usize);
+            TransportState::DataIn {
+                cbw,
+                data,
+                offset: 0,
+                status,
+            }
+        } else {
+            TransportState::Status {
+                cbw,
+                residue: cbw.data_transfer_length,
+                status,
+            }
+        };
+    }
+
+    fn receive_data(&mut self, data: &[u8]) {
+        let (cbw, received, status) = match &mut self.state {
+            TransportState::DataOut {
+                cbw,
+                lba,
+                sectors_left,
+                partial,
+                received,
+                status,
+            } => {
+                *received = received.saturating_add(data.len() as u32);
+                if let Some(next_lba) = lba {
+                    let room = (*sectors_left * SECTOR_SIZE) as usize - partial.len();
+                    partial.extend_from_slice(&data[..min(room, data.len())]);
+                    let whole_sectors = partial.len() - partial.len() % SECTOR_SIZE as usize;
+                    let mut sectors: Vec<u8> = partial.drain(..whole_sectors).collect();
+                    if !sectors.is_empty() {
+                        let offset = *next_lba * SECTOR_SIZE;
+                        let count = (sectors.len() as u64) / SECTOR_SIZE;
+                        *next_lba += count;
+                        *sectors_left -= count;
+                        if let Err(e) = self
+                            .disk
+                            .write_all_at_volatile(VolatileSlice::new(&mut sectors), offset)
+                        {
+                            error!("failed to write to disk: {}", e);
+                            self.sense = SENSE_WRITE_ERROR;
+                            *status = CSW_STATUS_FAILED;
+                            *lba = None;
+                        }
+                    }
+                    if *sectors_left == 0 {
+                        *lba = None;
+                    }
+                }
+                (*cbw, *received, *status)
+            }
+            _ => return,
+        };
+        if received >= cbw.data_transfer_length {
+            self.state = TransportState::Status {
+                cbw,
+                residue: 0,
+                status,
+            };
+        }
+    }
+
+    fn send_data(&mut self, max_len: usize) -> Option<Vec<u8>> {
+        match &mut self.state {
+            TransportState::DataIn {
+                cbw,
+                data,
+                offset,
+                status,
+            } => {
+                let len = min(max_len, data.len() - *offset);
+                let chunk = data[*offset..*offset + len].to_vec();
+                *offset += len;
+                // A short or empty packet ends the data stage early.
+                if *offset == data.len() {
+                    let residue = cbw.data_transfer_length - data.len() as u32;
+                    self.state = TransportState::Status {
+                        cbw: *cbw,
+                        residue,
+                        status: *status,
+                    };
+                }
+                Some(chunk)
+            }
+            TransportState::Status {
+                cbw,
+                residue,
+                status,
+            } => {
+                let mut csw = CSW_SIGNATURE.to_le_bytes().to_vec();
+                csw.extend_from_slice(&cbw.tag.to_le_bytes());
+                csw.extend_from_slice(&residue.to_le_bytes());
+                csw.push(*status);
+                self.state = TransportState::Command;
+                Some(csw)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl UsbFunction for MassStorage {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        device_descriptor(0, PRODUCT_ID)
+    }
+
+    fn config_descriptor(&self) -> Vec<u8> {
+        let mut body = descriptor_bytes(&InterfaceDescriptor {
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 2,
+            bInterfaceClass: INTERFACE_CLASS_MASS_STORAGE,
+            bInterfaceSubClass: INTERFACE_SUBCLASS_SCSI,
+            bInterfaceProtocol: INTERFACE_PROTOCOL_BULK_ONLY,
+            iInterface: 0,
+        });
+        body.extend(descriptor_bytes(&EndpointDescriptor {
+            bEndpointAddress: ENDPOINT_DIRECTION_IN | BULK_IN_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_BULK,
+            wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+            bInterval: 0,
+        }));
+        body.extend(descriptor_bytes(&EndpointDescriptor {
+            bEndpointAddress: BULK_OUT_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_BULK,
+            wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+            bInterval: 0,
+        }));
+        config_descriptor_bytes(1, &body)
+    }
+
+    fn strings(&self) -> &[&'static str] {
+        STRINGS
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, _data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_type() != ControlRequestType::Class {
+            return None;
+        }
+        match setup.request {
+            REQUEST_GET_MAX_LUN => Some(vec![0]),
+            REQUEST_BULK_ONLY_RESET => {
+                self.state = TransportState::Command;
+                Some(Vec::new())
+            }
+            _ => None,
+        }
+    }
+
+    fn write_endpoint(&mut self, endpoint: u8, data: &[u8]) {
+        if endpoint != BULK_OUT_ENDPOINT {
+            warn!(
+                "mass storage: data written to unknown endpoint {}",
+                endpoint
+            );
+            return;
+        }
+        match self.state {
+            TransportState::Command => match CommandBlockWrapper::parse(data) {
+                Some(cbw) => self.handle_command(cbw),
+                None => warn!("mass storage: invalid command block wrapper"),
+            },
+            TransportState::DataOut { .. } => self.receive_data(data),
+            _ => warn!("mass storage: unexpected data in the middle of a command"),
+        }
+    }
+
+    fn read_endpoint(&mut self, endpoint: u8, max_len: usize) -> Option<Vec<u8>> {
+        if endpoint != BULK_IN_ENDPOINT {
+            return None;
+        }
+        self.send_data(max_len)
+    }
+
+    fn reset(&mut self) {
+        self.state = TransportState::Command;
+        self.sense = SENSE_NONE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempfile::tempfile;
+
+    const TAG: u32 = 0x1234_5678;
+
+    fn command(data_transfer_length: u32, data_in: bool, cb: &[u8]) -> Vec<u8> {
+        let mut cbw = CBW_SIGNATURE.to_le_bytes().to_vec();
+        cbw.extend_from_slice(&TAG.to_le_bytes());
+        cbw.extend_from_slice(&data_transfer_length.to_le_bytes());
+        cbw.push(if data_in { CBW_FLAG_DATA_IN } else { 0 });
+        cbw.push(0);
+        cbw.push(cb.len() as u8);
+        cbw.extend_from_slice(cb);
+        cbw.resize(CBW_LEN, 0);
+        cbw
+    }
+
+    fn new_device(sectors: u64, read_only: bool) -> MassStorage {
+        let f: File = tempfile().unwrap();
+        f.set_len(sectors * SECTOR_SIZE).unwrap();
+        MassStorage::new(Box::new(f), read_only)
+    }
+
+    // Reads the command status wrapper and returns its residue and status.
+    fn read_status(device: &mut MassStorage) -> (u32, u8) {
+        let csw = device.read_endpoint(BULK_IN_ENDPOINT, 512).unwrap();
+        assert_eq!(csw.len(), 13);
+        assert_eq!(
+            u32::from_le_bytes(csw[0..4].try_into().unwrap()),
+            CSW_SIGNATURE
+        );
+        assert_eq!(u32::from_le_bytes(csw[4..8].try_into().unwrap()), TAG);
+        (u32::from_le_bytes(csw[8..12].try_into().unwrap()), csw[12])
+    }
+
+    #[test]
+    fn inquiry_and_capacity() {
+        let mut device = new_device(8, false);
+        device.write_endpoint(
+            BULK_OUT_ENDPOINT,
+            &command(36, true, &[INQUIRY, 0, 0, 0, 36, 0]),
+        );
+        let data = device.read_endpoint(BULK_IN_ENDPOINT, 512).unwrap();
+        assert_eq!(data.len(), 36);
+        assert_eq!(&data[8..14], b"crosvm");
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_PASSED));
+
+        device.write_endpoint(
+            BULK_OUT_ENDPOINT,
+            &command(8, true, &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        );
+        let data = device.read_endpoint(BULK_IN_ENDPOINT, 512).unwrap();
+        assert_eq!(data, vec![0, 0, 0, 7, 0, 0, 2, 0]);
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_PASSED));
+
+        // Nothing is sent until the next command.
+        assert_eq!(device.read_endpoint(BULK_IN_ENDPOINT, 512), None);
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut device = new_device(8, false);
+        let write_cmd = [WRITE_10, 0, 0, 0, 0, 2, 0, 0, 2, 0];
+        device.write_endpoint(BULK_OUT_ENDPOINT, &command(1024, false, &write_cmd));
+        let sectors: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        // Data may come in pieces that don't match sectors.
+        device.write_endpoint(BULK_OUT_ENDPOINT, &sectors[..100]);
+        device.write_endpoint(BULK_OUT_ENDPOINT, &sectors[100..]);
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_PASSED));
+
+        let read_cmd = [READ_10, 0, 0, 0, 0, 2, 0, 0, 2, 0];
+        device.write_endpoint(BULK_OUT_ENDPOINT, &command(1024, true, &read_cmd));
+        let mut data = device.read_endpoint(BULK_IN_ENDPOINT, 512).unwrap();
+        data.extend(device.read_endpoint(BULK_IN_ENDPOINT, 512).unwrap());
+        assert_eq!(data, sectors);
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let mut device = new_device(8, false);
+        let read_cmd = [READ_10, 0, 0, 0, 0, 7, 0, 0, 2, 0];
+        device.write_endpoint(BULK_OUT_ENDPOINT, &command(1024, true, &read_cmd));
+        // The data stage is cut short and the whole length is left over.
+        assert_eq!(
+            device.read_endpoint(BULK_IN_ENDPOINT, 1024),
+            Some(Vec::new())
+        );
+        assert_eq!(read_status(&mut device), (1024, CSW_STATUS_FAILED));
+
+        device.write_endpoint(
+            BULK_OUT_ENDPOINT,
+            &command(18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0]),
+        );
+        let sense = device.read_endpoint(BULK_IN_ENDPOINT, 512).unwrap();
+        assert_eq!((sense[2], sense[12]), (0x05, 0x21));
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn write_past_command() {
+        let mut device = new_device(8, false);
+        // Writes the last block but announces four blocks of data.
+        let write_cmd = [WRITE_10, 0, 0, 0, 0, 7, 0, 0, 1, 0];
+        device.write_endpoint(BULK_OUT_ENDPOINT, &command(2048, false, &write_cmd));
+        device.write_endpoint(BULK_OUT_ENDPOINT, &[0xaau8; 1024]);
+        device.write_endpoint(BULK_OUT_ENDPOINT, &[0xbbu8; 1024]);
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_PHASE_ERROR));
+
+        assert_eq!(device.disk.get_len().unwrap(), 8 * SECTOR_SIZE);
+        let mut sector = [0u8; 512];
+        device
+            .disk
+            .read_exact_at_volatile(VolatileSlice::new(&mut sector), 7 * SECTOR_SIZE)
+            .unwrap();
+        assert!(sector.iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn write_protected() {
+        let mut device = new_device(8, true);
+        let write_cmd = [WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        device.write_endpoint(BULK_OUT_ENDPOINT, &command(512, false, &write_cmd));
+        device.write_endpoint(BULK_OUT_ENDPOINT, &[0xffu8; 512]);
+        assert_eq!(read_status(&mut device), (0, CSW_STATUS_FAILED));
+        assert_eq!(device.sense, SENSE_WRITE_PROTECTED);
+
+        let mut sector = [0u8; 512];
+        device
+            .disk
+            .read_exact_at_volatile(VolatileSlice::new(&mut sector), 0)
+            .unwrap();
+        assert!(sector.iter().all(|&b| b == 0));
+    }
+}
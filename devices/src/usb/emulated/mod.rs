@@ -0,0 +1,92 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB devices emulated entirely in crosvm, so that they can be attached to the xHCI controller
+//! without any host USB hardware.
+
+mod emulated_device;
+pub mod hid;
+pub mod mass_storage;
+pub mod serial;
+
+pub use self::emulated_device::{EmulatedDevice, UsbFunction};
+
+use std::mem::size_of;
+
+use data_model::DataInit;
+use usb_util::{ConfigDescriptor, Descriptor, DescriptorHeader, DeviceDescriptor};
+
+/// Vendor id of the Linux USB gadget drivers, which the emulated devices borrow.
+const GADGET_VENDOR_ID: u16 = 0x0525;
+
+/// bDescriptorType of string descriptors.
+const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+
+/// bmAttributes of bulk endpoints.
+const ENDPOINT_ATTRIBUTES_BULK: u8 = 0x02;
+/// bmAttributes of interrupt endpoints.
+const ENDPOINT_ATTRIBUTES_INTERRUPT: u8 = 0x03;
+/// Direction bit of IN endpoint addresses.
+const ENDPOINT_DIRECTION_IN: u8 = 0x80;
+
+/// wMaxPacketSize of high-speed bulk endpoints.
+const BULK_MAX_PACKET_SIZE: u16 = 512;
+
+/// Builds the device descriptor shared by all emulated devices, which have a single configuration
+/// and strings for the manufacturer, product and serial number.
+fn device_descriptor(device_class: u8, product_id: u16) -> DeviceDescriptor {
+    DeviceDescriptor {
+        bcdUSB: 0x0200,
+        bDeviceClass: device_class,
+        bDeviceSubClass: 0,
+        bDeviceProtocol: 0,
+        bMaxPacketSize0: 64,
+        idVendor: GADGET_VENDOR_ID,
+        idProduct: product_id,
+        bcdDevice: 0x0100,
+        iManufacturer: 1,
+        iProduct: 2,
+        iSerialNumber: 3,
+        bNumConfigurations: 1,
+    }
+}
+
+/// Serializes `descriptor` along with its standard header.
+fn descriptor_bytes<T: Descriptor + DataInit>(descriptor: &T) -> Vec<u8> {
+    let header = DescriptorHeader {
+        bLength: (size_of::<DescriptorHeader>() + size_of::<T>()) as u8,
+        bDescriptorType: T::descriptor_type() as u8,
+    };
+    let mut bytes = header.as_slice().to_vec();
+    bytes.extend_from_slice(descriptor.as_slice());
+    bytes
+}
+
+/// Builds the descriptor of configuration 1, followed by `body`, which holds the interface,
+/// class-specific and endpoint descriptors of the configuration.
+fn config_descriptor_bytes(num_interfaces: u8, body: &[u8]) -> Vec<u8> {
+    let config = ConfigDescriptor {
+        wTotalLength: (size_of::<DescriptorHeader>() + size_of::<ConfigDescriptor>() + body.len())
+            as u16,
+        bNumInterfaces: num_interfaces,
+        bConfigurationValue: 1,
+        iConfiguration: 0,
+        // Bus powered, drawing up to 100 mA.
+        bmAttributes: 0x80,
+        bMaxPower: 50,
+    };
+    let mut bytes = descriptor_bytes(&config);
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// Encodes `s` as a UTF-16 string descriptor.
+fn string_descriptor_bytes(s: &str) -> Vec<u8> {
+    let mut bytes = vec![0, DESCRIPTOR_TYPE_STRING];
+    for c in s.encode_utf16() {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+    bytes[0] = bytes.len() as u8;
+    bytes
+}
@@ -0,0 +1,306 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A USB CDC-ACM serial port connected to a file, socket or pty.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use super::{
+    config_descriptor_bytes, descriptor_bytes, device_descriptor, UsbFunction,
+    BULK_MAX_PACKET_SIZE, ENDPOINT_ATTRIBUTES_BULK, ENDPOINT_ATTRIBUTES_INTERRUPT,
+    ENDPOINT_DIRECTION_IN,
+};
+use base::{error, warn, AsRawDescriptor, RawDescriptor};
+use usb_util::{
+    ControlRequestType, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor, UsbRequestSetup,
+};
+
+const PRODUCT_ID: u16 = 0xa4a7;
+const STRINGS: &[&str] = &["crosvm", "crosvm USB serial", "000000000001"];
+
+const DEVICE_CLASS_COMMUNICATIONS: u8 = 0x02;
+const INTERFACE_CLASS_COMMUNICATIONS: u8 = 0x02;
+const INTERFACE_SUBCLASS_ACM: u8 = 0x02;
+const INTERFACE_CLASS_DATA: u8 = 0x0a;
+
+const DESCRIPTOR_TYPE_CS_INTERFACE: u8 = 0x24;
+const FUNCTIONAL_HEADER: u8 = 0x00;
+const FUNCTIONAL_CALL_MANAGEMENT: u8 = 0x01;
+const FUNCTIONAL_ACM: u8 = 0x02;
+const FUNCTIONAL_UNION: u8 = 0x06;
+
+const COMMUNICATION_INTERFACE: u8 = 0;
+const DATA_INTERFACE: u8 = 1;
+
+const DATA_IN_ENDPOINT: u8 = 1;
+const DATA_OUT_ENDPOINT: u8 = 1;
+const NOTIFICATION_IN_ENDPOINT: u8 = 2;
+
+// CDC-ACM class requests.
+const REQUEST_SET_LINE_CODING: u8 = 0x20;
+const REQUEST_GET_LINE_CODING: u8 = 0x21;
+const REQUEST_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQUEST_SEND_BREAK: u8 = 0x23;
+
+const LINE_CODING_LEN: usize = 7;
+// 115200 bauds, 1 stop bit, no parity and 8 data bits.
+const DEFAULT_LINE_CODING: [u8; LINE_CODING_LEN] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
+// Data received from the source is dropped beyond this many bytes, like a UART overrun.
+const MAX_BUFFERED_BYTES: usize = 64 * 1024;
+
+/// A CDC-ACM serial port which sends what it reads from a file to the guest and writes what the
+/// guest sends to the same file.
+pub struct Serial {
+    file: File,
+    line_coding: [u8; LINE_CODING_LEN],
+    control_line_state: u16,
+    input: VecDeque<u8>,
+}
+
+impl Serial {
+    /// Create a serial port connected to `file`.
+    pub fn new(file: File) -> Serial {
+        Serial {
+            file,
+            line_coding: DEFAULT_LINE_CODING,
+            control_line_state: 0,
+            input: VecDeque::new(),
+        }
+    }
+
+    fn queue_input(&mut self, data: &[u8]) {
+        let len = min(data.len(), MAX_BUFFERED_BYTES - self.input.len());
+        if len < data.len() {
+            warn!(
+                "serial: dropping {} bytes the guest didn't read",
+                data.len() - len
+            );
+        }
+        self.input.extend(&data[..len]);
+    }
+}
+
+impl UsbFunction for Serial {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        device_descriptor(DEVICE_CLASS_COMMUNICATIONS, PRODUCT_ID)
+    }
+
+    fn config_descriptor(&self) -> Vec<u8> {
+        let mut body = descriptor_bytes(&InterfaceDescriptor {
+            bInterfaceNumber: COMMUNICATION_INTERFACE,
+            bAlternateSetting: 0,
+            bNumEndpoints: 1,
+            bInterfaceClass: INTERFACE_CLASS_COMMUNICATIONS,
+            bInterfaceSubClass: INTERFACE_SUBCLASS_ACM,
+            // AT commands aren't supported.
+            bInterfaceProtocol: 0,
+            iInterface: 0,
+        });
+        // Header, CDC 1.10.
+        body.extend(&[
+            5,
+            DESCRIPTOR_TYPE_CS_INTERFACE,
+            FUNCTIONAL_HEADER,
+            0x10,
+            0x01,
+        ]);
+        // Call management over the data interface.
+        body.extend(&[
+            5,
+            DESCRIPTOR_TYPE_CS_INTERFACE,
+            FUNCTIONAL_CALL_MANAGEMENT,
+            0x00,
+            DATA_INTERFACE,
+        ]);
+        // Supports line coding, control line state and serial state requests.
+        body.extend(&[4, DESCRIPTOR_TYPE_CS_INTERFACE, FUNCTIONAL_ACM, 0x02]);
+        body.extend(&[
+            5,
+            DESCRIPTOR_TYPE_CS_INTERFACE,
+            FUNCTIONAL_UNION,
+            COMMUNICATION_INTERFACE,
+            DATA_INTERFACE,
+        ]);
+        body.extend(descriptor_bytes(&EndpointDescriptor {
+            bEndpointAddress: ENDPOINT_DIRECTION_IN | NOTIFICATION_IN_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_INTERRUPT,
+            wMaxPacketSize: 16,
+            bInterval: 9,
+        }));
+        body.extend(descriptor_bytes(&InterfaceDescriptor {
+            bInterfaceNumber: DATA_INTERFACE,
+            bAlternateSetting: 0,
+            bNumEndpoints: 2,
+            bInterfaceClass: INTERFACE_CLASS_DATA,
+            bInterfaceSubClass: 0,
+            bInterfaceProtocol: 0,
+            iInterface: 0,
+        }));
+        body.extend(descriptor_bytes(&EndpointDescriptor {
+            bEndpointAddress: ENDPOINT_DIRECTION_IN | DATA_IN_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_BULK,
+            wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+            bInterval: 0,
+        }));
+        body.extend(descriptor_bytes(&EndpointDescriptor {
+            bEndpointAddress: DATA_OUT_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_BULK,
+            wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+            bInterval: 0,
+        }));
+        config_descriptor_bytes(2, &body)
+    }
+
+    fn strings(&self) -> &[&'static str] {
+        STRINGS
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_type() != ControlRequestType::Class {
+            return None;
+        }
+        match setup.request {
+            REQUEST_SET_LINE_CODING if data.len() == LINE_CODING_LEN => {
+                // The line coding doesn't matter to the file, but the guest expects it back.
+                self.line_coding.copy_from_slice(data);
+                Some(Vec::new())
+            }
+            REQUEST_GET_LINE_CODING => Some(self.line_coding.to_vec()),
+            REQUEST_SET_CONTROL_LINE_STATE => {
+                self.control_line_state = setup.value;
+                Some(Vec::new())
+            }
+            REQUEST_SEND_BREAK => Some(Vec::new()),
+            _ => None,
+        }
+    }
+
+    fn write_endpoint(&mut self, endpoint: u8, data: &[u8]) {
+        if endpoint != DATA_OUT_ENDPOINT {
+            warn!("serial: data written to unknown endpoint {}", endpoint);
+            return;
+        }
+        if let Err(e) = self.file.write_all(data) {
+            error!("serial: failed to write to file: {}", e);
+        }
+    }
+
+    fn read_endpoint(&mut self, endpoint: u8, max_len: usize) -> Option<Vec<u8>> {
+        // Serial state notifications are never sent.
+        if endpoint != DATA_IN_ENDPOINT || self.input.is_empty() {
+            return None;
+        }
+        let len = min(max_len, self.input.len());
+        Some(self.input.drain(..len).collect())
+    }
+
+    fn reset(&mut self) {
+        self.line_coding = DEFAULT_LINE_CODING;
+        self.control_line_state = 0;
+    }
+
+    fn event_source(&self) -> Option<RawDescriptor> {
+        Some(self.file.as_raw_descriptor())
+    }
+
+    fn read_event_source(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        match self.file.read(&mut buf) {
+            Ok(0) => Ok(false),
+            Ok(len) => {
+                self.queue_input(&buf[..len]);
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+    use tempfile::tempfile;
+    use usb_util::{
+        control_request_type, ControlRequestDataPhaseTransferDirection, ControlRequestRecipient,
+    };
+
+    fn class_request(
+        dir: ControlRequestDataPhaseTransferDirection,
+        request: u8,
+        length: u16,
+    ) -> UsbRequestSetup {
+        UsbRequestSetup::new(
+            control_request_type(
+                ControlRequestType::Class,
+                dir,
+                ControlRequestRecipient::Interface,
+            ),
+            request,
+            0,
+            COMMUNICATION_INTERFACE as u16,
+            length,
+        )
+    }
+
+    #[test]
+    fn line_coding() {
+        let mut serial = Serial::new(tempfile().unwrap());
+        let get = class_request(
+            ControlRequestDataPhaseTransferDirection::DeviceToHost,
+            REQUEST_GET_LINE_CODING,
+            7,
+        );
+        assert_eq!(
+            serial.control_request(&get, &[]),
+            Some(DEFAULT_LINE_CODING.to_vec())
+        );
+
+        // 9600 bauds, 2 stop bits, even parity and 7 data bits.
+        let line_coding = [0x80, 0x25, 0x00, 0x00, 2, 2, 7];
+        let set = class_request(
+            ControlRequestDataPhaseTransferDirection::HostToDevice,
+            REQUEST_SET_LINE_CODING,
+            7,
+        );
+        assert_eq!(serial.control_request(&set, &line_coding), Some(Vec::new()));
+        assert_eq!(
+            serial.control_request(&get, &[]),
+            Some(line_coding.to_vec())
+        );
+        assert_eq!(serial.control_request(&set, &line_coding[..3]), None);
+    }
+
+    #[test]
+    fn data_both_ways() {
+        let mut file = tempfile().unwrap();
+        file.write_all(b"from host").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut serial = Serial::new(file.try_clone().unwrap());
+
+        assert_eq!(serial.read_endpoint(DATA_IN_ENDPOINT, 512), None);
+        assert!(serial.read_event_source().unwrap());
+        assert_eq!(
+            serial.read_endpoint(DATA_IN_ENDPOINT, 4),
+            Some(b"from".to_vec())
+        );
+        assert_eq!(
+            serial.read_endpoint(DATA_IN_ENDPOINT, 512),
+            Some(b" host".to_vec())
+        );
+        assert_eq!(serial.read_endpoint(NOTIFICATION_IN_ENDPOINT, 512), None);
+
+        serial.write_endpoint(DATA_OUT_ENDPOINT, b" and guest");
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "from host and guest");
+    }
+}
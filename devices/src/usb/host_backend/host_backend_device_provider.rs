@@ -7,6 +7,11 @@ use std::sync::Arc;
 
 use super::error::*;
 use super::host_device::HostDevice;
+use crate::usb::emulated::hid::Hid;
+use crate::usb::emulated::mass_storage::MassStorage;
+use crate::usb::emulated::serial::Serial;
+use crate::usb::emulated::{EmulatedDevice, UsbFunction};
+use crate::usb::usbip::UsbipDevice;
 use crate::usb::xhci::usb_hub::UsbHub;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::utils::AsyncJobQueue;
@@ -18,7 +23,8 @@ use std::time::Duration;
 use sync::Mutex;
 use usb_util::Device;
 use vm_control::{
-    UsbControlAttachedDevice, UsbControlCommand, UsbControlResult, USB_CONTROL_MAX_PORTS,
+    UsbControlAttachedDevice, UsbControlCommand, UsbControlResult, UsbEmulatedDeviceKind,
+    USB_CONTROL_MAX_PORTS,
 };
 
 const SOCKET_TIMEOUT_MS: u64 = 2000;
@@ -124,12 +130,24 @@ pub struct ProviderInner {
     usb_hub: Arc<UsbHub>,
 
     // Map of USB hub port number to per-device context.
-    devices: Mutex<HashMap<u8, HostDeviceContext>>,
+    devices: Mutex<HashMap<u8, DeviceContext>>,
 }
 
-struct HostDeviceContext {
-    event_handler: Arc<dyn EventHandler>,
-    device: Arc<Mutex<Device>>,
+enum DeviceContext {
+    Host {
+        event_handler: Arc<dyn EventHandler>,
+        device: Arc<Mutex<Device>>,
+    },
+    // Only emulated devices with an event source need a context. The event loop only holds a
+    // weak reference to the handler, which is kept alive here.
+    Emulated {
+        _event_handler: Arc<dyn EventHandler>,
+        source: Descriptor,
+    },
+    Usbip {
+        _event_handler: Arc<dyn EventHandler>,
+        source: Descriptor,
+    },
 }
 
 impl ProviderInner {
@@ -178,7 +196,7 @@ impl ProviderInner {
             return UsbControlResult::FailedToOpenDevice;
         }
 
-        let device_ctx = HostDeviceContext {
+        let device_ctx = DeviceContext::Host {
             event_handler,
             device: arc_mutex_device.clone(),
         };
@@ -214,20 +232,147 @@ impl ProviderInner {
         }
     }
 
+    /// Create an emulated device of type `kind` backed by `file`.
+    fn handle_attach_emulated_device(
+        &self,
+        kind: UsbEmulatedDeviceKind,
+        file: File,
+    ) -> UsbControlResult {
+        match kind {
+            UsbEmulatedDeviceKind::MassStorage { read_only } => {
+                let disk = match disk::create_disk_file(file, disk::MAX_NESTING_DEPTH) {
+                    Ok(disk) => disk,
+                    Err(e) => {
+                        error!("failed to open disk for USB mass storage: {}", e);
+                        return UsbControlResult::FailedToInitEmulatedDevice;
+                    }
+                };
+                self.connect_emulated_device(MassStorage::new(disk, read_only))
+            }
+            UsbEmulatedDeviceKind::Keyboard => {
+                self.connect_emulated_device(Hid::new_keyboard(file))
+            }
+            UsbEmulatedDeviceKind::Tablet { width, height } => {
+                self.connect_emulated_device(Hid::new_tablet(file, width, height))
+            }
+            UsbEmulatedDeviceKind::Serial => self.connect_emulated_device(Serial::new(file)),
+        }
+    }
+
+    fn connect_emulated_device<F: UsbFunction + 'static>(&self, function: F) -> UsbControlResult {
+        let device =
+            EmulatedDevice::new(self.fail_handle.clone(), self.job_queue.clone(), function);
+
+        let device_ctx = match device.event_handler(self.event_loop.clone()) {
+            Some((event_handler, source)) => {
+                if let Err(e) = self.event_loop.add_event(
+                    &source,
+                    WatchingEvents::empty().set_read(),
+                    Arc::downgrade(&event_handler),
+                ) {
+                    error!("failed to add emulated device source to event loop: {}", e);
+                    return UsbControlResult::FailedToInitEmulatedDevice;
+                }
+                Some(DeviceContext::Emulated {
+                    _event_handler: event_handler,
+                    source,
+                })
+            }
+            None => None,
+        };
+
+        match self.usb_hub.connect_backend(Box::new(device)) {
+            Ok(port) => {
+                if let Some(device_ctx) = device_ctx {
+                    self.devices.lock().insert(port, device_ctx);
+                }
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect emulated device to hub: {}", e);
+                if let Some(DeviceContext::Emulated { source, .. }) = device_ctx {
+                    if let Err(e) = self.event_loop.remove_event_for_fd(&source) {
+                        error!("failed to remove emulated device source: {}", e);
+                    }
+                }
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
+    /// Import the device `busid` from the USB/IP server connected to `socket`.
+    fn handle_attach_usbip_device(&self, busid: &str, socket: File) -> UsbControlResult {
+        let device = match UsbipDevice::new(
+            self.fail_handle.clone(),
+            self.job_queue.clone(),
+            socket,
+            busid,
+        ) {
+            Ok(device) => device,
+            Err(e) => {
+                error!("failed to import USB/IP device {}: {}", busid, e);
+                return UsbControlResult::FailedToImportUsbipDevice;
+            }
+        };
+
+        let (event_handler, source) = device.event_handler(self.event_loop.clone());
+        if let Err(e) = self.event_loop.add_event(
+            &source,
+            WatchingEvents::empty().set_read(),
+            Arc::downgrade(&event_handler),
+        ) {
+            error!("failed to add USB/IP connection to event loop: {}", e);
+            return UsbControlResult::FailedToImportUsbipDevice;
+        }
+
+        match self.usb_hub.connect_backend(Box::new(device)) {
+            Ok(port) => {
+                self.devices.lock().insert(
+                    port,
+                    DeviceContext::Usbip {
+                        _event_handler: event_handler,
+                        source,
+                    },
+                );
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect USB/IP device to hub: {}", e);
+                if let Err(e) = self.event_loop.remove_event_for_fd(&source) {
+                    error!("failed to remove USB/IP connection from event loop: {}", e);
+                }
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
         match self.usb_hub.disconnect_port(port) {
             Ok(()) => {
-                if let Some(device_ctx) = self.devices.lock().remove(&port) {
-                    let _ = device_ctx.event_handler.on_event();
-                    let device = device_ctx.device.lock();
-                    let fd = device.fd();
-
-                    if let Err(e) = self.event_loop.remove_event_for_fd(&*fd) {
-                        error!(
-                            "failed to remove poll change handler from event loop: {}",
-                            e
-                        );
+                match self.devices.lock().remove(&port) {
+                    Some(DeviceContext::Host {
+                        event_handler,
+                        device,
+                    }) => {
+                        let _ = event_handler.on_event();
+                        let device = device.lock();
+                        let fd = device.fd();
+
+                        if let Err(e) = self.event_loop.remove_event_for_fd(&*fd) {
+                            error!(
+                                "failed to remove poll change handler from event loop: {}",
+                                e
+                            );
+                        }
                     }
+                    Some(DeviceContext::Emulated { source, .. })
+                    | Some(DeviceContext::Usbip { source, .. }) => {
+                        // The source was already removed if it was closed by its other end.
+                        if let Err(e) = self.event_loop.remove_event_for_fd(&source) {
+                            usb_debug!("failed to remove device source: {}", e);
+                        }
+                    }
+                    None => {}
                 }
                 UsbControlResult::Ok { port }
             }
@@ -264,6 +409,12 @@ impl ProviderInner {
         let cmd = tube.recv().map_err(Error::ReadControlTube)?;
         let result = match cmd {
             UsbControlCommand::AttachDevice { file, .. } => self.handle_attach_device(file),
+            UsbControlCommand::AttachEmulatedDevice { kind, file } => {
+                self.handle_attach_emulated_device(kind, file)
+            }
+            UsbControlCommand::AttachUsbipDevice { busid, socket } => {
+                self.handle_attach_usbip_device(&busid, socket)
+            }
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
         };
@@ -0,0 +1,12 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Devices imported from a USB/IP server over TCP or a Unix socket, so that the USB devices of
+//! other machines can be attached to the xHCI controller.
+
+mod protocol;
+mod usbip_device;
+
+pub use self::protocol::{Error, ImportedDevice, UsbipConnection};
+pub use self::usbip_device::UsbipDevice;
@@ -0,0 +1,792 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Client side of the USB/IP protocol, as described in the Linux kernel's
+//! Documentation/usb/usbip_protocol.rst. All fields are big-endian on the wire.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+
+use data_model::{Be16, Be32, DataInit, SBe32};
+use remain::sorted;
+use thiserror::Error;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to duplicate USB/IP socket: {0}")]
+    CloneSocket(io::Error),
+    #[error("USB/IP server refused to import {0}: status {1}")]
+    ImportRefused(String, u32),
+    #[error("invalid USB/IP bus id: {0}")]
+    InvalidBusId(String),
+    #[error("USB/IP server returned {0} bytes for a {1} bytes transfer")]
+    InvalidLength(i32, u32),
+    #[error("failed to read from USB/IP server: {0}")]
+    Read(io::Error),
+    #[error("failed to set USB/IP socket timeout: {0}")]
+    SetTimeout(io::Error),
+    #[error("unexpected USB/IP reply code {0:#x}")]
+    UnexpectedReply(u32),
+    #[error("unsupported USB/IP version {0:#x}")]
+    UnsupportedVersion(u16),
+    #[error("USB/IP reply for unknown sequence number {0}")]
+    UnknownSeqnum(u32),
+    #[error("failed to write to USB/IP server: {0}")]
+    Write(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Version of the protocol, as sent by the Linux usbip tools.
+const USBIP_VERSION: u16 = 0x0111;
+
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const USBIP_CMD_SUBMIT: u32 = 0x0001;
+const USBIP_CMD_UNLINK: u32 = 0x0002;
+const USBIP_RET_SUBMIT: u32 = 0x0003;
+const USBIP_RET_UNLINK: u32 = 0x0004;
+
+const USBIP_DIR_OUT: u32 = 0;
+const USBIP_DIR_IN: u32 = 1;
+
+/// transfer_flags bit of IN URBs, URB_DIR_IN in Linux.
+const URB_DIR_IN: u32 = 0x0200;
+
+/// number_of_packets of transfers that aren't isochronous.
+const NOT_ISOCHRONOUS: i32 = -1;
+
+const SYSFS_PATH_MAX: usize = 256;
+const SYSFS_BUS_ID_SIZE: usize = 32;
+
+// Speeds reported by the server, from enum usb_device_speed in Linux.
+const USB_SPEED_SUPER: u32 = 5;
+
+/// The status of a URB which was unlinked before it completed, -ECONNRESET in Linux.
+pub const UNLINKED_STATUS: i32 = -libc::ECONNRESET;
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct OpHeader {
+    version: Be16,
+    code: Be16,
+    status: Be32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for OpHeader {}
+
+// The part of struct usbip_usb_device that follows its path and bus id.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct UsbDeviceIds {
+    busnum: Be32,
+    devnum: Be32,
+    speed: Be32,
+    id_vendor: Be16,
+    id_product: Be16,
+    bcd_device: Be16,
+    b_device_class: u8,
+    b_device_sub_class: u8,
+    b_device_protocol: u8,
+    b_configuration_value: u8,
+    b_num_configurations: u8,
+    b_num_interfaces: u8,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for UsbDeviceIds {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct HeaderBasic {
+    command: Be32,
+    seqnum: Be32,
+    devid: Be32,
+    direction: Be32,
+    ep: Be32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for HeaderBasic {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct CmdSubmit {
+    transfer_flags: Be32,
+    transfer_buffer_length: SBe32,
+    start_frame: SBe32,
+    number_of_packets: SBe32,
+    interval: SBe32,
+    setup: [u8; 8],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for CmdSubmit {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct RetSubmit {
+    status: SBe32,
+    actual_length: SBe32,
+    start_frame: SBe32,
+    number_of_packets: SBe32,
+    error_count: SBe32,
+    padding: [u8; 8],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for RetSubmit {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct CmdUnlink {
+    unlink_seqnum: Be32,
+    padding: [u8; 24],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for CmdUnlink {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct RetUnlink {
+    status: SBe32,
+    padding: [u8; 24],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for RetUnlink {}
+
+/// A device exported by a USB/IP server.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ImportedDevice {
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl ImportedDevice {
+    /// The id of the device in URBs.
+    pub fn devid(&self) -> u32 {
+        self.busnum << 16 | self.devnum
+    }
+
+    /// Returns true for SuperSpeed devices and faster.
+    pub fn is_super_speed(&self) -> bool {
+        self.speed >= USB_SPEED_SUPER
+    }
+}
+
+/// Direction of the data stage of a URB.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UsbipDirection {
+    Out,
+    In,
+}
+
+/// A transfer request to an endpoint of the device.
+pub struct UsbipUrb<'a> {
+    pub endpoint: u8,
+    pub direction: UsbipDirection,
+    /// Setup packet of control transfers, ignored for other endpoints.
+    pub setup: [u8; 8],
+    /// Length of the data stage, which is `data` for OUT transfers.
+    pub length: u32,
+    pub data: &'a [u8],
+}
+
+/// A reply from the server.
+#[derive(Debug, PartialEq)]
+pub enum UsbipReply {
+    /// A URB completed, with `status` 0 or a negated errno, returning `data` for IN transfers.
+    Submit {
+        seqnum: u32,
+        status: i32,
+        actual_length: u32,
+        data: Vec<u8>,
+    },
+    /// The URB `unlinked_seqnum` was unlinked if `status` is `UNLINKED_STATUS`, or had already
+    /// completed if it is 0.
+    Unlink { unlinked_seqnum: u32, status: i32 },
+}
+
+// A URB the server hasn't replied to yet.
+struct SubmittedUrb {
+    direction: UsbipDirection,
+    length: u32,
+}
+
+/// A connection to a USB/IP server over which a single device was imported.
+pub struct UsbipConnection<S: Read + Write> {
+    stream: S,
+    devid: u32,
+    next_seqnum: u32,
+    submitted: HashMap<u32, SubmittedUrb>,
+    // Map of the sequence numbers of unlink commands to the URBs they unlink.
+    unlinks: HashMap<u32, u32>,
+    // Bytes received from the server that don't make up a complete reply yet.
+    received: Vec<u8>,
+}
+
+impl<S: Read + Write> UsbipConnection<S> {
+    /// Imports the device with bus id `busid`, such as "1-1", from the server at the other end of
+    /// `stream`.
+    pub fn import(mut stream: S, busid: &str) -> Result<(UsbipConnection<S>, ImportedDevice)> {
+        if busid.is_empty() || busid.len() >= SYSFS_BUS_ID_SIZE {
+            return Err(Error::InvalidBusId(busid.to_owned()));
+        }
+        let request = OpHeader {
+            version: USBIP_VERSION.into(),
+            code: OP_REQ_IMPORT.into(),
+            status: 0.into(),
+        };
+        let mut bus_id = [0u8; SYSFS_BUS_ID_SIZE];
+        bus_id[..busid.len()].copy_from_slice(busid.as_bytes());
+        let mut message = request.as_slice().to_vec();
+        message.extend_from_slice(&bus_id);
+        stream.write_all(&message).map_err(Error::Write)?;
+
+        let mut reply = OpHeader::default();
+        stream
+            .read_exact(reply.as_mut_slice())
+            .map_err(Error::Read)?;
+        if reply.version.to_native() != USBIP_VERSION {
+            return Err(Error::UnsupportedVersion(reply.version.to_native()));
+        }
+        if reply.code.to_native() != OP_REP_IMPORT {
+            return Err(Error::UnexpectedReply(reply.code.to_native() as u32));
+        }
+        if reply.status.to_native() != 0 {
+            return Err(Error::ImportRefused(
+                busid.to_owned(),
+                reply.status.to_native(),
+            ));
+        }
+
+        // The path and bus id of the device on the server are of no use to the client.
+        let mut names = [0u8; SYSFS_PATH_MAX + SYSFS_BUS_ID_SIZE];
+        stream.read_exact(&mut names).map_err(Error::Read)?;
+        let mut ids = UsbDeviceIds::default();
+        stream.read_exact(ids.as_mut_slice()).map_err(Error::Read)?;
+        let device = ImportedDevice {
+            busnum: ids.busnum.to_native(),
+            devnum: ids.devnum.to_native(),
+            speed: ids.speed.to_native(),
+            vendor_id: ids.id_vendor.to_native(),
+            product_id: ids.id_product.to_native(),
+        };
+
+        let connection = UsbipConnection {
+            stream,
+            devid: device.devid(),
+            next_seqnum: 1,
+            submitted: HashMap::new(),
+            unlinks: HashMap::new(),
+            received: Vec::new(),
+        };
+        Ok((connection, device))
+    }
+
+    /// Returns the stream to the server.
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    fn header(&mut self, command: u32, direction: u32, ep: u8) -> (u32, HeaderBasic) {
+        let seqnum = self.next_seqnum;
+        // Sequence number 0 isn't used.
+        self.next_seqnum = self.next_seqnum.wrapping_add(1).max(1);
+        let header = HeaderBasic {
+            command: command.into(),
+            seqnum: seqnum.into(),
+            devid: self.devid.into(),
+            direction: direction.into(),
+            ep: (ep as u32).into(),
+        };
+        (seqnum, header)
+    }
+
+    /// Encodes `urb` as a message to send to the server, expecting a reply to it from then on.
+    /// Returns the sequence number of the URB along with the message.
+    pub fn encode_submit(&mut self, urb: &UsbipUrb) -> (u32, Vec<u8>) {
+        let (direction, transfer_flags) = match urb.direction {
+            UsbipDirection::Out => (USBIP_DIR_OUT, 0),
+            UsbipDirection::In => (USBIP_DIR_IN, URB_DIR_IN),
+        };
+        let (seqnum, header) = self.header(USBIP_CMD_SUBMIT, direction, urb.endpoint);
+        let submit = CmdSubmit {
+            transfer_flags: transfer_flags.into(),
+            transfer_buffer_length: (urb.length as i32).into(),
+            start_frame: 0.into(),
+            number_of_packets: NOT_ISOCHRONOUS.into(),
+            // The interval of interrupt endpoints isn't part of xHCI transfers, and the server
+            // rejects interrupt URBs without one, so poll as often as possible.
+            interval: 1.into(),
+            setup: urb.setup,
+        };
+        let mut message = header.as_slice().to_vec();
+        message.extend_from_slice(submit.as_slice());
+        if urb.direction == UsbipDirection::Out {
+            message.extend_from_slice(urb.data);
+        }
+        self.submitted.insert(
+            seqnum,
+            SubmittedUrb {
+                direction: urb.direction,
+                length: urb.length,
+            },
+        );
+        (seqnum, message)
+    }
+
+    /// Encodes a message asking the server to unlink the URB `seqnum`. The server replies with an
+    /// `Unlink` reply, but may still complete the URB first.
+    pub fn encode_unlink(&mut self, seqnum: u32) -> Vec<u8> {
+        let (unlink_seqnum, header) = self.header(USBIP_CMD_UNLINK, USBIP_DIR_OUT, 0);
+        let unlink = CmdUnlink {
+            unlink_seqnum: seqnum.into(),
+            padding: [0; 24],
+        };
+        let mut message = header.as_slice().to_vec();
+        message.extend_from_slice(unlink.as_slice());
+        self.unlinks.insert(unlink_seqnum, seqnum);
+        message
+    }
+
+    /// Sends `urb` to the server. Returns the sequence number of the URB.
+    pub fn submit(&mut self, urb: &UsbipUrb) -> Result<u32> {
+        let (seqnum, message) = self.encode_submit(urb);
+        self.stream.write_all(&message).map_err(Error::Write)?;
+        Ok(seqnum)
+    }
+
+    /// Asks the server to unlink the URB `seqnum`, as `encode_unlink`.
+    pub fn unlink(&mut self, seqnum: u32) -> Result<()> {
+        let message = self.encode_unlink(seqnum);
+        self.stream.write_all(&message).map_err(Error::Write)
+    }
+
+    /// Buffers `data` received from the server, to be parsed by `next_reply`.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.received.extend_from_slice(data);
+    }
+
+    /// Parses the next reply out of the data received so far, or returns None until all of it
+    /// was received.
+    pub fn next_reply(&mut self) -> Result<Option<UsbipReply>> {
+        let header_end = mem::size_of::<HeaderBasic>();
+        if self.received.len() < header_end {
+            return Ok(None);
+        }
+        let header = HeaderBasic::from_reader(&self.received[..header_end]).map_err(Error::Read)?;
+        let seqnum = header.seqnum.to_native();
+        let (reply, end) = match header.command.to_native() {
+            USBIP_RET_SUBMIT => {
+                let ret_end = header_end + mem::size_of::<RetSubmit>();
+                if self.received.len() < ret_end {
+                    return Ok(None);
+                }
+                let ret = RetSubmit::from_reader(&self.received[header_end..ret_end])
+                    .map_err(Error::Read)?;
+                // Whether data follows depends on the direction of the URB, which the reply
+                // doesn't repeat.
+                let urb = self
+                    .submitted
+                    .get(&seqnum)
+                    .ok_or(Error::UnknownSeqnum(seqnum))?;
+                let actual_length = ret.actual_length.to_native();
+                if actual_length < 0 || actual_length as u32 > urb.length {
+                    return Err(Error::InvalidLength(actual_length, urb.length));
+                }
+                let data_end = match urb.direction {
+                    UsbipDirection::In => ret_end + actual_length as usize,
+                    UsbipDirection::Out => ret_end,
+                };
+                if self.received.len() < data_end {
+                    return Ok(None);
+                }
+                self.submitted.remove(&seqnum);
+                let reply = UsbipReply::Submit {
+                    seqnum,
+                    status: ret.status.to_native(),
+                    actual_length: actual_length as u32,
+                    data: self.received[ret_end..data_end].to_vec(),
+                };
+                (reply, data_end)
+            }
+            USBIP_RET_UNLINK => {
+                let ret_end = header_end + mem::size_of::<RetUnlink>();
+                if self.received.len() < ret_end {
+                    return Ok(None);
+                }
+                let ret = RetUnlink::from_reader(&self.received[header_end..ret_end])
+                    .map_err(Error::Read)?;
+                let unlinked_seqnum = self
+                    .unlinks
+                    .remove(&seqnum)
+                    .ok_or(Error::UnknownSeqnum(seqnum))?;
+                let status = ret.status.to_native();
+                if status == UNLINKED_STATUS {
+                    // No RET_SUBMIT follows for an unlinked URB.
+                    self.submitted.remove(&unlinked_seqnum);
+                }
+                let reply = UsbipReply::Unlink {
+                    unlinked_seqnum,
+                    status,
+                };
+                (reply, ret_end)
+            }
+            command => return Err(Error::UnexpectedReply(command)),
+        };
+        self.received.drain(..end);
+        Ok(Some(reply))
+    }
+
+    /// Reads the next reply from the server, blocking until it was received in full, or returns
+    /// None once the server closed the connection.
+    pub fn read_reply(&mut self) -> Result<Option<UsbipReply>> {
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some(reply) = self.next_reply()? {
+                return Ok(Some(reply));
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) if self.received.is_empty() => return Ok(None),
+                Ok(0) => return Err(Error::Read(io::ErrorKind::UnexpectedEof.into())),
+                Ok(len) => self.receive(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Read(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
+    use tempfile::TempDir;
+
+    const BUSID: &str = "1-1";
+    const DEVICE: ImportedDevice = ImportedDevice {
+        busnum: 1,
+        devnum: 2,
+        speed: 3,
+        vendor_id: 0x18d1,
+        product_id: 0x4ee7,
+    };
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 1, 0x00, 0x02, 0, 0, 0, 64, 0xd1, 0x18, 0xe7, 0x4e, 0x00, 0x01, 1, 2, 3, 1,
+    ];
+    const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 6, 0x00, 0x01, 0, 0, 18, 0];
+
+    // Reads `obj` in full, or returns false if the stream is closed before the first byte.
+    fn read_obj_or_eof<R: Read, T: DataInit>(reader: &mut R, obj: &mut T) -> Result<bool> {
+        let buf = obj.as_mut_slice();
+        let mut read = 0;
+        while read < buf.len() {
+            match reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(Error::Read(io::ErrorKind::UnexpectedEof.into())),
+                Ok(len) => read += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Read(e)),
+            }
+        }
+        Ok(true)
+    }
+
+    // A USB/IP server exporting a single device, which answers control transfers with its device
+    // descriptor, swallows bulk OUT transfers and never completes bulk IN transfers, so that they
+    // can be unlinked.
+    fn fake_server<S: Read + Write>(mut stream: S) {
+        let mut request = OpHeader::default();
+        stream.read_exact(request.as_mut_slice()).unwrap();
+        assert_eq!(request.version.to_native(), USBIP_VERSION);
+        assert_eq!(request.code.to_native(), OP_REQ_IMPORT);
+        let mut bus_id = [0u8; SYSFS_BUS_ID_SIZE];
+        stream.read_exact(&mut bus_id).unwrap();
+        let known = bus_id.starts_with(BUSID.as_bytes()) && bus_id[BUSID.len()] == 0;
+
+        let reply = OpHeader {
+            version: USBIP_VERSION.into(),
+            code: OP_REP_IMPORT.into(),
+            status: (!known as u32).into(),
+        };
+        stream.write_all(reply.as_slice()).unwrap();
+        if !known {
+            return;
+        }
+        let ids = UsbDeviceIds {
+            busnum: DEVICE.busnum.into(),
+            devnum: DEVICE.devnum.into(),
+            speed: DEVICE.speed.into(),
+            id_vendor: DEVICE.vendor_id.into(),
+            id_product: DEVICE.product_id.into(),
+            b_num_configurations: 1,
+            b_num_interfaces: 1,
+            ..Default::default()
+        };
+        stream
+            .write_all(&[0u8; SYSFS_PATH_MAX + SYSFS_BUS_ID_SIZE])
+            .unwrap();
+        stream.write_all(ids.as_slice()).unwrap();
+
+        let mut pending_in = Vec::new();
+        loop {
+            let mut header = HeaderBasic::default();
+            if !read_obj_or_eof(&mut stream, &mut header).unwrap() {
+                return;
+            }
+            assert_eq!(header.devid.to_native(), DEVICE.devid());
+            let seqnum = header.seqnum.to_native();
+            let mut reply = HeaderBasic {
+                seqnum: seqnum.into(),
+                ..Default::default()
+            };
+            match header.command.to_native() {
+                USBIP_CMD_SUBMIT => {
+                    let mut submit = CmdSubmit::default();
+                    stream.read_exact(submit.as_mut_slice()).unwrap();
+                    let length = submit.transfer_buffer_length.to_native() as usize;
+                    let (status, data) = match (header.ep.to_native(), header.direction.to_native())
+                    {
+                        (0, USBIP_DIR_IN) if submit.setup == GET_DEVICE_DESCRIPTOR => {
+                            (0, DEVICE_DESCRIPTOR[..length].to_vec())
+                        }
+                        (0, _) => (-libc::EPIPE, Vec::new()),
+                        (_, USBIP_DIR_OUT) => {
+                            let mut data = vec![0u8; length];
+                            stream.read_exact(&mut data).unwrap();
+                            (0, data)
+                        }
+                        _ => {
+                            pending_in.push(seqnum);
+                            continue;
+                        }
+                    };
+                    reply.command = USBIP_RET_SUBMIT.into();
+                    let ret = RetSubmit {
+                        status: status.into(),
+                        actual_length: (data.len() as i32).into(),
+                        ..Default::default()
+                    };
+                    stream.write_all(reply.as_slice()).unwrap();
+                    stream.write_all(ret.as_slice()).unwrap();
+                    if header.direction.to_native() == USBIP_DIR_IN {
+                        stream.write_all(&data).unwrap();
+                    }
+                }
+                USBIP_CMD_UNLINK => {
+                    let mut unlink = CmdUnlink::default();
+                    stream.read_exact(unlink.as_mut_slice()).unwrap();
+                    let unlink_seqnum = unlink.unlink_seqnum.to_native();
+                    let status = match pending_in.iter().position(|&s| s == unlink_seqnum) {
+                        Some(index) => {
+                            pending_in.remove(index);
+                            UNLINKED_STATUS
+                        }
+                        None => 0,
+                    };
+                    reply.command = USBIP_RET_UNLINK.into();
+                    let ret = RetUnlink {
+                        status: status.into(),
+                        padding: [0; 24],
+                    };
+                    stream.write_all(reply.as_slice()).unwrap();
+                    stream.write_all(ret.as_slice()).unwrap();
+                }
+                command => panic!("unexpected command {}", command),
+            }
+        }
+    }
+
+    fn exercise_connection<S: Read + Write>(stream: S) {
+        let (mut connection, device) = UsbipConnection::import(stream, BUSID).unwrap();
+        assert_eq!(device, DEVICE);
+        assert!(!device.is_super_speed());
+
+        let seqnum = connection
+            .submit(&UsbipUrb {
+                endpoint: 0,
+                direction: UsbipDirection::In,
+                setup: GET_DEVICE_DESCRIPTOR,
+                length: 18,
+                data: &[],
+            })
+            .unwrap();
+        assert_eq!(
+            connection.read_reply().unwrap(),
+            Some(UsbipReply::Submit {
+                seqnum,
+                status: 0,
+                actual_length: 18,
+                data: DEVICE_DESCRIPTOR.to_vec(),
+            })
+        );
+
+        // Unsupported control requests stall.
+        let seqnum = connection
+            .submit(&UsbipUrb {
+                endpoint: 0,
+                direction: UsbipDirection::Out,
+                setup: [0x00, 9, 1, 0, 0, 0, 0, 0],
+                length: 0,
+                data: &[],
+            })
+            .unwrap();
+        assert_eq!(
+            connection.read_reply().unwrap(),
+            Some(UsbipReply::Submit {
+                seqnum,
+                status: -libc::EPIPE,
+                actual_length: 0,
+                data: Vec::new(),
+            })
+        );
+
+        let in_seqnum = connection
+            .submit(&UsbipUrb {
+                endpoint: 1,
+                direction: UsbipDirection::In,
+                setup: [0; 8],
+                length: 512,
+                data: &[],
+            })
+            .unwrap();
+        let out_seqnum = connection
+            .submit(&UsbipUrb {
+                endpoint: 2,
+                direction: UsbipDirection::Out,
+                setup: [0; 8],
+                length: 5,
+                data: b"hello",
+            })
+            .unwrap();
+        assert_eq!(
+            connection.read_reply().unwrap(),
+            Some(UsbipReply::Submit {
+                seqnum: out_seqnum,
+                status: 0,
+                actual_length: 5,
+                data: Vec::new(),
+            })
+        );
+
+        connection.unlink(in_seqnum).unwrap();
+        assert_eq!(
+            connection.read_reply().unwrap(),
+            Some(UsbipReply::Unlink {
+                unlinked_seqnum: in_seqnum,
+                status: UNLINKED_STATUS,
+            })
+        );
+        // Unlinking a completed URB is harmless.
+        connection.unlink(out_seqnum).unwrap();
+        assert_eq!(
+            connection.read_reply().unwrap(),
+            Some(UsbipReply::Unlink {
+                unlinked_seqnum: out_seqnum,
+                status: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || fake_server(listener.accept().unwrap().0));
+
+        exercise_connection(TcpStream::connect(address).unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unix() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("usbip.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || fake_server(listener.accept().unwrap().0));
+
+        exercise_connection(UnixStream::connect(&path).unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_busid() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || fake_server(server));
+
+        match UsbipConnection::import(client, "2-1") {
+            Err(Error::ImportRefused(busid, 1)) => assert_eq!(busid, "2-1"),
+            _ => panic!("import of an unknown device should fail"),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn partial_replies() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || fake_server(server));
+        let (mut connection, _) = UsbipConnection::import(client, BUSID).unwrap();
+
+        let (seqnum, message) = connection.encode_submit(&UsbipUrb {
+            endpoint: 0,
+            direction: UsbipDirection::In,
+            setup: GET_DEVICE_DESCRIPTOR,
+            length: 18,
+            data: &[],
+        });
+        let mut stream = connection.stream();
+        stream.write_all(&message).unwrap();
+        let mut reply = [0u8; 20 + 28 + 18];
+        stream.read_exact(&mut reply).unwrap();
+
+        // Nothing is parsed until the whole reply was received.
+        for byte in &reply[..reply.len() - 1] {
+            connection.receive(&[*byte]);
+            assert_eq!(connection.next_reply().unwrap(), None);
+        }
+        connection.receive(&reply[reply.len() - 1..]);
+        assert_eq!(
+            connection.next_reply().unwrap(),
+            Some(UsbipReply::Submit {
+                seqnum,
+                status: 0,
+                actual_length: 18,
+                data: DEVICE_DESCRIPTOR.to_vec(),
+            })
+        );
+        assert_eq!(connection.next_reply().unwrap(), None);
+
+        connection
+            .stream()
+            .shutdown(std::net::Shutdown::Write)
+            .unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn closed_connection() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || fake_server(server));
+        let (mut connection, _) = UsbipConnection::import(client, BUSID).unwrap();
+
+        connection
+            .stream()
+            .shutdown(std::net::Shutdown::Write)
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(connection.read_reply().unwrap(), None);
+    }
+}
@@ -0,0 +1,594 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use super::protocol::{
+    Error as UsbipError, ImportedDevice, UsbipConnection, UsbipDirection, UsbipReply, UsbipUrb,
+    UNLINKED_STATUS,
+};
+use crate::usb::host_backend::error::*;
+use crate::usb::host_backend::host_device::ControlEndpointState;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::{BackendType, UsbDeviceAddress, XhciBackendDevice};
+use crate::usb::xhci::xhci_transfer::{
+    TransferDirection, XhciTransfer, XhciTransferState, XhciTransferType,
+};
+use crate::utils::{AsyncJobQueue, EventHandler, EventLoop, FailHandle};
+use base::{error, AsRawDescriptor, Descriptor};
+use data_model::DataInit;
+use sync::Mutex;
+use usb_util::{ControlRequestDataPhaseTransferDirection, TransferStatus, UsbRequestSetup};
+
+// The import and URBs are written with blocking calls, so a server which stops in the middle of
+// a message is disconnected after this long.
+const SOCKET_TIMEOUT_MS: u64 = 2000;
+
+// How much of the replies of the server is read at once.
+const RECV_BUFFER_SIZE: usize = 16384;
+
+// Sets the SO_RCVTIMEO or SO_SNDTIMEO timeout of `socket`.
+fn set_socket_timeout(socket: &File, kind: libc::c_int, timeout: Duration) -> io::Result<()> {
+    let timeval = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: libc::suseconds_t::from(timeout.subsec_micros() as i32),
+    };
+    // Safe because `socket` is a valid descriptor, the length of the pointer's data is the same
+    // as the passed in length, and the return value is checked.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_descriptor(),
+            libc::SOL_SOCKET,
+            kind,
+            &timeval as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Reads what the server sent so far from `socket`, failing with `WouldBlock` rather than waiting
+// for more. The socket itself stays blocking for the writes of the device.
+fn recv_nonblocking(socket: Descriptor, buf: &mut [u8]) -> io::Result<usize> {
+    // Safe because `buf` is valid for `buf.len()` bytes and the return value is checked.
+    let ret = unsafe {
+        libc::recv(
+            socket.as_raw_descriptor(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+// Converts the status of a completed URB, 0 or a negated errno, to the status of its transfer.
+fn transfer_status(status: i32) -> TransferStatus {
+    match -status {
+        0 => TransferStatus::Completed,
+        libc::ECONNRESET | libc::ENOENT => TransferStatus::Cancelled,
+        libc::ENODEV | libc::ESHUTDOWN => TransferStatus::NoDevice,
+        _ => TransferStatus::Error,
+    }
+}
+
+// A transfer waiting for the server to complete its URB.
+struct InFlightTransfer {
+    transfer: Arc<XhciTransfer>,
+    // The buffer to return the data of IN transfers in.
+    buffer: Option<ScatterGatherBuffer>,
+}
+
+/// State of a USB/IP device shared between the device and the handler of its connection.
+struct UsbipState {
+    connection: UsbipConnection<File>,
+    connected: bool,
+    in_flight: HashMap<u32, InFlightTransfer>,
+}
+
+impl UsbipState {
+    // Completes the transfer of URB `seqnum` with `status`, returning `data` for IN transfers.
+    fn complete(
+        &mut self,
+        seqnum: u32,
+        status: TransferStatus,
+        actual_length: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let in_flight = match self.in_flight.remove(&seqnum) {
+            Some(in_flight) => in_flight,
+            None => {
+                usb_debug!("reply for URB {} without a transfer", seqnum);
+                return Ok(());
+            }
+        };
+        let mut state = in_flight.transfer.state().lock();
+        let status = match *state {
+            XhciTransferState::Submitted { .. } => {
+                *state = XhciTransferState::Completed;
+                status
+            }
+            XhciTransferState::Cancelling => {
+                *state = XhciTransferState::Cancelled;
+                TransferStatus::Cancelled
+            }
+            _ => {
+                error!("xhci trasfer state is invalid");
+                *state = XhciTransferState::Completed;
+                return Err(Error::BadXhciTransferState);
+            }
+        };
+        drop(state);
+
+        let bytes_transferred = match (&status, &in_flight.buffer) {
+            (TransferStatus::Cancelled, _) => 0,
+            (_, Some(buffer)) => buffer.write(data).map_err(Error::WriteBuffer)? as u32,
+            (_, None) => actual_length,
+        };
+        usb_debug!("URB {} completed with {} bytes", seqnum, bytes_transferred);
+        in_flight
+            .transfer
+            .on_transfer_complete(&status, bytes_transferred)
+            .map_err(Error::TransferComplete)
+    }
+
+    // Encodes a request to unlink URB `seqnum` after its transfer was cancelled, or returns None
+    // if the URB completed in the meantime.
+    fn encode_unlink(&mut self, seqnum: u32) -> Option<Vec<u8>> {
+        if !self.connected || !self.in_flight.contains_key(&seqnum) {
+            return None;
+        }
+        Some(self.connection.encode_unlink(seqnum))
+    }
+
+    // Completes all transfers once the server is gone.
+    fn disconnect(&mut self) -> Result<()> {
+        self.connected = false;
+        let seqnums: Vec<u32> = self.in_flight.keys().cloned().collect();
+        for seqnum in seqnums {
+            self.complete(seqnum, TransferStatus::NoDevice, 0, &[])?;
+        }
+        Ok(())
+    }
+}
+
+// Writes `message` to the server with only `writer` locked, so that replies keep being handled
+// while the server is slow to read it, and drops the connection if the write fails.
+fn send_message(writer: &mut File, state: &Mutex<UsbipState>, message: &[u8]) -> Result<()> {
+    if let Err(e) = writer.write_all(message) {
+        error!("failed to write to USB/IP server: {}", e);
+        return state.lock().disconnect();
+    }
+    Ok(())
+}
+
+/// A device imported from a USB/IP server, to which transfers are forwarded as URBs.
+pub struct UsbipDevice {
+    fail_handle: Arc<dyn FailHandle>,
+    job_queue: Arc<AsyncJobQueue>,
+    device: ImportedDevice,
+    // Locked before `state` while a message is encoded and written, so that messages are sent in
+    // the order of their sequence numbers.
+    writer: Arc<Mutex<File>>,
+    state: Arc<Mutex<UsbipState>>,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+}
+
+impl UsbipDevice {
+    /// Import the device with bus id `busid` from the USB/IP server connected to `socket`.
+    pub fn new(
+        fail_handle: Arc<dyn FailHandle>,
+        job_queue: Arc<AsyncJobQueue>,
+        socket: File,
+        busid: &str,
+    ) -> Result<UsbipDevice> {
+        let timeout = Duration::from_millis(SOCKET_TIMEOUT_MS);
+        for kind in &[libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+            set_socket_timeout(&socket, *kind, timeout)
+                .map_err(|e| Error::Usbip(UsbipError::SetTimeout(e)))?;
+        }
+        let writer = socket
+            .try_clone()
+            .map_err(|e| Error::Usbip(UsbipError::CloneSocket(e)))?;
+        let (connection, device) = UsbipConnection::import(socket, busid).map_err(Error::Usbip)?;
+        usb_debug!("imported USB/IP device {}: {:?}", busid, device);
+        Ok(UsbipDevice {
+            fail_handle,
+            job_queue,
+            device,
+            writer: Arc::new(Mutex::new(writer)),
+            state: Arc::new(Mutex::new(UsbipState {
+                connection,
+                connected: true,
+                in_flight: HashMap::new(),
+            })),
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+        })
+    }
+
+    /// Returns a handler that completes transfers as the server replies, along with the
+    /// descriptor of the connection to poll it on.
+    pub fn event_handler(&self, event_loop: Arc<EventLoop>) -> (Arc<dyn EventHandler>, Descriptor) {
+        let source = Descriptor(self.state.lock().connection.stream().as_raw_descriptor());
+        let handler: Arc<dyn EventHandler> = Arc::new(UsbipEventHandler {
+            state: self.state.clone(),
+            event_loop,
+            source,
+        });
+        (handler, source)
+    }
+
+    // The callback runs with the transfer state locked, so the URB is unlinked later on the job
+    // queue.
+    fn cancel_callback(&self, seqnum: u32) -> Box<dyn FnOnce() + Send> {
+        let weak_writer: Weak<Mutex<File>> = Arc::downgrade(&self.writer);
+        let weak_state: Weak<Mutex<UsbipState>> = Arc::downgrade(&self.state);
+        let job_queue = self.job_queue.clone();
+        let fail_handle = self.fail_handle.clone();
+        Box::new(move || {
+            let job = move || {
+                if let (Some(writer), Some(state)) = (weak_writer.upgrade(), weak_state.upgrade()) {
+                    let mut writer = writer.lock();
+                    let message = state.lock().encode_unlink(seqnum);
+                    if let Some(message) = message {
+                        if let Err(e) = send_message(&mut writer, &state, &message) {
+                            error!("failed to cancel USB/IP transfer: {}", e);
+                            fail_handle.fail();
+                        }
+                    }
+                }
+            };
+            if let Err(e) = job_queue.queue_job(job) {
+                error!("failed to queue USB/IP unlink: {}", e);
+            }
+        })
+    }
+
+    // Sends `urb` to the server, which completes `xhci_transfer` once it replies.
+    fn submit_urb(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        urb: UsbipUrb,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let xhci_transfer = Arc::new(xhci_transfer);
+        let mut writer = self.writer.lock();
+        let mut state = self.state.lock();
+        // Hold the lock so that the transfer can't be cancelled before its URB is known.
+        let mut transfer_state = xhci_transfer.state().lock();
+        match mem::replace(&mut *transfer_state, XhciTransferState::Cancelled) {
+            XhciTransferState::Created => {}
+            XhciTransferState::Cancelled => {
+                drop(transfer_state);
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Cancelled, 0)
+                    .map_err(Error::TransferComplete);
+            }
+            _ => {
+                error!("xhci trasfer state is invalid");
+                return Err(Error::BadXhciTransferState);
+            }
+        }
+
+        if !state.connected {
+            *transfer_state = XhciTransferState::Completed;
+            drop(transfer_state);
+            return xhci_transfer
+                .on_transfer_complete(&TransferStatus::NoDevice, 0)
+                .map_err(Error::TransferComplete);
+        }
+        let (seqnum, message) = state.connection.encode_submit(&urb);
+        *transfer_state = XhciTransferState::Submitted {
+            cancel_callback: self.cancel_callback(seqnum),
+        };
+        drop(transfer_state);
+
+        let buffer = match urb.direction {
+            UsbipDirection::In => buffer,
+            UsbipDirection::Out => None,
+        };
+        state.in_flight.insert(
+            seqnum,
+            InFlightTransfer {
+                transfer: xhci_transfer,
+                buffer,
+            },
+        );
+        drop(state);
+        send_message(&mut writer, &self.state, &message)
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let setup = self.control_request_setup;
+        let mut data = Vec::new();
+        let direction = match setup.get_direction() {
+            ControlRequestDataPhaseTransferDirection::HostToDevice => {
+                if let Some(buffer) = &buffer {
+                    let len = min(
+                        buffer.len().map_err(Error::BufferLen)?,
+                        setup.length as usize,
+                    );
+                    data.resize(len, 0);
+                    buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+                }
+                UsbipDirection::Out
+            }
+            ControlRequestDataPhaseTransferDirection::DeviceToHost => UsbipDirection::In,
+        };
+        let mut setup_packet = [0u8; 8];
+        setup_packet.copy_from_slice(setup.as_slice());
+        let urb = UsbipUrb {
+            endpoint: 0,
+            direction,
+            setup: setup_packet,
+            length: match direction {
+                UsbipDirection::Out => data.len() as u32,
+                UsbipDirection::In => setup.length as u32,
+            },
+            data: &data,
+        };
+        self.submit_urb(xhci_transfer, urb, buffer)
+    }
+
+    fn handle_control_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage(setup) => {
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_debug!("setup stage setup buffer: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage(buffer) => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                // Requests with a DataStage will be executed here.
+                // Requests without a DataStage will be executed in StatusStage.
+                self.execute_control_transfer(xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    // Request was already executed during DataStage.
+                    // Just complete the StatusStage transfer.
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    // Execute the request now since there was no DataStage.
+                    self.execute_control_transfer(xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                // Non control transfer should not be handled in this function.
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_data_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let endpoint = xhci_transfer.get_endpoint_number();
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        let buffer = match transfer_type {
+            XhciTransferType::Normal(buffer) => buffer,
+            XhciTransferType::Noop => {
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete);
+            }
+            _ => {
+                error!(
+                    "unsupported {} transfer sent to endpoint {}",
+                    transfer_type, endpoint
+                );
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+
+        let length = buffer.len().map_err(Error::BufferLen)?;
+        match xhci_transfer.get_transfer_dir() {
+            TransferDirection::In => {
+                let urb = UsbipUrb {
+                    endpoint,
+                    direction: UsbipDirection::In,
+                    setup: [0; 8],
+                    length: length as u32,
+                    data: &[],
+                };
+                self.submit_urb(xhci_transfer, urb, Some(buffer))
+            }
+            TransferDirection::Out => {
+                let mut data = vec![0u8; length];
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+                let urb = UsbipUrb {
+                    endpoint,
+                    direction: UsbipDirection::Out,
+                    setup: [0; 8],
+                    length: length as u32,
+                    data: &data,
+                };
+                self.submit_urb(xhci_transfer, urb, None)
+            }
+            TransferDirection::Control => {
+                error!("control transfer sent to endpoint {}", endpoint);
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete)
+            }
+        }
+    }
+}
+
+impl XhciBackendDevice for UsbipDevice {
+    fn get_backend_type(&self) -> BackendType {
+        if self.device.is_super_speed() {
+            BackendType::Usb3
+        } else {
+            BackendType::Usb2
+        }
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.device.vendor_id
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.device.product_id
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            self.handle_control_transfer(transfer)
+        } else {
+            self.handle_data_transfer(transfer)
+        }
+    }
+
+    fn set_address(&mut self, _address: UsbDeviceAddress) {
+        // The server addresses the device on its own bus.
+        usb_debug!(
+            "Set address control transfer is received with address: {}",
+            _address
+        );
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // USB/IP has no command to reset the device, only the control endpoint is reset.
+        usb_debug!("resetting USB/IP device");
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        Ok(())
+    }
+}
+
+// Completes the transfers of a USB/IP device as the server replies to their URBs.
+struct UsbipEventHandler {
+    // Keeps the connection, and so `source`, open.
+    state: Arc<Mutex<UsbipState>>,
+    event_loop: Arc<EventLoop>,
+    source: Descriptor,
+}
+
+impl UsbipEventHandler {
+    fn on_event_helper(&self) -> Result<()> {
+        // The state isn't locked while reading, and replies are only parsed once they were
+        // received in full, so that a slow server stalls neither the event loop nor the device.
+        let mut buf = [0u8; RECV_BUFFER_SIZE];
+        let len = match recv_nonblocking(self.source, &mut buf) {
+            Ok(0) => {
+                usb_debug!("USB/IP connection closed");
+                return self.disconnect();
+            }
+            Ok(len) => len,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                return Ok(());
+            }
+            Err(e) => {
+                error!("failed to read USB/IP reply: {}", e);
+                return self.disconnect();
+            }
+        };
+
+        let mut state = self.state.lock();
+        state.connection.receive(&buf[..len]);
+        loop {
+            let reply = match state.connection.next_reply() {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    error!("failed to parse USB/IP reply: {}", e);
+                    drop(state);
+                    return self.disconnect();
+                }
+            };
+            match reply {
+                UsbipReply::Submit {
+                    seqnum,
+                    status,
+                    actual_length,
+                    data,
+                } => state.complete(seqnum, transfer_status(status), actual_length, &data)?,
+                UsbipReply::Unlink {
+                    unlinked_seqnum,
+                    status,
+                } => {
+                    // Otherwise the URB completed before it could be unlinked, and its transfer
+                    // was completed with it.
+                    if status == UNLINKED_STATUS {
+                        state.complete(unlinked_seqnum, TransferStatus::Cancelled, 0, &[])?;
+                    }
+                }
+            }
+        }
+    }
+
+    // Stops polling the connection once it is unusable, and completes its transfers.
+    fn disconnect(&self) -> Result<()> {
+        if let Err(e) = self.event_loop.remove_event_for_fd(&self.source) {
+            error!("failed to remove USB/IP connection from event loop: {}", e);
+        }
+        self.state.lock().disconnect()
+    }
+}
+
+impl EventHandler for UsbipEventHandler {
+    fn on_event(&self) -> std::result::Result<(), ()> {
+        self.on_event_helper().map_err(|e| {
+            error!("failed to complete USB/IP transfer: {}", e);
+        })
+    }
+}
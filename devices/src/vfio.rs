@@ -73,6 +73,8 @@ pub enum VfioError {
     VfioIrqMask(Error),
     #[error("failed to unmask vfio deviece's irq: {0}")]
     VfioIrqUnmask(Error),
+    #[error("container doesn't support the no-IOMMU driver type")]
+    VfioNoIommu,
     #[error("container dones't support VfioType1V2 IOMMU driver type")]
     VfioType1V2,
 }
@@ -89,6 +91,8 @@ static KVM_VFIO_FILE: OnceCell<SafeDescriptor> = OnceCell::new();
 pub struct VfioContainer {
     container: File,
     groups: HashMap<u32, Arc<VfioGroup>>,
+    // Whether the groups are no-IOMMU groups, used by drivers in this process.
+    noiommu: bool,
 }
 
 const VFIO_API_VERSION: u8 = 0;
@@ -110,15 +114,25 @@ impl VfioContainer {
         Ok(VfioContainer {
             container,
             groups: HashMap::new(),
+            noiommu: false,
         })
     }
 
+    /// Open a VfioContainer for devices without IOMMU protection, which are driven by this process
+    /// instead of being assigned to a VM. The kernel needs to be booted with
+    /// `vfio.enable_unsafe_noiommu_mode=1`.
+    pub fn new_noiommu() -> Result<Self> {
+        let mut container = Self::new()?;
+        container.noiommu = true;
+        Ok(container)
+    }
+
     fn is_group_set(&self, group_id: u32) -> bool {
         self.groups.get(&group_id).is_some()
     }
 
     fn check_extension(&self, val: u32) -> bool {
-        if val != VFIO_TYPE1_IOMMU && val != VFIO_TYPE1v2_IOMMU {
+        if val != VFIO_TYPE1_IOMMU && val != VFIO_TYPE1v2_IOMMU && val != VFIO_NOIOMMU_IOMMU {
             panic!("IOMMU type error");
         }
 
@@ -128,7 +142,7 @@ impl VfioContainer {
     }
 
     fn set_iommu(&self, val: u32) -> i32 {
-        if val != VFIO_TYPE1_IOMMU && val != VFIO_TYPE1v2_IOMMU {
+        if val != VFIO_TYPE1_IOMMU && val != VFIO_TYPE1v2_IOMMU && val != VFIO_NOIOMMU_IOMMU {
             panic!("IOMMU type error");
         }
 
@@ -242,6 +256,29 @@ impl VfioContainer {
             }
         }
     }
+
+    fn get_noiommu_group(&mut self, id: u32) -> Result<Arc<VfioGroup>> {
+        match self.groups.get(&id) {
+            Some(group) => Ok(group.clone()),
+            None => {
+                let group = Arc::new(VfioGroup::new(self, id)?);
+
+                if self.groups.is_empty() {
+                    if !self.check_extension(VFIO_NOIOMMU_IOMMU) {
+                        return Err(VfioError::VfioNoIommu);
+                    }
+
+                    if self.set_iommu(VFIO_NOIOMMU_IOMMU) < 0 {
+                        return Err(VfioError::ContainerSetIOMMU(get_error()));
+                    }
+                }
+
+                self.groups.insert(id, group.clone());
+
+                Ok(group)
+            }
+        }
+    }
 }
 
 impl AsRawDescriptor for VfioContainer {
@@ -255,15 +292,18 @@ struct VfioGroup {
 }
 
 impl VfioGroup {
-    fn new(container: &VfioContainer, id: u32) -> Result<Self> {
-        let mut group_path = String::from("/dev/vfio/");
-        let s_id = &id;
-        group_path.push_str(s_id.to_string().as_str());
+    // Returns the path of the character device of group `id`. No-IOMMU groups get their own
+    // device nodes.
+    fn path(id: u32, noiommu: bool) -> PathBuf {
+        let prefix = if noiommu { "noiommu-" } else { "" };
+        PathBuf::from(format!("/dev/vfio/{}{}", prefix, id))
+    }
 
+    fn new(container: &VfioContainer, id: u32) -> Result<Self> {
         let group_file = OpenOptions::new()
             .read(true)
             .write(true)
-            .open(Path::new(&group_path))
+            .open(Self::path(id, container.noiommu))
             .map_err(VfioError::OpenGroup)?;
 
         let mut group_status = vfio_group_status {
@@ -500,6 +540,26 @@ impl VfioDevice {
         })
     }
 
+    /// Create a new vfio device driven by this process instead of a guest, e.g. a userspace driver
+    /// running inside a VM. `container` must have been opened with `VfioContainer::new_noiommu`.
+    pub fn new_passthrough(sysfspath: &Path, container: Arc<Mutex<VfioContainer>>) -> Result<Self> {
+        let group_id = VfioGroup::get_group_id(sysfspath)?;
+        let group = container.lock().get_noiommu_group(group_id)?;
+        let name_osstr = sysfspath.file_name().ok_or(VfioError::InvalidPath)?;
+        let name_str = name_osstr.to_str().ok_or(VfioError::InvalidPath)?;
+        let name = String::from(name_str);
+        let dev = group.get_device(&name)?;
+        let regions = Self::get_regions(&dev)?;
+
+        Ok(VfioDevice {
+            dev,
+            name,
+            container,
+            group_descriptor: group.as_raw_descriptor(),
+            regions,
+        })
+    }
+
     /// Returns PCI device name, formatted as BUS:DEVICE.FUNCTION string.
     pub fn device_name(&self) -> &String {
         &self.name
@@ -1074,3 +1134,24 @@ impl VfioPciConfig {
             .region_write(VFIO_PCI_CONFIG_REGION_INDEX, &data, offset.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_path() {
+        assert_eq!(VfioGroup::path(5, false), Path::new("/dev/vfio/5"));
+        assert_eq!(VfioGroup::path(5, true), Path::new("/dev/vfio/noiommu-5"));
+    }
+
+    #[test]
+    fn new_noiommu() {
+        match VfioContainer::new_noiommu() {
+            Ok(container) => assert!(container.noiommu),
+            // The host has no VFIO support, so there is no container to open.
+            Err(VfioError::OpenContainer(_)) => {}
+            Err(e) => panic!("failed to open no-IOMMU container: {}", e),
+        }
+    }
+}
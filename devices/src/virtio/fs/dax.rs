@@ -0,0 +1,286 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use base::warn;
+use fuse::filesystem::RemoveMappingOne;
+use fuse::Notifier;
+use sync::Mutex;
+
+// The device starts asking the driver to remove mappings once more than this percentage of the
+// window is mapped...
+const RECLAIM_THRESHOLD_PERCENT: u64 = 90;
+// ... and asks it to remove enough of them to bring the mapped part of the window back down to this
+// percentage.
+const RECLAIM_TARGET_PERCENT: u64 = 75;
+
+struct Mapping {
+    len: u64,
+    // The value of `State::clock` when this range was mapped.
+    last_used: u64,
+    // Whether the driver has already been asked to remove this range.
+    reclaiming: bool,
+}
+
+#[derive(Default)]
+struct State {
+    // Mapped ranges of the window, indexed by their offset.
+    mappings: BTreeMap<u64, Mapping>,
+    // The total length of all ranges in `mappings`.
+    mapped: u64,
+    // The total length of the ranges in `mappings` that the driver has been asked to remove.
+    reclaiming: u64,
+    clock: u64,
+}
+
+impl State {
+    fn insert(&mut self, offset: u64, mapping: Mapping) {
+        self.mapped += mapping.len;
+        if mapping.reclaiming {
+            self.reclaiming += mapping.len;
+        }
+        self.mappings.insert(offset, mapping);
+    }
+
+    // Removes the part of any mapping that overlaps with `offset..offset + len`.
+    fn remove(&mut self, offset: u64, len: u64) {
+        let end = offset.saturating_add(len);
+        let overlapping: Vec<u64> = self
+            .mappings
+            .range(..offset)
+            .next_back()
+            .filter(|(start, m)| **start + m.len > offset)
+            .map(|(&start, _)| start)
+            .into_iter()
+            .chain(self.mappings.range(offset..end).map(|(&start, _)| start))
+            .collect();
+
+        for start in overlapping {
+            let m = match self.mappings.remove(&start) {
+                Some(m) => m,
+                None => continue,
+            };
+            self.mapped -= m.len;
+            if m.reclaiming {
+                self.reclaiming -= m.len;
+            }
+
+            // Keep the parts of the mapping that are outside the removed range.
+            let m_end = start + m.len;
+            if start < offset {
+                self.insert(
+                    start,
+                    Mapping {
+                        len: offset - start,
+                        ..m
+                    },
+                );
+            }
+            if m_end > end {
+                self.insert(
+                    end,
+                    Mapping {
+                        len: m_end - end,
+                        ..m
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Tracks the ranges that are mapped into the DAX window of a virtio-fs device and asks the driver
+/// to remove the least recently used ones before the window fills up.
+///
+/// The device only sees the driver set up and remove mappings, so a range counts as used when it
+/// is mapped. Ranges are reclaimed through the `Notifier` given to `set_notifier`. Without one, the
+/// driver is left to manage the window on its own.
+pub struct DaxWindow {
+    size: u64,
+    state: Mutex<State>,
+    notifier: Mutex<Option<Arc<dyn Notifier>>>,
+}
+
+impl DaxWindow {
+    /// Creates a tracker for a window of `size` bytes with nothing mapped.
+    pub fn new(size: u64) -> DaxWindow {
+        DaxWindow {
+            size,
+            state: Mutex::new(Default::default()),
+            notifier: Mutex::new(None),
+        }
+    }
+
+    /// Sets the notifier used to ask the driver to remove mappings.
+    pub fn set_notifier(&self, notifier: Arc<dyn Notifier>) {
+        *self.notifier.lock() = Some(notifier);
+    }
+
+    /// Returns the number of bytes of the window that are currently mapped.
+    pub fn mapped(&self) -> u64 {
+        self.state.lock().mapped
+    }
+
+    /// Records that `len` bytes at `offset` in the window were mapped, replacing any previous
+    /// mappings in that range, and reclaims the least recently used ranges if the window is
+    /// filling up.
+    pub fn map(&self, offset: u64, len: u64) {
+        {
+            let mut state = self.state.lock();
+            state.remove(offset, len);
+            state.clock += 1;
+            let last_used = state.clock;
+            state.insert(
+                offset,
+                Mapping {
+                    len,
+                    last_used,
+                    reclaiming: false,
+                },
+            );
+        }
+
+        self.reclaim();
+    }
+
+    /// Records that `len` bytes at `offset` in the window were unmapped.
+    pub fn unmap(&self, offset: u64, len: u64) {
+        self.state.lock().remove(offset, len);
+    }
+
+    fn reclaim(&self) {
+        let notifier = match self.notifier.lock().clone() {
+            Some(notifier) => notifier,
+            None => return,
+        };
+
+        let msgs = {
+            let mut state = self.state.lock();
+            let threshold = self.size * RECLAIM_THRESHOLD_PERCENT / 100;
+            let target = self.size * RECLAIM_TARGET_PERCENT / 100;
+            let mut remaining = state.mapped - state.reclaiming;
+            if remaining <= threshold {
+                return;
+            }
+
+            let mut lru: Vec<(u64, u64)> = state
+                .mappings
+                .iter()
+                .filter(|(_, m)| !m.reclaiming)
+                .map(|(&offset, m)| (m.last_used, offset))
+                .collect();
+            lru.sort_unstable();
+
+            let mut msgs = Vec::new();
+            for (_, offset) in lru {
+                if remaining <= target {
+                    break;
+                }
+
+                let m = state.mappings.get_mut(&offset).unwrap();
+                m.reclaiming = true;
+                let len = m.len;
+                state.reclaiming += len;
+                remaining -= len;
+                msgs.push(RemoveMappingOne {
+                    moffset: offset,
+                    len,
+                });
+            }
+            msgs
+        };
+
+        if let Err(e) = notifier.remove_mapping(&msgs) {
+            warn!("failed to reclaim DAX window: {}", e);
+
+            // Try again the next time something is mapped.
+            let mut state = self.state.lock();
+            for msg in msgs {
+                if let Some(m) = state.mappings.get_mut(&msg.moffset) {
+                    if m.reclaiming {
+                        m.reclaiming = false;
+                        let len = m.len;
+                        state.reclaiming -= len;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    const MIB: u64 = 1 << 20;
+
+    struct TestNotifier(Mutex<Vec<(u64, u64)>>);
+
+    impl Notifier for TestNotifier {
+        fn poll_wakeup(&self, _khandle: u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn remove_mapping(&self, msgs: &[RemoveMappingOne]) -> io::Result<()> {
+            self.0
+                .lock()
+                .extend(msgs.iter().map(|msg| (msg.moffset, msg.len)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_unmap() {
+        let window = DaxWindow::new(64 * MIB);
+        window.map(0, 4 * MIB);
+        window.map(8 * MIB, 2 * MIB);
+        assert_eq!(window.mapped(), 6 * MIB);
+
+        window.unmap(MIB, MIB);
+        assert_eq!(window.mapped(), 5 * MIB);
+
+        // Remapping part of a range replaces it.
+        window.map(3 * MIB, 2 * MIB);
+        assert_eq!(window.mapped(), 6 * MIB);
+
+        window.unmap(0, 16 * MIB);
+        assert_eq!(window.mapped(), 0);
+    }
+
+    #[test]
+    fn reclaim_least_recently_mapped() {
+        let window = DaxWindow::new(16 * MIB);
+        let notifier = Arc::new(TestNotifier(Mutex::new(Vec::new())));
+        window.set_notifier(notifier.clone());
+
+        for i in 0..7 {
+            window.map(i * 2 * MIB, 2 * MIB);
+        }
+        // Mapping a range again makes it the most recently used one.
+        window.map(0, 2 * MIB);
+        assert!(notifier.0.lock().is_empty());
+
+        // Filling up the window reclaims the oldest ranges down to the target.
+        window.map(14 * MIB, 2 * MIB);
+        assert_eq!(
+            *notifier.0.lock(),
+            vec![(2 * MIB, 2 * MIB), (4 * MIB, 2 * MIB)]
+        );
+
+        // Ranges that are already being reclaimed aren't requested again.
+        window.map(14 * MIB, 2 * MIB);
+        assert_eq!(notifier.0.lock().len(), 2);
+
+        // The driver removing the ranges makes room in the window.
+        window.unmap(2 * MIB, 4 * MIB);
+        assert_eq!(window.mapped(), 12 * MIB);
+        window.map(2 * MIB, 2 * MIB);
+        window.map(4 * MIB, 2 * MIB);
+        assert_eq!(notifier.0.lock().len(), 4);
+    }
+}
@@ -0,0 +1,627 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A file system that exposes the contents of a disk image as files.
+//!
+//! The root directory contains a `disk` file with the whole virtual disk followed by a `partN`
+//! file for each partition in its GUID partition table, if it has one. Since the disk image is
+//! opened with `disk::create_disk_file`, this works for every image format that crosvm can attach
+//! to a VM, including qcow2 and composite disks. The partition files can then be loop-mounted to
+//! reach the file systems inside of them.
+
+use std::convert::TryInto;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base::{warn, AsRawDescriptors, FileReadWriteAtVolatile, FileSync, RawDescriptor};
+use data_model::VolatileSlice;
+use disk::{DiskFile, DiskGetLen};
+use fuse::filesystem::{
+    Context, DirEntry, DirectoryIterator, Entry, FileSystem, FsOptions, OpenOptions, SetattrValid,
+    ZeroCopyReader, ZeroCopyWriter, ROOT_ID,
+};
+use sync::Mutex;
+
+use crate::virtio::fs::passthrough;
+
+type Inode = u64;
+type Handle = u64;
+
+const SECTOR_SIZE: u64 = 512;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+// The largest partition table that we are willing to read.
+const GPT_MAX_ENTRIES_SIZE: u64 = 1 << 20;
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+fn erofs() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+fn read_exact_at(disk: &mut dyn DiskFile, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    disk.read_exact_at_volatile(VolatileSlice::new(buf), offset)
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// A range of the disk that is exposed as a file.
+struct Region {
+    name: CString,
+    offset: u64,
+    len: u64,
+}
+
+// Returns the partitions in the GUID partition table of `disk`, which is `disk_len` bytes long.
+// Returns an empty list if the disk has no partition table.
+fn read_partitions(disk: &mut dyn DiskFile, disk_len: u64) -> io::Result<Vec<Region>> {
+    if disk_len < 2 * SECTOR_SIZE {
+        return Ok(Vec::new());
+    }
+
+    // The primary header lives in the second sector of the disk.
+    let mut header = [0u8; SECTOR_SIZE as usize];
+    read_exact_at(disk, &mut header, SECTOR_SIZE)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries_lba = le_u64(&header, 72);
+    let num_entries = u64::from(le_u32(&header, 80));
+    let entry_size = u64::from(le_u32(&header, 84));
+    let entries_size = num_entries.saturating_mul(entry_size);
+    if entry_size < 128 || entries_size > GPT_MAX_ENTRIES_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid partition table header",
+        ));
+    }
+
+    let mut entries = vec![0u8; entries_size as usize];
+    read_exact_at(disk, &mut entries, entries_lba.saturating_mul(SECTOR_SIZE))?;
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size as usize).enumerate() {
+        // Unused entries have an all-zero partition type GUID.
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        let first_lba = le_u64(entry, 32);
+        let last_lba = le_u64(entry, 40);
+        let offset = first_lba.saturating_mul(SECTOR_SIZE);
+        let end = last_lba.saturating_add(1).saturating_mul(SECTOR_SIZE);
+        if last_lba < first_lba || end > disk_len {
+            warn!("ignoring partition {} outside of the disk", index + 1);
+            continue;
+        }
+
+        partitions.push(Region {
+            name: CString::new(format!("part{}", index + 1)).unwrap(),
+            offset,
+            len: end - offset,
+        });
+    }
+
+    Ok(partitions)
+}
+
+/// Iterates over the entries of the root directory of a `DiskFs`.
+pub struct DiskDirIter {
+    entries: Vec<(libc::ino64_t, u32, CString)>,
+    offset: u64,
+    next: usize,
+}
+
+impl DirectoryIterator for DiskDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        let (ino, type_, name) = self.entries.get(self.next)?;
+        self.next += 1;
+
+        Some(DirEntry {
+            ino: *ino,
+            offset: self.offset + self.next as u64,
+            type_: *type_,
+            name,
+        })
+    }
+}
+
+/// A file system that serves a disk image and its partitions as regular files. See the module
+/// documentation for details.
+pub struct DiskFs {
+    disk: Mutex<Box<dyn DiskFile>>,
+
+    // The files in the root directory. The file at index `i` has inode `ROOT_ID + 1 + i`.
+    files: Vec<Region>,
+
+    writable: bool,
+
+    // The owner of every file.
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+
+    // The time at which the file system was created, which is used for every timestamp.
+    time: i64,
+
+    cfg: passthrough::Config,
+}
+
+impl DiskFs {
+    /// Creates a file system that serves `disk`. Writes to the files are passed through to `disk`
+    /// if `writable` is true.
+    pub fn new(
+        cfg: passthrough::Config,
+        mut disk: Box<dyn DiskFile>,
+        writable: bool,
+    ) -> io::Result<DiskFs> {
+        if cfg.use_dax {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DAX is not supported by disk file systems",
+            ));
+        }
+
+        let disk_len = disk.get_len()?;
+        let mut files = vec![Region {
+            name: CString::new("disk").unwrap(),
+            offset: 0,
+            len: disk_len,
+        }];
+        files.extend(read_partitions(&mut *disk, disk_len)?);
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        Ok(DiskFs {
+            disk: Mutex::new(disk),
+            files,
+            writable,
+            // Safe because these functions have no preconditions and can't fail.
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            time,
+            cfg,
+        })
+    }
+
+    pub fn cfg(&self) -> &passthrough::Config {
+        &self.cfg
+    }
+
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.disk.lock().as_raw_descriptors()
+    }
+
+    fn region(&self, inode: Inode) -> io::Result<&Region> {
+        inode
+            .checked_sub(ROOT_ID + 1)
+            .and_then(|index| self.files.get(index as usize))
+            .ok_or_else(ebadf)
+    }
+
+    fn attr(&self, inode: Inode) -> io::Result<libc::stat64> {
+        // Safe because this is a plain C struct for which all zeroes is a valid value.
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_ino = inode;
+        if inode == ROOT_ID {
+            attr.st_mode = libc::S_IFDIR | 0o755;
+            attr.st_nlink = 2;
+        } else {
+            let region = self.region(inode)?;
+            let perms = if self.writable { 0o644 } else { 0o444 };
+            attr.st_mode = libc::S_IFREG | perms;
+            attr.st_nlink = 1;
+            attr.st_size = region.len as libc::off64_t;
+            attr.st_blocks = ((region.len + 511) / 512) as libc::blkcnt64_t;
+        }
+        attr.st_uid = self.uid;
+        attr.st_gid = self.gid;
+        attr.st_blksize = SECTOR_SIZE as libc::blksize_t;
+        attr.st_atime = self.time;
+        attr.st_mtime = self.time;
+        attr.st_ctime = self.time;
+        Ok(attr)
+    }
+
+    // Returns the number of bytes of a request for `size` bytes at `offset` of `region` that are
+    // within the region.
+    fn clamp(region: &Region, size: u32, offset: u64) -> usize {
+        if offset >= region.len {
+            0
+        } else {
+            std::cmp::min(u64::from(size), region.len - offset) as usize
+        }
+    }
+}
+
+impl FileSystem for DiskFs {
+    type Inode = Inode;
+    type Handle = Handle;
+    type DirIter = DiskDirIter;
+
+    fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+        Ok(FsOptions::empty())
+    }
+
+    fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        if parent != ROOT_ID {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        let index = self
+            .files
+            .iter()
+            .position(|region| region.name.as_c_str() == name)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+
+        let inode = ROOT_ID + 1 + index as u64;
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.attr(inode)?,
+            attr_timeout: self.cfg.attr_timeout,
+            entry_timeout: self.cfg.entry_timeout,
+        })
+    }
+
+    fn getattr(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        Ok((self.attr(inode)?, self.cfg.attr_timeout))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        attr: libc::stat64,
+        _handle: Option<Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let current = self.attr(inode)?;
+
+        // The files have a fixed size and owner. A truncate to the current size is still allowed
+        // since that is what opening a file with O_TRUNC does.
+        if valid.intersects(SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID)
+            || (valid.contains(SetattrValid::SIZE) && attr.st_size != current.st_size)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        // There is nowhere to store timestamps so changes to them are silently dropped.
+        Ok((current, self.cfg.attr_timeout))
+    }
+
+    fn open(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        self.region(inode)?;
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY && !self.writable {
+            return Err(erofs());
+        }
+
+        Ok((None, OpenOptions::empty()))
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        mut w: W,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        let region = self.region(inode)?;
+        let len = DiskFs::clamp(region, size, offset);
+
+        // Disk images aren't necessarily plain files so the data has to be copied through a buffer.
+        let mut buf = vec![0u8; len];
+        read_exact_at(&mut **self.disk.lock(), &mut buf, region.offset + offset)?;
+        w.write_all(&buf)?;
+
+        Ok(len)
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        mut r: R,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        if !self.writable {
+            return Err(erofs());
+        }
+
+        let region = self.region(inode)?;
+        let len = DiskFs::clamp(region, size, offset);
+        if len == 0 && size != 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)?;
+        self.disk
+            .lock()
+            .write_all_at_volatile(VolatileSlice::new(&mut buf), region.offset + offset)?;
+
+        Ok(len)
+    }
+
+    fn flush(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _handle: Handle,
+        _lock_owner: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn fsync(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _datasync: bool,
+        _handle: Handle,
+    ) -> io::Result<()> {
+        self.region(inode)?;
+        if self.writable {
+            self.disk.lock().fsync()?;
+        }
+        Ok(())
+    }
+
+    fn release(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _flags: u32,
+        _handle: Handle,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn statfs(&self, _ctx: Context, _inode: Inode) -> io::Result<libc::statvfs64> {
+        // Safe because this is a plain C struct for which all zeroes is a valid value.
+        let mut st: libc::statvfs64 = unsafe { std::mem::zeroed() };
+        st.f_bsize = SECTOR_SIZE;
+        st.f_frsize = SECTOR_SIZE;
+        st.f_blocks = self.files[0].len / SECTOR_SIZE;
+        st.f_files = self.files.len() as u64 + 1;
+        st.f_namemax = 255;
+        if !self.writable {
+            st.f_flag = libc::ST_RDONLY;
+        }
+
+        Ok(st)
+    }
+
+    fn opendir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        if inode != ROOT_ID {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        Ok((None, OpenOptions::CACHE_DIR))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<Self::DirIter> {
+        if inode != ROOT_ID {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let dir_type = libc::DT_DIR as u32;
+        let dots = vec![
+            (ROOT_ID, dir_type, CString::new(".").unwrap()),
+            (ROOT_ID, dir_type, CString::new("..").unwrap()),
+        ];
+        let files = self.files.iter().enumerate().map(|(index, region)| {
+            (
+                ROOT_ID + 1 + index as u64,
+                libc::DT_REG as u32,
+                region.name.clone(),
+            )
+        });
+
+        Ok(DiskDirIter {
+            entries: dots
+                .into_iter()
+                .chain(files)
+                .skip(offset as usize)
+                .collect(),
+            offset,
+            next: 0,
+        })
+    }
+
+    fn releasedir(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _flags: u32,
+        _handle: Handle,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn access(&self, _ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
+        self.attr(inode)?;
+        if mask as i32 & libc::W_OK != 0 && !self.writable {
+            return Err(erofs());
+        }
+
+        // The client checks permissions itself.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    const CTX: Context = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    struct VecWriter(Vec<u8>);
+
+    impl io::Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+            let mut buf = vec![0; count];
+            let count = f.read_at(&mut buf, off)?;
+            self.0.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+    }
+
+    // Builds a 64 KiB raw disk with a partition table containing partitions 1 and 3.
+    fn test_disk() -> File {
+        let mut disk = tempfile::tempfile().expect("failed to create disk");
+        disk.write_all(&[0u8; 64 << 10]).unwrap();
+
+        let mut header = [0u8; SECTOR_SIZE as usize];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        disk.write_all_at(&header, SECTOR_SIZE).unwrap();
+
+        let mut entry = [0u8; 128];
+        entry[0] = 1;
+        entry[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&35u64.to_le_bytes());
+        disk.write_all_at(&entry, 2 * SECTOR_SIZE).unwrap();
+        entry[32..40].copy_from_slice(&36u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&36u64.to_le_bytes());
+        disk.write_all_at(&entry, 2 * SECTOR_SIZE + 2 * 128)
+            .unwrap();
+
+        disk.write_all_at(b"partition data", 34 * SECTOR_SIZE)
+            .unwrap();
+        disk
+    }
+
+    fn read(fs: &DiskFs, inode: Inode, size: u32, offset: u64) -> Vec<u8> {
+        let mut w = VecWriter(Vec::new());
+        fs.read(CTX, inode, 0, &mut w, size, offset, None, 0)
+            .expect("failed to read file");
+        w.0
+    }
+
+    #[test]
+    fn partitions() {
+        let fs = DiskFs::new(Default::default(), Box::new(test_disk()), false)
+            .expect("failed to create file system");
+
+        let mut iter = fs.readdir(CTX, ROOT_ID, 0, 4096, 0).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = iter.next() {
+            names.push(entry.name.to_str().unwrap().to_owned());
+        }
+        assert_eq!(names, vec![".", "..", "disk", "part1", "part3"]);
+
+        let disk = fs
+            .lookup(CTX, ROOT_ID, &CString::new("disk").unwrap())
+            .unwrap();
+        assert_eq!(disk.attr.st_size, 64 << 10);
+
+        let part1 = fs
+            .lookup(CTX, ROOT_ID, &CString::new("part1").unwrap())
+            .unwrap();
+        assert_eq!(part1.attr.st_size, 2 * SECTOR_SIZE as i64);
+        assert_eq!(read(&fs, part1.inode, 14, 0), b"partition data");
+        // Reads are clamped to the end of the partition.
+        assert_eq!(read(&fs, part1.inode, 4096, 1020).len(), 4);
+
+        let part3 = fs
+            .lookup(CTX, ROOT_ID, &CString::new("part3").unwrap())
+            .unwrap();
+        assert_eq!(part3.attr.st_size, SECTOR_SIZE as i64);
+    }
+
+    #[test]
+    fn read_only() {
+        let fs = DiskFs::new(Default::default(), Box::new(test_disk()), false)
+            .expect("failed to create file system");
+        let disk = fs
+            .lookup(CTX, ROOT_ID, &CString::new("disk").unwrap())
+            .unwrap();
+
+        assert_eq!(
+            fs.open(CTX, disk.inode, libc::O_RDWR as u32)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EROFS)
+        );
+    }
+
+    #[test]
+    fn no_partition_table() {
+        let mut disk = tempfile::tempfile().expect("failed to create disk");
+        disk.write_all(&[0u8; 4096]).unwrap();
+        let fs = DiskFs::new(Default::default(), Box::new(disk), true)
+            .expect("failed to create file system");
+
+        assert_eq!(fs.files.len(), 1);
+        assert!(fs
+            .lookup(CTX, ROOT_ID, &CString::new("part1").unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,1304 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A file system whose entire tree lives in memory.
+//!
+//! The tree can be populated from a tar archive, in which case the contents of files are read
+//! directly from the archive until they are first modified. A read-only `MemFs` serves an archive
+//! without unpacking it anywhere while a writable one behaves like a tmpfs whose changes are
+//! discarded when the VM exits.
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base::{warn, AsRawDescriptor, RawDescriptor};
+use fuse::filesystem::{
+    Context, DirEntry, DirectoryIterator, Entry, FileSystem, FsOptions, OpenOptions, SetattrValid,
+    ZeroCopyReader, ZeroCopyWriter, ROOT_ID,
+};
+use sync::Mutex;
+
+use crate::virtio::fs::passthrough;
+use crate::virtio::fs::tar::{self, EntryKind};
+
+type Inode = u64;
+type Handle = u64;
+
+const BLOCK_SIZE: u64 = 4096;
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+fn enoent() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+fn erofs() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+fn now() -> (i64, i64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() as i64, i64::from(now.subsec_nanos()))
+}
+
+fn new_attr(mode: u32, uid: u32, gid: u32, mtime: i64) -> libc::stat64 {
+    // Safe because this is a plain C struct for which all zeroes is a valid value.
+    let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+    attr.st_mode = mode;
+    attr.st_nlink = if mode & libc::S_IFMT == libc::S_IFDIR {
+        2
+    } else {
+        1
+    };
+    attr.st_uid = uid;
+    attr.st_gid = gid;
+    attr.st_blksize = BLOCK_SIZE as libc::blksize_t;
+    attr.st_atime = mtime;
+    attr.st_mtime = mtime;
+    attr.st_ctime = mtime;
+    attr
+}
+
+fn set_size(attr: &mut libc::stat64, size: u64) {
+    attr.st_size = size as libc::off64_t;
+    attr.st_blocks = ((size + 511) / 512) as libc::blkcnt64_t;
+}
+
+fn touch(attr: &mut libc::stat64) {
+    let (secs, nsecs) = now();
+    attr.st_mtime = secs;
+    attr.st_mtime_nsec = nsecs;
+    attr.st_ctime = secs;
+    attr.st_ctime_nsec = nsecs;
+}
+
+fn is_dir(attr: &libc::stat64) -> bool {
+    attr.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+enum FileData {
+    /// The contents are at this offset in the archive.
+    Archive(u64),
+    Memory(Vec<u8>),
+}
+
+enum NodeData {
+    Dir {
+        parent: Inode,
+        entries: BTreeMap<CString, Inode>,
+    },
+    File(FileData),
+    Symlink(Vec<u8>),
+    Special,
+}
+
+struct Node {
+    attr: libc::stat64,
+    data: NodeData,
+    // The number of lookups by the client that it hasn't forgotten yet.
+    lookups: u64,
+}
+
+struct Tree {
+    nodes: BTreeMap<Inode, Node>,
+    next_inode: Inode,
+}
+
+impl Tree {
+    fn new() -> Tree {
+        let mut nodes = BTreeMap::new();
+        let mut attr = new_attr(libc::S_IFDIR | 0o755, 0, 0, now().0);
+        attr.st_ino = ROOT_ID;
+        nodes.insert(
+            ROOT_ID,
+            Node {
+                attr,
+                data: NodeData::Dir {
+                    parent: ROOT_ID,
+                    entries: BTreeMap::new(),
+                },
+                // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
+                lookups: 2,
+            },
+        );
+
+        Tree {
+            nodes,
+            next_inode: ROOT_ID + 1,
+        }
+    }
+
+    fn node(&self, inode: Inode) -> io::Result<&Node> {
+        self.nodes.get(&inode).ok_or_else(ebadf)
+    }
+
+    fn node_mut(&mut self, inode: Inode) -> io::Result<&mut Node> {
+        self.nodes.get_mut(&inode).ok_or_else(ebadf)
+    }
+
+    fn entries(&self, dir: Inode) -> io::Result<&BTreeMap<CString, Inode>> {
+        match &self.node(dir)?.data {
+            NodeData::Dir { entries, .. } => Ok(entries),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: Inode) -> io::Result<&mut BTreeMap<CString, Inode>> {
+        match &mut self.node_mut(dir)?.data {
+            NodeData::Dir { entries, .. } => Ok(entries),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn child(&self, dir: Inode, name: &CStr) -> io::Result<Option<Inode>> {
+        Ok(self.entries(dir)?.get(name).copied())
+    }
+
+    // Adds a new node named `name` to `dir`, which must not already contain `name`.
+    fn add(&mut self, dir: Inode, name: &CStr, mut attr: libc::stat64, data: NodeData) -> Inode {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        attr.st_ino = inode;
+        let data = match data {
+            NodeData::Dir { entries, .. } => NodeData::Dir {
+                parent: dir,
+                entries,
+            },
+            data => data,
+        };
+        let child_is_dir = is_dir(&attr);
+        self.nodes.insert(
+            inode,
+            Node {
+                attr,
+                data,
+                lookups: 0,
+            },
+        );
+
+        if let Ok(parent) = self.node_mut(dir) {
+            if child_is_dir {
+                parent.attr.st_nlink += 1;
+            }
+            touch(&mut parent.attr);
+            if let NodeData::Dir { entries, .. } = &mut parent.data {
+                entries.insert(name.to_owned(), inode);
+            }
+        }
+
+        inode
+    }
+
+    // Drops a link to `inode` and frees it once nothing refers to it anymore.
+    fn unlink(&mut self, inode: Inode) {
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            node.attr.st_nlink = if is_dir(&node.attr) {
+                0
+            } else {
+                node.attr.st_nlink.saturating_sub(1)
+            };
+            touch(&mut node.attr);
+        }
+        self.maybe_free(inode);
+    }
+
+    fn maybe_free(&mut self, inode: Inode) {
+        let unused = self
+            .nodes
+            .get(&inode)
+            .map_or(false, |node| node.attr.st_nlink == 0 && node.lookups == 0);
+        if unused && inode != ROOT_ID {
+            self.nodes.remove(&inode);
+        }
+    }
+
+    // Removes `name` from `dir` without checking whether that is allowed.
+    fn remove_entry(&mut self, dir: Inode, name: &CStr) -> io::Result<Inode> {
+        let inode = self.entries_mut(dir)?.remove(name).ok_or_else(enoent)?;
+        let child_is_dir = is_dir(&self.node(inode)?.attr);
+        let parent = self.node_mut(dir)?;
+        if child_is_dir {
+            parent.attr.st_nlink -= 1;
+        }
+        touch(&mut parent.attr);
+        Ok(inode)
+    }
+
+    fn lookup_path(&self, path: &[&[u8]]) -> Option<Inode> {
+        path.iter().try_fold(ROOT_ID, |dir, name| {
+            let name = CString::new(*name).ok()?;
+            self.entries(dir).ok()?.get(&name).copied()
+        })
+    }
+
+    fn insert_archive_entry(&mut self, entry: &tar::Entry) -> io::Result<()> {
+        let mut components: Vec<&[u8]> = entry
+            .path
+            .split(|&b| b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .collect();
+        if components.iter().any(|c| *c == b"..") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive entry path leaves the archive",
+            ));
+        }
+
+        let kind_mode = match entry.kind {
+            EntryKind::File | EntryKind::HardLink => libc::S_IFREG,
+            EntryKind::Symlink => libc::S_IFLNK,
+            EntryKind::CharDevice => libc::S_IFCHR,
+            EntryKind::BlockDevice => libc::S_IFBLK,
+            EntryKind::Directory => libc::S_IFDIR,
+            EntryKind::Fifo => libc::S_IFIFO,
+        };
+        let mut attr = new_attr(kind_mode | entry.mode, entry.uid, entry.gid, entry.mtime);
+
+        let name = match components.pop() {
+            Some(name) => CString::new(name)?,
+            None => {
+                // An entry for the root directory itself.
+                if entry.kind == EntryKind::Directory {
+                    let root = self.node_mut(ROOT_ID)?;
+                    attr.st_ino = ROOT_ID;
+                    attr.st_nlink = root.attr.st_nlink;
+                    root.attr = attr;
+                }
+                return Ok(());
+            }
+        };
+
+        // Parent directories don't have to appear in the archive before their contents.
+        let mut dir = ROOT_ID;
+        for component in components {
+            let component = CString::new(component)?;
+            dir = match self.child(dir, &component)? {
+                Some(inode) => inode,
+                None => self.add(
+                    dir,
+                    &component,
+                    new_attr(libc::S_IFDIR | 0o755, entry.uid, entry.gid, entry.mtime),
+                    NodeData::Dir {
+                        parent: dir,
+                        entries: BTreeMap::new(),
+                    },
+                ),
+            };
+        }
+
+        // Later entries replace earlier ones, except that directories are merged.
+        if let Some(existing) = self.child(dir, &name)? {
+            let node = self.node_mut(existing)?;
+            if entry.kind == EntryKind::Directory && is_dir(&node.attr) {
+                attr.st_ino = existing;
+                attr.st_nlink = node.attr.st_nlink;
+                node.attr = attr;
+                return Ok(());
+            }
+            self.remove_entry(dir, &name)?;
+            self.unlink(existing);
+        }
+
+        let data = match entry.kind {
+            EntryKind::File => {
+                set_size(&mut attr, entry.size);
+                NodeData::File(FileData::Archive(entry.data_offset))
+            }
+            EntryKind::HardLink => {
+                let target: Vec<&[u8]> = entry
+                    .link_target
+                    .split(|&b| b == b'/')
+                    .filter(|c| !c.is_empty() && *c != b".")
+                    .collect();
+                let target = self
+                    .lookup_path(&target)
+                    .filter(|&inode| self.node(inode).map_or(false, |n| !is_dir(&n.attr)))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "archive hard link target does not exist",
+                        )
+                    })?;
+                self.node_mut(target)?.attr.st_nlink += 1;
+                self.entries_mut(dir)?.insert(name, target);
+                return Ok(());
+            }
+            EntryKind::Symlink => {
+                set_size(&mut attr, entry.link_target.len() as u64);
+                NodeData::Symlink(entry.link_target.clone())
+            }
+            EntryKind::Directory => NodeData::Dir {
+                parent: dir,
+                entries: BTreeMap::new(),
+            },
+            EntryKind::CharDevice | EntryKind::BlockDevice | EntryKind::Fifo => {
+                attr.st_rdev = libc::makedev(entry.dev_major, entry.dev_minor);
+                NodeData::Special
+            }
+        };
+        self.add(dir, &name, attr, data);
+
+        Ok(())
+    }
+}
+
+/// Iterates over a snapshot of the entries of a `MemFs` directory.
+pub struct MemDirIter {
+    entries: Vec<(libc::ino64_t, u32, CString)>,
+    offset: u64,
+    next: usize,
+}
+
+impl DirectoryIterator for MemDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        let (ino, type_, name) = self.entries.get(self.next)?;
+        self.next += 1;
+
+        Some(DirEntry {
+            ino: *ino,
+            offset: self.offset + self.next as u64,
+            type_: *type_,
+            name,
+        })
+    }
+}
+
+/// A file system that keeps its whole tree in memory, optionally reading file contents from a tar
+/// archive. See the module documentation for details.
+pub struct MemFs {
+    tree: Mutex<Tree>,
+
+    // The archive that the tree was populated from.
+    archive: Option<Mutex<File>>,
+
+    writable: bool,
+
+    cfg: passthrough::Config,
+}
+
+impl MemFs {
+    /// Creates an empty, writable file system.
+    pub fn new(cfg: passthrough::Config) -> io::Result<MemFs> {
+        MemFs::with_tree(cfg, Tree::new(), None, true)
+    }
+
+    /// Creates a file system with the contents of the tar `archive`. The archive is never modified,
+    /// even if `writable` is true.
+    pub fn from_archive(
+        cfg: passthrough::Config,
+        archive: File,
+        writable: bool,
+    ) -> io::Result<MemFs> {
+        let mut tree = Tree::new();
+        for entry in tar::read_entries(&archive)? {
+            if let Err(e) = tree.insert_archive_entry(&entry) {
+                warn!(
+                    "skipping archive entry {}: {}",
+                    String::from_utf8_lossy(&entry.path),
+                    e
+                );
+            }
+        }
+
+        MemFs::with_tree(cfg, tree, Some(archive), writable)
+    }
+
+    fn with_tree(
+        cfg: passthrough::Config,
+        tree: Tree,
+        archive: Option<File>,
+        writable: bool,
+    ) -> io::Result<MemFs> {
+        if cfg.use_dax {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DAX is not supported by in-memory file systems",
+            ));
+        }
+
+        Ok(MemFs {
+            tree: Mutex::new(tree),
+            archive: archive.map(Mutex::new),
+            writable,
+            cfg,
+        })
+    }
+
+    pub fn cfg(&self) -> &passthrough::Config {
+        &self.cfg
+    }
+
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.archive
+            .iter()
+            .map(|archive| archive.lock().as_raw_descriptor())
+            .collect()
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(erofs())
+        }
+    }
+
+    fn entry(&self, node: &mut Node) -> Entry {
+        node.lookups += 1;
+        Entry {
+            inode: node.attr.st_ino,
+            generation: 0,
+            attr: node.attr,
+            attr_timeout: self.cfg.attr_timeout,
+            entry_timeout: self.cfg.entry_timeout,
+        }
+    }
+
+    // Moves the contents of a file out of the archive so that they can be modified.
+    fn file_contents<'a>(&self, node: &'a mut Node) -> io::Result<&'a mut Vec<u8>> {
+        let data = match &mut node.data {
+            NodeData::File(data) => data,
+            NodeData::Dir { .. } => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        if let FileData::Archive(offset) = *data {
+            let archive = self.archive.as_ref().ok_or_else(ebadf)?;
+            let mut contents = vec![0; node.attr.st_size as usize];
+            archive.lock().read_exact_at(&mut contents, offset)?;
+            *data = FileData::Memory(contents);
+        }
+
+        match data {
+            FileData::Memory(contents) => Ok(contents),
+            FileData::Archive(_) => unreachable!(),
+        }
+    }
+
+    fn create_node(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        data: NodeData,
+    ) -> io::Result<Entry> {
+        self.check_writable()?;
+
+        let mut tree = self.tree.lock();
+        if tree.child(parent, name)?.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let mut attr = new_attr(mode, ctx.uid, ctx.gid, 0);
+        let (secs, nsecs) = now();
+        attr.st_atime = secs;
+        attr.st_atime_nsec = nsecs;
+        touch(&mut attr);
+        if let NodeData::Symlink(target) = &data {
+            set_size(&mut attr, target.len() as u64);
+        }
+
+        let inode = tree.add(parent, name, attr, data);
+        let node = tree.node_mut(inode)?;
+        Ok(self.entry(node))
+    }
+}
+
+impl FileSystem for MemFs {
+    type Inode = Inode;
+    type Handle = Handle;
+    type DirIter = MemDirIter;
+
+    fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+        Ok(FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO)
+    }
+
+    fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        let mut tree = self.tree.lock();
+        let inode = tree.child(parent, name)?.ok_or_else(enoent)?;
+        let node = tree.node_mut(inode)?;
+        Ok(self.entry(node))
+    }
+
+    fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
+        let mut tree = self.tree.lock();
+        if let Ok(node) = tree.node_mut(inode) {
+            node.lookups = node.lookups.saturating_sub(count);
+            tree.maybe_free(inode);
+        }
+    }
+
+    fn getattr(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let tree = self.tree.lock();
+        Ok((tree.node(inode)?.attr, self.cfg.attr_timeout))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        attr: libc::stat64,
+        _handle: Option<Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        self.check_writable()?;
+
+        let mut tree = self.tree.lock();
+        let node = tree.node_mut(inode)?;
+
+        if valid.contains(SetattrValid::SIZE) {
+            let size = attr.st_size as u64;
+            self.file_contents(node)?.resize(size as usize, 0);
+            set_size(&mut node.attr, size);
+            touch(&mut node.attr);
+        }
+        if valid.contains(SetattrValid::MODE) {
+            node.attr.st_mode = (node.attr.st_mode & libc::S_IFMT) | (attr.st_mode & 0o7777);
+        }
+        if valid.contains(SetattrValid::UID) {
+            node.attr.st_uid = attr.st_uid;
+        }
+        if valid.contains(SetattrValid::GID) {
+            node.attr.st_gid = attr.st_gid;
+        }
+
+        let (secs, nsecs) = now();
+        if valid.contains(SetattrValid::ATIME_NOW) {
+            node.attr.st_atime = secs;
+            node.attr.st_atime_nsec = nsecs;
+        } else if valid.contains(SetattrValid::ATIME) {
+            node.attr.st_atime = attr.st_atime;
+            node.attr.st_atime_nsec = attr.st_atime_nsec;
+        }
+        if valid.contains(SetattrValid::MTIME_NOW) {
+            node.attr.st_mtime = secs;
+            node.attr.st_mtime_nsec = nsecs;
+        } else if valid.contains(SetattrValid::MTIME) {
+            node.attr.st_mtime = attr.st_mtime;
+            node.attr.st_mtime_nsec = attr.st_mtime_nsec;
+        }
+        node.attr.st_ctime = secs;
+        node.attr.st_ctime_nsec = nsecs;
+
+        Ok((node.attr, self.cfg.attr_timeout))
+    }
+
+    fn readlink(&self, _ctx: Context, inode: Inode) -> io::Result<Vec<u8>> {
+        match &self.tree.lock().node(inode)?.data {
+            NodeData::Symlink(target) => Ok(target.clone()),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn symlink(
+        &self,
+        ctx: Context,
+        linkname: &CStr,
+        parent: Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        self.create_node(
+            ctx,
+            parent,
+            name,
+            libc::S_IFLNK | 0o777,
+            NodeData::Symlink(linkname.to_bytes().to_vec()),
+        )
+    }
+
+    fn mknod(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        let data = match mode & libc::S_IFMT {
+            libc::S_IFREG => NodeData::File(FileData::Memory(Vec::new())),
+            libc::S_IFDIR => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            _ => NodeData::Special,
+        };
+        let is_special = matches!(data, NodeData::Special);
+        let entry = self.create_node(ctx, parent, name, mode & !umask, data)?;
+        if !is_special {
+            return Ok(entry);
+        }
+
+        let mut tree = self.tree.lock();
+        let node = tree.node_mut(entry.inode)?;
+        node.attr.st_rdev = u64::from(rdev);
+        Ok(Entry {
+            attr: node.attr,
+            ..entry
+        })
+    }
+
+    fn mkdir(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.create_node(
+            ctx,
+            parent,
+            name,
+            libc::S_IFDIR | (mode & !umask & 0o7777),
+            NodeData::Dir {
+                parent,
+                entries: BTreeMap::new(),
+            },
+        )
+    }
+
+    fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
+
+        let mut tree = self.tree.lock();
+        let inode = tree.child(parent, name)?.ok_or_else(enoent)?;
+        if is_dir(&tree.node(inode)?.attr) {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        tree.remove_entry(parent, name)?;
+        tree.unlink(inode);
+
+        Ok(())
+    }
+
+    fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
+
+        let mut tree = self.tree.lock();
+        let inode = tree.child(parent, name)?.ok_or_else(enoent)?;
+        if !tree.entries(inode)?.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        tree.remove_entry(parent, name)?;
+        tree.unlink(inode);
+
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        _ctx: Context,
+        olddir: Inode,
+        oldname: &CStr,
+        newdir: Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.check_writable()?;
+        if flags & !(libc::RENAME_NOREPLACE as u32) != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut tree = self.tree.lock();
+        let inode = tree.child(olddir, oldname)?.ok_or_else(enoent)?;
+        let src_is_dir = is_dir(&tree.node(inode)?.attr);
+
+        if let Some(existing) = tree.child(newdir, newname)? {
+            if existing == inode {
+                return Ok(());
+            }
+            if flags & libc::RENAME_NOREPLACE as u32 != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            match (src_is_dir, is_dir(&tree.node(existing)?.attr)) {
+                (true, true) => {
+                    if !tree.entries(existing)?.is_empty() {
+                        return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+                    }
+                }
+                (true, false) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                (false, true) => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                (false, false) => {}
+            }
+        }
+
+        if src_is_dir {
+            // A directory can't be moved into itself.
+            let mut dir = newdir;
+            while dir != ROOT_ID {
+                if dir == inode {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                dir = match &tree.node(dir)?.data {
+                    NodeData::Dir { parent, .. } => *parent,
+                    _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                };
+            }
+        }
+
+        // Make sure that the new directory exists before changing anything.
+        tree.entries(newdir)?;
+        if let Some(existing) = tree.child(newdir, newname)? {
+            tree.remove_entry(newdir, newname)?;
+            tree.unlink(existing);
+        }
+        tree.remove_entry(olddir, oldname)?;
+
+        let node = tree.node_mut(inode)?;
+        touch(&mut node.attr);
+        if let NodeData::Dir { parent, .. } = &mut node.data {
+            *parent = newdir;
+        }
+        let newparent = tree.node_mut(newdir)?;
+        if src_is_dir {
+            newparent.attr.st_nlink += 1;
+        }
+        touch(&mut newparent.attr);
+        tree.entries_mut(newdir)?.insert(newname.to_owned(), inode);
+
+        Ok(())
+    }
+
+    fn link(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        newparent: Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        self.check_writable()?;
+
+        let mut tree = self.tree.lock();
+        if is_dir(&tree.node(inode)?.attr) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        if tree.child(newparent, newname)?.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        tree.entries_mut(newparent)?
+            .insert(newname.to_owned(), inode);
+        touch(&mut tree.node_mut(newparent)?.attr);
+
+        let node = tree.node_mut(inode)?;
+        node.attr.st_nlink += 1;
+        let (secs, nsecs) = now();
+        node.attr.st_ctime = secs;
+        node.attr.st_ctime_nsec = nsecs;
+        Ok(self.entry(node))
+    }
+
+    fn open(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let flags = flags as i32;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            self.check_writable()?;
+        }
+
+        let mut tree = self.tree.lock();
+        let node = tree.node_mut(inode)?;
+        if flags & libc::O_TRUNC != 0 {
+            self.file_contents(node)?.clear();
+            set_size(&mut node.attr, 0);
+            touch(&mut node.attr);
+        }
+
+        // Nothing else can change the contents of a read-only file system.
+        let opts = if self.writable {
+            OpenOptions::empty()
+        } else {
+            OpenOptions::KEEP_CACHE
+        };
+        Ok((None, opts))
+    }
+
+    fn create(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        _flags: u32,
+        umask: u32,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        let entry = self.create_node(
+            ctx,
+            parent,
+            name,
+            libc::S_IFREG | (mode & !umask & 0o7777),
+            NodeData::File(FileData::Memory(Vec::new())),
+        )?;
+
+        Ok((entry, None, OpenOptions::empty()))
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        mut w: W,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        let tree = self.tree.lock();
+        let node = tree.node(inode)?;
+        let file_size = node.attr.st_size as u64;
+        if offset >= file_size {
+            return Ok(0);
+        }
+        let len = std::cmp::min(u64::from(size), file_size - offset) as usize;
+
+        match &node.data {
+            NodeData::File(FileData::Memory(contents)) => {
+                let start = offset as usize;
+                w.write_all(&contents[start..start + len])?;
+            }
+            NodeData::File(FileData::Archive(archive_offset)) => {
+                let archive_offset = *archive_offset;
+                drop(tree);
+
+                let archive = self.archive.as_ref().ok_or_else(ebadf)?;
+                w.write_all_from(&mut archive.lock(), len, archive_offset + offset)?;
+            }
+            NodeData::Dir { .. } => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+
+        Ok(len)
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        mut r: R,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        self.check_writable()?;
+
+        let mut tree = self.tree.lock();
+        let node = tree.node_mut(inode)?;
+        let contents = self.file_contents(node)?;
+
+        let start = offset as usize;
+        let end = start
+            .checked_add(size as usize)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?;
+        if end > contents.len() {
+            contents.resize(end, 0);
+        }
+        r.read_exact(&mut contents[start..end])?;
+
+        let len = contents.len() as u64;
+        set_size(&mut node.attr, len);
+        touch(&mut node.attr);
+
+        Ok(size as usize)
+    }
+
+    fn flush(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _handle: Handle,
+        _lock_owner: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn fsync(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _datasync: bool,
+        _handle: Handle,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn release(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _flags: u32,
+        _handle: Handle,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn statfs(&self, _ctx: Context, _inode: Inode) -> io::Result<libc::statvfs64> {
+        let tree = self.tree.lock();
+
+        // Safe because this is a plain C struct for which all zeroes is a valid value.
+        let mut st: libc::statvfs64 = unsafe { std::mem::zeroed() };
+        st.f_bsize = BLOCK_SIZE;
+        st.f_frsize = BLOCK_SIZE;
+        st.f_namemax = 255;
+
+        // Like a tmpfs without a size limit, a writable file system reports no sizes at all.
+        if !self.writable {
+            st.f_blocks = tree
+                .nodes
+                .values()
+                .map(|node| (node.attr.st_size as u64 + BLOCK_SIZE - 1) / BLOCK_SIZE)
+                .sum();
+            st.f_files = tree.nodes.len() as u64;
+            st.f_flag = libc::ST_RDONLY;
+        }
+
+        Ok(st)
+    }
+
+    fn opendir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        self.tree.lock().entries(inode)?;
+
+        let opts = if self.writable {
+            OpenOptions::empty()
+        } else {
+            OpenOptions::CACHE_DIR
+        };
+        Ok((None, opts))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<Self::DirIter> {
+        let tree = self.tree.lock();
+        let node = tree.node(inode)?;
+        let (parent, entries) = match &node.data {
+            NodeData::Dir { parent, entries } => (*parent, entries),
+            _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        };
+
+        let dir_type = libc::DT_DIR as u32;
+        let dots = vec![
+            (inode, dir_type, CString::new(".").unwrap()),
+            (parent, dir_type, CString::new("..").unwrap()),
+        ];
+        let children = entries.iter().filter_map(|(name, &child)| {
+            let mode = tree.node(child).ok()?.attr.st_mode;
+            Some((child, (mode & libc::S_IFMT) >> 12, name.clone()))
+        });
+
+        Ok(MemDirIter {
+            entries: dots
+                .into_iter()
+                .chain(children)
+                .skip(offset as usize)
+                .collect(),
+            offset,
+            next: 0,
+        })
+    }
+
+    fn fsyncdir(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _datasync: bool,
+        _handle: Handle,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn releasedir(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _flags: u32,
+        _handle: Handle,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn access(&self, _ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
+        self.tree.lock().node(inode)?;
+        if mask as i32 & libc::W_OK != 0 {
+            self.check_writable()?;
+        }
+
+        // The client checks permissions itself.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::ffi::OsStrExt;
+
+    use crate::virtio::fs::tar::tests::TarBuilder;
+
+    const CTX: Context = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    struct VecWriter(Vec<u8>);
+
+    impl io::Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+            let mut buf = vec![0; count];
+            let count = f.read_at(&mut buf, off)?;
+            self.0.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+    }
+
+    struct SliceReader<'a>(&'a [u8]);
+
+    impl<'a> io::Read for SliceReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            io::Read::read(&mut self.0, buf)
+        }
+    }
+
+    impl<'a> ZeroCopyReader for SliceReader<'a> {
+        fn read_to(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+            let count = std::cmp::min(count, self.0.len());
+            let written = f.write_at(&self.0[..count], off)?;
+            self.0 = &self.0[written..];
+            Ok(written)
+        }
+    }
+
+    fn test_archive() -> File {
+        TarBuilder::default()
+            .dir("dir/")
+            .file("dir/file", b"archived data")
+            .symlink("link", "dir/file")
+            .hard_link("dir/hard", "dir/file")
+            .file("implicit/parent/file", b"")
+            .build()
+    }
+
+    fn lookup(fs: &MemFs, path: &str) -> io::Result<Entry> {
+        let mut inode = ROOT_ID;
+        let mut names = path.split('/').peekable();
+        while let Some(name) = names.next() {
+            let entry = fs.lookup(CTX, inode, &CString::new(name).unwrap())?;
+            if names.peek().is_none() {
+                return Ok(entry);
+            }
+            inode = entry.inode;
+        }
+        Err(enoent())
+    }
+
+    fn read(fs: &MemFs, inode: Inode) -> Vec<u8> {
+        let mut w = VecWriter(Vec::new());
+        fs.read(CTX, inode, 0, &mut w, 4096, 0, None, 0)
+            .expect("failed to read file");
+        w.0
+    }
+
+    fn list(fs: &MemFs, inode: Inode) -> Vec<String> {
+        let mut iter = fs
+            .readdir(CTX, inode, 0, 4096, 0)
+            .expect("failed to read directory");
+        let mut names = Vec::new();
+        while let Some(entry) = iter.next() {
+            names.push(String::from_utf8(entry.name.to_bytes().to_vec()).unwrap());
+        }
+        names
+    }
+
+    #[test]
+    fn archive_contents() {
+        let fs = MemFs::from_archive(Default::default(), test_archive(), false)
+            .expect("failed to load archive");
+
+        let file = lookup(&fs, "dir/file").expect("file is missing");
+        assert_eq!(file.attr.st_size, 13);
+        assert_eq!(file.attr.st_uid, 1000);
+        assert_eq!(file.attr.st_nlink, 2);
+        assert_eq!(read(&fs, file.inode), b"archived data");
+
+        let hard = lookup(&fs, "dir/hard").expect("hard link is missing");
+        assert_eq!(hard.inode, file.inode);
+
+        let link = lookup(&fs, "link").expect("symlink is missing");
+        assert_eq!(fs.readlink(CTX, link.inode).unwrap(), b"dir/file");
+
+        lookup(&fs, "implicit/parent/file").expect("file in implicit directory is missing");
+        assert_eq!(
+            list(&fs, ROOT_ID),
+            vec![".", "..", "dir", "implicit", "link"]
+        );
+
+        let dir = lookup(&fs, "dir").unwrap();
+        assert_eq!(list(&fs, dir.inode), vec![".", "..", "file", "hard"]);
+    }
+
+    #[test]
+    fn read_only() {
+        let fs = MemFs::from_archive(Default::default(), test_archive(), false)
+            .expect("failed to load archive");
+        let file = lookup(&fs, "dir/file").unwrap();
+
+        assert_eq!(
+            fs.open(CTX, file.inode, libc::O_RDWR as u32)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EROFS)
+        );
+        assert_eq!(
+            fs.unlink(CTX, ROOT_ID, &CString::new("link").unwrap())
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EROFS)
+        );
+    }
+
+    #[test]
+    fn writable_archive() {
+        let archive = test_archive();
+        let len = archive.metadata().unwrap().len() as usize;
+        let mut original = vec![0; len];
+        archive.read_exact_at(&mut original, 0).unwrap();
+        let copy = archive.try_clone().unwrap();
+        let fs =
+            MemFs::from_archive(Default::default(), archive, true).expect("failed to load archive");
+        let file = lookup(&fs, "dir/file").unwrap();
+
+        let data = b"modified";
+        fs.write(
+            CTX,
+            file.inode,
+            0,
+            SliceReader(&data[..]),
+            data.len() as u32,
+            0,
+            None,
+            false,
+            0,
+        )
+        .expect("failed to write file");
+
+        assert_eq!(read(&fs, file.inode), b"modified data");
+        let mut contents = vec![0; len];
+        copy.read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(contents, original);
+    }
+
+    #[test]
+    fn tmpfs_operations() {
+        let fs = MemFs::new(Default::default()).expect("failed to create file system");
+        let name = |s: &str| CString::new(s).unwrap();
+
+        let dir = fs.mkdir(CTX, ROOT_ID, &name("dir"), 0o755, 0).unwrap();
+        let (file, _, _) = fs.create(CTX, ROOT_ID, &name("file"), 0o644, 0, 0).unwrap();
+        assert_eq!(fs.getattr(CTX, ROOT_ID, None).unwrap().0.st_nlink, 3);
+
+        fs.rename(CTX, ROOT_ID, &name("file"), dir.inode, &name("moved"), 0)
+            .expect("failed to rename file");
+        assert_eq!(list(&fs, dir.inode), vec![".", "..", "moved"]);
+        assert_eq!(
+            fs.rmdir(CTX, ROOT_ID, &name("dir"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+        assert_eq!(
+            fs.rename(CTX, ROOT_ID, &name("dir"), dir.inode, &name("sub"), 0)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+
+        fs.unlink(CTX, dir.inode, &name("moved"))
+            .expect("failed to unlink file");
+        // The client still holds a lookup of the file.
+        assert!(fs.getattr(CTX, file.inode, None).is_ok());
+        fs.forget(CTX, file.inode, 1);
+        assert!(fs.getattr(CTX, file.inode, None).is_err());
+
+        fs.rmdir(CTX, ROOT_ID, &name("dir"))
+            .expect("failed to remove directory");
+        assert_eq!(list(&fs, ROOT_ID), vec![".", ".."]);
+        assert_eq!(fs.getattr(CTX, ROOT_ID, None).unwrap().0.st_nlink, 2);
+    }
+
+    // Requires CAP_SYS_ADMIN to mount the file system on the host.
+    #[test]
+    #[ignore]
+    fn fuse_mount() {
+        let fs = MemFs::from_archive(Default::default(), test_archive(), true)
+            .expect("failed to load archive");
+        let max_size = fs.max_buffer_size();
+        let mountpoint = tempfile::tempdir().expect("failed to create mountpoint");
+        let dev_fuse =
+            fuse::mount::mount_dev_fuse(mountpoint.path(), "crosvm-test", libc::MS_NOSUID, &[])
+                .expect("failed to mount file system");
+        let server = std::thread::spawn(move || {
+            fuse::worker::start_message_loop(dev_fuse, max_size, max_size, fs)
+        });
+
+        let file = mountpoint.path().join("dir/file");
+        assert_eq!(std::fs::read(&file).unwrap(), b"archived data");
+        std::fs::write(&file, b"new data").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"new data");
+        std::fs::create_dir(mountpoint.path().join("new_dir")).unwrap();
+        assert!(mountpoint.path().join("new_dir").is_dir());
+
+        let path = CString::new(mountpoint.path().as_os_str().as_bytes()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        assert_eq!(unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) }, 0);
+        server
+            .join()
+            .expect("server thread panicked")
+            .expect("server failed");
+    }
+}
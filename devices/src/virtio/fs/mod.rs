@@ -2,8 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::io;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
@@ -25,23 +27,43 @@ use crate::virtio::{
 };
 
 mod caps;
+mod dax;
+pub mod diskfs;
+pub mod memfs;
 mod multikey;
+pub mod overlay;
 pub mod passthrough;
+mod poll_wakeup;
 mod read_dir;
+mod tar;
 mod worker;
 
+use fuse::filesystem::FileSystem;
 use fuse::Server;
+use memfs::MemFs;
+use overlay::OverlayFs;
 use passthrough::PassthroughFs;
-use worker::Worker;
+use worker::{NotificationQueue, Worker};
 
-pub use worker::process_fs_queue;
+pub use dax::DaxWindow;
+pub use worker::{process_fs_queue, BlockingRequests};
 
 // The fs device does not have a fixed number of queues.
 pub const QUEUE_SIZE: u16 = 1024;
 
 const FS_BAR_NUM: u8 = 4;
 const FS_BAR_OFFSET: u64 = 0;
-const FS_BAR_SIZE: u64 = 1 << 33;
+
+/// The device can send FUSE notifications through the notification queue, which is virtqueue 1.
+const VIRTIO_FS_F_NOTIFICATION: u32 = 0;
+
+/// Not part of the virtio spec. The device can ask the driver to remove DAX mappings with
+/// `NotifyOpcode::RemoveMapping` on the notification queue. Only offered along with
+/// `VIRTIO_FS_F_NOTIFICATION` when the `dax_reclaim` option is set.
+const VIRTIO_FS_F_CROSVM_DAX_RECLAIM: u32 = 23;
+
+/// The minimum size of the buffers in the notification queue.
+pub const NOTIFY_BUF_SIZE: u32 = 4096;
 
 /// Defined in kernel/include/uapi/linux/virtio_fs.h.
 const VIRTIO_FS_SHMCAP_ID_CACHE: u8 = 0;
@@ -57,6 +79,8 @@ pub struct virtio_fs_config {
     pub tag: [u8; FS_MAX_TAG_LEN],
     /// Number of request queues
     pub num_request_queues: Le32,
+    /// Minimum number of bytes required for each buffer in the notification queue
+    pub notify_buf_size: Le32,
 }
 
 // Safe because all members are plain old data and any value is valid.
@@ -96,6 +120,9 @@ pub enum Error {
     /// Failed to signal the virio used queue.
     #[error("failed to signal used queue: {0}")]
     SignalUsedQueue(SysError),
+    /// Failed to spawn a thread to handle a blocking request.
+    #[error("failed to spawn thread for blocking request: {0}")]
+    SpawnBlockingRequest(io::Error),
     /// The tag for the Fs device was too long to fit in the config space.
     #[error("Fs device tag is too long: len = {0}, max = {}", FS_MAX_TAG_LEN)]
     TagTooLong(usize),
@@ -112,9 +139,52 @@ impl From<fuse::Error> for Error {
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+/// The file system implementation that serves the requests of an `Fs` device.
+enum FsBackend {
+    Passthrough(PassthroughFs),
+    Overlay(OverlayFs),
+    Mem(MemFs),
+}
+
+impl FsBackend {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        match self {
+            FsBackend::Passthrough(fs) => fs.keep_rds(),
+            FsBackend::Overlay(fs) => fs.keep_rds(),
+            FsBackend::Mem(fs) => fs.keep_rds(),
+        }
+    }
+
+    fn use_dax(&self) -> bool {
+        match self {
+            FsBackend::Passthrough(fs) => fs.cfg().use_dax,
+            FsBackend::Overlay(fs) => fs.cfg().use_dax,
+            FsBackend::Mem(fs) => fs.cfg().use_dax,
+        }
+    }
+
+    fn dax_window_size(&self) -> u64 {
+        match self {
+            FsBackend::Passthrough(fs) => fs.cfg().dax_window_size,
+            FsBackend::Overlay(fs) => fs.cfg().dax_window_size,
+            FsBackend::Mem(fs) => fs.cfg().dax_window_size,
+        }
+    }
+
+    fn dax_reclaim(&self) -> bool {
+        match self {
+            FsBackend::Passthrough(fs) => fs.cfg().dax_reclaim,
+            FsBackend::Overlay(fs) => fs.cfg().dax_reclaim,
+            FsBackend::Mem(fs) => fs.cfg().dax_reclaim,
+        }
+    }
+}
+
 pub struct Fs {
     cfg: virtio_fs_config,
-    fs: Option<PassthroughFs>,
+    fs: Option<FsBackend>,
+    dax_window_size: u64,
+    dax: Option<Arc<DaxWindow>>,
     queue_sizes: Box<[u16]>,
     avail_features: u64,
     acked_features: u64,
@@ -130,6 +200,63 @@ impl Fs {
         num_workers: usize,
         fs_cfg: passthrough::Config,
         tube: Tube,
+    ) -> Result<Fs> {
+        let fs = PassthroughFs::new(fs_cfg).map_err(Error::CreateFs)?;
+        Fs::with_backend(
+            base_features,
+            tag,
+            num_workers,
+            FsBackend::Passthrough(fs),
+            tube,
+        )
+    }
+
+    /// Creates an Fs device that serves a copy-on-write overlay of `upper` on top of `lowers`,
+    /// which are ordered from the top-most layer down.
+    pub fn new_overlay(
+        base_features: u64,
+        tag: &str,
+        num_workers: usize,
+        fs_cfg: passthrough::Config,
+        upper: &Path,
+        lowers: &[PathBuf],
+        tube: Tube,
+    ) -> Result<Fs> {
+        let fs = OverlayFs::new(fs_cfg, upper, lowers).map_err(Error::CreateFs)?;
+        Fs::with_backend(
+            base_features,
+            tag,
+            num_workers,
+            FsBackend::Overlay(fs),
+            tube,
+        )
+    }
+
+    /// Creates an Fs device whose contents live in memory. If `archive` is given, the file system
+    /// starts out with the contents of that tar archive. Otherwise it starts out empty.
+    pub fn new_memfs(
+        base_features: u64,
+        tag: &str,
+        num_workers: usize,
+        fs_cfg: passthrough::Config,
+        archive: Option<File>,
+        writable: bool,
+        tube: Tube,
+    ) -> Result<Fs> {
+        let fs = match archive {
+            Some(archive) => MemFs::from_archive(fs_cfg, archive, writable),
+            None => MemFs::new(fs_cfg),
+        }
+        .map_err(Error::CreateFs)?;
+        Fs::with_backend(base_features, tag, num_workers, FsBackend::Mem(fs), tube)
+    }
+
+    fn with_backend(
+        base_features: u64,
+        tag: &str,
+        num_workers: usize,
+        fs: FsBackend,
+        tube: Tube,
     ) -> Result<Fs> {
         if tag.len() > FS_MAX_TAG_LEN {
             return Err(Error::TagTooLong(tag.len()));
@@ -141,18 +268,26 @@ impl Fs {
         let cfg = virtio_fs_config {
             tag: cfg_tag,
             num_request_queues: Le32::from(num_workers as u32),
+            notify_buf_size: Le32::from(NOTIFY_BUF_SIZE),
         };
 
-        let fs = PassthroughFs::new(fs_cfg).map_err(Error::CreateFs)?;
+        // There is always a high priority queue and a notification queue in addition to the
+        // request queues. The notification queue is only used if the driver acks
+        // `VIRTIO_FS_F_NOTIFICATION`.
+        let num_queues = num_workers + 2;
 
-        // There is always a high priority queue in addition to the request queues.
-        let num_queues = num_workers + 1;
+        let mut avail_features = base_features | (1 << VIRTIO_FS_F_NOTIFICATION);
+        if fs.use_dax() && fs.dax_reclaim() {
+            avail_features |= 1 << VIRTIO_FS_F_CROSVM_DAX_RECLAIM;
+        }
 
         Ok(Fs {
             cfg,
+            dax_window_size: fs.dax_window_size(),
             fs: Some(fs),
+            dax: None,
             queue_sizes: vec![QUEUE_SIZE; num_queues].into_boxed_slice(),
-            avail_features: base_features,
+            avail_features,
             acked_features: 0,
             pci_bar: None,
             tube: Some(tube),
@@ -178,6 +313,56 @@ impl Fs {
             }
         }
     }
+
+    fn start_workers<F: FileSystem + Send + Sync + 'static>(
+        &mut self,
+        server: Server<F>,
+        guest_mem: GuestMemory,
+        irq: Arc<Interrupt>,
+        queues: Vec<(Queue, Event)>,
+        socket: Arc<Mutex<Tube>>,
+        slot: u32,
+    ) {
+        let server = Arc::new(server);
+        let mut watch_resample_event = true;
+        for (idx, (queue, evt)) in queues.into_iter().enumerate() {
+            let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e)))
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("fs: failed creating kill Event pair: {}", e);
+                    self.stop_workers();
+                    return;
+                }
+            };
+
+            let mem = guest_mem.clone();
+            let server = server.clone();
+            let irq = irq.clone();
+            let socket = Arc::clone(&socket);
+            let dax = self.dax.clone();
+
+            let worker_result = thread::Builder::new()
+                .name(format!("virtio-fs worker {}", idx))
+                .spawn(move || {
+                    let mut worker = Worker::new(mem, queue, server, irq, socket, slot, dax);
+                    worker.run(evt, kill_evt, watch_resample_event)
+                });
+
+            if watch_resample_event {
+                watch_resample_event = false;
+            }
+
+            match worker_result {
+                Ok(worker) => self.workers.push((self_kill_evt, worker)),
+                Err(e) => {
+                    error!("fs: failed to spawn virtio_fs worker: {}", e);
+                    self.stop_workers();
+                    return;
+                }
+            }
+        }
+    }
 }
 
 impl VirtioDevice for Fs {
@@ -185,7 +370,7 @@ impl VirtioDevice for Fs {
         let mut fds = self
             .fs
             .as_ref()
-            .map(PassthroughFs::keep_rds)
+            .map(FsBackend::keep_rds)
             .unwrap_or_else(Vec::new);
         if let Some(rd) = self.tube.as_ref().map(|s| s.as_raw_descriptor()) {
             fds.push(rd);
@@ -229,15 +414,21 @@ impl VirtioDevice for Fs {
         queues: Vec<Queue>,
         queue_evts: Vec<Event>,
     ) {
-        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+        let notification = self.acked_features & (1 << VIRTIO_FS_F_NOTIFICATION) != 0;
+        let dax_reclaim =
+            notification && self.acked_features & (1 << VIRTIO_FS_F_CROSVM_DAX_RECLAIM) != 0;
+        let num_queues = if notification {
+            self.queue_sizes.len()
+        } else {
+            self.queue_sizes.len() - 1
+        };
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             return;
         }
 
         let fs = self.fs.take().expect("missing file system implementation");
-        let use_dax = fs.cfg().use_dax;
+        let use_dax = fs.use_dax();
 
-        let server = Arc::new(Server::new(fs));
-        let irq = Arc::new(interrupt);
         let socket = self.tube.take().expect("missing mapping socket");
         let mut slot = 0;
 
@@ -262,47 +453,47 @@ impl VirtioDevice for Fs {
         }
 
         let socket = Arc::new(Mutex::new(socket));
-        let mut watch_resample_event = true;
-        for (idx, (queue, evt)) in queues.into_iter().zip(queue_evts.into_iter()).enumerate() {
-            let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e)))
-            {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("fs: failed creating kill Event pair: {}", e);
-                    self.stop_workers();
-                    return;
-                }
-            };
-
-            let mem = guest_mem.clone();
-            let server = server.clone();
-            let irq = irq.clone();
-            let socket = Arc::clone(&socket);
-
-            let worker_result = thread::Builder::new()
-                .name(format!("virtio-fs worker {}", idx))
-                .spawn(move || {
-                    let mut worker = Worker::new(mem, queue, server, irq, socket, slot);
-                    worker.run(evt, kill_evt, watch_resample_event)
-                });
+        let irq = Arc::new(interrupt);
+        if use_dax {
+            self.dax = Some(Arc::new(DaxWindow::new(self.dax_window_size)));
+        }
 
-            if watch_resample_event {
-                watch_resample_event = false;
+        let mut queues: Vec<(Queue, Event)> =
+            queues.into_iter().zip(queue_evts.into_iter()).collect();
+        if notification {
+            let (queue, _) = queues.remove(1);
+            let notifier: Arc<dyn fuse::Notifier> = Arc::new(NotificationQueue::new(
+                guest_mem.clone(),
+                queue,
+                Arc::clone(&irq),
+            ));
+            match &fs {
+                FsBackend::Passthrough(fs) => fs.set_notifier(Arc::clone(&notifier)),
+                FsBackend::Overlay(fs) => fs.set_notifier(Arc::clone(&notifier)),
+                FsBackend::Mem(fs) => fs.set_notifier(Arc::clone(&notifier)),
             }
-
-            match worker_result {
-                Ok(worker) => self.workers.push((self_kill_evt, worker)),
-                Err(e) => {
-                    error!("fs: failed to spawn virtio_fs worker: {}", e);
-                    self.stop_workers();
-                    return;
+            if dax_reclaim {
+                if let Some(dax) = &self.dax {
+                    dax.set_notifier(notifier);
                 }
             }
         }
+
+        match fs {
+            FsBackend::Passthrough(fs) => {
+                self.start_workers(Server::new(fs), guest_mem, irq, queues, socket, slot)
+            }
+            FsBackend::Overlay(fs) => {
+                self.start_workers(Server::new(fs), guest_mem, irq, queues, socket, slot)
+            }
+            FsBackend::Mem(fs) => {
+                self.start_workers(Server::new(fs), guest_mem, irq, queues, socket, slot)
+            }
+        }
     }
 
     fn get_device_bars(&mut self, address: PciAddress) -> Vec<PciBarConfiguration> {
-        if self.fs.as_ref().map_or(false, |fs| !fs.cfg().use_dax) {
+        if self.fs.as_ref().map_or(false, |fs| !fs.use_dax()) {
             return vec![];
         }
 
@@ -315,14 +506,14 @@ impl VirtioDevice for Fs {
 
         vec![PciBarConfiguration::new(
             FS_BAR_NUM as usize,
-            FS_BAR_SIZE,
+            self.dax_window_size,
             PciBarRegionType::Memory64BitRegion,
             PciBarPrefetchable::NotPrefetchable,
         )]
     }
 
     fn get_device_caps(&self) -> Vec<Box<dyn PciCapability>> {
-        if self.fs.as_ref().map_or(false, |fs| !fs.cfg().use_dax) {
+        if self.fs.as_ref().map_or(false, |fs| !fs.use_dax()) {
             return vec![];
         }
 
@@ -330,7 +521,7 @@ impl VirtioDevice for Fs {
             PciCapabilityType::SharedMemoryConfig,
             FS_BAR_NUM,
             FS_BAR_OFFSET,
-            FS_BAR_SIZE,
+            self.dax_window_size,
             VIRTIO_FS_SHMCAP_ID_CACHE,
         ))]
     }
@@ -0,0 +1,1632 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A copy-on-write file system that layers a writable upper directory over one or more read-only
+//! lower directories.
+//!
+//! Each layer is served by its own `PassthroughFs`. Lookups walk the layers from the top down and
+//! directories that exist in several layers are merged. Any modification first copies the affected
+//! file, along with its parent directories, up into the upper layer so that the lower layers are
+//! never written to. Deleted lower entries are hidden by AUFS-style whiteouts: an empty
+//! `.wh.<name>` file in the upper layer hides `<name>` in every layer below it and a
+//! `.wh..wh..opq` file inside a directory hides the contents of all lower directories with the same
+//! path. Names starting with `.wh.` are therefore never visible to the client.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::RawDescriptor;
+use fuse::filesystem::{
+    Context, DirEntry, DirectoryIterator, Entry, FileLock, FileSystem, FsOptions, GetxattrReply,
+    IoctlFlags, IoctlReply, ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader,
+    ZeroCopyWriter, ROOT_ID,
+};
+use fuse::Notifier;
+use sync::Mutex;
+
+use crate::virtio::fs::multikey::MultikeyBTreeMap;
+use crate::virtio::fs::passthrough::{self, PassthroughFs};
+
+type Inode = u64;
+type Handle = u64;
+
+// The index of the writable layer in `OverlayFs::layers`.
+const UPPER: usize = 0;
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq\0";
+
+// How much data is copied at a time when copying up a regular file.
+const COPY_UP_CHUNK_SIZE: usize = 128 * 1024;
+
+// The size of the buffer used to read the directories of the individual layers.
+const READDIR_BUF_SIZE: u32 = 64 * 1024;
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+fn enoent() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+fn is_dir(st: &libc::stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn is_whiteout(name: &CStr) -> bool {
+    name.to_bytes().starts_with(WHITEOUT_PREFIX)
+}
+
+fn whiteout_name(name: &CStr) -> CString {
+    let mut buf = WHITEOUT_PREFIX.to_vec();
+    buf.extend_from_slice(name.to_bytes());
+
+    // The unwrap is safe because neither the prefix nor `name` contain interior nul-bytes.
+    CString::new(buf).unwrap()
+}
+
+fn opaque_marker() -> &'static CStr {
+    // Safe because this is a constant value and a valid C string.
+    unsafe { CStr::from_bytes_with_nul_unchecked(OPAQUE_MARKER) }
+}
+
+// Whiteouts and copied-up files are created with the credentials of the device rather than those
+// of the caller, who may not have permission to write to the upper layer directly.
+fn device_ctx(ctx: Context) -> Context {
+    Context {
+        uid: 0,
+        gid: 0,
+        pid: ctx.pid,
+    }
+}
+
+/// The location of an overlay inode in one of the layers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct LayerInode {
+    layer: usize,
+    inode: Inode,
+}
+
+struct InodeData {
+    inode: Inode,
+
+    // The layers that make up this inode, from the top-most one down. Only merged directories have
+    // more than one entry that matters: for everything else only the first entry is ever used.
+    stack: Mutex<Vec<LayerInode>>,
+
+    // The parent directory and name used to re-create this inode in the upper layer when it is
+    // copied up. Only the root has no parent.
+    parent: Option<Arc<InodeData>>,
+    name: CString,
+
+    refcount: AtomicU64,
+
+    // Each entry in `stack` holds a lookup count on its layer, which is dropped along with this
+    // inode.
+    layers: Arc<Vec<PassthroughFs>>,
+}
+
+impl InodeData {
+    fn top(&self) -> LayerInode {
+        self.stack.lock()[0]
+    }
+}
+
+impl Drop for InodeData {
+    fn drop(&mut self) {
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        for l in self.stack.get_mut().drain(..) {
+            self.layers[l.layer].forget(ctx, l.inode, 1);
+        }
+    }
+}
+
+struct DirSnapshotEntry {
+    ino: libc::ino64_t,
+    type_: u32,
+    name: CString,
+}
+
+enum HandleData {
+    File {
+        inode: Inode,
+        layer: LayerInode,
+        handle: Handle,
+    },
+    // Directory contents are merged from all layers when the directory is opened.
+    Dir {
+        inode: Inode,
+        entries: Arc<Vec<DirSnapshotEntry>>,
+    },
+}
+
+impl HandleData {
+    fn inode(&self) -> Inode {
+        match self {
+            HandleData::File { inode, .. } => *inode,
+            HandleData::Dir { inode, .. } => *inode,
+        }
+    }
+}
+
+/// Iterates over the merged contents of a directory opened with `OverlayFs::opendir`.
+pub struct OverlayDirIter {
+    entries: Arc<Vec<DirSnapshotEntry>>,
+    next: usize,
+}
+
+impl DirectoryIterator for OverlayDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        let entry = self.entries.get(self.next)?;
+        self.next += 1;
+
+        Some(DirEntry {
+            ino: entry.ino,
+            offset: self.next as u64,
+            type_: entry.type_,
+            name: &entry.name,
+        })
+    }
+}
+
+// Collects the data read from a lower layer while copying up a file.
+struct CopyUpBuf(Vec<u8>);
+
+impl io::Write for CopyUpBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ZeroCopyWriter for CopyUpBuf {
+    fn write_from(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+        let start = self.0.len();
+        self.0.resize(start + count, 0);
+        let res = f.read_at(&mut self.0[start..], off);
+        self.0.truncate(start + *res.as_ref().unwrap_or(&0));
+        res
+    }
+}
+
+// Supplies the data collected by a `CopyUpBuf` to the upper layer.
+struct CopyUpReader<'a>(&'a [u8]);
+
+impl<'a> io::Read for CopyUpReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.0, buf)
+    }
+}
+
+impl<'a> ZeroCopyReader for CopyUpReader<'a> {
+    fn read_to(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+        let count = cmp::min(count, self.0.len());
+        let written = f.write_at(&self.0[..count], off)?;
+        self.0 = &self.0[written..];
+        Ok(written)
+    }
+}
+
+/// A file system that presents the union of a writable upper directory and one or more read-only
+/// lower directories. The lower directories are never modified: files are copied up into the
+/// upper directory before they are written to and deleted entries are hidden with whiteouts.
+///
+/// Renaming a directory that has contents in a lower layer fails with `EXDEV`, which makes the
+/// client fall back to copying it. Extended attributes are not copied up.
+pub struct OverlayFs {
+    // The upper layer followed by the lower layers, from the top-most one down.
+    layers: Arc<Vec<PassthroughFs>>,
+
+    // Inodes are keyed by the top-most layer inode that they refer to.
+    inodes: Mutex<MultikeyBTreeMap<Inode, LayerInode, Arc<InodeData>>>,
+    next_inode: AtomicU64,
+
+    handles: Mutex<BTreeMap<Handle, Arc<HandleData>>>,
+    next_handle: AtomicU64,
+
+    // Serializes copy-ups so that two threads never try to copy up the same file at once.
+    copy_up_lock: Mutex<()>,
+
+    cfg: passthrough::Config,
+}
+
+impl OverlayFs {
+    /// Creates an overlay of `upper` over `lowers`, which are ordered from the top-most layer down.
+    pub fn new(
+        cfg: passthrough::Config,
+        upper: &Path,
+        lowers: &[PathBuf],
+    ) -> io::Result<OverlayFs> {
+        if cfg.use_dax {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DAX is not supported by the overlay file system",
+            ));
+        }
+        if lowers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the overlay file system needs at least one lower directory",
+            ));
+        }
+
+        let mut layers = Vec::with_capacity(lowers.len() + 1);
+        layers.push(PassthroughFs::new_at(cfg.clone(), upper)?);
+        for lower in lowers {
+            layers.push(PassthroughFs::new_at(cfg.clone(), lower)?);
+        }
+
+        Ok(OverlayFs {
+            layers: Arc::new(layers),
+
+            inodes: Mutex::new(MultikeyBTreeMap::new()),
+            next_inode: AtomicU64::new(ROOT_ID + 1),
+
+            handles: Mutex::new(BTreeMap::new()),
+            next_handle: AtomicU64::new(1),
+
+            copy_up_lock: Mutex::new(()),
+
+            cfg,
+        })
+    }
+
+    pub fn cfg(&self) -> &passthrough::Config {
+        &self.cfg
+    }
+
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.layers
+            .iter()
+            .flat_map(PassthroughFs::keep_rds)
+            .collect()
+    }
+
+    fn find_inode(&self, inode: Inode) -> io::Result<Arc<InodeData>> {
+        self.inodes.lock().get(&inode).cloned().ok_or_else(ebadf)
+    }
+
+    fn find_handle(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
+        self.handles
+            .lock()
+            .get(&handle)
+            .filter(|hd| hd.inode() == inode)
+            .cloned()
+            .ok_or_else(ebadf)
+    }
+
+    fn find_file_handle(&self, handle: Handle, inode: Inode) -> io::Result<(LayerInode, Handle)> {
+        match *self.find_handle(handle, inode)? {
+            HandleData::File { layer, handle, .. } => Ok((layer, handle)),
+            HandleData::Dir { .. } => Err(ebadf()),
+        }
+    }
+
+    fn insert_handle(&self, data: HandleData) -> Handle {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().insert(handle, Arc::new(data));
+        handle
+    }
+
+    // Looks up `name` in a single layer, returning `None` if it doesn't exist there.
+    fn layer_lookup(
+        &self,
+        ctx: Context,
+        dir: LayerInode,
+        name: &CStr,
+    ) -> io::Result<Option<Entry>> {
+        match self.layers[dir.layer].lookup(ctx, dir.inode, name) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn layer_contains(&self, ctx: Context, dir: LayerInode, name: &CStr) -> io::Result<bool> {
+        Ok(match self.layer_lookup(ctx, dir, name)? {
+            Some(entry) => {
+                self.layers[dir.layer].forget(ctx, entry.inode, 1);
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn forget_found(&self, ctx: Context, found: Vec<(LayerInode, Entry)>) {
+        for (l, _) in found {
+            self.layers[l.layer].forget(ctx, l.inode, 1);
+        }
+    }
+
+    // Finds every layer inode that makes up `name` in `parent`, from the top-most one down. The
+    // caller is responsible for dropping the lookup count of each returned entry.
+    fn lookup_layers(
+        &self,
+        ctx: Context,
+        parent: &InodeData,
+        name: &CStr,
+    ) -> io::Result<Vec<(LayerInode, Entry)>> {
+        let stack = parent.stack.lock().clone();
+        let whiteout = whiteout_name(name);
+
+        let mut found: Vec<(LayerInode, Entry)> = Vec::new();
+        for dir in stack {
+            let res = self
+                .layer_lookup(ctx, dir, name)
+                .and_then(|entry| match entry {
+                    Some(entry) => {
+                        let l = LayerInode {
+                            layer: dir.layer,
+                            inode: entry.inode,
+                        };
+
+                        // Only directories are merged with the layers below them.
+                        if !found.is_empty() && !is_dir(&entry.attr) {
+                            self.layers[l.layer].forget(ctx, l.inode, 1);
+                            return Ok(true);
+                        }
+
+                        let last =
+                            !is_dir(&entry.attr) || self.layer_contains(ctx, l, opaque_marker())?;
+                        found.push((l, entry));
+                        Ok(last)
+                    }
+                    None => self.layer_contains(ctx, dir, &whiteout),
+                });
+
+            match res {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => {
+                    self.forget_found(ctx, found);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    // Whether `name` would be visible in `parent` through any of its layers.
+    fn is_visible(&self, ctx: Context, parent: &InodeData, name: &CStr) -> io::Result<bool> {
+        let found = self.lookup_layers(ctx, parent, name)?;
+        let visible = !found.is_empty();
+        self.forget_found(ctx, found);
+        Ok(visible)
+    }
+
+    // Returns the overlay entry for the layer inodes in `found`, taking over their lookup counts.
+    fn add_entry(
+        &self,
+        ctx: Context,
+        parent: &Arc<InodeData>,
+        name: &CStr,
+        found: Vec<(LayerInode, Entry)>,
+    ) -> Entry {
+        let (top, top_entry) = &found[0];
+        let mut entry = Entry {
+            inode: 0,
+            generation: 0,
+            attr: top_entry.attr,
+            attr_timeout: top_entry.attr_timeout,
+            entry_timeout: top_entry.entry_timeout,
+        };
+
+        let mut inodes = self.inodes.lock();
+        if let Some(data) = inodes.get_alt(top) {
+            // Matches the behavior of PassthroughFs: the existing inode already holds a lookup count
+            // on each of its layers so the new ones aren't needed.
+            data.refcount.fetch_add(1, Ordering::Acquire);
+            entry.inode = data.inode;
+            drop(inodes);
+
+            self.forget_found(ctx, found);
+            return entry;
+        }
+
+        let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        let top = *top;
+        inodes.insert(
+            inode,
+            top,
+            Arc::new(InodeData {
+                inode,
+                stack: Mutex::new(found.into_iter().map(|(l, _)| l).collect()),
+                parent: Some(Arc::clone(parent)),
+                name: name.to_owned(),
+                refcount: AtomicU64::new(1),
+                layers: Arc::clone(&self.layers),
+            }),
+        );
+
+        entry.inode = inode;
+        entry
+    }
+
+    // Reads all entries of a directory in a single layer.
+    fn read_layer_dir(&self, ctx: Context, dir: LayerInode) -> io::Result<Vec<DirSnapshotEntry>> {
+        let fs = &self.layers[dir.layer];
+        let handle = fs
+            .opendir(ctx, dir.inode, libc::O_RDONLY as u32)?
+            .0
+            .ok_or_else(ebadf)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let res = loop {
+            let mut iter = match fs.readdir(ctx, dir.inode, handle, READDIR_BUF_SIZE, offset) {
+                Ok(iter) => iter,
+                Err(e) => break Err(e),
+            };
+
+            let count = entries.len();
+            while let Some(dirent) = iter.next() {
+                offset = dirent.offset;
+                entries.push(DirSnapshotEntry {
+                    ino: dirent.ino,
+                    type_: dirent.type_,
+                    name: dirent.name.to_owned(),
+                });
+            }
+            if entries.len() == count {
+                break Ok(entries);
+            }
+        };
+
+        fs.releasedir(ctx, dir.inode, 0, handle)?;
+        res
+    }
+
+    // Merges the contents of the directories in `stack`, hiding whited-out entries.
+    fn merged_entries(
+        &self,
+        ctx: Context,
+        stack: &[LayerInode],
+    ) -> io::Result<Vec<DirSnapshotEntry>> {
+        let mut seen = BTreeSet::new();
+        let mut merged = Vec::new();
+        for dir in stack {
+            // Whiteouts only hide entries in lower layers.
+            let mut hidden = Vec::new();
+            for entry in self.read_layer_dir(ctx, *dir)? {
+                let name = entry.name.to_bytes();
+                if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    hidden.push(target.to_vec());
+                } else if seen.insert(name.to_vec()) {
+                    merged.push(entry);
+                }
+            }
+            seen.extend(hidden);
+        }
+
+        Ok(merged)
+    }
+
+    // Deletes the whiteouts and opaque marker in a directory of the upper layer.
+    fn clear_whiteouts(&self, ctx: Context, dir: Inode) -> io::Result<()> {
+        let dir = LayerInode {
+            layer: UPPER,
+            inode: dir,
+        };
+        for entry in self.read_layer_dir(ctx, dir)? {
+            if is_whiteout(&entry.name) {
+                self.layers[UPPER].unlink(ctx, dir.inode, &entry.name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Creates an empty regular file named `name` in a directory of the upper layer.
+    fn create_marker(&self, ctx: Context, dir: Inode, name: &CStr) -> io::Result<()> {
+        match self.layers[UPPER].mknod(device_ctx(ctx), dir, name, libc::S_IFREG | 0o600, 0, 0) {
+            Ok(entry) => {
+                self.layers[UPPER].forget(ctx, entry.inode, 1);
+                Ok(())
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove_whiteout(&self, ctx: Context, dir: Inode, name: &CStr) -> io::Result<()> {
+        match self.layers[UPPER].unlink(ctx, dir, &whiteout_name(name)) {
+            Err(e) if e.raw_os_error() != Some(libc::ENOENT) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Makes sure that `inode` exists in the upper layer and returns its inode there.
+    fn copy_up(&self, ctx: Context, inode: &Arc<InodeData>) -> io::Result<Inode> {
+        let top = inode.top();
+        if top.layer == UPPER {
+            return Ok(top.inode);
+        }
+
+        let _guard = self.copy_up_lock.lock();
+        self.do_copy_up(ctx, inode)
+    }
+
+    fn do_copy_up(&self, ctx: Context, inode: &Arc<InodeData>) -> io::Result<Inode> {
+        let top = inode.top();
+        if top.layer == UPPER {
+            return Ok(top.inode);
+        }
+
+        // The root always has an entry in the upper layer so every other inode has a parent.
+        let parent = inode.parent.as_ref().ok_or_else(ebadf)?;
+        let upper_parent = self.do_copy_up(ctx, parent)?;
+
+        let lower = &self.layers[top.layer];
+        let upper = &self.layers[UPPER];
+        let dctx = device_ctx(ctx);
+        let (st, _) = lower.getattr(ctx, top.inode, None)?;
+        let perms = st.st_mode & 0o7777;
+
+        let entry = match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => upper.mkdir(dctx, upper_parent, &inode.name, perms, 0)?,
+            libc::S_IFREG => {
+                let (entry, handle, _) = upper.create(
+                    dctx,
+                    upper_parent,
+                    &inode.name,
+                    perms,
+                    (libc::O_WRONLY | libc::O_EXCL) as u32,
+                    0,
+                )?;
+                let handle = handle.ok_or_else(ebadf)?;
+                let res = self.copy_up_data(ctx, top, entry.inode, handle);
+                upper.release(ctx, entry.inode, 0, handle, false, false, None)?;
+                if let Err(e) = res {
+                    upper.forget(ctx, entry.inode, 1);
+                    let _ = upper.unlink(dctx, upper_parent, &inode.name);
+                    return Err(e);
+                }
+                entry
+            }
+            libc::S_IFLNK => {
+                let target = CString::new(lower.readlink(ctx, top.inode)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                upper.symlink(dctx, &target, upper_parent, &inode.name)?
+            }
+            _ => upper.mknod(
+                dctx,
+                upper_parent,
+                &inode.name,
+                st.st_mode,
+                st.st_rdev as u32,
+                0,
+            )?,
+        };
+
+        let mut valid = SetattrValid::UID | SetattrValid::GID;
+        if st.st_mode & libc::S_IFMT != libc::S_IFLNK {
+            valid |= SetattrValid::ATIME | SetattrValid::MTIME;
+        }
+        if let Err(e) = upper.setattr(ctx, entry.inode, st, None, valid) {
+            upper.forget(ctx, entry.inode, 1);
+            return Err(e);
+        }
+
+        let upper_inode = LayerInode {
+            layer: UPPER,
+            inode: entry.inode,
+        };
+        let mut inodes = self.inodes.lock();
+        inode.stack.lock().insert(0, upper_inode);
+
+        // The inode is now keyed by its entry in the upper layer.
+        if let Some(data) = inodes.remove(&inode.inode) {
+            inodes.insert(inode.inode, upper_inode, data);
+        }
+
+        Ok(entry.inode)
+    }
+
+    fn copy_up_data(
+        &self,
+        ctx: Context,
+        src: LayerInode,
+        dst: Inode,
+        dst_handle: Handle,
+    ) -> io::Result<()> {
+        let lower = &self.layers[src.layer];
+        let upper = &self.layers[UPPER];
+        let handle = lower
+            .open(ctx, src.inode, libc::O_RDONLY as u32)?
+            .0
+            .ok_or_else(ebadf)?;
+
+        let mut buf = CopyUpBuf(Vec::with_capacity(COPY_UP_CHUNK_SIZE));
+        let mut offset = 0;
+        let res = 'copy: loop {
+            buf.0.clear();
+            match lower.read(
+                ctx,
+                src.inode,
+                handle,
+                &mut buf,
+                COPY_UP_CHUNK_SIZE as u32,
+                offset,
+                None,
+                0,
+            ) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+
+            let mut data = CopyUpReader(&buf.0);
+            while !data.0.is_empty() {
+                let len = data.0.len();
+                match upper.write(
+                    ctx, dst, dst_handle, &mut data, len as u32, offset, None, false, 0,
+                ) {
+                    Ok(0) => break 'copy Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    Ok(n) => offset += n as u64,
+                    Err(e) => break 'copy Err(e),
+                }
+            }
+        };
+
+        lower.release(ctx, src.inode, 0, handle, false, false, None)?;
+        res
+    }
+
+    // Finishes creating `name` in the upper layer directory of `parent`.
+    fn add_upper_entry(
+        &self,
+        ctx: Context,
+        parent: &Arc<InodeData>,
+        upper_parent: Inode,
+        name: &CStr,
+        entry: Entry,
+    ) -> io::Result<Entry> {
+        if let Err(e) = self.remove_whiteout(ctx, upper_parent, name) {
+            self.layers[UPPER].forget(ctx, entry.inode, 1);
+            return Err(e);
+        }
+
+        let l = LayerInode {
+            layer: UPPER,
+            inode: entry.inode,
+        };
+        Ok(self.add_entry(ctx, parent, name, vec![(l, entry)]))
+    }
+
+    fn do_rmdir(
+        &self,
+        ctx: Context,
+        parent: &Arc<InodeData>,
+        name: &CStr,
+        found: &[(LayerInode, Entry)],
+    ) -> io::Result<()> {
+        if !is_dir(&found[0].1.attr) {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let stack: Vec<LayerInode> = found.iter().map(|(l, _)| *l).collect();
+        if self
+            .merged_entries(ctx, &stack)?
+            .iter()
+            .any(|e| e.name.to_bytes() != b"." && e.name.to_bytes() != b"..")
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+
+        let upper_parent = self.copy_up(ctx, parent)?;
+        if stack[0].layer == UPPER {
+            self.clear_whiteouts(ctx, stack[0].inode)?;
+            self.layers[UPPER].rmdir(ctx, upper_parent, name)?;
+        }
+
+        if self.is_visible(ctx, parent, name)? {
+            self.create_marker(ctx, upper_parent, &whiteout_name(name))?;
+        }
+
+        Ok(())
+    }
+
+    fn do_rename(
+        &self,
+        ctx: Context,
+        olddir: &Arc<InodeData>,
+        oldname: &CStr,
+        newdir: &Arc<InodeData>,
+        newname: &CStr,
+        src: &Arc<InodeData>,
+        flags: u32,
+    ) -> io::Result<()> {
+        let exchange = flags & libc::RENAME_EXCHANGE as u32 != 0;
+
+        // Directories with contents in a lower layer would have to be copied up recursively.
+        let src_is_dir = is_dir(&self.getattr(ctx, src.inode, None)?.0);
+        if src_is_dir && src.stack.lock().iter().any(|l| l.layer != UPPER) {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        match self.lookup(ctx, newdir.inode, newname) {
+            Ok(entry) => {
+                let res = self.find_inode(entry.inode).and_then(|dst| {
+                    let has_lower = dst.stack.lock().iter().any(|l| l.layer != UPPER);
+                    if is_dir(&entry.attr) && has_lower {
+                        Err(io::Error::from_raw_os_error(libc::EXDEV))
+                    } else if exchange {
+                        self.copy_up(ctx, &dst).map(|_| ())
+                    } else if is_dir(&entry.attr) && src_is_dir {
+                        // An empty directory may still contain whiteouts, which would make the
+                        // rename fail.
+                        let upper = dst.top();
+                        if self
+                            .merged_entries(ctx, &[upper])?
+                            .iter()
+                            .all(|e| e.name.to_bytes() == b"." || e.name.to_bytes() == b"..")
+                        {
+                            self.clear_whiteouts(ctx, upper.inode)?;
+                        }
+                        Ok(())
+                    } else {
+                        Ok(())
+                    }
+                });
+                self.forget(ctx, entry.inode, 1);
+                res?;
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+
+        let src_upper = self.copy_up(ctx, src)?;
+        let upper_old = self.copy_up(ctx, olddir)?;
+        let upper_new = self.copy_up(ctx, newdir)?;
+        let newdir_upper = LayerInode {
+            layer: UPPER,
+            inode: upper_new,
+        };
+        let had_whiteout = self.layer_contains(ctx, newdir_upper, &whiteout_name(newname))?;
+
+        self.layers[UPPER].rename(ctx, upper_old, oldname, upper_new, newname, flags)?;
+
+        if !exchange {
+            if had_whiteout {
+                // Don't let the lower directory that was whited out show through.
+                if src_is_dir {
+                    self.create_marker(ctx, src_upper, opaque_marker())?;
+                }
+                self.remove_whiteout(ctx, upper_new, newname)?;
+            }
+
+            if self.is_visible(ctx, olddir, oldname)? {
+                self.create_marker(ctx, upper_old, &whiteout_name(oldname))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FileSystem for OverlayFs {
+    type Inode = Inode;
+    type Handle = Handle;
+    type DirIter = OverlayDirIter;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        // Every open needs a handle in the layer that it refers to.
+        let capable = capable - (FsOptions::ZERO_MESSAGE_OPEN | FsOptions::ZERO_MESSAGE_OPENDIR);
+
+        let mut opts = FsOptions::all();
+        for layer in self.layers.iter() {
+            opts &= layer.init(capable)?;
+        }
+
+        // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
+        let stack = (0..self.layers.len())
+            .map(|layer| LayerInode {
+                layer,
+                inode: ROOT_ID,
+            })
+            .collect();
+        self.inodes.lock().insert(
+            ROOT_ID,
+            LayerInode {
+                layer: UPPER,
+                inode: ROOT_ID,
+            },
+            Arc::new(InodeData {
+                inode: ROOT_ID,
+                stack: Mutex::new(stack),
+                parent: None,
+                name: CString::default(),
+                refcount: AtomicU64::new(2),
+                layers: Arc::clone(&self.layers),
+            }),
+        );
+
+        Ok(opts)
+    }
+
+    fn destroy(&self) {
+        self.handles.lock().clear();
+        self.inodes.lock().clear();
+        for layer in self.layers.iter() {
+            layer.destroy();
+        }
+    }
+
+    fn lookup(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        if is_whiteout(name) {
+            return Err(enoent());
+        }
+
+        let data = self.find_inode(parent)?;
+        let found = self.lookup_layers(ctx, &data, name)?;
+        if found.is_empty() {
+            return Err(enoent());
+        }
+
+        Ok(self.add_entry(ctx, &data, name, found))
+    }
+
+    fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
+        let mut inodes = self.inodes.lock();
+        if let Some(data) = inodes.get(&inode) {
+            // Acquiring the lock on `inodes` prevents any other thread from changing the refcount.
+            let refcount = data.refcount.load(Ordering::Relaxed).saturating_sub(count);
+            data.refcount.store(refcount, Ordering::Relaxed);
+            if refcount == 0 {
+                // The layer inodes are forgotten once nothing refers to this inode anymore.
+                inodes.remove(&inode);
+            }
+        }
+    }
+
+    fn getattr(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let top = self.find_inode(inode)?.top();
+        self.layers[top.layer].getattr(ctx, top.inode, None)
+    }
+
+    fn setattr(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        attr: libc::stat64,
+        handle: Option<Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let data = self.find_inode(inode)?;
+        let upper = self.copy_up(ctx, &data)?;
+
+        // Handles that were opened before the file was copied up can't be used.
+        let handle = handle
+            .and_then(|h| self.find_file_handle(h, inode).ok())
+            .filter(|(l, _)| l.layer == UPPER && l.inode == upper)
+            .map(|(_, h)| h);
+        self.layers[UPPER].setattr(ctx, upper, attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: Context, inode: Inode) -> io::Result<Vec<u8>> {
+        let top = self.find_inode(inode)?.top();
+        self.layers[top.layer].readlink(ctx, top.inode)
+    }
+
+    fn symlink(
+        &self,
+        ctx: Context,
+        linkname: &CStr,
+        parent: Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        if is_whiteout(name) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let data = self.find_inode(parent)?;
+        let upper_parent = self.copy_up(ctx, &data)?;
+        let entry = self.layers[UPPER].symlink(ctx, linkname, upper_parent, name)?;
+        self.add_upper_entry(ctx, &data, upper_parent, name, entry)
+    }
+
+    fn mknod(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        if is_whiteout(name) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let data = self.find_inode(parent)?;
+        let upper_parent = self.copy_up(ctx, &data)?;
+        let entry = self.layers[UPPER].mknod(ctx, upper_parent, name, mode, rdev, umask)?;
+        self.add_upper_entry(ctx, &data, upper_parent, name, entry)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        if is_whiteout(name) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let data = self.find_inode(parent)?;
+        let upper_parent = self.copy_up(ctx, &data)?;
+        let dir = LayerInode {
+            layer: UPPER,
+            inode: upper_parent,
+        };
+        let whited_out = self.layer_contains(ctx, dir, &whiteout_name(name))?;
+
+        let entry = self.layers[UPPER].mkdir(ctx, upper_parent, name, mode, umask)?;
+        if whited_out {
+            // A directory replacing a deleted one must not show the old contents.
+            if let Err(e) = self.create_marker(ctx, entry.inode, opaque_marker()) {
+                self.layers[UPPER].forget(ctx, entry.inode, 1);
+                let _ = self.layers[UPPER].rmdir(ctx, upper_parent, name);
+                return Err(e);
+            }
+        }
+
+        self.add_upper_entry(ctx, &data, upper_parent, name, entry)
+    }
+
+    fn unlink(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        if is_whiteout(name) {
+            return Err(enoent());
+        }
+
+        let data = self.find_inode(parent)?;
+        let found = self.lookup_layers(ctx, &data, name)?;
+        if found.is_empty() {
+            return Err(enoent());
+        }
+        let in_upper = found[0].0.layer == UPPER;
+        self.forget_found(ctx, found);
+
+        let upper_parent = self.copy_up(ctx, &data)?;
+        if in_upper {
+            self.layers[UPPER].unlink(ctx, upper_parent, name)?;
+        }
+
+        if self.is_visible(ctx, &data, name)? {
+            self.create_marker(ctx, upper_parent, &whiteout_name(name))?;
+        }
+
+        Ok(())
+    }
+
+    fn rmdir(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        if is_whiteout(name) {
+            return Err(enoent());
+        }
+
+        let data = self.find_inode(parent)?;
+        let found = self.lookup_layers(ctx, &data, name)?;
+        if found.is_empty() {
+            return Err(enoent());
+        }
+
+        let res = self.do_rmdir(ctx, &data, name, &found);
+        self.forget_found(ctx, found);
+        res
+    }
+
+    fn rename(
+        &self,
+        ctx: Context,
+        olddir: Inode,
+        oldname: &CStr,
+        newdir: Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        if is_whiteout(newname) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let old = self.find_inode(olddir)?;
+        let new = self.find_inode(newdir)?;
+        let entry = self.lookup(ctx, olddir, oldname)?;
+        let res = self
+            .find_inode(entry.inode)
+            .and_then(|src| self.do_rename(ctx, &old, oldname, &new, newname, &src, flags));
+        self.forget(ctx, entry.inode, 1);
+        res
+    }
+
+    fn link(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        newparent: Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        if is_whiteout(newname) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let data = self.find_inode(inode)?;
+        let parent = self.find_inode(newparent)?;
+        let upper = self.copy_up(ctx, &data)?;
+        let upper_parent = self.copy_up(ctx, &parent)?;
+        let entry = self.layers[UPPER].link(ctx, upper, upper_parent, newname)?;
+        self.add_upper_entry(ctx, &parent, upper_parent, newname, entry)
+    }
+
+    fn open(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let data = self.find_inode(inode)?;
+        let writable =
+            flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0;
+        let layer = if writable {
+            LayerInode {
+                layer: UPPER,
+                inode: self.copy_up(ctx, &data)?,
+            }
+        } else {
+            data.top()
+        };
+
+        let (handle, opts) = self.layers[layer.layer].open(ctx, layer.inode, flags)?;
+        let handle = handle.ok_or_else(ebadf)?;
+        let handle = self.insert_handle(HandleData::File {
+            inode,
+            layer,
+            handle,
+        });
+
+        Ok((Some(handle), opts))
+    }
+
+    fn create(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        if is_whiteout(name) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let data = self.find_inode(parent)?;
+        let upper_parent = self.copy_up(ctx, &data)?;
+        let (entry, handle, opts) =
+            self.layers[UPPER].create(ctx, upper_parent, name, mode, flags, umask)?;
+        let layer = LayerInode {
+            layer: UPPER,
+            inode: entry.inode,
+        };
+        let handle = handle.ok_or_else(ebadf)?;
+
+        let entry = match self.add_upper_entry(ctx, &data, upper_parent, name, entry) {
+            Ok(entry) => entry,
+            Err(e) => {
+                let _ = self.layers[UPPER].release(ctx, layer.inode, 0, handle, false, false, None);
+                return Err(e);
+            }
+        };
+        let handle = self.insert_handle(HandleData::File {
+            inode: entry.inode,
+            layer,
+            handle,
+        });
+
+        Ok((entry, Some(handle), opts))
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        w: W,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].read(ctx, l.inode, h, w, size, offset, lock_owner, flags)
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        r: R,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].write(
+            ctx,
+            l.inode,
+            h,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            flags,
+        )
+    }
+
+    fn flush(&self, ctx: Context, inode: Inode, handle: Handle, lock_owner: u64) -> io::Result<()> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].flush(ctx, l.inode, h, lock_owner)
+    }
+
+    fn fsync(&self, ctx: Context, inode: Inode, datasync: bool, handle: Handle) -> io::Result<()> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].fsync(ctx, l.inode, datasync, h)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].fallocate(ctx, l.inode, h, mode, offset, length)
+    }
+
+    fn release(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        flags: u32,
+        handle: Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.handles.lock().remove(&handle);
+        self.layers[l.layer].release(ctx, l.inode, flags, h, flush, flock_release, lock_owner)
+    }
+
+    fn statfs(&self, ctx: Context, _inode: Inode) -> io::Result<libc::statvfs64> {
+        self.layers[UPPER].statfs(ctx, ROOT_ID)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let data = self.find_inode(inode)?;
+        let upper = self.copy_up(ctx, &data)?;
+        self.layers[UPPER].setxattr(ctx, upper, name, value, flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        let top = self.find_inode(inode)?.top();
+        self.layers[top.layer].getxattr(ctx, top.inode, name, size)
+    }
+
+    fn listxattr(&self, ctx: Context, inode: Inode, size: u32) -> io::Result<ListxattrReply> {
+        let top = self.find_inode(inode)?.top();
+        self.layers[top.layer].listxattr(ctx, top.inode, size)
+    }
+
+    fn removexattr(&self, ctx: Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        let data = self.find_inode(inode)?;
+        let upper = self.copy_up(ctx, &data)?;
+        self.layers[UPPER].removexattr(ctx, upper, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        _flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let data = self.find_inode(inode)?;
+        let stack = data.stack.lock().clone();
+        let entries = self.merged_entries(ctx, &stack)?;
+        let handle = self.insert_handle(HandleData::Dir {
+            inode,
+            entries: Arc::new(entries),
+        });
+
+        Ok((Some(handle), OpenOptions::empty()))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<Self::DirIter> {
+        match &*self.find_handle(handle, inode)? {
+            HandleData::Dir { entries, .. } => Ok(OverlayDirIter {
+                entries: Arc::clone(entries),
+                next: offset as usize,
+            }),
+            HandleData::File { .. } => Err(ebadf()),
+        }
+    }
+
+    fn fsyncdir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _datasync: bool,
+        handle: Handle,
+    ) -> io::Result<()> {
+        self.find_handle(handle, inode).map(|_| ())
+    }
+
+    fn releasedir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _flags: u32,
+        handle: Handle,
+    ) -> io::Result<()> {
+        self.find_handle(handle, inode)?;
+        self.handles.lock().remove(&handle);
+        Ok(())
+    }
+
+    fn access(&self, ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
+        let top = self.find_inode(inode)?.top();
+        self.layers[top.layer].access(ctx, top.inode, mask)
+    }
+
+    fn ioctl<R: io::Read>(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        flags: IoctlFlags,
+        cmd: u32,
+        arg: u64,
+        in_size: u32,
+        out_size: u32,
+        r: R,
+    ) -> io::Result<IoctlReply> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].ioctl(ctx, l.inode, h, flags, cmd, arg, in_size, out_size, r)
+    }
+
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].getlk(ctx, l.inode, h, owner, lock, flags)
+    }
+
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].setlk(ctx, l.inode, h, owner, lock, flags)
+    }
+
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].setlkw(ctx, l.inode, h, owner, lock, flags)
+    }
+
+    fn poll(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].poll(ctx, l.inode, h, khandle, flags, events)
+    }
+
+    fn set_notifier(&self, notifier: Arc<dyn Notifier>) {
+        for layer in self.layers.iter() {
+            layer.set_notifier(Arc::clone(&notifier));
+        }
+    }
+
+    fn lseek(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let (l, h) = self.find_file_handle(handle, inode)?;
+        self.layers[l.layer].lseek(ctx, l.inode, h, offset, whence)
+    }
+
+    fn copy_file_range(
+        &self,
+        ctx: Context,
+        inode_src: Inode,
+        handle_src: Handle,
+        offset_src: u64,
+        inode_dst: Inode,
+        handle_dst: Handle,
+        offset_dst: u64,
+        length: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        let (src, src_handle) = self.find_file_handle(handle_src, inode_src)?;
+        let (dst, dst_handle) = self.find_file_handle(handle_dst, inode_dst)?;
+        if src.layer != dst.layer {
+            // Lets the client fall back to copying the data itself.
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        self.layers[dst.layer].copy_file_range(
+            ctx, src.inode, src_handle, offset_src, dst.inode, dst_handle, offset_dst, length,
+            flags,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    const CTX: Context = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    struct TestOverlay {
+        fs: OverlayFs,
+        upper: TempDir,
+        lower: TempDir,
+    }
+
+    fn test_overlay() -> TestOverlay {
+        let upper = TempDir::new().expect("failed to create upper dir");
+        let lower = TempDir::new().expect("failed to create lower dir");
+        let fs = OverlayFs::new(
+            Default::default(),
+            upper.path(),
+            &[lower.path().to_path_buf()],
+        )
+        .expect("failed to create overlay");
+        fs.init(FsOptions::empty()).expect("failed to init overlay");
+
+        TestOverlay { fs, upper, lower }
+    }
+
+    fn cstr(name: &str) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    fn lookup(fs: &OverlayFs, parent: Inode, name: &str) -> io::Result<Inode> {
+        fs.lookup(CTX, parent, &cstr(name)).map(|entry| entry.inode)
+    }
+
+    fn list_dir(fs: &OverlayFs, inode: Inode) -> Vec<String> {
+        let handle = fs
+            .opendir(CTX, inode, 0)
+            .expect("failed to open directory")
+            .0
+            .unwrap();
+        let mut iter = fs
+            .readdir(CTX, inode, handle, 4096, 0)
+            .expect("failed to read directory");
+
+        let mut names = Vec::new();
+        while let Some(entry) = iter.next() {
+            let name = String::from_utf8(entry.name.to_bytes().to_vec()).unwrap();
+            if name != "." && name != ".." {
+                names.push(name);
+            }
+        }
+        fs.releasedir(CTX, inode, 0, handle)
+            .expect("failed to release directory");
+
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn write_copies_up() {
+        let t = test_overlay();
+        fs::create_dir(t.lower.path().join("dir")).unwrap();
+        fs::write(t.lower.path().join("dir/file"), b"lower data").unwrap();
+
+        let dir = lookup(&t.fs, ROOT_ID, "dir").unwrap();
+        let file = lookup(&t.fs, dir, "file").unwrap();
+        let handle =
+            t.fs.open(CTX, file, libc::O_RDWR as u32)
+                .expect("failed to open file")
+                .0
+                .unwrap();
+
+        let data = b"upper";
+        t.fs.write(
+            CTX,
+            file,
+            handle,
+            CopyUpReader(&data[..]),
+            data.len() as u32,
+            0,
+            None,
+            false,
+            0,
+        )
+        .expect("failed to write file");
+        t.fs.release(CTX, file, 0, handle, false, false, None)
+            .expect("failed to release file");
+
+        assert_eq!(
+            fs::read(t.lower.path().join("dir/file")).unwrap(),
+            b"lower data"
+        );
+        assert_eq!(
+            fs::read(t.upper.path().join("dir/file")).unwrap(),
+            b"upper data"
+        );
+    }
+
+    #[test]
+    fn merged_directory_contents() {
+        let t = test_overlay();
+        fs::create_dir(t.lower.path().join("dir")).unwrap();
+        fs::write(t.lower.path().join("dir/lower"), b"").unwrap();
+        fs::create_dir(t.upper.path().join("dir")).unwrap();
+        fs::write(t.upper.path().join("dir/upper"), b"").unwrap();
+
+        let dir = lookup(&t.fs, ROOT_ID, "dir").unwrap();
+        assert_eq!(list_dir(&t.fs, dir), vec!["lower", "upper"]);
+        lookup(&t.fs, dir, "lower").expect("lower file is not visible");
+        lookup(&t.fs, dir, "upper").expect("upper file is not visible");
+    }
+
+    #[test]
+    fn unlink_creates_whiteout() {
+        let t = test_overlay();
+        fs::write(t.lower.path().join("file"), b"").unwrap();
+
+        t.fs.unlink(CTX, ROOT_ID, &cstr("file"))
+            .expect("failed to unlink file");
+
+        assert!(t.lower.path().join("file").exists());
+        assert!(t.upper.path().join(".wh.file").exists());
+        assert_eq!(
+            lookup(&t.fs, ROOT_ID, "file").unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            lookup(&t.fs, ROOT_ID, ".wh.file")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert!(list_dir(&t.fs, ROOT_ID).is_empty());
+    }
+
+    #[test]
+    fn recreated_directory_is_opaque() {
+        let t = test_overlay();
+        fs::create_dir(t.lower.path().join("dir")).unwrap();
+        fs::write(t.lower.path().join("dir/file"), b"").unwrap();
+
+        assert_eq!(
+            t.fs.rmdir(CTX, ROOT_ID, &cstr("dir"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+
+        let dir = lookup(&t.fs, ROOT_ID, "dir").unwrap();
+        t.fs.unlink(CTX, dir, &cstr("file"))
+            .expect("failed to unlink file");
+        t.fs.rmdir(CTX, ROOT_ID, &cstr("dir"))
+            .expect("failed to remove directory");
+        assert!(t.upper.path().join(".wh.dir").exists());
+        assert!(!t.upper.path().join("dir").exists());
+
+        let entry =
+            t.fs.mkdir(CTX, ROOT_ID, &cstr("dir"), 0o755, 0)
+                .expect("failed to create directory");
+        assert!(list_dir(&t.fs, entry.inode).is_empty());
+        assert!(!t.upper.path().join(".wh.dir").exists());
+        assert!(t.upper.path().join("dir/.wh..wh..opq").exists());
+        assert!(t.lower.path().join("dir/file").exists());
+    }
+
+    #[test]
+    fn rename_lower_file() {
+        let t = test_overlay();
+        fs::write(t.lower.path().join("old"), b"data").unwrap();
+
+        t.fs.rename(CTX, ROOT_ID, &cstr("old"), ROOT_ID, &cstr("new"), 0)
+            .expect("failed to rename file");
+
+        assert_eq!(list_dir(&t.fs, ROOT_ID), vec!["new"]);
+        assert_eq!(fs::read(t.upper.path().join("new")).unwrap(), b"data");
+        assert!(t.upper.path().join(".wh.old").exists());
+        assert!(t.lower.path().join("old").exists());
+    }
+}
@@ -5,33 +5,38 @@
 use std::{
     borrow::Cow,
     cmp,
-    collections::{btree_map, BTreeMap},
-    ffi::{CStr, CString},
+    collections::{btree_map, BTreeMap, BTreeSet},
+    ffi::{CStr, CString, OsStr},
     fs::File,
-    io,
+    io::{self, Write},
     mem::{self, size_of, MaybeUninit},
     os::raw::{c_int, c_long},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
     ptr::{addr_of, addr_of_mut},
     str::FromStr,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use base::{
     error, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr, ioctl_with_mut_ptr, ioctl_with_ptr,
-    AsRawDescriptor, FileFlags, FromRawDescriptor, RawDescriptor,
+    register_interrupting_rt_signal_handler, AsRawDescriptor, FileFlags, FromRawDescriptor,
+    RawDescriptor, SIGRTMIN,
 };
 use data_model::DataInit;
 use fuse::filesystem::{
-    Context, DirectoryIterator, Entry, FileSystem, FsOptions, GetxattrReply, IoctlFlags,
+    Context, DirectoryIterator, Entry, FileLock, FileSystem, FsOptions, GetxattrReply, IoctlFlags,
     IoctlReply, ListxattrReply, OpenOptions, RemoveMappingOne, SetattrValid, ZeroCopyReader,
-    ZeroCopyWriter, ROOT_ID,
+    ZeroCopyWriter, OFFSET_MAX, ROOT_ID,
 };
-use fuse::sys::WRITE_KILL_PRIV;
-use fuse::Mapper;
+use fuse::sys::{LK_FLOCK, POLL_SCHEDULE_NOTIFY, WRITE_KILL_PRIV};
+use fuse::{Mapper, Notifier};
+use once_cell::sync::{Lazy, OnceCell};
 use sync::Mutex;
 
 #[cfg(feature = "chromeos")]
@@ -45,16 +50,20 @@ use {
 
 use crate::virtio::fs::caps::{Capability, Caps, Set as CapSet, Value as CapValue};
 use crate::virtio::fs::multikey::MultikeyBTreeMap;
+use crate::virtio::fs::poll_wakeup::PollWakeups;
 use crate::virtio::fs::read_dir::ReadDir;
 
 const EMPTY_CSTR: &[u8] = b"\0";
-const ROOT_CSTR: &[u8] = b"/\0";
 const PROC_CSTR: &[u8] = b"/proc\0";
 
 const USER_VIRTIOFS_XATTR: &[u8] = b"user.virtiofs.";
 const SECURITY_XATTR: &[u8] = b"security.";
 const SELINUX_XATTR: &[u8] = b"security.selinux";
 
+// Used to ensure that only one thread at a time uses chdir(). Since chdir() affects the process-wide
+// CWD, this is shared by every `PassthroughFs` in the process.
+static CHDIR_MUTEX: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
 const FSCRYPT_KEY_DESCRIPTOR_SIZE: usize = 8;
 const FSCRYPT_KEY_IDENTIFIER_SIZE: usize = 16;
 
@@ -62,6 +71,14 @@ const FSCRYPT_KEY_IDENTIFIER_SIZE: usize = 16;
 #[cfg(feature = "chromeos")]
 const DEFAULT_DBUS_TIMEOUT: Duration = Duration::from_secs(25);
 
+// How long to wait before interrupting a `setlkw` again if the thread still hasn't noticed that
+// the lock owner was released, which happens if the signal arrives just before the thread blocks.
+const LOCK_INTERRUPT_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+// Whether the handler of the signal used to interrupt a pending `setlkw` was registered. This is
+// only attempted once per process.
+static LOCK_INTERRUPT_HANDLER: OnceCell<base::Result<()>> = OnceCell::new();
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct fscrypt_policy_v1 {
@@ -206,6 +223,116 @@ impl AsRawDescriptor for HandleData {
     }
 }
 
+// Identifies the owner of a set of file locks in the client. `fcntl` and `flock` locks are tracked
+// separately because the kernel releases them at different times.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct LockOwnerKey {
+    inode: Inode,
+    owner: u64,
+    flock: bool,
+}
+
+// A file opened on behalf of a single lock owner in the client. All of the owner's locks are
+// placed on this file as open file description (OFD) locks or with `flock` so that lock owners in
+// the client contend with each other, and with processes on the host, even though all of their
+// requests are made by this process.
+struct LockOwnerData {
+    file: File,
+    // Set once the owner's locks have been released so that a pending `setlkw` stops waiting.
+    released: AtomicBool,
+    // Threads blocked in `setlkw` on `file`, which are interrupted once `released` is set.
+    waiters: Mutex<BTreeSet<libc::pthread_t>>,
+}
+
+impl LockOwnerData {
+    fn new(file: File) -> LockOwnerData {
+        LockOwnerData {
+            file,
+            released: AtomicBool::new(false),
+            waiters: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    // Marks the owner's locks as released and interrupts every `setlkw` waiting on them.
+    fn release(&self) {
+        self.released.store(true, Ordering::Release);
+        // Without a handler the signal would kill the process. No thread can be waiting then
+        // because `wait_for_lock` refuses to block.
+        if !lock_interrupt_handler_registered() {
+            return;
+        }
+        loop {
+            {
+                let waiters = self.waiters.lock();
+                if waiters.is_empty() {
+                    return;
+                }
+                for &waiter in waiters.iter() {
+                    // Safe because waiters only remove themselves from the set while holding its
+                    // lock and before exiting, so `waiter` is still running.
+                    unsafe { libc::pthread_kill(waiter, lock_interrupt_signal()) };
+                }
+            }
+            thread::sleep(LOCK_INTERRUPT_RETRY_DELAY);
+        }
+    }
+
+    // Places `lock` on `file`, waiting for conflicting locks to be released first. Fails with
+    // `EINTR` if the owner's locks are released in the meantime.
+    fn wait_for_lock(&self, lock: &FileLock, flock: bool) -> io::Result<()> {
+        register_lock_interrupt_handler()?;
+
+        // Safe because this has no preconditions.
+        let waiter = unsafe { libc::pthread_self() };
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if self.released.load(Ordering::Acquire) {
+                    return Err(io::Error::from_raw_os_error(libc::EINTR));
+                }
+                waiters.insert(waiter);
+            }
+
+            let res = set_lock(&self.file, lock, flock, true);
+            self.waiters.lock().remove(&waiter);
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+}
+
+// Releases the locks of all of `owners`, whose files stay open until every waiter has been
+// interrupted. Closing one of them earlier could let a waiter on another owner acquire its lock.
+fn release_all(owners: &[Arc<LockOwnerData>]) {
+    for data in owners {
+        data.release();
+    }
+}
+
+fn lock_interrupt_signal() -> c_int {
+    SIGRTMIN() + 1
+}
+
+extern "C" fn handle_lock_interrupt(_: c_int) {}
+
+// Registers a handler for the signal that interrupts a pending `setlkw`. The handler doesn't do
+// anything, but without one the signal would kill the process.
+fn register_lock_interrupt_handler() -> io::Result<()> {
+    let res = *LOCK_INTERRUPT_HANDLER.get_or_init(|| {
+        // Safe because the handler doesn't do anything.
+        unsafe {
+            register_interrupting_rt_signal_handler(lock_interrupt_signal(), handle_lock_interrupt)
+        }
+    });
+    res.map_err(io::Error::from)
+}
+
+fn lock_interrupt_handler_registered() -> bool {
+    matches!(LOCK_INTERRUPT_HANDLER.get(), Some(Ok(())))
+}
+
 macro_rules! scoped_cred {
     ($name:ident, $ty:ty, $syscall_nr:expr) => {
         #[derive(Debug)]
@@ -443,6 +570,96 @@ impl Default for CachePolicy {
     }
 }
 
+/// The ID that unmapped IDs are translated to, `nobody` and `nogroup` on most systems.
+pub const OVERFLOW_ID: u32 = 65534;
+
+// A range of `count` consecutive IDs starting at `guest` in the guest and at `host` on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IdRange {
+    guest: u32,
+    host: u32,
+    count: u32,
+}
+
+/// Translates user or group IDs between the guest and the host. An empty map leaves all IDs
+/// unchanged. Otherwise IDs that aren't covered by any range in the map are translated to the
+/// overflow ID in both directions, like IDs outside of a user namespace's maps.
+///
+/// The map is parsed from a comma-separated list of "GUEST HOST COUNT" ranges, the same format that
+/// is used for user namespace ID maps.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    /// Returns true if the map doesn't translate any IDs.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Translates the guest ID `id` into the ID used on the host.
+    pub fn to_host(&self, id: u32) -> u32 {
+        if self.is_empty() {
+            return id;
+        }
+        self.ranges
+            .iter()
+            .find(|r| id >= r.guest && id - r.guest < r.count)
+            .map_or(OVERFLOW_ID, |r| r.host + (id - r.guest))
+    }
+
+    /// Translates the host ID `id` into the ID seen by the guest.
+    pub fn to_guest(&self, id: u32) -> u32 {
+        if self.is_empty() {
+            return id;
+        }
+        self.ranges
+            .iter()
+            .find(|r| id >= r.host && id - r.host < r.count)
+            .map_or(OVERFLOW_ID, |r| r.guest + (id - r.host))
+    }
+}
+
+impl FromStr for IdMap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for range in s.split(',') {
+            let mut fields = range.split_whitespace().map(u32::from_str);
+            let (guest, host, count) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(Ok(guest)), Some(Ok(host)), Some(Ok(count))) => (guest, host, count),
+                _ => return Err("ID map ranges must be of the form `GUEST HOST COUNT`"),
+            };
+            if fields.next().is_some() {
+                return Err("ID map ranges must be of the form `GUEST HOST COUNT`");
+            }
+            if count == 0
+                || guest.checked_add(count - 1).is_none()
+                || host.checked_add(count - 1).is_none()
+            {
+                return Err("invalid ID map range");
+            }
+
+            // Translation has to work in both directions, so neither side may overlap.
+            let overlaps = |start: u32, other: u32, other_count: u32| {
+                u64::from(start) < u64::from(other) + u64::from(other_count)
+                    && u64::from(other) < u64::from(start) + u64::from(count)
+            };
+            if ranges.iter().any(|r: &IdRange| {
+                overlaps(guest, r.guest, r.count) || overlaps(host, r.host, r.count)
+            }) {
+                return Err("ID map ranges must not overlap");
+            }
+
+            ranges.push(IdRange { guest, host, count });
+        }
+
+        Ok(IdMap { ranges })
+    }
+}
+
 /// Options that configure the behavior of the file system.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -511,6 +728,19 @@ pub struct Config {
     /// The default value for this option is `false`.
     pub use_dax: bool,
 
+    /// The size of the DAX window in bytes, which must be a power of two. This limits how much of
+    /// the shared files the VM can map at the same time.
+    ///
+    /// The default value for this option is 8 GiB.
+    pub dax_window_size: u64,
+
+    /// Ask the VM to remove the least recently mapped ranges when the DAX window starts to fill
+    /// up. This uses a notification that is not part of the virtio-fs spec, so it is only sent
+    /// to drivers that ack crosvm's own feature bit for it.
+    ///
+    /// The default value for this option is `false`.
+    pub dax_reclaim: bool,
+
     /// Enable support for POSIX acls.
     ///
     /// Enable POSIX acl support for the shared directory. This requires that the underlying file
@@ -518,6 +748,22 @@ pub struct Config {
     ///
     /// The default value for this option is `true`.
     pub posix_acl: bool,
+
+    /// Translates the user IDs of callers and of file owners between the guest and the host. This
+    /// lets files created by a guest user be owned by a different user on the host without setting
+    /// up a user namespace for the device.
+    ///
+    /// The default value for this option is an empty map, which leaves user IDs unchanged.
+    pub uid_map: IdMap,
+
+    /// Like `uid_map`, but for group IDs.
+    pub gid_map: IdMap,
+
+    /// A file that gets a record of every request that modifies the file system, along with the
+    /// affected paths, the guest credentials of the caller, and the result.
+    ///
+    /// The default value for this option is `None`.
+    pub audit_log: Option<Arc<File>>,
 }
 
 impl Default for Config {
@@ -532,7 +778,12 @@ impl Default for Config {
             #[cfg(feature = "chromeos")]
             privileged_quota_uids: Default::default(),
             use_dax: false,
+            dax_window_size: 1 << 33,
+            dax_reclaim: false,
             posix_acl: true,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+            audit_log: None,
         }
     }
 }
@@ -541,7 +792,9 @@ impl Default for Config {
 /// system. To keep the implementation simple it servers the contents of its root directory. Users
 /// that wish to serve only a specific directory should set up the environment so that that
 /// directory ends up as the root of the file system process. One way to accomplish this is via a
-/// combination of mount namespaces and the pivot_root system call.
+/// combination of mount namespaces and the pivot_root system call. Alternatively, `new_at` serves
+/// an arbitrary directory, which is used when several directories need to be served by the same
+/// process.
 pub struct PassthroughFs {
     // File descriptors for various points in the file system tree.
     inodes: Mutex<MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>>,
@@ -552,6 +805,15 @@ pub struct PassthroughFs {
     handles: Mutex<BTreeMap<Handle, Arc<HandleData>>>,
     next_handle: AtomicU64,
 
+    // Files holding the locks of each lock owner in the client. See `LockOwnerData`.
+    lock_owners: Mutex<BTreeMap<LockOwnerKey, Arc<LockOwnerData>>>,
+
+    // Used to tell the client when a file it polled becomes ready, if the transport supports it.
+    notifier: Mutex<Option<Arc<dyn Notifier>>>,
+
+    // Sends the poll wakeups, started once the client first asks for one.
+    poll_wakeups: Mutex<Option<PollWakeups>>,
+
     // File descriptor pointing to the `/proc` directory. This is used to convert an fd from
     // `inodes` into one that can go into `handles`. This is accomplished by reading the
     // `self/fd/{}` symlink. We keep an open fd here in case the file system tree that we are meant
@@ -568,10 +830,6 @@ pub struct PassthroughFs {
     // Whether zero message opendir is supported by the kernel driver.
     zero_message_opendir: AtomicBool,
 
-    // Used to ensure that only one thread at a time uses chdir(). Since chdir() affects the
-    // process-wide CWD, we cannot allow more than one thread to do it at the same time.
-    chdir_mutex: Mutex<()>,
-
     // Used when creating files / directories / nodes. Since the umask is process-wide, we can only
     // allow one thread at a time to change it.
     umask: Mutex<Umask>,
@@ -582,11 +840,22 @@ pub struct PassthroughFs {
     #[cfg(feature = "chromeos")]
     dbus_fd: Option<std::os::unix::io::RawFd>,
 
+    // The directory that is opened as the root inode in `init`.
+    root: CString,
+
     cfg: Config,
 }
 
 impl PassthroughFs {
     pub fn new(cfg: Config) -> io::Result<PassthroughFs> {
+        PassthroughFs::new_at(cfg, Path::new("/"))
+    }
+
+    /// Creates a `PassthroughFs` that serves `root` instead of the root directory of the process.
+    pub fn new_at(cfg: Config, root: &Path) -> io::Result<PassthroughFs> {
+        let root = CString::new(root.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
         // Safe because this is a constant value and a valid C string.
         let proc_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_CSTR) };
 
@@ -628,13 +897,17 @@ impl PassthroughFs {
             handles: Mutex::new(BTreeMap::new()),
             next_handle: AtomicU64::new(1),
 
+            lock_owners: Mutex::new(BTreeMap::new()),
+
+            notifier: Mutex::new(None),
+            poll_wakeups: Mutex::new(None),
+
             proc,
 
             writeback: AtomicBool::new(false),
             zero_message_open: AtomicBool::new(false),
             zero_message_opendir: AtomicBool::new(false),
 
-            chdir_mutex: Mutex::new(()),
             umask: Mutex::new(Umask),
 
             #[cfg(feature = "chromeos")]
@@ -642,6 +915,7 @@ impl PassthroughFs {
             #[cfg(feature = "chromeos")]
             dbus_fd,
 
+            root,
             cfg,
         })
     }
@@ -651,12 +925,14 @@ impl PassthroughFs {
     }
 
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
-        #[cfg_attr(not(feature = "chromeos"), allow(unused_mut))]
         let mut keep_rds = vec![self.proc.as_raw_descriptor()];
         #[cfg(feature = "chromeos")]
         if let Some(fd) = self.dbus_fd {
             keep_rds.push(fd);
         }
+        if let Some(log) = &self.cfg.audit_log {
+            keep_rds.push(log.as_raw_descriptor());
+        }
         keep_rds
     }
 
@@ -679,6 +955,110 @@ impl PassthroughFs {
         Cow::Owned(CString::new(newname).expect("Failed to re-write xattr name"))
     }
 
+    // Switches the credentials of the current thread to the host IDs of the caller in `ctx`.
+    fn set_creds(&self, ctx: Context) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        set_creds(
+            self.cfg.uid_map.to_host(ctx.uid),
+            self.cfg.gid_map.to_host(ctx.gid),
+        )
+    }
+
+    // Translates the owner of `st` into the IDs seen by the guest.
+    fn guest_stat(&self, mut st: libc::stat64) -> libc::stat64 {
+        st.st_uid = self.cfg.uid_map.to_guest(st.st_uid);
+        st.st_gid = self.cfg.gid_map.to_guest(st.st_gid);
+        st
+    }
+
+    // Returns the path of `inode`, or of the entry `name` in `inode`, for the audit log.
+    fn audit_path(&self, inode: Inode, name: Option<&CStr>) -> PathBuf {
+        let mut path = self
+            .find_inode(inode)
+            .and_then(|data| {
+                let link = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
+                    .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+                let mut buf = vec![0; libc::PATH_MAX as usize];
+
+                // Safe because this will only modify the contents of `buf` and we check the return
+                // value.
+                let res = unsafe {
+                    libc::readlinkat(
+                        self.proc.as_raw_descriptor(),
+                        link.as_ptr(),
+                        buf.as_mut_ptr() as *mut libc::c_char,
+                        buf.len(),
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                buf.truncate(res as usize);
+                Ok(PathBuf::from(OsStr::from_bytes(&buf)))
+            })
+            .unwrap_or_else(|_| PathBuf::from(format!("<inode {}>", inode)));
+
+        if let Some(name) = name {
+            path.push(OsStr::from_bytes(name.to_bytes()));
+        }
+        path
+    }
+
+    // Runs `f`, which handles the request `op` from `ctx`, and records it in the audit log if there
+    // is one. `paths` are the entries modified by the request, given as an inode or as a name in a
+    // directory inode.
+    fn audited<T, F>(
+        &self,
+        ctx: Context,
+        op: &str,
+        paths: &[(Inode, Option<&CStr>)],
+        f: F,
+    ) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        let log = match &self.cfg.audit_log {
+            Some(log) => log,
+            None => return f(),
+        };
+
+        // Resolve the paths first since the request may remove or rename them.
+        let paths: Vec<PathBuf> = paths
+            .iter()
+            .map(|&(inode, name)| self.audit_path(inode, name))
+            .collect();
+
+        let res = f();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = format!(
+            "{}.{:06} {} uid={} gid={} pid={}",
+            now.as_secs(),
+            now.subsec_micros(),
+            op,
+            ctx.uid,
+            ctx.gid,
+            ctx.pid
+        );
+        for path in &paths {
+            record.push_str(&format!(" {:?}", path));
+        }
+        match &res {
+            Ok(_) => record.push_str(" ok\n"),
+            Err(e) => record.push_str(&format!(" err={}\n", e.raw_os_error().unwrap_or(libc::EIO))),
+        }
+
+        // Write each record with a single call so that records of concurrent requests don't get
+        // interleaved.
+        if let Err(e) = (&**log).write_all(record.as_bytes()) {
+            error!("failed to write to the fs audit log: {}", e);
+        }
+
+        res
+    }
+
     fn find_inode(&self, inode: Inode) -> io::Result<Arc<InodeData>> {
         self.inodes
             .lock()
@@ -779,7 +1159,7 @@ impl PassthroughFs {
         Entry {
             inode,
             generation: 0,
-            attr: st,
+            attr: self.guest_stat(st),
             attr_timeout: self.cfg.attr_timeout,
             entry_timeout: self.cfg.entry_timeout,
         }
@@ -872,10 +1252,127 @@ impl PassthroughFs {
         Err(ebadf())
     }
 
+    // Returns the file used to hold the locks of `key`, opening it if this is the first request
+    // from that lock owner.
+    fn get_lock_owner(&self, key: LockOwnerKey, handle: Handle) -> io::Result<Arc<LockOwnerData>> {
+        if let Some(data) = self.lock_owners.lock().get(&key) {
+            return Ok(Arc::clone(data));
+        }
+
+        // Open the new file with the same access mode as the client's file so that the host checks
+        // the lock type against the same permissions.
+        let file = if self.zero_message_open.load(Ordering::Relaxed) {
+            let data = self.find_inode(key.inode)?;
+            self.open_inode(&data, libc::O_RDWR)
+                .or_else(|_| self.open_inode(&data, libc::O_RDONLY))?
+        } else {
+            let data = self.find_handle(handle, key.inode)?;
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            let flags = unsafe { libc::fcntl(data.as_raw_descriptor(), libc::F_GETFL) };
+            if flags < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            self.open_fd(data.as_raw_descriptor(), flags & libc::O_ACCMODE)?
+        };
+
+        let data = Arc::new(LockOwnerData::new(file));
+
+        // Another thread may have opened a file for the same owner in the meantime, in which case
+        // we use that one instead.
+        Ok(Arc::clone(
+            self.lock_owners.lock().entry(key).or_insert(data),
+        ))
+    }
+
+    // Releases all locks held by `key`.
+    fn release_lock_owner(&self, key: LockOwnerKey) {
+        if let Some(data) = self.lock_owners.lock().remove(&key) {
+            // The locks are released when the last reference to the file is dropped.
+            data.release();
+        }
+    }
+
+    // Releases all locks held on `inode`.
+    fn release_inode_locks(&self, inode: Inode) {
+        let mut lock_owners = self.lock_owners.lock();
+
+        let first = LockOwnerKey {
+            inode,
+            owner: 0,
+            flock: false,
+        };
+        let last = LockOwnerKey {
+            inode,
+            owner: u64::MAX,
+            flock: true,
+        };
+        let keys: Vec<LockOwnerKey> = lock_owners.range(first..=last).map(|(k, _)| *k).collect();
+        let released: Vec<Arc<LockOwnerData>> =
+            keys.iter().filter_map(|k| lock_owners.remove(k)).collect();
+        release_all(&released);
+    }
+
+    fn do_setlk(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: &FileLock,
+        flags: u32,
+        wait: bool,
+    ) -> io::Result<()> {
+        let key = LockOwnerKey {
+            inode,
+            owner,
+            flock: flags & LK_FLOCK != 0,
+        };
+
+        // An owner we haven't seen before doesn't hold any locks.
+        if lock.type_ == libc::F_UNLCK as u32 && !self.lock_owners.lock().contains_key(&key) {
+            return Ok(());
+        }
+
+        let data = self.get_lock_owner(key, handle)?;
+        if wait {
+            // The wait is abandoned once the owner's locks are released, e.g. because the client
+            // closed the file after the waiting process was interrupted.
+            data.wait_for_lock(lock, key.flock)
+        } else {
+            set_lock(&data.file, lock, key.flock, false)
+        }
+    }
+
+    // Sends a poll wakeup for `khandle` once `data` is ready for any of `events`. Fails with ENOSYS
+    // if there is no way to notify the client, which then stops waiting for wakeups.
+    fn schedule_poll_wakeup(
+        &self,
+        data: &Arc<dyn AsRawDescriptor + Send + Sync>,
+        khandle: u64,
+        events: u32,
+    ) -> io::Result<()> {
+        let mut poll_wakeups = self.poll_wakeups.lock();
+        if let Some(poll_wakeups) = &*poll_wakeups {
+            return poll_wakeups.add(data, khandle, events);
+        }
+
+        let notifier = self
+            .notifier
+            .lock()
+            .clone()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))?;
+        let new_wakeups = PollWakeups::new(notifier)?;
+        new_wakeups.add(data, khandle, events)?;
+        *poll_wakeups = Some(new_wakeups);
+
+        Ok(())
+    }
+
     fn do_getattr(&self, inode: &InodeData) -> io::Result<(libc::stat64, Duration)> {
         let st = stat(inode)?;
 
-        Ok((st, self.cfg.attr_timeout))
+        Ok((self.guest_stat(st), self.cfg.attr_timeout))
     }
 
     fn do_unlink(&self, parent: &InodeData, name: &CStr, flags: libc::c_int) -> io::Result<()> {
@@ -914,7 +1411,7 @@ impl PassthroughFs {
         F: FnOnce() -> T,
     {
         let root = self.find_inode(ROOT_ID).expect("failed to find root inode");
-        let chdir_lock = self.chdir_mutex.lock();
+        let chdir_lock = CHDIR_MUTEX.lock();
 
         // Safe because this doesn't modify any memory and we check the return value. Since the
         // fchdir should never fail we just use debug_asserts.
@@ -1045,7 +1542,7 @@ impl PassthroughFs {
         let in_attr = fsxattr::from_reader(r)?;
 
         #[cfg(feature = "chromeos")]
-        let st = stat(&*data)?;
+        let st = self.guest_stat(stat(&*data)?);
 
         // Changing quota project ID requires CAP_FOWNER or being file owner.
         // Here we use privileged_quota_uids because we cannot perform a CAP_FOWNER check.
@@ -1303,11 +1800,12 @@ impl PassthroughFs {
     }
 }
 
+// Drops `count` references to `inode`, returning true if that was the last reference.
 fn forget_one(
     inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
     inode: Inode,
     count: u64,
-) {
+) -> bool {
     if let Some(data) = inodes.get(&inode) {
         // Acquiring the write lock on the inode map prevents new lookups from incrementing the
         // refcount but there is the possibility that a previous lookup already acquired a
@@ -1333,11 +1831,127 @@ fn forget_one(
                     // until we release the lock. So there's is no other release store for us to
                     // synchronize with before deleting the entry.
                     inodes.remove(&inode);
+                    return true;
                 }
-                break;
+                return false;
             }
         }
     }
+
+    false
+}
+
+// Returns the subset of `events` that `f` is ready for, waiting up to `timeout` milliseconds for
+// at least one of them.
+fn poll_once<F: AsRawDescriptor + ?Sized>(f: &F, events: u32, timeout: c_int) -> io::Result<u32> {
+    let mut pollfd = libc::pollfd {
+        fd: f.as_raw_descriptor(),
+        events: events as libc::c_short,
+        revents: 0,
+    };
+
+    // Safe because this only modifies `pollfd` and we check the return value.
+    let res = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(pollfd.revents as u16 as u32)
+}
+
+// Converts a FUSE lock into a `flock64` describing the same range.
+fn lock_to_flock(lock: &FileLock) -> io::Result<libc::flock64> {
+    if lock.start > OFFSET_MAX || lock.end < lock.start {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    match lock.type_ as c_int {
+        libc::F_RDLCK | libc::F_WRLCK | libc::F_UNLCK => {}
+        _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    }
+
+    // Safe because this is a plain C struct and all zeroes is a valid value. OFD locks also
+    // require `l_pid` to be 0.
+    let mut flock: libc::flock64 = unsafe { mem::zeroed() };
+    flock.l_type = lock.type_ as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = lock.start as libc::off64_t;
+    // A length of 0 extends the lock to the end of the file.
+    flock.l_len = if lock.end >= OFFSET_MAX {
+        0
+    } else {
+        (lock.end - lock.start + 1) as libc::off64_t
+    };
+
+    Ok(flock)
+}
+
+// Converts a lock returned by `F_OFD_GETLK` into a FUSE lock.
+fn flock_to_lock(flock: &libc::flock64) -> FileLock {
+    let start = flock.l_start as u64;
+    let end = if flock.l_len == 0 {
+        OFFSET_MAX
+    } else {
+        start + flock.l_len as u64 - 1
+    };
+
+    FileLock {
+        start,
+        end,
+        type_: flock.l_type as u32,
+        // The pid of a process on the host means nothing to the client.
+        pid: 0,
+    }
+}
+
+// Places `lock` on `file`, using `flock` if `flock` is true and an OFD lock otherwise. If `wait` is
+// false, fails with `EAGAIN` if a conflicting lock is held through another file.
+fn set_lock(file: &File, lock: &FileLock, flock: bool, wait: bool) -> io::Result<()> {
+    let res = if flock {
+        let operation = match lock.type_ as c_int {
+            libc::F_RDLCK => libc::LOCK_SH,
+            libc::F_WRLCK => libc::LOCK_EX,
+            libc::F_UNLCK => libc::LOCK_UN,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let operation = if wait {
+            operation
+        } else {
+            operation | libc::LOCK_NB
+        };
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        unsafe { libc::flock(file.as_raw_descriptor(), operation) }
+    } else {
+        let flock = lock_to_flock(lock)?;
+        let cmd = if wait {
+            libc::F_OFD_SETLKW
+        } else {
+            libc::F_OFD_SETLK
+        };
+
+        // Safe because this only reads `flock` and we check the return value.
+        unsafe {
+            libc::fcntl(
+                file.as_raw_descriptor(),
+                cmd,
+                &flock as *const libc::flock64,
+            )
+        }
+    };
+
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EACCES) {
+            // POSIX allows either error for a conflicting lock but FUSE expects `EAGAIN`.
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        } else {
+            Err(e)
+        }
+    } else {
+        Ok(())
+    }
 }
 
 // Strips any `user.virtiofs.` prefix from `buf`. If buf contains one or more nul-bytes, each
@@ -1376,12 +1990,9 @@ impl FileSystem for PassthroughFs {
     type DirIter = ReadDir<Box<[u8]>>;
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
-        // Safe because this is a constant value and a valid C string.
-        let root = unsafe { CStr::from_bytes_with_nul_unchecked(ROOT_CSTR) };
-
         let flags = libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
         // Safe because this doesn't modify any memory and we check the return value.
-        let raw_descriptor = unsafe { libc::openat(libc::AT_FDCWD, root.as_ptr(), flags) };
+        let raw_descriptor = unsafe { libc::openat(libc::AT_FDCWD, self.root.as_ptr(), flags) };
         if raw_descriptor < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -1416,7 +2027,9 @@ impl FileSystem for PassthroughFs {
         let mut opts = FsOptions::DO_READDIRPLUS
             | FsOptions::READDIRPLUS_AUTO
             | FsOptions::EXPORT_SUPPORT
-            | FsOptions::DONT_MASK;
+            | FsOptions::DONT_MASK
+            | FsOptions::POSIX_LOCKS
+            | FsOptions::FLOCK_LOCKS;
         if self.cfg.posix_acl {
             opts |= FsOptions::POSIX_ACL;
         }
@@ -1438,6 +2051,11 @@ impl FileSystem for PassthroughFs {
     }
 
     fn destroy(&self) {
+        let released: Vec<Arc<LockOwnerData>> = mem::take(&mut *self.lock_owners.lock())
+            .into_iter()
+            .map(|(_, data)| data)
+            .collect();
+        release_all(&released);
         self.handles.lock().clear();
         self.inodes.lock().clear();
     }
@@ -1471,14 +2089,18 @@ impl FileSystem for PassthroughFs {
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
         let mut inodes = self.inodes.lock();
 
-        forget_one(&mut inodes, inode, count)
+        if forget_one(&mut inodes, inode, count) {
+            self.release_inode_locks(inode);
+        }
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
         let mut inodes = self.inodes.lock();
 
         for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
+            if forget_one(&mut inodes, inode, count) {
+                self.release_inode_locks(inode);
+            }
         }
     }
 
@@ -1517,26 +2139,30 @@ impl FileSystem for PassthroughFs {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
-        let data = self.find_inode(parent)?;
+        self.audited(ctx, "mkdir", &[(parent, Some(name))], || {
+            let data = self.find_inode(parent)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-        let res = {
-            let mut um = self.umask.lock();
-            let _scoped_umask = um.set(umask);
+            let (_uid, _gid) = self.set_creds(ctx)?;
+            let res = {
+                let mut um = self.umask.lock();
+                let _scoped_umask = um.set(umask);
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            unsafe { libc::mkdirat(data.as_raw_descriptor(), name.as_ptr(), mode) }
-        };
-        if res == 0 {
-            self.do_lookup(&data, name)
-        } else {
-            Err(io::Error::last_os_error())
-        }
+                // Safe because this doesn't modify any memory and we check the return value.
+                unsafe { libc::mkdirat(data.as_raw_descriptor(), name.as_ptr(), mode) }
+            };
+            if res == 0 {
+                self.do_lookup(&data, name)
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
-    fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
-        let data = self.find_inode(parent)?;
-        self.do_unlink(&data, name, libc::AT_REMOVEDIR)
+    fn rmdir(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.audited(ctx, "rmdir", &[(parent, Some(name))], || {
+            let data = self.find_inode(parent)?;
+            self.do_unlink(&data, name, libc::AT_REMOVEDIR)
+        })
     }
 
     fn readdir(
@@ -1580,10 +2206,27 @@ impl FileSystem for PassthroughFs {
         inode: Inode,
         _flags: u32,
         handle: Handle,
-        _flush: bool,
-        _flock_release: bool,
-        _lock_owner: Option<u64>,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
+        if let Some(owner) = lock_owner {
+            if flush {
+                self.release_lock_owner(LockOwnerKey {
+                    inode,
+                    owner,
+                    flock: false,
+                });
+            }
+            if flock_release {
+                self.release_lock_owner(LockOwnerKey {
+                    inode,
+                    owner,
+                    flock: true,
+                });
+            }
+        }
+
         if self.zero_message_open.load(Ordering::Relaxed) {
             Ok(())
         } else {
@@ -1598,38 +2241,40 @@ impl FileSystem for PassthroughFs {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
-        let data = self.find_inode(parent)?;
+        self.audited(ctx, "tmpfile", &[(parent, None)], || {
+            let data = self.find_inode(parent)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
 
-        let tmpflags = libc::O_RDWR | libc::O_TMPFILE | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+            let tmpflags = libc::O_RDWR | libc::O_TMPFILE | libc::O_CLOEXEC | libc::O_NOFOLLOW;
 
-        // Safe because this is a valid c string.
-        let current_dir = unsafe { CStr::from_bytes_with_nul_unchecked(b".\0") };
+            // Safe because this is a valid c string.
+            let current_dir = unsafe { CStr::from_bytes_with_nul_unchecked(b".\0") };
 
-        let fd = {
-            let mut um = self.umask.lock();
-            let _scoped_umask = um.set(umask);
+            let fd = {
+                let mut um = self.umask.lock();
+                let _scoped_umask = um.set(umask);
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            unsafe {
-                libc::openat(
-                    data.as_raw_descriptor(),
-                    current_dir.as_ptr(),
-                    tmpflags,
-                    mode,
-                )
+                // Safe because this doesn't modify any memory and we check the return value.
+                unsafe {
+                    libc::openat(
+                        data.as_raw_descriptor(),
+                        current_dir.as_ptr(),
+                        tmpflags,
+                        mode,
+                    )
+                }
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
             }
-        };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
 
-        // Safe because we just opened this fd.
-        let tmpfile = unsafe { File::from_raw_descriptor(fd) };
+            // Safe because we just opened this fd.
+            let tmpfile = unsafe { File::from_raw_descriptor(fd) };
 
-        let st = stat(&tmpfile)?;
-        Ok(self.add_entry(tmpfile, st, tmpflags))
+            let st = stat(&tmpfile)?;
+            Ok(self.add_entry(tmpfile, st, tmpflags))
+        })
     }
 
     fn create(
@@ -1641,52 +2286,56 @@ impl FileSystem for PassthroughFs {
         flags: u32,
         umask: u32,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
-        let data = self.find_inode(parent)?;
+        self.audited(ctx, "create", &[(parent, Some(name))], || {
+            let data = self.find_inode(parent)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
 
-        let create_flags =
-            (flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW) & !libc::O_DIRECT;
+            let create_flags = (flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW)
+                & !libc::O_DIRECT;
 
-        let fd = {
-            let mut um = self.umask.lock();
-            let _scoped_umask = um.set(umask);
+            let fd = {
+                let mut um = self.umask.lock();
+                let _scoped_umask = um.set(umask);
 
-            // Safe because this doesn't modify any memory and we check the return value. We don't
-            // really check `flags` because if the kernel can't handle poorly specified flags then
-            // we have much bigger problems.
-            unsafe { libc::openat(data.as_raw_descriptor(), name.as_ptr(), create_flags, mode) }
-        };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
+                // Safe because this doesn't modify any memory and we check the return value. We don't
+                // really check `flags` because if the kernel can't handle poorly specified flags then
+                // we have much bigger problems.
+                unsafe { libc::openat(data.as_raw_descriptor(), name.as_ptr(), create_flags, mode) }
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
 
-        // Safe because we just opened this fd.
-        let file = unsafe { File::from_raw_descriptor(fd) };
+            // Safe because we just opened this fd.
+            let file = unsafe { File::from_raw_descriptor(fd) };
 
-        let st = stat(&file)?;
-        let entry = self.add_entry(file, st, create_flags);
+            let st = stat(&file)?;
+            let entry = self.add_entry(file, st, create_flags);
 
-        let (handle, opts) = if self.zero_message_open.load(Ordering::Relaxed) {
-            (None, OpenOptions::KEEP_CACHE)
-        } else {
-            self.do_open(
-                entry.inode,
-                flags & !((libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY) as u32),
-            )
-            .map_err(|e| {
-                // Don't leak the entry.
-                self.forget(ctx, entry.inode, 1);
-                e
-            })?
-        };
+            let (handle, opts) = if self.zero_message_open.load(Ordering::Relaxed) {
+                (None, OpenOptions::KEEP_CACHE)
+            } else {
+                self.do_open(
+                    entry.inode,
+                    flags & !((libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY) as u32),
+                )
+                .map_err(|e| {
+                    // Don't leak the entry.
+                    self.forget(ctx, entry.inode, 1);
+                    e
+                })?
+            };
 
-        Ok((entry, handle, opts))
+            Ok((entry, handle, opts))
+        })
     }
 
-    fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
-        let data = self.find_inode(parent)?;
-        self.do_unlink(&data, name, 0)
+    fn unlink(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.audited(ctx, "unlink", &[(parent, Some(name))], || {
+            let data = self.find_inode(parent)?;
+            self.do_unlink(&data, name, 0)
+        })
     }
 
     fn read<W: io::Write + ZeroCopyWriter>(
@@ -1729,7 +2378,7 @@ impl FileSystem for PassthroughFs {
 
     fn write<R: io::Read + ZeroCopyReader>(
         &self,
-        _ctx: Context,
+        ctx: Context,
         inode: Inode,
         handle: Handle,
         mut r: R,
@@ -1739,39 +2388,41 @@ impl FileSystem for PassthroughFs {
         _delayed_write: bool,
         flags: u32,
     ) -> io::Result<usize> {
-        // When the WRITE_KILL_PRIV flag is set, drop CAP_FSETID so that the kernel will
-        // automatically clear the setuid and setgid bits for us.
-        let _fsetid = if flags & WRITE_KILL_PRIV != 0 {
-            Some(drop_cap_fsetid()?)
-        } else {
-            None
-        };
+        self.audited(ctx, "write", &[(inode, None)], || {
+            // When the WRITE_KILL_PRIV flag is set, drop CAP_FSETID so that the kernel will
+            // automatically clear the setuid and setgid bits for us.
+            let _fsetid = if flags & WRITE_KILL_PRIV != 0 {
+                Some(drop_cap_fsetid()?)
+            } else {
+                None
+            };
 
-        if self.zero_message_open.load(Ordering::Relaxed) {
-            let data = self.find_inode(inode)?;
+            if self.zero_message_open.load(Ordering::Relaxed) {
+                let data = self.find_inode(inode)?;
 
-            let mut file = data.file.lock();
-            let mut flags = file.1;
-            match flags & libc::O_ACCMODE {
-                libc::O_RDONLY => {
-                    flags &= !libc::O_RDONLY;
-                    flags |= libc::O_RDWR;
+                let mut file = data.file.lock();
+                let mut flags = file.1;
+                match flags & libc::O_ACCMODE {
+                    libc::O_RDONLY => {
+                        flags &= !libc::O_RDONLY;
+                        flags |= libc::O_RDWR;
 
-                    // We need to get a writable handle for this file.
-                    let newfile = self.open_fd(file.0.as_raw_descriptor(), libc::O_RDWR)?;
-                    *file = (newfile, flags);
+                        // We need to get a writable handle for this file.
+                        let newfile = self.open_fd(file.0.as_raw_descriptor(), libc::O_RDWR)?;
+                        *file = (newfile, flags);
+                    }
+                    libc::O_WRONLY | libc::O_RDWR => {}
+                    _ => panic!("Unexpected flags: {:#x}", flags),
                 }
-                libc::O_WRONLY | libc::O_RDWR => {}
-                _ => panic!("Unexpected flags: {:#x}", flags),
-            }
 
-            r.read_to(&mut file.0, size as usize, offset)
-        } else {
-            let data = self.find_handle(handle, inode)?;
+                r.read_to(&mut file.0, size as usize, offset)
+            } else {
+                let data = self.find_handle(handle, inode)?;
 
-            let mut f = data.file.lock();
-            r.read_to(&mut f, size as usize, offset)
-        }
+                let mut f = data.file.lock();
+                r.read_to(&mut f, size as usize, offset)
+            }
+        })
     }
 
     fn getattr(
@@ -1786,164 +2437,172 @@ impl FileSystem for PassthroughFs {
 
     fn setattr(
         &self,
-        _ctx: Context,
+        ctx: Context,
         inode: Inode,
         attr: libc::stat64,
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
-        let inode_data = self.find_inode(inode)?;
+        self.audited(ctx, "setattr", &[(inode, None)], || {
+            let inode_data = self.find_inode(inode)?;
 
-        enum Data {
-            Handle(Arc<HandleData>, RawDescriptor),
-            ProcPath(CString),
-        }
+            enum Data {
+                Handle(Arc<HandleData>, RawDescriptor),
+                ProcPath(CString),
+            }
 
-        // If we have a handle then use it otherwise get a new fd from the inode.
-        let data = if let Some(handle) = handle.filter(|&h| h != 0) {
-            let hd = self.find_handle(handle, inode)?;
+            // If we have a handle then use it otherwise get a new fd from the inode.
+            let data = if let Some(handle) = handle.filter(|&h| h != 0) {
+                let hd = self.find_handle(handle, inode)?;
 
-            let fd = hd.file.lock().as_raw_descriptor();
-            Data::Handle(hd, fd)
-        } else {
-            let pathname = CString::new(format!("self/fd/{}", inode_data.as_raw_descriptor()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            Data::ProcPath(pathname)
-        };
+                let fd = hd.file.lock().as_raw_descriptor();
+                Data::Handle(hd, fd)
+            } else {
+                let pathname = CString::new(format!("self/fd/{}", inode_data.as_raw_descriptor()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Data::ProcPath(pathname)
+            };
 
-        if valid.contains(SetattrValid::MODE) {
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = unsafe {
-                match data {
-                    Data::Handle(_, fd) => libc::fchmod(fd, attr.st_mode),
-                    Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc.as_raw_descriptor(), p.as_ptr(), attr.st_mode, 0)
+            if valid.contains(SetattrValid::MODE) {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    match data {
+                        Data::Handle(_, fd) => libc::fchmod(fd, attr.st_mode),
+                        Data::ProcPath(ref p) => libc::fchmodat(
+                            self.proc.as_raw_descriptor(),
+                            p.as_ptr(),
+                            attr.st_mode,
+                            0,
+                        ),
                     }
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
                 }
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
             }
-        }
-
-        if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
-            let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
-            } else {
-                // Cannot use -1 here because these are unsigned values.
-                ::std::u32::MAX
-            };
-            let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
-            } else {
-                // Cannot use -1 here because these are unsigned values.
-                ::std::u32::MAX
-            };
 
-            // Safe because this is a constant value and a valid C string.
-            let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+            if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+                let uid = if valid.contains(SetattrValid::UID) {
+                    self.cfg.uid_map.to_host(attr.st_uid)
+                } else {
+                    // Cannot use -1 here because these are unsigned values.
+                    ::std::u32::MAX
+                };
+                let gid = if valid.contains(SetattrValid::GID) {
+                    self.cfg.gid_map.to_host(attr.st_gid)
+                } else {
+                    // Cannot use -1 here because these are unsigned values.
+                    ::std::u32::MAX
+                };
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = unsafe {
-                libc::fchownat(
-                    inode_data.as_raw_descriptor(),
-                    empty.as_ptr(),
-                    uid,
-                    gid,
-                    libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
-                )
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+                // Safe because this is a constant value and a valid C string.
+                let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::fchownat(
+                        inode_data.as_raw_descriptor(),
+                        empty.as_ptr(),
+                        uid,
+                        gid,
+                        libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
             }
-        }
 
-        if valid.contains(SetattrValid::SIZE) {
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::ftruncate64(fd, attr.st_size) },
-                _ => {
-                    // There is no `ftruncateat` so we need to get a new fd and truncate it.
-                    let f = self.open_inode(&inode_data, libc::O_NONBLOCK | libc::O_RDWR)?;
-                    unsafe { libc::ftruncate64(f.as_raw_descriptor(), attr.st_size) }
+            if valid.contains(SetattrValid::SIZE) {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = match data {
+                    Data::Handle(_, fd) => unsafe { libc::ftruncate64(fd, attr.st_size) },
+                    _ => {
+                        // There is no `ftruncateat` so we need to get a new fd and truncate it.
+                        let f = self.open_inode(&inode_data, libc::O_NONBLOCK | libc::O_RDWR)?;
+                        unsafe { libc::ftruncate64(f.as_raw_descriptor(), attr.st_size) }
+                    }
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
                 }
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
             }
-        }
-
-        if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
-            let mut tvs = [
-                libc::timespec {
-                    tv_sec: 0,
-                    tv_nsec: libc::UTIME_OMIT,
-                },
-                libc::timespec {
-                    tv_sec: 0,
-                    tv_nsec: libc::UTIME_OMIT,
-                },
-            ];
 
-            if valid.contains(SetattrValid::ATIME_NOW) {
-                tvs[0].tv_nsec = libc::UTIME_NOW;
-            } else if valid.contains(SetattrValid::ATIME) {
-                tvs[0].tv_sec = attr.st_atime;
-                tvs[0].tv_nsec = attr.st_atime_nsec;
-            }
+            if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
+                let mut tvs = [
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: libc::UTIME_OMIT,
+                    },
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: libc::UTIME_OMIT,
+                    },
+                ];
+
+                if valid.contains(SetattrValid::ATIME_NOW) {
+                    tvs[0].tv_nsec = libc::UTIME_NOW;
+                } else if valid.contains(SetattrValid::ATIME) {
+                    tvs[0].tv_sec = attr.st_atime;
+                    tvs[0].tv_nsec = attr.st_atime_nsec;
+                }
 
-            if valid.contains(SetattrValid::MTIME_NOW) {
-                tvs[1].tv_nsec = libc::UTIME_NOW;
-            } else if valid.contains(SetattrValid::MTIME) {
-                tvs[1].tv_sec = attr.st_mtime;
-                tvs[1].tv_nsec = attr.st_mtime_nsec;
-            }
+                if valid.contains(SetattrValid::MTIME_NOW) {
+                    tvs[1].tv_nsec = libc::UTIME_NOW;
+                } else if valid.contains(SetattrValid::MTIME) {
+                    tvs[1].tv_sec = attr.st_mtime;
+                    tvs[1].tv_nsec = attr.st_mtime_nsec;
+                }
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::futimens(fd, tvs.as_ptr()) },
-                Data::ProcPath(ref p) => unsafe {
-                    libc::utimensat(self.proc.as_raw_descriptor(), p.as_ptr(), tvs.as_ptr(), 0)
-                },
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = match data {
+                    Data::Handle(_, fd) => unsafe { libc::futimens(fd, tvs.as_ptr()) },
+                    Data::ProcPath(ref p) => unsafe {
+                        libc::utimensat(self.proc.as_raw_descriptor(), p.as_ptr(), tvs.as_ptr(), 0)
+                    },
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
             }
-        }
 
-        self.do_getattr(&inode_data)
+            self.do_getattr(&inode_data)
+        })
     }
 
     fn rename(
         &self,
-        _ctx: Context,
+        ctx: Context,
         olddir: Inode,
         oldname: &CStr,
         newdir: Inode,
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
-        let old_inode = self.find_inode(olddir)?;
-        let new_inode = self.find_inode(newdir)?;
+        let paths = [(olddir, Some(oldname)), (newdir, Some(newname))];
+        self.audited(ctx, "rename", &paths, || {
+            let old_inode = self.find_inode(olddir)?;
+            let new_inode = self.find_inode(newdir)?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
-        // and we have glibc 2.28.
-        let res = unsafe {
-            libc::syscall(
-                libc::SYS_renameat2,
-                old_inode.as_raw_descriptor(),
-                oldname.as_ptr(),
-                new_inode.as_raw_descriptor(),
-                newname.as_ptr(),
-                flags,
-            )
-        };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            // Safe because this doesn't modify any memory and we check the return value.
+            // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
+            // and we have glibc 2.28.
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_renameat2,
+                    old_inode.as_raw_descriptor(),
+                    oldname.as_ptr(),
+                    new_inode.as_raw_descriptor(),
+                    newname.as_ptr(),
+                    flags,
+                )
+            };
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
     fn mknod(
@@ -1955,60 +2614,65 @@ impl FileSystem for PassthroughFs {
         rdev: u32,
         umask: u32,
     ) -> io::Result<Entry> {
-        let data = self.find_inode(parent)?;
-
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-
-        let res = {
-            let mut um = self.umask.lock();
-            let _scoped_umask = um.set(umask);
+        self.audited(ctx, "mknod", &[(parent, Some(name))], || {
+            let data = self.find_inode(parent)?;
+
+            let (_uid, _gid) = self.set_creds(ctx)?;
+
+            let res = {
+                let mut um = self.umask.lock();
+                let _scoped_umask = um.set(umask);
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                unsafe {
+                    libc::mknodat(
+                        data.as_raw_descriptor(),
+                        name.as_ptr(),
+                        mode as libc::mode_t,
+                        rdev as libc::dev_t,
+                    )
+                }
+            };
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            unsafe {
-                libc::mknodat(
-                    data.as_raw_descriptor(),
-                    name.as_ptr(),
-                    mode as libc::mode_t,
-                    rdev as libc::dev_t,
-                )
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                self.do_lookup(&data, name)
             }
-        };
-
-        if res < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            self.do_lookup(&data, name)
-        }
+        })
     }
 
     fn link(
         &self,
-        _ctx: Context,
+        ctx: Context,
         inode: Inode,
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
-        let data = self.find_inode(inode)?;
-        let new_inode = self.find_inode(newparent)?;
+        let paths = [(inode, None), (newparent, Some(newname))];
+        self.audited(ctx, "link", &paths, || {
+            let data = self.find_inode(inode)?;
+            let new_inode = self.find_inode(newparent)?;
 
-        let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::linkat(
-                self.proc.as_raw_descriptor(),
-                path.as_ptr(),
-                new_inode.as_raw_descriptor(),
-                newname.as_ptr(),
-                libc::AT_SYMLINK_FOLLOW,
-            )
-        };
-        if res == 0 {
-            self.do_lookup(&new_inode, newname)
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::linkat(
+                    self.proc.as_raw_descriptor(),
+                    path.as_ptr(),
+                    new_inode.as_raw_descriptor(),
+                    newname.as_ptr(),
+                    libc::AT_SYMLINK_FOLLOW,
+                )
+            };
+            if res == 0 {
+                self.do_lookup(&new_inode, newname)
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
     fn symlink(
@@ -2018,18 +2682,21 @@ impl FileSystem for PassthroughFs {
         parent: Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
-        let data = self.find_inode(parent)?;
+        self.audited(ctx, "symlink", &[(parent, Some(name))], || {
+            let data = self.find_inode(parent)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res =
-            unsafe { libc::symlinkat(linkname.as_ptr(), data.as_raw_descriptor(), name.as_ptr()) };
-        if res == 0 {
-            self.do_lookup(&data, name)
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::symlinkat(linkname.as_ptr(), data.as_raw_descriptor(), name.as_ptr())
+            };
+            if res == 0 {
+                self.do_lookup(&data, name)
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
     fn readlink(&self, _ctx: Context, inode: Inode) -> io::Result<Vec<u8>> {
@@ -2062,8 +2729,15 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
+        // POSIX locks are released whenever the owner closes any of its fds for the file.
+        self.release_lock_owner(LockOwnerKey {
+            inode,
+            owner: lock_owner,
+            flock: false,
+        });
+
         let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
             self.find_inode(inode)?
         } else {
@@ -2088,6 +2762,127 @@ impl FileSystem for PassthroughFs {
         }
     }
 
+    fn getlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<FileLock> {
+        let key = LockOwnerKey {
+            inode,
+            owner,
+            flock: false,
+        };
+        let data = self.get_lock_owner(key, handle)?;
+        let mut flock = lock_to_flock(&lock)?;
+
+        // Safe because this only modifies `flock` and we check the return value.
+        let res = unsafe {
+            libc::fcntl(
+                data.file.as_raw_descriptor(),
+                libc::F_OFD_GETLK,
+                &mut flock as *mut libc::flock64,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if flock.l_type == libc::F_UNLCK as libc::c_short {
+            Ok(FileLock {
+                type_: libc::F_UNLCK as u32,
+                ..lock
+            })
+        } else {
+            Ok(flock_to_lock(&flock))
+        }
+    }
+
+    fn setlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(inode, handle, owner, &lock, flags, false)
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(inode, handle, owner, &lock, flags, true)
+    }
+
+    fn poll(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        let data: Arc<dyn AsRawDescriptor + Send + Sync> =
+            if self.zero_message_open.load(Ordering::Relaxed) {
+                self.find_inode(inode)?
+            } else {
+                self.find_handle(handle, inode)?
+            };
+
+        let revents = poll_once(&*data, events, 0)?;
+        if revents == 0 && flags & POLL_SCHEDULE_NOTIFY != 0 {
+            self.schedule_poll_wakeup(&data, khandle, events)?;
+        }
+
+        Ok(revents)
+    }
+
+    fn set_notifier(&self, notifier: Arc<dyn Notifier>) {
+        *self.notifier.lock() = Some(notifier);
+    }
+
+    fn lseek(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
+            self.find_inode(inode)?
+        } else {
+            self.find_handle(handle, inode)?
+        };
+
+        // Safe because this doesn't modify any memory and we check the return value. Reads and
+        // writes always use an explicit offset so moving the file position doesn't affect them.
+        let res = unsafe {
+            libc::lseek64(
+                data.as_raw_descriptor(),
+                offset as libc::off64_t,
+                whence as c_int,
+            )
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as u64)
+        }
+    }
+
     fn fsync(&self, _ctx: Context, inode: Inode, datasync: bool, handle: Handle) -> io::Result<()> {
         if self.zero_message_open.load(Ordering::Relaxed) {
             let data = self.find_inode(inode)?;
@@ -2121,7 +2916,8 @@ impl FileSystem for PassthroughFs {
     fn access(&self, ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
         let data = self.find_inode(inode)?;
 
-        let st = stat(&*data)?;
+        // Compare against the owner seen by the guest since `ctx` has guest IDs.
+        let st = self.guest_stat(stat(&*data)?);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -2166,57 +2962,60 @@ impl FileSystem for PassthroughFs {
 
     fn setxattr(
         &self,
-        _ctx: Context,
+        ctx: Context,
         inode: Inode,
         name: &CStr,
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
-        // We can't allow the VM to set this xattr because an unprivileged process may use it to set
-        // a privileged xattr.
-        if self.cfg.rewrite_security_xattrs && name.to_bytes().starts_with(USER_VIRTIOFS_XATTR) {
-            return Err(io::Error::from_raw_os_error(libc::EPERM));
-        }
-
-        let data = self.find_inode(inode)?;
-        let name = self.rewrite_xattr_name(name);
+        self.audited(ctx, "setxattr", &[(inode, None)], || {
+            // We can't allow the VM to set this xattr because an unprivileged process may use it to set
+            // a privileged xattr.
+            if self.cfg.rewrite_security_xattrs && name.to_bytes().starts_with(USER_VIRTIOFS_XATTR)
+            {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
 
-        let res = if data.filetype == FileType::Other {
-            // For non-regular files and directories, we cannot open the fd normally. Instead we
-            // emulate an _at syscall by changing the CWD to /proc, running the path based syscall,
-            // and then setting the CWD back to the root directory.
-            let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let data = self.find_inode(inode)?;
+            let name = self.rewrite_xattr_name(name);
+
+            let res = if data.filetype == FileType::Other {
+                // For non-regular files and directories, we cannot open the fd normally. Instead we
+                // emulate an _at syscall by changing the CWD to /proc, running the path based syscall,
+                // and then setting the CWD back to the root directory.
+                let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                self.with_proc_chdir(|| unsafe {
+                    libc::setxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void,
+                        value.len() as libc::size_t,
+                        flags as c_int,
+                    )
+                })
+            } else {
+                // For regular files and directories, we can just use fsetxattr. Safe because this
+                // doesn't modify any memory and we check the return value.
+                unsafe {
+                    libc::fsetxattr(
+                        data.as_raw_descriptor(),
+                        name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void,
+                        value.len() as libc::size_t,
+                        flags as c_int,
+                    )
+                }
+            };
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            self.with_proc_chdir(|| unsafe {
-                libc::setxattr(
-                    path.as_ptr(),
-                    name.as_ptr(),
-                    value.as_ptr() as *const libc::c_void,
-                    value.len() as libc::size_t,
-                    flags as c_int,
-                )
-            })
-        } else {
-            // For regular files and directories, we can just use fsetxattr. Safe because this
-            // doesn't modify any memory and we check the return value.
-            unsafe {
-                libc::fsetxattr(
-                    data.as_raw_descriptor(),
-                    name.as_ptr(),
-                    value.as_ptr() as *const libc::c_void,
-                    value.len() as libc::size_t,
-                    flags as c_int,
-                )
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
             }
-        };
-
-        if res < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(())
-        }
+        })
     }
 
     fn getxattr(
@@ -2294,88 +3093,93 @@ impl FileSystem for PassthroughFs {
         }
     }
 
-    fn removexattr(&self, _ctx: Context, inode: Inode, name: &CStr) -> io::Result<()> {
-        // We don't allow the VM to set this xattr so we also pretend there is no value associated
-        // with it.
-        if self.cfg.rewrite_security_xattrs && name.to_bytes().starts_with(USER_VIRTIOFS_XATTR) {
-            return Err(io::Error::from_raw_os_error(libc::ENODATA));
-        }
+    fn removexattr(&self, ctx: Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        self.audited(ctx, "removexattr", &[(inode, None)], || {
+            // We don't allow the VM to set this xattr so we also pretend there is no value associated
+            // with it.
+            if self.cfg.rewrite_security_xattrs && name.to_bytes().starts_with(USER_VIRTIOFS_XATTR)
+            {
+                return Err(io::Error::from_raw_os_error(libc::ENODATA));
+            }
 
-        let data = self.find_inode(inode)?;
-        let name = self.rewrite_xattr_name(name);
+            let data = self.find_inode(inode)?;
+            let name = self.rewrite_xattr_name(name);
 
-        let res = if data.filetype == FileType::Other {
-            // For non-regular files and directories, we cannot open the fd normally. Instead we
-            // emulate an _at syscall by changing the CWD to /proc, running the path based syscall,
-            // and then setting the CWD back to the root directory.
-            let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let res = if data.filetype == FileType::Other {
+                // For non-regular files and directories, we cannot open the fd normally. Instead we
+                // emulate an _at syscall by changing the CWD to /proc, running the path based syscall,
+                // and then setting the CWD back to the root directory.
+                let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            self.with_proc_chdir(|| unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) })
-        } else {
-            // For regular files and directories, we can just use fremovexattr. Safe because this
-            // doesn't modify any memory and we check the return value.
-            unsafe { libc::fremovexattr(data.as_raw_descriptor(), name.as_ptr()) }
-        };
+                // Safe because this doesn't modify any memory and we check the return value.
+                self.with_proc_chdir(|| unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) })
+            } else {
+                // For regular files and directories, we can just use fremovexattr. Safe because this
+                // doesn't modify any memory and we check the return value.
+                unsafe { libc::fremovexattr(data.as_raw_descriptor(), name.as_ptr()) }
+            };
 
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
     fn fallocate(
         &self,
-        _ctx: Context,
+        ctx: Context,
         inode: Inode,
         handle: Handle,
         mode: u32,
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
-        let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
-            let data = self.find_inode(inode)?;
+        self.audited(ctx, "fallocate", &[(inode, None)], || {
+            let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
+                let data = self.find_inode(inode)?;
 
-            {
-                // fallocate needs a writable fd
-                let mut file = data.file.lock();
-                let mut flags = file.1;
-                match flags & libc::O_ACCMODE {
-                    libc::O_RDONLY => {
-                        flags &= !libc::O_RDONLY;
-                        flags |= libc::O_RDWR;
-
-                        // We need to get a writable handle for this file.
-                        let newfile = self.open_fd(file.0.as_raw_descriptor(), libc::O_RDWR)?;
-                        *file = (newfile, flags);
+                {
+                    // fallocate needs a writable fd
+                    let mut file = data.file.lock();
+                    let mut flags = file.1;
+                    match flags & libc::O_ACCMODE {
+                        libc::O_RDONLY => {
+                            flags &= !libc::O_RDONLY;
+                            flags |= libc::O_RDWR;
+
+                            // We need to get a writable handle for this file.
+                            let newfile = self.open_fd(file.0.as_raw_descriptor(), libc::O_RDWR)?;
+                            *file = (newfile, flags);
+                        }
+                        libc::O_WRONLY | libc::O_RDWR => {}
+                        _ => panic!("Unexpected flags: {:#x}", flags),
                     }
-                    libc::O_WRONLY | libc::O_RDWR => {}
-                    _ => panic!("Unexpected flags: {:#x}", flags),
                 }
-            }
 
-            data
-        } else {
-            self.find_handle(handle, inode)?
-        };
+                data
+            } else {
+                self.find_handle(handle, inode)?
+            };
 
-        let fd = data.as_raw_descriptor();
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::fallocate64(
-                fd,
-                mode as libc::c_int,
-                offset as libc::off64_t,
-                length as libc::off64_t,
-            )
-        };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            let fd = data.as_raw_descriptor();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::fallocate64(
+                    fd,
+                    mode as libc::c_int,
+                    offset as libc::off64_t,
+                    length as libc::off64_t,
+                )
+            };
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
     fn ioctl<R: io::Read>(
@@ -2462,39 +3266,41 @@ impl FileSystem for PassthroughFs {
         length: u64,
         flags: u64,
     ) -> io::Result<usize> {
-        // We need to change credentials during a write so that the kernel will remove setuid or
-        // setgid bits from the file if it was written to by someone other than the owner.
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-        let (src_data, dst_data): (Arc<dyn AsRawDescriptor>, Arc<dyn AsRawDescriptor>) =
-            if self.zero_message_open.load(Ordering::Relaxed) {
-                (self.find_inode(inode_src)?, self.find_inode(inode_dst)?)
-            } else {
-                (
-                    self.find_handle(handle_src, inode_src)?,
-                    self.find_handle(handle_dst, inode_dst)?,
-                )
-            };
+        self.audited(ctx, "copy_file_range", &[(inode_dst, None)], || {
+            // We need to change credentials during a write so that the kernel will remove setuid or
+            // setgid bits from the file if it was written to by someone other than the owner.
+            let (_uid, _gid) = self.set_creds(ctx)?;
+            let (src_data, dst_data): (Arc<dyn AsRawDescriptor>, Arc<dyn AsRawDescriptor>) =
+                if self.zero_message_open.load(Ordering::Relaxed) {
+                    (self.find_inode(inode_src)?, self.find_inode(inode_dst)?)
+                } else {
+                    (
+                        self.find_handle(handle_src, inode_src)?,
+                        self.find_handle(handle_dst, inode_dst)?,
+                    )
+                };
 
-        let src = src_data.as_raw_descriptor();
-        let dst = dst_data.as_raw_descriptor();
+            let src = src_data.as_raw_descriptor();
+            let dst = dst_data.as_raw_descriptor();
 
-        let res = unsafe {
-            libc::syscall(
-                libc::SYS_copy_file_range,
-                src,
-                &offset_src,
-                dst,
-                &offset_dst,
-                length,
-                flags,
-            )
-        };
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_copy_file_range,
+                    src,
+                    &offset_src,
+                    dst,
+                    &offset_dst,
+                    length,
+                    flags,
+                )
+            };
 
-        if res >= 0 {
-            Ok(res as usize)
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            if res >= 0 {
+                Ok(res as usize)
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
     }
 
     fn set_up_mapping<M: Mapper>(
@@ -2568,6 +3374,255 @@ impl FileSystem for PassthroughFs {
 mod tests {
     use super::*;
 
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Component, Path};
+    use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+
+    const CTX: Context = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    // Serves the host's root directory, with `path` created as an empty file. Returns the inode
+    // and an open handle for `path`.
+    fn open_test_file(path: &Path) -> (PassthroughFs, Inode, Handle) {
+        File::create(path).expect("failed to create test file");
+        open_existing_test_file(path)
+    }
+
+    // Like `open_test_file`, but for a file the test already set up, such as a sparse file or a
+    // FIFO, which creating the file would truncate or block on.
+    fn open_existing_test_file(path: &Path) -> (PassthroughFs, Inode, Handle) {
+        let fs = PassthroughFs::new(Default::default()).expect("failed to create PassthroughFs");
+        fs.init(FsOptions::empty())
+            .expect("failed to init PassthroughFs");
+
+        let mut inode = ROOT_ID;
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                let name = CString::new(name.as_bytes()).unwrap();
+                inode = fs.lookup(CTX, inode, &name).expect("lookup failed").inode;
+            }
+        }
+
+        let (handle, _) = fs
+            .open(CTX, inode, libc::O_RDWR as u32)
+            .expect("failed to open test file");
+
+        (fs, inode, handle.unwrap())
+    }
+
+    fn lock(type_: c_int) -> FileLock {
+        FileLock {
+            start: 0,
+            end: OFFSET_MAX,
+            type_: type_ as u32,
+            pid: 0,
+        }
+    }
+
+    fn is_eagain(res: io::Result<()>) -> bool {
+        matches!(res, Err(e) if e.raw_os_error() == Some(libc::EAGAIN))
+    }
+
+    // Returns true if a separate process on the host can place a `type_` lock on all of `path`.
+    fn host_process_can_lock(path: &Path, type_: c_int) -> bool {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+        // Safe because the child only makes async-signal-safe calls before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0, "fork failed");
+        if pid == 0 {
+            // Safe because these calls don't modify any memory other than `flock`.
+            unsafe {
+                let fd = libc::open(path.as_ptr(), libc::O_RDWR);
+                let mut flock: libc::flock64 = mem::zeroed();
+                flock.l_type = type_ as libc::c_short;
+                flock.l_whence = libc::SEEK_SET as libc::c_short;
+                let res = libc::fcntl(fd, libc::F_SETLK, &flock as *const libc::flock64);
+                libc::_exit(if fd >= 0 && res == 0 { 0 } else { 1 });
+            }
+        }
+
+        let mut status = 0;
+        // Safe because this only modifies `status` and we check the return value.
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+    }
+
+    #[test]
+    fn posix_locks_contend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("locked");
+        let (fs, inode, handle) = open_test_file(&path);
+
+        fs.setlk(CTX, inode, handle, 1, lock(libc::F_WRLCK), 0)
+            .expect("failed to lock file");
+
+        // Another owner in the client conflicts with the lock.
+        assert!(is_eagain(fs.setlk(
+            CTX,
+            inode,
+            handle,
+            2,
+            lock(libc::F_RDLCK),
+            0
+        )));
+        let conflict = fs
+            .getlk(CTX, inode, handle, 2, lock(libc::F_RDLCK), 0)
+            .unwrap();
+        assert_eq!(conflict.type_, libc::F_WRLCK as u32);
+        assert_eq!(conflict.start, 0);
+        assert_eq!(conflict.end, OFFSET_MAX);
+
+        // The owner's own locks don't conflict with each other.
+        let conflict = fs
+            .getlk(CTX, inode, handle, 1, lock(libc::F_RDLCK), 0)
+            .unwrap();
+        assert_eq!(conflict.type_, libc::F_UNLCK as u32);
+
+        // Neither does a process on the host.
+        assert!(!host_process_can_lock(&path, libc::F_RDLCK));
+
+        // Closing any of the owner's files releases its locks.
+        fs.flush(CTX, inode, handle, 1).unwrap();
+        fs.setlk(CTX, inode, handle, 2, lock(libc::F_RDLCK), 0)
+            .expect("failed to lock file after flush");
+        assert!(host_process_can_lock(&path, libc::F_RDLCK));
+        assert!(!host_process_can_lock(&path, libc::F_WRLCK));
+    }
+
+    #[test]
+    fn flock_locks_contend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("locked");
+        let (fs, inode, handle) = open_test_file(&path);
+
+        fs.setlk(CTX, inode, handle, 1, lock(libc::F_WRLCK), LK_FLOCK)
+            .expect("failed to lock file");
+        assert!(is_eagain(fs.setlk(
+            CTX,
+            inode,
+            handle,
+            2,
+            lock(libc::F_RDLCK),
+            LK_FLOCK
+        )));
+
+        // `flock` locks are independent of POSIX locks.
+        fs.setlk(CTX, inode, handle, 2, lock(libc::F_WRLCK), 0)
+            .expect("failed to place POSIX lock");
+
+        // Unlike POSIX locks, `flock` locks are only released when the file is released.
+        fs.flush(CTX, inode, handle, 1).unwrap();
+        assert!(is_eagain(fs.setlk(
+            CTX,
+            inode,
+            handle,
+            2,
+            lock(libc::F_RDLCK),
+            LK_FLOCK
+        )));
+        fs.release(CTX, inode, 0, handle, false, true, Some(1))
+            .unwrap();
+        fs.setlk(CTX, inode, handle, 2, lock(libc::F_RDLCK), LK_FLOCK)
+            .expect("failed to lock file after release");
+    }
+
+    #[test]
+    fn setlkw_waits_for_unlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("locked");
+        let (fs, inode, handle) = open_test_file(&path);
+        let fs = Arc::new(fs);
+
+        fs.setlk(CTX, inode, handle, 1, lock(libc::F_WRLCK), 0)
+            .expect("failed to lock file");
+
+        let (tx, rx) = channel();
+        let waiter = Arc::clone(&fs);
+        thread::spawn(move || {
+            tx.send(waiter.setlkw(CTX, inode, handle, 2, lock(libc::F_WRLCK), 0))
+                .unwrap();
+        });
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(50)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+
+        fs.setlk(CTX, inode, handle, 1, lock(libc::F_UNLCK), 0)
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("setlkw did not finish")
+            .expect("setlkw failed");
+        assert!(!host_process_can_lock(&path, libc::F_RDLCK));
+    }
+
+    #[test]
+    fn setlkw_abandoned_on_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("locked");
+        let (fs, inode, handle) = open_test_file(&path);
+        let fs = Arc::new(fs);
+
+        fs.setlk(CTX, inode, handle, 1, lock(libc::F_WRLCK), 0)
+            .expect("failed to lock file");
+
+        let (tx, rx) = channel();
+        let waiter = Arc::clone(&fs);
+        thread::spawn(move || {
+            tx.send(waiter.setlkw(CTX, inode, handle, 2, lock(libc::F_WRLCK), 0))
+                .unwrap();
+        });
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(50)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+
+        // The client closing the waiter's file gives up on the lock.
+        fs.flush(CTX, inode, handle, 2).unwrap();
+        let err = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("setlkw did not finish")
+            .expect_err("setlkw acquired a contended lock");
+        assert_eq!(err.raw_os_error(), Some(libc::EINTR));
+    }
+
+    #[test]
+    fn setlkw_abandoned_on_destroy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("locked");
+        let (fs, inode, handle) = open_test_file(&path);
+        let fs = Arc::new(fs);
+
+        fs.setlk(CTX, inode, handle, 1, lock(libc::F_WRLCK), 0)
+            .expect("failed to lock file");
+
+        let (tx, rx) = channel();
+        let waiter = Arc::clone(&fs);
+        thread::spawn(move || {
+            tx.send(waiter.setlkw(CTX, inode, handle, 2, lock(libc::F_WRLCK), 0))
+                .unwrap();
+        });
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(50)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+
+        // Resetting the device destroys the file system, which must not leave the waiter blocked.
+        fs.destroy();
+        let err = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("setlkw did not finish")
+            .expect_err("setlkw acquired a contended lock");
+        assert_eq!(err.raw_os_error(), Some(libc::EINTR));
+    }
+
     #[test]
     fn rewrite_xattr_names() {
         let cfg = Config {
@@ -2635,4 +3690,200 @@ mod tests {
         strip_xattr_prefix(&mut actual);
         assert_eq!(&actual[..], b"security.sehash");
     }
+
+    #[test]
+    fn lseek_data_and_holes() {
+        const HOLE_SIZE: u64 = 1 << 20;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("sparse");
+        let mut f = File::create(&path).unwrap();
+        f.seek(SeekFrom::Start(HOLE_SIZE)).unwrap();
+        f.write_all(b"data").unwrap();
+        let (fs, inode, handle) = open_existing_test_file(&path);
+
+        // File systems that don't track holes report the whole file as data.
+        let data = fs
+            .lseek(CTX, inode, handle, 0, libc::SEEK_DATA as u32)
+            .unwrap();
+        assert!(data <= HOLE_SIZE);
+        let hole = fs
+            .lseek(CTX, inode, handle, HOLE_SIZE, libc::SEEK_HOLE as u32)
+            .unwrap();
+        assert_eq!(hole, HOLE_SIZE + 4);
+
+        let err = fs
+            .lseek(CTX, inode, handle, hole, libc::SEEK_DATA as u32)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENXIO));
+    }
+
+    struct TestNotifier(Mutex<Sender<u64>>);
+
+    impl Notifier for TestNotifier {
+        fn poll_wakeup(&self, khandle: u64) -> io::Result<()> {
+            self.0.lock().send(khandle).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn poll_wakeup_when_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("fifo");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+
+        // Opening a FIFO for reading and writing doesn't wait for another process to open it.
+        let (fs, inode, handle) = open_existing_test_file(&path);
+        let (tx, rx) = channel();
+        fs.set_notifier(Arc::new(TestNotifier(Mutex::new(tx))));
+
+        let events = (libc::POLLIN | libc::POLLOUT) as u32;
+        let revents = fs.poll(CTX, inode, handle, 1, 0, events).unwrap();
+        assert_eq!(revents, libc::POLLOUT as u32);
+
+        let revents = fs
+            .poll(
+                CTX,
+                inode,
+                handle,
+                7,
+                POLL_SCHEDULE_NOTIFY,
+                libc::POLLIN as u32,
+            )
+            .unwrap();
+        assert_eq!(revents, 0);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(50)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writer.write_all(b"ready").unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 7);
+
+        let revents = fs.poll(CTX, inode, handle, 7, 0, events).unwrap();
+        assert_eq!(revents, events);
+    }
+
+    #[test]
+    fn poll_wakeup_without_notifier() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("fifo");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+        let (fs, inode, handle) = open_existing_test_file(&path);
+
+        // The client would wait forever for a wakeup that can't be sent.
+        let err = fs
+            .poll(
+                CTX,
+                inode,
+                handle,
+                7,
+                POLL_SCHEDULE_NOTIFY,
+                libc::POLLIN as u32,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+
+        // Polling without asking for a wakeup still works.
+        let revents = fs
+            .poll(CTX, inode, handle, 7, 0, libc::POLLIN as u32)
+            .unwrap();
+        assert_eq!(revents, 0);
+    }
+
+    #[test]
+    fn parse_id_map() {
+        assert!(IdMap::from_str("").is_err());
+        assert!(IdMap::from_str("1000 2000").is_err());
+        assert!(IdMap::from_str("1000 2000 1 4").is_err());
+        assert!(IdMap::from_str("1000 2000 0").is_err());
+        assert!(IdMap::from_str("1000 4294967295 2").is_err());
+        assert!(IdMap::from_str("0 5000 10,5 6000 1").is_err());
+        assert!(IdMap::from_str("0 5000 10,20 5009 1").is_err());
+
+        let map = IdMap::from_str("0 5000 10, 1000 2000 1").unwrap();
+        assert!(!map.is_empty());
+        assert_eq!(map.to_host(0), 5000);
+        assert_eq!(map.to_host(9), 5009);
+        assert_eq!(map.to_host(1000), 2000);
+        assert_eq!(map.to_guest(5003), 3);
+        assert_eq!(map.to_guest(2000), 1000);
+
+        // An empty map passes all IDs through.
+        assert_eq!(IdMap::default().to_host(10), 10);
+        assert_eq!(IdMap::default().to_guest(2001), 2001);
+    }
+
+    #[test]
+    fn unmapped_ids_overflow() {
+        let map = IdMap::from_str("0 5000 10, 1000 2000 1").unwrap();
+
+        // Guest IDs outside of every range.
+        assert_eq!(map.to_host(10), OVERFLOW_ID);
+        assert_eq!(map.to_host(999), OVERFLOW_ID);
+        assert_eq!(map.to_host(5000), OVERFLOW_ID);
+        assert_eq!(map.to_host(u32::MAX), OVERFLOW_ID);
+
+        // Host IDs outside of every range, including the host's own root.
+        assert_eq!(map.to_guest(0), OVERFLOW_ID);
+        assert_eq!(map.to_guest(2001), OVERFLOW_ID);
+        assert_eq!(map.to_guest(4999), OVERFLOW_ID);
+        assert_eq!(map.to_guest(5010), OVERFLOW_ID);
+    }
+
+    #[test]
+    fn audit_log_records_mutations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap().join("file");
+        File::create(&path).unwrap();
+        let mut log = tempfile::tempfile().unwrap();
+
+        let cfg = Config {
+            audit_log: Some(Arc::new(log.try_clone().unwrap())),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new(cfg).expect("failed to create PassthroughFs");
+        fs.init(FsOptions::empty())
+            .expect("failed to init PassthroughFs");
+
+        let mut inode = ROOT_ID;
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                let name = CString::new(name.as_bytes()).unwrap();
+                inode = fs.lookup(CTX, inode, &name).expect("lookup failed").inode;
+            }
+        }
+
+        // Safe because a zeroed stat64 is a valid value.
+        let mut attr: libc::stat64 = unsafe { mem::zeroed() };
+        attr.st_size = 4096;
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 42,
+        };
+        fs.setattr(ctx, inode, attr, None, SetattrValid::SIZE)
+            .expect("setattr failed");
+        assert!(fs
+            .setattr(ctx, ROOT_ID + 1_000_000, attr, None, SetattrValid::SIZE)
+            .is_err());
+
+        let mut contents = String::new();
+        log.seek(SeekFrom::Start(0)).unwrap();
+        log.read_to_string(&mut contents).unwrap();
+        let records: Vec<&str> = contents.lines().collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].ends_with(&format!(" setattr uid=0 gid=0 pid=42 {:?} ok", path)));
+        assert!(records[1].ends_with(&format!(
+            " setattr uid=0 gid=0 pid=42 \"<inode {}>\" err={}",
+            ROOT_ID + 1_000_000,
+            libc::EBADF
+        )));
+    }
 }
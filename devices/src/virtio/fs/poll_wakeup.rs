@@ -0,0 +1,186 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+use base::{error, AsRawDescriptor, Event, FromRawDescriptor};
+use fuse::Notifier;
+use sync::Mutex;
+
+// How often the thread drops the waiters of files that the client released before they became
+// ready, in milliseconds.
+const PRUNE_INTERVAL_MS: libc::c_int = 1000;
+
+// A file that the client polled, waiting to become ready for any of `events`.
+struct Waiter {
+    // A duplicate of the file's descriptor, which stays valid even if the original is replaced.
+    file: File,
+    events: u32,
+    // The waiter is given up once the client releases the file.
+    data: Weak<dyn AsRawDescriptor + Send + Sync>,
+}
+
+struct Shared {
+    // Waiters by kernel handle. Only one wakeup is needed per handle no matter how many times the
+    // client polls in the meantime.
+    waiters: Mutex<BTreeMap<u64, Waiter>>,
+    // Signaled when a waiter is added or the thread has to exit.
+    update_evt: Event,
+    exit: AtomicBool,
+}
+
+/// Waits for all the files that the client asked to be notified about on a single thread, and
+/// sends a poll wakeup for each once it is ready.
+pub struct PollWakeups {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PollWakeups {
+    /// Starts the thread that sends wakeups through `notifier`.
+    pub fn new(notifier: Arc<dyn Notifier>) -> io::Result<PollWakeups> {
+        let shared = Arc::new(Shared {
+            waiters: Mutex::new(BTreeMap::new()),
+            update_evt: Event::new()?,
+            exit: AtomicBool::new(false),
+        });
+
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("virtio-fs poll".to_string())
+            .spawn(move || run(&thread_shared, &*notifier))?;
+
+        Ok(PollWakeups {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Sends a poll wakeup for `khandle` once `data` is ready for any of `events`.
+    pub fn add(
+        &self,
+        data: &Arc<dyn AsRawDescriptor + Send + Sync>,
+        khandle: u64,
+        events: u32,
+    ) -> io::Result<()> {
+        let mut waiters = self.shared.waiters.lock();
+        if waiters.contains_key(&khandle) {
+            return Ok(());
+        }
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe { libc::fcntl(data.as_raw_descriptor(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just duplicated this descriptor.
+        let file = unsafe { File::from_raw_descriptor(fd) };
+        waiters.insert(
+            khandle,
+            Waiter {
+                file,
+                events,
+                data: Arc::downgrade(data),
+            },
+        );
+        self.shared.update_evt.write(1)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for PollWakeups {
+    fn drop(&mut self) {
+        self.shared.exit.store(true, Ordering::Release);
+        if let Err(e) = self.shared.update_evt.write(1) {
+            error!("failed to stop the poll wakeup thread: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("poll wakeup thread panicked");
+            }
+        }
+    }
+}
+
+fn run(shared: &Shared, notifier: &dyn Notifier) {
+    let mut pollfds = Vec::new();
+    let mut khandles = Vec::new();
+    let mut ready = Vec::new();
+
+    while !shared.exit.load(Ordering::Acquire) {
+        pollfds.clear();
+        khandles.clear();
+        pollfds.push(libc::pollfd {
+            fd: shared.update_evt.as_raw_descriptor(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        {
+            let mut waiters = shared.waiters.lock();
+            waiters.retain(|_, w| w.data.strong_count() > 0);
+            for (&khandle, w) in waiters.iter() {
+                pollfds.push(libc::pollfd {
+                    fd: w.file.as_raw_descriptor(),
+                    events: w.events as libc::c_short,
+                    revents: 0,
+                });
+                khandles.push(khandle);
+            }
+        }
+
+        // Released files are only noticed on the next pass, so there is no need to wake up
+        // while nothing is waiting.
+        let timeout = if khandles.is_empty() {
+            -1
+        } else {
+            PRUNE_INTERVAL_MS
+        };
+
+        // Safe because this only modifies `pollfds`, whose length is passed along with it, and we
+        // check the return value.
+        let res =
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("failed to poll files: {}", e);
+            return;
+        }
+
+        if pollfds[0].revents != 0 {
+            if let Err(e) = shared.update_evt.read() {
+                error!("failed to read poll wakeup event: {}", e);
+                return;
+            }
+        }
+
+        ready.clear();
+        {
+            let mut waiters = shared.waiters.lock();
+            for (pollfd, khandle) in pollfds[1..].iter().zip(&khandles) {
+                if pollfd.revents != 0 {
+                    waiters.remove(khandle);
+                    ready.push(*khandle);
+                }
+            }
+        }
+
+        // The waiters aren't locked while sending, which may wait for room in the queue.
+        for &khandle in &ready {
+            if let Err(e) = notifier.poll_wakeup(khandle) {
+                error!("failed to send poll wakeup: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A minimal reader for tar archives.
+//!
+//! Supports ustar archives along with the GNU long name extensions and the `path`, `linkpath`,
+//! and `size` records of pax extended headers, which together cover the archives produced by GNU
+//! tar and bsdtar. Only the headers are read: file contents stay in the archive and are referred
+//! to by their offset.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// The type of a tar archive entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    /// A hard link to the entry named by `link_target`.
+    HardLink,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
+}
+
+/// An entry in a tar archive.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: Vec<u8>,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub size: u64,
+    /// The offset of the contents of the entry in the archive.
+    pub data_offset: u64,
+    pub link_target: Vec<u8>,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Returns the bytes of a NUL-terminated header field.
+fn field(buf: &[u8]) -> &[u8] {
+    match buf.iter().position(|&b| b == 0) {
+        Some(end) => &buf[..end],
+        None => buf,
+    }
+}
+
+// Parses a numeric header field, which is either octal text or, for values that don't fit, a GNU
+// base-256 number marked by the high bit of the first byte.
+fn number(buf: &[u8]) -> io::Result<u64> {
+    if buf.first().map_or(false, |b| b & 0x80 != 0) {
+        if buf[0] & 0x40 != 0 {
+            return Err(invalid_data("negative number in tar header"));
+        }
+        let mut value: u64 = u64::from(buf[0] & 0x3f);
+        for &b in &buf[1..] {
+            value = value
+                .checked_mul(256)
+                .map(|v| v | u64::from(b))
+                .ok_or_else(|| invalid_data("number in tar header is too large"))?;
+        }
+        return Ok(value);
+    }
+
+    let text = field(buf);
+    let text = std::str::from_utf8(text)
+        .map_err(|_| invalid_data("invalid number in tar header"))?
+        .trim_matches(' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid_data("invalid number in tar header"))
+}
+
+fn checksum_matches(header: &[u8; BLOCK_SIZE as usize]) -> io::Result<bool> {
+    let expected = number(&header[148..156])?;
+
+    // The checksum is computed with its own field filled with spaces.
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+        .map(u64::from)
+        .sum();
+    Ok(sum == expected)
+}
+
+// Applies the records of a pax extended header to the next entry.
+fn parse_pax(
+    data: &[u8],
+    path: &mut Option<Vec<u8>>,
+    link: &mut Option<Vec<u8>>,
+    size: &mut Option<u64>,
+) -> io::Result<()> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        // Each record is "<len> <key>=<value>\n", where <len> includes the whole record.
+        let space = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| invalid_data("malformed pax record"))?;
+        let len: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&len| len > space && len <= rest.len())
+            .ok_or_else(|| invalid_data("malformed pax record"))?;
+        let record = &rest[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        rest = &rest[len..];
+
+        let eq = match record.iter().position(|&b| b == b'=') {
+            Some(eq) => eq,
+            None => continue,
+        };
+        let (key, value) = (&record[..eq], &record[eq + 1..]);
+        match key {
+            b"path" => *path = Some(value.to_vec()),
+            b"linkpath" => *link = Some(value.to_vec()),
+            b"size" => {
+                *size = Some(
+                    std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|s| s.parse().ok())
+                        .ok_or_else(|| invalid_data("invalid size in pax record"))?,
+                )
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn read_data(archive: &File, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    // Extended headers are small; refuse anything that looks like a corrupt size.
+    if size > 1 << 20 {
+        return Err(invalid_data("extended tar header is too large"));
+    }
+    let mut data = vec![0; size as usize];
+    archive.read_exact_at(&mut data, offset)?;
+    Ok(data)
+}
+
+/// Reads the headers of all the entries in `archive`. Entries of types that have no meaning
+/// outside of tar itself are skipped.
+pub fn read_entries(archive: &File) -> io::Result<Vec<Entry>> {
+    let archive_len = archive.metadata()?.len();
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    // Overrides for the next entry from GNU long name entries and pax extended headers.
+    let mut long_path = None;
+    let mut long_link = None;
+    let mut pax_size = None;
+
+    while offset.saturating_add(BLOCK_SIZE) <= archive_len {
+        let mut header = [0u8; BLOCK_SIZE as usize];
+        archive.read_exact_at(&mut header, offset)?;
+
+        // The archive ends with blocks of zeroes.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !checksum_matches(&header)? {
+            return Err(invalid_data("tar header checksum mismatch"));
+        }
+
+        let typeflag = header[156];
+        let size = match pax_size.take() {
+            Some(size) => size,
+            None => number(&header[124..136])?,
+        };
+        let data_offset = offset + BLOCK_SIZE;
+        offset = size
+            .checked_add(BLOCK_SIZE - 1)
+            .and_then(|s| data_offset.checked_add(s / BLOCK_SIZE * BLOCK_SIZE))
+            .filter(|&end| end <= archive_len)
+            .ok_or_else(|| invalid_data("tar archive is truncated"))?;
+
+        let kind = match typeflag {
+            b'0' | b'\0' | b'7' => EntryKind::File,
+            b'1' => EntryKind::HardLink,
+            b'2' => EntryKind::Symlink,
+            b'3' => EntryKind::CharDevice,
+            b'4' => EntryKind::BlockDevice,
+            b'5' => EntryKind::Directory,
+            b'6' => EntryKind::Fifo,
+            b'L' => {
+                let name = read_data(archive, data_offset, size)?;
+                long_path = Some(field(&name).to_vec());
+                continue;
+            }
+            b'K' => {
+                let name = read_data(archive, data_offset, size)?;
+                long_link = Some(field(&name).to_vec());
+                continue;
+            }
+            b'x' => {
+                let data = read_data(archive, data_offset, size)?;
+                parse_pax(&data, &mut long_path, &mut long_link, &mut pax_size)?;
+                continue;
+            }
+            _ => {
+                // Global pax headers, volume labels, etc.
+                long_path = None;
+                long_link = None;
+                continue;
+            }
+        };
+
+        let path = long_path.take().unwrap_or_else(|| {
+            let name = field(&header[0..100]);
+            let prefix = field(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                let mut path = prefix.to_vec();
+                path.push(b'/');
+                path.extend_from_slice(name);
+                path
+            } else {
+                name.to_vec()
+            }
+        });
+        let link_target = long_link
+            .take()
+            .unwrap_or_else(|| field(&header[157..257]).to_vec());
+
+        entries.push(Entry {
+            path,
+            kind,
+            mode: number(&header[100..108])? as u32 & 0o7777,
+            uid: number(&header[108..116])? as u32,
+            gid: number(&header[116..124])? as u32,
+            mtime: number(&header[136..148])? as i64,
+            size: if kind == EntryKind::File { size } else { 0 },
+            data_offset,
+            link_target,
+            dev_major: number(&header[329..337])? as u32,
+            dev_minor: number(&header[337..345])? as u32,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    /// Builds tar archives for tests.
+    #[derive(Default)]
+    pub struct TarBuilder {
+        data: Vec<u8>,
+    }
+
+    impl TarBuilder {
+        fn header(&mut self, name: &[u8], typeflag: u8, mode: u32, size: usize, link: &[u8]) {
+            let mut header = [0u8; BLOCK_SIZE as usize];
+            header[..name.len()].copy_from_slice(name);
+            header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+            header[108..116].copy_from_slice(b"0001750\0");
+            header[116..124].copy_from_slice(b"0001750\0");
+            header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+            header[136..148].copy_from_slice(b"14000000000\0");
+            header[156] = typeflag;
+            header[157..157 + link.len()].copy_from_slice(link);
+            header[257..265].copy_from_slice(b"ustar\x0000");
+
+            header[148..156].copy_from_slice(b"        ");
+            let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+            header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+            self.data.extend_from_slice(&header);
+        }
+
+        fn contents(&mut self, data: &[u8]) {
+            self.data.extend_from_slice(data);
+            let padding =
+                (BLOCK_SIZE as usize - data.len() % BLOCK_SIZE as usize) % BLOCK_SIZE as usize;
+            self.data.resize(self.data.len() + padding, 0);
+        }
+
+        pub fn dir(mut self, name: &str) -> Self {
+            self.header(name.as_bytes(), b'5', 0o755, 0, b"");
+            self
+        }
+
+        pub fn file(mut self, name: &str, data: &[u8]) -> Self {
+            if name.len() >= 100 {
+                self.header(b"././@LongLink", b'L', 0, name.len() + 1, b"");
+                let mut long = name.as_bytes().to_vec();
+                long.push(0);
+                self.contents(&long);
+                self.header(&name.as_bytes()[..99], b'0', 0o644, data.len(), b"");
+            } else {
+                self.header(name.as_bytes(), b'0', 0o644, data.len(), b"");
+            }
+            self.contents(data);
+            self
+        }
+
+        pub fn symlink(mut self, name: &str, target: &str) -> Self {
+            self.header(name.as_bytes(), b'2', 0o777, 0, target.as_bytes());
+            self
+        }
+
+        pub fn hard_link(mut self, name: &str, target: &str) -> Self {
+            self.header(name.as_bytes(), b'1', 0o644, 0, target.as_bytes());
+            self
+        }
+
+        pub fn build(mut self) -> File {
+            self.data
+                .resize(self.data.len() + 2 * BLOCK_SIZE as usize, 0);
+            let mut f = tempfile::tempfile().expect("failed to create archive");
+            f.write_all(&self.data).expect("failed to write archive");
+            f
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let long_name = format!("dir/{}", "x".repeat(120));
+        let archive = TarBuilder::default()
+            .dir("dir/")
+            .file("dir/file", b"hello")
+            .file(&long_name, b"long")
+            .symlink("link", "dir/file")
+            .build();
+
+        let entries = read_entries(&archive).expect("failed to read archive");
+        assert_eq!(entries.len(), 4);
+
+        assert_eq!(entries[0].path, b"dir/");
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[0].mode, 0o755);
+        assert_eq!(entries[0].uid, 1000);
+
+        assert_eq!(entries[1].path, b"dir/file");
+        assert_eq!(entries[1].kind, EntryKind::File);
+        assert_eq!(entries[1].size, 5);
+        let mut data = [0u8; 5];
+        archive
+            .read_exact_at(&mut data, entries[1].data_offset)
+            .unwrap();
+        assert_eq!(&data, b"hello");
+
+        assert_eq!(entries[2].path, long_name.as_bytes());
+        assert_eq!(entries[2].size, 4);
+
+        assert_eq!(entries[3].kind, EntryKind::Symlink);
+        assert_eq!(entries[3].link_target, b"dir/file");
+    }
+
+    #[test]
+    fn pax_records() {
+        let mut path = None;
+        let mut link = None;
+        let mut size = None;
+        parse_pax(
+            b"20 path=a/long/name\n15 size=123456\n",
+            &mut path,
+            &mut link,
+            &mut size,
+        )
+        .expect("failed to parse pax records");
+
+        assert_eq!(path.as_deref(), Some(&b"a/long/name"[..]));
+        assert_eq!(link, None);
+        assert_eq!(size, Some(123456));
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let mut archive = TarBuilder::default().file("file", b"data").build();
+        archive.write_all_at(b"garbage", 0).unwrap();
+        archive.flush().unwrap();
+
+        assert_eq!(
+            read_entries(&archive).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
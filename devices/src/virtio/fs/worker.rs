@@ -5,16 +5,20 @@
 use std::convert::{TryFrom, TryInto};
 use std::fs::File;
 use std::io;
+use std::mem::{self, size_of};
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
 use base::{error, Event, PollToken, SafeDescriptor, Tube, WaitContext};
-use fuse::filesystem::{FileSystem, ZeroCopyReader, ZeroCopyWriter};
+use fuse::filesystem::{FileSystem, RemoveMappingOne, ZeroCopyReader, ZeroCopyWriter};
+use fuse::sys::{OutHeader, RemoveMappingIn};
 use sync::Mutex;
 use vm_control::{FsMappingRequest, VmResponse};
 use vm_memory::GuestMemory;
 
-use crate::virtio::fs::{Error, Result};
+use crate::virtio::fs::{DaxWindow, Error, Result, NOTIFY_BUF_SIZE};
 use crate::virtio::{Interrupt, Queue, Reader, SignalableInterrupt, Writer};
 
 impl fuse::Reader for Reader {}
@@ -50,11 +54,12 @@ impl ZeroCopyWriter for Writer {
 struct Mapper {
     tube: Arc<Mutex<Tube>>,
     slot: u32,
+    dax: Option<Arc<DaxWindow>>,
 }
 
 impl Mapper {
-    fn new(tube: Arc<Mutex<Tube>>, slot: u32) -> Self {
-        Self { tube, slot }
+    fn new(tube: Arc<Mutex<Tube>>, slot: u32, dax: Option<Arc<DaxWindow>>) -> Self {
+        Self { tube, slot, dax }
     }
 
     fn process_request(&self, request: &FsMappingRequest) -> io::Result<()> {
@@ -85,79 +90,276 @@ impl fuse::Mapper for Mapper {
         file_offset: u64,
         prot: u32,
     ) -> io::Result<()> {
-        let mem_offset: usize = mem_offset.try_into().map_err(|e| {
-            error!("mem_offset {} is too big: {}", mem_offset, e);
-            io::Error::from_raw_os_error(libc::EINVAL)
-        })?;
-
-        let fd = SafeDescriptor::try_from(fd)?;
-
         let request = FsMappingRequest::CreateMemoryMapping {
             slot: self.slot,
-            fd,
+            fd: SafeDescriptor::try_from(fd)?,
             size,
             file_offset,
             prot,
-            mem_offset,
+            mem_offset: mem_offset.try_into().map_err(|e| {
+                error!("mem_offset {} is too big: {}", mem_offset, e);
+                io::Error::from_raw_os_error(libc::EINVAL)
+            })?,
         };
 
-        self.process_request(&request)
+        self.process_request(&request)?;
+        if let Some(dax) = &self.dax {
+            dax.map(mem_offset, size as u64);
+        }
+        Ok(())
     }
 
     fn unmap(&self, offset: u64, size: u64) -> io::Result<()> {
-        let offset: usize = offset.try_into().map_err(|e| {
-            error!("offset {} is too big: {}", offset, e);
-            io::Error::from_raw_os_error(libc::EINVAL)
-        })?;
-        let size: usize = size.try_into().map_err(|e| {
-            error!("size {} is too big: {}", size, e);
-            io::Error::from_raw_os_error(libc::EINVAL)
-        })?;
-
         let request = FsMappingRequest::RemoveMemoryMapping {
             slot: self.slot,
-            offset,
-            size,
+            offset: offset.try_into().map_err(|e| {
+                error!("offset {} is too big: {}", offset, e);
+                io::Error::from_raw_os_error(libc::EINVAL)
+            })?,
+            size: size.try_into().map_err(|e| {
+                error!("size {} is too big: {}", size, e);
+                io::Error::from_raw_os_error(libc::EINVAL)
+            })?,
         };
 
-        self.process_request(&request)
+        self.process_request(&request)?;
+        if let Some(dax) = &self.dax {
+            dax.unmap(offset, size);
+        }
+        Ok(())
+    }
+}
+
+// The number of mappings that fit in a single remove mapping notification.
+const MAX_REMOVE_MAPPINGS: usize =
+    (NOTIFY_BUF_SIZE as usize - size_of::<OutHeader>() - size_of::<RemoveMappingIn>())
+        / size_of::<RemoveMappingOne>();
+
+/// Sends notifications to the driver through the notification queue of an `Fs` device.
+pub struct NotificationQueue {
+    mem: GuestMemory,
+    queue: Mutex<Queue>,
+    irq: Arc<Interrupt>,
+}
+
+impl NotificationQueue {
+    pub fn new(mem: GuestMemory, queue: Queue, irq: Arc<Interrupt>) -> NotificationQueue {
+        NotificationQueue {
+            mem,
+            queue: Mutex::new(queue),
+            irq,
+        }
+    }
+
+    // Writes a notification with `encode` into the next buffer that the driver made available.
+    fn send<F>(&self, encode: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Writer) -> io::Result<usize>,
+    {
+        let mut queue = self.queue.lock();
+        let avail_desc = queue
+            .pop(&self.mem)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOBUFS))?;
+        let index = avail_desc.index;
+        let res = Writer::new(self.mem.clone(), avail_desc)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            .and_then(|mut writer| {
+                encode(&mut writer)?;
+                Ok(writer.bytes_written())
+            });
+
+        // Return the buffer even if the notification couldn't be written so that it isn't lost.
+        let len = *res.as_ref().unwrap_or(&0);
+        queue.add_used(&self.mem, index, len as u32);
+        queue.trigger_interrupt(&self.mem, &*self.irq);
+        res.map(|_| ())
+    }
+}
+
+impl fuse::Notifier for NotificationQueue {
+    fn poll_wakeup(&self, khandle: u64) -> io::Result<()> {
+        self.send(|w| fuse::encode_poll_wakeup(khandle, w))
+    }
+
+    fn remove_mapping(&self, msgs: &[RemoveMappingOne]) -> io::Result<()> {
+        for chunk in msgs.chunks(MAX_REMOVE_MAPPINGS) {
+            self.send(|w| fuse::encode_remove_mapping(chunk, w))?;
+        }
+        Ok(())
+    }
+}
+
+// The maximum number of requests that may block at the same time, e.g. while waiting for a file
+// lock. Further requests that may block fail with `ENOLCK` until one of them completes.
+const MAX_BLOCKING_REQUESTS: usize = 32;
+
+// The thread handling a request that may block.
+struct BlockingRequest {
+    thread: JoinHandle<()>,
+    // Set right before the thread exits.
+    done: Arc<AtomicBool>,
+}
+
+impl BlockingRequest {
+    fn join(self) {
+        if self.thread.join().is_err() {
+            error!("virtio-fs blocking request thread panicked");
+        }
+    }
+}
+
+/// The threads handling the requests of a queue that may block for an arbitrarily long time.
+#[derive(Default)]
+pub struct BlockingRequests {
+    requests: Vec<BlockingRequest>,
+}
+
+impl BlockingRequests {
+    pub fn new() -> BlockingRequests {
+        Default::default()
+    }
+
+    // Joins the threads of the requests that completed and returns true if there is room for
+    // another request.
+    fn has_room(&mut self) -> bool {
+        let (done, pending) = mem::take(&mut self.requests)
+            .into_iter()
+            .partition(|r: &BlockingRequest| r.done.load(Ordering::Acquire));
+        self.requests = pending;
+        for request in done {
+            request.join();
+        }
+        self.requests.len() < MAX_BLOCKING_REQUESTS
+    }
+
+    fn spawn<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let done = Arc::new(AtomicBool::new(false));
+        let thread_done = Arc::clone(&done);
+        let thread = thread::Builder::new()
+            .name("virtio-fs blocking request".to_string())
+            .spawn(move || {
+                f();
+                thread_done.store(true, Ordering::Release);
+            })?;
+        self.requests.push(BlockingRequest { thread, done });
+        Ok(())
+    }
+
+    /// Waits for all the requests to complete. Requests that are still waiting must be abandoned
+    /// first with `fuse::Server::reset`.
+    pub fn join_all(&mut self) {
+        for request in self.requests.drain(..) {
+            request.join();
+        }
     }
 }
 
 pub struct Worker<F: FileSystem + Sync> {
     mem: GuestMemory,
-    queue: Queue,
+    queue: Arc<Mutex<Queue>>,
     server: Arc<fuse::Server<F>>,
     irq: Arc<Interrupt>,
     tube: Arc<Mutex<Tube>>,
     slot: u32,
+    dax: Option<Arc<DaxWindow>>,
+    blocking: BlockingRequests,
 }
 
-pub fn process_fs_queue<I: SignalableInterrupt, F: FileSystem + Sync>(
+/// Handles all available requests on `queue`.
+///
+/// Requests that may block indefinitely, like waiting for a file lock, are handled on threads in
+/// `blocking` so that they don't hold up the rest of the queue. Those threads return the
+/// descriptor chain to `queue` and signal `interrupt` once the request completes. If too many
+/// requests are already blocked, the request fails with `ENOLCK` instead.
+#[allow(clippy::too_many_arguments)]
+pub fn process_fs_queue<I, F>(
     mem: &GuestMemory,
-    interrupt: &I,
-    queue: &mut Queue,
+    interrupt: &Arc<I>,
+    queue: &Arc<Mutex<Queue>>,
     server: &Arc<fuse::Server<F>>,
     tube: &Arc<Mutex<Tube>>,
     slot: u32,
-) -> Result<()> {
-    let mapper = Mapper::new(Arc::clone(tube), slot);
-    while let Some(avail_desc) = queue.pop(mem) {
-        let reader =
-            Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::InvalidDescriptorChain)?;
-        let writer =
-            Writer::new(mem.clone(), avail_desc.clone()).map_err(Error::InvalidDescriptorChain)?;
-
-        let total = server.handle_message(reader, writer, &mapper)?;
-
-        queue.add_used(mem, avail_desc.index, total as u32);
-        queue.trigger_interrupt(mem, &*interrupt);
+    dax: Option<&Arc<DaxWindow>>,
+    blocking: &mut BlockingRequests,
+) -> Result<()>
+where
+    I: SignalableInterrupt + Send + Sync + 'static,
+    F: FileSystem + Send + Sync + 'static,
+{
+    let mapper = Mapper::new(Arc::clone(tube), slot, dax.cloned());
+    loop {
+        // Don't hold the lock while handling the request so that blocking requests can complete
+        // in the meantime.
+        let avail_desc = match queue.lock().pop(mem) {
+            Some(avail_desc) => avail_desc,
+            None => break,
+        };
+        let index = avail_desc.index;
+        let chain = Reader::new(mem.clone(), avail_desc.clone()).and_then(|reader| {
+            let writer = Writer::new(mem.clone(), avail_desc)?;
+            Ok((reader, writer))
+        });
+        let (reader, writer) = match chain {
+            Ok(chain) => chain,
+            Err(e) => {
+                complete_request(mem, &**interrupt, queue, index, 0);
+                return Err(Error::InvalidDescriptorChain(e));
+            }
+        };
+
+        let res = if server.may_block(&reader) {
+            if blocking.has_room() {
+                let mem = mem.clone();
+                let interrupt = Arc::clone(interrupt);
+                let queue = Arc::clone(queue);
+                let server = Arc::clone(server);
+                let mapper = Mapper::new(Arc::clone(tube), slot, dax.cloned());
+                blocking
+                    .spawn(move || {
+                        let total = match server.handle_message(reader, writer, &mapper) {
+                            Ok(total) => total,
+                            Err(e) => {
+                                error!("virtio-fs transport error: {}", e);
+                                0
+                            }
+                        };
+                        complete_request(&mem, &*interrupt, &queue, index, total);
+                    })
+                    .map_err(Error::SpawnBlockingRequest)?;
+                continue;
+            }
+
+            // Fail the request rather than hold up the queue until another one completes.
+            server.reject_message(reader, writer, io::Error::from_raw_os_error(libc::ENOLCK))
+        } else {
+            server.handle_message(reader, writer, &mapper)
+        };
+
+        // Give the descriptor back to the guest even if the request failed.
+        complete_request(mem, &**interrupt, queue, index, *res.as_ref().unwrap_or(&0));
+        res?;
     }
 
     Ok(())
 }
 
-impl<F: FileSystem + Sync> Worker<F> {
+// Returns the descriptor chain at `index` to the guest, with `total` bytes written to it.
+fn complete_request<I: SignalableInterrupt>(
+    mem: &GuestMemory,
+    interrupt: &I,
+    queue: &Mutex<Queue>,
+    index: u16,
+    total: usize,
+) {
+    let mut queue = queue.lock();
+    queue.add_used(mem, index, total as u32);
+    queue.trigger_interrupt(mem, interrupt);
+}
+
+impl<F: FileSystem + Send + Sync + 'static> Worker<F> {
     pub fn new(
         mem: GuestMemory,
         queue: Queue,
@@ -165,14 +367,17 @@ impl<F: FileSystem + Sync> Worker<F> {
         irq: Arc<Interrupt>,
         tube: Arc<Mutex<Tube>>,
         slot: u32,
+        dax: Option<Arc<DaxWindow>>,
     ) -> Worker<F> {
         Worker {
             mem,
-            queue,
+            queue: Arc::new(Mutex::new(queue)),
             server,
             irq,
             tube,
             slot,
+            dax,
+            blocking: BlockingRequests::new(),
         }
     }
 
@@ -235,11 +440,13 @@ impl<F: FileSystem + Sync> Worker<F> {
                         queue_evt.read().map_err(Error::ReadQueueEvent)?;
                         if let Err(e) = process_fs_queue(
                             &self.mem,
-                            &*self.irq,
-                            &mut self.queue,
+                            &self.irq,
+                            &self.queue,
                             &self.server,
                             &self.tube,
                             self.slot,
+                            self.dax.as_ref(),
+                            &mut self.blocking,
                         ) {
                             error!("virtio-fs transport error: {}", e);
                             return Err(e);
@@ -248,7 +455,13 @@ impl<F: FileSystem + Sync> Worker<F> {
                     Token::InterruptResample => {
                         self.irq.interrupt_resample();
                     }
-                    Token::Kill => return Ok(()),
+                    Token::Kill => {
+                        // Abandon the requests that are still waiting so that they don't outlive
+                        // the device.
+                        self.server.reset();
+                        self.blocking.join_all();
+                        return Ok(());
+                    }
                 }
             }
         }
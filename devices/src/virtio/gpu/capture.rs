@@ -0,0 +1,282 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encoding of the frames shown on scanouts into PNG screenshots and raw or YUV4MPEG2 recordings.
+//!
+//! Frames are in the B8G8R8X8 layout that virtio-gpu kms uses for its scanouts.
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Write};
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use vm_control::GpuRecordFormat;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+/// The contents of a scanout at the time it was captured.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// Rows of B8G8R8X8 pixels, without any padding between them.
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// Creates a black frame of the given size.
+    pub fn new(width: u32, height: u32) -> Frame {
+        Frame {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * BYTES_PER_PIXEL],
+        }
+    }
+
+    /// Returns the stride in bytes of the rows of `pixels`.
+    pub fn stride(&self) -> u32 {
+        self.width * BYTES_PER_PIXEL as u32
+    }
+
+    // Returns the red, green and blue components of the pixel at (x, y).
+    fn rgb(&self, x: usize, y: usize) -> (i32, i32, i32) {
+        let offset = (y * self.width as usize + x) * BYTES_PER_PIXEL;
+        let p = &self.pixels[offset..offset + BYTES_PER_PIXEL];
+        (p[2] as i32, p[1] as i32, p[0] as i32)
+    }
+}
+
+fn write_png_chunk<W: Write>(w: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(chunk_type)?;
+    w.write_all(data)?;
+    w.write_all(&crc.sum().to_be_bytes())
+}
+
+/// Writes `frame` to `w` as an 8-bit RGB PNG image.
+pub fn write_png<W: Write>(w: &mut W, frame: &Frame) -> io::Result<()> {
+    w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&frame.width.to_be_bytes());
+    header.extend_from_slice(&frame.height.to_be_bytes());
+    // 8 bits per channel, truecolor, deflate, adaptive filtering and no interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_png_chunk(w, b"IHDR", &header)?;
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    let mut row = Vec::with_capacity(1 + frame.width as usize * 3);
+    for y in 0..frame.height as usize {
+        row.clear();
+        // Rows are stored without filtering.
+        row.push(0);
+        for x in 0..frame.width as usize {
+            let (r, g, b) = frame.rgb(x, y);
+            row.extend_from_slice(&[r as u8, g as u8, b as u8]);
+        }
+        encoder.write_all(&row)?;
+    }
+    write_png_chunk(w, b"IDAT", &encoder.finish()?)?;
+
+    write_png_chunk(w, b"IEND", &[])
+}
+
+// Converts a color to BT.601 limited range Y'CbCr.
+fn rgb_to_yuv((r, g, b): (i32, i32, i32)) -> (u8, u8, u8) {
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+/// Writes the planes of `frame` to `w` as 4:2:0 Y'CbCr, averaging the chroma of each 2x2 block.
+pub fn write_yuv420<W: Write>(w: &mut W, frame: &Frame) -> io::Result<()> {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let chroma_width = (width + 1) / 2;
+    let chroma_height = (height + 1) / 2;
+
+    let mut y_plane = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            y_plane.push(rgb_to_yuv(frame.rgb(x, y)).0);
+        }
+    }
+
+    let mut u_plane = Vec::with_capacity(chroma_width * chroma_height);
+    let mut v_plane = Vec::with_capacity(chroma_width * chroma_height);
+    for chroma_y in 0..chroma_height {
+        for chroma_x in 0..chroma_width {
+            let (mut r, mut g, mut b, mut count) = (0, 0, 0, 0);
+            for y in chroma_y * 2..min(chroma_y * 2 + 2, height) {
+                for x in chroma_x * 2..min(chroma_x * 2 + 2, width) {
+                    let rgb = frame.rgb(x, y);
+                    r += rgb.0;
+                    g += rgb.1;
+                    b += rgb.2;
+                    count += 1;
+                }
+            }
+            let (_, u, v) = rgb_to_yuv((r / count, g / count, b / count));
+            u_plane.push(u);
+            v_plane.push(v);
+        }
+    }
+
+    w.write_all(&y_plane)?;
+    w.write_all(&u_plane)?;
+    w.write_all(&v_plane)
+}
+
+/// Appends the frames flushed to a scanout to a file.
+pub struct Recorder {
+    format: GpuRecordFormat,
+    file: File,
+    // The frame size given in the stream header, which every frame of a Y4M stream must have.
+    size: Option<(u32, u32)>,
+}
+
+impl Recorder {
+    pub fn new(format: GpuRecordFormat, file: File) -> Recorder {
+        Recorder {
+            format,
+            file,
+            size: None,
+        }
+    }
+
+    /// Appends `frame` to the recording.
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match self.format {
+            GpuRecordFormat::Raw => self.file.write_all(&frame.pixels),
+            GpuRecordFormat::Y4m => {
+                match self.size {
+                    None => {
+                        // Frames are written as they are flushed, so the frame rate is nominal.
+                        writeln!(
+                            self.file,
+                            "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C420jpeg",
+                            frame.width, frame.height
+                        )?;
+                        self.size = Some((frame.width, frame.height));
+                    }
+                    Some(size) if size != (frame.width, frame.height) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "frame size changed during the recording",
+                        ));
+                    }
+                    Some(_) => {}
+                }
+                self.file.write_all(b"FRAME\n")?;
+                write_yuv420(&mut self.file, frame)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::read::ZlibDecoder;
+    use std::io::{Read, Seek, SeekFrom};
+
+    fn test_frame() -> Frame {
+        let mut frame = Frame::new(3, 2);
+        // A red, a green and a blue pixel over three white ones.
+        frame.pixels[..12].copy_from_slice(&[0, 0, 255, 0, 0, 255, 0, 0, 255, 0, 0, 0]);
+        for p in frame.pixels[12..].iter_mut() {
+            *p = 255;
+        }
+        frame
+    }
+
+    #[test]
+    fn png_layout() {
+        let mut png = Vec::new();
+        write_png(&mut png, &test_frame()).unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..29], &[0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        // The CRC of the IHDR chunk, as computed by zlib's crc32.
+        assert_eq!(&png[29..33], &[0x12, 0x16, 0xf1, 0x4d]);
+        assert_eq!(&png[37..41], b"IDAT");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        let idat_len = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        let mut rows = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut rows)
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                0, 255, 0, 0, 0, 255, 0, 0, 0, 255, //
+                0, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+            ]
+        );
+    }
+
+    #[test]
+    fn yuv_conversion() {
+        assert_eq!(rgb_to_yuv((0, 0, 0)), (16, 128, 128));
+        assert_eq!(rgb_to_yuv((255, 255, 255)), (235, 128, 128));
+        assert_eq!(rgb_to_yuv((255, 0, 0)), (82, 90, 240));
+    }
+
+    #[test]
+    fn yuv420_planes() {
+        let mut planes = Vec::new();
+        write_yuv420(&mut planes, &test_frame()).unwrap();
+
+        // 3x2 luma samples and 2x1 samples for each chroma plane.
+        assert_eq!(planes.len(), 6 + 2 + 2);
+        assert_eq!(&planes[..6], &[82, 144, 41, 235, 235, 235]);
+        // The first chroma samples average the red, green and two white pixels to (191, 191, 127)
+        // and the second ones the blue and a white pixel to (127, 127, 255).
+        assert_eq!(&planes[6..8], &[100, 184]);
+        assert_eq!(&planes[8..10], &[133, 119]);
+    }
+
+    #[test]
+    fn y4m_recording() {
+        let file = tempfile::tempfile().unwrap();
+        let mut recorder = Recorder::new(GpuRecordFormat::Y4m, file.try_clone().unwrap());
+        recorder.write_frame(&test_frame()).unwrap();
+        recorder.write_frame(&test_frame()).unwrap();
+        recorder
+            .write_frame(&Frame::new(2, 2))
+            .expect_err("frame size change was accepted");
+
+        let mut contents = Vec::new();
+        let mut file = file;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+
+        let header = b"YUV4MPEG2 W3 H2 F60:1 Ip A1:1 C420jpeg\n";
+        assert_eq!(&contents[..header.len()], header);
+        assert_eq!(contents.len(), header.len() + 2 * (6 + 10));
+    }
+
+    #[test]
+    fn raw_recording() {
+        let file = tempfile::tempfile().unwrap();
+        let mut recorder = Recorder::new(GpuRecordFormat::Raw, file.try_clone().unwrap());
+        recorder.write_frame(&test_frame()).unwrap();
+        recorder.write_frame(&Frame::new(1, 1)).unwrap();
+
+        let mut contents = Vec::new();
+        let mut file = file;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 3 * 2 * 4 + 4);
+        assert_eq!(&contents[..4], &[0, 0, 255, 0]);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Generation of the EDID blobs describing the displays of the GPU to the guest.
+//!
+//! Each display is described by a single EDID 1.4 block whose preferred mode is the display's
+//! size, with CVT reduced blanking timings at 60Hz.
+
+use std::cmp::{max, min};
+
+/// The size of an EDID block without extensions.
+pub const EDID_BLOCK_SIZE: usize = 128;
+
+/// The largest size of a mode described by a detailed timing descriptor.
+pub const EDID_MAX_DISPLAY_SIZE: u32 = 4095;
+
+const MANUFACTURER_ID: &[u8; 3] = b"GGL";
+const PRODUCT_CODE: u16 = 1;
+const MONITOR_NAME: &[u8] = b"crosvm";
+// Manufacture year, as an offset from 1990.
+const MANUFACTURE_YEAR: u8 = (2021 - 1990) as u8;
+
+// Displays are advertised as having this density to compute their physical size.
+const DOTS_PER_INCH: u32 = 96;
+const REFRESH_RATE: u32 = 60;
+
+// CVT reduced blanking constants.
+const H_BLANK: u32 = 160;
+const H_FRONT_PORCH: u32 = 48;
+const H_SYNC: u32 = 32;
+const V_FRONT_PORCH: u32 = 3;
+const V_SYNC: u32 = 5;
+const MIN_V_BACK_PORCH: u32 = 6;
+const MIN_V_BLANK_MICROSECONDS: u32 = 460;
+// The pixel clock is given in units of 10kHz, in 16 bits.
+const MAX_PIXEL_CLOCK: u64 = 0xffff * 10_000;
+
+// The sRGB primaries and white point, in units of 1/1024.
+const CHROMATICITY: [(u16, u16); 4] = [(655, 338), (307, 614), (154, 61), (320, 337)];
+
+// Returns the physical length of `pixels` at `DOTS_PER_INCH`, rounded to a multiple of `unit`
+// tenths of a millimeter.
+fn physical_size(pixels: u32, unit: u32) -> u32 {
+    let denominator = DOTS_PER_INCH * unit;
+    (pixels * 254 + denominator / 2) / denominator
+}
+
+fn detailed_timing_descriptor(width: u32, height: u32) -> [u8; 18] {
+    // Reserve enough lines for the minimum vertical blanking time at the refresh rate.
+    let frame_microseconds = 1_000_000 / REFRESH_RATE;
+    let v_blank_lines =
+        (MIN_V_BLANK_MICROSECONDS * height) / (frame_microseconds - MIN_V_BLANK_MICROSECONDS) + 1;
+    let v_blank = max(v_blank_lines, V_FRONT_PORCH + V_SYNC + MIN_V_BACK_PORCH);
+
+    let pixels_per_frame = (width + H_BLANK) as u64 * (height + v_blank) as u64;
+    let pixel_clock = min(pixels_per_frame * REFRESH_RATE as u64, MAX_PIXEL_CLOCK);
+    // Rounded up, to not go below the refresh rate.
+    let pixel_clock = ((pixel_clock + 9_999) / 10_000) as u16;
+
+    let width_mm = min(physical_size(width, 10), 0xfff);
+    let height_mm = min(physical_size(height, 10), 0xfff);
+
+    let mut descriptor = [0u8; 18];
+    descriptor[0..2].copy_from_slice(&pixel_clock.to_le_bytes());
+    descriptor[2] = width as u8;
+    descriptor[3] = H_BLANK as u8;
+    descriptor[4] = ((width >> 4) & 0xf0) as u8 | (H_BLANK >> 8) as u8;
+    descriptor[5] = height as u8;
+    descriptor[6] = v_blank as u8;
+    descriptor[7] = ((height >> 4) & 0xf0) as u8 | (v_blank >> 8) as u8;
+    descriptor[8] = H_FRONT_PORCH as u8;
+    descriptor[9] = H_SYNC as u8;
+    descriptor[10] = ((V_FRONT_PORCH << 4) | V_SYNC) as u8;
+    descriptor[11] = 0;
+    descriptor[12] = width_mm as u8;
+    descriptor[13] = height_mm as u8;
+    descriptor[14] = ((width_mm >> 4) & 0xf0) as u8 | (height_mm >> 8) as u8;
+    // Digital separate sync, with a positive horizontal and a negative vertical polarity.
+    descriptor[17] = 0x1a;
+    descriptor
+}
+
+fn display_descriptor(tag: u8, data: &[u8]) -> [u8; 18] {
+    let mut descriptor = [0u8; 18];
+    descriptor[3] = tag;
+    descriptor[5..5 + data.len()].copy_from_slice(data);
+    descriptor
+}
+
+/// Returns the EDID of a display whose preferred mode is `width` by `height` pixels.
+///
+/// `serial` distinguishes the displays of the GPU from each other. The size of the mode is clamped
+/// to `EDID_MAX_DISPLAY_SIZE`.
+pub fn create_edid(serial: u32, width: u32, height: u32) -> [u8; EDID_BLOCK_SIZE] {
+    let width = min(width, EDID_MAX_DISPLAY_SIZE);
+    let height = min(height, EDID_MAX_DISPLAY_SIZE);
+
+    let mut edid = [0u8; EDID_BLOCK_SIZE];
+    edid[0..8].copy_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+
+    let manufacturer = MANUFACTURER_ID
+        .iter()
+        .fold(0u16, |id, c| (id << 5) | (c - b'A' + 1) as u16);
+    edid[8..10].copy_from_slice(&manufacturer.to_be_bytes());
+    edid[10..12].copy_from_slice(&PRODUCT_CODE.to_le_bytes());
+    edid[12..16].copy_from_slice(&serial.to_le_bytes());
+    edid[17] = MANUFACTURE_YEAR;
+    // EDID 1.4.
+    edid[18] = 1;
+    edid[19] = 4;
+
+    // Digital input with 8 bits per color.
+    edid[20] = 0xa0;
+    // The size in centimeters.
+    edid[21] = min(physical_size(width, 100), 0xff) as u8;
+    edid[22] = min(physical_size(height, 100), 0xff) as u8;
+    // A gamma of 2.2.
+    edid[23] = 120;
+    // RGB 4:4:4, sRGB is the default color space and the preferred timing is the native mode.
+    edid[24] = 0x06;
+
+    let low_bits = |i: usize| {
+        let (x, y) = CHROMATICITY[i];
+        (((x & 0x3) << 2) | (y & 0x3)) as u8
+    };
+    edid[25] = (low_bits(0) << 4) | low_bits(1);
+    edid[26] = (low_bits(2) << 4) | low_bits(3);
+    for (i, &(x, y)) in CHROMATICITY.iter().enumerate() {
+        edid[27 + i * 2] = (x >> 2) as u8;
+        edid[28 + i * 2] = (y >> 2) as u8;
+    }
+
+    // No established timings, and the standard timings are all unused.
+    for standard_timing in edid[38..54].chunks_exact_mut(2) {
+        standard_timing.copy_from_slice(&[0x01, 0x01]);
+    }
+
+    let mut name = [b' '; 13];
+    name[..MONITOR_NAME.len()].copy_from_slice(MONITOR_NAME);
+    name[MONITOR_NAME.len()] = b'\n';
+
+    edid[54..72].copy_from_slice(&detailed_timing_descriptor(width, height));
+    edid[72..90].copy_from_slice(&display_descriptor(0xfc, &name));
+    edid[90..108].copy_from_slice(&display_descriptor(0x10, &[]));
+    edid[108..126].copy_from_slice(&display_descriptor(0x10, &[]));
+
+    let sum = edid.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    edid[127] = 0u8.wrapping_sub(sum);
+    edid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_and_checksum() {
+        let edid = create_edid(3, 1920, 1080);
+        assert_eq!(
+            &edid[..8],
+            &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]
+        );
+        // "GGL"
+        assert_eq!(&edid[8..10], &[0x1c, 0xec]);
+        assert_eq!(&edid[12..16], &[3, 0, 0, 0]);
+        assert_eq!(edid.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+    }
+
+    #[test]
+    fn preferred_mode() {
+        let edid = create_edid(0, 1920, 1080);
+        let dtd = &edid[54..72];
+
+        // The CVT reduced blanking timings of 1920x1080 at 60Hz are 2080x1111 in total, which
+        // is a pixel clock of 138.66MHz.
+        let h_active = dtd[2] as u32 | ((dtd[4] as u32 & 0xf0) << 4);
+        let h_blank = dtd[3] as u32 | ((dtd[4] as u32 & 0x0f) << 8);
+        let v_active = dtd[5] as u32 | ((dtd[7] as u32 & 0xf0) << 4);
+        let v_blank = dtd[6] as u32 | ((dtd[7] as u32 & 0x0f) << 8);
+        assert_eq!((h_active, h_blank), (1920, 160));
+        assert_eq!((v_active, v_blank), (1080, 31));
+        assert_eq!(u16::from_le_bytes([dtd[0], dtd[1]]), 13866);
+
+        // 1920x1080 at 96 DPI is 508x286mm.
+        assert_eq!(dtd[12] as u32 | ((dtd[14] as u32 & 0xf0) << 4), 508);
+        assert_eq!(dtd[13] as u32 | ((dtd[14] as u32 & 0x0f) << 8), 286);
+        assert_eq!((edid[21], edid[22]), (51, 29));
+    }
+
+    #[test]
+    fn monitor_name() {
+        let edid = create_edid(0, 800, 600);
+        assert_eq!(&edid[72..77], &[0, 0, 0, 0xfc, 0]);
+        assert_eq!(&edid[77..90], b"crosvm\n      ");
+    }
+
+    #[test]
+    fn large_modes_are_clamped() {
+        let edid = create_edid(0, 7680, 4320);
+        let dtd = &edid[54..72];
+        assert_eq!(dtd[2] as u32 | ((dtd[4] as u32 & 0xf0) << 4), 4095);
+        assert_eq!(dtd[5] as u32 | ((dtd[7] as u32 & 0xf0) << 4), 4095);
+        assert_eq!(u16::from_le_bytes([dtd[0], dtd[1]]), 0xffff);
+    }
+}
@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod capture;
+mod edid;
 mod protocol;
 mod udmabuf;
 mod udmabuf_bindings;
@@ -13,8 +15,10 @@ use std::convert::TryFrom;
 use std::i64;
 use std::io::Read;
 use std::mem::{self, size_of};
+use std::net::{SocketAddr, TcpListener};
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -34,6 +38,9 @@ use resources::Alloc;
 
 use serde::{Deserialize, Serialize};
 use sync::Mutex;
+use vm_control::{
+    DeviceControlRequest, DeviceControlResponse, GpuControlCommand, GpuControlResult,
+};
 use vm_memory::{GuestAddress, GuestMemory};
 
 use super::{
@@ -47,7 +54,8 @@ use self::protocol::*;
 pub use self::protocol::{
     virtio_gpu_config, VIRTIO_GPU_F_CONTEXT_INIT, VIRTIO_GPU_F_CREATE_GUEST_HANDLE,
     VIRTIO_GPU_F_EDID, VIRTIO_GPU_F_RESOURCE_BLOB, VIRTIO_GPU_F_RESOURCE_SYNC,
-    VIRTIO_GPU_F_RESOURCE_UUID, VIRTIO_GPU_F_VIRGL, VIRTIO_GPU_SHM_ID_HOST_VISIBLE,
+    VIRTIO_GPU_F_RESOURCE_UUID, VIRTIO_GPU_F_VIRGL, VIRTIO_GPU_MAX_SCANOUTS,
+    VIRTIO_GPU_SHM_ID_HOST_VISIBLE,
 };
 use self::virtio_gpu::VirtioGpu;
 
@@ -69,6 +77,8 @@ pub enum GpuMode {
 pub struct GpuDisplayParameters {
     pub width: u32,
     pub height: u32,
+    /// Serve the display to VNC clients on this address.
+    pub vnc: Option<SocketAddr>,
 }
 
 impl Default for GpuDisplayParameters {
@@ -76,6 +86,7 @@ impl Default for GpuDisplayParameters {
         GpuDisplayParameters {
             width: DEFAULT_DISPLAY_WIDTH,
             height: DEFAULT_DISPLAY_HEIGHT,
+            vnc: None,
         }
     }
 }
@@ -88,6 +99,7 @@ pub struct GpuParameters {
     pub renderer_use_gles: bool,
     pub renderer_use_glx: bool,
     pub renderer_use_surfaceless: bool,
+    pub renderer_use_software: bool,
     pub gfxstream_use_guest_angle: bool,
     pub gfxstream_use_syncfd: bool,
     pub use_vulkan: bool,
@@ -95,6 +107,9 @@ pub struct GpuParameters {
     pub mode: GpuMode,
     pub cache_path: Option<String>,
     pub cache_size: Option<String>,
+    // The most displays that can be connected at once, including those added at runtime. Defaults
+    // to the number of `displays`.
+    pub max_displays: Option<u32>,
 }
 
 // First queue is for virtio gpu commands. Second queue is for cursor commands, which we expect
@@ -114,6 +129,7 @@ impl Default for GpuParameters {
             renderer_use_gles: true,
             renderer_use_glx: false,
             renderer_use_surfaceless: true,
+            renderer_use_software: false,
             gfxstream_use_guest_angle: false,
             gfxstream_use_syncfd: true,
             use_vulkan: false,
@@ -121,10 +137,22 @@ impl Default for GpuParameters {
             cache_path: None,
             cache_size: None,
             udmabuf: false,
+            max_displays: None,
         }
     }
 }
 
+impl GpuParameters {
+    /// Returns the number of scanouts to offer to the guest, which is never less than the number
+    /// of `displays` or more than the protocol allows.
+    pub fn num_scanouts(&self) -> u32 {
+        let num_displays = self.displays.len() as u32;
+        self.max_displays
+            .map_or(num_displays, |max_displays| max_displays.max(num_displays))
+            .min(VIRTIO_GPU_MAX_SCANOUTS as u32)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct VirtioScanoutBlobData {
     pub width: u32,
@@ -230,6 +258,7 @@ impl QueueReader for SharedQueueReader {
 fn build(
     display_backends: &[DisplayBackend],
     display_params: Vec<GpuDisplayParameters>,
+    num_scanouts: u32,
     rutabaga_builder: RutabagaBuilder,
     event_devices: Vec<EventDevice>,
     gpu_device_tube: Tube,
@@ -261,6 +290,7 @@ fn build(
     VirtioGpu::new(
         display,
         display_params,
+        num_scanouts,
         rutabaga_builder,
         event_devices,
         gpu_device_tube,
@@ -367,6 +397,67 @@ impl Frontend {
         }
     }
 
+    /// Processes incoming requests on `gpu_control_tube` and returns `true` if the displays
+    /// changed.
+    pub fn process_gpu_control(&mut self, gpu_control_tube: &Tube) -> bool {
+        let mut displays_changed = false;
+        let request: DeviceControlRequest<GpuControlCommand> = match gpu_control_tube.recv() {
+            Ok(request) => request,
+            Err(e) => {
+                error!("error receiving gpu control request: {}", e);
+                return false;
+            }
+        };
+        let response = match request.command {
+            GpuControlCommand::Screenshot { scanout_id, file } => {
+                self.virtio_gpu.screenshot(scanout_id, file)
+            }
+            GpuControlCommand::RecordStart {
+                scanout_id,
+                format,
+                file,
+            } => self.virtio_gpu.start_recording(scanout_id, format, file),
+            GpuControlCommand::RecordStop { scanout_id } => {
+                self.virtio_gpu.stop_recording(scanout_id)
+            }
+            GpuControlCommand::ListDisplays => {
+                GpuControlResult::Displays(self.virtio_gpu.displays())
+            }
+            GpuControlCommand::AddDisplay { width, height } => {
+                match self.virtio_gpu.add_display(width, height) {
+                    Ok(scanout_id) => {
+                        displays_changed = true;
+                        GpuControlResult::DisplayAdded { scanout_id }
+                    }
+                    Err(result) => result,
+                }
+            }
+            GpuControlCommand::RemoveDisplay { scanout_id } => {
+                let result = self.virtio_gpu.remove_display(scanout_id);
+                displays_changed = result == GpuControlResult::Ok;
+                result
+            }
+            GpuControlCommand::ResizeDisplay {
+                scanout_id,
+                width,
+                height,
+            } => {
+                let result = self.virtio_gpu.resize_display(scanout_id, width, height);
+                displays_changed = result == GpuControlResult::Ok;
+                result
+            }
+        };
+
+        let response = DeviceControlResponse {
+            id: request.id,
+            result: response,
+        };
+        if let Err(e) = gpu_control_tube.send(&response) {
+            error!("error sending gpu control response: {}", e);
+        }
+        displays_changed
+    }
+
     fn process_gpu_command(
         &mut self,
         mem: &GuestMemory,
@@ -376,9 +467,10 @@ impl Frontend {
         self.virtio_gpu.force_ctx_0();
 
         match cmd {
-            GpuCommand::GetDisplayInfo(_) => Ok(GpuResponse::OkDisplayInfo(
-                self.virtio_gpu.display_info().to_vec(),
-            )),
+            GpuCommand::GetDisplayInfo(_) => {
+                Ok(GpuResponse::OkDisplayInfo(self.virtio_gpu.display_info()))
+            }
+            GpuCommand::GetEdid(info) => self.virtio_gpu.get_edid(info.scanout.to_native()),
             GpuCommand::ResourceCreate2d(info) => {
                 let resource_id = info.resource_id.to_native();
 
@@ -449,6 +541,8 @@ impl Frontend {
                 info.pos.scanout_id.to_native(),
                 info.pos.x.into(),
                 info.pos.y.into(),
+                info.hot_x.into(),
+                info.hot_y.into(),
             ),
             GpuCommand::MoveCursor(info) => self.virtio_gpu.move_cursor(
                 info.pos.scanout_id.to_native(),
@@ -794,6 +888,9 @@ struct Worker {
     cursor_queue: LocalQueueReader,
     cursor_evt: Event,
     resource_bridges: Vec<Tube>,
+    gpu_control_tube: Option<Tube>,
+    // Set when the displays change, until the driver acknowledges it in the config space.
+    config_event: Arc<AtomicBool>,
     kill_evt: Event,
     state: Frontend,
 }
@@ -805,6 +902,7 @@ impl Worker {
             CtrlQueue,
             CursorQueue,
             Display,
+            GpuControl,
             InterruptResample,
             Kill,
             ResourceBridge { index: usize },
@@ -838,6 +936,12 @@ impl Worker {
             }
         }
 
+        if let Some(gpu_control_tube) = &self.gpu_control_tube {
+            if let Err(e) = wait_ctx.add(gpu_control_tube, Token::GpuControl) {
+                error!("failed to add gpu control tube to WaitContext: {}", e);
+            }
+        }
+
         // TODO(davidriley): The entire main loop processing is somewhat racey and incorrect with
         // respect to cursor vs control queue processing.  As both currently and originally
         // written, while the control queue is only processed/read from after the the cursor queue
@@ -874,9 +978,17 @@ impl Worker {
             // This display isn't typically used when the virt-wl device is available and it can
             // lead to hung fds (crbug.com/1027379). Disable if it's hung.
             for event in events.iter().filter(|e| e.is_hungup) {
-                if let Token::Display = event.token {
-                    error!("default display hang-up detected");
-                    let _ = wait_ctx.delete(&*self.state.display().borrow());
+                match event.token {
+                    Token::Display => {
+                        error!("default display hang-up detected");
+                        let _ = wait_ctx.delete(&*self.state.display().borrow());
+                    }
+                    Token::GpuControl => {
+                        if let Some(gpu_control_tube) = self.gpu_control_tube.take() {
+                            let _ = wait_ctx.delete(&gpu_control_tube);
+                        }
+                    }
+                    _ => {}
                 }
             }
 
@@ -900,6 +1012,14 @@ impl Worker {
                             let _ = self.exit_evt.write(1);
                         }
                     }
+                    Token::GpuControl => {
+                        if let Some(gpu_control_tube) = &self.gpu_control_tube {
+                            if self.state.process_gpu_control(gpu_control_tube) {
+                                self.config_event.store(true, Ordering::SeqCst);
+                                self.interrupt.signal_config_changed();
+                            }
+                        }
+                    }
                     Token::ResourceBridge { index } => {
                         process_resource_bridge[index] = true;
                     }
@@ -959,6 +1079,8 @@ pub enum DisplayBackend {
     Wayland(Option<PathBuf>),
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    /// Serve each display to VNC clients accepted by the listener at its index, if any.
+    Vnc(Vec<Option<Arc<TcpListener>>>),
     /// Emulate a display without actually displaying it.
     Stub,
 }
@@ -968,6 +1090,13 @@ impl DisplayBackend {
         match self {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_ref()),
+            DisplayBackend::Vnc(listeners) => {
+                let listeners = listeners
+                    .iter()
+                    .map(|l| l.as_ref().map(|l| l.try_clone()).transpose())
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                GpuDisplay::open_vnc(listeners)
+            }
             DisplayBackend::Stub => GpuDisplay::open_stub(),
         }
     }
@@ -976,13 +1105,15 @@ impl DisplayBackend {
 pub struct Gpu {
     exit_evt: Event,
     gpu_device_tube: Option<Tube>,
+    gpu_control_tube: Option<Tube>,
     resource_bridges: Vec<Tube>,
     event_devices: Vec<EventDevice>,
     kill_evt: Option<Event>,
-    config_event: bool,
+    config_event: Arc<AtomicBool>,
     worker_thread: Option<thread::JoinHandle<()>>,
     display_backends: Vec<DisplayBackend>,
     display_params: Vec<GpuDisplayParameters>,
+    num_scanouts: u32,
     rutabaga_builder: Option<RutabagaBuilder>,
     pci_bar: Option<Alloc>,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
@@ -996,6 +1127,7 @@ impl Gpu {
     pub fn new(
         exit_evt: Event,
         gpu_device_tube: Option<Tube>,
+        gpu_control_tube: Option<Tube>,
         resource_bridges: Vec<Tube>,
         display_backends: Vec<DisplayBackend>,
         gpu_parameters: &GpuParameters,
@@ -1055,18 +1187,21 @@ impl Gpu {
             .set_display_height(display_height)
             .set_virglrenderer_flags(virglrenderer_flags)
             .set_gfxstream_flags(gfxstream_flags)
-            .set_rutabaga_channels(rutabaga_channels_opt);
+            .set_rutabaga_channels(rutabaga_channels_opt)
+            .set_software_renderer(gpu_parameters.renderer_use_software);
 
         Gpu {
             exit_evt,
             gpu_device_tube,
+            gpu_control_tube,
             resource_bridges,
             event_devices,
-            config_event: false,
+            config_event: Arc::new(AtomicBool::new(false)),
             kill_evt: None,
             worker_thread: None,
             display_backends,
             display_params: gpu_parameters.displays.clone(),
+            num_scanouts: gpu_parameters.num_scanouts(),
             rutabaga_builder: Some(rutabaga_builder),
             pci_bar: None,
             map_request,
@@ -1091,6 +1226,7 @@ impl Gpu {
         build(
             &self.display_backends,
             self.display_params.clone(),
+            self.num_scanouts,
             rutabaga_builder,
             event_devices,
             tube,
@@ -1115,7 +1251,7 @@ impl Gpu {
 
     fn get_config(&self) -> virtio_gpu_config {
         let mut events_read = 0;
-        if self.config_event {
+        if self.config_event.load(Ordering::SeqCst) {
             events_read |= VIRTIO_GPU_EVENT_DISPLAY;
         }
 
@@ -1146,7 +1282,9 @@ impl Gpu {
         virtio_gpu_config {
             events_read: Le32::from(events_read),
             events_clear: Le32::from(0),
-            num_scanouts: Le32::from(self.display_params.len() as u32),
+            // Displays can be added at runtime up to `num_scanouts`, so the scanouts without a
+            // display are disabled in the display info.
+            num_scanouts: Le32::from(self.num_scanouts),
             num_capsets: Le32::from(num_capsets),
         }
     }
@@ -1179,11 +1317,21 @@ impl VirtioDevice for Gpu {
             keep_rds.push(gpu_device_tube.as_raw_descriptor());
         }
 
+        if let Some(ref gpu_control_tube) = self.gpu_control_tube {
+            keep_rds.push(gpu_control_tube.as_raw_descriptor());
+        }
+
         keep_rds.push(self.exit_evt.as_raw_descriptor());
         for bridge in &self.resource_bridges {
             keep_rds.push(bridge.as_raw_descriptor());
         }
 
+        for display_backend in &self.display_backends {
+            if let DisplayBackend::Vnc(listeners) = display_backend {
+                keep_rds.extend(listeners.iter().flatten().map(|l| l.as_raw_descriptor()));
+            }
+        }
+
         keep_rds
     }
 
@@ -1215,7 +1363,7 @@ impl VirtioDevice for Gpu {
             }
         };
 
-        self.base_features | rutabaga_features
+        self.base_features | 1 << VIRTIO_GPU_F_EDID | rutabaga_features
     }
 
     fn ack_features(&mut self, value: u64) {
@@ -1230,7 +1378,7 @@ impl VirtioDevice for Gpu {
         let mut cfg = self.get_config();
         copy_config(cfg.as_mut_slice(), offset, data, 0);
         if (cfg.events_clear.to_native() & VIRTIO_GPU_EVENT_DISPLAY) != 0 {
-            self.config_event = false;
+            self.config_event.store(false, Ordering::SeqCst);
         }
     }
 
@@ -1263,6 +1411,8 @@ impl VirtioDevice for Gpu {
         self.kill_evt = Some(self_kill_evt);
 
         let resource_bridges = mem::take(&mut self.resource_bridges);
+        let gpu_control_tube = self.gpu_control_tube.take();
+        let config_event = self.config_event.clone();
 
         let irq = Arc::new(interrupt);
         let ctrl_queue = SharedQueueReader::new(queues.remove(0), &irq);
@@ -1271,6 +1421,7 @@ impl VirtioDevice for Gpu {
         let cursor_evt = queue_evts.remove(0);
         let display_backends = self.display_backends.clone();
         let display_params = self.display_params.clone();
+        let num_scanouts = self.num_scanouts;
         let event_devices = self.event_devices.split_off(0);
         let map_request = Arc::clone(&self.map_request);
         let external_blob = self.external_blob;
@@ -1294,6 +1445,7 @@ impl VirtioDevice for Gpu {
                         let virtio_gpu = match build(
                             &display_backends,
                             display_params,
+                            num_scanouts,
                             rutabaga_builder,
                             event_devices,
                             gpu_device_tube,
@@ -1316,6 +1468,8 @@ impl VirtioDevice for Gpu {
                             cursor_queue,
                             cursor_evt,
                             resource_bridges,
+                            gpu_control_tube,
+                            config_event,
                             kill_evt,
                             state: Frontend::new(virtio_gpu, fence_state),
                         }
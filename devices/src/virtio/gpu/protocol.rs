@@ -73,8 +73,9 @@ pub const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
 pub const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
 pub const VIRTIO_GPU_RESP_OK_CAPSET_INFO: u32 = 0x1102;
 pub const VIRTIO_GPU_RESP_OK_CAPSET: u32 = 0x1103;
+/* The plane info response is not upstreamed and shares its value with the EDID response. */
 pub const VIRTIO_GPU_RESP_OK_RESOURCE_PLANE_INFO: u32 = 0x1104;
-pub const VIRTIO_GPU_RESP_OK_EDID: u32 = 0x1105;
+pub const VIRTIO_GPU_RESP_OK_EDID: u32 = 0x1104;
 pub const VIRTIO_GPU_RESP_OK_RESOURCE_UUID: u32 = 0x1105;
 pub const VIRTIO_GPU_RESP_OK_MAP_INFO: u32 = 0x1106;
 
@@ -131,7 +132,7 @@ pub fn virtio_gpu_cmd_str(cmd: u32) -> &'static str {
         VIRTIO_GPU_RESP_OK_DISPLAY_INFO => "VIRTIO_GPU_RESP_OK_DISPLAY_INFO",
         VIRTIO_GPU_RESP_OK_CAPSET_INFO => "VIRTIO_GPU_RESP_OK_CAPSET_INFO",
         VIRTIO_GPU_RESP_OK_CAPSET => "VIRTIO_GPU_RESP_OK_CAPSET",
-        VIRTIO_GPU_RESP_OK_RESOURCE_PLANE_INFO => "VIRTIO_GPU_RESP_OK_RESOURCE_PLANE_INFO",
+        VIRTIO_GPU_RESP_OK_EDID => "VIRTIO_GPU_RESP_OK_EDID",
         VIRTIO_GPU_RESP_OK_RESOURCE_UUID => "VIRTIO_GPU_RESP_OK_RESOURCE_UUID",
         VIRTIO_GPU_RESP_OK_MAP_INFO => "VIRTIO_GPU_RESP_OK_MAP_INFO",
         VIRTIO_GPU_RESP_ERR_UNSPEC => "VIRTIO_GPU_RESP_ERR_UNSPEC",
@@ -305,7 +306,7 @@ pub struct virtio_gpu_display_one {
 unsafe impl DataInit for virtio_gpu_display_one {}
 
 /* VIRTIO_GPU_RESP_OK_DISPLAY_INFO */
-const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct virtio_gpu_resp_display_info {
@@ -315,6 +316,30 @@ pub struct virtio_gpu_resp_display_info {
 
 unsafe impl DataInit for virtio_gpu_resp_display_info {}
 
+/* VIRTIO_GPU_CMD_GET_EDID */
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct virtio_gpu_cmd_get_edid {
+    pub hdr: virtio_gpu_ctrl_hdr,
+    pub scanout: Le32,
+    pub padding: Le32,
+}
+
+unsafe impl DataInit for virtio_gpu_cmd_get_edid {}
+
+/* VIRTIO_GPU_RESP_OK_EDID */
+pub const VIRTIO_GPU_EDID_MAX_SIZE: usize = 1024;
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct virtio_gpu_resp_edid {
+    pub hdr: virtio_gpu_ctrl_hdr,
+    pub size: Le32,
+    pub padding: Le32,
+    pub edid: [u8; VIRTIO_GPU_EDID_MAX_SIZE],
+}
+
+unsafe impl DataInit for virtio_gpu_resp_edid {}
+
 /* data passed in the control vq, 3d related */
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -620,6 +645,7 @@ pub enum GpuCommand {
     ResourceDetachBacking(virtio_gpu_resource_detach_backing),
     GetCapsetInfo(virtio_gpu_get_capset_info),
     GetCapset(virtio_gpu_get_capset),
+    GetEdid(virtio_gpu_cmd_get_edid),
     CtxCreate(virtio_gpu_ctx_create),
     CtxDestroy(virtio_gpu_ctx_destroy),
     CtxAttachResource(virtio_gpu_ctx_resource),
@@ -679,6 +705,7 @@ impl fmt::Debug for GpuCommand {
             ResourceDetachBacking(_info) => f.debug_struct("ResourceDetachBacking").finish(),
             GetCapsetInfo(_info) => f.debug_struct("GetCapsetInfo").finish(),
             GetCapset(_info) => f.debug_struct("GetCapset").finish(),
+            GetEdid(_info) => f.debug_struct("GetEdid").finish(),
             CtxCreate(_info) => f.debug_struct("CtxCreate").finish(),
             CtxDestroy(_info) => f.debug_struct("CtxDestroy").finish(),
             CtxAttachResource(_info) => f.debug_struct("CtxAttachResource").finish(),
@@ -714,6 +741,7 @@ impl GpuCommand {
             VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => ResourceDetachBacking(cmd.read_obj()?),
             VIRTIO_GPU_CMD_GET_CAPSET_INFO => GetCapsetInfo(cmd.read_obj()?),
             VIRTIO_GPU_CMD_GET_CAPSET => GetCapset(cmd.read_obj()?),
+            VIRTIO_GPU_CMD_GET_EDID => GetEdid(cmd.read_obj()?),
             VIRTIO_GPU_CMD_CTX_CREATE => CtxCreate(cmd.read_obj()?),
             VIRTIO_GPU_CMD_CTX_DESTROY => CtxDestroy(cmd.read_obj()?),
             VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE => CtxAttachResource(cmd.read_obj()?),
@@ -747,6 +775,7 @@ impl GpuCommand {
             ResourceDetachBacking(info) => &info.hdr,
             GetCapsetInfo(info) => &info.hdr,
             GetCapset(info) => &info.hdr,
+            GetEdid(info) => &info.hdr,
             CtxCreate(info) => &info.hdr,
             CtxDestroy(info) => &info.hdr,
             CtxAttachResource(info) => &info.hdr,
@@ -775,13 +804,15 @@ pub struct GpuResponsePlaneInfo {
 #[derive(Debug)]
 pub enum GpuResponse {
     OkNoData,
-    OkDisplayInfo(Vec<(u32, u32)>),
+    /// The `(width, height, enabled)` of each scanout, indexed by scanout id.
+    OkDisplayInfo(Vec<(u32, u32, bool)>),
     OkCapsetInfo {
         capset_id: u32,
         version: u32,
         size: u32,
     },
     OkCapset(Vec<u8>),
+    OkEdid(Vec<u8>),
     OkResourcePlaneInfo {
         format_modifier: u64,
         plane_info: Vec<GpuResponsePlaneInfo>,
@@ -858,6 +889,9 @@ impl Display for GpuResponse {
 #[sorted]
 #[derive(Error, Debug)]
 pub enum GpuResponseEncodeError {
+    /// An EDID larger than the response can hold was in a `OkEdid`.
+    #[error("{0} bytes is too large for an EDID")]
+    EdidTooLarge(usize),
     /// An I/O error occurred.
     #[error("an I/O error occurred: {0}")]
     IO(io::Error),
@@ -913,10 +947,11 @@ impl GpuResponse {
                     hdr,
                     pmodes: Default::default(),
                 };
-                for (disp_mode, &(width, height)) in disp_info.pmodes.iter_mut().zip(info) {
+                for (disp_mode, &(width, height, enabled)) in disp_info.pmodes.iter_mut().zip(info)
+                {
                     disp_mode.r.width = Le32::from(width);
                     disp_mode.r.height = Le32::from(height);
-                    disp_mode.enabled = Le32::from(1);
+                    disp_mode.enabled = Le32::from(enabled as u32);
                 }
                 resp.write_obj(disp_info)?;
                 size_of_val(&disp_info)
@@ -940,6 +975,20 @@ impl GpuResponse {
                 resp.write_all(data)?;
                 size_of_val(&hdr) + data.len()
             }
+            GpuResponse::OkEdid(ref blob) => {
+                if blob.len() > VIRTIO_GPU_EDID_MAX_SIZE {
+                    return Err(GpuResponseEncodeError::EdidTooLarge(blob.len()));
+                }
+                let mut resp_edid = virtio_gpu_resp_edid {
+                    hdr,
+                    size: Le32::from(blob.len() as u32),
+                    padding: Le32::from(0),
+                    edid: [0; VIRTIO_GPU_EDID_MAX_SIZE],
+                };
+                resp_edid.edid[..blob.len()].copy_from_slice(blob);
+                resp.write_obj(resp_edid)?;
+                size_of_val(&resp_edid)
+            }
             GpuResponse::OkResourcePlaneInfo {
                 format_modifier,
                 ref plane_info,
@@ -1005,6 +1054,7 @@ impl GpuResponse {
             GpuResponse::OkDisplayInfo(_) => VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
             GpuResponse::OkCapsetInfo { .. } => VIRTIO_GPU_RESP_OK_CAPSET_INFO,
             GpuResponse::OkCapset(_) => VIRTIO_GPU_RESP_OK_CAPSET,
+            GpuResponse::OkEdid(_) => VIRTIO_GPU_RESP_OK_EDID,
             GpuResponse::OkResourcePlaneInfo { .. } => VIRTIO_GPU_RESP_OK_RESOURCE_PLANE_INFO,
             GpuResponse::OkResourceUuid { .. } => VIRTIO_GPU_RESP_OK_RESOURCE_UUID,
             GpuResponse::OkMapInfo { .. } => VIRTIO_GPU_RESP_OK_MAP_INFO,
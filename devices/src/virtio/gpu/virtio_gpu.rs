@@ -4,6 +4,7 @@
 
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
+use std::fs::File;
 use std::num::NonZeroU32;
 use std::rc::Rc;
 use std::result::Result;
@@ -25,6 +26,8 @@ use libc::c_void;
 
 use resources::Alloc;
 
+use super::capture::{self, Frame, Recorder};
+use super::edid::{create_edid, EDID_MAX_DISPLAY_SIZE};
 use super::protocol::{
     GpuResponse::{self, *},
     GpuResponsePlaneInfo, VirtioGpuResult, VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE,
@@ -36,7 +39,7 @@ use sync::Mutex;
 
 use vm_memory::{GuestAddress, GuestMemory};
 
-use vm_control::{MemSlot, VmMemoryRequest, VmMemoryResponse};
+use vm_control::{GpuControlResult, GpuRecordFormat, MemSlot, VmMemoryRequest, VmMemoryResponse};
 
 struct VirtioGpuResource {
     resource_id: u32,
@@ -151,9 +154,23 @@ impl VirtioGpuScanout {
         self.surface_id = None;
     }
 
-    fn set_position(&self, display: &Rc<RefCell<GpuDisplay>>, x: u32, y: u32) -> VirtioGpuResult {
+    fn set_cursor_hotspot(
+        &self,
+        display: &Rc<RefCell<GpuDisplay>>,
+        hot_x: u32,
+        hot_y: u32,
+    ) -> VirtioGpuResult {
         if let Some(surface_id) = self.surface_id {
-            display.borrow_mut().set_position(surface_id, x, y)?;
+            display
+                .borrow_mut()
+                .set_cursor_hotspot(surface_id, hot_x, hot_y)?;
+        }
+        Ok(OkNoData)
+    }
+
+    fn move_cursor(&self, display: &Rc<RefCell<GpuDisplay>>, x: u32, y: u32) -> VirtioGpuResult {
+        if let Some(surface_id) = self.surface_id {
+            display.borrow_mut().move_cursor(surface_id, x, y)?;
         }
         Ok(OkNoData)
     }
@@ -208,6 +225,30 @@ impl VirtioGpuScanout {
         Ok(OkNoData)
     }
 
+    /// Reads the contents of the resource that is shown on this scanout.
+    fn capture(&self, rutabaga: &mut Rutabaga) -> Result<Frame, GpuControlResult> {
+        let resource_id = self
+            .resource_id
+            .ok_or(GpuControlResult::NoScanoutResource)?;
+
+        let mut frame = Frame::new(self.width, self.height);
+        let mut transfer = Transfer3D::new_2d(0, 0, self.width, self.height);
+        transfer.stride = frame.stride();
+        rutabaga
+            .transfer_read(
+                0,
+                resource_id.get(),
+                transfer,
+                Some(VolatileSlice::new(&mut frame.pixels)),
+            )
+            .map_err(|e| {
+                error!("failed to read scanout resource {}: {}", resource_id, e);
+                GpuControlResult::CaptureFailed
+            })?;
+
+        Ok(frame)
+    }
+
     fn import_resource_to_display(
         display: &Rc<RefCell<GpuDisplay>>,
         resource: &mut VirtioGpuResource,
@@ -257,7 +298,10 @@ impl VirtioGpuScanout {
 /// Handles functionality related to displays, input events and hypervisor memory management.
 pub struct VirtioGpu {
     display: Rc<RefCell<GpuDisplay>>,
-    scanouts: Vec<VirtioGpuScanout>,
+    // The enabled scanouts, by scanout id.
+    scanouts: Map<u32, VirtioGpuScanout>,
+    // The number of scanouts offered to the guest, which bounds the ids in `scanouts`.
+    num_scanouts: u32,
     cursor_scanout: VirtioGpuScanout,
     // Maps event devices to scanout number.
     event_devices: Map<u32, u32>,
@@ -268,6 +312,8 @@ pub struct VirtioGpu {
     resources: Map<u32, VirtioGpuResource>,
     external_blob: bool,
     udmabuf_driver: Option<UdmabufDriver>,
+    // Maps scanout ids to the recordings of their frames.
+    recorders: Map<u32, Recorder>,
 }
 
 fn sglist_to_rutabaga_iovecs(
@@ -292,11 +338,21 @@ fn sglist_to_rutabaga_iovecs(
     Ok(rutabaga_iovecs)
 }
 
+// Displays must fit in the detailed timing descriptor of their EDID.
+fn validate_display_size(width: u32, height: u32) -> Result<(), GpuControlResult> {
+    let valid_sizes = 1..=EDID_MAX_DISPLAY_SIZE;
+    if !valid_sizes.contains(&width) || !valid_sizes.contains(&height) {
+        return Err(GpuControlResult::InvalidDisplaySize);
+    }
+    Ok(())
+}
+
 impl VirtioGpu {
     /// Creates a new instance of the VirtioGpu state tracker.
     pub fn new(
         display: GpuDisplay,
         display_params: Vec<GpuDisplayParameters>,
+        num_scanouts: u32,
         rutabaga_builder: RutabagaBuilder,
         event_devices: Vec<EventDevice>,
         gpu_device_tube: Tube,
@@ -324,18 +380,19 @@ impl VirtioGpu {
             .iter()
             .enumerate()
             .map(|(display_index, &display_param)| {
-                VirtioGpuScanout::new(
-                    display_param.width,
-                    display_param.height,
-                    display_index as u32,
+                let scanout_id = display_index as u32;
+                (
+                    scanout_id,
+                    VirtioGpuScanout::new(display_param.width, display_param.height, scanout_id),
                 )
             })
-            .collect::<Vec<_>>();
+            .collect::<Map<_, _>>();
         let cursor_scanout = VirtioGpuScanout::new_cursor();
 
         let mut virtio_gpu = VirtioGpu {
             display: Rc::new(RefCell::new(display)),
             scanouts,
+            num_scanouts,
             cursor_scanout,
             event_devices: Default::default(),
             gpu_device_tube,
@@ -345,6 +402,7 @@ impl VirtioGpu {
             resources: Default::default(),
             external_blob,
             udmabuf_driver,
+            recorders: Default::default(),
         };
 
         for event_device in event_devices {
@@ -374,12 +432,73 @@ impl VirtioGpu {
         &self.display
     }
 
-    /// Gets the list of supported display resolutions as a slice of `(width, height)` tuples.
-    pub fn display_info(&self) -> Vec<(u32, u32)> {
+    /// Gets the `(width, height, enabled)` of every scanout the guest may use, indexed by scanout
+    /// id.
+    pub fn display_info(&self) -> Vec<(u32, u32, bool)> {
+        (0..self.num_scanouts)
+            .map(|scanout_id| match self.scanouts.get(&scanout_id) {
+                Some(scanout) => (scanout.width, scanout.height, true),
+                None => (0, 0, false),
+            })
+            .collect::<Vec<_>>()
+    }
+
+    /// Gets the EDID describing the display of the scanout `scanout_id`.
+    pub fn get_edid(&self, scanout_id: u32) -> VirtioGpuResult {
+        let scanout = self.scanouts.get(&scanout_id).ok_or(ErrInvalidScanoutId)?;
+        Ok(OkEdid(
+            create_edid(scanout_id, scanout.width, scanout.height).to_vec(),
+        ))
+    }
+
+    /// Gets the `(width, height)` of the enabled scanouts, by scanout id.
+    pub fn displays(&self) -> Map<u32, (u32, u32)> {
         self.scanouts
             .iter()
-            .map(|scanout| (scanout.width, scanout.height))
-            .collect::<Vec<_>>()
+            .map(|(&scanout_id, scanout)| (scanout_id, (scanout.width, scanout.height)))
+            .collect()
+    }
+
+    /// Enables the first unused scanout with a display of the given size and returns its id.
+    pub fn add_display(&mut self, width: u32, height: u32) -> Result<u32, GpuControlResult> {
+        validate_display_size(width, height)?;
+        let scanout_id = (0..self.num_scanouts)
+            .find(|scanout_id| !self.scanouts.contains_key(scanout_id))
+            .ok_or(GpuControlResult::TooManyDisplays)?;
+        self.scanouts
+            .insert(scanout_id, VirtioGpuScanout::new(width, height, scanout_id));
+        Ok(scanout_id)
+    }
+
+    /// Disables the scanout `scanout_id`, releasing its display surface.
+    pub fn remove_display(&mut self, scanout_id: u32) -> GpuControlResult {
+        let mut scanout = match self.scanouts.remove(&scanout_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchScanout,
+        };
+
+        let surface_id = scanout.surface_id;
+        scanout.release_surface(&self.display);
+        self.release_cursor_of(surface_id);
+        self.recorders.remove(&scanout_id);
+        GpuControlResult::Ok
+    }
+
+    /// Changes the size of the display of the scanout `scanout_id`.
+    ///
+    /// The scanout shows nothing until the guest sets a resource of the new size on it, and any
+    /// recording of it is stopped.
+    pub fn resize_display(&mut self, scanout_id: u32, width: u32, height: u32) -> GpuControlResult {
+        if let Err(result) = validate_display_size(width, height) {
+            return result;
+        }
+        match self.remove_display(scanout_id) {
+            GpuControlResult::Ok => {}
+            result => return result,
+        }
+        self.scanouts
+            .insert(scanout_id, VirtioGpuScanout::new(width, height, scanout_id));
+        GpuControlResult::Ok
     }
 
     /// Processes the internal `display` events and returns `true` if any display was closed.
@@ -391,7 +510,7 @@ impl VirtioGpu {
             Err(e) => error!("failed to dispatch events: {}", e),
         }
 
-        for scanout in &self.scanouts {
+        for scanout in self.scanouts.values() {
             let close_requested = scanout
                 .surface_id
                 .map(|surface_id| display.close_requested(surface_id))
@@ -432,9 +551,20 @@ impl VirtioGpu {
             None => return Ok(OkNoData),
         };
 
-        for scanout in &mut self.scanouts {
+        for (&scanout_id, scanout) in self.scanouts.iter_mut() {
             if scanout.resource_id == resource_id {
                 scanout.flush(&self.display, resource, &mut self.rutabaga)?;
+
+                if let Some(recorder) = self.recorders.get_mut(&scanout_id) {
+                    if let Err(e) = scanout
+                        .capture(&mut self.rutabaga)
+                        .map_err(|e| e.to_string())
+                        .and_then(|frame| recorder.write_frame(&frame).map_err(|e| e.to_string()))
+                    {
+                        error!("stopped recording scanout {}: {}", scanout_id, e);
+                        self.recorders.remove(&scanout_id);
+                    }
+                }
             }
         }
         if self.cursor_scanout.resource_id == resource_id {
@@ -445,25 +575,89 @@ impl VirtioGpu {
         Ok(OkNoData)
     }
 
-    /// Updates the cursor's memory to the given resource_id, and sets its position to the given
-    /// coordinates.
+    /// Writes the current contents of the scanout `scanout_id` to `file` as a PNG image.
+    pub fn screenshot(&mut self, scanout_id: u32, mut file: File) -> GpuControlResult {
+        let frame = match self.scanouts.get(&scanout_id) {
+            Some(scanout) => match scanout.capture(&mut self.rutabaga) {
+                Ok(frame) => frame,
+                Err(result) => return result,
+            },
+            None => return GpuControlResult::NoSuchScanout,
+        };
+
+        match capture::write_png(&mut file, &frame) {
+            Ok(()) => GpuControlResult::Ok,
+            Err(e) => {
+                error!("failed to write screenshot: {}", e);
+                GpuControlResult::WriteFailed
+            }
+        }
+    }
+
+    /// Starts appending the frames flushed to the scanout `scanout_id` to `file`, beginning with
+    /// its current contents.
+    pub fn start_recording(
+        &mut self,
+        scanout_id: u32,
+        format: GpuRecordFormat,
+        file: File,
+    ) -> GpuControlResult {
+        let scanout = match self.scanouts.get(&scanout_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchScanout,
+        };
+        if self.recorders.contains_key(&scanout_id) {
+            return GpuControlResult::AlreadyRecording;
+        }
+
+        let mut recorder = Recorder::new(format, file);
+        // A scanout without a resource yet is recorded from its first flush.
+        if let Ok(frame) = scanout.capture(&mut self.rutabaga) {
+            if let Err(e) = recorder.write_frame(&frame) {
+                error!("failed to record scanout {}: {}", scanout_id, e);
+                return GpuControlResult::WriteFailed;
+            }
+        }
+
+        self.recorders.insert(scanout_id, recorder);
+        GpuControlResult::Ok
+    }
+
+    /// Stops a recording started with `start_recording`.
+    pub fn stop_recording(&mut self, scanout_id: u32) -> GpuControlResult {
+        if !self.scanouts.contains_key(&scanout_id) {
+            return GpuControlResult::NoSuchScanout;
+        }
+
+        match self.recorders.remove(&scanout_id) {
+            Some(_) => GpuControlResult::Ok,
+            None => GpuControlResult::NotRecording,
+        }
+    }
+
+    /// Updates the cursor's memory to the given resource_id and its hotspot to (hot_x, hot_y), and
+    /// moves its hotspot to the given coordinates.
     pub fn update_cursor(
         &mut self,
         resource_id: u32,
         scanout_id: u32,
         x: u32,
         y: u32,
+        hot_x: u32,
+        hot_y: u32,
     ) -> VirtioGpuResult {
         self.update_scanout_resource(SurfaceType::Cursor, scanout_id, None, resource_id)?;
 
-        self.cursor_scanout.set_position(&self.display, x, y)?;
+        self.cursor_scanout
+            .set_cursor_hotspot(&self.display, hot_x, hot_y)?;
+        self.cursor_scanout.move_cursor(&self.display, x, y)?;
 
         self.flush_resource(resource_id)
     }
 
-    /// Moves the cursor's position to the given coordinates.
+    /// Moves the cursor's hotspot to the given coordinates.
     pub fn move_cursor(&mut self, _scanout_id: u32, x: u32, y: u32) -> VirtioGpuResult {
-        self.cursor_scanout.set_position(&self.display, x, y)?;
+        self.cursor_scanout.move_cursor(&self.display, x, y)?;
         self.cursor_scanout.commit(&self.display)?;
         Ok(OkNoData)
     }
@@ -814,6 +1008,15 @@ impl VirtioGpu {
         }
     }
 
+    // Forgets the cursor's surface if it was shown on the surface `surface_id`, as the display
+    // releases it along with the surface it is a child of.
+    fn release_cursor_of(&mut self, surface_id: Option<u32>) {
+        if surface_id.is_some() && self.cursor_scanout.parent_surface_id == surface_id {
+            self.cursor_scanout.release_surface(&self.display);
+            self.cursor_scanout.parent_surface_id = None;
+        }
+    }
+
     fn update_scanout_resource(
         &mut self,
         scanout_type: SurfaceType,
@@ -830,7 +1033,7 @@ impl VirtioGpu {
 
                 scanout_parent_surface_id = self
                     .scanouts
-                    .get(parent_scanout_id as usize)
+                    .get(&parent_scanout_id)
                     .ok_or(ErrInvalidScanoutId)
                     .map(|parent_scanout| parent_scanout.surface_id)?;
 
@@ -839,19 +1042,24 @@ impl VirtioGpu {
             SurfaceType::Scanout => {
                 scanout = self
                     .scanouts
-                    .get_mut(scanout_id as usize)
+                    .get_mut(&scanout_id)
                     .ok_or(ErrInvalidScanoutId)?;
             }
         };
 
         // Virtio spec: "The driver can use resource_id = 0 to disable a scanout."
         if resource_id == 0 {
+            let mut released_surface_id = None;
             // Ignore any initial set_scanout(..., resource_id: 0) calls.
             if scanout.resource_id.is_some() {
+                released_surface_id = scanout.surface_id;
                 scanout.release_surface(&self.display);
             }
 
             scanout.resource_id = None;
+            if scanout_type == SurfaceType::Scanout {
+                self.release_cursor_of(released_surface_id);
+            }
             return Ok(OkNoData);
         }
 
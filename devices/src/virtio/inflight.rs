@@ -0,0 +1,378 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tracks the descriptors a device has taken from its virtqueues but not yet returned, in a
+//! memory region shared with a vhost-user frontend (VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD).
+//!
+//! The frontend keeps the region across device restarts, so a restarted device can find the
+//! requests its predecessor never completed and process them again. The layout of the region
+//! is the one for split virtqueues in the vhost-user specification.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use base::{MemoryMapping, MemoryMappingBuilder, MmapError, SharedMemory};
+use data_model::DataInit;
+use remain::sorted;
+use thiserror::Error as ThisError;
+
+// Each queue's part of the region starts at a multiple of this.
+const INFLIGHT_ALIGNMENT: u64 = 64;
+const INFLIGHT_VERSION: u16 = 1;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum InflightError {
+    /// Failed to create the shared memory for the region.
+    #[error("failed to create in-flight region memory: {0}")]
+    CreateMemory(base::Error),
+    /// The region was given with no queues or zero-sized queues.
+    #[error("in-flight region for {num_queues} queues of size {queue_size} is empty")]
+    EmptyRegion { num_queues: u16, queue_size: u16 },
+    /// Failed to map the region.
+    #[error("failed to map in-flight region: {0}")]
+    MapRegion(MmapError),
+    /// The region is too small for the queues it is supposed to track.
+    #[error("in-flight region of {size} bytes is smaller than the {expected} bytes needed")]
+    RegionTooSmall { size: u64, expected: u64 },
+}
+
+pub type InflightResult<T> = std::result::Result<T, InflightError>;
+
+// The header of each queue's part of the region.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct QueueRegionSplit {
+    features: u64,
+    version: u16,
+    desc_num: u16,
+    last_batch_head: u16,
+    used_idx: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for QueueRegionSplit {}
+
+// The state of one descriptor, following the header in the array indexed by descriptor head.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct DescStateSplit {
+    inflight: u8,
+    padding: [u8; 5],
+    next: u16,
+    counter: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for DescStateSplit {}
+
+const HEADER_SIZE: usize = std::mem::size_of::<QueueRegionSplit>();
+const DESC_STATE_SIZE: usize = std::mem::size_of::<DescStateSplit>();
+
+// Offsets of the fields that are updated on their own.
+const LAST_BATCH_HEAD_OFFSET: usize = 12;
+const USED_IDX_OFFSET: usize = 14;
+const COUNTER_OFFSET: usize = 8;
+
+/// Returns the size of the part of the region that tracks one queue of `queue_size` entries.
+fn queue_region_size(queue_size: u16) -> u64 {
+    let size = (HEADER_SIZE + DESC_STATE_SIZE * queue_size as usize) as u64;
+    (size + INFLIGHT_ALIGNMENT - 1) / INFLIGHT_ALIGNMENT * INFLIGHT_ALIGNMENT
+}
+
+/// A region shared with the frontend that tracks the in-flight descriptors of a device's queues.
+pub struct InflightRegion {
+    mapping: MemoryMapping,
+    num_queues: u16,
+    queue_size: u16,
+}
+
+impl InflightRegion {
+    /// Allocates a region for `num_queues` queues of up to `queue_size` entries. Returns the
+    /// memory backing it, to be handed to the frontend, and its size.
+    pub fn allocate(num_queues: u16, queue_size: u16) -> InflightResult<(File, u64)> {
+        if num_queues == 0 || queue_size == 0 {
+            return Err(InflightError::EmptyRegion {
+                num_queues,
+                queue_size,
+            });
+        }
+
+        let size = queue_region_size(queue_size) * u64::from(num_queues);
+        let shm = SharedMemory::named("vhost_user_inflight", size)
+            .map_err(InflightError::CreateMemory)?;
+        let region = InflightRegion {
+            mapping: MemoryMappingBuilder::new(size as usize)
+                .from_shared_memory(&shm)
+                .build()
+                .map_err(InflightError::MapRegion)?,
+            num_queues,
+            queue_size,
+        };
+        // The memory is zeroed, so every queue starts without a version. That tells the first
+        // device to use it that there is nothing to resubmit.
+        for queue_index in 0..num_queues {
+            let header = QueueRegionSplit {
+                desc_num: queue_size,
+                ..Default::default()
+            };
+            region
+                .mapping
+                .write_obj(header, region.queue_offset(queue_index))
+                .map_err(InflightError::MapRegion)?;
+        }
+
+        Ok((File::from(base::SafeDescriptor::from(shm)), size))
+    }
+
+    /// Maps the region the frontend handed back in `file`, `mmap_size` bytes at `mmap_offset`.
+    pub fn new(
+        file: &File,
+        mmap_size: u64,
+        mmap_offset: u64,
+        num_queues: u16,
+        queue_size: u16,
+    ) -> InflightResult<InflightRegion> {
+        if num_queues == 0 || queue_size == 0 {
+            return Err(InflightError::EmptyRegion {
+                num_queues,
+                queue_size,
+            });
+        }
+
+        let expected = queue_region_size(queue_size) * u64::from(num_queues);
+        if mmap_size < expected {
+            return Err(InflightError::RegionTooSmall {
+                size: mmap_size,
+                expected,
+            });
+        }
+
+        let mapping = MemoryMappingBuilder::new(mmap_size as usize)
+            .from_file(file)
+            .offset(mmap_offset)
+            .build()
+            .map_err(InflightError::MapRegion)?;
+        Ok(InflightRegion {
+            mapping,
+            num_queues,
+            queue_size,
+        })
+    }
+
+    fn queue_offset(&self, queue_index: u16) -> usize {
+        (queue_region_size(self.queue_size) * u64::from(queue_index)) as usize
+    }
+
+    /// Returns the tracker for the queue at `queue_index`, or `None` if the region has no room for
+    /// that queue.
+    pub fn queue(self: &Arc<Self>, queue_index: usize) -> Option<QueueInflight> {
+        if queue_index >= self.num_queues as usize {
+            return None;
+        }
+        Some(QueueInflight {
+            region: Arc::clone(self),
+            offset: self.queue_offset(queue_index as u16),
+            desc_num: 0,
+            counter: 1,
+            resubmit: VecDeque::new(),
+        })
+    }
+}
+
+/// Tracks the in-flight descriptors of one queue in an `InflightRegion`.
+#[derive(Clone)]
+pub struct QueueInflight {
+    region: Arc<InflightRegion>,
+    offset: usize,
+    desc_num: u16,
+    // Orders the descriptors by when they were taken from the available ring.
+    counter: u64,
+    // Descriptors a previous device left in flight, oldest first.
+    resubmit: VecDeque<u16>,
+}
+
+impl QueueInflight {
+    fn desc_offset(&self, desc_index: u16) -> usize {
+        self.offset + HEADER_SIZE + DESC_STATE_SIZE * desc_index as usize
+    }
+
+    fn write_obj<T: DataInit>(&self, val: T, offset: usize) {
+        // The offsets are within the region, which `InflightRegion::new` checked to be large
+        // enough for all of its queues.
+        self.region.mapping.write_obj(val, offset).unwrap();
+    }
+
+    fn read_obj<T: DataInit>(&self, offset: usize) -> T {
+        self.region.mapping.read_obj(offset).unwrap()
+    }
+
+    /// Picks up the state left behind by the previous device using this queue, given the index
+    /// in the used ring it completed requests up to. Returns how many descriptors it had taken
+    /// but not completed; they are resubmitted before any new ones from the available ring.
+    pub fn recover(&mut self, used_idx: u16) -> u16 {
+        let mut header: QueueRegionSplit = self.read_obj(self.offset);
+        self.desc_num = std::cmp::min(header.desc_num, self.region.queue_size);
+        self.resubmit.clear();
+        self.counter = 1;
+
+        if header.version == 0 {
+            // Nothing used this queue before.
+            header.version = INFLIGHT_VERSION;
+            header.used_idx = used_idx;
+            self.write_obj(header, self.offset);
+            return 0;
+        }
+
+        if header.used_idx != used_idx {
+            // The previous device died after putting `last_batch_head` in the used ring but
+            // before marking it done here.
+            if header.last_batch_head < self.desc_num {
+                self.write_obj(0u8, self.desc_offset(header.last_batch_head));
+            }
+            fence(Ordering::SeqCst);
+            self.write_obj(used_idx, self.offset + USED_IDX_OFFSET);
+        }
+
+        let mut inflight = Vec::new();
+        for desc_index in 0..self.desc_num {
+            let state: DescStateSplit = self.read_obj(self.desc_offset(desc_index));
+            if state.inflight != 0 {
+                inflight.push((state.counter, desc_index));
+            }
+        }
+        inflight.sort_unstable();
+        if let Some((counter, _)) = inflight.last() {
+            self.counter = counter + 1;
+        }
+        self.resubmit = inflight
+            .into_iter()
+            .map(|(_, desc_index)| desc_index)
+            .collect();
+        self.resubmit.len() as u16
+    }
+
+    /// Returns the next descriptor to resubmit without removing it.
+    pub fn peek_resubmit(&self) -> Option<u16> {
+        self.resubmit.front().copied()
+    }
+
+    /// Removes the next descriptor to resubmit.
+    pub fn pop_resubmit(&mut self) -> Option<u16> {
+        self.resubmit.pop_front()
+    }
+
+    /// Records that the descriptor chain headed by `desc_index` was taken from the available
+    /// ring.
+    pub fn start(&mut self, desc_index: u16) {
+        if desc_index >= self.desc_num {
+            return;
+        }
+        let offset = self.desc_offset(desc_index);
+        self.write_obj(self.counter, offset + COUNTER_OFFSET);
+        fence(Ordering::SeqCst);
+        self.write_obj(1u8, offset);
+        self.counter += 1;
+    }
+
+    /// Records that the descriptor chain headed by `desc_index` is about to be put in the used
+    /// ring.
+    pub fn begin_used(&self, desc_index: u16) {
+        if desc_index >= self.desc_num {
+            return;
+        }
+        self.write_obj(desc_index, self.offset + LAST_BATCH_HEAD_OFFSET);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Records that the descriptor chain headed by `desc_index` was put in the used ring, which
+    /// now has `used_idx` entries.
+    pub fn end_used(&self, desc_index: u16, used_idx: u16) {
+        if desc_index >= self.desc_num {
+            return;
+        }
+        fence(Ordering::SeqCst);
+        self.write_obj(0u8, self.desc_offset(desc_index));
+        fence(Ordering::SeqCst);
+        self.write_obj(used_idx, self.offset + USED_IDX_OFFSET);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_region(num_queues: u16, queue_size: u16) -> Arc<InflightRegion> {
+        let (file, size) = InflightRegion::allocate(num_queues, queue_size).unwrap();
+        Arc::new(InflightRegion::new(&file, size, 0, num_queues, queue_size).unwrap())
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(HEADER_SIZE, 16);
+        assert_eq!(DESC_STATE_SIZE, 16);
+        assert_eq!(queue_region_size(1), 64);
+        assert_eq!(queue_region_size(16), 320);
+    }
+
+    #[test]
+    fn region_too_small() {
+        let (file, size) = InflightRegion::allocate(1, 16).unwrap();
+        assert!(matches!(
+            InflightRegion::new(&file, size, 0, 2, 16),
+            Err(InflightError::RegionTooSmall { .. })
+        ));
+        assert!(new_region(1, 16).queue(1).is_none());
+    }
+
+    #[test]
+    fn resubmit_in_order() {
+        let region = new_region(2, 16);
+        let mut queue = region.queue(1).unwrap();
+        assert_eq!(queue.recover(0), 0);
+
+        // Take four descriptors and complete two of them, out of order.
+        for desc_index in &[3, 1, 7, 2] {
+            queue.start(*desc_index);
+        }
+        queue.begin_used(1);
+        queue.end_used(1, 1);
+        queue.begin_used(7);
+        queue.end_used(7, 2);
+
+        let mut restarted = region.queue(1).unwrap();
+        assert_eq!(restarted.recover(2), 2);
+        assert_eq!(restarted.pop_resubmit(), Some(3));
+        assert_eq!(restarted.pop_resubmit(), Some(2));
+        assert_eq!(restarted.pop_resubmit(), None);
+
+        // The other queue is untouched.
+        assert_eq!(region.queue(0).unwrap().recover(0), 0);
+    }
+
+    #[test]
+    fn resubmit_skips_used_descriptor() {
+        let region = new_region(1, 16);
+        let mut queue = region.queue(0).unwrap();
+        queue.recover(0);
+
+        queue.start(4);
+        queue.start(5);
+        // The device dies after putting 5 in the used ring but before marking it done.
+        queue.begin_used(5);
+
+        let mut restarted = region.queue(0).unwrap();
+        assert_eq!(restarted.recover(1), 1);
+        assert_eq!(restarted.pop_resubmit(), Some(4));
+
+        // Descriptors taken after the restart come after the resubmitted ones.
+        restarted.start(6);
+        let mut restarted = region.queue(0).unwrap();
+        assert_eq!(restarted.recover(1), 2);
+        assert_eq!(restarted.pop_resubmit(), Some(4));
+        assert_eq!(restarted.pop_resubmit(), Some(6));
+    }
+}
@@ -93,6 +93,33 @@ pub fn new_multi_touch_config(idx: u32, width: u32, height: u32) -> VirtioInputC
     )
 }
 
+/// Instantiates a VirtioInputConfig object with the default configuration for a tablet, an
+/// absolute pointer that supports left, right and middle buttons, X and Y axes and a wheel.
+pub fn new_tablet_config(idx: u32, width: u32, height: u32) -> VirtioInputConfig {
+    VirtioInputConfig::new(
+        virtio_input_device_ids::new(0, 0, 0, 0),
+        name_with_index(b"Crosvm Virtio Tablet ", idx),
+        name_with_index(b"virtio-tablet-", idx),
+        virtio_input_bitmap::new([0u8; 128]),
+        default_tablet_events(),
+        default_tablet_absinfo(width, height),
+    )
+}
+
+/// Instantiates a VirtioInputConfig object with the default configuration for a gamepad. It
+/// follows the layout of an Xbox 360 controller: four face buttons, two bumpers, select, start
+/// and mode buttons, two clickable thumbsticks, two analog triggers and a D-pad hat.
+pub fn new_gamepad_config(idx: u32) -> VirtioInputConfig {
+    VirtioInputConfig::new(
+        virtio_input_device_ids::new(0, 0, 0, 0),
+        name_with_index(b"Crosvm Virtio Gamepad ", idx),
+        name_with_index(b"virtio-gamepad-", idx),
+        virtio_input_bitmap::new([0u8; 128]),
+        default_gamepad_events(),
+        default_gamepad_absinfo(),
+    )
+}
+
 fn default_touchscreen_absinfo(width: u32, height: u32) -> BTreeMap<u16, virtio_input_absinfo> {
     let mut absinfo: BTreeMap<u16, virtio_input_absinfo> = BTreeMap::new();
     absinfo.insert(ABS_X, virtio_input_absinfo::new(0, width, 0, 0));
@@ -169,6 +196,59 @@ fn default_mouse_events() -> BTreeMap<u16, virtio_input_bitmap> {
     supported_events
 }
 
+fn default_tablet_absinfo(width: u32, height: u32) -> BTreeMap<u16, virtio_input_absinfo> {
+    let mut absinfo: BTreeMap<u16, virtio_input_absinfo> = BTreeMap::new();
+    absinfo.insert(ABS_X, virtio_input_absinfo::new(0, width, 0, 0));
+    absinfo.insert(ABS_Y, virtio_input_absinfo::new(0, height, 0, 0));
+    absinfo
+}
+
+fn default_tablet_events() -> BTreeMap<u16, virtio_input_bitmap> {
+    let mut supported_events: BTreeMap<u16, virtio_input_bitmap> = BTreeMap::new();
+    supported_events.insert(
+        EV_KEY,
+        virtio_input_bitmap::from_bits(&[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]),
+    );
+    supported_events.insert(EV_REL, virtio_input_bitmap::from_bits(&[REL_WHEEL]));
+    supported_events.insert(EV_ABS, virtio_input_bitmap::from_bits(&[ABS_X, ABS_Y]));
+    supported_events
+}
+
+fn default_gamepad_absinfo() -> BTreeMap<u16, virtio_input_absinfo> {
+    // The minimums are negative; the guest reads them back as signed values.
+    let stick = virtio_input_absinfo::new(-32768i32 as u32, 32767, 16, 128);
+    let trigger = virtio_input_absinfo::new(0, 255, 0, 0);
+    let hat = virtio_input_absinfo::new(-1i32 as u32, 1, 0, 0);
+    let mut absinfo: BTreeMap<u16, virtio_input_absinfo> = BTreeMap::new();
+    absinfo.insert(ABS_X, stick);
+    absinfo.insert(ABS_Y, stick);
+    absinfo.insert(ABS_RX, stick);
+    absinfo.insert(ABS_RY, stick);
+    absinfo.insert(ABS_Z, trigger);
+    absinfo.insert(ABS_RZ, trigger);
+    absinfo.insert(ABS_HAT0X, hat);
+    absinfo.insert(ABS_HAT0Y, hat);
+    absinfo
+}
+
+fn default_gamepad_events() -> BTreeMap<u16, virtio_input_bitmap> {
+    let mut supported_events: BTreeMap<u16, virtio_input_bitmap> = BTreeMap::new();
+    supported_events.insert(
+        EV_KEY,
+        virtio_input_bitmap::from_bits(&[
+            BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL, BTN_TR, BTN_SELECT, BTN_START,
+            BTN_MODE, BTN_THUMBL, BTN_THUMBR,
+        ]),
+    );
+    supported_events.insert(
+        EV_ABS,
+        virtio_input_bitmap::from_bits(&[
+            ABS_X, ABS_Y, ABS_Z, ABS_RX, ABS_RY, ABS_RZ, ABS_HAT0X, ABS_HAT0Y,
+        ]),
+    );
+    supported_events
+}
+
 fn default_keyboard_events() -> BTreeMap<u16, virtio_input_bitmap> {
     let mut supported_events: BTreeMap<u16, virtio_input_bitmap> = BTreeMap::new();
     supported_events.insert(
@@ -325,6 +405,103 @@ fn default_switch_events() -> BTreeMap<u16, virtio_input_bitmap> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::virtio::input::virtio_input_config;
+    use data_model::DataInit;
+
+    // Selects `select`/`subsel` the way the guest driver does and reads back the whole config.
+    fn read_config(config: &mut VirtioInputConfig, select: u8, subsel: u8) -> virtio_input_config {
+        config.write(0, &[select, subsel]);
+        let mut data = [0u8; std::mem::size_of::<virtio_input_config>()];
+        config.read(0, &mut data);
+        *virtio_input_config::from_slice(&data).unwrap()
+    }
+
+    fn read_absinfo(config: &mut VirtioInputConfig, axis: u16) -> (i32, i32, u32, u32) {
+        let cfg = read_config(config, VIRTIO_INPUT_CFG_ABS_INFO, axis as u8);
+        assert_eq!(
+            cfg.size as usize,
+            std::mem::size_of::<virtio_input_absinfo>()
+        );
+        let absinfo = virtio_input_absinfo::from_slice(&cfg.payload[..cfg.size as usize]).unwrap();
+        (
+            u32::from(absinfo.min) as i32,
+            u32::from(absinfo.max) as i32,
+            absinfo.fuzz.into(),
+            absinfo.flat.into(),
+        )
+    }
+
+    fn read_bits(config: &mut VirtioInputConfig, select: u8, subsel: u8) -> Vec<u16> {
+        let cfg = read_config(config, select, subsel);
+        (0..cfg.size as u16 * 8)
+            .filter(|bit| cfg.payload[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+            .collect()
+    }
+
+    #[test]
+    fn test_new_tablet_config() {
+        let mut config = new_tablet_config(1, 1920, 1080);
+        let name = read_config(&mut config, VIRTIO_INPUT_CFG_ID_NAME, 0);
+        assert_eq!(
+            &name.payload[..name.size as usize],
+            b"Crosvm Virtio Tablet 1"
+        );
+        assert_eq!(config.serial_name, b"virtio-tablet-1".to_vec());
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, 0),
+            vec![EV_KEY, EV_REL, EV_ABS]
+        );
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8),
+            vec![BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]
+        );
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8),
+            vec![REL_WHEEL]
+        );
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            vec![ABS_X, ABS_Y]
+        );
+        assert_eq!(read_absinfo(&mut config, ABS_X), (0, 1920, 0, 0));
+        assert_eq!(read_absinfo(&mut config, ABS_Y), (0, 1080, 0, 0));
+        assert!(read_bits(&mut config, VIRTIO_INPUT_CFG_PROP_BITS, 0).is_empty());
+    }
+
+    #[test]
+    fn test_new_gamepad_config() {
+        let mut config = new_gamepad_config(0);
+        assert_eq!(config.serial_name, b"virtio-gamepad-0".to_vec());
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, 0),
+            vec![EV_KEY, EV_ABS]
+        );
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8),
+            vec![
+                BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL, BTN_TR, BTN_SELECT, BTN_START,
+                BTN_MODE, BTN_THUMBL, BTN_THUMBR
+            ]
+        );
+        assert_eq!(
+            read_bits(&mut config, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            vec![ABS_X, ABS_Y, ABS_Z, ABS_RX, ABS_RY, ABS_RZ, ABS_HAT0X, ABS_HAT0Y]
+        );
+        for &axis in &[ABS_X, ABS_Y, ABS_RX, ABS_RY] {
+            assert_eq!(read_absinfo(&mut config, axis), (-32768, 32767, 16, 128));
+        }
+        for &axis in &[ABS_Z, ABS_RZ] {
+            assert_eq!(read_absinfo(&mut config, axis), (0, 255, 0, 0));
+        }
+        for &axis in &[ABS_HAT0X, ABS_HAT0Y] {
+            assert_eq!(read_absinfo(&mut config, axis), (-1, 1, 0, 0));
+        }
+        // Axes that aren't supported have no info.
+        assert_eq!(
+            read_config(&mut config, VIRTIO_INPUT_CFG_ABS_INFO, ABS_MT_SLOT as u8).size,
+            0
+        );
+    }
 
     #[test]
     fn test_new_switches_config() {
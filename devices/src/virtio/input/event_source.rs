@@ -38,6 +38,9 @@ pub trait EventSource: AsRawDescriptor {
     fn pop_available_event(&mut self) -> Option<virtio_input_event>;
     /// Sends a status update event to the source
     fn send_event(&mut self, vio_evt: &virtio_input_event) -> Result<()>;
+    /// Queues events that didn't come from the source so they are delivered after the events
+    /// already received.
+    fn inject_events(&mut self, events: &[virtio_input_event]);
 }
 
 /// Encapsulates implementation details common to all kinds of event sources.
@@ -97,6 +100,10 @@ where
         self.queue.pop_front()
     }
 
+    fn inject_events(&mut self, events: &[virtio_input_event]) {
+        self.queue.extend(events);
+    }
+
     fn send_event(&mut self, vio_evt: &virtio_input_event, encoding: EventType) -> Result<()> {
         // Miscellaneous events produced by the device are sent back to it by the kernel input
         // subsystem, but because these events are handled by the host kernel as well as the
@@ -183,6 +190,10 @@ where
         self.evt_source_impl
             .send_event(vio_evt, EventType::VirtioInputEvent)
     }
+
+    fn inject_events(&mut self, events: &[virtio_input_event]) {
+        self.evt_source_impl.inject_events(events)
+    }
 }
 
 /// Encapsulates an event device node as an event source
@@ -235,6 +246,10 @@ where
         self.evt_source_impl
             .send_event(vio_evt, EventType::InputEvent)
     }
+
+    fn inject_events(&mut self, events: &[virtio_input_event]) {
+        self.evt_source_impl.inject_events(events)
+    }
 }
 
 #[cfg(test)]
@@ -373,4 +388,29 @@ mod tests {
             "no events should pop"
         );
     }
+
+    #[test]
+    fn inject_after_receive() {
+        let evts = instantiate_input_events(4usize);
+        let mut source =
+            EventSourceImpl::new(SourceMock::new(&evts[..2].to_vec()), input_event::SIZE * 2);
+        assert_eq!(
+            source.receive_events::<input_event>().unwrap(),
+            2,
+            "should receive all events"
+        );
+        let injected: Vec<virtio_input_event> = evts[2..]
+            .iter()
+            .map(|e| input_event::decode(e.as_slice()))
+            .collect();
+        source.inject_events(&injected);
+        assert_eq!(
+            source.available_events(),
+            evts.len(),
+            "injected events should be available"
+        );
+        for evt in &evts {
+            assert_events_match(&source.pop_available_event().unwrap(), evt);
+        }
+    }
 }
@@ -10,10 +10,16 @@ mod event_source;
 
 use self::constants::*;
 
-use base::{error, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
+use base::{
+    error, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, Tube, TubeError, WaitContext,
+};
 use data_model::{DataInit, Le16, Le32};
 use remain::sorted;
 use thiserror::Error;
+use vm_control::{
+    DeviceControlRequest, DeviceControlResponse, InputControlCommand, InputControlResult,
+    InputRecordEntry,
+};
 use vm_memory::GuestMemory;
 
 use self::event_source::{EvdevEventSource, EventSource, SocketEventSource};
@@ -23,9 +29,10 @@ use super::{
 };
 use linux_input_sys::{virtio_input_event, InputEventDecoder};
 use std::collections::BTreeMap;
-use std::io::Read;
-use std::io::Write;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
 use std::thread;
+use std::time::Instant;
 
 const EVENT_QUEUE_SIZE: u16 = 64;
 const STATUS_QUEUE_SIZE: u16 = 64;
@@ -34,6 +41,12 @@ const QUEUE_SIZES: &[u16] = &[EVENT_QUEUE_SIZE, STATUS_QUEUE_SIZE];
 #[sorted]
 #[derive(Error, Debug)]
 pub enum InputError {
+    // Failed to receive a request on the control tube
+    #[error("failed to receive input control request: {0}")]
+    ControlRequest(TubeError),
+    // Failed to send a response on the control tube
+    #[error("failed to send input control response: {0}")]
+    ControlResponse(TubeError),
     // Virtio descriptor error
     #[error("virtio descriptor error: {0}")]
     Descriptor(DescriptorError),
@@ -328,18 +341,44 @@ impl VirtioInputConfig {
     }
 }
 
+// Appends the events delivered to the guest to a file, one `InputRecordEntry` per line.
+struct EventRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl EventRecorder {
+    fn new(file: File) -> EventRecorder {
+        EventRecorder {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, evt: virtio_input_event) -> io::Result<()> {
+        let entry = InputRecordEntry {
+            time_us: self.start.elapsed().as_micros() as u64,
+            event: evt.into(),
+        };
+        writeln!(self.writer, "{}", entry)
+    }
+}
+
 struct Worker<T: EventSource> {
     interrupt: Interrupt,
     event_source: T,
     event_queue: Queue,
     status_queue: Queue,
     guest_memory: GuestMemory,
+    control_tube: Option<Tube>,
+    recorder: Option<EventRecorder>,
 }
 
 impl<T: EventSource> Worker<T> {
     // Fills a virtqueue with events from the source.  Returns the number of bytes written.
     fn fill_event_virtqueue(
         event_source: &mut T,
+        recorder: &mut Option<EventRecorder>,
         avail_desc: DescriptorChain,
         mem: &GuestMemory,
     ) -> Result<usize> {
@@ -348,6 +387,12 @@ impl<T: EventSource> Worker<T> {
         while writer.available_bytes() >= virtio_input_event::SIZE {
             if let Some(evt) = event_source.pop_available_event() {
                 writer.write_obj(evt).map_err(InputError::WriteQueue)?;
+                if let Some(r) = recorder {
+                    if let Err(e) = r.record(evt) {
+                        error!("Input: failed to record event, stopping recording: {}", e);
+                        *recorder = None;
+                    }
+                }
             } else {
                 break;
             }
@@ -371,6 +416,7 @@ impl<T: EventSource> Worker<T> {
 
                     let bytes_written = match Worker::fill_event_virtqueue(
                         &mut self.event_source,
+                        &mut self.recorder,
                         avail_desc,
                         &self.guest_memory,
                     ) {
@@ -391,6 +437,12 @@ impl<T: EventSource> Worker<T> {
             }
         }
 
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.writer.flush() {
+                error!("Input: failed to flush recorded events: {}", e);
+            }
+        }
+
         needs_interrupt
     }
 
@@ -434,6 +486,43 @@ impl<T: EventSource> Worker<T> {
         Ok(needs_interrupt)
     }
 
+    // Handles one request received on the control tube.
+    fn process_control_request(&mut self, control_tube: &Tube) -> Result<()> {
+        let request: DeviceControlRequest<InputControlCommand> =
+            control_tube.recv().map_err(InputError::ControlRequest)?;
+        let response = match request.command {
+            InputControlCommand::Inject(events) => {
+                let events: Vec<virtio_input_event> =
+                    events.into_iter().map(virtio_input_event::from).collect();
+                self.event_source.inject_events(&events);
+                InputControlResult::Ok
+            }
+            InputControlCommand::RecordStart { file } => {
+                if self.recorder.is_some() {
+                    InputControlResult::AlreadyRecording
+                } else {
+                    self.recorder = Some(EventRecorder::new(file));
+                    InputControlResult::Ok
+                }
+            }
+            InputControlCommand::RecordStop => match self.recorder.take() {
+                Some(mut recorder) => {
+                    if let Err(e) = recorder.writer.flush() {
+                        error!("Input: failed to flush recorded events: {}", e);
+                    }
+                    InputControlResult::Ok
+                }
+                None => InputControlResult::NotRecording,
+            },
+        };
+        control_tube
+            .send(&DeviceControlResponse {
+                id: request.id,
+                result: response,
+            })
+            .map_err(InputError::ControlResponse)
+    }
+
     fn run(&mut self, event_queue_evt: Event, status_queue_evt: Event, kill_evt: Event) {
         if let Err(e) = self.event_source.init() {
             error!("failed initializing event source: {}", e);
@@ -445,6 +534,7 @@ impl<T: EventSource> Worker<T> {
             EventQAvailable,
             StatusQAvailable,
             InputEventsAvailable,
+            ControlTube,
             InterruptResample,
             Kill,
         }
@@ -469,6 +559,12 @@ impl<T: EventSource> Worker<T> {
                 return;
             }
         }
+        if let Some(control_tube) = &self.control_tube {
+            if let Err(e) = wait_ctx.add(control_tube, Token::ControlTube) {
+                error!("failed adding control tube to WaitContext: {}", e);
+                return;
+            }
+        }
 
         'wait: loop {
             let wait_events = match wait_ctx.wait() {
@@ -503,6 +599,21 @@ impl<T: EventSource> Worker<T> {
                         Err(e) => error!("error receiving events: {}", e),
                         Ok(_cnt) => needs_interrupt |= self.send_events(),
                     },
+                    Token::ControlTube => {
+                        if let Some(control_tube) = self.control_tube.take() {
+                            match self.process_control_request(&control_tube) {
+                                Ok(()) => {
+                                    self.control_tube = Some(control_tube);
+                                    needs_interrupt |= self.send_events();
+                                }
+                                Err(e) => {
+                                    // The other end has most likely gone away, so stop listening.
+                                    error!("{}", e);
+                                    let _ = wait_ctx.delete(&control_tube);
+                                }
+                            }
+                        }
+                    }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
@@ -533,6 +644,7 @@ pub struct Input<T: EventSource> {
     config: VirtioInputConfig,
     source: Option<T>,
     virtio_features: u64,
+    control_tube: Option<Tube>,
 }
 
 impl<T: EventSource> Drop for Input<T> {
@@ -553,10 +665,14 @@ where
     T: 'static + EventSource + Send,
 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
         if let Some(source) = &self.source {
-            return vec![source.as_raw_descriptor()];
+            keep_rds.push(source.as_raw_descriptor());
+        }
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
         }
-        Vec::new()
+        keep_rds
     }
 
     fn device_type(&self) -> u32 {
@@ -607,6 +723,7 @@ where
         let event_queue_evt = queue_evts.remove(0);
 
         if let Some(source) = self.source.take() {
+            let control_tube = self.control_tube.take();
             let worker_result = thread::Builder::new()
                 .name(String::from("virtio_input"))
                 .spawn(move || {
@@ -616,6 +733,8 @@ where
                         event_queue,
                         status_queue,
                         guest_memory: mem,
+                        control_tube,
+                        recorder: None,
                     };
                     worker.run(event_queue_evt, status_queue_evt, kill_evt);
                     worker
@@ -650,6 +769,7 @@ where
                 }
                 Ok(worker) => {
                     self.source = Some(worker.event_source);
+                    self.control_tube = worker.control_tube;
                     return true;
                 }
             }
@@ -669,6 +789,7 @@ where
         config: VirtioInputConfig::from_evdev(&source)?,
         source: Some(EvdevEventSource::new(source)),
         virtio_features,
+        control_tube: None,
     })
 }
 
@@ -689,16 +810,19 @@ where
         config: defaults::new_single_touch_config(idx, width, height),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
     })
 }
 
-/// Creates a new virtio touch device which supports multi touch.
+/// Creates a new virtio touch device which supports multi touch. Events can also be injected
+/// and recorded through `control_tube`.
 pub fn new_multi_touch<T>(
     idx: u32,
     source: T,
     width: u32,
     height: u32,
     virtio_features: u64,
+    control_tube: Option<Tube>,
 ) -> Result<Input<SocketEventSource<T>>>
 where
     T: Read + Write + AsRawDescriptor,
@@ -709,6 +833,7 @@ where
         config: defaults::new_multi_touch_config(idx, width, height),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube,
     })
 }
 
@@ -730,14 +855,17 @@ where
         config: defaults::new_trackpad_config(idx, width, height),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
     })
 }
 
-/// Creates a new virtio mouse which supports primary, secondary, wheel and REL events.
+/// Creates a new virtio mouse which supports primary, secondary, wheel and REL events. Events can
+/// also be injected and recorded through `control_tube`.
 pub fn new_mouse<T>(
     idx: u32,
     source: T,
     virtio_features: u64,
+    control_tube: Option<Tube>,
 ) -> Result<Input<SocketEventSource<T>>>
 where
     T: Read + Write + AsRawDescriptor,
@@ -748,14 +876,59 @@ where
         config: defaults::new_mouse_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube,
+    })
+}
+
+/// Creates a new virtio tablet, an absolute pointer which supports primary, secondary and middle
+/// buttons, a wheel and X and Y axes ranging from zero to `width` and `height`.
+pub fn new_tablet<T>(
+    idx: u32,
+    source: T,
+    width: u32,
+    height: u32,
+    virtio_features: u64,
+) -> Result<Input<SocketEventSource<T>>>
+where
+    T: Read + Write + AsRawDescriptor,
+{
+    Ok(Input {
+        kill_evt: None,
+        worker_thread: None,
+        config: defaults::new_tablet_config(idx, width, height),
+        source: Some(SocketEventSource::new(source)),
+        virtio_features,
+        control_tube: None,
+    })
+}
+
+/// Creates a new virtio gamepad with the buttons, thumbsticks, triggers and D-pad of an Xbox 360
+/// controller.
+pub fn new_gamepad<T>(
+    idx: u32,
+    source: T,
+    virtio_features: u64,
+) -> Result<Input<SocketEventSource<T>>>
+where
+    T: Read + Write + AsRawDescriptor,
+{
+    Ok(Input {
+        kill_evt: None,
+        worker_thread: None,
+        config: defaults::new_gamepad_config(idx),
+        source: Some(SocketEventSource::new(source)),
+        virtio_features,
+        control_tube: None,
     })
 }
 
 /// Creates a new virtio keyboard, which supports the same events as an en-us physical keyboard.
+/// Events can also be injected and recorded through `control_tube`.
 pub fn new_keyboard<T>(
     idx: u32,
     source: T,
     virtio_features: u64,
+    control_tube: Option<Tube>,
 ) -> Result<Input<SocketEventSource<T>>>
 where
     T: Read + Write + AsRawDescriptor,
@@ -766,6 +939,7 @@ where
         config: defaults::new_keyboard_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube,
     })
 }
 
@@ -784,5 +958,6 @@ where
         config: defaults::new_switches_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
     })
 }
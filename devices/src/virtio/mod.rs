@@ -6,6 +6,7 @@
 
 mod balloon;
 mod descriptor_utils;
+mod inflight;
 mod input;
 mod interrupt;
 mod iommu;
@@ -20,6 +21,7 @@ mod video;
 mod virtio_device;
 mod virtio_pci_common_config;
 mod virtio_pci_device;
+mod vsock;
 pub mod wl;
 
 pub mod block;
@@ -40,6 +42,7 @@ pub use self::descriptor_utils::Error as DescriptorError;
 pub use self::descriptor_utils::*;
 #[cfg(feature = "gpu")]
 pub use self::gpu::*;
+pub use self::inflight::*;
 pub use self::input::*;
 pub use self::interrupt::*;
 pub use self::iommu::*;
@@ -56,6 +59,7 @@ pub use self::tpm::*;
 pub use self::video::*;
 pub use self::virtio_device::*;
 pub use self::virtio_pci_device::*;
+pub use self::vsock::*;
 pub use self::wl::*;
 
 use crate::ProtectionType;
@@ -93,6 +97,7 @@ const TYPE_PMEM: u32 = 27;
 const TYPE_MAC80211_HWSIM: u32 = 29;
 const TYPE_VIDEO_ENC: u32 = 30;
 const TYPE_VIDEO_DEC: u32 = 31;
+const TYPE_VHOST_USER: u32 = 43;
 // Additional types invented by crosvm
 const MAX_VIRTIO_DEVICE_ID: u32 = 63;
 const TYPE_WL: u32 = MAX_VIRTIO_DEVICE_ID;
@@ -135,6 +140,7 @@ pub fn type_to_str(type_: u32) -> Option<&'static str> {
         TYPE_TPM => "tpm",
         TYPE_VIDEO_DEC => "video-decoder",
         TYPE_VIDEO_ENC => "video-encoder",
+        TYPE_VHOST_USER => "vhost-user",
         _ => return None,
     })
 }
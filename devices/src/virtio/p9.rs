@@ -5,21 +5,28 @@
 use std::io::{self, Write};
 use std::mem;
 use std::result;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
 use std::thread;
 
 use base::{error, warn, Error as SysError, Event, PollToken, RawDescriptor, WaitContext};
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error;
 use vm_memory::GuestMemory;
 
 use super::{
-    copy_config, DescriptorError, Interrupt, Queue, Reader, SignalableInterrupt, VirtioDevice,
-    Writer, TYPE_9P,
+    copy_config, DescriptorChain, DescriptorError, Interrupt, Queue, Reader, SignalableInterrupt,
+    VirtioDevice, Writer, TYPE_9P,
 };
 
 const QUEUE_SIZE: u16 = 128;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
+// Number of threads that handle requests from the queue. Each request is handled synchronously so
+// more threads allow slow operations on the host to overlap.
+const NUM_REQUEST_THREADS: usize = 8;
+
 // The only virtio_9p feature.
 const VIRTIO_9P_MOUNT_TAG: u8 = 0;
 
@@ -51,6 +58,9 @@ pub enum P9Error {
     /// Failed to signal the virio used queue.
     #[error("failed to signal used queue: {0}")]
     SignalUsedQueue(SysError),
+    /// Failed to spawn a thread for handling requests.
+    #[error("failed to spawn request thread: {0}")]
+    SpawnRequestThread(io::Error),
     /// The tag for the 9P device was too large to fit in the config space.
     #[error("P9 device tag is too long: len = {0}, max = {}", ::std::u16::MAX)]
     TagTooLong(usize),
@@ -61,34 +71,70 @@ pub enum P9Error {
 
 pub type P9Result<T> = result::Result<T, P9Error>;
 
+#[derive(Clone)]
 struct Worker {
-    interrupt: Interrupt,
+    interrupt: Arc<Interrupt>,
     mem: GuestMemory,
-    queue: Queue,
-    server: p9::Server,
+    queue: Arc<Mutex<Queue>>,
+    server: Arc<p9::Server>,
 }
 
 impl Worker {
-    fn process_queue(&mut self) -> P9Result<()> {
-        while let Some(avail_desc) = self.queue.pop(&self.mem) {
-            let mut reader = Reader::new(self.mem.clone(), avail_desc.clone())
-                .map_err(P9Error::InvalidDescriptorChain)?;
-            let mut writer = Writer::new(self.mem.clone(), avail_desc.clone())
-                .map_err(P9Error::InvalidDescriptorChain)?;
-
-            self.server
-                .handle_message(&mut reader, &mut writer)
-                .map_err(P9Error::Internal)?;
-
-            self.queue
-                .add_used(&self.mem, avail_desc.index, writer.bytes_written() as u32);
-        }
-        self.queue.trigger_interrupt(&self.mem, &self.interrupt);
+    // Returns the number of bytes written to the descriptor chain.
+    fn handle_request(&self, avail_desc: DescriptorChain) -> P9Result<u32> {
+        let mut reader = Reader::new(self.mem.clone(), avail_desc.clone())
+            .map_err(P9Error::InvalidDescriptorChain)?;
+        let mut writer =
+            Writer::new(self.mem.clone(), avail_desc).map_err(P9Error::InvalidDescriptorChain)?;
+
+        self.server
+            .handle_message(&mut reader, &mut writer)
+            .map_err(P9Error::Internal)?;
 
-        Ok(())
+        Ok(writer.bytes_written() as u32)
     }
 
-    fn run(&mut self, queue_evt: Event, kill_evt: Event) -> P9Result<()> {
+    // Handles requests sent by the queue thread until it goes away.
+    fn handle_requests(&self, requests: &Mutex<Receiver<DescriptorChain>>) {
+        loop {
+            // Only hold the lock while waiting so that other threads can pick up the next request
+            // while this one is being handled.
+            let avail_desc = match requests.lock().recv() {
+                Ok(avail_desc) => avail_desc,
+                Err(_) => return,
+            };
+
+            let index = avail_desc.index;
+            // Always return the descriptor, even on failure, so the guest doesn't wait on it
+            // forever and the queue doesn't run out of descriptors.
+            let len = match self.handle_request(avail_desc) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("virtio_9p: failed to handle request: {}", e);
+                    0
+                }
+            };
+
+            let mut queue = self.queue.lock();
+            queue.add_used(&self.mem, index, len);
+            queue.trigger_interrupt(&self.mem, &*self.interrupt);
+        }
+    }
+
+    fn run(&self, queue_evt: Event, kill_evt: Event) -> P9Result<()> {
+        let (requests_tx, requests_rx) = channel();
+        let requests_rx = Arc::new(Mutex::new(requests_rx));
+        let mut threads = Vec::with_capacity(NUM_REQUEST_THREADS);
+        for idx in 0..NUM_REQUEST_THREADS {
+            let worker = self.clone();
+            let requests_rx = Arc::clone(&requests_rx);
+            let thread = thread::Builder::new()
+                .name(format!("virtio_9p request {}", idx))
+                .spawn(move || worker.handle_requests(&requests_rx))
+                .map_err(P9Error::SpawnRequestThread)?;
+            threads.push(thread);
+        }
+
         #[derive(PollToken)]
         enum Token {
             // A request is ready on the queue.
@@ -114,12 +160,31 @@ impl Worker {
                 match event.token {
                     Token::QueueReady => {
                         queue_evt.read().map_err(P9Error::ReadQueueEvent)?;
-                        self.process_queue()?;
+                        loop {
+                            // Don't hold the lock while sending so that the request threads can
+                            // return completed requests in the meantime.
+                            let avail_desc = match self.queue.lock().pop(&self.mem) {
+                                Some(avail_desc) => avail_desc,
+                                None => break,
+                            };
+
+                            // The request threads only exit once `requests_tx` is dropped.
+                            let _ = requests_tx.send(avail_desc);
+                        }
                     }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => return Ok(()),
+                    Token::Kill => {
+                        // Let the request threads finish what they already received.
+                        mem::drop(requests_tx);
+                        for thread in threads {
+                            if thread.join().is_err() {
+                                error!("virtio_9p request thread panicked");
+                            }
+                        }
+                        return Ok(());
+                    }
                 }
             }
         }
@@ -224,11 +289,11 @@ impl VirtioDevice for P9 {
                 thread::Builder::new()
                     .name("virtio_9p".to_string())
                     .spawn(move || {
-                        let mut worker = Worker {
-                            interrupt,
+                        let worker = Worker {
+                            interrupt: Arc::new(interrupt),
                             mem: guest_mem,
-                            queue: queues.remove(0),
-                            server,
+                            queue: Arc::new(Mutex::new(queues.remove(0))),
+                            server: Arc::new(server),
                         };
 
                         worker.run(queue_evts.remove(0), kill_evt)
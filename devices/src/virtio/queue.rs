@@ -9,9 +9,9 @@ use std::sync::atomic::{fence, Ordering};
 use base::error;
 use cros_async::{AsyncError, EventAsync};
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::{GuestAddress, GuestMemory};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
-use super::{SignalableInterrupt, VIRTIO_MSI_NO_VECTOR};
+use super::{QueueInflight, SignalableInterrupt, VIRTIO_MSI_NO_VECTOR};
 
 const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 const VIRTQ_DESC_F_WRITE: u16 = 0x2;
@@ -232,6 +232,10 @@ pub struct Queue {
     // processing requests. This is the count of how many are in flight(could be several contexts
     // handling requests in parallel). When this count is zero, notifications are re-enabled.
     notification_disable_count: usize,
+
+    // Tracks the descriptors taken from this queue but not yet used, for a vhost-user frontend
+    // to hand to a restarted device.
+    inflight: Option<QueueInflight>,
 }
 
 impl Queue {
@@ -250,6 +254,7 @@ impl Queue {
             features: 0,
             last_used: Wrapping(0),
             notification_disable_count: 0,
+            inflight: None,
         }
     }
 
@@ -271,6 +276,7 @@ impl Queue {
         self.next_used = Wrapping(0);
         self.features = 0;
         self.last_used = Wrapping(0);
+        self.inflight = None;
     }
 
     pub fn is_valid(&self, mem: &GuestMemory) -> bool {
@@ -420,22 +426,50 @@ impl Queue {
         let avail_index = self.get_avail_index(mem);
         let avail_len = avail_index - self.next_avail;
 
+        // Descriptors a previous device left in flight come before anything new.
+        if let Some(descriptor_index) = self.inflight.as_ref().and_then(|i| i.peek_resubmit()) {
+            return DescriptorChain::checked_new(
+                mem,
+                self.desc_table,
+                queue_size,
+                descriptor_index,
+                0,
+            );
+        }
+
         if avail_len.0 > queue_size || self.next_avail == avail_index {
             return None;
         }
 
-        let desc_idx_addr_offset = 4 + (u64::from(self.next_avail.0 % queue_size) * 2);
-        let desc_idx_addr = mem.checked_offset(self.avail_ring, desc_idx_addr_offset)?;
-
         // This index is checked below in checked_new.
-        let descriptor_index: u16 = mem.read_obj_from_addr(desc_idx_addr).unwrap();
+        let descriptor_index = self.next_avail_descriptor_index(mem)?;
 
         DescriptorChain::checked_new(mem, self.desc_table, queue_size, descriptor_index, 0)
     }
 
+    // Get the index of the descriptor chain head at `next_avail` in the available ring.
+    fn next_avail_descriptor_index(&self, mem: &GuestMemory) -> Option<u16> {
+        let desc_idx_addr_offset = 4 + (u64::from(self.next_avail.0 % self.actual_size()) * 2);
+        let desc_idx_addr = mem.checked_offset(self.avail_ring, desc_idx_addr_offset)?;
+        Some(mem.read_obj_from_addr(desc_idx_addr).unwrap())
+    }
+
     /// Remove the first available descriptor chain from the queue.
     /// This function should only be called immediately following `peek`.
     pub fn pop_peeked(&mut self, mem: &GuestMemory) {
+        if let Some(mut inflight) = self.inflight.take() {
+            let resubmitted = inflight.pop_resubmit().is_some();
+            if !resubmitted {
+                if let Some(descriptor_index) = self.next_avail_descriptor_index(mem) {
+                    inflight.start(descriptor_index);
+                }
+            }
+            self.inflight = Some(inflight);
+            if resubmitted {
+                return;
+            }
+        }
+
         self.next_avail += Wrapping(1);
         if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
             self.set_avail_event(mem, self.next_avail);
@@ -482,6 +516,10 @@ impl Queue {
             return;
         }
 
+        if let Some(inflight) = &self.inflight {
+            inflight.begin_used(desc_index);
+        }
+
         let used_ring = self.used_ring;
         let next_used = (self.next_used.0 % self.actual_size()) as usize;
         let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
@@ -493,6 +531,28 @@ impl Queue {
 
         self.next_used += Wrapping(1);
         self.set_used_index(mem, self.next_used);
+
+        if let Some(inflight) = &self.inflight {
+            inflight.end_used(desc_index, self.next_used.0);
+        }
+    }
+
+    /// Tracks the descriptors taken from this queue in `inflight`, which is shared with a
+    /// vhost-user frontend, and picks up the requests a previous device left in flight there.
+    ///
+    /// The queue resumes from the index in the used ring: the descriptors the previous device
+    /// had taken but not used are resubmitted before any new ones in the available ring.
+    pub fn set_inflight(
+        &mut self,
+        mem: &GuestMemory,
+        mut inflight: QueueInflight,
+    ) -> Result<(), GuestMemoryError> {
+        let used_index: u16 = mem.read_obj_from_addr(self.used_ring.unchecked_add(2))?;
+        let num_inflight = inflight.recover(used_index);
+        self.next_used = Wrapping(used_index);
+        self.next_avail = Wrapping(used_index) + Wrapping(num_inflight);
+        self.inflight = Some(inflight);
+        Ok(())
     }
 
     /// Enable / Disable guest notify device that requests are available on
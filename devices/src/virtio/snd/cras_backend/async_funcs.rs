@@ -6,21 +6,28 @@ use futures::{
     channel::{mpsc, oneshot},
     SinkExt, StreamExt,
 };
+use std::collections::BTreeMap;
 use std::io::{self, Write};
 use std::rc::Rc;
 
-use base::{debug, error};
+use base::{debug, error, AsyncTube};
 use cros_async::{sync::Condvar, sync::Mutex as AsyncMutex, EventAsync, Executor};
 use data_model::{DataInit, Le32};
+use vm_control::{
+    DeviceControlRequest, DeviceControlResponse, SndControlCommand, SndControlResult,
+};
 use vm_memory::GuestMemory;
 
-use crate::virtio::cras_backend::{Parameters, PcmResponse};
+use crate::virtio::cras_backend::{PcmResponse, StreamSourceGenerator};
 use crate::virtio::snd::common::*;
 use crate::virtio::snd::constants::*;
 use crate::virtio::snd::layout::*;
 use crate::virtio::{DescriptorChain, Queue, Reader, SignalableInterrupt, Writer};
 
-use super::{DirectionalStream, Error, SndData, StreamInfo, WorkerStatus};
+use super::{
+    DirectionalStream, Error, SndData, StreamInfo, WorkerStatus, HDA_DEFCONF_ASSOCIATION_MASK,
+    HDA_DEFCONF_ASSOCIATION_SHIFT, HDA_DEFCONF_SEQUENCE_MASK,
+};
 
 // Returns true if the operation is successful. Returns error if there is
 // a runtime/internal error
@@ -29,8 +36,8 @@ async fn process_pcm_ctrl(
     mem: &GuestMemory,
     tx_send: &mpsc::UnboundedSender<PcmResponse>,
     rx_send: &mpsc::UnboundedSender<PcmResponse>,
-    streams: &Rc<AsyncMutex<Vec<AsyncMutex<StreamInfo<'_>>>>>,
-    params: &Parameters,
+    streams: &Rc<AsyncMutex<Vec<AsyncMutex<StreamInfo>>>>,
+    stream_source_generator: &StreamSourceGenerator,
     cmd_code: u32,
     writer: &mut Writer,
     stream_id: usize,
@@ -59,7 +66,7 @@ async fn process_pcm_ctrl(
     let result = match cmd_code {
         VIRTIO_SND_R_PCM_PREPARE => {
             stream
-                .prepare(ex, mem.clone(), tx_send, rx_send, params)
+                .prepare(ex, mem.clone(), tx_send, rx_send, stream_source_generator)
                 .await
         }
         VIRTIO_SND_R_PCM_START => stream.start().await,
@@ -164,11 +171,10 @@ pub async fn start_pcm_worker(
         let mut writer = Writer::new(mem.clone(), desc_chain).map_err(Error::DescriptorChain)?;
 
         let copy_data = async {
-            // stream_id was already read in handle_pcm_queue
+            // stream_id was already read in handle_pcm_queue, which also made sure the message
+            // came from the queue matching the stream's direction.
             reader.consume(std::mem::size_of::<virtio_snd_pcm_xfer>());
 
-            // TODO: How to check wrong direction?
-            // return Err(Error::StreamWrongDirection);
             match &mut dstream {
                 DirectionalStream::Output(stream) => {
                     let mut dst_buf = stream
@@ -304,10 +310,12 @@ pub async fn send_pcm_response_worker<I: SignalableInterrupt>(
 }
 
 /// Handle messages from the tx or the rx queue. One invocation is needed for
-/// each queue.
-pub async fn handle_pcm_queue<'a>(
+/// each queue. `direction` is VIRTIO_SND_D_OUTPUT for the tx queue and VIRTIO_SND_D_INPUT for the
+/// rx queue.
+pub async fn handle_pcm_queue(
     mem: &GuestMemory,
-    streams: &Rc<AsyncMutex<Vec<AsyncMutex<StreamInfo<'a>>>>>,
+    streams: &Rc<AsyncMutex<Vec<AsyncMutex<StreamInfo>>>>,
+    direction: u8,
     mut response_sender: mpsc::UnboundedSender<PcmResponse>,
     queue: &Rc<AsyncMutex<Queue>>,
     queue_event: EventAsync,
@@ -355,6 +363,24 @@ pub async fn handle_pcm_queue<'a>(
         };
 
         match stream_info.sender.as_ref() {
+            Some(_) if stream_info.direction != direction => {
+                error!(
+                    "stream {} is {} but the message came from the {} queue",
+                    stream_id,
+                    get_virtio_direction_name(stream_info.direction),
+                    get_virtio_direction_name(direction)
+                );
+                defer_pcm_response_to_worker(
+                    desc_chain,
+                    mem,
+                    virtio_snd_pcm_status {
+                        status: Le32::from(VIRTIO_SND_S_IO_ERR),
+                        latency_bytes: Le32::from(0),
+                    },
+                    &mut response_sender,
+                )
+                .await?;
+            }
             Some(mut s) => {
                 s.send(desc_chain).await.map_err(Error::MpscSend)?;
             }
@@ -383,14 +409,14 @@ pub async fn handle_pcm_queue<'a>(
 pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
     ex: &Executor,
     mem: &GuestMemory,
-    streams: &Rc<AsyncMutex<Vec<AsyncMutex<StreamInfo<'_>>>>>,
+    streams: &Rc<AsyncMutex<Vec<AsyncMutex<StreamInfo>>>>,
     snd_data: &SndData,
     mut queue: Queue,
     mut queue_event: EventAsync,
     interrupt: &I,
     tx_send: mpsc::UnboundedSender<PcmResponse>,
     rx_send: mpsc::UnboundedSender<PcmResponse>,
-    params: &Parameters,
+    stream_source_generator: &StreamSourceGenerator,
 ) -> Result<(), Error> {
     loop {
         let desc_chain = queue
@@ -418,12 +444,13 @@ pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
                         reader.read_obj().map_err(Error::ReadMessage)?;
                     let start_id: usize = u32::from(query_info.start_id) as usize;
                     let count: usize = u32::from(query_info.count) as usize;
-                    if start_id + count > snd_data.jack_info.len() {
+                    let jack_info = snd_data.jack_info.read_lock().await;
+                    if start_id + count > jack_info.len() {
                         error!(
                             "start_id({}) + count({}) must be smaller than the number of jacks ({})",
                             start_id,
                             count,
-                            snd_data.jack_info.len()
+                            jack_info.len()
                         );
                         return writer
                             .write_obj(VIRTIO_SND_S_BAD_MSG)
@@ -435,9 +462,9 @@ pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
                     writer
                         .write_obj(VIRTIO_SND_S_OK)
                         .map_err(Error::WriteResponse)?;
-                    for i in start_id..(start_id + count) {
+                    for info in &jack_info[start_id..(start_id + count)] {
                         writer
-                            .write_all(snd_data.jack_info[i].as_slice())
+                            .write_all(info.as_slice())
                             .map_err(Error::WriteResponse)?;
                     }
                     Ok(())
@@ -481,7 +508,7 @@ pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
                             "start_id({}) + count({}) must be smaller than the number of chmaps ({})",
                             start_id,
                             count,
-                            snd_data.pcm_info.len()
+                            snd_data.chmap_info.len()
                         );
                         return writer
                             .write_obj(VIRTIO_SND_S_BAD_MSG)
@@ -501,7 +528,55 @@ pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
                     Ok(())
                 }
                 VIRTIO_SND_R_JACK_REMAP => {
-                    unreachable!("remap is unsupported");
+                    let remap: virtio_snd_jack_remap =
+                        reader.read_obj().map_err(Error::ReadMessage)?;
+                    let jack_id: usize = u32::from(remap.hdr.jack_id) as usize;
+                    let association: u32 = remap.association.into();
+                    let sequence: u32 = remap.sequence.into();
+
+                    let mut jack_info = snd_data.jack_info.lock().await;
+                    let num_jacks = jack_info.len();
+                    let info = match jack_info.get_mut(jack_id) {
+                        Some(info) => info,
+                        None => {
+                            error!("jack_id {} < jacks {}", jack_id, num_jacks);
+                            return writer
+                                .write_obj(VIRTIO_SND_S_BAD_MSG)
+                                .map_err(Error::WriteResponse);
+                        }
+                    };
+                    if u32::from(info.features) & (1 << VIRTIO_SND_JACK_F_REMAP) == 0 {
+                        error!("jack {} does not support remapping", jack_id);
+                        return writer
+                            .write_obj(VIRTIO_SND_S_NOT_SUPP)
+                            .map_err(Error::WriteResponse);
+                    }
+                    let association_max =
+                        HDA_DEFCONF_ASSOCIATION_MASK >> HDA_DEFCONF_ASSOCIATION_SHIFT;
+                    if association > association_max || sequence > HDA_DEFCONF_SEQUENCE_MASK {
+                        error!(
+                            "Invalid association ({}) or sequence ({}) for jack {}",
+                            association, sequence, jack_id
+                        );
+                        return writer
+                            .write_obj(VIRTIO_SND_S_BAD_MSG)
+                            .map_err(Error::WriteResponse);
+                    }
+
+                    // The association and sequence override the ones in the HDA pin default
+                    // configuration, which the guest reads back with VIRTIO_SND_R_JACK_INFO.
+                    let defconf = u32::from(info.hda_reg_defconf)
+                        & !(HDA_DEFCONF_ASSOCIATION_MASK | HDA_DEFCONF_SEQUENCE_MASK);
+                    info.hda_reg_defconf =
+                        (defconf | association << HDA_DEFCONF_ASSOCIATION_SHIFT | sequence).into();
+                    debug!(
+                        "VIRTIO_SND_R_JACK_REMAP for jack id={}: association={} sequence={}",
+                        jack_id, association, sequence
+                    );
+
+                    writer
+                        .write_obj(VIRTIO_SND_S_OK)
+                        .map_err(Error::WriteResponse)
                 }
                 VIRTIO_SND_R_PCM_SET_PARAMS => {
                     // Raise VIRTIO_SND_S_BAD_MSG or IO error?
@@ -628,7 +703,7 @@ pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
                         &tx_send,
                         &rx_send,
                         streams,
-                        params,
+                        stream_source_generator,
                         code,
                         &mut writer,
                         stream_id,
@@ -652,24 +727,91 @@ pub async fn handle_ctrl_queue<I: SignalableInterrupt>(
     }
 }
 
-/// Send events to the audio driver.
+/// Send events to the audio driver. Each event received from `events` is written to the next
+/// buffer the driver made available on the event queue.
 pub async fn handle_event_queue<I: SignalableInterrupt>(
     mem: &GuestMemory,
     mut queue: Queue,
     mut queue_event: EventAsync,
     interrupt: &I,
+    events: &mut mpsc::UnboundedReceiver<virtio_snd_event>,
 ) -> Result<(), Error> {
-    loop {
+    while let Some(event) = events.next().await {
         let desc_chain = queue
             .next_async(mem, &mut queue_event)
             .await
             .map_err(Error::Async)?;
 
-        // TODO(woodychow): Poll and forward events from cras asynchronously (API to be added)
         let index = desc_chain.index;
-        queue.add_used(mem, index, 0);
+        let mut writer = Writer::new(mem.clone(), desc_chain).map_err(Error::DescriptorChain)?;
+        writer.write_obj(event).map_err(Error::WriteResponse)?;
+        queue.add_used(mem, index, writer.bytes_written() as u32);
         queue.trigger_interrupt(&mem, interrupt);
     }
+    debug!("Event channel is closed.");
+    Ok(())
+}
+
+/// Handle commands from the crosvm control socket, if the device was given a control tube.
+/// Changing a jack's connection state sends a jack event to the audio driver.
+pub async fn handle_control_tube(
+    tube: Option<AsyncTube>,
+    snd_data: &SndData,
+    event_sender: mpsc::UnboundedSender<virtio_snd_event>,
+) -> Result<(), Error> {
+    let tube = match tube {
+        Some(t) => t,
+        None => return futures::future::pending().await,
+    };
+
+    loop {
+        let request = tube
+            .next::<DeviceControlRequest<SndControlCommand>>()
+            .await
+            .map_err(Error::ReceiveControlCommand)?;
+
+        let result = match request.command {
+            SndControlCommand::ListJacks => {
+                let jack_info = snd_data.jack_info.read_lock().await;
+                SndControlResult::Jacks(
+                    jack_info
+                        .iter()
+                        .enumerate()
+                        .map(|(jack_id, info)| (jack_id as u32, info.connected != 0))
+                        .collect::<BTreeMap<_, _>>(),
+                )
+            }
+            SndControlCommand::SetJackConnected { jack_id, connected } => {
+                let mut jack_info = snd_data.jack_info.lock().await;
+                match jack_info.get_mut(jack_id as usize) {
+                    Some(info) => {
+                        if (info.connected != 0) != connected {
+                            info.connected = connected as u8;
+                            let code = if connected {
+                                VIRTIO_SND_EVT_JACK_CONNECTED
+                            } else {
+                                VIRTIO_SND_EVT_JACK_DISCONNECTED
+                            };
+                            event_sender
+                                .unbounded_send(virtio_snd_event {
+                                    hdr: virtio_snd_hdr { code: code.into() },
+                                    data: jack_id.into(),
+                                })
+                                .map_err(|e| Error::MpscSend(e.into_send_error()))?;
+                        }
+                        SndControlResult::Ok
+                    }
+                    None => SndControlResult::NoSuchJack,
+                }
+            }
+        };
+
+        let response = DeviceControlResponse {
+            id: request.id,
+            result,
+        };
+        tube.send(&response).map_err(Error::SendControlResponse)?;
+    }
 }
 
 // Async task that waits for a signal from the kill event given to the device at startup.  Once this event is
@@ -5,22 +5,28 @@
 // virtio-sound spec: https://github.com/oasis-tcs/virtio-spec/blob/master/virtio-sound.tex
 
 use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::{FromStr, ParseBoolError};
 use std::thread;
 
-use audio_streams::{SampleFormat, StreamSource};
-use base::{error, warn, Error as SysError, Event, RawDescriptor};
+#[cfg(feature = "audio_pulse")]
+use audio_streams::pulse::PulseStreamSource;
+use audio_streams::wav::WavStreamSource;
+use audio_streams::{BoxError, NoopStreamSource, SampleFormat, StreamSource};
+use base::{error, warn, AsRawDescriptor, Error as SysError, Event, RawDescriptor, Tube};
 use cros_async::sync::{Condvar, Mutex as AsyncMutex};
-use cros_async::{select6, AsyncError, EventAsync, Executor, SelectResult};
+use cros_async::{select8, AsyncError, EventAsync, Executor, SelectResult};
 use data_model::DataInit;
 use futures::channel::{
     mpsc,
     oneshot::{self, Canceled},
 };
 use futures::{pin_mut, Future, TryFutureExt};
-use libcras::{BoxError, CrasClient, CrasClientType, CrasSocketType};
+#[cfg(feature = "audio_cras")]
+use libcras::{CrasClient, CrasClientType, CrasSocketType};
 use sys_util::{set_rt_prio_limit, set_rt_round_robin};
 use thiserror::Error as ThisError;
 use vm_memory::GuestMemory;
@@ -71,6 +77,7 @@ pub enum Error {
     #[error("Failed to write message response: {0}")]
     WriteResponse(io::Error),
     /// Libcras error.
+    #[cfg(feature = "audio_cras")]
     #[error("Error in libcras: {0}")]
     Libcras(libcras::Error),
     // Mpsc read error.
@@ -82,6 +89,15 @@ pub enum Error {
     /// Stream not found.
     #[error("stream id ({0}) < num_streams ({1})")]
     StreamNotFound(usize, usize),
+    /// Creating an async tube failed.
+    #[error("Failed to create async tube: {0}")]
+    CreateAsyncTube(base::TubeError),
+    /// Receiving a command from the control tube failed.
+    #[error("Failed to receive control command: {0}")]
+    ReceiveControlCommand(base::TubeError),
+    /// Sending a response on the control tube failed.
+    #[error("Failed to send control response: {0}")]
+    SendControlResponse(base::TubeError),
     /// Fetch buffer error
     #[error("Failed to get buffer from CRAS: {0}")]
     FetchBuffer(BoxError),
@@ -106,22 +122,82 @@ pub enum Error {
     /// Failed to parse bool value.
     #[error("Invalid bool value: {0}")]
     InvalidBoolValue(ParseBoolError),
+    /// A parameter required by the backend is missing.
+    #[error("Missing snd parameter {0} for the backend")]
+    MissingParameter(String),
+    /// Opening a WAV file failed.
+    #[error("Failed to open WAV file: {0}")]
+    OpenWavFile(io::Error),
+    /// A WAV file was given to another backend.
+    #[error("Snd parameter {0} is exclusive to the wav backend")]
+    UnexpectedWavFile(String),
 }
 
-/// Holds the parameters for a cras sound device
+/// Where the streams of a sound device are played and captured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamSourceBackend {
+    #[cfg(feature = "audio_cras")]
+    CRAS,
+    NULL,
+    WAV,
+    #[cfg(feature = "audio_pulse")]
+    PULSE,
+}
+
+impl Default for StreamSourceBackend {
+    #[cfg(feature = "audio_cras")]
+    fn default() -> Self {
+        StreamSourceBackend::CRAS
+    }
+
+    #[cfg(not(feature = "audio_cras"))]
+    fn default() -> Self {
+        StreamSourceBackend::NULL
+    }
+}
+
+impl FromStr for StreamSourceBackend {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "audio_cras")]
+            "cras" => Ok(StreamSourceBackend::CRAS),
+            "null" => Ok(StreamSourceBackend::NULL),
+            "wav" => Ok(StreamSourceBackend::WAV),
+            #[cfg(feature = "audio_pulse")]
+            "pulse" => Ok(StreamSourceBackend::PULSE),
+            _ => Err(Error::InvalidParameterValue(
+                s.to_string(),
+                "must be cras, null, wav or pulse".to_string(),
+            )),
+        }
+    }
+}
+
+/// Holds the parameters for a sound device whose streams come from a `StreamSource`
 #[derive(Debug, Clone)]
 pub struct Parameters {
     pub capture: bool,
+    pub backend: StreamSourceBackend,
+    #[cfg(feature = "audio_cras")]
     pub client_type: CrasClientType,
+    #[cfg(feature = "audio_cras")]
     pub socket_type: CrasSocketType,
+    pub playback_file: Option<PathBuf>,
+    pub capture_file: Option<PathBuf>,
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Parameters {
             capture: true,
+            backend: Default::default(),
+            #[cfg(feature = "audio_cras")]
             client_type: CrasClientType::CRAS_CLIENT_TYPE_CROSVM,
+            #[cfg(feature = "audio_cras")]
             socket_type: CrasSocketType::Unified,
+            playback_file: None,
+            capture_file: None,
         }
     }
 }
@@ -140,11 +216,22 @@ impl FromStr for Parameters {
                 "capture" => {
                     params.capture = v.parse::<bool>().map_err(Error::InvalidBoolValue)?;
                 }
+                "backend" => {
+                    params.backend = v.parse()?;
+                }
+                "playback_file" => {
+                    params.playback_file = Some(PathBuf::from(v));
+                }
+                "capture_file" => {
+                    params.capture_file = Some(PathBuf::from(v));
+                }
+                #[cfg(feature = "audio_cras")]
                 "client_type" => {
                     params.client_type = v.parse().map_err(|e: libcras::CrasSysError| {
                         Error::InvalidParameterValue(v.to_string(), e.to_string())
                     })?;
                 }
+                #[cfg(feature = "audio_cras")]
                 "socket_type" => {
                     params.socket_type = v.parse().map_err(|e: libcras::Error| {
                         Error::InvalidParameterValue(v.to_string(), e.to_string())
@@ -156,10 +243,95 @@ impl FromStr for Parameters {
             }
         }
 
+        // playback_file is required for and, like capture_file, exclusive to the wav backend,
+        // which only captures from a capture_file.
+        if params.backend == StreamSourceBackend::WAV {
+            if params.playback_file.is_none() {
+                return Err(Error::MissingParameter("playback_file".to_string()));
+            }
+            params.capture &= params.capture_file.is_some();
+        } else if params.playback_file.is_some() {
+            return Err(Error::UnexpectedWavFile("playback_file".to_string()));
+        } else if params.capture_file.is_some() {
+            return Err(Error::UnexpectedWavFile("capture_file".to_string()));
+        }
+
         Ok(params)
     }
 }
 
+/// Creates the `StreamSource` of each stream as it is prepared. WAV files are opened along with
+/// the device, which can't reach them once it is jailed.
+#[derive(Clone)]
+pub enum StreamSourceGenerator {
+    #[cfg(feature = "audio_cras")]
+    Cras(Parameters),
+    Null,
+    Wav(WavStreamSource),
+    #[cfg(feature = "audio_pulse")]
+    Pulse,
+}
+
+impl StreamSourceGenerator {
+    pub fn new(params: &Parameters) -> Result<Self, Error> {
+        match params.backend {
+            #[cfg(feature = "audio_cras")]
+            StreamSourceBackend::CRAS => Ok(StreamSourceGenerator::Cras(params.clone())),
+            StreamSourceBackend::NULL => Ok(StreamSourceGenerator::Null),
+            StreamSourceBackend::WAV => {
+                let playback_file = params
+                    .playback_file
+                    .as_ref()
+                    .ok_or_else(|| Error::MissingParameter("playback_file".to_string()))?;
+                let playback_file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(playback_file)
+                    .map_err(Error::OpenWavFile)?;
+                let capture_file = params
+                    .capture_file
+                    .as_ref()
+                    .map(File::open)
+                    .transpose()
+                    .map_err(Error::OpenWavFile)?;
+                Ok(StreamSourceGenerator::Wav(WavStreamSource::new(
+                    Some(playback_file),
+                    capture_file,
+                )))
+            }
+            #[cfg(feature = "audio_pulse")]
+            StreamSourceBackend::PULSE => Ok(StreamSourceGenerator::Pulse),
+        }
+    }
+
+    fn generate(&self) -> Result<Box<dyn StreamSource>, Error> {
+        match self {
+            #[cfg(feature = "audio_cras")]
+            StreamSourceGenerator::Cras(params) => {
+                let mut client =
+                    CrasClient::with_type(params.socket_type).map_err(Error::Libcras)?;
+                if params.capture {
+                    client.enable_cras_capture();
+                }
+                client.set_client_type(params.client_type);
+                Ok(Box::new(client))
+            }
+            StreamSourceGenerator::Null => Ok(Box::new(NoopStreamSource::new())),
+            StreamSourceGenerator::Wav(stream_source) => Ok(Box::new(stream_source.clone())),
+            #[cfg(feature = "audio_pulse")]
+            StreamSourceGenerator::Pulse => Ok(Box::new(PulseStreamSource::new())),
+        }
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        match self {
+            StreamSourceGenerator::Wav(stream_source) => stream_source.keep_fds(),
+            _ => Vec::new(),
+        }
+    }
+}
+
 pub enum DirectionalStream {
     Input(Box<dyn audio_streams::capture::AsyncCaptureBufferStream>),
     Output(Box<dyn audio_streams::AsyncPlaybackBufferStream>),
@@ -172,8 +344,8 @@ pub enum WorkerStatus {
     Quit = 2,
 }
 
-pub struct StreamInfo<'a> {
-    client: Option<CrasClient<'a>>,
+pub struct StreamInfo {
+    stream_source: Option<Box<dyn StreamSource>>,
     channels: u8,
     format: SampleFormat,
     frame_rate: u32,
@@ -189,7 +361,7 @@ pub struct StreamInfo<'a> {
     worker_future: Option<Box<dyn Future<Output = Result<(), Error>> + Unpin>>,
 }
 
-impl fmt::Debug for StreamInfo<'_> {
+impl fmt::Debug for StreamInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("StreamInfo")
             .field("channels", &self.channels)
@@ -203,10 +375,10 @@ impl fmt::Debug for StreamInfo<'_> {
     }
 }
 
-impl Default for StreamInfo<'_> {
+impl Default for StreamInfo {
     fn default() -> Self {
         StreamInfo {
-            client: None,
+            stream_source: None,
             channels: 0,
             format: SampleFormat::U8,
             frame_rate: 0,
@@ -222,9 +394,9 @@ impl Default for StreamInfo<'_> {
     }
 }
 
-// Stores constant data
+// Stores constant data, except for the jacks which change on hotplug and remap.
 pub struct SndData {
-    jack_info: Vec<virtio_snd_jack_info>,
+    jack_info: AsyncMutex<Vec<virtio_snd_jack_info>>,
     pcm_info: Vec<virtio_snd_pcm_info>,
     chmap_info: Vec<virtio_snd_chmap_info>,
 }
@@ -240,6 +412,20 @@ const SUPPORTED_FRAME_RATES: u64 = 1 << VIRTIO_SND_PCM_RATE_8000
     | 1 << VIRTIO_SND_PCM_RATE_44100
     | 1 << VIRTIO_SND_PCM_RATE_48000;
 
+// HDA pin default configurations (Intel HDA spec 7.3.3.31): a black front headphone jack and a
+// pink front microphone jack, each in its own association.
+const HDA_DEFCONF_HEADPHONE: u32 = 0x0221_1010;
+const HDA_DEFCONF_MIC: u32 = 0x02a1_9020;
+const HDA_DEFCONF_ASSOCIATION_SHIFT: u32 = 4;
+const HDA_DEFCONF_ASSOCIATION_MASK: u32 = 0xf0;
+const HDA_DEFCONF_SEQUENCE_MASK: u32 = 0x0f;
+
+// HDA pin capabilities (Intel HDA spec 7.3.4.9).
+const HDA_PINCAP_PRESENCE_DETECT: u32 = 1 << 2;
+const HDA_PINCAP_HEADPHONE_DRIVE: u32 = 1 << 3;
+const HDA_PINCAP_OUTPUT: u32 = 1 << 4;
+const HDA_PINCAP_INPUT: u32 = 1 << 5;
+
 // Response from pcm_worker to pcm_queue
 pub struct PcmResponse {
     desc_index: u16,
@@ -248,14 +434,14 @@ pub struct PcmResponse {
     done: Option<oneshot::Sender<()>>, // when pcm response is written to the queue
 }
 
-impl<'a> StreamInfo<'a> {
+impl StreamInfo {
     async fn prepare(
         &mut self,
         ex: &Executor,
         mem: GuestMemory,
         tx_send: &mpsc::UnboundedSender<PcmResponse>,
         rx_send: &mpsc::UnboundedSender<PcmResponse>,
-        params: &Parameters,
+        stream_source_generator: &StreamSourceGenerator,
     ) -> Result<(), Error> {
         if self.state != VIRTIO_SND_R_PCM_SET_PARAMS
             && self.state != VIRTIO_SND_R_PCM_PREPARE
@@ -273,13 +459,8 @@ impl<'a> StreamInfo<'a> {
             error!("period_bytes must be divisible by frame size");
             return Err(Error::OperationNotSupported);
         }
-        if self.client.is_none() {
-            let mut client = CrasClient::with_type(params.socket_type).map_err(Error::Libcras)?;
-            if params.capture {
-                client.enable_cras_capture();
-            }
-            client.set_client_type(params.client_type);
-            self.client = Some(client);
+        if self.stream_source.is_none() {
+            self.stream_source = Some(stream_source_generator.generate()?);
         }
         // (*)
         // `buffer_size` in `audio_streams` API indicates the buffer size in bytes that the stream
@@ -290,7 +471,7 @@ impl<'a> StreamInfo<'a> {
         let (stream, pcm_sender) = match self.direction {
             VIRTIO_SND_D_OUTPUT => (
                 DirectionalStream::Output(
-                    self.client
+                    self.stream_source
                         .as_mut()
                         .unwrap()
                         .new_async_playback_stream(
@@ -309,7 +490,7 @@ impl<'a> StreamInfo<'a> {
             VIRTIO_SND_D_INPUT => {
                 (
                     DirectionalStream::Input(
-                        self.client
+                        self.stream_source
                             .as_mut()
                             .unwrap()
                             .new_async_capture_stream(
@@ -391,7 +572,7 @@ impl<'a> StreamInfo<'a> {
         }
         self.state = VIRTIO_SND_R_PCM_RELEASE;
         self.release_worker().await?;
-        self.client = None;
+        self.stream_source = None;
         Ok(())
     }
 
@@ -418,11 +599,18 @@ pub struct VirtioSndCras {
     worker_threads: Vec<thread::JoinHandle<()>>,
     kill_evt: Option<Event>,
     params: Parameters,
+    stream_source_generator: StreamSourceGenerator,
+    control_tube: Option<Tube>,
 }
 
 impl VirtioSndCras {
-    pub fn new(base_features: u64, params: Parameters) -> Result<VirtioSndCras, Error> {
-        let cfg = hardcoded_virtio_snd_config();
+    pub fn new(
+        base_features: u64,
+        params: Parameters,
+        control_tube: Option<Tube>,
+    ) -> Result<VirtioSndCras, Error> {
+        let cfg = hardcoded_virtio_snd_config(&params);
+        let stream_source_generator = StreamSourceGenerator::new(&params)?;
 
         let avail_features = base_features;
 
@@ -434,82 +622,105 @@ impl VirtioSndCras {
             worker_threads: Vec::new(),
             kill_evt: None,
             params,
+            stream_source_generator,
+            control_tube,
         })
     }
+
+    /// Returns the name of the seccomp policy of the device for its backend.
+    pub fn minijail_policy(&self) -> &'static str {
+        match self.params.backend {
+            #[cfg(feature = "audio_cras")]
+            StreamSourceBackend::CRAS => "cras_snd_device",
+            StreamSourceBackend::NULL => "null_snd_device",
+            StreamSourceBackend::WAV => "wav_snd_device",
+            #[cfg(feature = "audio_pulse")]
+            StreamSourceBackend::PULSE => "pulse_snd_device",
+        }
+    }
 }
 
 // To be used with hardcoded_snd_data
-pub fn hardcoded_virtio_snd_config() -> virtio_snd_config {
+pub fn hardcoded_virtio_snd_config(params: &Parameters) -> virtio_snd_config {
+    // Without capture there is no input stream, microphone jack or input channel maps.
+    let directions = if params.capture { 2 } else { 1 };
     virtio_snd_config {
-        jacks: 0.into(),
-        streams: 2.into(),
-        chmaps: 2.into(),
+        jacks: directions.into(),
+        streams: directions.into(),
+        chmaps: (2 * directions).into(),
     }
 }
 
-// To be used with hardcoded_virtio_snd_config
-// TODO(woodychow): Remove this once we can query config from CRAS
-fn hardcoded_snd_data() -> SndData {
-    let jack_info: Vec<virtio_snd_jack_info> = Vec::new();
-    let mut pcm_info: Vec<virtio_snd_pcm_info> = Vec::new();
-    let mut chmap_info: Vec<virtio_snd_chmap_info> = Vec::new();
-
-    // for _ in 0..(Into::<u32>::into(self.cfg.streams) as usize) {
-    // TODO(woodychow): Remove this hack
-    // Assume this single device for now
-    pcm_info.push(virtio_snd_pcm_info {
+fn new_jack_info(hda_reg_defconf: u32, hda_reg_caps: u32) -> virtio_snd_jack_info {
+    virtio_snd_jack_info {
         hdr: virtio_snd_info {
             hda_fn_nid: 0.into(),
         },
-        features: 0.into(), /* 1 << VIRTIO_SND_PCM_F_XXX */
-        formats: SUPPORTED_FORMATS.into(),
-        rates: SUPPORTED_FRAME_RATES.into(),
-        direction: VIRTIO_SND_D_OUTPUT,
-        channels_min: 1,
-        channels_max: 2,
-        padding: [0; 5],
-    });
-    pcm_info.push(virtio_snd_pcm_info {
+        features: (1 << VIRTIO_SND_JACK_F_REMAP).into(),
+        hda_reg_defconf: hda_reg_defconf.into(),
+        hda_reg_caps: (HDA_PINCAP_PRESENCE_DETECT | hda_reg_caps).into(),
+        connected: 1,
+        padding: [0; 7],
+    }
+}
+
+fn new_pcm_info(direction: u8) -> virtio_snd_pcm_info {
+    virtio_snd_pcm_info {
         hdr: virtio_snd_info {
             hda_fn_nid: 0.into(),
         },
         features: 0.into(), /* 1 << VIRTIO_SND_PCM_F_XXX */
         formats: SUPPORTED_FORMATS.into(),
         rates: SUPPORTED_FRAME_RATES.into(),
-        direction: VIRTIO_SND_D_INPUT,
+        direction,
         channels_min: 1,
         channels_max: 2,
         padding: [0; 5],
-    });
-    // }
-
-    // for _ in 0..(Into::<u32>::into(self.cfg.chmaps) as usize) {
+    }
+}
 
-    // Use stereo channel map.
+fn new_chmap_info(direction: u8, channel_positions: &[u8]) -> virtio_snd_chmap_info {
     let mut positions = [VIRTIO_SND_CHMAP_NONE; VIRTIO_SND_CHMAP_MAX_SIZE];
-    positions[0] = VIRTIO_SND_CHMAP_FL;
-    positions[1] = VIRTIO_SND_CHMAP_FR;
-
-    chmap_info.push(virtio_snd_chmap_info {
-        hdr: virtio_snd_info {
-            hda_fn_nid: 0.into(),
-        },
-        direction: VIRTIO_SND_D_OUTPUT,
-        channels: 2,
-        positions,
-    });
-    chmap_info.push(virtio_snd_chmap_info {
+    positions[..channel_positions.len()].copy_from_slice(channel_positions);
+    virtio_snd_chmap_info {
         hdr: virtio_snd_info {
             hda_fn_nid: 0.into(),
         },
-        direction: VIRTIO_SND_D_INPUT,
-        channels: 2,
+        direction,
+        channels: channel_positions.len() as u8,
         positions,
-    });
-    // }
+    }
+}
+
+// To be used with hardcoded_virtio_snd_config
+// TODO(woodychow): Remove this once we can query config from CRAS
+fn hardcoded_snd_data(params: &Parameters) -> SndData {
+    let mut jack_info = vec![new_jack_info(
+        HDA_DEFCONF_HEADPHONE,
+        HDA_PINCAP_OUTPUT | HDA_PINCAP_HEADPHONE_DRIVE,
+    )];
+    let mut pcm_info = vec![new_pcm_info(VIRTIO_SND_D_OUTPUT)];
+    // One channel map for each channel count a stream supports.
+    let mut chmap_info = vec![
+        new_chmap_info(VIRTIO_SND_D_OUTPUT, &[VIRTIO_SND_CHMAP_MONO]),
+        new_chmap_info(
+            VIRTIO_SND_D_OUTPUT,
+            &[VIRTIO_SND_CHMAP_FL, VIRTIO_SND_CHMAP_FR],
+        ),
+    ];
+
+    if params.capture {
+        jack_info.push(new_jack_info(HDA_DEFCONF_MIC, HDA_PINCAP_INPUT));
+        pcm_info.push(new_pcm_info(VIRTIO_SND_D_INPUT));
+        chmap_info.push(new_chmap_info(VIRTIO_SND_D_INPUT, &[VIRTIO_SND_CHMAP_MONO]));
+        chmap_info.push(new_chmap_info(
+            VIRTIO_SND_D_INPUT,
+            &[VIRTIO_SND_CHMAP_FL, VIRTIO_SND_CHMAP_FR],
+        ));
+    }
 
     SndData {
-        jack_info,
+        jack_info: AsyncMutex::new(jack_info),
         pcm_info,
         chmap_info,
     }
@@ -517,7 +728,11 @@ fn hardcoded_snd_data() -> SndData {
 
 impl VirtioDevice for VirtioSndCras {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        Vec::new()
+        let mut keep_rds = self.stream_source_generator.keep_rds();
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
+        keep_rds
     }
 
     fn device_type(&self) -> u32 {
@@ -574,6 +789,8 @@ impl VirtioDevice for VirtioSndCras {
         self.kill_evt = Some(self_kill_evt);
 
         let params = self.params.clone();
+        let stream_source_generator = self.stream_source_generator.clone();
+        let control_tube = self.control_tube.take();
 
         let worker_result = thread::Builder::new()
             .name("virtio_snd w".to_string())
@@ -588,10 +805,11 @@ impl VirtioDevice for VirtioSndCras {
                     interrupt,
                     queues,
                     guest_mem,
-                    hardcoded_snd_data(),
+                    hardcoded_snd_data(&params),
                     queue_evts,
                     kill_evt,
-                    params,
+                    control_tube,
+                    stream_source_generator,
                 ) {
                     error!("{}", err_string);
                 }
@@ -629,7 +847,8 @@ fn run_worker(
     snd_data: SndData,
     queue_evts: Vec<Event>,
     kill_evt: Event,
-    params: Parameters,
+    control_tube: Option<Tube>,
+    stream_source_generator: StreamSourceGenerator,
 ) -> Result<(), String> {
     let ex = Executor::new().expect("Failed to create an executor");
 
@@ -640,7 +859,7 @@ fn run_worker(
     let interrupt = Rc::new(interrupt);
 
     let ctrl_queue = queues.remove(0);
-    let _event_queue = queues.remove(0);
+    let event_queue = queues.remove(0);
     let tx_queue = Rc::new(AsyncMutex::new(queues.remove(0)));
     let rx_queue = Rc::new(AsyncMutex::new(queues.remove(0)));
 
@@ -650,7 +869,7 @@ fn run_worker(
         .collect();
 
     let ctrl_queue_evt = evts_async.remove(0);
-    let _event_queue_evt = evts_async.remove(0);
+    let event_queue_evt = evts_async.remove(0);
     let tx_queue_evt = evts_async.remove(0);
     let rx_queue_evt = evts_async.remove(0);
 
@@ -669,23 +888,44 @@ fn run_worker(
         interrupt.as_ref(),
         tx_send,
         rx_send,
-        &params,
+        &stream_source_generator,
     );
 
-    // TODO(woodychow): Enable this when libcras sends jack connect/disconnect evts
-    // let f_event = handle_event_queue(
-    //     &mem,
-    //     snd_state,
-    //     event_queue,
-    //     event_queue_evt,
-    //     interrupt,
-    // );
+    // TODO(woodychow): Also forward jack events from libcras once it sends them.
+    let (event_send, mut event_recv) = mpsc::unbounded();
+    let f_event = handle_event_queue(
+        &mem,
+        event_queue,
+        event_queue_evt,
+        interrupt.as_ref(),
+        &mut event_recv,
+    );
 
-    let f_tx = handle_pcm_queue(&mem, &streams, tx_send2, &tx_queue, tx_queue_evt);
+    let control_tube = control_tube
+        .map(|t| t.into_async_tube(&ex))
+        .transpose()
+        .map_err(|e| Error::CreateAsyncTube(e).to_string())?;
+    let f_control = handle_control_tube(control_tube, &snd_data, event_send);
+
+    let f_tx = handle_pcm_queue(
+        &mem,
+        &streams,
+        VIRTIO_SND_D_OUTPUT,
+        tx_send2,
+        &tx_queue,
+        tx_queue_evt,
+    );
 
     let f_tx_response = send_pcm_response_worker(&mem, &tx_queue, interrupt.as_ref(), &mut tx_recv);
 
-    let f_rx = handle_pcm_queue(&mem, &streams, rx_send2, &rx_queue, rx_queue_evt);
+    let f_rx = handle_pcm_queue(
+        &mem,
+        &streams,
+        VIRTIO_SND_D_INPUT,
+        rx_send2,
+        &rx_queue,
+        rx_queue_evt,
+    );
 
     let f_rx_response = send_pcm_response_worker(&mem, &rx_queue, interrupt.as_ref(), &mut rx_recv);
 
@@ -693,20 +933,37 @@ fn run_worker(
     let kill_evt = EventAsync::new(kill_evt.0, &ex).expect("failed to set up the kill event");
     let f_kill = wait_kill(kill_evt);
 
-    pin_mut!(f_ctrl, f_tx, f_tx_response, f_rx, f_rx_response, f_kill);
+    pin_mut!(
+        f_ctrl,
+        f_event,
+        f_control,
+        f_tx,
+        f_tx_response,
+        f_rx,
+        f_rx_response,
+        f_kill
+    );
 
-    match ex.run_until(select6(
+    match ex.run_until(select8(
         f_ctrl,
+        f_event,
+        f_control,
         f_tx,
         f_tx_response,
         f_rx,
         f_rx_response,
         f_kill,
     )) {
-        Ok((r_ctrl, r_tx, r_tx_response, r_rx, r_rx_response, _r_kill)) => {
+        Ok((r_ctrl, r_event, r_control, r_tx, r_tx_response, r_rx, r_rx_response, _r_kill)) => {
             if let SelectResult::Finished(Err(e)) = r_ctrl {
                 return Err(format!("Error in handling ctrl queue: {}", e));
             }
+            if let SelectResult::Finished(Err(e)) = r_event {
+                return Err(format!("Error in handling event queue: {}", e));
+            }
+            if let SelectResult::Finished(Err(e)) = r_control {
+                return Err(format!("Error in handling control tube: {}", e));
+            }
             if let SelectResult::Finished(Err(e)) = r_tx {
                 return Err(format!("Error in handling tx queue: {}", e));
             }
@@ -730,6 +987,7 @@ fn run_worker(
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "audio_cras")]
     #[test]
     fn parameters_fromstr() {
         fn check_success(
@@ -780,4 +1038,89 @@ mod tests {
             CrasSocketType::Unified,
         );
     }
+
+    #[test]
+    fn parameters_backend_fromstr() {
+        let params = "backend=wav,playback_file=/tmp/playback.wav"
+            .parse::<Parameters>()
+            .expect("parse should have succeded");
+        assert_eq!(params.backend, StreamSourceBackend::WAV);
+        assert_eq!(
+            params.playback_file,
+            Some(PathBuf::from("/tmp/playback.wav"))
+        );
+        // The wav backend only captures from a file.
+        assert!(!params.capture);
+
+        let params = "backend=wav,playback_file=/tmp/playback.wav,capture_file=/tmp/capture.wav"
+            .parse::<Parameters>()
+            .expect("parse should have succeded");
+        assert_eq!(params.capture_file, Some(PathBuf::from("/tmp/capture.wav")));
+        assert!(params.capture);
+
+        let params = "backend=null,capture=false"
+            .parse::<Parameters>()
+            .expect("parse should have succeded");
+        assert_eq!(params.backend, StreamSourceBackend::NULL);
+        assert!(!params.capture);
+
+        for s in &[
+            "backend=none",
+            "backend=wav",
+            "backend=wav,capture_file=/tmp/capture.wav",
+            "backend=null,playback_file=/tmp/playback.wav",
+            "backend=null,capture_file=/tmp/capture.wav",
+        ] {
+            s.parse::<Parameters>()
+                .expect_err("parse should have failed");
+        }
+    }
+
+    #[test]
+    fn wav_stream_source_keeps_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let params = Parameters {
+            backend: StreamSourceBackend::WAV,
+            playback_file: Some(dir.path().join("playback.wav")),
+            ..Default::default()
+        };
+        let generator = StreamSourceGenerator::new(&params).expect("failed to open WAV file");
+        assert_eq!(generator.keep_rds().len(), 1);
+        assert!(dir.path().join("playback.wav").exists());
+
+        let params = Parameters {
+            capture_file: Some(dir.path().join("missing.wav")),
+            ..params
+        };
+        assert!(StreamSourceGenerator::new(&params).is_err());
+    }
+
+    #[test]
+    fn snd_data_matches_config() {
+        for &capture in &[false, true] {
+            let params = Parameters {
+                capture,
+                ..Default::default()
+            };
+            let cfg = hardcoded_virtio_snd_config(&params);
+            let snd_data = hardcoded_snd_data(&params);
+
+            assert_eq!(
+                snd_data.jack_info.into_inner().len(),
+                u32::from(cfg.jacks) as usize
+            );
+            assert_eq!(snd_data.pcm_info.len(), u32::from(cfg.streams) as usize);
+            assert_eq!(snd_data.chmap_info.len(), u32::from(cfg.chmaps) as usize);
+
+            // Every channel count a stream accepts has a channel map.
+            for pcm_info in &snd_data.pcm_info {
+                for channels in pcm_info.channels_min..=pcm_info.channels_max {
+                    assert!(snd_data
+                        .chmap_info
+                        .iter()
+                        .any(|c| c.direction == pcm_info.direction && c.channels == channels));
+                }
+            }
+        }
+    }
 }
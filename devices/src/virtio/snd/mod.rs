@@ -6,7 +6,7 @@ pub mod common;
 pub mod constants;
 pub mod layout;
 
-#[cfg(feature = "audio_cras")]
+#[cfg(feature = "audio")]
 pub mod cras_backend;
 
 pub mod vios_backend;
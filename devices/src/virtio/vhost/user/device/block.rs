@@ -26,6 +26,7 @@ use crate::virtio::block::*;
 use crate::virtio::vhost::user::device::handler::{
     CallEvent, DeviceRequestHandler, VhostUserBackend,
 };
+use crate::virtio::vhost::user::device::vvu::{sysfs_path, VvuDevice};
 use crate::virtio::{self, base_features, copy_config, Queue};
 use crate::ProtectionType;
 
@@ -182,7 +183,9 @@ impl VhostUserBackend for BlockBackend {
     }
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
-        VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::MQ
+        VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD
     }
 
     fn ack_protocol_features(&mut self, features: u64) -> anyhow::Result<()> {
@@ -315,6 +318,12 @@ pub fn run_block_device(program_name: &str, args: std::env::Args) -> anyhow::Res
     );
     opts.optflag("h", "help", "print this help menu");
     opts.optopt("", "socket", "path to a socket", "PATH");
+    opts.optopt(
+        "",
+        "vfio",
+        "PCI address of a virtio-vhost-user device bound to vfio-pci",
+        "ADDRESS",
+    );
 
     let matches = match opts.parse(args) {
         Ok(m) => m,
@@ -332,8 +341,8 @@ pub fn run_block_device(program_name: &str, args: std::env::Args) -> anyhow::Res
         bail!("Must specify the file for the block device.");
     }
 
-    if !matches.opt_present("socket") {
-        bail!("Must specify the socket for the vhost user device.");
+    if matches.opt_present("socket") == matches.opt_present("vfio") {
+        bail!("Must specify either the socket or the vfio device for the vhost user device.");
     }
 
     let ex = Executor::new().context("failed to create executor")?;
@@ -341,15 +350,23 @@ pub fn run_block_device(program_name: &str, args: std::env::Args) -> anyhow::Res
         .set(ex.clone())
         .map_err(|_| anyhow!("failed to set executor"))?;
 
-    // We can unwrap after `opt_str()` safely because they are required options.
-    let socket = matches.opt_str("socket").unwrap();
+    // We can unwrap after `opt_str()` safely because it is a required option.
     let filearg = matches.opt_str("file").unwrap();
     let fileopts = filearg.split(':').collect::<Vec<&str>>();
     let filename = fileopts.get(0).context("Must specify the filename")?;
     let block = BlockBackend::new(BLOCK_EXECUTOR.clone(), filename, fileopts[1..].to_vec())?;
     let handler = DeviceRequestHandler::new(block);
 
-    if let Err(e) = ex.run_until(handler.run(socket, &ex)) {
+    let result = match (matches.opt_str("socket"), matches.opt_str("vfio")) {
+        (Some(socket), _) => ex.run_until(handler.run(socket, &ex)),
+        (None, Some(address)) => {
+            let device = VvuDevice::new(&sysfs_path(&address))
+                .context("failed to initialize the virtio-vhost-user device")?;
+            ex.run_until(handler.run_vvu(device, &ex))
+        }
+        (None, None) => unreachable!(),
+    };
+    if let Err(e) = result {
         bail!("error occurred: {}", e);
     }
 
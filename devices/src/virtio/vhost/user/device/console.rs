@@ -27,6 +27,7 @@ use crate::virtio::console::{
 use crate::virtio::vhost::user::device::handler::{
     CallEvent, DeviceRequestHandler, VhostUserBackend,
 };
+use crate::virtio::vhost::user::device::vvu::{sysfs_path, VvuDevice};
 use crate::virtio::{self, copy_config};
 use crate::ProtectionType;
 
@@ -248,7 +249,11 @@ impl VhostUserBackend for ConsoleBackend {
     }
 }
 
-fn run_console(params: &SerialParameters, socket: &str) -> anyhow::Result<()> {
+fn run_console(
+    params: &SerialParameters,
+    socket: Option<&str>,
+    vfio: Option<&str>,
+) -> anyhow::Result<()> {
     // We need to pass an event as per Serial Device API but we don't really use it anyway.
     let evt = Event::new()?;
     // Same for keep_rds, we don't really use this.
@@ -267,7 +272,16 @@ fn run_console(params: &SerialParameters, socket: &str) -> anyhow::Result<()> {
 
     let _ = CONSOLE_EXECUTOR.set(ex.clone());
 
-    if let Err(e) = ex.run_until(handler.run(socket, &ex)) {
+    let result = match (socket, vfio) {
+        (Some(socket), _) => ex.run_until(handler.run(socket, &ex)),
+        (None, Some(address)) => {
+            let device = VvuDevice::new(&sysfs_path(address))
+                .context("failed to initialize the virtio-vhost-user device")?;
+            ex.run_until(handler.run_vvu(device, &ex))
+        }
+        (None, None) => bail!("Must specify either the socket or the vfio device"),
+    };
+    if let Err(e) = result {
         bail!(e);
     }
     Ok(())
@@ -279,6 +293,12 @@ pub fn run_console_device(program_name: &str, args: std::env::Args) -> anyhow::R
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
     opts.optopt("", "socket", "path to a socket", "PATH");
+    opts.optopt(
+        "",
+        "vfio",
+        "PCI address of a virtio-vhost-user device bound to vfio-pci",
+        "ADDRESS",
+    );
     opts.optopt("", "output-file", "path to a file", "OUTFILE");
     opts.optopt("", "input-file", "path to a file", "INFILE");
 
@@ -294,12 +314,12 @@ pub fn run_console_device(program_name: &str, args: std::env::Args) -> anyhow::R
         return Ok(());
     }
 
-    if !matches.opt_present("socket") {
-        bail!("Must specify the socket for the vhost user device.");
+    if matches.opt_present("socket") == matches.opt_present("vfio") {
+        bail!("Must specify either the socket or the vfio device for the vhost user device.");
     }
 
-    // We can unwrap after `opt_str()` safely because we just checked for it being present.
-    let socket = matches.opt_str("socket").unwrap();
+    let socket = matches.opt_str("socket");
+    let vfio = matches.opt_str("vfio");
 
     let output_file = matches.opt_str("output-file").map(PathBuf::from);
     let input_file = matches.opt_str("input-file").map(PathBuf::from);
@@ -327,7 +347,7 @@ pub fn run_console_device(program_name: &str, args: std::env::Args) -> anyhow::R
         stdin: true,
     };
 
-    if let Err(e) = run_console(&params, &socket) {
+    if let Err(e) = run_console(&params, socket.as_deref(), vfio.as_deref()) {
         bail!("error occurred: {}", e);
     }
 
@@ -24,7 +24,7 @@ use vmm_vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioF
 use crate::virtio;
 use crate::virtio::copy_config;
 use crate::virtio::fs::passthrough::PassthroughFs;
-use crate::virtio::fs::{process_fs_queue, virtio_fs_config, FS_MAX_TAG_LEN};
+use crate::virtio::fs::{process_fs_queue, virtio_fs_config, BlockingRequests, FS_MAX_TAG_LEN};
 use crate::virtio::vhost::user::device::handler::{
     CallEvent, DeviceRequestHandler, VhostUserBackend,
 };
@@ -33,22 +33,37 @@ use crate::ProtectionType;
 static FS_EXECUTOR: OnceCell<Executor> = OnceCell::new();
 
 async fn handle_fs_queue(
-    mut queue: virtio::Queue,
+    queue: virtio::Queue,
     mem: GuestMemory,
     call_evt: Arc<Mutex<CallEvent>>,
     kick_evt: EventAsync,
     server: Arc<fuse::Server<PassthroughFs>>,
     tube: Arc<Mutex<Tube>>,
+    blocking: Arc<Mutex<BlockingRequests>>,
 ) {
     // Slot is always going to be 0 because we do not support DAX
     let slot: u32 = 0;
 
+    // Blocking requests complete on other threads, which need their own references to the queue
+    // and the call event.
+    let queue = Arc::new(Mutex::new(queue));
+    let interrupt = Arc::new(call_evt);
+
     loop {
         if let Err(e) = kick_evt.next_val().await {
             error!("Failed to read kick event for fs queue: {}", e);
             break;
         }
-        if let Err(e) = process_fs_queue(&mem, &call_evt, &mut queue, &server, &tube, slot) {
+        if let Err(e) = process_fs_queue(
+            &mem,
+            &interrupt,
+            &queue,
+            &server,
+            &tube,
+            slot,
+            None,
+            &mut blocking.lock(),
+        ) {
             error!("Process FS queue failed: {}", e);
             break;
         }
@@ -120,6 +135,8 @@ struct FsBackend {
     acked_features: u64,
     acked_protocol_features: VhostUserProtocolFeatures,
     workers: [Option<AbortHandle>; Self::MAX_QUEUE_NUM],
+    // The requests of all queues that may block, which are abandoned on reset.
+    blocking: Arc<Mutex<BlockingRequests>>,
     keep_rds: Vec<RawDescriptor>,
 }
 
@@ -153,6 +170,7 @@ impl FsBackend {
             acked_features: 0,
             acked_protocol_features: VhostUserProtocolFeatures::empty(),
             workers: Default::default(),
+            blocking: Arc::new(Mutex::new(BlockingRequests::new())),
             keep_rds,
         })
     }
@@ -204,6 +222,8 @@ impl VhostUserBackend for FsBackend {
         let config = virtio_fs_config {
             tag: self.tag,
             num_request_queues: Le32::from(1),
+            // There is no notification queue.
+            notify_buf_size: Le32::from(0),
         };
         copy_config(data, 0, config.as_slice(), offset);
     }
@@ -212,6 +232,8 @@ impl VhostUserBackend for FsBackend {
         for handle in self.workers.iter_mut().filter_map(Option::take) {
             handle.abort();
         }
+        self.server.reset();
+        self.blocking.lock().join_all();
     }
 
     fn start_queue(
@@ -246,6 +268,7 @@ impl VhostUserBackend for FsBackend {
                 kick_evt,
                 self.server.clone(),
                 Arc::new(Mutex::new(fs_device_tube)),
+                Arc::clone(&self.blocking),
             ),
             registration,
         ))
@@ -495,6 +495,7 @@ pub fn run_gpu_device(program_name: &str, args: std::env::Args) -> anyhow::Resul
     let gpu = Rc::new(RefCell::new(Gpu::new(
         exit_evt,
         gpu_device_tube,
+        None,       // gpu_control_tube, captures are not supported by the vhost-user device
         Vec::new(), // resource_bridges, handled separately by us
         display_backends,
         &gpu_parameters,
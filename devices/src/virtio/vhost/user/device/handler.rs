@@ -50,7 +50,7 @@ use std::fs::File;
 use std::io;
 use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -59,6 +59,8 @@ use base::{
     SharedMemoryUnix, UnlinkUnixListener,
 };
 use cros_async::{AsyncError, AsyncWrapper, Executor};
+use futures::future::{select, Either};
+use futures::pin_mut;
 use remain::sorted;
 use sync::Mutex;
 use sys_util::clear_fd_flags;
@@ -76,7 +78,8 @@ use vmm_vhost::vhost_user::{
     Error as VhostError, Result as VhostResult, VhostUserSlaveReqHandlerMut,
 };
 
-use crate::virtio::{Queue, SignalableInterrupt};
+use crate::virtio::vhost::user::device::vvu::{Error as VvuError, VvuDevice};
+use crate::virtio::{InflightRegion, Queue, SignalableInterrupt};
 
 /// An event to deliver an interrupt to the guest.
 ///
@@ -262,9 +265,15 @@ pub enum HandlerError {
     /// Failed to create a UNIX domain socket listener.
     #[error("failed to create a UNIX domain socket listener: {0}")]
     CreateSocketListener(io::Error),
+    /// Failed to create a UNIX domain socket pair.
+    #[error("failed to create a UNIX domain socket pair: {0}")]
+    CreateSocketPair(io::Error),
     /// Failed to handle a vhost-user request.
     #[error("failed to handle a vhost-user request: {0}")]
     HandleVhostUserRequest(VhostError),
+    /// The virtio-vhost-user device failed.
+    #[error("virtio-vhost-user device failed: {0}")]
+    Vvu(VvuError),
     /// Failed to wait for the handler socket to become readable.
     #[error("failed to wait for the handler socket to become readable: {0}")]
     WaitForHandler(AsyncError),
@@ -281,6 +290,7 @@ where
     owned: bool,
     vmm_maps: Option<Vec<MappingInfo>>,
     mem: Option<GuestMemory>,
+    inflight: Option<Arc<InflightRegion>>,
     backend: B,
 }
 
@@ -300,6 +310,7 @@ where
             owned: false,
             vmm_maps: None,
             mem: None,
+            inflight: None,
             backend,
         }
     }
@@ -340,6 +351,45 @@ where
                 .map_err(HandlerError::HandleVhostUserRequest)?;
         }
     }
+
+    /// Handles messages from a VMM whose vhost-user frontend is connected to `device`, a
+    /// virtio-vhost-user device of the VM this process runs in.
+    pub async fn run_vvu(self, device: VvuDevice, ex: &Executor) -> HandlerResult<()> {
+        // The device is relayed to the request handler through a socket pair, so that the handler
+        // deals with it the same way as with a frontend connected over a socket.
+        let (frontend, backend) = UnixStream::pair().map_err(HandlerError::CreateSocketPair)?;
+        let mut req_handler =
+            SlaveReqHandler::from_stream(backend, Arc::new(std::sync::Mutex::new(self)));
+        let h = SafeDescriptor::try_from(&req_handler as &dyn AsRawFd)
+            .map(AsyncWrapper::new)
+            .expect("failed to get safe descriptor for handler");
+        let handler_source = ex.async_from(h).map_err(HandlerError::CreateAsyncSource)?;
+
+        let handle_requests = async {
+            loop {
+                if let Err(e) = handler_source.wait_readable().await {
+                    break HandlerError::WaitForHandler(e);
+                }
+                if let Err(e) = req_handler.handle_request() {
+                    break HandlerError::HandleVhostUserRequest(e);
+                }
+            }
+        };
+        pin_mut!(handle_requests);
+        let relay = device.run(frontend, ex);
+        pin_mut!(relay);
+
+        match select(handle_requests, relay).await {
+            Either::Left((e, _)) => Err(e),
+            Either::Right((r, _)) => r.map_err(HandlerError::Vvu),
+        }
+    }
+
+    // Whether the frontend agreed to keep a region tracking the in-flight descriptors.
+    fn inflight_negotiated(&self) -> bool {
+        VhostUserProtocolFeatures::from_bits_truncate(self.backend.acked_protocol_features())
+            .contains(VhostUserProtocolFeatures::INFLIGHT_SHMFD)
+    }
 }
 
 impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for DeviceRequestHandler<B> {
@@ -353,6 +403,7 @@ impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for DeviceRequestHandler<B
 
     fn reset_owner(&mut self) -> VhostResult<()> {
         self.owned = false;
+        self.inflight = None;
         self.backend.reset();
         Ok(())
     }
@@ -513,7 +564,7 @@ impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for DeviceRequestHandler<B
             let vring = &mut self.vrings[index as usize];
             vring.queue.ready = true;
 
-            let queue = vring.queue.clone();
+            let mut queue = vring.queue.clone();
             let call_evt = vring
                 .call_evt
                 .as_ref()
@@ -524,6 +575,16 @@ impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for DeviceRequestHandler<B
                 .cloned()
                 .ok_or(VhostError::InvalidOperation)?;
 
+            if let Some(inflight) = &self.inflight {
+                let inflight = inflight
+                    .queue(index as usize)
+                    .ok_or(VhostError::InvalidParam)?;
+                queue.set_inflight(&mem, inflight).map_err(|e| {
+                    error!("failed to read the used ring of queue {}: {}", index, e);
+                    VhostError::InvalidParam
+                })?;
+            }
+
             if let Err(e) =
                 self.backend
                     .start_queue(index as usize, queue, mem, Arc::clone(call_evt), kick_evt)
@@ -618,13 +679,51 @@ impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for DeviceRequestHandler<B
 
     fn get_inflight_fd(
         &mut self,
-        _inflight: &VhostUserInflight,
+        inflight: &VhostUserInflight,
     ) -> VhostResult<(VhostUserInflight, File)> {
-        unimplemented!("get_inflight_fd");
+        if !self.inflight_negotiated() {
+            return Err(VhostError::InvalidOperation);
+        }
+        if inflight.num_queues as usize > self.vrings.len()
+            || inflight.queue_size > B::MAX_VRING_LEN
+        {
+            return Err(VhostError::InvalidParam);
+        }
+
+        let (file, mmap_size) = InflightRegion::allocate(inflight.num_queues, inflight.queue_size)
+            .map_err(|e| {
+                error!("failed to allocate an in-flight region: {}", e);
+                VhostError::InvalidParam
+            })?;
+        let inflight = VhostUserInflight {
+            mmap_size,
+            mmap_offset: 0,
+            num_queues: inflight.num_queues,
+            queue_size: inflight.queue_size,
+        };
+        Ok((inflight, file))
     }
 
-    fn set_inflight_fd(&mut self, _inflight: &VhostUserInflight, _file: File) -> VhostResult<()> {
-        unimplemented!("set_inflight_fd");
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, file: File) -> VhostResult<()> {
+        if !self.inflight_negotiated() {
+            return Err(VhostError::InvalidOperation);
+        }
+
+        // The region may have been filled in by a previous instance of this device. What it
+        // left in flight is resubmitted as each queue is started.
+        let region = InflightRegion::new(
+            &file,
+            inflight.mmap_size,
+            inflight.mmap_offset,
+            inflight.num_queues,
+            inflight.queue_size,
+        )
+        .map_err(|e| {
+            error!("failed to map the in-flight region: {}", e);
+            VhostError::InvalidParam
+        })?;
+        self.inflight = Some(Arc::new(region));
+        Ok(())
     }
 
     fn get_max_mem_slots(&mut self) -> VhostResult<u64> {
@@ -651,13 +750,18 @@ impl<B: VhostUserBackend> VhostUserSlaveReqHandlerMut for DeviceRequestHandler<B
 mod tests {
     use super::*;
 
+    use std::sync::atomic::AtomicUsize;
     use std::sync::mpsc::channel;
     use std::sync::Barrier;
 
+    use base::Tube;
     use data_model::DataInit;
     use tempfile::{Builder, TempDir};
+    use vmm_vhost::vhost_user::{Listener, SlaveListener};
 
+    use crate::pci::MsixConfig;
     use crate::virtio::vhost::user::vmm::VhostUserHandler;
+    use crate::virtio::Interrupt;
 
     #[sorted]
     #[derive(ThisError, Debug)]
@@ -679,10 +783,14 @@ mod tests {
 
     const FAKE_CONFIG_DATA: FakeConfig = FakeConfig { x: 1, y: 2 };
 
+    // The queues the backend was asked to start, with the guest memory they live in.
+    type StartedQueues = Arc<std::sync::Mutex<Vec<(Queue, GuestMemory)>>>;
+
     struct FakeBackend {
         avail_features: u64,
         acked_features: u64,
         acked_protocol_features: VhostUserProtocolFeatures,
+        started_queues: StartedQueues,
     }
 
     impl FakeBackend {
@@ -691,6 +799,7 @@ mod tests {
                 avail_features: VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
                 acked_features: 0,
                 acked_protocol_features: VhostUserProtocolFeatures::empty(),
+                started_queues: Default::default(),
             }
         }
     }
@@ -722,7 +831,7 @@ mod tests {
         }
 
         fn protocol_features(&self) -> VhostUserProtocolFeatures {
-            VhostUserProtocolFeatures::CONFIG
+            VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::INFLIGHT_SHMFD
         }
 
         fn ack_protocol_features(&mut self, features: u64) -> std::result::Result<(), Self::Error> {
@@ -746,11 +855,12 @@ mod tests {
         fn start_queue(
             &mut self,
             _idx: usize,
-            _queue: Queue,
-            _mem: GuestMemory,
+            queue: Queue,
+            mem: GuestMemory,
             _doorbell: Arc<Mutex<Self::Doorbell>>,
             _kick_evt: Event,
         ) -> std::result::Result<(), Self::Error> {
+            self.started_queues.lock().unwrap().push((queue, mem));
             Ok(())
         }
 
@@ -763,8 +873,6 @@ mod tests {
 
     #[test]
     fn test_vhost_user_activate() {
-        use vmm_vhost::vhost_user::{Listener, SlaveListener};
-
         const QUEUES_NUM: usize = 2;
 
         let dir = temp_dir();
@@ -811,7 +919,7 @@ mod tests {
                 let irqfd = Event::new().unwrap();
 
                 vmm_handler
-                    .activate_vring(&mem, idx, &queue, &queue_evt, &irqfd)
+                    .activate_vring(&mem, idx, &queue, &queue_evt, &irqfd, 0)
                     .unwrap();
             }
 
@@ -854,4 +962,104 @@ mod tests {
 
         dev_bar.wait();
     }
+
+    // Serves one connection on `listener` with a fresh backend until it starts its queue, then
+    // hangs up as if the backend process had died.
+    fn run_device(listener: Listener) -> std::thread::JoinHandle<(Queue, GuestMemory)> {
+        let backend = FakeBackend::new();
+        let started_queues = Arc::clone(&backend.started_queues);
+        let handler = Arc::new(std::sync::Mutex::new(DeviceRequestHandler::new(backend)));
+        let mut listener = SlaveListener::new(listener, handler).unwrap();
+        std::thread::spawn(move || {
+            let mut req_handler = listener.accept().unwrap().unwrap();
+            while started_queues.lock().unwrap().is_empty() {
+                req_handler.handle_request().unwrap();
+            }
+            req_handler.handle_request().expect("set_vring_enable");
+            let mut started_queues = started_queues.lock().unwrap();
+            started_queues.pop().unwrap()
+        })
+    }
+
+    #[test]
+    fn test_vhost_user_resubmit_inflight() {
+        const QUEUE_SIZE: u16 = 16;
+        const NUM_REQUESTS: u16 = 3;
+
+        let dir = temp_dir();
+        let path = dir.path().join("sock");
+
+        let device = run_device(Listener::new(&path, true).unwrap());
+
+        let features = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let mut vmm_handler = VhostUserHandler::new_reconnectable(
+            &path,
+            1,
+            features,
+            features,
+            VhostUserProtocolFeatures::INFLIGHT_SHMFD,
+        )
+        .unwrap();
+
+        // The guest offers three requests of one descriptor each.
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.desc_table = GuestAddress(0x1000);
+        queue.avail_ring = GuestAddress(0x2000);
+        queue.used_ring = GuestAddress(0x3000);
+        for i in 0..NUM_REQUESTS {
+            let desc = queue.desc_table.unchecked_add(u64::from(i) * 16);
+            mem.write_obj_at_addr(0x8000u64 + u64::from(i) * 0x100, desc)
+                .unwrap();
+            mem.write_obj_at_addr(0x100u32, desc.unchecked_add(8))
+                .unwrap();
+            mem.write_obj_at_addr(i, queue.avail_ring.unchecked_add(4 + u64::from(i) * 2))
+                .unwrap();
+        }
+        mem.write_obj_at_addr(NUM_REQUESTS, queue.avail_ring.unchecked_add(2))
+            .unwrap();
+
+        let queue_evt = Event::new().unwrap();
+        let (msi_tube, _msi_device_tube) = Tube::pair().unwrap();
+        let interrupt = Interrupt::new(
+            Arc::new(AtomicUsize::new(0)),
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            Some(Arc::new(Mutex::new(MsixConfig::new(1, msi_tube)))),
+            0,
+        );
+        vmm_handler
+            .activate(
+                &mem,
+                &interrupt,
+                &[queue.clone()],
+                &[queue_evt.try_clone().unwrap()],
+            )
+            .unwrap();
+
+        // The device takes all three requests and completes only the second one before it dies.
+        let (mut device_queue, device_mem) = device.join().unwrap();
+        for i in 0..NUM_REQUESTS {
+            assert_eq!(device_queue.pop(&device_mem).unwrap().index, i);
+        }
+        device_queue.add_used(&device_mem, 1, 0);
+
+        let device = run_device(Listener::new(&path, true).unwrap());
+        vmm_handler.reconnect().unwrap();
+        let (mut device_queue, device_mem) = device.join().unwrap();
+
+        // The restarted device gets the requests that were in flight, in the order they were
+        // taken, and nothing else.
+        assert_eq!(device_queue.pop(&device_mem).unwrap().index, 0);
+        assert_eq!(device_queue.pop(&device_mem).unwrap().index, 2);
+        assert!(device_queue.pop(&device_mem).is_none());
+        assert_eq!(queue_evt.read().unwrap(), 1);
+
+        // Requests the guest offers from now on are picked up after them.
+        mem.write_obj_at_addr(1u16, queue.avail_ring.unchecked_add(4 + 2 * 3))
+            .unwrap();
+        mem.write_obj_at_addr(NUM_REQUESTS + 1, queue.avail_ring.unchecked_add(2))
+            .unwrap();
+        assert_eq!(device_queue.pop(&device_mem).unwrap().index, 1);
+    }
 }
@@ -9,7 +9,9 @@ mod fs;
 mod gpu;
 mod handler;
 mod net;
+mod net_switch;
 mod vsock;
+mod vvu;
 mod wl;
 
 pub use block::run_block_device;
@@ -27,6 +27,7 @@ use crate::virtio::net::{
 use crate::virtio::vhost::user::device::handler::{
     CallEvent, DeviceRequestHandler, VhostUserBackend,
 };
+use crate::virtio::vhost::user::device::net_switch::run_switch;
 use crate::{virtio, ProtectionType};
 
 thread_local! {
@@ -240,7 +241,7 @@ impl VhostUserBackend for NetBackend {
     }
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
-        VhostUserProtocolFeatures::CONFIG
+        VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::INFLIGHT_SHMFD
     }
 
     fn ack_protocol_features(&mut self, features: u64) -> anyhow::Result<()> {
@@ -352,6 +353,12 @@ pub fn run_net_device(program_name: &str, args: std::env::Args) -> anyhow::Resul
         "TAP FD with a socket path",
         "SOCKET_PATH,TAP_FD",
     );
+    opts.optmulti(
+        "",
+        "switch",
+        "Socket path of a port on a userspace L2 switch shared by all --switch devices",
+        "SOCKET_PATH",
+    );
 
     let matches = match opts.parse(args) {
         Ok(m) => m,
@@ -367,8 +374,9 @@ pub fn run_net_device(program_name: &str, args: std::env::Args) -> anyhow::Resul
 
     let device_args = matches.opt_strs("device");
     let tap_fd_args = matches.opt_strs("tap-fd");
+    let switch_args = matches.opt_strs("switch");
     let num_devices = device_args.len() + tap_fd_args.len();
-    if num_devices == 0 {
+    if num_devices + switch_args.len() == 0 {
         bail!("no device option was passed");
     }
 
@@ -405,7 +413,10 @@ pub fn run_net_device(program_name: &str, args: std::env::Args) -> anyhow::Resul
         devices.push((socket.to_string(), backend));
     }
 
-    let mut threads = Vec::with_capacity(num_devices);
+    let mut threads = Vec::with_capacity(num_devices + switch_args.len());
+    if !switch_args.is_empty() {
+        threads.extend(run_switch(switch_args).context("failed to start switch")?);
+    }
     for (socket, backend) in devices {
         let handler = DeviceRequestHandler::new(backend);
         let ex = Executor::new().context("failed to create executor")?;
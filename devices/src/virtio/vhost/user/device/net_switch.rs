@@ -0,0 +1,544 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A vhost-user net backend that connects several VM frontends to each other through a simple
+//! learning L2 switch instead of a host tap device.
+//!
+//! Every frontend socket becomes one port of the switch. Frames transmitted by a guest are
+//! forwarded to the port that last sent a frame from the destination MAC address, or flooded to
+//! all other ports when the destination is unknown, broadcast or multicast. No host network
+//! interface is involved, so the backend can run without any privileges.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, bail, Context};
+use base::{error, warn, Event};
+use cros_async::{EventAsync, Executor};
+use data_model::DataInit;
+use futures::future::{AbortHandle, Abortable};
+use once_cell::sync::OnceCell;
+use sync::Mutex;
+use virtio_sys::virtio_net::{self, virtio_net_hdr_v1};
+use vm_memory::GuestMemory;
+use vmm_vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
+
+use crate::virtio::net::{build_config, NetError};
+use crate::virtio::vhost::user::device::handler::{
+    CallEvent, DeviceRequestHandler, VhostUserBackend,
+};
+use crate::virtio::{self, Reader, Writer};
+use crate::ProtectionType;
+
+thread_local! {
+    static SWITCH_EXECUTOR: OnceCell<Executor> = OnceCell::new();
+}
+
+/// Length of the `virtio_net_hdr_v1` that precedes every frame on the rx and tx queues.
+const VNET_HDR_LEN: usize = mem::size_of::<virtio_net_hdr_v1>();
+/// Length of an Ethernet header (destination MAC, source MAC and EtherType).
+const ETH_HDR_LEN: usize = 14;
+/// MTU advertised to the guests connected to the switch.
+const SWITCH_MTU: u16 = 1500;
+/// Maximum number of frames queued for a port whose guest is not consuming its rx queue.
+const MAX_PENDING_FRAMES: usize = 256;
+
+type MacAddr = [u8; 6];
+
+fn is_multicast(mac: &MacAddr) -> bool {
+    // The I/G bit is set for both multicast and broadcast addresses.
+    mac[0] & 0x1 != 0
+}
+
+/// Frames waiting to be delivered to the guest connected to one port of the switch.
+struct SwitchPort {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    // Signaled whenever a frame is added to `frames`.
+    frames_evt: Event,
+}
+
+/// A learning L2 switch with a fixed number of ports.
+pub struct Switch {
+    ports: Vec<SwitchPort>,
+    // Maps a MAC address to the port it was last seen on.
+    mac_table: Mutex<BTreeMap<MacAddr, usize>>,
+}
+
+impl Switch {
+    /// Creates a switch with `num_ports` ports.
+    pub fn new(num_ports: usize) -> anyhow::Result<Switch> {
+        let mut ports = Vec::with_capacity(num_ports);
+        for _ in 0..num_ports {
+            ports.push(SwitchPort {
+                frames: Mutex::new(VecDeque::new()),
+                frames_evt: Event::new().context("failed to create frames event")?,
+            });
+        }
+
+        Ok(Switch {
+            ports,
+            mac_table: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Returns the number of ports of this switch.
+    pub fn num_ports(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// Forwards the Ethernet `frame` received from port `src` to its destination port(s).
+    ///
+    /// The source address of the frame is learned so that later frames addressed to it are only
+    /// delivered to `src`.
+    pub fn forward(&self, src: usize, frame: Vec<u8>) {
+        if frame.len() < ETH_HDR_LEN {
+            warn!("net switch: dropping runt frame of {} bytes", frame.len());
+            return;
+        }
+
+        let mut dst_mac: MacAddr = Default::default();
+        dst_mac.copy_from_slice(&frame[0..6]);
+        let mut src_mac: MacAddr = Default::default();
+        src_mac.copy_from_slice(&frame[6..12]);
+
+        let dst_port = {
+            let mut mac_table = self.mac_table.lock();
+            if !is_multicast(&src_mac) {
+                mac_table.insert(src_mac, src);
+            }
+            if is_multicast(&dst_mac) {
+                None
+            } else {
+                mac_table.get(&dst_mac).copied()
+            }
+        };
+
+        match dst_port {
+            // The destination is on the same segment as the sender, so there is nothing to do.
+            Some(dst) if dst == src => {}
+            Some(dst) => self.enqueue(dst, frame),
+            None => {
+                for dst in (0..self.ports.len()).filter(|&p| p != src) {
+                    self.enqueue(dst, frame.clone());
+                }
+            }
+        }
+    }
+
+    fn enqueue(&self, dst: usize, frame: Vec<u8>) {
+        let port = &self.ports[dst];
+        {
+            let mut frames = port.frames.lock();
+            if frames.len() >= MAX_PENDING_FRAMES {
+                // The guest is not draining its rx queue; behave like a congested link.
+                return;
+            }
+            frames.push_back(frame);
+        }
+
+        if let Err(e) = port.frames_evt.write(1) {
+            error!("net switch: failed to signal port {}: {}", dst, e);
+        }
+    }
+
+    /// Removes the oldest frame queued for port `port`.
+    fn pop_frame(&self, port: usize) -> Option<Vec<u8>> {
+        self.ports[port].frames.lock().pop_front()
+    }
+
+    /// Puts back `frame` as the oldest frame queued for port `port`.
+    fn unpop_frame(&self, port: usize, frame: Vec<u8>) {
+        self.ports[port].frames.lock().push_front(frame);
+    }
+
+    fn frames_event(&self, port: usize) -> anyhow::Result<Event> {
+        self.ports[port]
+            .frames_evt
+            .try_clone()
+            .context("failed to clone frames event")
+    }
+}
+
+/// Writes the frames queued for `port` into the guest's rx queue.
+///
+/// Returns `NetError::RxDescriptorsExhausted` if frames remain queued because the guest has not
+/// provided enough buffers.
+fn process_switch_rx(
+    switch: &Switch,
+    port: usize,
+    call_evt: &Arc<Mutex<CallEvent>>,
+    rx_queue: &mut virtio::Queue,
+    mem: &GuestMemory,
+) -> Result<(), NetError> {
+    let mut needs_interrupt = false;
+    let mut result = Ok(());
+
+    while let Some(frame) = switch.pop_frame(port) {
+        let desc_chain = match rx_queue.peek(mem) {
+            Some(desc) => desc,
+            None => {
+                switch.unpop_frame(port, frame);
+                result = Err(NetError::RxDescriptorsExhausted);
+                break;
+            }
+        };
+
+        let index = desc_chain.index;
+        let bytes_written = match Writer::new(mem.clone(), desc_chain) {
+            Ok(mut writer) => {
+                if writer.available_bytes() < VNET_HDR_LEN + frame.len() {
+                    warn!("net switch: rx: buffer is too small to hold frame");
+                    0
+                } else {
+                    // No offloads are negotiated, so every field but `num_buffers` is zero.
+                    let mut hdr = [0u8; VNET_HDR_LEN];
+                    hdr[VNET_HDR_LEN - 2..].copy_from_slice(&1u16.to_le_bytes());
+                    match writer
+                        .write_all(&hdr)
+                        .and_then(|_| writer.write_all(&frame))
+                    {
+                        Ok(()) => writer.bytes_written() as u32,
+                        Err(e) => {
+                            // Still hand the descriptor back below so the guest can reuse it.
+                            result = Err(NetError::WriteBuffer(e));
+                            0
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                error!("net switch: failed to create Writer: {}", e);
+                0
+            }
+        };
+
+        rx_queue.pop_peeked(mem);
+        rx_queue.add_used(mem, index, bytes_written);
+        needs_interrupt = true;
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    if needs_interrupt {
+        rx_queue.trigger_interrupt(mem, call_evt);
+    }
+
+    result
+}
+
+/// Reads all the frames the guest put on its tx queue and hands them to the switch.
+fn process_switch_tx(
+    switch: &Switch,
+    port: usize,
+    call_evt: &Arc<Mutex<CallEvent>>,
+    tx_queue: &mut virtio::Queue,
+    mem: &GuestMemory,
+) {
+    while let Some(desc_chain) = tx_queue.pop(mem) {
+        let index = desc_chain.index;
+
+        match Reader::new(mem.clone(), desc_chain) {
+            Ok(mut reader) => {
+                // The header carries offload information, none of which has been negotiated.
+                reader.consume(VNET_HDR_LEN);
+                let mut frame = Vec::with_capacity(reader.available_bytes());
+                match reader.read_to_end(&mut frame) {
+                    Ok(_) => switch.forward(port, frame),
+                    Err(e) => error!("net switch: tx: failed to read frame: {}", e),
+                }
+            }
+            Err(e) => error!("net switch: failed to create Reader: {}", e),
+        }
+
+        tx_queue.add_used(mem, index, 0);
+    }
+
+    tx_queue.trigger_interrupt(mem, call_evt);
+}
+
+async fn run_switch_rx_queue(
+    mut queue: virtio::Queue,
+    mem: GuestMemory,
+    switch: Arc<Switch>,
+    port: usize,
+    frames_evt: EventAsync,
+    call_evt: Arc<Mutex<CallEvent>>,
+    kick_evt: EventAsync,
+) {
+    loop {
+        match process_switch_rx(&switch, port, &call_evt, &mut queue, &mem) {
+            Ok(()) => {
+                if let Err(e) = frames_evt.next_val().await {
+                    error!("Failed to read frames event for rx queue: {}", e);
+                    break;
+                }
+            }
+            Err(NetError::RxDescriptorsExhausted) => {
+                if let Err(e) = kick_evt.next_val().await {
+                    error!("Failed to read kick event for rx queue: {}", e);
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Failed to process rx queue: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+async fn run_switch_tx_queue(
+    mut queue: virtio::Queue,
+    mem: GuestMemory,
+    switch: Arc<Switch>,
+    port: usize,
+    call_evt: Arc<Mutex<CallEvent>>,
+    kick_evt: EventAsync,
+) {
+    loop {
+        if let Err(e) = kick_evt.next_val().await {
+            error!("Failed to read kick event for tx queue: {}", e);
+            break;
+        }
+
+        process_switch_tx(&switch, port, &call_evt, &mut queue, &mem);
+    }
+}
+
+/// A vhost-user net backend for one port of a `Switch`.
+struct SwitchPortBackend {
+    switch: Arc<Switch>,
+    port: usize,
+    avail_features: u64,
+    acked_features: u64,
+    acked_protocol_features: VhostUserProtocolFeatures,
+    workers: [Option<AbortHandle>; Self::MAX_QUEUE_NUM],
+}
+
+impl SwitchPortBackend {
+    fn new(switch: Arc<Switch>, port: usize) -> Self {
+        // Guests compute checksums and segment packets themselves since frames never reach a
+        // host network stack that could do it for them.
+        let avail_features = virtio::base_features(ProtectionType::Unprotected)
+            | 1 << virtio_net::VIRTIO_NET_F_MTU
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+
+        Self {
+            switch,
+            port,
+            avail_features,
+            acked_features: 0,
+            acked_protocol_features: VhostUserProtocolFeatures::empty(),
+            workers: Default::default(),
+        }
+    }
+}
+
+impl VhostUserBackend for SwitchPortBackend {
+    const MAX_QUEUE_NUM: usize = 2; /* rx, tx */
+    const MAX_VRING_LEN: u16 = 256;
+
+    type Doorbell = CallEvent;
+    type Error = anyhow::Error;
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) -> anyhow::Result<()> {
+        let unrequested_features = value & !self.avail_features;
+        if unrequested_features != 0 {
+            bail!("invalid features are given: {:#x}", unrequested_features);
+        }
+
+        self.acked_features |= value;
+
+        Ok(())
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn ack_protocol_features(&mut self, features: u64) -> anyhow::Result<()> {
+        let features = VhostUserProtocolFeatures::from_bits(features)
+            .ok_or_else(|| anyhow!("invalid protocol features are given: {:#x}", features))?;
+        let supported = self.protocol_features();
+        self.acked_protocol_features = features & supported;
+        Ok(())
+    }
+
+    fn acked_protocol_features(&self) -> u64 {
+        self.acked_protocol_features.bits()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config_space = build_config(1 /* vq_pairs */, SWITCH_MTU);
+        virtio::copy_config(data, 0, config_space.as_slice(), offset);
+    }
+
+    fn reset(&mut self) {}
+
+    fn start_queue(
+        &mut self,
+        idx: usize,
+        mut queue: virtio::Queue,
+        mem: GuestMemory,
+        call_evt: Arc<Mutex<CallEvent>>,
+        kick_evt: Event,
+    ) -> anyhow::Result<()> {
+        if let Some(handle) = self.workers.get_mut(idx).and_then(Option::take) {
+            warn!("Starting new queue handler without stopping old handler");
+            handle.abort();
+        }
+
+        // Enable any virtqueue features that were negotiated (like VIRTIO_RING_F_EVENT_IDX).
+        queue.ack_features(self.acked_features);
+
+        SWITCH_EXECUTOR.with(|ex| {
+            // Safe because the executor is initialized in run_switch() below.
+            let ex = ex.get().expect("Executor not initialized");
+
+            let kick_evt = EventAsync::new(kick_evt.0, ex)
+                .context("failed to create EventAsync for kick_evt")?;
+            let switch = Arc::clone(&self.switch);
+            let (handle, registration) = AbortHandle::new_pair();
+            match idx {
+                0 => {
+                    let frames_evt = EventAsync::new(switch.frames_event(self.port)?.0, ex)
+                        .context("failed to create EventAsync for frames_evt")?;
+                    ex.spawn_local(Abortable::new(
+                        run_switch_rx_queue(
+                            queue, mem, switch, self.port, frames_evt, call_evt, kick_evt,
+                        ),
+                        registration,
+                    ))
+                    .detach();
+                }
+                1 => {
+                    ex.spawn_local(Abortable::new(
+                        run_switch_tx_queue(queue, mem, switch, self.port, call_evt, kick_evt),
+                        registration,
+                    ))
+                    .detach();
+                }
+                _ => bail!("attempted to start unknown queue: {}", idx),
+            }
+
+            self.workers[idx] = Some(handle);
+            Ok(())
+        })
+    }
+
+    fn stop_queue(&mut self, idx: usize) {
+        if let Some(handle) = self.workers.get_mut(idx).and_then(Option::take) {
+            handle.abort();
+        }
+    }
+}
+
+/// Spawns one thread per socket in `sockets`, each serving a port of a single shared switch.
+pub(super) fn run_switch(
+    sockets: Vec<String>,
+) -> anyhow::Result<Vec<JoinHandle<anyhow::Result<()>>>> {
+    let switch = Arc::new(Switch::new(sockets.len())?);
+
+    let mut threads = Vec::with_capacity(switch.num_ports());
+    for (port, socket) in sockets.into_iter().enumerate() {
+        let backend = SwitchPortBackend::new(Arc::clone(&switch), port);
+        let handler = DeviceRequestHandler::new(backend);
+        let ex = Executor::new().context("failed to create executor")?;
+
+        threads.push(thread::spawn(move || {
+            SWITCH_EXECUTOR.with(|thread_ex| {
+                let _ = thread_ex.set(ex.clone());
+            });
+            if let Err(e) = ex.run_until(handler.run(&socket, &ex)) {
+                bail!("error occurred: {}", e);
+            }
+            Ok(())
+        }));
+    }
+
+    Ok(threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dst: MacAddr, src: MacAddr) -> Vec<u8> {
+        let mut f = Vec::new();
+        f.extend_from_slice(&dst);
+        f.extend_from_slice(&src);
+        f.extend_from_slice(&[0x08, 0x00]);
+        f.extend_from_slice(b"payload");
+        f
+    }
+
+    const MAC_A: MacAddr = [0x02, 0, 0, 0, 0, 0xa];
+    const MAC_B: MacAddr = [0x02, 0, 0, 0, 0, 0xb];
+    const BROADCAST: MacAddr = [0xff; 6];
+
+    fn pending(switch: &Switch, port: usize) -> usize {
+        switch.ports[port].frames.lock().len()
+    }
+
+    #[test]
+    fn unknown_destination_is_flooded() {
+        let switch = Switch::new(3).unwrap();
+        switch.forward(0, frame(MAC_B, MAC_A));
+
+        assert_eq!(pending(&switch, 0), 0);
+        assert_eq!(pending(&switch, 1), 1);
+        assert_eq!(pending(&switch, 2), 1);
+    }
+
+    #[test]
+    fn learned_destination_is_unicast() {
+        let switch = Switch::new(3).unwrap();
+        // B announces itself from port 2.
+        switch.forward(2, frame(BROADCAST, MAC_B));
+        assert_eq!(pending(&switch, 0), 1);
+        assert_eq!(pending(&switch, 1), 1);
+
+        switch.forward(0, frame(MAC_B, MAC_A));
+        assert_eq!(pending(&switch, 1), 1);
+        assert_eq!(pending(&switch, 2), 1);
+        assert_eq!(switch.pop_frame(2).unwrap(), frame(MAC_B, MAC_A));
+    }
+
+    #[test]
+    fn frame_to_own_segment_is_dropped() {
+        let switch = Switch::new(2).unwrap();
+        switch.forward(0, frame(BROADCAST, MAC_A));
+        switch.forward(0, frame(MAC_A, MAC_B));
+        assert_eq!(pending(&switch, 1), 1);
+    }
+
+    #[test]
+    fn runt_frame_is_dropped() {
+        let switch = Switch::new(2).unwrap();
+        switch.forward(0, vec![0xff; ETH_HDR_LEN - 1]);
+        assert_eq!(pending(&switch, 1), 0);
+    }
+
+    #[test]
+    fn backlog_is_bounded() {
+        let switch = Switch::new(2).unwrap();
+        for _ in 0..MAX_PENDING_FRAMES + 10 {
+            switch.forward(0, frame(BROADCAST, MAC_A));
+        }
+        assert_eq!(pending(&switch, 1), MAX_PENDING_FRAMES);
+    }
+}
@@ -0,0 +1,1139 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Userspace driver for the virtio-vhost-user PCI device, which lets a vhost-user device backend
+//! run inside a VM and serve a frontend connected to the device on the host.
+//!
+//! The device must be bound to `vfio-pci` in no-IOMMU mode. Vhost-user messages are exchanged
+//! with the device over its rx and tx queues and relayed to a `DeviceRequestHandler` through a
+//! socket pair. Since the host keeps the file descriptors attached to the frontend's messages, the
+//! driver attaches its own in their place:
+//!
+//! * `SET_MEM_TABLE` gets the device's shared memory BAR, into which the frontend's memory is
+//!   mapped.
+//! * `SET_VRING_KICK` gets the MSI-X eventfd of the vector assigned to the vring.
+//! * `SET_VRING_CALL` gets an eventfd that rings the vring's doorbell when written.
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::num::Wrapping;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use base::{
+    pagesize, AsRawDescriptor, Event, MappedRegion, MemoryMapping, MemoryMappingBuilder, MmapError,
+    SafeDescriptor, ScmSocket,
+};
+use cros_async::{AsyncError, AsyncWrapper, EventAsync, Executor};
+use data_model::{DataInit, Le16, Le32, Le64};
+use futures::future::select_all;
+use remain::sorted;
+use sync::Mutex;
+use sys_util::clear_fd_flags;
+use thiserror::Error as ThisError;
+use vfio_sys::{VFIO_PCI_BAR0_REGION_INDEX, VFIO_PCI_MSIX_IRQ_INDEX};
+
+use crate::vfio::{VfioContainer, VfioDevice, VfioError, VfioPciConfig};
+use crate::virtio::vhost::user::proxy::{
+    memory_regions, vring_payload, MemoryRegion, MessageHeader, MAX_ATTACHED_FDS,
+    MAX_MESSAGE_PAYLOAD_SIZE, MEMORY_REGIONS_OFFSET, MESSAGE_HEADER_SIZE,
+    NOTIFICATION_MSIX_VECTOR_OFFSET, NOTIFICATION_SELECT_OFFSET, VHOST_USER_SET_MEM_TABLE,
+    VHOST_USER_SET_VRING_CALL, VHOST_USER_SET_VRING_KICK, VHOST_USER_VRING_INDEX_MASK,
+    VHOST_USER_VRING_NOFD_MASK, VIRTIO_VHOST_USER_STATUS_MASTER_UP,
+    VIRTIO_VHOST_USER_STATUS_SLAVE_UP,
+};
+use crate::virtio::{
+    PciCapabilityType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FEATURES_OK,
+    DEVICE_RESET, VIRTIO_F_VERSION_1,
+};
+
+const PCI_COMMAND: u32 = 0x4;
+const PCI_COMMAND_MEMORY: u16 = 0x2;
+const PCI_COMMAND_MASTER: u16 = 0x4;
+const PCI_STATUS: u32 = 0x6;
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+const PCI_CAPABILITY_LIST: u32 = 0x34;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+const PCI_MSIX_FLAGS: u32 = 0x2;
+const PCI_MSIX_FLAGS_QSIZE: u16 = 0x7ff;
+
+// Offsets of the fields of `struct virtio_pci_cap` and the structures extending it.
+const VIRTIO_PCI_CAP_CFG_TYPE: u32 = 3;
+const VIRTIO_PCI_CAP_BAR: u32 = 4;
+const VIRTIO_PCI_CAP_OFFSET: u32 = 8;
+const VIRTIO_PCI_CAP_MULTIPLIER: u32 = 16;
+const VIRTIO_PCI_CAP_OFFSET_HI: u32 = 16;
+
+// Offsets of the fields of `struct virtio_pci_common_cfg`.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_AVAIL: u64 = 0x28;
+const COMMON_QUEUE_USED: u64 = 0x30;
+
+// Offsets of the fields of `VirtioVhostUserConfig`.
+const CONFIG_STATUS: u64 = 0;
+const CONFIG_MAX_VHOST_QUEUES: u64 = 4;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+// MSI-X vectors: one for configuration changes, one per queue and one per vring.
+const CONFIG_VECTOR: u16 = 0;
+const RX_QUEUE_VECTOR: u16 = 1;
+const TX_QUEUE_VECTOR: u16 = 2;
+const VRING_VECTOR_BASE: u16 = 3;
+
+/// Number of descriptors in each queue.
+const QUEUE_SIZE: u16 = 32;
+/// Number of single-page descriptors chained for each message, enough for the header and the
+/// largest payload.
+const DESCRIPTORS_PER_MESSAGE: u16 = 2;
+
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_PFN_MASK: u64 = (1 << 55) - 1;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum Error {
+    /// Failed to allocate memory shared with the device.
+    #[error("failed to allocate memory shared with the device: {0}")]
+    AllocateMemory(MmapError),
+    /// The backend closed its socket.
+    #[error("the backend closed its socket")]
+    BackendClosed,
+    /// Failed to make the backend's socket blocking.
+    #[error("failed to clear O_NONBLOCK on the backend's socket: {0}")]
+    ClearNonBlocking(base::Error),
+    /// Failed to create an async source.
+    #[error("failed to create an async source: {0}")]
+    CreateAsyncSource(AsyncError),
+    /// Failed to create an event.
+    #[error("failed to create an event: {0}")]
+    CreateEvent(base::Error),
+    /// The device rejected the features.
+    #[error("the device rejected the driver's features")]
+    FeaturesRejected,
+    /// The frontend disconnected from the device.
+    #[error("the vhost-user frontend disconnected")]
+    FrontendDisconnected,
+    /// Failed to look up the physical address of memory shared with the device.
+    #[error("failed to look up a physical address: {0}")]
+    GetPhysicalAddress(io::Error),
+    /// Failed to lock memory shared with the device.
+    #[error("failed to lock memory shared with the device: {0}")]
+    LockMemory(io::Error),
+    /// The message is malformed.
+    #[error("malformed message")]
+    MalformedMessage,
+    /// A required capability is missing.
+    #[error("the device has no {0} capability")]
+    MissingCapability(&'static str),
+    /// Failed to open the shared memory BAR.
+    #[error("failed to open the shared memory BAR {0}: {1}")]
+    OpenSharedMemory(PathBuf, io::Error),
+    /// The physical address of memory shared with the device is not available.
+    #[error("physical address of {0:#x} is not available; is CAP_SYS_ADMIN missing?")]
+    PhysicalAddressUnavailable(usize),
+    /// Failed to read from the backend's socket.
+    #[error("failed to read from the backend's socket: {0}")]
+    ReadSocket(io::Error),
+    /// A queue of the device is smaller than the driver requires.
+    #[error("queue {0} has only {1} entries")]
+    SmallQueue(u16, u16),
+    /// The device doesn't support VIRTIO_F_VERSION_1.
+    #[error("the device doesn't support VIRTIO_F_VERSION_1")]
+    UnsupportedDevice,
+    /// Failed to set up the VFIO device.
+    #[error("failed to set up the vfio device: {0}")]
+    Vfio(VfioError),
+    /// A vring index was out of range.
+    #[error("invalid vring index: {0}")]
+    VringIndex(u64),
+    /// Failed to wait for an interrupt.
+    #[error("failed to wait for an interrupt: {0}")]
+    WaitInterrupt(AsyncError),
+    /// Failed to wait for the backend's socket to become readable.
+    #[error("failed to wait for the backend's socket: {0}")]
+    WaitSocket(AsyncError),
+    /// Failed to send a message to the backend.
+    #[error("failed to send a message to the backend: {0}")]
+    WriteSocket(base::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Location of a structure in a BAR of the device.
+#[derive(Clone, Copy)]
+struct BarRegion {
+    index: u32,
+    offset: u64,
+}
+
+/// Capabilities of a virtio-vhost-user device.
+#[derive(Default)]
+struct Capabilities {
+    common: Option<BarRegion>,
+    notify: Option<(BarRegion, u32)>,
+    device: Option<BarRegion>,
+    doorbell: Option<(BarRegion, u32)>,
+    notification: Option<BarRegion>,
+    shared_memory: Option<BarRegion>,
+    msix_vectors: u16,
+}
+
+impl Capabilities {
+    fn parse(config: &VfioPciConfig) -> Capabilities {
+        let mut caps = Capabilities::default();
+        if config.read_config_word(PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+            return caps;
+        }
+
+        let mut pos = config.read_config_byte(PCI_CAPABILITY_LIST) as u32;
+        while pos != 0 {
+            let id = config.read_config_byte(pos);
+            match id {
+                PCI_CAP_ID_MSIX => {
+                    let flags = config.read_config_word(pos + PCI_MSIX_FLAGS);
+                    caps.msix_vectors = (flags & PCI_MSIX_FLAGS_QSIZE) + 1;
+                }
+                PCI_CAP_ID_VNDR => {
+                    let cfg_type = config.read_config_byte(pos + VIRTIO_PCI_CAP_CFG_TYPE);
+                    let region = BarRegion {
+                        index: VFIO_PCI_BAR0_REGION_INDEX
+                            + config.read_config_byte(pos + VIRTIO_PCI_CAP_BAR) as u32,
+                        offset: config.read_config_dword(pos + VIRTIO_PCI_CAP_OFFSET) as u64,
+                    };
+                    let multiplier = || config.read_config_dword(pos + VIRTIO_PCI_CAP_MULTIPLIER);
+                    match cfg_type {
+                        t if t == PciCapabilityType::CommonConfig as u8 => {
+                            caps.common = Some(region)
+                        }
+                        t if t == PciCapabilityType::NotifyConfig as u8 => {
+                            caps.notify = Some((region, multiplier()))
+                        }
+                        t if t == PciCapabilityType::DeviceConfig as u8 => {
+                            caps.device = Some(region)
+                        }
+                        t if t == PciCapabilityType::DoorbellConfig as u8 => {
+                            caps.doorbell = Some((region, multiplier()))
+                        }
+                        t if t == PciCapabilityType::NotificationConfig as u8 => {
+                            caps.notification = Some(region)
+                        }
+                        t if t == PciCapabilityType::SharedMemoryConfig as u8 => {
+                            let offset_hi =
+                                config.read_config_dword(pos + VIRTIO_PCI_CAP_OFFSET_HI);
+                            caps.shared_memory = Some(BarRegion {
+                                offset: region.offset | (offset_hi as u64) << 32,
+                                ..region
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+            pos = config.read_config_byte(pos + 1) as u32;
+        }
+        caps
+    }
+}
+
+fn required<T>(cap: Option<T>, name: &'static str) -> Result<T> {
+    cap.ok_or(Error::MissingCapability(name))
+}
+
+// Returns the physical address backing the virtual address `addr` of this process.
+fn virt_to_phys(pagemap: &File, addr: usize) -> Result<u64> {
+    let page_size = pagesize();
+    let mut entry = [0u8; 8];
+    pagemap
+        .read_exact_at(&mut entry, (addr / page_size * entry.len()) as u64)
+        .map_err(Error::GetPhysicalAddress)?;
+    let entry = u64::from_le_bytes(entry);
+    let pfn = entry & PAGEMAP_PFN_MASK;
+    if entry & PAGEMAP_PRESENT == 0 || pfn == 0 {
+        return Err(Error::PhysicalAddressUnavailable(addr));
+    }
+    Ok(pfn * page_size as u64 + (addr % page_size) as u64)
+}
+
+/// A page of memory shared with the device, which accesses it by its physical address.
+struct DmaPage {
+    mapping: MemoryMapping,
+    phys: u64,
+}
+
+impl DmaPage {
+    fn new(pagemap: &File) -> Result<DmaPage> {
+        let size = pagesize();
+        let mapping = MemoryMappingBuilder::new(size)
+            .build()
+            .map_err(Error::AllocateMemory)?;
+        // Keep the page resident so that its physical address stays valid. There is no IOMMU
+        // to translate the device's accesses.
+        // Safe because the range is mapped by `mapping`.
+        if unsafe { libc::mlock(mapping.as_ptr() as *const libc::c_void, size) } < 0 {
+            return Err(Error::LockMemory(io::Error::last_os_error()));
+        }
+        let phys = virt_to_phys(pagemap, mapping.as_ptr() as usize)?;
+        Ok(DmaPage { mapping, phys })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Desc {
+    addr: Le64,
+    len: Le32,
+    flags: Le16,
+    next: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for Desc {}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct UsedElem {
+    id: Le32,
+    len: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for UsedElem {}
+
+// Offsets of the `idx` and `ring` fields of the available and used rings.
+const RING_IDX: usize = 2;
+const RING_ENTRIES: usize = 4;
+
+/// The driver side of a split virtqueue whose descriptors each point to a page of their own.
+/// Messages occupy chains of `DESCRIPTORS_PER_MESSAGE` descriptors.
+struct DriverQueue {
+    index: u16,
+    desc: DmaPage,
+    avail: DmaPage,
+    used: DmaPage,
+    buffers: Vec<DmaPage>,
+    free_heads: Vec<u16>,
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+    notify: BarRegion,
+}
+
+impl DriverQueue {
+    fn new(pagemap: &File, index: u16, notify: BarRegion) -> Result<DriverQueue> {
+        let buffers = (0..QUEUE_SIZE)
+            .map(|_| DmaPage::new(pagemap))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(DriverQueue {
+            index,
+            desc: DmaPage::new(pagemap)?,
+            avail: DmaPage::new(pagemap)?,
+            used: DmaPage::new(pagemap)?,
+            buffers,
+            free_heads: (0..QUEUE_SIZE)
+                .step_by(DESCRIPTORS_PER_MESSAGE as usize)
+                .collect(),
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            notify,
+        })
+    }
+
+    // Makes a chain of buffers available to the device. The chain is device-writable if `data`
+    // is `None` and holds `data` otherwise.
+    fn push(&mut self, data: Option<&[u8]>) -> Result<()> {
+        let head = self.free_heads.pop().ok_or(Error::MalformedMessage)?;
+        let page_size = pagesize();
+        let mut remaining = data.map(|d| d.len());
+        for i in 0..DESCRIPTORS_PER_MESSAGE {
+            let index = head + i;
+            let buffer = &self.buffers[index as usize];
+            let (len, last) = match (data, remaining) {
+                (Some(data), Some(rem)) => {
+                    let start = data.len() - rem;
+                    let len = rem.min(page_size);
+                    // Writes to an anonymous mapping of a page can't fail.
+                    let _ = buffer.mapping.write_slice(&data[start..start + len], 0);
+                    remaining = Some(rem - len);
+                    (len, rem - len == 0)
+                }
+                _ => (page_size, i + 1 == DESCRIPTORS_PER_MESSAGE),
+            };
+            let mut flags = 0;
+            if data.is_none() {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if !last {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = Desc {
+                addr: Le64::from(buffer.phys),
+                len: Le32::from(len as u32),
+                flags: Le16::from(flags),
+                next: Le16::from(index + 1),
+            };
+            let _ = self
+                .desc
+                .mapping
+                .write_obj(desc, index as usize * size_of::<Desc>());
+            if last {
+                break;
+            }
+        }
+        if remaining.map_or(false, |rem| rem > 0) {
+            self.free_heads.push(head);
+            return Err(Error::MalformedMessage);
+        }
+
+        let slot = (self.next_avail.0 % QUEUE_SIZE) as usize;
+        let _ = self
+            .avail
+            .mapping
+            .write_obj(Le16::from(head), RING_ENTRIES + slot * 2);
+        self.next_avail += Wrapping(1);
+        // The ring entry must be visible before the index is.
+        fence(Ordering::SeqCst);
+        let _ = self
+            .avail
+            .mapping
+            .write_obj(Le16::from(self.next_avail.0), RING_IDX);
+        Ok(())
+    }
+
+    // Returns the head and the written length of the next chain the device is done with.
+    fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let used_idx: Le16 = self.used.mapping.read_obj(RING_IDX).ok()?;
+        if used_idx.to_native() == self.next_used.0 {
+            return None;
+        }
+        // The ring entry must not be read before the index is.
+        fence(Ordering::SeqCst);
+        let slot = (self.next_used.0 % QUEUE_SIZE) as usize;
+        let elem: UsedElem = self
+            .used
+            .mapping
+            .read_obj(RING_ENTRIES + slot * size_of::<UsedElem>())
+            .ok()?;
+        self.next_used += Wrapping(1);
+        Some((elem.id.to_native() as u16, elem.len.to_native() as usize))
+    }
+
+    // Copies out `len` bytes written by the device into the chain at `head`.
+    fn read(&self, head: u16, len: usize) -> Vec<u8> {
+        let page_size = pagesize();
+        let mut data = vec![0u8; len.min(page_size * DESCRIPTORS_PER_MESSAGE as usize)];
+        for (i, chunk) in data.chunks_mut(page_size).enumerate() {
+            let _ = self.buffers[head as usize + i].mapping.read_slice(chunk, 0);
+        }
+        data
+    }
+
+    fn release(&mut self, head: u16) {
+        self.free_heads.push(head);
+    }
+
+    fn kick(&self, vfio: &VfioDevice) {
+        vfio.region_write(
+            self.notify.index,
+            &self.index.to_le_bytes(),
+            self.notify.offset,
+        );
+    }
+}
+
+/// Rings the doorbells of the vrings.
+struct Doorbells {
+    vfio: Arc<VfioDevice>,
+    region: BarRegion,
+    multiplier: u32,
+}
+
+impl Doorbells {
+    fn ring(&self, index: usize) {
+        self.vfio.region_write(
+            self.region.index,
+            &(index as u32).to_le_bytes(),
+            self.region.offset + index as u64 * self.multiplier as u64,
+        );
+    }
+}
+
+/// Returns the sysfs path of the PCI device at `address`, e.g. `0000:00:05.0`.
+pub fn sysfs_path(address: &str) -> PathBuf {
+    Path::new("/sys/bus/pci/devices").join(address)
+}
+
+/// A virtio-vhost-user device driven from userspace.
+pub struct VvuDevice {
+    vfio: Arc<VfioDevice>,
+    rx_queue: DriverQueue,
+    tx_queue: DriverQueue,
+    device_config: BarRegion,
+    doorbells: Doorbells,
+    // The MSI-X eventfds, indexed by vector.
+    irqs: Vec<Event>,
+    shared_memory: File,
+    shared_memory_offset: u64,
+    num_vrings: usize,
+}
+
+impl VvuDevice {
+    /// Initializes the virtio-vhost-user device at `sysfs_path`, e.g.
+    /// `/sys/bus/pci/devices/0000:00:05.0`, and tells the host that the backend is up.
+    pub fn new(sysfs_path: &Path) -> Result<VvuDevice> {
+        let container = VfioContainer::new_noiommu().map_err(Error::Vfio)?;
+        let vfio = Arc::new(
+            VfioDevice::new_passthrough(sysfs_path, Arc::new(Mutex::new(container)))
+                .map_err(Error::Vfio)?,
+        );
+
+        let config = VfioPciConfig::new(vfio.clone());
+        let command = config.read_config_word(PCI_COMMAND);
+        config.write_config_word(
+            command | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER,
+            PCI_COMMAND,
+        );
+
+        let caps = Capabilities::parse(&config);
+        let common = required(caps.common, "common configuration")?;
+        let (notify, notify_multiplier) = required(caps.notify, "notification")?;
+        let device_config = required(caps.device, "device configuration")?;
+        let (doorbell, doorbell_multiplier) = required(caps.doorbell, "doorbell")?;
+        let notification = required(caps.notification, "vring notification")?;
+        let shared_memory = required(caps.shared_memory, "shared memory")?;
+        if caps.msix_vectors <= VRING_VECTOR_BASE {
+            return Err(Error::MissingCapability("MSI-X"));
+        }
+
+        let irqs = (0..caps.msix_vectors)
+            .map(|_| Event::new().map_err(Error::CreateEvent))
+            .collect::<Result<Vec<_>>>()?;
+        vfio.irq_enable(&irqs.iter().collect::<Vec<_>>(), VFIO_PCI_MSIX_IRQ_INDEX)
+            .map_err(Error::Vfio)?;
+
+        let read_common = |offset: u64, data: &mut [u8]| {
+            vfio.region_read(common.index, data, common.offset + offset)
+        };
+        let write_common = |offset: u64, data: &[u8]| {
+            vfio.region_write(common.index, data, common.offset + offset)
+        };
+
+        write_common(COMMON_DEVICE_STATUS, &[DEVICE_RESET as u8]);
+        let mut status = DEVICE_ACKNOWLEDGE as u8 | DEVICE_DRIVER as u8;
+        write_common(COMMON_DEVICE_STATUS, &[status]);
+
+        let mut features = [0u8; 4];
+        write_common(COMMON_DEVICE_FEATURE_SELECT, &1u32.to_le_bytes());
+        read_common(COMMON_DEVICE_FEATURE, &mut features);
+        let version_1 = 1u32 << (VIRTIO_F_VERSION_1 - 32);
+        if u32::from_le_bytes(features) & version_1 == 0 {
+            return Err(Error::UnsupportedDevice);
+        }
+        write_common(COMMON_DRIVER_FEATURE_SELECT, &0u32.to_le_bytes());
+        write_common(COMMON_DRIVER_FEATURE, &0u32.to_le_bytes());
+        write_common(COMMON_DRIVER_FEATURE_SELECT, &1u32.to_le_bytes());
+        write_common(COMMON_DRIVER_FEATURE, &version_1.to_le_bytes());
+        status |= DEVICE_FEATURES_OK as u8;
+        write_common(COMMON_DEVICE_STATUS, &[status]);
+        let mut device_status = [0u8];
+        read_common(COMMON_DEVICE_STATUS, &mut device_status);
+        if device_status[0] & DEVICE_FEATURES_OK as u8 == 0 {
+            return Err(Error::FeaturesRejected);
+        }
+
+        write_common(COMMON_MSIX_CONFIG, &CONFIG_VECTOR.to_le_bytes());
+
+        let pagemap = File::open("/proc/self/pagemap").map_err(Error::GetPhysicalAddress)?;
+        let mut queues = Vec::new();
+        for &(index, vector) in &[(RX_QUEUE, RX_QUEUE_VECTOR), (TX_QUEUE, TX_QUEUE_VECTOR)] {
+            write_common(COMMON_QUEUE_SELECT, &index.to_le_bytes());
+            let mut size = [0u8; 2];
+            read_common(COMMON_QUEUE_SIZE, &mut size);
+            let size = u16::from_le_bytes(size);
+            if size < QUEUE_SIZE {
+                return Err(Error::SmallQueue(index, size));
+            }
+            let mut notify_off = [0u8; 2];
+            read_common(COMMON_QUEUE_NOTIFY_OFF, &mut notify_off);
+            let notify = BarRegion {
+                offset: notify.offset
+                    + u16::from_le_bytes(notify_off) as u64 * notify_multiplier as u64,
+                ..notify
+            };
+
+            let queue = DriverQueue::new(&pagemap, index, notify)?;
+            write_common(COMMON_QUEUE_SIZE, &QUEUE_SIZE.to_le_bytes());
+            write_common(COMMON_QUEUE_MSIX_VECTOR, &vector.to_le_bytes());
+            write_common(COMMON_QUEUE_DESC, &queue.desc.phys.to_le_bytes());
+            write_common(COMMON_QUEUE_AVAIL, &queue.avail.phys.to_le_bytes());
+            write_common(COMMON_QUEUE_USED, &queue.used.phys.to_le_bytes());
+            write_common(COMMON_QUEUE_ENABLE, &1u16.to_le_bytes());
+            queues.push(queue);
+        }
+        let tx_queue = queues.pop().unwrap();
+        let mut rx_queue = queues.pop().unwrap();
+
+        status |= DEVICE_DRIVER_OK as u8;
+        write_common(COMMON_DEVICE_STATUS, &[status]);
+
+        let mut max_vhost_queues = [0u8; 4];
+        vfio.region_read(
+            device_config.index,
+            &mut max_vhost_queues,
+            device_config.offset + CONFIG_MAX_VHOST_QUEUES,
+        );
+        let num_vrings = (u32::from_le_bytes(max_vhost_queues) as usize)
+            .min(irqs.len() - VRING_VECTOR_BASE as usize);
+        for vring in 0..num_vrings as u16 {
+            vfio.region_write(
+                notification.index,
+                &vring.to_le_bytes(),
+                notification.offset + NOTIFICATION_SELECT_OFFSET,
+            );
+            vfio.region_write(
+                notification.index,
+                &(VRING_VECTOR_BASE + vring).to_le_bytes(),
+                notification.offset + NOTIFICATION_MSIX_VECTOR_OFFSET,
+            );
+        }
+
+        while !rx_queue.free_heads.is_empty() {
+            rx_queue.push(None)?;
+        }
+        rx_queue.kick(&vfio);
+
+        let shared_memory_path = sysfs_path.join(format!(
+            "resource{}",
+            shared_memory.index - VFIO_PCI_BAR0_REGION_INDEX
+        ));
+        let shared_memory_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&shared_memory_path)
+            .map_err(|e| Error::OpenSharedMemory(shared_memory_path, e))?;
+
+        let device = VvuDevice {
+            vfio: vfio.clone(),
+            rx_queue,
+            tx_queue,
+            device_config,
+            doorbells: Doorbells {
+                vfio,
+                region: doorbell,
+                multiplier: doorbell_multiplier,
+            },
+            irqs,
+            shared_memory: shared_memory_file,
+            shared_memory_offset: shared_memory.offset,
+            num_vrings,
+        };
+        write_status(
+            &device.vfio,
+            device_config,
+            read_status(&device.vfio, device_config) | VIRTIO_VHOST_USER_STATUS_SLAVE_UP,
+        );
+        Ok(device)
+    }
+
+    /// Relays messages between the device and the backend connected to `socket` until either
+    /// side goes away.
+    pub async fn run(self, socket: UnixStream, ex: &Executor) -> Result<()> {
+        let VvuDevice {
+            vfio,
+            mut rx_queue,
+            mut tx_queue,
+            device_config,
+            doorbells,
+            mut irqs,
+            shared_memory,
+            shared_memory_offset,
+            num_vrings,
+        } = self;
+
+        let vring_irqs = irqs.split_off(VRING_VECTOR_BASE as usize);
+        let mut queue_irqs = irqs
+            .into_iter()
+            .map(|irq| EventAsync::new(irq.0, ex).map_err(Error::CreateAsyncSource))
+            .collect::<Result<Vec<_>>>()?;
+        let tx_irq = queue_irqs.remove(TX_QUEUE_VECTOR as usize);
+        let rx_irq = queue_irqs.remove(RX_QUEUE_VECTOR as usize);
+        let config_irq = queue_irqs.remove(CONFIG_VECTOR as usize);
+
+        let call_evts = (0..num_vrings)
+            .map(|_| Event::new().map_err(Error::CreateEvent))
+            .collect::<Result<Vec<_>>>()?;
+        let fds = MessageFds {
+            shared_memory: &shared_memory,
+            shared_memory_offset,
+            kick_evts: &vring_irqs[..num_vrings],
+            call_evts: &call_evts,
+        };
+
+        let socket_source = SafeDescriptor::try_from(&socket as &dyn AsRawFd)
+            .map(AsyncWrapper::new)
+            .map_err(Error::ReadSocket)?;
+        let socket_source = ex
+            .async_from(socket_source)
+            .map_err(Error::CreateAsyncSource)?;
+        // Messages are read with blocking reads once the socket is readable, so that a message
+        // split across several reads is still read whole.
+        clear_fd_flags(socket.as_raw_fd(), libc::O_NONBLOCK).map_err(Error::ClearNonBlocking)?;
+
+        let mut futures: Vec<Pin<Box<dyn Future<Output = Result<()>>>>> = Vec::new();
+
+        // Messages from the frontend.
+        futures.push(Box::pin(async {
+            loop {
+                rx_irq.next_val().await.map_err(Error::WaitInterrupt)?;
+                while let Some((head, len)) = rx_queue.pop_used() {
+                    let message = rx_queue.read(head, len);
+                    rx_queue.release(head);
+                    rx_queue.push(None)?;
+                    fds.forward(message, &socket)?;
+                }
+                rx_queue.kick(&vfio);
+            }
+        }));
+
+        // Replies from the backend.
+        futures.push(Box::pin(async {
+            loop {
+                socket_source
+                    .wait_readable()
+                    .await
+                    .map_err(Error::WaitSocket)?;
+                let reply = read_message(&socket)?;
+                loop {
+                    while let Some((head, _)) = tx_queue.pop_used() {
+                        tx_queue.release(head);
+                    }
+                    if !tx_queue.free_heads.is_empty() {
+                        break;
+                    }
+                    tx_irq.next_val().await.map_err(Error::WaitInterrupt)?;
+                }
+                tx_queue.push(Some(&reply))?;
+                tx_queue.kick(&vfio);
+            }
+        }));
+
+        // Configuration changes, which tell whether the frontend is still connected.
+        futures.push(Box::pin(async {
+            let mut master_up =
+                read_status(&vfio, device_config) & VIRTIO_VHOST_USER_STATUS_MASTER_UP != 0;
+            loop {
+                config_irq.next_val().await.map_err(Error::WaitInterrupt)?;
+                let up =
+                    read_status(&vfio, device_config) & VIRTIO_VHOST_USER_STATUS_MASTER_UP != 0;
+                if master_up && !up {
+                    return Err(Error::FrontendDisconnected);
+                }
+                master_up = up;
+            }
+        }));
+
+        // Used buffer notifications from the backend.
+        for (index, evt) in call_evts.iter().enumerate() {
+            let evt = evt.try_clone().map_err(Error::CreateEvent)?;
+            let evt = EventAsync::new(evt.0, ex).map_err(Error::CreateAsyncSource)?;
+            let doorbells = &doorbells;
+            futures.push(Box::pin(async move {
+                loop {
+                    evt.next_val().await.map_err(Error::WaitInterrupt)?;
+                    doorbells.ring(index);
+                }
+            }));
+        }
+
+        select_all(futures).await.0
+    }
+}
+
+fn read_status(vfio: &VfioDevice, device_config: BarRegion) -> u32 {
+    let mut status = [0u8; 4];
+    vfio.region_read(
+        device_config.index,
+        &mut status,
+        device_config.offset + CONFIG_STATUS,
+    );
+    u32::from_le_bytes(status)
+}
+
+fn write_status(vfio: &VfioDevice, device_config: BarRegion, status: u32) {
+    vfio.region_write(
+        device_config.index,
+        &status.to_le_bytes(),
+        device_config.offset + CONFIG_STATUS,
+    );
+}
+
+/// The file descriptors attached to messages forwarded to the backend.
+struct MessageFds<'a> {
+    shared_memory: &'a File,
+    shared_memory_offset: u64,
+    kick_evts: &'a [Event],
+    call_evts: &'a [Event],
+}
+
+impl MessageFds<'_> {
+    // Attaches file descriptors to a message from the frontend and sends it to the backend.
+    fn forward(&self, mut message: Vec<u8>, socket: &UnixStream) -> Result<()> {
+        let header = *MessageHeader::from_slice(
+            message
+                .get(..MESSAGE_HEADER_SIZE)
+                .ok_or(Error::MalformedMessage)?,
+        )
+        .ok_or(Error::MalformedMessage)?;
+        let request = header.request.to_native();
+        let payload = &mut message[MESSAGE_HEADER_SIZE..];
+
+        let mut fds: Vec<RawFd> = Vec::new();
+        match request {
+            VHOST_USER_SET_MEM_TABLE => {
+                // The regions were mapped back to back into the shared memory BAR.
+                let regions = memory_regions(payload).ok_or(Error::MalformedMessage)?;
+                if regions.len() > MAX_ATTACHED_FDS {
+                    return Err(Error::MalformedMessage);
+                }
+                let mut offset = self.shared_memory_offset;
+                for (i, mut region) in regions.into_iter().enumerate() {
+                    region.mmap_offset = Le64::from(offset);
+                    offset += region.memory_size.to_native();
+                    let start = MEMORY_REGIONS_OFFSET + i * size_of::<MemoryRegion>();
+                    payload[start..start + size_of::<MemoryRegion>()]
+                        .copy_from_slice(region.as_slice());
+                    fds.push(self.shared_memory.as_raw_fd());
+                }
+            }
+            VHOST_USER_SET_VRING_KICK | VHOST_USER_SET_VRING_CALL => {
+                let value = vring_payload(payload).ok_or(Error::MalformedMessage)?;
+                if value & VHOST_USER_VRING_NOFD_MASK == 0 {
+                    let index = value & VHOST_USER_VRING_INDEX_MASK;
+                    let evts = if request == VHOST_USER_SET_VRING_KICK {
+                        self.kick_evts
+                    } else {
+                        self.call_evts
+                    };
+                    let evt = evts.get(index as usize).ok_or(Error::VringIndex(index))?;
+                    fds.push(evt.as_raw_descriptor());
+                }
+            }
+            _ => {}
+        }
+
+        socket
+            .send_bufs_with_fds(&[&message], &fds)
+            .map_err(Error::WriteSocket)?;
+        Ok(())
+    }
+}
+
+// Reads a whole message from the backend.
+fn read_message(mut socket: &UnixStream) -> Result<Vec<u8>> {
+    let read_err = |e: io::Error| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::BackendClosed
+        } else {
+            Error::ReadSocket(e)
+        }
+    };
+
+    let mut message = vec![0u8; MESSAGE_HEADER_SIZE];
+    socket.read_exact(&mut message).map_err(read_err)?;
+    let header = *MessageHeader::from_slice(&message).ok_or(Error::MalformedMessage)?;
+    let size = header.size.to_native() as usize;
+    if size > MAX_MESSAGE_PAYLOAD_SIZE {
+        return Err(Error::MalformedMessage);
+    }
+    message.resize(MESSAGE_HEADER_SIZE + size, 0);
+    socket
+        .read_exact(&mut message[MESSAGE_HEADER_SIZE..])
+        .map_err(read_err)?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::virtio::vhost::user::proxy::VHOST_USER_GET_PROTOCOL_FEATURES;
+
+    // A page standing in for DMA memory, at a made up physical address.
+    fn dma_page(phys: u64) -> DmaPage {
+        DmaPage {
+            mapping: MemoryMappingBuilder::new(pagesize()).build().unwrap(),
+            phys,
+        }
+    }
+
+    fn driver_queue() -> DriverQueue {
+        DriverQueue {
+            index: 0,
+            desc: dma_page(0x1000),
+            avail: dma_page(0x2000),
+            used: dma_page(0x3000),
+            buffers: (0..QUEUE_SIZE)
+                .map(|i| dma_page(0x10_0000 + u64::from(i) * pagesize() as u64))
+                .collect(),
+            free_heads: (0..QUEUE_SIZE)
+                .step_by(DESCRIPTORS_PER_MESSAGE as usize)
+                .collect(),
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            notify: BarRegion {
+                index: 0,
+                offset: 0,
+            },
+        }
+    }
+
+    fn desc(queue: &DriverQueue, index: u16) -> Desc {
+        queue
+            .desc
+            .mapping
+            .read_obj(index as usize * size_of::<Desc>())
+            .unwrap()
+    }
+
+    fn message(request: u32, payload: &[u8]) -> Vec<u8> {
+        let header = MessageHeader {
+            request: Le32::from(request),
+            flags: Le32::from(0),
+            size: Le32::from(payload.len() as u32),
+        };
+        let mut message = header.as_slice().to_vec();
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn push_spans_descriptors() {
+        let mut queue = driver_queue();
+        let data: Vec<u8> = (0..pagesize() + 10).map(|i| i as u8).collect();
+        queue.push(Some(&data)).unwrap();
+
+        let avail_idx: Le16 = queue.avail.mapping.read_obj(RING_IDX).unwrap();
+        assert_eq!(avail_idx.to_native(), 1);
+        let head: Le16 = queue.avail.mapping.read_obj(RING_ENTRIES).unwrap();
+        let head = head.to_native();
+
+        let first = desc(&queue, head);
+        assert_eq!(first.addr.to_native(), queue.buffers[head as usize].phys);
+        assert_eq!(first.len.to_native() as usize, pagesize());
+        assert_eq!(first.flags.to_native(), VIRTQ_DESC_F_NEXT);
+        assert_eq!(first.next.to_native(), head + 1);
+        let second = desc(&queue, head + 1);
+        assert_eq!(second.len.to_native(), 10);
+        assert_eq!(second.flags.to_native(), 0);
+
+        assert_eq!(queue.read(head, data.len()), data);
+    }
+
+    #[test]
+    fn push_writable() {
+        let mut queue = driver_queue();
+        queue.push(None).unwrap();
+
+        let head: Le16 = queue.avail.mapping.read_obj(RING_ENTRIES).unwrap();
+        let head = head.to_native();
+        let first = desc(&queue, head);
+        assert_eq!(
+            first.flags.to_native(),
+            VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT
+        );
+        let second = desc(&queue, head + 1);
+        assert_eq!(second.flags.to_native(), VIRTQ_DESC_F_WRITE);
+        assert_eq!(second.len.to_native() as usize, pagesize());
+    }
+
+    #[test]
+    fn push_too_large() {
+        let mut queue = driver_queue();
+        let free = queue.free_heads.len();
+        let data = vec![0u8; pagesize() * DESCRIPTORS_PER_MESSAGE as usize + 1];
+        assert!(matches!(
+            queue.push(Some(&data)),
+            Err(Error::MalformedMessage)
+        ));
+        // The chain is returned and nothing was made available.
+        assert_eq!(queue.free_heads.len(), free);
+        let avail_idx: Le16 = queue.avail.mapping.read_obj(RING_IDX).unwrap();
+        assert_eq!(avail_idx.to_native(), 0);
+    }
+
+    #[test]
+    fn pop_used() {
+        let mut queue = driver_queue();
+        assert!(queue.pop_used().is_none());
+
+        let elem = UsedElem {
+            id: Le32::from(4),
+            len: Le32::from(20),
+        };
+        queue.used.mapping.write_obj(elem, RING_ENTRIES).unwrap();
+        queue
+            .used
+            .mapping
+            .write_obj(Le16::from(1), RING_IDX)
+            .unwrap();
+
+        assert_eq!(queue.pop_used(), Some((4, 20)));
+        assert!(queue.pop_used().is_none());
+    }
+
+    #[test]
+    fn read_whole_message() {
+        let (mut backend, driver) = UnixStream::pair().unwrap();
+        let sent = message(VHOST_USER_GET_PROTOCOL_FEATURES, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        // Split the message to check that it is read whole.
+        backend.write_all(&sent[..5]).unwrap();
+        backend.write_all(&sent[5..]).unwrap();
+        assert_eq!(read_message(&driver).unwrap(), sent);
+
+        drop(backend);
+        assert!(matches!(read_message(&driver), Err(Error::BackendClosed)));
+    }
+
+    #[test]
+    fn read_oversized_message() {
+        let (mut backend, driver) = UnixStream::pair().unwrap();
+        let header = MessageHeader {
+            request: Le32::from(VHOST_USER_GET_PROTOCOL_FEATURES),
+            flags: Le32::from(0),
+            size: Le32::from(MAX_MESSAGE_PAYLOAD_SIZE as u32 + 1),
+        };
+        backend.write_all(header.as_slice()).unwrap();
+        assert!(matches!(
+            read_message(&driver),
+            Err(Error::MalformedMessage)
+        ));
+    }
+
+    // Forwards `message` and returns what the backend receives along with the attached fds.
+    fn forward(fds: &MessageFds, message: Vec<u8>) -> Result<(Vec<u8>, Vec<File>)> {
+        let (driver, backend) = UnixStream::pair().unwrap();
+        let len = message.len();
+        fds.forward(message, &driver)?;
+
+        let mut received = vec![0u8; len];
+        let mut raw_fds = [0 as RawFd; MAX_ATTACHED_FDS];
+        let (received_len, fd_count) = backend.recv_with_fds(&mut received, &mut raw_fds).unwrap();
+        assert_eq!(received_len, len);
+        let files = raw_fds[..fd_count]
+            .iter()
+            // Safe because we own the received fds.
+            .map(|fd| unsafe { File::from_raw_fd(*fd) })
+            .collect();
+        Ok((received, files))
+    }
+
+    #[test]
+    fn forward_mem_table() {
+        let shared_memory = tempfile::tempfile().unwrap();
+        let fds = MessageFds {
+            shared_memory: &shared_memory,
+            shared_memory_offset: 0x1000,
+            kick_evts: &[],
+            call_evts: &[],
+        };
+
+        let region = MemoryRegion {
+            guest_phys_addr: Le64::from(0),
+            memory_size: Le64::from(0x4000),
+            user_addr: Le64::from(0x7f00_0000),
+            mmap_offset: Le64::from(0x123),
+        };
+        let mut payload = vec![0u8; MEMORY_REGIONS_OFFSET];
+        payload[..4].copy_from_slice(&2u32.to_le_bytes());
+        payload.extend_from_slice(region.as_slice());
+        payload.extend_from_slice(region.as_slice());
+
+        let (received, files) = forward(&fds, message(VHOST_USER_SET_MEM_TABLE, &payload)).unwrap();
+        assert_eq!(files.len(), 2);
+        // The regions are mapped back to back into the shared memory BAR.
+        let regions = memory_regions(&received[MESSAGE_HEADER_SIZE..]).unwrap();
+        assert_eq!(regions[0].mmap_offset.to_native(), 0x1000);
+        assert_eq!(regions[1].mmap_offset.to_native(), 0x5000);
+        assert_eq!(regions[1].user_addr.to_native(), 0x7f00_0000);
+    }
+
+    #[test]
+    fn forward_vring_fds() {
+        let shared_memory = tempfile::tempfile().unwrap();
+        let kick_evts = [Event::new().unwrap(), Event::new().unwrap()];
+        let call_evts = [Event::new().unwrap(), Event::new().unwrap()];
+        let fds = MessageFds {
+            shared_memory: &shared_memory,
+            shared_memory_offset: 0,
+            kick_evts: &kick_evts,
+            call_evts: &call_evts,
+        };
+
+        let (received, mut files) = forward(
+            &fds,
+            message(VHOST_USER_SET_VRING_KICK, &1u64.to_le_bytes()),
+        )
+        .unwrap();
+        assert_eq!(
+            received,
+            message(VHOST_USER_SET_VRING_KICK, &1u64.to_le_bytes())
+        );
+        assert_eq!(files.len(), 1);
+        files[0].write_all(&1u64.to_ne_bytes()).unwrap();
+        assert_eq!(kick_evts[1].read().unwrap(), 1);
+
+        let (_, mut files) = forward(
+            &fds,
+            message(VHOST_USER_SET_VRING_CALL, &0u64.to_le_bytes()),
+        )
+        .unwrap();
+        assert_eq!(files.len(), 1);
+        files[0].write_all(&1u64.to_ne_bytes()).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+
+        // No fd is attached if the frontend didn't attach one.
+        let nofd = 1 | VHOST_USER_VRING_NOFD_MASK;
+        let (_, files) = forward(
+            &fds,
+            message(VHOST_USER_SET_VRING_CALL, &nofd.to_le_bytes()),
+        )
+        .unwrap();
+        assert!(files.is_empty());
+
+        assert!(matches!(
+            forward(
+                &fds,
+                message(VHOST_USER_SET_VRING_KICK, &2u64.to_le_bytes())
+            ),
+            Err(Error::VringIndex(2))
+        ));
+    }
+}
@@ -2,26 +2,106 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::io;
+//! Implements the virtio-vhost-user device, which exposes a vhost-user slave socket on the host to
+//! a driver in the guest so that a vhost-user device backend can run inside a VM.
+//!
+//! Messages from the vhost-user frontend are forwarded to the guest through the rx queue and the
+//! guest's replies are sent back through the tx queue. File descriptors cannot cross into the
+//! guest, so the device consumes the ones attached to messages itself:
+//!
+//! * The memory regions of `SET_MEM_TABLE` are mapped back to back into a shared memory BAR.
+//! * Kicks on the eventfds of `SET_VRING_KICK` are injected as the MSI-X vector the driver chose
+//!   for that vring in the notification structure.
+//! * Writes to a vring's doorbell are forwarded to the eventfd of `SET_VRING_CALL`.
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::thread;
 
-use base::{error, Event, EventType, PollToken, RawDescriptor, WaitContext};
-use data_model::{DataInit, Le32};
+use base::{
+    error, AsRawDescriptor, Descriptor, Error as SysError, Event, EventType, FromRawDescriptor,
+    IntoRawDescriptor, PollToken, RawDescriptor, SafeDescriptor, ScmSocket, Tube, TubeError,
+    WaitContext,
+};
+use data_model::{DataInit, Le16, Le32, Le64};
+use remain::sorted;
+use resources::Alloc;
+use sync::Mutex;
+use thiserror::Error as ThisError;
+use vm_control::{FsMappingRequest, VmResponse};
 use vm_memory::GuestMemory;
+use vmm_vhost::vhost_user::message::VhostUserProtocolFeatures;
 
+use crate::pci::{
+    PciAddress, PciBarConfiguration, PciBarIndex, PciBarPrefetchable, PciBarRegionType,
+    PciCapability,
+};
 use crate::virtio::descriptor_utils::Error as DescriptorUtilsError;
-use crate::virtio::{copy_config, Interrupt, Queue, Reader, VirtioDevice, Writer};
-
-use remain::sorted;
-use thiserror::Error as ThisError;
+use crate::virtio::{
+    copy_config, Interrupt, PciCapabilityType, Queue, Reader, SignalableInterrupt, VirtioDevice,
+    VirtioPciCap, VirtioPciNotifyCap, VirtioPciShmCap, Writer, TYPE_VHOST_USER,
+    VIRTIO_MSI_NO_VECTOR,
+};
 
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
-const TYPE_VIRTIO_VHOST_USER: u32 = 43;
+/// The maximum number of vrings the frontend may set up.
+pub const MAX_VHOST_QUEUES: usize = 16;
+
+/// Set by the driver in `VirtioVhostUserConfig::status` once the backend is ready.
+pub const VIRTIO_VHOST_USER_STATUS_SLAVE_UP: u32 = 1 << 0;
+/// Set by the device in `VirtioVhostUserConfig::status` while a frontend is connected.
+pub const VIRTIO_VHOST_USER_STATUS_MASTER_UP: u32 = 1 << 1;
+
+// The doorbells and the notification structure share one BAR.
+const DOORBELL_BAR_NUM: u8 = 2;
+const DOORBELL_BAR_SIZE: u64 = 0x2000;
+const DOORBELL_OFFSET: u64 = 0;
+const DOORBELL_OFFSET_MULTIPLIER: u32 = 4;
+const DOORBELL_SIZE: u64 = MAX_VHOST_QUEUES as u64 * DOORBELL_OFFSET_MULTIPLIER as u64;
+const NOTIFICATION_OFFSET: u64 = 0x1000;
+const NOTIFICATION_SIZE: u64 = size_of::<VirtioVhostUserNotification>() as u64;
+
+/// Offset of `notification_select` within the notification structure.
+pub const NOTIFICATION_SELECT_OFFSET: u64 = 0;
+/// Offset of `notification_msix_vector` within the notification structure.
+pub const NOTIFICATION_MSIX_VECTOR_OFFSET: u64 = 2;
+
+// The memory regions of the frontend are mapped into this BAR.
+const SHARED_MEMORY_BAR_NUM: u8 = 4;
+const SHARED_MEMORY_BAR_SIZE: u64 = 1 << 33;
+const SHARED_MEMORY_OFFSET: u64 = 0;
+const SHARED_MEMORY_ID: u8 = 0;
+
+/// Size of the header that starts every vhost-user message.
+pub const MESSAGE_HEADER_SIZE: usize = size_of::<MessageHeader>();
+/// The largest payload a vhost-user message may carry.
+pub const MAX_MESSAGE_PAYLOAD_SIZE: usize = 0x1000;
+/// The largest number of file descriptors attached to a vhost-user message.
+pub const MAX_ATTACHED_FDS: usize = 32;
+
+// Vhost-user requests carrying file descriptors the device has to deal with.
+pub const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+pub const VHOST_USER_SET_VRING_KICK: u32 = 12;
+pub const VHOST_USER_SET_VRING_CALL: u32 = 13;
+pub const VHOST_USER_SET_VRING_ERR: u32 = 14;
+
+/// Flag of `MessageHeader::flags` marking a reply.
+pub const VHOST_USER_REPLY_MASK: u32 = 1 << 2;
+/// The vring index of `SET_VRING_{KICK,CALL,ERR}` payloads.
+pub const VHOST_USER_VRING_INDEX_MASK: u64 = 0xff;
+/// Set in `SET_VRING_{KICK,CALL,ERR}` payloads when no file descriptor is attached.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 1 << 8;
 
 #[sorted]
 #[derive(ThisError, Debug)]
@@ -29,21 +109,60 @@ pub enum Error {
     /// Failed to accept connection on a socket.
     #[error("failed to accept connection on a socket: {0}")]
     AcceptConnection(std::io::Error),
+    /// Failed to allocate the shared memory region.
+    #[error("failed to allocate shared memory region: {0}")]
+    AllocateSharedMemory(SysError),
     /// Failed to create a listener.
     #[error("failed to create a listener: {0}")]
     CreateListener(std::io::Error),
     /// Failed to create a wait context object.
     #[error("failed to create a wait context object: {0}")]
     CreateWaitContext(base::Error),
-    /// There are no more available descriptors to receive into.
-    #[error("no rx descriptors available")]
-    RxDescriptorsExhausted,
-    /// Removing read event from the VhostVmmSocket fd events failed.
-    #[error("failed to disable EPOLLIN on VhostVmmSocket fd: {0}")]
-    WaitContextDisableVhostVmmSocket(base::Error),
-    /// Adding read event to the VhostVmmSocket fd events failed.
-    #[error("failed to enable EPOLLIN on VhostVmmSocket fd: {0}")]
-    WaitContextEnableVhostVmmSocket(base::Error),
+    /// A vring index was out of range.
+    #[error("invalid vring index: {0}")]
+    InvalidVringIndex(u64),
+    /// The message is malformed.
+    #[error("malformed message")]
+    MalformedMessage,
+    /// Failed to map a memory region of the frontend.
+    #[error("failed to map memory region: {0}")]
+    MapMemoryRegion(SysError),
+    /// The number of attached file descriptors doesn't match the memory regions.
+    #[error("{0} fds attached to a memory table of {1} regions")]
+    MemoryRegionFdMismatch(usize, usize),
+    /// The message from the frontend is larger than allowed.
+    #[error("message payload of {0} bytes is too large")]
+    MessageTooLarge(usize),
+    /// Failed to read a message from the frontend.
+    #[error("failed to read message from the frontend: {0}")]
+    ReadSocket(std::io::Error),
+    /// Failed to read a reply from the guest.
+    #[error("failed to read a reply from the guest: {0}")]
+    ReadTxBuffer(std::io::Error),
+    /// Failed to create a Reader.
+    #[error("failed to create a Reader: {0}")]
+    ReaderCreation(DescriptorUtilsError),
+    /// The rx buffer is too small for a message.
+    #[error("rx buffer of {0} bytes can't hold a message of {1} bytes")]
+    RxBufferTooSmall(usize, usize),
+    /// The frontend's memory doesn't fit in the shared memory BAR.
+    #[error("frontend memory doesn't fit in the shared memory BAR")]
+    SharedMemoryTooSmall,
+    /// Failed to receive a response from the main process.
+    #[error("failed to receive a response from the main process: {0}")]
+    TubeRecv(TubeError),
+    /// Failed to send a request to the main process.
+    #[error("failed to send a request to the main process: {0}")]
+    TubeSend(TubeError),
+    /// The main process responded unexpectedly.
+    #[error("unexpected response from the main process: {0:?}")]
+    UnexpectedResponse(VmResponse),
+    /// Failed to unmap a memory region of the frontend.
+    #[error("failed to unmap memory region: {0}")]
+    UnmapMemoryRegion(SysError),
+    /// Failed to update a wait context.
+    #[error("failed to update a wait context: {0}")]
+    WaitContextUpdate(base::Error),
     /// Failed to wait for events.
     #[error("failed to wait for events: {0}")]
     WaitError(base::Error),
@@ -53,93 +172,190 @@ pub enum Error {
     /// Failed to create a Writer.
     #[error("failed to create a Writer: {0}")]
     WriterCreation(DescriptorUtilsError),
+    /// Failed to send a reply to the frontend.
+    #[error("failed to send a reply to the frontend: {0}")]
+    WriteSocket(std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
-struct VirtioVhostUserConfig {
-    status: Le32,
-    max_vhost_queues: Le32,
-    uuid: [u8; 16],
+pub struct VirtioVhostUserConfig {
+    pub status: Le32,
+    pub max_vhost_queues: Le32,
+    pub uuid: [u8; 16],
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VirtioVhostUserConfig {}
 
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct VirtioVhostUserNotification {
+    notification_select: Le16,
+    notification_msix_vector: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VirtioVhostUserNotification {}
+
+/// The header of a vhost-user message.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct MessageHeader {
+    pub request: Le32,
+    pub flags: Le32,
+    pub size: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for MessageHeader {}
+
+/// A memory region in the payload of `SET_MEM_TABLE`, which starts with a `u32` region count
+/// followed by 4 bytes of padding.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: Le64,
+    pub memory_size: Le64,
+    pub user_addr: Le64,
+    pub mmap_offset: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for MemoryRegion {}
+
+/// Offset of the first `MemoryRegion` in the payload of `SET_MEM_TABLE`.
+pub const MEMORY_REGIONS_OFFSET: usize = 8;
+
+/// Returns the memory regions in the payload of a `SET_MEM_TABLE` message.
+pub fn memory_regions(payload: &[u8]) -> Option<Vec<MemoryRegion>> {
+    let count = u32::from_le_bytes(payload.get(0..4)?.try_into().ok()?) as usize;
+    let end = MEMORY_REGIONS_OFFSET + count.checked_mul(size_of::<MemoryRegion>())?;
+    payload
+        .get(MEMORY_REGIONS_OFFSET..end)?
+        .chunks(size_of::<MemoryRegion>())
+        .map(|chunk| MemoryRegion::from_slice(chunk).copied())
+        .collect()
+}
+
+/// Returns the payload of a `SET_VRING_{KICK,CALL,ERR}` message.
+pub fn vring_payload(payload: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(payload.get(0..8)?.try_into().ok()?))
+}
+
+/// Returns the end offset of a frontend memory region of `size` bytes placed at `mem_offset` in the
+/// shared memory BAR, or an error if the region doesn't fit.
+fn shared_memory_region_end(mem_offset: usize, size: u64) -> Result<usize> {
+    usize::try_from(size)
+        .ok()
+        .and_then(|size| mem_offset.checked_add(size))
+        .filter(|&end| end as u64 <= SHARED_MEMORY_BAR_SIZE)
+        .ok_or(Error::SharedMemoryTooSmall)
+}
+
+/// State of the vrings shared between the worker and the BAR handlers.
+struct Vrings {
+    call_evts: Vec<Option<Event>>,
+    notification_vectors: Vec<u16>,
+}
+
+impl Vrings {
+    fn new() -> Vrings {
+        Vrings {
+            call_evts: (0..MAX_VHOST_QUEUES).map(|_| None).collect(),
+            notification_vectors: vec![VIRTIO_MSI_NO_VECTOR; MAX_VHOST_QUEUES],
+        }
+    }
+}
+
+#[derive(PollToken, Debug, Clone)]
+enum Token {
+    // A frontend is connecting to the listener.
+    Listener,
+    // Data is available on the frontend's socket.
+    Connection,
+    // The vhost-device has made a read buffer available.
+    RxQueue,
+    // The vhost-device has sent a buffer to the |Worker::tx_queue|.
+    TxQueue,
+    // The frontend kicked a vring.
+    Kick { index: usize },
+    // Check if any interrupts need to be re-asserted.
+    InterruptResample,
+    // crosvm has requested the device to shut down.
+    Kill,
+}
+
 struct Worker {
     mem: GuestMemory,
     interrupt: Interrupt,
     rx_queue: Queue,
     tx_queue: Queue,
-    vhost_vmm_socket: UnixStream,
+    listener: UnixListener,
+    connection: Option<UnixStream>,
+    // A message from the frontend waiting for a buffer in the rx queue.
+    pending_message: Option<Vec<u8>>,
+    mapper: Tube,
+    shared_memory_slot: u32,
+    // The (offset, size) of each memory region mapped into the shared memory BAR.
+    mappings: Vec<(usize, usize)>,
+    kick_evts: Vec<Option<Event>>,
+    vrings: Arc<Mutex<Vrings>>,
+    status: Arc<AtomicU32>,
 }
 
 impl Worker {
     fn run(&mut self, rx_queue_evt: Event, tx_queue_evt: Event, kill_evt: Event) -> Result<()> {
-        #[derive(PollToken, Debug, Clone)]
-        pub enum Token {
-            // Data is available on the vhost vmm socket.
-            VhostVmmSocket,
-            // The vhost-device has made a read buffer available.
-            RxQueue,
-            // The vhost-device has sent a buffer to the |Worker::tx_queue|.
-            TxQueue,
-            // crosvm has requested the device to shut down.
-            Kill,
-        }
-
         let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
-            (&self.vhost_vmm_socket, Token::VhostVmmSocket),
+            (&Descriptor(self.listener.as_raw_fd()), Token::Listener),
             (&rx_queue_evt, Token::RxQueue),
             (&tx_queue_evt, Token::TxQueue),
             (&kill_evt, Token::Kill),
         ])
         .map_err(Error::CreateWaitContext)?;
+        if let Some(resample_evt) = self.interrupt.get_resample_evt() {
+            wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .map_err(Error::CreateWaitContext)?;
+        }
 
-        let mut vhost_vmm_socket_polling_enabled = true;
         'wait: loop {
             let events = wait_ctx.wait().map_err(Error::WaitError)?;
             for event in events.iter().filter(|e| e.is_readable) {
                 match event.token {
-                    Token::VhostVmmSocket => match self.process_rx() {
-                        Ok(()) => {}
-                        Err(Error::RxDescriptorsExhausted) => {
-                            wait_ctx
-                                .modify(
-                                    &self.vhost_vmm_socket,
-                                    EventType::None,
-                                    Token::VhostVmmSocket,
-                                )
-                                .map_err(Error::WaitContextDisableVhostVmmSocket)?;
-                            vhost_vmm_socket_polling_enabled = false;
+                    Token::Listener => self.accept(&wait_ctx)?,
+                    Token::Connection => {
+                        if let Err(e) = self.process_message(&wait_ctx) {
+                            error!("dropping vhost-user frontend: {}", e);
+                            self.disconnect(&wait_ctx)?;
                         }
-                        Err(e) => return Err(e),
-                    },
+                    }
                     Token::RxQueue => {
                         if let Err(e) = rx_queue_evt.read() {
-                            error!("net: error reading rx queue Event: {}", e);
+                            error!("error reading rx queue event: {}", e);
                             break 'wait;
                         }
-                        if !vhost_vmm_socket_polling_enabled {
-                            wait_ctx
-                                .modify(
-                                    &self.vhost_vmm_socket,
-                                    EventType::Read,
-                                    Token::VhostVmmSocket,
-                                )
-                                .map_err(Error::WaitContextEnableVhostVmmSocket)?;
-                            vhost_vmm_socket_polling_enabled = true;
-                        }
+                        self.process_rx(&wait_ctx)?;
                     }
                     Token::TxQueue => {
                         if let Err(e) = tx_queue_evt.read() {
-                            error!("error reading rx queue event: {}", e);
+                            error!("error reading tx queue event: {}", e);
                             break 'wait;
                         }
                         self.process_tx();
                     }
+                    Token::Kick { index } => {
+                        if let Some(evt) = &self.kick_evts[index] {
+                            let _ = evt.read();
+                        }
+                        let vector = self.vrings.lock().notification_vectors[index];
+                        self.interrupt.signal_used_queue(vector);
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
                     Token::Kill => {
                         let _ = kill_evt.read();
                         break 'wait;
@@ -147,155 +363,439 @@ impl Worker {
                 }
             }
         }
+        self.release()
+    }
+
+    fn accept(&mut self, wait_ctx: &WaitContext<Token>) -> Result<()> {
+        let (socket, _) = self.listener.accept().map_err(Error::AcceptConnection)?;
+
+        // Serve one frontend at a time.
+        wait_ctx
+            .delete(&Descriptor(self.listener.as_raw_fd()))
+            .map_err(Error::WaitContextUpdate)?;
+        wait_ctx
+            .add(&socket, Token::Connection)
+            .map_err(Error::WaitContextUpdate)?;
+        self.connection = Some(socket);
+
+        self.status
+            .fetch_or(VIRTIO_VHOST_USER_STATUS_MASTER_UP, Ordering::SeqCst);
+        self.interrupt.signal_config_changed();
         Ok(())
     }
 
-    fn process_rx(&mut self) -> Result<()> {
-        let mut exhausted_queue = false;
+    fn disconnect(&mut self, wait_ctx: &WaitContext<Token>) -> Result<()> {
+        if let Some(connection) = &self.connection {
+            wait_ctx
+                .delete(connection)
+                .map_err(Error::WaitContextUpdate)?;
+        }
+        for evt in self.kick_evts.iter().flatten() {
+            wait_ctx.delete(evt).map_err(Error::WaitContextUpdate)?;
+        }
+        self.release()?;
 
-        // Read as many frames as possible.
-        loop {
-            let desc_chain = match self.rx_queue.peek(&self.mem) {
-                Some(desc) => desc,
-                None => {
-                    exhausted_queue = true;
-                    break;
-                }
-            };
+        self.status
+            .fetch_and(!VIRTIO_VHOST_USER_STATUS_MASTER_UP, Ordering::SeqCst);
+        self.interrupt.signal_config_changed();
 
-            let index = desc_chain.index;
-            let bytes_written = match Writer::new(self.mem.clone(), desc_chain) {
-                Ok(mut writer) => {
-                    match writer.write_from(&mut self.vhost_vmm_socket, writer.available_bytes()) {
-                        Ok(_) => {}
-                        Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {
-                            error!("rx: buffer is too small to hold frame");
-                            break;
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            // No more to read.
-                            break;
-                        }
-                        Err(e) => {
-                            error!("rx: failed to write slice: {}", e);
-                            return Err(Error::WriteBuffer(e));
-                        }
-                    };
+        wait_ctx
+            .add(&Descriptor(self.listener.as_raw_fd()), Token::Listener)
+            .map_err(Error::WaitContextUpdate)
+    }
+
+    // Drops everything the current frontend set up.
+    fn release(&mut self) -> Result<()> {
+        self.connection = None;
+        self.pending_message = None;
+        self.kick_evts.iter_mut().for_each(|evt| *evt = None);
+        self.vrings
+            .lock()
+            .call_evts
+            .iter_mut()
+            .for_each(|evt| *evt = None);
+        self.unmap_memory()
+    }
+
+    // Reads a message from the frontend, handles the attached file descriptors and forwards the
+    // message to the guest.
+    fn process_message(&mut self, wait_ctx: &WaitContext<Token>) -> Result<()> {
+        let connection = match &self.connection {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let mut message = vec![0u8; MESSAGE_HEADER_SIZE];
+        let mut fds = [0 as RawFd; MAX_ATTACHED_FDS];
+        let (len, fd_count) = connection
+            .recv_with_fds(&mut message, &mut fds)
+            .map_err(|e| Error::ReadSocket(e.into()))?;
+        let files: Vec<File> = fds[..fd_count]
+            .iter()
+            // Safe because we own the received fds.
+            .map(|fd| unsafe { File::from_raw_fd(*fd) })
+            .collect();
+        if len == 0 {
+            return self.disconnect(wait_ctx);
+        }
+        let mut connection_ref = connection;
+        connection_ref
+            .read_exact(&mut message[len..])
+            .map_err(Error::ReadSocket)?;
+
+        let header = *MessageHeader::from_slice(&message).ok_or(Error::MalformedMessage)?;
+        let size = header.size.to_native() as usize;
+        if size > MAX_MESSAGE_PAYLOAD_SIZE {
+            return Err(Error::MessageTooLarge(size));
+        }
+        message.resize(MESSAGE_HEADER_SIZE + size, 0);
+        connection_ref
+            .read_exact(&mut message[MESSAGE_HEADER_SIZE..])
+            .map_err(Error::ReadSocket)?;
 
-                    writer.bytes_written() as u32
+        let payload = &mut message[MESSAGE_HEADER_SIZE..];
+        match header.request.to_native() {
+            VHOST_USER_SET_MEM_TABLE => self.set_mem_table(payload, files)?,
+            VHOST_USER_SET_VRING_KICK => {
+                let (index, evt) = vring_event(payload, files)?;
+                if let Some(old) = self.kick_evts[index].take() {
+                    wait_ctx.delete(&old).map_err(Error::WaitContextUpdate)?;
                 }
-                Err(e) => {
-                    error!("failed to create Writer: {}", e);
-                    0
+                if let Some(evt) = &evt {
+                    wait_ctx
+                        .add(evt, Token::Kick { index })
+                        .map_err(Error::WaitContextUpdate)?;
                 }
-            };
-
-            // The driver is able to deal with a descriptor with 0 bytes written.
-            self.rx_queue.pop_peeked(&self.mem);
-            self.rx_queue.add_used(&self.mem, index, bytes_written);
-            self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+                self.kick_evts[index] = evt;
+            }
+            VHOST_USER_SET_VRING_CALL => {
+                let (index, evt) = vring_event(payload, files)?;
+                self.vrings.lock().call_evts[index] = evt;
+            }
+            VHOST_USER_SET_VRING_ERR => drop_vring_err_fd(payload)?,
+            _ => {}
         }
 
-        if exhausted_queue {
-            Err(Error::RxDescriptorsExhausted)
+        self.pending_message = Some(message);
+        self.process_rx(wait_ctx)
+    }
+
+    // Delivers the pending message to the guest and resumes reading from the frontend once it
+    // was delivered.
+    fn process_rx(&mut self, wait_ctx: &WaitContext<Token>) -> Result<()> {
+        let message = match self.pending_message.take() {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        let connection = match &self.connection {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let desc_chain = match self.rx_queue.pop(&self.mem) {
+            Some(desc) => desc,
+            None => {
+                // Stop reading from the frontend until the driver provides a buffer.
+                self.pending_message = Some(message);
+                return wait_ctx
+                    .modify(connection, EventType::None, Token::Connection)
+                    .map_err(Error::WaitContextUpdate);
+            }
+        };
+
+        let index = desc_chain.index;
+        let mut writer =
+            Writer::new(self.mem.clone(), desc_chain).map_err(Error::WriterCreation)?;
+        let result = if writer.available_bytes() < message.len() {
+            Err(Error::RxBufferTooSmall(
+                writer.available_bytes(),
+                message.len(),
+            ))
         } else {
-            Ok(())
-        }
+            writer.write_all(&message).map_err(Error::WriteBuffer)
+        };
+        self.rx_queue
+            .add_used(&self.mem, index, writer.bytes_written() as u32);
+        self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+        result?;
+
+        wait_ctx
+            .modify(connection, EventType::Read, Token::Connection)
+            .map_err(Error::WaitContextUpdate)
     }
 
     fn process_tx(&mut self) {
         while let Some(desc_chain) = self.tx_queue.pop(&self.mem) {
             let index = desc_chain.index;
-            match Reader::new(self.mem.clone(), desc_chain) {
-                Ok(mut reader) => {
-                    let expected_count = reader.available_bytes();
-                    match reader.read_to(&mut self.vhost_vmm_socket, expected_count) {
-                        Ok(count) => {
-                            // Datagram messages should be sent as whole.
-                            // TODO: Should this be a panic! as it will violate the Linux API.
-                            if count != expected_count {
-                                error!("wrote only {} bytes of {}", count, expected_count);
-                            }
-                        }
-                        Err(e) => error!("failed to write message to vhost-vmm: {}", e),
-                    }
-                }
-                Err(e) => error!("failed to create Reader: {}", e),
+            if let Err(e) = self.send_reply(desc_chain) {
+                error!("failed to forward reply to the frontend: {}", e);
             }
             self.tx_queue.add_used(&self.mem, index, 0);
             self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
         }
     }
+
+    fn send_reply(&mut self, desc_chain: crate::virtio::DescriptorChain) -> Result<()> {
+        let mut reader =
+            Reader::new(self.mem.clone(), desc_chain).map_err(Error::ReaderCreation)?;
+        let mut reply = Vec::with_capacity(reader.available_bytes());
+        reader
+            .read_to_end(&mut reply)
+            .map_err(Error::ReadTxBuffer)?;
+
+        filter_reply(&mut reply)?;
+
+        match &self.connection {
+            Some(connection) => {
+                let mut connection: &UnixStream = connection;
+                connection.write_all(&reply).map_err(Error::WriteSocket)
+            }
+            // The frontend is gone, so there's no one to reply to.
+            None => Ok(()),
+        }
+    }
+
+    fn set_mem_table(&mut self, payload: &[u8], files: Vec<File>) -> Result<()> {
+        self.unmap_memory()?;
+
+        let regions = memory_regions(payload).ok_or(Error::MalformedMessage)?;
+        if regions.len() != files.len() {
+            return Err(Error::MemoryRegionFdMismatch(files.len(), regions.len()));
+        }
+
+        let mut mem_offset = 0usize;
+        for (region, file) in regions.iter().zip(files.into_iter()) {
+            let end = shared_memory_region_end(mem_offset, region.memory_size.to_native())?;
+            let size = end - mem_offset;
+
+            let request = FsMappingRequest::CreateMemoryMapping {
+                slot: self.shared_memory_slot,
+                fd: SafeDescriptor::from(file),
+                size,
+                file_offset: region.mmap_offset.to_native(),
+                prot: (libc::PROT_READ | libc::PROT_WRITE) as u32,
+                mem_offset,
+            };
+            self.mapper.send(&request).map_err(Error::TubeSend)?;
+            match self.mapper.recv().map_err(Error::TubeRecv)? {
+                VmResponse::Ok => {}
+                VmResponse::Err(e) => return Err(Error::MapMemoryRegion(e)),
+                r => return Err(Error::UnexpectedResponse(r)),
+            }
+            self.mappings.push((mem_offset, size));
+            mem_offset = end;
+        }
+
+        Ok(())
+    }
+
+    fn unmap_memory(&mut self) -> Result<()> {
+        for (offset, size) in self.mappings.drain(..) {
+            let request = FsMappingRequest::RemoveMemoryMapping {
+                slot: self.shared_memory_slot,
+                offset,
+                size,
+            };
+            self.mapper.send(&request).map_err(Error::TubeSend)?;
+            match self.mapper.recv().map_err(Error::TubeRecv)? {
+                VmResponse::Ok => {}
+                VmResponse::Err(e) => return Err(Error::UnmapMemoryRegion(e)),
+                r => return Err(Error::UnexpectedResponse(r)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Errors aren't reported through the guest, so tells the backend that there is no eventfd for
+// them in the payload of a `SET_VRING_ERR` message.
+fn drop_vring_err_fd(payload: &mut [u8]) -> Result<()> {
+    let value = vring_payload(payload).ok_or(Error::MalformedMessage)?;
+    payload[..8].copy_from_slice(&(value | VHOST_USER_VRING_NOFD_MASK).to_le_bytes());
+    Ok(())
+}
+
+// Hides the protocol features that require file descriptors the device doesn't handle from a
+// reply of the backend.
+fn filter_reply(reply: &mut [u8]) -> Result<()> {
+    let header = *MessageHeader::from_slice(
+        reply
+            .get(..MESSAGE_HEADER_SIZE)
+            .ok_or(Error::MalformedMessage)?,
+    )
+    .ok_or(Error::MalformedMessage)?;
+    if header.request.to_native() == VHOST_USER_GET_PROTOCOL_FEATURES
+        && header.flags.to_native() & VHOST_USER_REPLY_MASK != 0
+    {
+        let payload = &mut reply[MESSAGE_HEADER_SIZE..];
+        let features = vring_payload(payload).ok_or(Error::MalformedMessage)?;
+        let supported = (VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::REPLY_ACK
+            | VhostUserProtocolFeatures::CONFIG)
+            .bits();
+        payload[..8].copy_from_slice(&(features & supported).to_le_bytes());
+    }
+    Ok(())
+}
+
+// Returns the vring index and the eventfd of a `SET_VRING_{KICK,CALL}` message.
+fn vring_event(payload: &[u8], mut files: Vec<File>) -> Result<(usize, Option<Event>)> {
+    let value = vring_payload(payload).ok_or(Error::MalformedMessage)?;
+    let index = value & VHOST_USER_VRING_INDEX_MASK;
+    if index as usize >= MAX_VHOST_QUEUES {
+        return Err(Error::InvalidVringIndex(index));
+    }
+
+    let evt = if value & VHOST_USER_VRING_NOFD_MASK != 0 {
+        None
+    } else {
+        let file = files.pop().ok_or(Error::MalformedMessage)?;
+        // Safe because we own the file.
+        Some(unsafe { Event::from_raw_descriptor(file.into_raw_descriptor()) })
+    };
+    Ok((index as usize, evt))
 }
 
 pub struct VirtioVhostUser {
-    vhost_vmm_socket: Option<UnixStream>,
-    config: VirtioVhostUserConfig,
+    base_features: u64,
+    listener: Option<UnixListener>,
+    mapper: Option<Tube>,
+    pci_bar: Option<Alloc>,
+    shared_memory_slot: Option<u32>,
+    status: Arc<AtomicU32>,
+    vrings: Arc<Mutex<Vrings>>,
+    notification_select: u16,
     kill_evt: Option<Event>,
-    worker_thread: Option<thread::JoinHandle<Worker>>,
+    worker_thread: Option<thread::JoinHandle<Result<Worker>>>,
 }
 
 impl VirtioVhostUser {
-    pub fn new(vhost_vmm_socket_path: &Path) -> Result<VirtioVhostUser> {
-        let listener = UnixListener::bind(vhost_vmm_socket_path).map_err(Error::CreateListener)?;
-        let (socket, _) = listener.accept().map_err(Error::AcceptConnection)?;
+    /// Creates a virtio-vhost-user device that listens for a vhost-user frontend on
+    /// `socket_path`. `mapper` is used to map the frontend's memory into the device's BAR.
+    pub fn new(base_features: u64, socket_path: &Path, mapper: Tube) -> Result<VirtioVhostUser> {
+        let listener = UnixListener::bind(socket_path).map_err(Error::CreateListener)?;
         Ok(VirtioVhostUser {
-            vhost_vmm_socket: Some(socket),
-            config: Default::default(),
+            base_features,
+            listener: Some(listener),
+            mapper: Some(mapper),
+            pci_bar: None,
+            shared_memory_slot: None,
+            status: Arc::new(AtomicU32::new(0)),
+            vrings: Arc::new(Mutex::new(Vrings::new())),
+            notification_select: 0,
             kill_evt: None,
             worker_thread: None,
         })
     }
-}
 
-impl Drop for VirtioVhostUser {
-    fn drop(&mut self) {
+    fn config(&self) -> VirtioVhostUserConfig {
+        VirtioVhostUserConfig {
+            status: Le32::from(self.status.load(Ordering::SeqCst)),
+            max_vhost_queues: Le32::from(MAX_VHOST_QUEUES as u32),
+            uuid: [0; 16],
+        }
+    }
+
+    fn allocate_shared_memory(&mut self, mapper: &Tube) -> Result<u32> {
+        if let Some(slot) = self.shared_memory_slot {
+            return Ok(slot);
+        }
+
+        let request = FsMappingRequest::AllocateSharedMemoryRegion(
+            self.pci_bar
+                .as_ref()
+                .cloned()
+                .expect("shared memory BAR not allocated"),
+        );
+        mapper.send(&request).map_err(Error::TubeSend)?;
+        let slot = match mapper.recv().map_err(Error::TubeRecv)? {
+            VmResponse::RegisterMemory { pfn: _, slot } => slot,
+            VmResponse::Err(e) => return Err(Error::AllocateSharedMemory(e)),
+            r => return Err(Error::UnexpectedResponse(r)),
+        };
+        self.shared_memory_slot = Some(slot);
+        Ok(slot)
+    }
+
+    fn stop_worker(&mut self) -> Option<Worker> {
         if let Some(kill_evt) = self.kill_evt.take() {
-            match kill_evt.write(1) {
-                Ok(()) => {
-                    if let Some(worker_thread) = self.worker_thread.take() {
-                        // Ignore the result because there is nothing we can do about it.
-                        let _ = worker_thread.join();
-                    }
-                }
-                Err(e) => error!("failed to write kill event: {}", e),
+            if let Err(e) = kill_evt.write(1) {
+                error!("failed to write kill event: {}", e);
+                return None;
             }
         }
+
+        match self.worker_thread.take()?.join() {
+            Ok(Ok(worker)) => Some(worker),
+            Ok(Err(e)) => {
+                error!("virtio-vhost-user worker failed: {}", e);
+                None
+            }
+            Err(e) => {
+                error!("virtio-vhost-user worker panicked: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for VirtioVhostUser {
+    fn drop(&mut self) {
+        self.stop_worker();
     }
 }
 
 impl VirtioDevice for VirtioVhostUser {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        vec![]
+        let mut rds = Vec::new();
+        if let Some(listener) = &self.listener {
+            rds.push(listener.as_raw_fd());
+        }
+        if let Some(mapper) = &self.mapper {
+            rds.push(mapper.as_raw_descriptor());
+        }
+        rds
     }
 
     fn device_type(&self) -> u32 {
-        TYPE_VIRTIO_VHOST_USER
+        TYPE_VHOST_USER
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
         QUEUE_SIZES
     }
 
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         copy_config(
             data,
             0, /* dst_offset */
-            self.config.as_slice(),
+            self.config().as_slice(),
             offset,
         );
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        // TODO: Confirm if |data| is guaranteed to be sent in LE.
-        copy_config(
-            self.config.as_mut_slice(),
-            offset,
-            data,
-            0, /* src_offset */
+        let mut config = self.config();
+        copy_config(config.as_mut_slice(), offset, data, 0 /* src_offset */);
+
+        // Only the SLAVE_UP bit is writable by the driver.
+        let slave_up = config.status.to_native() & VIRTIO_VHOST_USER_STATUS_SLAVE_UP;
+        let status = self.status.load(Ordering::SeqCst);
+        self.status.store(
+            (status & !VIRTIO_VHOST_USER_STATUS_SLAVE_UP) | slave_up,
+            Ordering::SeqCst,
         );
     }
 
+    fn extra_msix_vectors(&self) -> usize {
+        // One vector per vring for the notifications.
+        MAX_VHOST_QUEUES
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemory,
@@ -308,6 +808,23 @@ impl VirtioDevice for VirtioVhostUser {
             return;
         }
 
+        let (listener, mapper) = match (self.listener.take(), self.mapper.take()) {
+            (Some(l), Some(m)) => (l, m),
+            _ => {
+                error!("virtio-vhost-user activated twice");
+                return;
+            }
+        };
+        let shared_memory_slot = match self.allocate_shared_memory(&mapper) {
+            Ok(slot) => slot,
+            Err(e) => {
+                error!("{}", e);
+                self.listener = Some(listener);
+                self.mapper = Some(mapper);
+                return;
+            }
+        };
+
         let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
             Ok(v) => v,
             Err(e) => {
@@ -317,13 +834,8 @@ impl VirtioDevice for VirtioVhostUser {
         };
         self.kill_evt = Some(self_kill_evt);
 
-        // The socket will be moved to the worker thread. Guaranteed to be valid as a connection is
-        // ensured in |VirtioVhostUser::new|.
-        let vhost_vmm_socket = self
-            .vhost_vmm_socket
-            .take()
-            .expect("socket connection missing");
-
+        let vrings = self.vrings.clone();
+        let status = self.status.clone();
         let worker_result = thread::Builder::new()
             .name("virtio_vhost_user".to_string())
             .spawn(move || {
@@ -334,12 +846,20 @@ impl VirtioDevice for VirtioVhostUser {
                     interrupt,
                     rx_queue,
                     tx_queue,
-                    vhost_vmm_socket,
+                    listener,
+                    connection: None,
+                    pending_message: None,
+                    mapper,
+                    shared_memory_slot,
+                    mappings: Vec::new(),
+                    kick_evts: (0..MAX_VHOST_QUEUES).map(|_| None).collect(),
+                    vrings,
+                    status,
                 };
                 let rx_queue_evt = queue_evts.remove(0);
                 let tx_queue_evt = queue_evts.remove(0);
-                let _ = worker.run(rx_queue_evt, tx_queue_evt, kill_evt);
-                worker
+                worker.run(rx_queue_evt, tx_queue_evt, kill_evt)?;
+                Ok(worker)
             });
 
         match worker_result {
@@ -353,7 +873,247 @@ impl VirtioDevice for VirtioVhostUser {
     }
 
     fn reset(&mut self) -> bool {
-        // TODO
+        let worker = match self.stop_worker() {
+            Some(worker) => worker,
+            None => return false,
+        };
+        self.listener = Some(worker.listener);
+        self.mapper = Some(worker.mapper);
+        self.status.store(0, Ordering::SeqCst);
+        *self.vrings.lock() = Vrings::new();
+        self.notification_select = 0;
         true
     }
+
+    fn get_device_bars(&mut self, address: PciAddress) -> Vec<PciBarConfiguration> {
+        self.pci_bar = Some(Alloc::PciBar {
+            bus: address.bus,
+            dev: address.dev,
+            func: address.func,
+            bar: SHARED_MEMORY_BAR_NUM,
+        });
+
+        vec![
+            PciBarConfiguration::new(
+                DOORBELL_BAR_NUM as usize,
+                DOORBELL_BAR_SIZE,
+                PciBarRegionType::Memory64BitRegion,
+                PciBarPrefetchable::NotPrefetchable,
+            ),
+            PciBarConfiguration::new(
+                SHARED_MEMORY_BAR_NUM as usize,
+                SHARED_MEMORY_BAR_SIZE,
+                PciBarRegionType::Memory64BitRegion,
+                PciBarPrefetchable::NotPrefetchable,
+            ),
+        ]
+    }
+
+    fn get_device_caps(&self) -> Vec<Box<dyn PciCapability>> {
+        vec![
+            Box::new(VirtioPciNotifyCap::new(
+                PciCapabilityType::DoorbellConfig,
+                DOORBELL_BAR_NUM,
+                DOORBELL_OFFSET as u32,
+                DOORBELL_SIZE as u32,
+                Le32::from(DOORBELL_OFFSET_MULTIPLIER),
+            )),
+            Box::new(VirtioPciCap::new(
+                PciCapabilityType::NotificationConfig,
+                DOORBELL_BAR_NUM,
+                NOTIFICATION_OFFSET as u32,
+                NOTIFICATION_SIZE as u32,
+            )),
+            Box::new(VirtioPciShmCap::new(
+                PciCapabilityType::SharedMemoryConfig,
+                SHARED_MEMORY_BAR_NUM,
+                SHARED_MEMORY_OFFSET,
+                SHARED_MEMORY_BAR_SIZE,
+                SHARED_MEMORY_ID,
+            )),
+        ]
+    }
+
+    fn read_bar(&mut self, bar_index: PciBarIndex, offset: u64, data: &mut [u8]) {
+        if bar_index != DOORBELL_BAR_NUM as usize || data.len() != 2 {
+            return;
+        }
+
+        let value = match offset.checked_sub(NOTIFICATION_OFFSET) {
+            Some(NOTIFICATION_SELECT_OFFSET) => self.notification_select,
+            Some(NOTIFICATION_MSIX_VECTOR_OFFSET) => self
+                .vrings
+                .lock()
+                .notification_vectors
+                .get(self.notification_select as usize)
+                .copied()
+                .unwrap_or(VIRTIO_MSI_NO_VECTOR),
+            _ => return,
+        };
+        data.copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_bar(&mut self, bar_index: PciBarIndex, offset: u64, data: &[u8]) {
+        if bar_index != DOORBELL_BAR_NUM as usize {
+            return;
+        }
+
+        if (DOORBELL_OFFSET..DOORBELL_OFFSET + DOORBELL_SIZE).contains(&offset) {
+            let index = ((offset - DOORBELL_OFFSET) / DOORBELL_OFFSET_MULTIPLIER as u64) as usize;
+            if let Some(evt) = &self.vrings.lock().call_evts[index] {
+                if let Err(e) = evt.write(1) {
+                    error!("failed to signal vring {}: {}", index, e);
+                }
+            }
+            return;
+        }
+
+        let value = match data.try_into() {
+            Ok(bytes) => u16::from_le_bytes(bytes),
+            Err(_) => return,
+        };
+        match offset.checked_sub(NOTIFICATION_OFFSET) {
+            Some(NOTIFICATION_SELECT_OFFSET) => self.notification_select = value,
+            Some(NOTIFICATION_MSIX_VECTOR_OFFSET) => {
+                if let Some(vector) = self
+                    .vrings
+                    .lock()
+                    .notification_vectors
+                    .get_mut(self.notification_select as usize)
+                {
+                    *vector = value;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(request: u32, flags: u32, payload: &[u8]) -> Vec<u8> {
+        let header = MessageHeader {
+            request: Le32::from(request),
+            flags: Le32::from(flags),
+            size: Le32::from(payload.len() as u32),
+        };
+        let mut message = header.as_slice().to_vec();
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn parse_memory_regions() {
+        let region = MemoryRegion {
+            guest_phys_addr: Le64::from(0x1000),
+            memory_size: Le64::from(0x2000),
+            user_addr: Le64::from(0x7f00_0000),
+            mmap_offset: Le64::from(0x100),
+        };
+        let mut payload = vec![0u8; MEMORY_REGIONS_OFFSET];
+        payload[..4].copy_from_slice(&2u32.to_le_bytes());
+        payload.extend_from_slice(region.as_slice());
+        payload.extend_from_slice(region.as_slice());
+
+        let regions = memory_regions(&payload).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[1].guest_phys_addr.to_native(), 0x1000);
+        assert_eq!(regions[1].memory_size.to_native(), 0x2000);
+        assert_eq!(regions[1].mmap_offset.to_native(), 0x100);
+
+        // The count claims more regions than the payload holds.
+        payload[..4].copy_from_slice(&3u32.to_le_bytes());
+        assert!(memory_regions(&payload).is_none());
+    }
+
+    #[test]
+    fn shared_memory_region_bounds() {
+        assert_eq!(shared_memory_region_end(0x1000, 0x2000).unwrap(), 0x3000);
+        let bar_size = SHARED_MEMORY_BAR_SIZE as usize;
+        assert_eq!(
+            shared_memory_region_end(0, SHARED_MEMORY_BAR_SIZE).unwrap(),
+            bar_size
+        );
+        assert!(matches!(
+            shared_memory_region_end(bar_size, 1),
+            Err(Error::SharedMemoryTooSmall)
+        ));
+        // Sizes chosen by the frontend must not overflow the offset.
+        assert!(matches!(
+            shared_memory_region_end(0x1000, u64::MAX),
+            Err(Error::SharedMemoryTooSmall)
+        ));
+    }
+
+    #[test]
+    fn vring_event_with_fd() {
+        let evt = Event::new().unwrap();
+        // Safe because we own the cloned descriptor.
+        let file = unsafe { File::from_raw_fd(evt.try_clone().unwrap().into_raw_descriptor()) };
+        let (index, vring_evt) = vring_event(&3u64.to_le_bytes(), vec![file]).unwrap();
+        assert_eq!(index, 3);
+        vring_evt.unwrap().write(1).unwrap();
+        assert_eq!(evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn vring_event_without_fd() {
+        let value = 2 | VHOST_USER_VRING_NOFD_MASK;
+        let (index, evt) = vring_event(&value.to_le_bytes(), Vec::new()).unwrap();
+        assert_eq!(index, 2);
+        assert!(evt.is_none());
+
+        // The fd is missing even though the message says there is one.
+        assert!(matches!(
+            vring_event(&2u64.to_le_bytes(), Vec::new()),
+            Err(Error::MalformedMessage)
+        ));
+        assert!(matches!(
+            vring_event(&(MAX_VHOST_QUEUES as u64).to_le_bytes(), Vec::new()),
+            Err(Error::InvalidVringIndex(_))
+        ));
+    }
+
+    #[test]
+    fn vring_err_fd_dropped() {
+        let mut payload = 5u64.to_le_bytes();
+        drop_vring_err_fd(&mut payload).unwrap();
+        assert_eq!(u64::from_le_bytes(payload), 5 | VHOST_USER_VRING_NOFD_MASK);
+
+        assert!(matches!(
+            drop_vring_err_fd(&mut [0u8; 4]),
+            Err(Error::MalformedMessage)
+        ));
+    }
+
+    #[test]
+    fn protocol_features_filtered() {
+        let features = VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::SLAVE_REQ
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD;
+        let mut reply = message(
+            VHOST_USER_GET_PROTOCOL_FEATURES,
+            VHOST_USER_REPLY_MASK,
+            &features.bits().to_le_bytes(),
+        );
+        filter_reply(&mut reply).unwrap();
+        assert_eq!(
+            vring_payload(&reply[MESSAGE_HEADER_SIZE..]),
+            Some((VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG).bits())
+        );
+
+        // Other replies pass through unchanged.
+        let original = message(VHOST_USER_SET_VRING_CALL, VHOST_USER_REPLY_MASK, &[0xff; 8]);
+        let mut reply = original.clone();
+        filter_reply(&mut reply).unwrap();
+        assert_eq!(reply, original);
+
+        assert!(matches!(
+            filter_reply(&mut [0u8; 4]),
+            Err(Error::MalformedMessage)
+        ));
+    }
 }
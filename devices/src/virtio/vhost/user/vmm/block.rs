@@ -2,19 +2,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::cell::RefCell;
-use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::Arc;
 use std::thread;
 use std::u32;
 
 use base::{error, Event, RawDescriptor};
 use cros_async::Executor;
+use sync::Mutex;
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::GuestMemory;
 use vmm_vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
 
-use crate::virtio::vhost::user::vmm::{handler::VhostUserHandler, worker::Worker, Error, Result};
+use crate::virtio::vhost::user::vmm::{handler::VhostUserHandler, worker::Worker, Result};
 use crate::virtio::{block::common::virtio_blk_config, Interrupt, Queue, VirtioDevice, TYPE_BLOCK};
 
 const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
@@ -29,14 +29,12 @@ const QUEUE_SIZE: u16 = 256;
 pub struct Block {
     kill_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<Worker>>,
-    handler: RefCell<VhostUserHandler>,
+    handler: Arc<Mutex<VhostUserHandler>>,
     queue_sizes: Vec<u16>,
 }
 
 impl Block {
     pub fn new<P: AsRef<Path>>(base_features: u64, socket_path: P) -> Result<Block> {
-        let socket = UnixStream::connect(&socket_path).map_err(Error::SocketConnect)?;
-
         let allow_features = 1u64 << crate::virtio::VIRTIO_F_VERSION_1
             | 1 << VIRTIO_BLK_F_SEG_MAX
             | 1 << VIRTIO_BLK_F_RO
@@ -48,10 +46,11 @@ impl Block {
             | base_features
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
         let init_features = base_features | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
-        let allow_protocol_features = VhostUserProtocolFeatures::CONFIG;
+        let allow_protocol_features =
+            VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::INFLIGHT_SHMFD;
 
-        let mut handler = VhostUserHandler::new_from_stream(
-            socket,
+        let mut handler = VhostUserHandler::new_reconnectable(
+            socket_path,
             // TODO(b/181753022): Support multiple queues.
             1, /* queues_num */
             allow_features,
@@ -63,7 +62,7 @@ impl Block {
         Ok(Block {
             kill_evt: None,
             worker_thread: None,
-            handler: RefCell::new(handler),
+            handler: Arc::new(Mutex::new(handler)),
             queue_sizes,
         })
     }
@@ -88,11 +87,11 @@ impl VirtioDevice for Block {
     }
 
     fn features(&self) -> u64 {
-        self.handler.borrow().avail_features
+        self.handler.lock().avail_features
     }
 
     fn ack_features(&mut self, features: u64) {
-        if let Err(e) = self.handler.borrow_mut().ack_features(features) {
+        if let Err(e) = self.handler.lock().ack_features(features) {
             error!("failed to enable features 0x{:x}: {}", features, e);
         }
     }
@@ -108,7 +107,7 @@ impl VirtioDevice for Block {
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         if let Err(e) = self
             .handler
-            .borrow_mut()
+            .lock()
             .read_config::<virtio_blk_config>(offset, data)
         {
             error!("failed to read config: {}", e);
@@ -124,7 +123,7 @@ impl VirtioDevice for Block {
     ) {
         if let Err(e) = self
             .handler
-            .borrow_mut()
+            .lock()
             .activate(&mem, &interrupt, &queues, &queue_evts)
         {
             error!("failed to activate queues: {}", e);
@@ -140,6 +139,7 @@ impl VirtioDevice for Block {
         };
         self.kill_evt = Some(self_kill_evt);
 
+        let handler = self.handler.clone();
         let worker_result = thread::Builder::new()
             .name("vhost_user_virtio_blk".to_string())
             .spawn(move || {
@@ -150,7 +150,7 @@ impl VirtioDevice for Block {
                     kill_evt,
                 };
 
-                if let Err(e) = worker.run(&ex, interrupt) {
+                if let Err(e) = worker.run_with_reconnect(&ex, interrupt, handler) {
                     error!("failed to start a worker: {}", e);
                 }
                 worker
@@ -167,7 +167,7 @@ impl VirtioDevice for Block {
     }
 
     fn reset(&mut self) -> bool {
-        if let Err(e) = self.handler.borrow_mut().reset(self.queue_sizes.len()) {
+        if let Err(e) = self.handler.lock().reset(self.queue_sizes.len()) {
             error!("Failed to reset block device: {}", e);
             false
         } else {
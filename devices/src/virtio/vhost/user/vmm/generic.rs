@@ -0,0 +1,188 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cell::RefCell;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+
+use base::{error, Event, RawDescriptor};
+use cros_async::Executor;
+use vm_memory::GuestMemory;
+use vmm_vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
+
+use crate::virtio::vhost::user::vmm::{handler::VhostUserHandler, worker::Worker, Error, Result};
+use crate::virtio::{Interrupt, Queue, VirtioDevice};
+
+/// Feature bits 0 to 23 are device-specific. Since crosvm knows nothing about the device, all of
+/// them are passed through between the guest and the backend.
+const DEVICE_FEATURES_MASK: u64 = (1 << 24) - 1;
+
+/// A vhost-user frontend for a device type crosvm has no dedicated support for. The device ID,
+/// the queue layout and the size of the configuration space are supplied by the user, and
+/// everything else is left to the backend.
+pub struct Generic {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+    handler: RefCell<VhostUserHandler>,
+    device_type: u32,
+    queue_sizes: Vec<u16>,
+    config_size: u64,
+}
+
+impl Generic {
+    pub fn new<P: AsRef<Path>>(
+        base_features: u64,
+        socket_path: P,
+        device_type: u32,
+        queue_sizes: Vec<u16>,
+        config_size: u64,
+    ) -> Result<Generic> {
+        let socket = UnixStream::connect(&socket_path).map_err(Error::SocketConnect)?;
+
+        let allow_features = 1u64 << crate::virtio::VIRTIO_F_VERSION_1
+            | DEVICE_FEATURES_MASK
+            | base_features
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let init_features = base_features | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let allow_protocol_features = if config_size > 0 {
+            VhostUserProtocolFeatures::CONFIG
+        } else {
+            VhostUserProtocolFeatures::empty()
+        };
+
+        let handler = VhostUserHandler::new_from_stream(
+            socket,
+            queue_sizes.len() as u64,
+            allow_features,
+            init_features,
+            allow_protocol_features,
+        )?;
+
+        Ok(Generic {
+            kill_evt: None,
+            worker_thread: None,
+            handler: RefCell::new(handler),
+            device_type,
+            queue_sizes,
+            config_size,
+        })
+    }
+}
+
+impl VirtioDevice for Generic {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        Vec::new()
+    }
+
+    fn features(&self) -> u64 {
+        self.handler.borrow().avail_features
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        if let Err(e) = self.handler.borrow_mut().ack_features(features) {
+            error!("failed to enable features 0x{:x}: {}", features, e);
+        }
+    }
+
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.queue_sizes.as_slice()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if let Err(e) = self
+            .handler
+            .borrow_mut()
+            .read_config_len(self.config_size, offset, data)
+        {
+            error!("failed to read config: {}", e);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if let Err(e) = self
+            .handler
+            .borrow_mut()
+            .write_config_len(self.config_size, offset, data)
+        {
+            error!("failed to write config: {}", e);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if let Err(e) = self
+            .handler
+            .borrow_mut()
+            .activate(&mem, &interrupt, &queues, &queue_evts)
+        {
+            error!("failed to activate queues: {}", e);
+            return;
+        }
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let worker_result = thread::Builder::new()
+            .name("vhost_user_generic".to_string())
+            .spawn(move || {
+                let ex = Executor::new().expect("failed to create an executor");
+                let mut worker = Worker {
+                    queues,
+                    mem,
+                    kill_evt,
+                };
+
+                if let Err(e) = worker.run(&ex, interrupt) {
+                    error!("failed to start a worker: {}", e);
+                }
+                worker
+            });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn vhost-user generic worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Err(e) = self.handler.borrow_mut().reset(self.queue_sizes.len()) {
+            error!("Failed to reset generic device: {}", e);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+impl Drop for Generic {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
@@ -2,14 +2,17 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::io::Write;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use base::{AsRawDescriptor, Event, Tube};
+use base::{AsRawDescriptor, Event, SafeDescriptor, Tube};
 use vm_memory::GuestMemory;
 use vmm_vhost::vhost_user::message::{
-    VhostUserConfigFlags, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
+    VhostUserConfigFlags, VhostUserInflight, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
 };
 use vmm_vhost::vhost_user::{Master, VhostUserMaster};
 use vmm_vhost::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
@@ -23,11 +26,59 @@ fn set_features(vu: &mut Master, avail_features: u64, ack_features: u64) -> Resu
     Ok(features)
 }
 
+/// Whether the backend on the other end of `fd` has closed its end of the connection.
+fn peer_hung_up(fd: RawFd) -> bool {
+    let mut buf = [0u8; 1];
+    // Safe because `buf` is valid for `buf.len()` bytes and we check the return value. MSG_PEEK
+    // leaves any pending message in place for the `Master`.
+    let ret = unsafe {
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    match ret {
+        0 => true,
+        r if r < 0 => !matches!(
+            io::Error::last_os_error().kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
+
+/// The socket path of a backend that may be reconnected to, along with a duplicate of the current
+/// connection that is watched for the backend going away.
+struct BackendSocket {
+    path: PathBuf,
+    max_queue_num: u64,
+    sock: UnixStream,
+}
+
+/// A vring as it was handed to the backend on activation.
+struct ActiveVring {
+    queue: Queue,
+    queue_evt: Event,
+    irqfd: Event,
+}
+
+/// Everything the backend was given on activation, kept so that it can be replayed to a restarted
+/// backend.
+struct ActiveState {
+    mem: GuestMemory,
+    vrings: Vec<ActiveVring>,
+}
+
 pub struct VhostUserHandler {
     vu: Master,
     pub avail_features: u64,
     acked_features: u64,
     protocol_features: VhostUserProtocolFeatures,
+    backend: Option<BackendSocket>,
+    inflight: Option<(VhostUserInflight, File)>,
+    active: Option<ActiveState>,
 }
 
 impl VhostUserHandler {
@@ -65,6 +116,32 @@ impl VhostUserHandler {
         )
     }
 
+    /// Creates a `VhostUserHandler` instance attached to the provided UDS path, like
+    /// `new_from_path`, that also supports reconnecting to the backend through `reconnect` after
+    /// the backend process was restarted.
+    pub fn new_reconnectable<P: AsRef<Path>>(
+        path: P,
+        max_queue_num: u64,
+        allow_features: u64,
+        init_features: u64,
+        allow_protocol_features: VhostUserProtocolFeatures,
+    ) -> Result<Self> {
+        let sock = UnixStream::connect(&path).map_err(Error::SocketConnect)?;
+        let watch_sock = sock.try_clone().map_err(Error::CloneSocket)?;
+        let mut handler = Self::new(
+            Master::from_stream(sock, max_queue_num),
+            allow_features,
+            init_features,
+            allow_protocol_features,
+        )?;
+        handler.backend = Some(BackendSocket {
+            path: path.as_ref().to_path_buf(),
+            max_queue_num,
+            sock: watch_sock,
+        });
+        Ok(handler)
+    }
+
     /// Creates a `VhostUserHandler` instance with features and protocol features initialized.
     fn new(
         mut vu: Master,
@@ -92,6 +169,9 @@ impl VhostUserHandler {
             avail_features,
             acked_features,
             protocol_features,
+            backend: None,
+            inflight: None,
+            active: None,
         })
     }
 
@@ -120,8 +200,18 @@ impl VhostUserHandler {
     }
 
     /// Gets the device configuration space at `offset` and writes it into `data`.
-    pub fn read_config<T>(&mut self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        let config_len = std::mem::size_of::<T>() as u64;
+    pub fn read_config<T>(&mut self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.read_config_len(std::mem::size_of::<T>() as u64, offset, data)
+    }
+
+    /// Gets the device configuration space at `offset` and writes it into `data`, for a
+    /// configuration space of `config_len` bytes whose layout is not known to crosvm.
+    pub fn read_config_len(
+        &mut self,
+        config_len: u64,
+        offset: u64,
+        mut data: &mut [u8],
+    ) -> Result<()> {
         let data_len = data.len() as u64;
         offset
             .checked_add(data_len)
@@ -146,7 +236,12 @@ impl VhostUserHandler {
 
     /// Writes `data` into the device configuration space at `offset`.
     pub fn write_config<T>(&mut self, offset: u64, data: &[u8]) -> Result<()> {
-        let config_len = std::mem::size_of::<T>() as u64;
+        self.write_config_len(std::mem::size_of::<T>() as u64, offset, data)
+    }
+
+    /// Writes `data` into the device configuration space at `offset`, for a configuration space
+    /// of `config_len` bytes whose layout is not known to crosvm.
+    pub fn write_config_len(&mut self, config_len: u64, offset: u64, data: &[u8]) -> Result<()> {
         let data_len = data.len() as u64;
         offset
             .checked_add(data_len)
@@ -194,7 +289,27 @@ impl VhostUserHandler {
         Ok(())
     }
 
-    /// Activates a vring for the given `queue`.
+    /// Asks the backend for a region to track in-flight descriptors in and hands it back, so that
+    /// a restarted backend can pick up requests its predecessor had not completed.
+    fn set_up_inflight(&mut self, queues: &[Queue]) -> Result<()> {
+        let request = VhostUserInflight {
+            mmap_size: 0,
+            mmap_offset: 0,
+            num_queues: queues.len() as u16,
+            queue_size: queues.iter().map(|q| q.max_size).max().unwrap_or(0),
+        };
+        let (inflight, file) = self
+            .vu
+            .get_inflight_fd(&request)
+            .map_err(Error::GetInflightFd)?;
+        self.vu
+            .set_inflight_fd(&inflight, file.as_raw_fd())
+            .map_err(Error::SetInflightFd)?;
+        self.inflight = Some((inflight, file));
+        Ok(())
+    }
+
+    /// Activates a vring for the given `queue`, starting at `base` in the available ring.
     pub fn activate_vring(
         &mut self,
         mem: &GuestMemory,
@@ -202,6 +317,7 @@ impl VhostUserHandler {
         queue: &Queue,
         queue_evt: &Event,
         irqfd: &Event,
+        base: u16,
     ) -> Result<()> {
         self.vu
             .set_vring_num(queue_index, queue.actual_size())
@@ -227,7 +343,7 @@ impl VhostUserHandler {
             .map_err(Error::SetVringAddr)?;
 
         self.vu
-            .set_vring_base(queue_index, 0)
+            .set_vring_base(queue_index, base)
             .map_err(Error::SetVringBase)?;
 
         self.vu
@@ -253,25 +369,153 @@ impl VhostUserHandler {
     ) -> Result<()> {
         self.set_mem_table(mem)?;
 
+        if self
+            .protocol_features
+            .contains(VhostUserProtocolFeatures::INFLIGHT_SHMFD)
+        {
+            self.set_up_inflight(queues)?;
+        }
+
         let msix_config_opt = interrupt
             .get_msix_config()
             .as_ref()
             .ok_or(Error::MsixConfigUnavailable)?;
         let msix_config = msix_config_opt.lock();
 
+        let mut vrings = Vec::with_capacity(queues.len());
         for (queue_index, queue) in queues.iter().enumerate() {
             let queue_evt = &queue_evts[queue_index];
             let irqfd = msix_config
                 .get_irqfd(queue.vector as usize)
                 .unwrap_or_else(|| interrupt.get_interrupt_evt());
-            self.activate_vring(mem, queue_index, queue, queue_evt, irqfd)?;
+            self.activate_vring(mem, queue_index, queue, queue_evt, irqfd, 0)?;
+
+            if self.backend.is_some() {
+                vrings.push(ActiveVring {
+                    queue: queue.clone(),
+                    queue_evt: queue_evt.try_clone().map_err(Error::CloneEvent)?,
+                    irqfd: irqfd.try_clone().map_err(Error::CloneEvent)?,
+                });
+            }
+        }
+
+        if self.backend.is_some() {
+            self.active = Some(ActiveState {
+                mem: mem.clone(),
+                vrings,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns a duplicate of the connection to the backend which becomes readable once the
+    /// backend goes away, or `None` if this handler cannot reconnect.
+    pub fn backend_socket(&self) -> Result<Option<File>> {
+        match &self.backend {
+            Some(backend) => {
+                let sock = SafeDescriptor::try_from(&backend.sock as &dyn AsRawFd)
+                    .map_err(Error::CloneSocket)?;
+                Ok(Some(File::from(sock)))
+            }
+            None => Ok(None),
         }
+    }
+
+    /// Returns true if the backend has closed the connection.
+    ///
+    /// The backend never sends anything on its own over this connection, so the socket only
+    /// becomes readable without a pending request when the backend hangs up.
+    pub fn backend_disconnected(&self) -> bool {
+        match &self.backend {
+            Some(backend) => peer_hung_up(backend.sock.as_raw_fd()),
+            None => false,
+        }
+    }
 
+    /// Connects to a restarted backend and brings it to the state its predecessor was in: the
+    /// negotiated features, the memory table, the in-flight tracking region and the vrings.
+    ///
+    /// The vrings are only restored if the backend tracked its in-flight descriptors. Otherwise
+    /// there is no telling which requests the previous backend completed out of order, so this
+    /// returns `Error::ReconnectWithoutInflight` and the vrings stay stopped until the device is
+    /// reset and activated again.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let backend = self.backend.as_mut().ok_or(Error::ReconnectUnsupported)?;
+        let sock = UnixStream::connect(&backend.path).map_err(Error::SocketConnect)?;
+        let watch_sock = sock.try_clone().map_err(Error::CloneSocket)?;
+        let mut vu = Master::from_stream(sock, backend.max_queue_num);
+
+        vu.set_owner().map_err(Error::SetOwner)?;
+        let features = vu.get_features().map_err(Error::GetFeatures)?;
+        if features & self.acked_features != self.acked_features {
+            return Err(Error::ReconnectFeaturesMismatch);
+        }
+        vu.set_features(self.acked_features)
+            .map_err(Error::SetFeatures)?;
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            let avail_protocol_features = vu
+                .get_protocol_features()
+                .map_err(Error::GetProtocolFeatures)?;
+            if !avail_protocol_features.contains(self.protocol_features) {
+                return Err(Error::ReconnectFeaturesMismatch);
+            }
+            vu.set_protocol_features(self.protocol_features)
+                .map_err(Error::SetProtocolFeatures)?;
+        }
+
+        self.vu = vu;
+        backend.sock = watch_sock;
+
+        if self.active.is_some() && self.inflight.is_none() {
+            self.active = None;
+            return Err(Error::ReconnectWithoutInflight);
+        }
+
+        if let Some(active) = self.active.take() {
+            let res = self.restore(&active);
+            self.active = Some(active);
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Replays the activation in `active` to the current backend.
+    fn restore(&mut self, active: &ActiveState) -> Result<()> {
+        self.set_mem_table(&active.mem)?;
+
+        if let Some((inflight, file)) = &self.inflight {
+            self.vu
+                .set_inflight_fd(inflight, file.as_raw_fd())
+                .map_err(Error::SetInflightFd)?;
+        }
+
+        for (queue_index, vring) in active.vrings.iter().enumerate() {
+            // The previous backend's position in the available ring died with it. Resume from the
+            // used index: the new backend finds the requests its predecessor had taken but not
+            // completed in the in-flight region, and resubmits them.
+            let used_idx = active
+                .mem
+                .read_obj_from_addr::<u16>(vring.queue.used_ring.unchecked_add(2))
+                .map_err(Error::ReadUsedIndex)?;
+            self.activate_vring(
+                &active.mem,
+                queue_index,
+                &vring.queue,
+                &vring.queue_evt,
+                &vring.irqfd,
+                used_idx,
+            )?;
+            // The guest may have kicked the queue while there was no backend to notice.
+            vring.queue_evt.write(1).map_err(Error::KickVring)?;
+        }
         Ok(())
     }
 
     /// Deactivates all vrings.
     pub fn reset(&mut self, queues_num: usize) -> Result<()> {
+        self.active = None;
+        self.inflight = None;
         for queue_index in 0..queues_num {
             self.vu
                 .set_vring_enable(queue_index, false)
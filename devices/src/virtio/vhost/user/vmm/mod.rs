@@ -5,6 +5,7 @@
 mod block;
 mod console;
 mod fs;
+mod generic;
 #[cfg(feature = "gpu")]
 mod gpu;
 mod handler;
@@ -19,6 +20,7 @@ mod worker;
 pub use self::block::*;
 pub use self::console::*;
 pub use self::fs::*;
+pub use self::generic::*;
 #[cfg(feature = "gpu")]
 pub use self::gpu::*;
 pub use self::handler::VhostUserHandler;
@@ -37,6 +39,12 @@ use vmm_vhost::Error as VhostError;
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum Error {
+    /// Failed to clone an event.
+    #[error("failed to clone event: {0}")]
+    CloneEvent(base::Error),
+    /// Failed to duplicate the connection to the backend.
+    #[error("failed to duplicate the backend socket: {0}")]
+    CloneSocket(std::io::Error),
     /// Failed to copy config to a buffer.
     #[error("failed to copy config to a buffer: {0}")]
     CopyConfig(std::io::Error),
@@ -52,6 +60,9 @@ pub enum Error {
     /// Failed to get host address.
     #[error("failed to get host address: {0}")]
     GetHostAddress(GuestMemoryError),
+    /// Failed to get the in-flight descriptor tracking region.
+    #[error("failed to get inflight fd: {0}")]
+    GetInflightFd(VhostError),
     /// Failed to get protocol features.
     #[error("failed to get protocol features: {0}")]
     GetProtocolFeatures(VhostError),
@@ -68,12 +79,27 @@ pub enum Error {
         offset: u64,
         config_len: u64,
     },
+    /// Failed to kick a vring after reconnecting.
+    #[error("failed to kick vring: {0}")]
+    KickVring(base::Error),
     /// MSI-X config is unavailable.
     #[error("MSI-X config is unavailable")]
     MsixConfigUnavailable,
     /// MSI-X irqfd is unavailable.
     #[error("MSI-X irqfd is unavailable")]
     MsixIrqfdUnavailable,
+    /// Failed to read the used ring index from guest memory.
+    #[error("failed to read the used ring index: {0}")]
+    ReadUsedIndex(GuestMemoryError),
+    /// The restarted backend does not offer the features negotiated with its predecessor.
+    #[error("restarted backend does not support the negotiated features")]
+    ReconnectFeaturesMismatch,
+    /// The handler was not created with a socket path to reconnect to.
+    #[error("reconnecting is not supported by this handler")]
+    ReconnectUnsupported,
+    /// The backend was restarted while it had vrings running but no in-flight region.
+    #[error("cannot resume the vrings of a restarted backend without in-flight tracking")]
+    ReconnectWithoutInflight,
     /// Failed to reset owner.
     #[error("failed to reset owner: {0}")]
     ResetOwner(VhostError),
@@ -86,6 +112,9 @@ pub enum Error {
     /// Failed to set features.
     #[error("failed to set features: {0}")]
     SetFeatures(VhostError),
+    /// Failed to set the in-flight descriptor tracking region.
+    #[error("failed to set inflight fd: {0}")]
+    SetInflightFd(VhostError),
     /// Failed to set memory map regions.
     #[error("failed to set memory map regions: {0}")]
     SetMemTable(VhostError),
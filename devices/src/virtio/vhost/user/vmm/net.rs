@@ -2,14 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::cell::RefCell;
-use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::Arc;
 use std::thread;
 use std::u32;
 
 use base::{error, Event, RawDescriptor};
 use cros_async::Executor;
+use sync::Mutex;
 use virtio_sys::virtio_net;
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::GuestMemory;
@@ -25,14 +25,12 @@ const QUEUE_SIZE: u16 = 256;
 pub struct Net {
     kill_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<Worker>>,
-    handler: RefCell<VhostUserHandler>,
+    handler: Arc<Mutex<VhostUserHandler>>,
     queue_sizes: Vec<u16>,
 }
 
 impl Net {
     pub fn new<P: AsRef<Path>>(base_features: u64, socket_path: P) -> Result<Net> {
-        let socket = UnixStream::connect(&socket_path).map_err(Error::SocketConnect)?;
-
         let allow_features = 1 << crate::virtio::VIRTIO_F_VERSION_1
             | 1 << virtio_net::VIRTIO_NET_F_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ
@@ -47,11 +45,12 @@ impl Net {
             | base_features
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
         let init_features = base_features | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
-        let allow_protocol_features =
-            VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG;
+        let allow_protocol_features = VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD;
 
-        let mut handler = VhostUserHandler::new_from_stream(
-            socket,
+        let mut handler = VhostUserHandler::new_reconnectable(
+            socket_path,
             3, /* # of queues */
             allow_features,
             init_features,
@@ -62,7 +61,7 @@ impl Net {
         Ok(Net {
             kill_evt: None,
             worker_thread: None,
-            handler: RefCell::new(handler),
+            handler: Arc::new(Mutex::new(handler)),
             queue_sizes,
         })
     }
@@ -87,11 +86,11 @@ impl VirtioDevice for Net {
     }
 
     fn features(&self) -> u64 {
-        self.handler.borrow().avail_features
+        self.handler.lock().avail_features
     }
 
     fn ack_features(&mut self, features: u64) {
-        if let Err(e) = self.handler.borrow_mut().ack_features(features) {
+        if let Err(e) = self.handler.lock().ack_features(features) {
             error!("failed to enable features 0x{:x}: {}", features, e);
         }
     }
@@ -107,7 +106,7 @@ impl VirtioDevice for Net {
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         if let Err(e) = self
             .handler
-            .borrow_mut()
+            .lock()
             .read_config::<VirtioNetConfig>(offset, data)
         {
             error!("failed to read config: {}", e);
@@ -123,7 +122,7 @@ impl VirtioDevice for Net {
     ) {
         if let Err(e) = self
             .handler
-            .borrow_mut()
+            .lock()
             .activate(&mem, &interrupt, &queues, &queue_evts)
         {
             error!("failed to activate queues: {}", e);
@@ -139,6 +138,7 @@ impl VirtioDevice for Net {
         };
         self.kill_evt = Some(self_kill_evt);
 
+        let handler = self.handler.clone();
         let worker_result = thread::Builder::new()
             .name("vhost_user_virtio_net".to_string())
             .spawn(move || {
@@ -148,7 +148,7 @@ impl VirtioDevice for Net {
                     mem,
                     kill_evt,
                 };
-                if let Err(e) = worker.run(&ex, interrupt) {
+                if let Err(e) = worker.run_with_reconnect(&ex, interrupt, handler) {
                     error!("failed to start a worker: {}", e);
                 }
                 worker
@@ -165,7 +165,7 @@ impl VirtioDevice for Net {
     }
 
     fn reset(&mut self) -> bool {
-        if let Err(e) = self.handler.borrow_mut().reset(self.queue_sizes.len()) {
+        if let Err(e) = self.handler.lock().reset(self.queue_sizes.len()) {
             error!("Failed to reset net device: {}", e);
             false
         } else {
@@ -2,22 +2,42 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use base::{error, Event};
-use cros_async::{select2, AsyncError, EventAsync, Executor, SelectResult};
-use futures::pin_mut;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::{error, info, Event};
+use cros_async::{select3, AsyncError, EventAsync, Executor, SelectResult, TimerAsync};
+use futures::{future, pin_mut};
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use vm_memory::GuestMemory;
 
 use crate::virtio::interrupt::SignalableInterrupt;
+use crate::virtio::vhost::user::vmm::{handler::VhostUserHandler, Error as HandlerError};
 use crate::virtio::{Interrupt, Queue};
 
+// How long to wait between attempts to reach a restarted backend.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
 #[sorted]
 #[derive(ThisError, Debug)]
 enum Error {
+    /// Failed to get the connection to the backend.
+    #[error("failed to get the backend socket: {0}")]
+    BackendSocket(HandlerError),
+    /// Failed to create an async source for the backend socket.
+    #[error("failed to create an async backend socket: {0}")]
+    CreateAsyncSocket(AsyncError),
     /// Failed to read the resample event.
     #[error("failed to read the resample event: {0}")]
     ReadResampleEvent(AsyncError),
+    /// Failed to wait between reconnection attempts.
+    #[error("failed to wait for the reconnect timer: {0}")]
+    ReconnectTimer(cros_async::Error),
+    /// Failed to wait on the backend socket.
+    #[error("failed to wait on the backend socket: {0}")]
+    WaitBackendSocket(AsyncError),
 }
 
 pub struct Worker {
@@ -48,8 +68,78 @@ impl Worker {
         let _ = kill_evt.next_val().await;
     }
 
+    // Waits for the backend to go away, then keeps trying to connect to a restarted backend until
+    // one shows up and has been brought back to the state of the previous one.
+    async fn handle_backend_reconnect(
+        ex: &Executor,
+        handler: Arc<Mutex<VhostUserHandler>>,
+    ) -> Result<(), Error> {
+        loop {
+            let sock = handler.lock().backend_socket();
+            let sock = match sock.map_err(Error::BackendSocket)? {
+                Some(sock) => sock,
+                // This handler has nothing to reconnect to.
+                None => return future::pending().await,
+            };
+            let sock = ex.async_from(sock).map_err(Error::CreateAsyncSocket)?;
+            loop {
+                sock.wait_readable()
+                    .await
+                    .map_err(Error::WaitBackendSocket)?;
+                // Replies to requests are consumed with the lock held, so once we have it the
+                // socket is only readable if the backend hung up.
+                if handler.lock().backend_disconnected() {
+                    break;
+                }
+            }
+
+            error!("vhost-user backend disconnected, waiting for it to restart");
+            let resumed = loop {
+                TimerAsync::sleep(ex, RECONNECT_INTERVAL)
+                    .await
+                    .map_err(Error::ReconnectTimer)?;
+                let res = handler.lock().reconnect();
+                match res {
+                    Ok(()) => break true,
+                    Err(HandlerError::SocketConnect(_)) => {}
+                    // The backend is connected, but the guest's requests cannot be resumed.
+                    Err(HandlerError::ReconnectWithoutInflight) => break false,
+                    Err(e) => error!("failed to reconnect to vhost-user backend: {}", e),
+                }
+            };
+            if resumed {
+                info!("reconnected to restarted vhost-user backend");
+            } else {
+                error!(
+                    "restarted vhost-user backend cannot resume in-flight requests, \
+                     the device must be reset"
+                );
+            }
+        }
+    }
+
     // Runs asynchronous tasks.
     pub fn run(&mut self, ex: &Executor, interrupt: Interrupt) -> Result<(), String> {
+        self.run_inner(ex, interrupt, None)
+    }
+
+    // Runs asynchronous tasks, and reconnects `handler` to its backend whenever the backend is
+    // restarted.
+    pub fn run_with_reconnect(
+        &mut self,
+        ex: &Executor,
+        interrupt: Interrupt,
+        handler: Arc<Mutex<VhostUserHandler>>,
+    ) -> Result<(), String> {
+        self.run_inner(ex, interrupt, Some(handler))
+    }
+
+    fn run_inner(
+        &mut self,
+        ex: &Executor,
+        interrupt: Interrupt,
+        handler: Option<Arc<Mutex<VhostUserHandler>>>,
+    ) -> Result<(), String> {
         let resample_evt = interrupt
             .get_resample_evt()
             .expect("resample event required")
@@ -71,14 +161,403 @@ impl Worker {
         let kill = Self::wait_kill(kill_evt);
         pin_mut!(kill);
 
-        match ex.run_until(select2(resample, kill)) {
-            Ok((resample_res, _)) => {
+        let reconnect = async {
+            match handler {
+                Some(handler) => Self::handle_backend_reconnect(ex, handler).await,
+                None => future::pending().await,
+            }
+        };
+        pin_mut!(reconnect);
+
+        match ex.run_until(select3(resample, kill, reconnect)) {
+            Ok((resample_res, _, reconnect_res)) => {
                 if let SelectResult::Finished(Err(e)) = resample_res {
                     return Err(format!("failed to resample a irq value: {:?}", e));
                 }
+                if let SelectResult::Finished(Err(e)) = reconnect_res {
+                    return Err(format!("failed to reconnect to the backend: {}", e));
+                }
                 Ok(())
             }
             Err(e) => Err(e.to_string()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use base::Tube;
+    use cros_async::select2;
+    use tempfile::{tempfile, Builder};
+    use vm_memory::GuestAddress;
+    use vmm_vhost::vhost_user::message::{
+        VhostUserConfigFlags, VhostUserInflight, VhostUserMemoryRegion, VhostUserProtocolFeatures,
+        VhostUserSingleMemoryRegion, VhostUserVirtioFeatures, VhostUserVringAddrFlags,
+        VhostUserVringState,
+    };
+    use vmm_vhost::vhost_user::{
+        Listener, Result as VhostResult, SlaveListener, VhostUserSlaveReqHandlerMut,
+    };
+
+    use crate::pci::MsixConfig;
+
+    // Written to the in-flight region by the first backend, standing in for a descriptor it took
+    // from the queue but never completed.
+    const INFLIGHT_DESCRIPTOR: &[u8] = b"inflight descriptor";
+    const INFLIGHT_SIZE: u64 = 4096;
+
+    // The used index of the queue when the first backend dies.
+    const USED_IDX: u16 = 5;
+
+    // A backend that records the vring bases and the in-flight region it is given.
+    struct FakeBackend {
+        protocol_features: VhostUserProtocolFeatures,
+        vring_bases: Vec<u32>,
+        inflight: Option<File>,
+        enabled: bool,
+    }
+
+    impl VhostUserSlaveReqHandlerMut for FakeBackend {
+        fn set_owner(&mut self) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn reset_owner(&mut self) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn get_features(&mut self) -> VhostResult<u64> {
+            Ok(VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits())
+        }
+
+        fn set_features(&mut self, _features: u64) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn get_protocol_features(&mut self) -> VhostResult<VhostUserProtocolFeatures> {
+            Ok(self.protocol_features)
+        }
+
+        fn set_protocol_features(&mut self, _features: u64) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_mem_table(
+            &mut self,
+            _contexts: &[VhostUserMemoryRegion],
+            _files: Vec<File>,
+        ) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn get_queue_num(&mut self) -> VhostResult<u64> {
+            Ok(1)
+        }
+
+        fn set_vring_num(&mut self, _index: u32, _num: u32) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_vring_addr(
+            &mut self,
+            _index: u32,
+            _flags: VhostUserVringAddrFlags,
+            _descriptor: u64,
+            _used: u64,
+            _available: u64,
+            _log: u64,
+        ) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_vring_base(&mut self, _index: u32, base: u32) -> VhostResult<()> {
+            self.vring_bases.push(base);
+            Ok(())
+        }
+
+        fn get_vring_base(&mut self, index: u32) -> VhostResult<VhostUserVringState> {
+            Ok(VhostUserVringState::new(index, 0))
+        }
+
+        fn set_vring_kick(&mut self, _index: u8, _file: Option<File>) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_vring_call(&mut self, _index: u8, _file: Option<File>) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_vring_err(&mut self, _index: u8, _fd: Option<File>) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_vring_enable(&mut self, _index: u32, enable: bool) -> VhostResult<()> {
+            self.enabled = enable;
+            Ok(())
+        }
+
+        fn get_config(
+            &mut self,
+            _offset: u32,
+            size: u32,
+            _flags: VhostUserConfigFlags,
+        ) -> VhostResult<Vec<u8>> {
+            Ok(vec![0; size as usize])
+        }
+
+        fn set_config(
+            &mut self,
+            _offset: u32,
+            _buf: &[u8],
+            _flags: VhostUserConfigFlags,
+        ) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn set_slave_req_fd(&mut self, _fd: File) {}
+
+        fn get_inflight_fd(
+            &mut self,
+            inflight: &VhostUserInflight,
+        ) -> VhostResult<(VhostUserInflight, File)> {
+            let mut file = tempfile().unwrap();
+            file.set_len(INFLIGHT_SIZE).unwrap();
+            file.write_all(INFLIGHT_DESCRIPTOR).unwrap();
+            let inflight = VhostUserInflight {
+                mmap_size: INFLIGHT_SIZE,
+                mmap_offset: 0,
+                num_queues: inflight.num_queues,
+                queue_size: inflight.queue_size,
+            };
+            Ok((inflight, file))
+        }
+
+        fn set_inflight_fd(
+            &mut self,
+            _inflight: &VhostUserInflight,
+            file: File,
+        ) -> VhostResult<()> {
+            self.inflight = Some(file);
+            Ok(())
+        }
+
+        fn get_max_mem_slots(&mut self) -> VhostResult<u64> {
+            Ok(0)
+        }
+
+        fn add_mem_region(
+            &mut self,
+            _region: &VhostUserSingleMemoryRegion,
+            _fd: File,
+        ) -> VhostResult<()> {
+            Ok(())
+        }
+
+        fn remove_mem_region(&mut self, _region: &VhostUserSingleMemoryRegion) -> VhostResult<()> {
+            Ok(())
+        }
+    }
+
+    fn new_backend(
+        protocol_features: VhostUserProtocolFeatures,
+    ) -> Arc<std::sync::Mutex<FakeBackend>> {
+        Arc::new(std::sync::Mutex::new(FakeBackend {
+            protocol_features,
+            vring_bases: Vec::new(),
+            inflight: None,
+            enabled: false,
+        }))
+    }
+
+    // Serves one connection on `listener` until the vring is enabled, then writes `done` and hangs
+    // up as if the backend process had died.
+    fn run_backend(
+        listener: Listener,
+        done: Event,
+        protocol_features: VhostUserProtocolFeatures,
+    ) -> thread::JoinHandle<Arc<std::sync::Mutex<FakeBackend>>> {
+        thread::spawn(move || {
+            let backend = new_backend(protocol_features);
+            let mut listener = SlaveListener::new(listener, Arc::clone(&backend)).unwrap();
+            let mut req_handler = listener.accept().unwrap().unwrap();
+            while !backend.lock().unwrap().enabled {
+                req_handler.handle_request().unwrap();
+            }
+            done.write(1).unwrap();
+            backend
+        })
+    }
+
+    // Serves one connection on `listener` until the frontend hangs up.
+    fn run_backend_until_hangup(
+        listener: Listener,
+        protocol_features: VhostUserProtocolFeatures,
+    ) -> thread::JoinHandle<Arc<std::sync::Mutex<FakeBackend>>> {
+        thread::spawn(move || {
+            let backend = new_backend(protocol_features);
+            let mut listener = SlaveListener::new(listener, Arc::clone(&backend)).unwrap();
+            let mut req_handler = listener.accept().unwrap().unwrap();
+            while req_handler.handle_request().is_ok() {}
+            backend
+        })
+    }
+
+    fn new_interrupt() -> Interrupt {
+        let (msi_tube, _msi_device_tube) = Tube::pair().unwrap();
+        Interrupt::new(
+            Arc::new(AtomicUsize::new(0)),
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            Some(Arc::new(Mutex::new(MsixConfig::new(1, msi_tube)))),
+            0,
+        )
+    }
+
+    fn new_queue() -> Queue {
+        let mut queue = Queue::new(16);
+        queue.desc_table = GuestAddress(0x1000);
+        queue.avail_ring = GuestAddress(0x2000);
+        queue.used_ring = GuestAddress(0x3000);
+        queue
+    }
+
+    #[test]
+    fn reconnect_restores_vring_and_inflight() {
+        let dir = Builder::new().prefix("/tmp/vhost_test").tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let done = Event::new().unwrap();
+
+        let backend = run_backend(
+            Listener::new(&path, true).unwrap(),
+            done.try_clone().unwrap(),
+            VhostUserProtocolFeatures::INFLIGHT_SHMFD,
+        );
+        let features = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let mut handler = VhostUserHandler::new_reconnectable(
+            &path,
+            1,
+            features,
+            features,
+            VhostUserProtocolFeatures::INFLIGHT_SHMFD,
+        )
+        .unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let queue = new_queue();
+        let queue_evt = Event::new().unwrap();
+        let interrupt = new_interrupt();
+        handler
+            .activate(
+                &mem,
+                &interrupt,
+                &[queue.clone()],
+                &[queue_evt.try_clone().unwrap()],
+            )
+            .unwrap();
+
+        let first = backend.join().unwrap();
+        assert_eq!(first.lock().unwrap().vring_bases, vec![0]);
+        done.read().unwrap();
+
+        // The first backend completed some requests before it died.
+        mem.write_obj_at_addr(USED_IDX, queue.used_ring.unchecked_add(2))
+            .unwrap();
+
+        let backend = run_backend(
+            Listener::new(&path, true).unwrap(),
+            done.try_clone().unwrap(),
+            VhostUserProtocolFeatures::INFLIGHT_SHMFD,
+        );
+
+        let ex = Executor::new().unwrap();
+        let reconnect = Worker::handle_backend_reconnect(&ex, Arc::new(Mutex::new(handler)));
+        pin_mut!(reconnect);
+        let done = EventAsync::new(done.0, &ex).unwrap();
+        let restored = done.next_val();
+        pin_mut!(restored);
+        let (reconnect_res, _) = ex.run_until(select2(reconnect, restored)).unwrap();
+        if let SelectResult::Finished(res) = reconnect_res {
+            panic!("reconnect task exited early: {:?}", res);
+        }
+
+        let second = backend.join().unwrap();
+        let mut second = second.lock().unwrap();
+        // The restarted backend resumes from the used index.
+        assert_eq!(second.vring_bases, vec![u32::from(USED_IDX)]);
+
+        // And gets the in-flight region its predecessor filled in.
+        let mut inflight = second
+            .inflight
+            .take()
+            .expect("in-flight region was not resubmitted");
+        let mut descriptor = vec![0; INFLIGHT_DESCRIPTOR.len()];
+        inflight.seek(SeekFrom::Start(0)).unwrap();
+        inflight.read_exact(&mut descriptor).unwrap();
+        assert_eq!(descriptor, INFLIGHT_DESCRIPTOR);
+
+        // The queue is kicked in case the guest added requests while there was no backend.
+        assert_eq!(queue_evt.read().unwrap(), 1);
+    }
+    #[test]
+    fn reconnect_without_inflight() {
+        let dir = Builder::new().prefix("/tmp/vhost_test").tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let done = Event::new().unwrap();
+
+        // This backend does not track its in-flight descriptors.
+        let backend = run_backend(
+            Listener::new(&path, true).unwrap(),
+            done.try_clone().unwrap(),
+            VhostUserProtocolFeatures::empty(),
+        );
+        let features = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let mut handler = VhostUserHandler::new_reconnectable(
+            &path,
+            1,
+            features,
+            features,
+            VhostUserProtocolFeatures::INFLIGHT_SHMFD,
+        )
+        .unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let queue = new_queue();
+        let queue_evt = Event::new().unwrap();
+        let interrupt = new_interrupt();
+        let queues = [queue.clone()];
+        handler
+            .activate(&mem, &interrupt, &queues, &[queue_evt.try_clone().unwrap()])
+            .unwrap();
+        backend.join().unwrap();
+        mem.write_obj_at_addr(USED_IDX, queue.used_ring.unchecked_add(2))
+            .unwrap();
+
+        // The vrings of the restarted backend are not resumed.
+        let backend = run_backend_until_hangup(
+            Listener::new(&path, true).unwrap(),
+            VhostUserProtocolFeatures::empty(),
+        );
+        assert!(matches!(
+            handler.reconnect(),
+            Err(HandlerError::ReconnectWithoutInflight)
+        ));
+
+        // But resetting the device brings them back up from scratch.
+        handler.reset(1).unwrap();
+        handler
+            .activate(&mem, &interrupt, &queues, &[queue_evt.try_clone().unwrap()])
+            .unwrap();
+        drop(handler);
+        let second = backend.join().unwrap();
+        let second = second.lock().unwrap();
+        assert_eq!(second.vring_bases, vec![0]);
+        assert!(second.enabled);
+    }
+}
@@ -85,6 +85,12 @@ pub trait VirtioDevice: Send {
         Vec::new()
     }
 
+    /// Returns the number of MSI-X vectors the device needs in addition to the one per queue and
+    /// the one for configuration changes.
+    fn extra_msix_vectors(&self) -> usize {
+        0
+    }
+
     /// Invoked when the device is sandboxed.
     fn on_device_sandboxed(&mut self) {}
 
@@ -30,13 +30,15 @@ pub enum PciCapabilityType {
     IsrConfig = 3,
     DeviceConfig = 4,
     PciConfig = 5,
+    DoorbellConfig = 6,
+    NotificationConfig = 7,
     SharedMemoryConfig = 8,
 }
 
 #[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct VirtioPciCap {
+pub struct VirtioPciCap {
     // _cap_vndr and _cap_next are autofilled based on id() in pci configuration
     _cap_vndr: u8,    // Generic PCI field: PCI_CAP_ID_VNDR
     _cap_next: u8,    // Generic PCI field: next ptr
@@ -262,8 +264,10 @@ impl VirtioPciDevice {
 
         let num_queues = device.queue_max_sizes().len();
 
-        // One MSI-X vector per queue plus one for configuration changes.
-        let msix_num = u16::try_from(num_queues + 1).map_err(|_| base::Error::new(ERANGE))?;
+        // One MSI-X vector per queue plus one for configuration changes, plus any the device asks
+        // for on top of that.
+        let msix_num = u16::try_from(num_queues + 1 + device.extra_msix_vectors())
+            .map_err(|_| base::Error::new(ERANGE))?;
         let msix_config = Arc::new(Mutex::new(MsixConfig::new(msix_num, msi_device_tube)));
 
         let config_regs = PciConfiguration::new(
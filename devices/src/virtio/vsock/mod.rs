@@ -0,0 +1,440 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A virtio-vsock device implemented entirely in userspace.
+//!
+//! Unlike `virtio::vhost::Vsock`, this device does not need the host `vhost-vsock` kernel module.
+//! Guest streams are forwarded to host Unix domain sockets instead of host vsock sockets; see
+//! `muxer` for how ports are mapped.
+
+mod muxer;
+mod protocol;
+
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use base::{
+    error, warn, Error as SysError, Event, PollToken, RawDescriptor, UnlinkUnixListener,
+    WaitContext,
+};
+use data_model::{DataInit, Le64};
+use remain::sorted;
+use thiserror::Error as ThisError;
+use vm_memory::GuestMemory;
+
+use self::muxer::Muxer;
+use self::protocol::{virtio_vsock_config, virtio_vsock_hdr};
+use super::{
+    copy_config, DescriptorError, Interrupt, Queue, Reader, SignalableInterrupt, VirtioDevice,
+    Writer, TYPE_VSOCK,
+};
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 3;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+/// Largest payload accepted from the guest in a single packet.
+const MAX_TX_PAYLOAD: usize = 64 * 1024;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum VsockError {
+    /// Failed to bind the host Unix domain socket.
+    #[error("failed to bind host socket {0}: {1}")]
+    BindListener(PathBuf, io::Error),
+    /// Failed to create the WaitContext.
+    #[error("failed to create wait context: {0}")]
+    CreateWaitContext(SysError),
+    /// Descriptor chain was invalid.
+    #[error("failed to validate descriptor chain: {0}")]
+    DescriptorChain(DescriptorError),
+    /// The guest sent a packet whose payload is too large or does not fit its buffer.
+    #[error("invalid payload length in guest packet: {1}")]
+    InvalidPayloadLength(virtio_vsock_hdr, usize),
+    /// Failed to read a packet from the guest.
+    #[error("failed to read packet from the guest: {0}")]
+    ReadPacket(io::Error),
+    /// Failed to make the host socket non-blocking.
+    #[error("failed to set host socket non-blocking: {0}")]
+    SetNonBlocking(io::Error),
+    /// Error while waiting for events.
+    #[error("failed to wait for events: {0}")]
+    WaitError(SysError),
+    /// Failed to write a packet to the guest.
+    #[error("failed to write packet to the guest: {0}")]
+    WritePacket(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, VsockError>;
+
+struct Worker {
+    interrupt: Interrupt,
+    mem: GuestMemory,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    muxer: Muxer,
+}
+
+fn read_packet(mut reader: Reader) -> Result<(virtio_vsock_hdr, Vec<u8>)> {
+    let hdr: virtio_vsock_hdr = reader.read_obj().map_err(VsockError::ReadPacket)?;
+    let len = hdr.len.to_native() as usize;
+    if len > MAX_TX_PAYLOAD || len > reader.available_bytes() {
+        return Err(VsockError::InvalidPayloadLength(hdr, len));
+    }
+    let mut payload = vec![0u8; len];
+    reader
+        .read_exact(&mut payload)
+        .map_err(VsockError::ReadPacket)?;
+    Ok((hdr, payload))
+}
+
+impl Worker {
+    /// Fills the rx queue with packets from the muxer. Returns true if any buffer was used.
+    fn process_rx(&mut self) -> bool {
+        let mut needs_interrupt = false;
+
+        while self.muxer.has_pending_rx() {
+            let desc_chain = match self.rx_queue.peek(&self.mem) {
+                Some(desc) => desc,
+                None => break,
+            };
+            let index = desc_chain.index;
+
+            let mut writer = match Writer::new(self.mem.clone(), desc_chain) {
+                Ok(w) => w,
+                Err(e) => {
+                    error!("vsock: failed to create Writer: {}", e);
+                    self.rx_queue.pop_peeked(&self.mem);
+                    self.rx_queue.add_used(&self.mem, index, 0);
+                    needs_interrupt = true;
+                    continue;
+                }
+            };
+
+            let max_payload = writer
+                .available_bytes()
+                .saturating_sub(size_of::<virtio_vsock_hdr>());
+            let (hdr, payload) = match self.muxer.recv_for_guest(max_payload) {
+                Some(pkt) => pkt,
+                None => break,
+            };
+
+            if let Err(e) = writer
+                .write_obj(hdr)
+                .and_then(|_| writer.write_all(&payload))
+                .map_err(VsockError::WritePacket)
+            {
+                error!("vsock: {}", e);
+            }
+
+            self.rx_queue.pop_peeked(&self.mem);
+            self.rx_queue
+                .add_used(&self.mem, index, writer.bytes_written() as u32);
+            needs_interrupt = true;
+        }
+
+        needs_interrupt
+    }
+
+    /// Hands the packets in the tx queue over to the muxer. Returns true if any buffer was used.
+    fn process_tx(&mut self) -> bool {
+        let mut needs_interrupt = false;
+
+        while let Some(desc_chain) = self.tx_queue.pop(&self.mem) {
+            let index = desc_chain.index;
+
+            match Reader::new(self.mem.clone(), desc_chain)
+                .map_err(VsockError::DescriptorChain)
+                .and_then(read_packet)
+            {
+                Ok((hdr, payload)) => self.muxer.send_to_host(&hdr, &payload),
+                Err(e) => {
+                    error!("vsock: {}", e);
+                    // Dropping part of a stream would corrupt it, so reset the connection instead.
+                    if let VsockError::InvalidPayloadLength(hdr, _) = e {
+                        self.muxer.reject_packet(&hdr);
+                    }
+                }
+            }
+
+            self.tx_queue.add_used(&self.mem, index, 0);
+            needs_interrupt = true;
+        }
+
+        needs_interrupt
+    }
+
+    fn run(&mut self, queue_evts: Vec<Event>, kill_evt: Event) {
+        #[derive(PollToken)]
+        enum Token {
+            RxQueue,
+            TxQueue,
+            EventQueue,
+            Muxer,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&queue_evts[0], Token::RxQueue),
+            (&queue_evts[1], Token::TxQueue),
+            (&queue_evts[2], Token::EventQueue),
+            (&self.muxer, Token::Muxer),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                return;
+            }
+        };
+        if let Some(resample_evt) = self.interrupt.get_resample_evt() {
+            if wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .is_err()
+            {
+                error!("failed adding resample event to WaitContext.");
+                return;
+            }
+        }
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            let mut tx_needs_interrupt = false;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::RxQueue => {
+                        if let Err(e) = queue_evts[0].read() {
+                            error!("failed reading rx queue Event: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::TxQueue => {
+                        if let Err(e) = queue_evts[1].read() {
+                            error!("failed reading tx queue Event: {}", e);
+                            break 'wait;
+                        }
+                        tx_needs_interrupt |= self.process_tx();
+                    }
+                    Token::EventQueue => {
+                        if let Err(e) = queue_evts[2].read() {
+                            error!("failed reading event queue Event: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::Muxer => {
+                        if let Err(e) = self.muxer.process_events() {
+                            error!("vsock: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => break 'wait,
+                }
+            }
+
+            // Any of the events above may have produced packets for the guest or made room for
+            // them.
+            if self.process_rx() {
+                self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            if tx_needs_interrupt {
+                self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+        }
+    }
+}
+
+/// Virtio device for exposing vsock streams to the guest, backed by host Unix domain sockets.
+pub struct Vsock {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+    cid: u64,
+    uds_path: PathBuf,
+    listener: Option<UnlinkUnixListener>,
+    avail_features: u64,
+    acked_features: u64,
+}
+
+impl Vsock {
+    /// Creates a new virtio-vsock device for a guest with CID `cid`. Host applications connect to
+    /// the guest through a Unix domain socket bound at `uds_path`, and guest connections to host
+    /// port `P` are forwarded to `<uds_path>_P`.
+    pub fn new(base_features: u64, cid: u64, uds_path: &Path) -> Result<Vsock> {
+        let listener = UnixListener::bind(uds_path)
+            .map(UnlinkUnixListener)
+            .map_err(|e| VsockError::BindListener(uds_path.to_path_buf(), e))?;
+
+        Ok(Vsock {
+            kill_evt: None,
+            worker_thread: None,
+            cid,
+            uds_path: uds_path.to_path_buf(),
+            listener: Some(listener),
+            avail_features: base_features,
+            acked_features: 0,
+        })
+    }
+}
+
+impl Drop for Vsock {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+
+        if let Some(listener) = &self.listener {
+            keep_rds.push(listener.as_raw_fd());
+        }
+
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("vsock: virtio-vsock got unknown feature ack: {:x}", v);
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = virtio_vsock_config {
+            guest_cid: Le64::from(self.cid),
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!("vsock: expected {} queues, got {}", NUM_QUEUES, queues.len());
+            return;
+        }
+
+        let listener = match self.listener.take() {
+            Some(l) => l,
+            None => return,
+        };
+        let muxer = match Muxer::new(self.cid, &self.uds_path, listener) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("vsock: failed to create muxer: {}", e);
+                return;
+            }
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let acked_features = self.acked_features;
+        for queue in queues.iter_mut() {
+            queue.ack_features(acked_features);
+        }
+        // The event queue is only used to notify the guest of transport resets, which never
+        // happen with this device.
+        let tx_queue = queues.remove(1);
+        let rx_queue = queues.remove(0);
+
+        let worker_result = thread::Builder::new()
+            .name("virtio_vsock".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    interrupt,
+                    mem,
+                    rx_queue,
+                    tx_queue,
+                    muxer,
+                };
+                worker.run(queue_evts, kill_evt);
+                worker
+            });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_vsock worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(worker) => {
+                    // Dropping the muxer closes every open connection.
+                    self.listener = Some(worker.muxer.into_listener());
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,907 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Multiplexes guest vsock streams onto host Unix domain sockets.
+//!
+//! Connections are mapped the same way as in Firecracker:
+//!
+//! * A guest connecting to host port `P` is connected to the Unix socket at `<uds_path>_P`, which
+//!   must be listened on by a host application.
+//! * A host application connects to the Unix socket at `<uds_path>` and writes `CONNECT P\n` to
+//!   reach guest port `P`. Once the guest accepts the connection, the muxer replies with
+//!   `OK <host port>\n` and the socket carries the stream from then on.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::num::Wrapping;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use base::{
+    error, warn, AsRawDescriptor, Descriptor, EventType, PollToken, RawDescriptor,
+    UnlinkUnixListener, WaitContext,
+};
+
+use super::protocol::*;
+use super::{Result, VsockError};
+
+/// Number of bytes the muxer is willing to buffer for each connection while the host socket is
+/// not writable. This is advertised to the guest as `buf_alloc`.
+const CONN_TX_BUF_SIZE: u32 = 256 * 1024;
+/// The guest is told about forwarded bytes once this many have accumulated.
+const CONN_CREDIT_UPDATE_THRESHOLD: u32 = CONN_TX_BUF_SIZE / 4;
+/// Largest payload read from a host socket into a single packet.
+const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+/// Longest `CONNECT <port>\n` line accepted from a host application.
+const MAX_HANDSHAKE_LEN: usize = 32;
+/// Host ports used by host-initiated connections are allocated starting from here so they never
+/// collide with the ports guests typically connect to.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+
+#[derive(PollToken)]
+enum MuxerToken {
+    Listener,
+    Handshake { id: u32 },
+    Connection { id: u32 },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// A host application asked to connect and the guest has not answered yet.
+    LocalInit,
+    Established,
+    /// The host socket was closed and the guest was asked to shut down the connection.
+    Closing,
+}
+
+/// A host application that connected to the listening socket but has not yet sent its
+/// `CONNECT` line.
+struct Handshake {
+    stream: UnixStream,
+    buf: Vec<u8>,
+}
+
+struct Connection {
+    local_port: u32,
+    peer_port: u32,
+    stream: UnixStream,
+    state: ConnState,
+    /// Guest data that has not been written to `stream` yet.
+    tx_buf: VecDeque<u8>,
+    /// Number of guest bytes written to `stream`.
+    fwd_cnt: Wrapping<u32>,
+    /// Value of `fwd_cnt` the guest last heard about.
+    last_fwd_cnt_sent: Wrapping<u32>,
+    /// Number of bytes sent to the guest.
+    rx_cnt: Wrapping<u32>,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: Wrapping<u32>,
+    /// `VIRTIO_VSOCK_SHUTDOWN_*` flags received from the guest.
+    peer_shutdown: u32,
+    /// The host socket is known to have data (or EOF) to read.
+    readable: bool,
+    /// The host socket was hung up and is no longer waited on.
+    hungup: bool,
+}
+
+impl Connection {
+    fn new(local_port: u32, peer_port: u32, stream: UnixStream, state: ConnState) -> Self {
+        Connection {
+            local_port,
+            peer_port,
+            stream,
+            state,
+            tx_buf: VecDeque::new(),
+            fwd_cnt: Wrapping(0),
+            last_fwd_cnt_sent: Wrapping(0),
+            rx_cnt: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            peer_shutdown: 0,
+            readable: false,
+            hungup: false,
+        }
+    }
+
+    /// Number of bytes the guest can currently accept on this connection.
+    fn peer_credit(&self) -> u32 {
+        let in_flight = (self.rx_cnt - self.peer_fwd_cnt).0;
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    fn can_send_to_guest(&self) -> bool {
+        self.state == ConnState::Established
+            && self.readable
+            && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0
+            && self.peer_credit() > 0
+    }
+
+    fn event_type(&self) -> EventType {
+        let read = self.state == ConnState::Established
+            && !self.readable
+            && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0;
+        let write = !self.tx_buf.is_empty();
+        match (read, write) {
+            (true, true) => EventType::ReadWrite,
+            (true, false) => EventType::Read,
+            (false, true) => EventType::Write,
+            (false, false) => EventType::None,
+        }
+    }
+
+    /// Writes as much of `tx_buf` as possible to the host socket.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.tx_buf.is_empty() {
+            let (front, _) = self.tx_buf.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.tx_buf.drain(..n);
+                    self.fwd_cnt += Wrapping(n as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.tx_buf.is_empty() && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+            // The guest will not send anything else; let the host application see EOF.
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+}
+
+/// Builds a header-only packet for `conn`.
+fn conn_packet(guest_cid: u64, conn: &mut Connection, op: u16, flags: u32) -> virtio_vsock_hdr {
+    conn.last_fwd_cnt_sent = conn.fwd_cnt;
+    virtio_vsock_hdr {
+        src_cid: VMADDR_CID_HOST.into(),
+        dst_cid: guest_cid.into(),
+        src_port: conn.local_port.into(),
+        dst_port: conn.peer_port.into(),
+        len: 0.into(),
+        type_: VIRTIO_VSOCK_TYPE_STREAM.into(),
+        op: op.into(),
+        flags: flags.into(),
+        buf_alloc: CONN_TX_BUF_SIZE.into(),
+        fwd_cnt: conn.fwd_cnt.0.into(),
+    }
+}
+
+/// Builds a reset packet answering `hdr`, for packets that do not belong to any connection.
+fn reset_packet(hdr: &virtio_vsock_hdr) -> virtio_vsock_hdr {
+    virtio_vsock_hdr {
+        src_cid: hdr.dst_cid,
+        dst_cid: hdr.src_cid,
+        src_port: hdr.dst_port,
+        dst_port: hdr.src_port,
+        len: 0.into(),
+        type_: VIRTIO_VSOCK_TYPE_STREAM.into(),
+        op: VIRTIO_VSOCK_OP_RST.into(),
+        ..Default::default()
+    }
+}
+
+/// Routes vsock packets between the guest and host Unix domain sockets.
+pub struct Muxer {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnlinkUnixListener,
+    wait_ctx: WaitContext<MuxerToken>,
+    handshakes: BTreeMap<u32, Handshake>,
+    next_handshake_id: u32,
+    conns: BTreeMap<u32, Connection>,
+    // Maps (local port, peer port) to a key of `conns`.
+    conn_ids: BTreeMap<(u32, u32), u32>,
+    next_conn_id: u32,
+    next_local_port: u32,
+    // Id of the connection that last sent data to the guest, used to share the rx queue fairly.
+    last_rx_conn: u32,
+    // Header-only packets waiting to be sent to the guest.
+    pending: VecDeque<virtio_vsock_hdr>,
+}
+
+impl Muxer {
+    /// Creates a muxer for a guest with CID `guest_cid`, accepting host connections on
+    /// `listener`, which must be bound to `uds_path`.
+    pub fn new(guest_cid: u64, uds_path: &Path, listener: UnlinkUnixListener) -> Result<Muxer> {
+        listener
+            .set_nonblocking(true)
+            .map_err(VsockError::SetNonBlocking)?;
+        let wait_ctx = WaitContext::build_with(&[(
+            &Descriptor(listener.as_raw_fd()),
+            MuxerToken::Listener,
+        )])
+        .map_err(VsockError::CreateWaitContext)?;
+
+        Ok(Muxer {
+            guest_cid,
+            uds_path: uds_path.to_path_buf(),
+            listener,
+            wait_ctx,
+            handshakes: BTreeMap::new(),
+            next_handshake_id: 0,
+            conns: BTreeMap::new(),
+            conn_ids: BTreeMap::new(),
+            next_conn_id: 0,
+            next_local_port: FIRST_LOCAL_PORT,
+            last_rx_conn: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Consumes the muxer and returns the listening socket so that a new muxer can be created
+    /// after the device is reset. All connections are closed.
+    pub fn into_listener(self) -> UnlinkUnixListener {
+        self.listener
+    }
+
+    /// Returns true if `recv_for_guest` has a packet to return.
+    pub fn has_pending_rx(&self) -> bool {
+        !self.pending.is_empty() || self.conns.values().any(Connection::can_send_to_guest)
+    }
+
+    /// Handles all the host socket events that are ready, without blocking.
+    pub fn process_events(&mut self) -> Result<()> {
+        let events = self
+            .wait_ctx
+            .wait_timeout(Duration::from_secs(0))
+            .map_err(VsockError::WaitError)?;
+        for event in events.iter() {
+            match event.token {
+                MuxerToken::Listener => self.accept_host_connections(),
+                MuxerToken::Handshake { id } => self.process_handshake(id),
+                MuxerToken::Connection { id } => {
+                    if event.is_writable {
+                        self.flush_conn(id);
+                    }
+                    if event.is_readable || event.is_hungup {
+                        if let Some(conn) = self.conns.get_mut(&id) {
+                            conn.readable = true;
+                        }
+                    }
+                    if event.is_hungup {
+                        self.host_hangup(id);
+                    } else {
+                        self.update_interest(id);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the packet `hdr` sent by the guest along with its `payload`.
+    pub fn send_to_host(&mut self, hdr: &virtio_vsock_hdr, payload: &[u8]) {
+        let op = hdr.op.to_native();
+        if op == VIRTIO_VSOCK_OP_INVALID {
+            return;
+        }
+        if hdr.dst_cid.to_native() != VMADDR_CID_HOST
+            || hdr.src_cid.to_native() != self.guest_cid
+            || hdr.type_.to_native() != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            if op != VIRTIO_VSOCK_OP_RST {
+                self.pending.push_back(reset_packet(hdr));
+            }
+            return;
+        }
+
+        let key = (hdr.dst_port.to_native(), hdr.src_port.to_native());
+        let id = match self.conn_ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                match op {
+                    VIRTIO_VSOCK_OP_REQUEST => self.connect_to_host(hdr),
+                    VIRTIO_VSOCK_OP_RST => {}
+                    _ => self.pending.push_back(reset_packet(hdr)),
+                }
+                return;
+            }
+        };
+
+        let guest_cid = self.guest_cid;
+        let conn = match self.conns.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc.to_native();
+        conn.peer_fwd_cnt = Wrapping(hdr.fwd_cnt.to_native());
+
+        match op {
+            VIRTIO_VSOCK_OP_RESPONSE if conn.state == ConnState::LocalInit => {
+                let reply = format!("OK {}\n", conn.local_port);
+                if let Err(e) = conn.stream.write_all(reply.as_bytes()) {
+                    warn!("vsock: failed to complete host connection: {}", e);
+                    self.reset_conn(id);
+                    return;
+                }
+                conn.state = ConnState::Established;
+            }
+            VIRTIO_VSOCK_OP_RW if conn.state == ConnState::Established => {
+                if conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+                    || conn.tx_buf.len() + payload.len() > CONN_TX_BUF_SIZE as usize
+                {
+                    warn!("vsock: guest sent data beyond its credit or after shutdown");
+                    self.reset_conn(id);
+                    return;
+                }
+                conn.tx_buf.extend(payload);
+                self.flush_conn(id);
+            }
+            VIRTIO_VSOCK_OP_RW if conn.state == ConnState::Closing => {
+                // The host application is gone; drop the data.
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.peer_shutdown |= hdr.flags.to_native()
+                    & (VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND);
+                if conn.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND {
+                    // Give the host application whatever is left before closing the connection.
+                    let _ = conn.flush();
+                    self.reset_conn(id);
+                    return;
+                }
+                self.flush_conn(id);
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                self.remove_conn(id);
+                return;
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                let pkt = conn_packet(guest_cid, conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+                self.pending.push_back(pkt);
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+            _ => {
+                warn!("vsock: unexpected packet op {} for connection", op);
+                self.reset_conn(id);
+                return;
+            }
+        }
+
+        self.update_interest(id);
+    }
+
+    /// Resets the connection `hdr` belongs to, for packets from the guest that could not be read.
+    pub fn reject_packet(&mut self, hdr: &virtio_vsock_hdr) {
+        let key = (hdr.dst_port.to_native(), hdr.src_port.to_native());
+        let id = if hdr.src_cid.to_native() == self.guest_cid {
+            self.conn_ids.get(&key).copied()
+        } else {
+            None
+        };
+        match id {
+            Some(id) => self.reset_conn(id),
+            None if hdr.op.to_native() != VIRTIO_VSOCK_OP_RST => {
+                self.pending.push_back(reset_packet(hdr))
+            }
+            None => {}
+        }
+    }
+
+    /// Returns the next packet for the guest, whose payload will be at most `max_payload` bytes
+    /// long.
+    pub fn recv_for_guest(&mut self, max_payload: usize) -> Option<(virtio_vsock_hdr, Vec<u8>)> {
+        if let Some(hdr) = self.pending.pop_front() {
+            return Some((hdr, Vec::new()));
+        }
+        if max_payload == 0 {
+            return None;
+        }
+
+        // Start after the connection served last time so that a busy connection can't starve
+        // the others.
+        let ids: Vec<u32> = self
+            .conns
+            .range(self.last_rx_conn.wrapping_add(1)..)
+            .chain(self.conns.range(..=self.last_rx_conn))
+            .filter(|(_, conn)| conn.can_send_to_guest())
+            .map(|(&id, _)| id)
+            .collect();
+
+        let guest_cid = self.guest_cid;
+        for id in ids {
+            let conn = match self.conns.get_mut(&id) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let len = max_payload
+                .min(MAX_PKT_PAYLOAD)
+                .min(conn.peer_credit() as usize);
+            let mut buf = vec![0u8; len];
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    // The host application closed the socket.
+                    self.last_rx_conn = id;
+                    return self.close_conn(id).map(|pkt| (pkt, Vec::new()));
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    conn.rx_cnt += Wrapping(n as u32);
+                    let mut pkt = conn_packet(guest_cid, conn, VIRTIO_VSOCK_OP_RW, 0);
+                    pkt.len = (n as u32).into();
+                    self.last_rx_conn = id;
+                    return Some((pkt, buf));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    conn.readable = false;
+                    self.update_interest(id);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("vsock: failed to read from host socket: {}", e);
+                    self.reset_conn(id);
+                    return self.pending.pop_front().map(|hdr| (hdr, Vec::new()));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn connect_to_host(&mut self, hdr: &virtio_vsock_hdr) {
+        let local_port = hdr.dst_port.to_native();
+        let peer_port = hdr.src_port.to_native();
+        let path = format!("{}_{}", self.uds_path.display(), local_port);
+
+        let stream = match UnixStream::connect(&path).and_then(|s| {
+            s.set_nonblocking(true)?;
+            Ok(s)
+        }) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("vsock: failed to connect to {}: {}", path, e);
+                self.pending.push_back(reset_packet(hdr));
+                return;
+            }
+        };
+
+        let mut conn = Connection::new(local_port, peer_port, stream, ConnState::Established);
+        conn.peer_buf_alloc = hdr.buf_alloc.to_native();
+        conn.peer_fwd_cnt = Wrapping(hdr.fwd_cnt.to_native());
+        let pkt = conn_packet(self.guest_cid, &mut conn, VIRTIO_VSOCK_OP_RESPONSE, 0);
+        if self.add_conn(conn).is_some() {
+            self.pending.push_back(pkt);
+        } else {
+            self.pending.push_back(reset_packet(hdr));
+        }
+    }
+
+    fn accept_host_connections(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("vsock: failed to accept host connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("vsock: failed to set host connection non-blocking: {}", e);
+                continue;
+            }
+
+            let id = self.next_handshake_id;
+            self.next_handshake_id = self.next_handshake_id.wrapping_add(1);
+            if let Err(e) = self.wait_ctx.add(&stream, MuxerToken::Handshake { id }) {
+                error!("vsock: failed to wait for host connection: {}", e);
+                continue;
+            }
+            self.handshakes.insert(
+                id,
+                Handshake {
+                    stream,
+                    buf: Vec::new(),
+                },
+            );
+        }
+    }
+
+    fn process_handshake(&mut self, id: u32) {
+        let mut handshake = match self.handshakes.remove(&id) {
+            Some(h) => h,
+            None => return,
+        };
+
+        let mut buf = [0u8; MAX_HANDSHAKE_LEN];
+        let room = MAX_HANDSHAKE_LEN - handshake.buf.len();
+        match handshake.stream.read(&mut buf[..room]) {
+            Ok(0) => {
+                let _ = self.wait_ctx.delete(&handshake.stream);
+                return;
+            }
+            Ok(n) => handshake.buf.extend_from_slice(&buf[..n]),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                self.handshakes.insert(id, handshake);
+                return;
+            }
+            Err(e) => {
+                warn!("vsock: failed to read from host connection: {}", e);
+                let _ = self.wait_ctx.delete(&handshake.stream);
+                return;
+            }
+        }
+
+        let line_len = match handshake.buf.iter().position(|&b| b == b'\n') {
+            Some(pos) => pos,
+            None if handshake.buf.len() < MAX_HANDSHAKE_LEN => {
+                self.handshakes.insert(id, handshake);
+                return;
+            }
+            None => {
+                warn!("vsock: host connection sent an overlong handshake");
+                let _ = self.wait_ctx.delete(&handshake.stream);
+                return;
+            }
+        };
+
+        // Whatever was sent after the CONNECT line can't be forwarded before the guest accepts
+        // the connection, so a well behaved client must wait for the `OK` reply.
+        let _ = self.wait_ctx.delete(&handshake.stream);
+        let peer_port = match parse_connect(&handshake.buf[..line_len]) {
+            Some(port) => port,
+            None => {
+                warn!("vsock: host connection sent an invalid handshake");
+                return;
+            }
+        };
+
+        let local_port = match self.alloc_local_port(peer_port) {
+            Some(port) => port,
+            None => {
+                warn!("vsock: no host port available for connection");
+                return;
+            }
+        };
+        let mut conn = Connection::new(
+            local_port,
+            peer_port,
+            handshake.stream,
+            ConnState::LocalInit,
+        );
+        let pkt = conn_packet(self.guest_cid, &mut conn, VIRTIO_VSOCK_OP_REQUEST, 0);
+        if self.add_conn(conn).is_some() {
+            self.pending.push_back(pkt);
+        }
+    }
+
+    fn alloc_local_port(&mut self, peer_port: u32) -> Option<u32> {
+        for _ in 0..FIRST_LOCAL_PORT {
+            let port = self.next_local_port;
+            self.next_local_port = match self.next_local_port.checked_add(1) {
+                Some(p) => p,
+                None => FIRST_LOCAL_PORT,
+            };
+            if !self.conn_ids.contains_key(&(port, peer_port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn add_conn(&mut self, conn: Connection) -> Option<u32> {
+        let key = (conn.local_port, conn.peer_port);
+        if self.conn_ids.contains_key(&key) {
+            warn!("vsock: connection {:?} already exists", key);
+            return None;
+        }
+
+        let id = self.next_conn_id;
+        self.next_conn_id = self.next_conn_id.wrapping_add(1);
+        if let Err(e) =
+            self.wait_ctx
+                .add_for_event(&conn.stream, conn.event_type(), MuxerToken::Connection { id })
+        {
+            error!("vsock: failed to wait for host socket: {}", e);
+            return None;
+        }
+        self.conn_ids.insert(key, id);
+        self.conns.insert(id, conn);
+        Some(id)
+    }
+
+    fn flush_conn(&mut self, id: u32) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if let Err(e) = conn.flush() {
+            warn!("vsock: failed to write to host socket: {}", e);
+            self.reset_conn(id);
+            return;
+        }
+
+        if (conn.fwd_cnt - conn.last_fwd_cnt_sent).0 >= CONN_CREDIT_UPDATE_THRESHOLD {
+            let pkt = conn_packet(self.guest_cid, conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+            self.pending.push_back(pkt);
+        }
+    }
+
+    /// Stops waiting on the host socket of a connection whose host application went away. Any
+    /// data left in the socket is still forwarded to the guest before the connection is closed.
+    fn host_hangup(&mut self, id: u32) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        // Hangups are reported regardless of the events waited for, so keeping the socket in the
+        // wait context would make it trigger continuously.
+        if !conn.hungup {
+            conn.hungup = true;
+            let _ = self.wait_ctx.delete(&conn.stream);
+        }
+        // Nothing more will be read from the socket if the guest stopped receiving.
+        if conn.state == ConnState::Established
+            && conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+        {
+            if let Some(pkt) = self.close_conn(id) {
+                self.pending.push_back(pkt);
+            }
+        }
+    }
+
+    /// Marks the connection as closed by the host and returns the shutdown packet for the guest.
+    fn close_conn(&mut self, id: u32) -> Option<virtio_vsock_hdr> {
+        let conn = self.conns.get_mut(&id)?;
+        conn.readable = false;
+        conn.state = ConnState::Closing;
+        if !conn.hungup {
+            conn.hungup = true;
+            let _ = self.wait_ctx.delete(&conn.stream);
+        }
+        Some(conn_packet(
+            self.guest_cid,
+            conn,
+            VIRTIO_VSOCK_OP_SHUTDOWN,
+            VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND,
+        ))
+    }
+
+    fn update_interest(&mut self, id: u32) {
+        if let Some(conn) = self.conns.get(&id).filter(|conn| !conn.hungup) {
+            if let Err(e) = self.wait_ctx.modify(
+                &conn.stream,
+                conn.event_type(),
+                MuxerToken::Connection { id },
+            ) {
+                error!("vsock: failed to update host socket events: {}", e);
+            }
+        }
+    }
+
+    /// Closes the connection and tells the guest about it.
+    fn reset_conn(&mut self, id: u32) {
+        if let Some(mut conn) = self.remove_conn(id) {
+            let pkt = conn_packet(self.guest_cid, &mut conn, VIRTIO_VSOCK_OP_RST, 0);
+            self.pending.push_back(pkt);
+        }
+    }
+
+    fn remove_conn(&mut self, id: u32) -> Option<Connection> {
+        let conn = self.conns.remove(&id)?;
+        self.conn_ids.remove(&(conn.local_port, conn.peer_port));
+        if !conn.hungup {
+            let _ = self.wait_ctx.delete(&conn.stream);
+        }
+        Some(conn)
+    }
+}
+
+impl AsRawDescriptor for Muxer {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.wait_ctx.as_raw_descriptor()
+    }
+}
+
+/// Parses a `CONNECT <port>` line sent by a host application.
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.trim_end_matches('\r').split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("connect") {
+        return None;
+    }
+    let port = words.next()?.parse().ok()?;
+    if words.next().is_some() {
+        return None;
+    }
+    Some(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+
+    use tempfile::TempDir;
+
+    const GUEST_CID: u64 = 3;
+
+    fn guest_packet(src_port: u32, dst_port: u32, op: u16, len: u32) -> virtio_vsock_hdr {
+        virtio_vsock_hdr {
+            src_cid: GUEST_CID.into(),
+            dst_cid: VMADDR_CID_HOST.into(),
+            src_port: src_port.into(),
+            dst_port: dst_port.into(),
+            len: len.into(),
+            type_: VIRTIO_VSOCK_TYPE_STREAM.into(),
+            op: op.into(),
+            flags: 0.into(),
+            buf_alloc: 65536.into(),
+            fwd_cnt: 0.into(),
+        }
+    }
+
+    fn new_muxer(dir: &TempDir) -> (Muxer, PathBuf) {
+        let path = dir.path().join("vsock");
+        let listener = UnlinkUnixListener(UnixListener::bind(&path).unwrap());
+        (Muxer::new(GUEST_CID, &path, listener).unwrap(), path)
+    }
+
+    fn wait_for_rx(muxer: &mut Muxer) -> (virtio_vsock_hdr, Vec<u8>) {
+        for _ in 0..100 {
+            muxer.process_events().unwrap();
+            if let Some(pkt) = muxer.recv_for_guest(4096) {
+                return pkt;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no packet for the guest");
+    }
+
+    #[test]
+    fn parse_connect_line() {
+        assert_eq!(parse_connect(b"CONNECT 52"), Some(52));
+        assert_eq!(parse_connect(b"connect 1234\r"), Some(1234));
+        assert_eq!(parse_connect(b"CONNECT"), None);
+        assert_eq!(parse_connect(b"CONNECT x"), None);
+        assert_eq!(parse_connect(b"LISTEN 52"), None);
+    }
+
+    #[test]
+    fn guest_connect_refused() {
+        let dir = TempDir::new().unwrap();
+        let (mut muxer, _) = new_muxer(&dir);
+
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_REQUEST, 0), &[]);
+        let (hdr, _) = muxer.recv_for_guest(4096).unwrap();
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_RST);
+        assert_eq!(hdr.dst_port.to_native(), 1024);
+    }
+
+    #[test]
+    fn guest_connect_and_transfer() {
+        let dir = TempDir::new().unwrap();
+        let (mut muxer, path) = new_muxer(&dir);
+        let host = UnixListener::bind(format!("{}_52", path.display())).unwrap();
+
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_REQUEST, 0), &[]);
+        let (hdr, _) = muxer.recv_for_guest(4096).unwrap();
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_RESPONSE);
+        let (mut host_stream, _) = host.accept().unwrap();
+
+        // Guest to host.
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_RW, 5), b"hello");
+        let mut buf = [0u8; 5];
+        host_stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Host to guest.
+        host_stream.write_all(b"world").unwrap();
+        let (hdr, payload) = wait_for_rx(&mut muxer);
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_RW);
+        assert_eq!(hdr.src_port.to_native(), 52);
+        assert_eq!(hdr.dst_port.to_native(), 1024);
+        assert_eq!(hdr.fwd_cnt.to_native(), 5);
+        assert_eq!(payload, b"world");
+
+        // Closing the host socket shuts the stream down.
+        drop(host_stream);
+        let (hdr, _) = wait_for_rx(&mut muxer);
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_SHUTDOWN);
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_RST, 0), &[]);
+        assert!(!muxer.has_pending_rx());
+        assert!(muxer.conns.is_empty());
+    }
+
+    #[test]
+    fn guest_respects_credit() {
+        let dir = TempDir::new().unwrap();
+        let (mut muxer, path) = new_muxer(&dir);
+        let host = UnixListener::bind(format!("{}_52", path.display())).unwrap();
+
+        let mut req = guest_packet(1024, 52, VIRTIO_VSOCK_OP_REQUEST, 0);
+        req.buf_alloc = 3.into();
+        muxer.send_to_host(&req, &[]);
+        muxer.recv_for_guest(4096).unwrap();
+        let (mut host_stream, _) = host.accept().unwrap();
+
+        host_stream.write_all(b"abcdef").unwrap();
+        let (_, payload) = wait_for_rx(&mut muxer);
+        assert_eq!(payload, b"abc");
+        // The guest has no credit left until it reports forwarding the data.
+        muxer.process_events().unwrap();
+        assert!(muxer.recv_for_guest(4096).is_none());
+
+        let mut update = guest_packet(1024, 52, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+        update.buf_alloc = 3.into();
+        update.fwd_cnt = 3.into();
+        muxer.send_to_host(&update, &[]);
+        let (_, payload) = wait_for_rx(&mut muxer);
+        assert_eq!(payload, b"def");
+    }
+
+    #[test]
+    fn host_connect() {
+        let dir = TempDir::new().unwrap();
+        let (mut muxer, path) = new_muxer(&dir);
+
+        let mut host_stream = UnixStream::connect(&path).unwrap();
+        host_stream.write_all(b"CONNECT 1234\n").unwrap();
+        let (hdr, _) = wait_for_rx(&mut muxer);
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!(hdr.dst_cid.to_native(), GUEST_CID);
+        assert_eq!(hdr.dst_port.to_native(), 1234);
+        let local_port = hdr.src_port.to_native();
+
+        muxer.send_to_host(
+            &guest_packet(1234, local_port, VIRTIO_VSOCK_OP_RESPONSE, 0),
+            &[],
+        );
+        let mut reader = std::io::BufReader::new(host_stream.try_clone().unwrap());
+        let mut line = String::new();
+        std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+        assert_eq!(line, format!("OK {}\n", local_port));
+
+        host_stream.write_all(b"ping").unwrap();
+        let (hdr, payload) = wait_for_rx(&mut muxer);
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_RW);
+        assert_eq!(payload, b"ping");
+    }
+
+    #[test]
+    fn rejected_packet_resets_connection() {
+        let dir = TempDir::new().unwrap();
+        let (mut muxer, path) = new_muxer(&dir);
+        let host = UnixListener::bind(format!("{}_52", path.display())).unwrap();
+
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_REQUEST, 0), &[]);
+        muxer.recv_for_guest(4096).unwrap();
+        let (mut host_stream, _) = host.accept().unwrap();
+
+        muxer.reject_packet(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_RW, 1 << 20));
+        let (hdr, _) = muxer.recv_for_guest(4096).unwrap();
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_RST);
+        assert_eq!(hdr.dst_port.to_native(), 1024);
+        assert!(muxer.conns.is_empty());
+
+        // The host application sees the stream end rather than a gap in the data.
+        let mut buf = Vec::new();
+        host_stream.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn packet_without_connection_is_reset() {
+        let dir = TempDir::new().unwrap();
+        let (mut muxer, _) = new_muxer(&dir);
+
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_RW, 1), b"x");
+        let (hdr, _) = muxer.recv_for_guest(4096).unwrap();
+        assert_eq!(hdr.op.to_native(), VIRTIO_VSOCK_OP_RST);
+
+        // Resets are never answered.
+        muxer.send_to_host(&guest_packet(1024, 52, VIRTIO_VSOCK_OP_RST, 0), &[]);
+        assert!(muxer.recv_for_guest(4096).is_none());
+    }
+}
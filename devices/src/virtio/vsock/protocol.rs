@@ -0,0 +1,68 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Wire format of the virtio-vsock transport, as described in section 5.10 of the virtio spec.
+
+use data_model::{DataInit, Le16, Le32, Le64};
+
+/// The well-known context ID of the host.
+pub const VMADDR_CID_HOST: u64 = 2;
+
+/// The only socket type supported by the device.
+pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Packet operations.
+pub const VIRTIO_VSOCK_OP_INVALID: u16 = 0;
+pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Flags of VIRTIO_VSOCK_OP_SHUTDOWN.
+pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+/// Header of every packet exchanged on the rx and tx queues.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct virtio_vsock_hdr {
+    pub src_cid: Le64,
+    pub dst_cid: Le64,
+    pub src_port: Le32,
+    pub dst_port: Le32,
+    pub len: Le32,
+    pub type_: Le16,
+    pub op: Le16,
+    pub flags: Le32,
+    pub buf_alloc: Le32,
+    pub fwd_cnt: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_vsock_hdr {}
+
+/// Device configuration space.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct virtio_vsock_config {
+    pub guest_cid: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_vsock_config {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::size_of;
+
+    #[test]
+    fn header_size() {
+        assert_eq!(size_of::<virtio_vsock_hdr>(), 44);
+    }
+}
@@ -7,13 +7,15 @@ use std::ffi::CStr;
 use std::fs::File;
 use std::io;
 use std::mem;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::sys;
 
-use crate::server::Mapper;
+use crate::server::{Mapper, Notifier};
 pub use crate::sys::{
-    FsOptions, IoctlFlags, IoctlIovec, OpenOptions, RemoveMappingOne, SetattrValid, ROOT_ID,
+    FileLock, FsOptions, IoctlFlags, IoctlIovec, OpenOptions, RemoveMappingOne, SetattrValid,
+    OFFSET_MAX, ROOT_ID,
 };
 
 const MAX_BUFFER_SIZE: u32 = 1 << 20;
@@ -1093,18 +1095,72 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn getlk(&self) -> io::Result<()> {
+    /// Test for a POSIX file lock.
+    ///
+    /// Checks whether `lock` could be placed on the file associated with `inode` on behalf of
+    /// `owner`. Returns the first lock that conflicts with `lock` or, if there is no such lock,
+    /// `lock` with its type set to `F_UNLCK`. Ranges use inclusive `start` and `end` offsets, with
+    /// an `end` of `OFFSET_MAX` meaning the lock extends to the end of the file.
+    ///
+    /// `handle` is the `Handle` returned by the file system from the `open` method, if any. If the
+    /// file system did not return a `Handle` from `open` then the contents of `handle` are
+    /// undefined.
+    ///
+    /// This method is only called if the `FsOptions::POSIX_LOCKS` feature is enabled. If it is not
+    /// enabled, then the kernel will handle locks locally without involving the file system.
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlk(&self) -> io::Result<()> {
+    /// Acquire, modify, or release a POSIX file lock.
+    ///
+    /// Places `lock` on the file associated with `inode` on behalf of `owner`, or removes the
+    /// locks `owner` holds in that range if the lock type is `F_UNLCK`. If a conflicting lock is
+    /// held by another owner, this method must fail with `EAGAIN` instead of waiting.
+    ///
+    /// If `flags` contains `LK_FLOCK`, then the request was made with `flock(2)` rather than
+    /// `fcntl(2)` and applies to the whole file. This only happens if the `FsOptions::FLOCK_LOCKS`
+    /// feature is enabled.
+    ///
+    /// All locks held by `owner` must be removed when `flush` is called with the same lock owner
+    /// and, for `flock(2)` locks, when `release` is called with `flock_release` set.
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlkw(&self) -> io::Result<()> {
+    /// Acquire, modify, or release a POSIX file lock, waiting for conflicting locks to go away.
+    ///
+    /// This is the same as `setlk` except that if a conflicting lock is held by another owner then
+    /// this method waits until the lock can be placed. The wait may be arbitrarily long so callers
+    /// of `Server::handle_message` should use `Server::may_block` to avoid handling these
+    /// requests on a thread that other requests depend on.
+    ///
+    /// Implementations should stop waiting and fail with `EINTR` once `destroy` is called.
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
@@ -1113,18 +1169,79 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn poll(&self) -> io::Result<()> {
+    /// Poll a file for I/O readiness.
+    ///
+    /// Returns the subset of `events` (`POLLIN`, `POLLOUT`, etc) for which the file associated
+    /// with `inode` is currently ready, without waiting.
+    ///
+    /// If `flags` contains `POLL_SCHEDULE_NOTIFY` and none of the events are ready, then the
+    /// client would like to be told once that changes. In that case the file system should call
+    /// `Notifier::poll_wakeup` with `khandle` on the notifier passed to `set_notifier`, if any.
+    /// The client polls the file again after receiving the notification.
+    ///
+    /// `handle` is the `Handle` returned by the file system from the `open` method, if any. If the
+    /// file system did not return a `Handle` from `open` then the contents of `handle` are
+    /// undefined.
+    ///
+    /// If this method fails with an `ENOSYS` error, then the kernel will treat that as a permanent
+    /// failure and consider all files to always be ready for reading and writing without
+    /// forwarding any more poll requests to the file system.
+    fn poll(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn notify_reply(&self) -> io::Result<()> {
+    /// Set the notifier used to send unsolicited messages to the client.
+    ///
+    /// This is called by transports that can deliver notifications before any requests are
+    /// handled. File systems that never send notifications can ignore it.
+    fn set_notifier(&self, notifier: Arc<dyn Notifier>) {}
+
+    /// Receive data requested with a retrieve notification.
+    ///
+    /// `data` holds the contents of the file associated with `inode` starting at `offset`, as
+    /// requested by the retrieve notification identified by `notify_unique`. The client doesn't
+    /// expect a reply to this message.
+    fn notify_reply(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        notify_unique: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn lseek(&self) -> io::Result<()> {
+    /// Reposition the offset of an open file.
+    ///
+    /// Returns the offset in the file associated with `inode` found by applying `whence` to
+    /// `offset`. The kernel handles `SEEK_SET`, `SEEK_CUR`, and `SEEK_END` itself so in practice
+    /// `whence` is either `SEEK_DATA` or `SEEK_HOLE`, used to find the data regions of sparse
+    /// files. Fails with `ENXIO` if there is no matching region at or after `offset`.
+    ///
+    /// `handle` is the `Handle` returned by the file system from the `open` method, if any. If the
+    /// file system did not return a `Handle` from `open` then the contents of `handle` are
+    /// undefined.
+    ///
+    /// If this method fails with an `ENOSYS` error, then the kernel will treat that as a permanent
+    /// failure and treat the whole file as data without forwarding any more `SEEK_DATA` or
+    /// `SEEK_HOLE` requests to the file system.
+    fn lseek(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
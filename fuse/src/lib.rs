@@ -18,7 +18,9 @@ pub mod sys;
 pub mod worker;
 
 pub use mount::mount;
-pub use server::{Mapper, Reader, Server, Writer};
+pub use server::{
+    encode_poll_wakeup, encode_remove_mapping, Mapper, Notifier, Reader, Server, Writer,
+};
 
 /// Errors that may occur during the creation or operation of an Fs device.
 #[sorted]
@@ -4,13 +4,15 @@
 
 use std::ffi::{CString, OsStr};
 use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 /// Mount options to pass to mount(2) for a FUSE filesystem. See the [official document](
 /// https://www.kernel.org/doc/html/latest/filesystems/fuse.html#mount-options) for the
 /// descriptions.
+#[derive(Clone)]
 pub enum MountOption<'a> {
     FD(RawFd),
     RootMode(u32),
@@ -89,6 +91,37 @@ pub fn mount<P: AsRef<OsStr>>(
     }
 }
 
+/// Opens /dev/fuse and mounts a FUSE filesystem served through it at `mountpoint`, owned by the
+/// current user. `options` are passed to mount(2) in addition to the ones needed to set up the
+/// connection. Returns the /dev/fuse file, which can be passed to
+/// `fuse::worker::start_message_loop()`.
+///
+/// Like `mount`, this requires CAP_SYS_ADMIN privilege.
+pub fn mount_dev_fuse<P: AsRef<OsStr>>(
+    mountpoint: P,
+    name: &str,
+    flags: libc::c_ulong,
+    options: &[MountOption],
+) -> Result<File, io::Error> {
+    let dev_fuse = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+
+    // Safe because these functions have no preconditions and can't fail.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let mut all_options = vec![
+        MountOption::FD(dev_fuse.as_raw_fd()),
+        MountOption::RootMode(libc::S_IFDIR),
+        MountOption::UserId(uid),
+        MountOption::GroupId(gid),
+    ];
+    all_options.extend(options.iter().map(MountOption::clone));
+    mount(mountpoint, name, flags, &all_options)?;
+
+    Ok(dev_fuse)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,6 +120,66 @@ impl<'a, M: Mapper> Mapper for &'a M {
     }
 }
 
+/// A trait for sending unsolicited notifications to the FUSE kernel driver.
+///
+/// Notifications aren't replies to any request so they need their own path to the driver, which
+/// not every transport has.
+pub trait Notifier: Send + Sync {
+    /// Tells the driver that the file it polled with `khandle` may now be ready, so that it polls
+    /// the file again.
+    fn poll_wakeup(&self, khandle: u64) -> io::Result<()>;
+
+    /// Asks the driver to tear down the DAX mappings in `msgs` and to remove them with
+    /// `FUSE_REMOVEMAPPING` requests, so that the device can reclaim that part of the window.
+    /// Transports without a DAX window don't support this.
+    fn remove_mapping(&self, _msgs: &[RemoveMappingOne]) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+/// Encodes a notification that the file polled with `khandle` may now be ready into `w`. Returns
+/// the length of the encoded message, which must reach the driver in a single write.
+pub fn encode_poll_wakeup<W: io::Write>(khandle: u64, mut w: W) -> io::Result<usize> {
+    let out = NotifyPollWakeupOut { kh: khandle };
+    let header = OutHeader {
+        len: (size_of::<OutHeader>() + size_of::<NotifyPollWakeupOut>()) as u32,
+        // Notifications carry their type in the `error` field and have a `unique` of 0.
+        error: NotifyOpcode::Poll as i32,
+        unique: 0,
+    };
+
+    w.write_all(header.as_slice())?;
+    w.write_all(out.as_slice())?;
+    Ok(header.len as usize)
+}
+
+/// Encodes a request for the driver to remove the DAX mappings in `msgs` into `w`. Returns the
+/// length of the encoded message, which must reach the driver in a single write.
+pub fn encode_remove_mapping<W: io::Write>(
+    msgs: &[RemoveMappingOne],
+    mut w: W,
+) -> io::Result<usize> {
+    let count: u32 = msgs
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let in_ = RemoveMappingIn { count };
+    let header = OutHeader {
+        len: (size_of::<OutHeader>()
+            + size_of::<RemoveMappingIn>()
+            + msgs.len() * size_of::<RemoveMappingOne>()) as u32,
+        error: NotifyOpcode::RemoveMapping as i32,
+        unique: 0,
+    };
+
+    w.write_all(header.as_slice())?;
+    w.write_all(in_.as_slice())?;
+    for msg in msgs {
+        w.write_all(msg.as_slice())?;
+    }
+    Ok(header.len as usize)
+}
+
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
 }
@@ -129,6 +189,37 @@ impl<F: FileSystem + Sync> Server<F> {
         Server { fs }
     }
 
+    /// Returns true if handling the message in `r` may block for an arbitrarily long time, like a
+    /// `FUSE_SETLKW` request waiting for a conflicting lock to be released. Transports that handle
+    /// requests on a single thread should hand these messages off to another thread so that the
+    /// requests that would release the lock can still make progress. `r` is not consumed.
+    pub fn may_block<R: Reader + Clone>(&self, r: &R) -> bool {
+        let mut r = r.clone();
+        match InHeader::from_reader(&mut r) {
+            Ok(in_header) => in_header.opcode == Opcode::Setlkw as u32,
+            Err(_) => false,
+        }
+    }
+
+    /// Replies to the message in `r` with `err` without handling it, e.g. because a transport
+    /// has no room left for another message that `may_block`.
+    pub fn reject_message<R: Reader, W: Writer>(
+        &self,
+        mut r: R,
+        w: W,
+        err: io::Error,
+    ) -> Result<usize> {
+        let in_header = InHeader::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+        reply_error(err, in_header.unique, w)
+    }
+
+    /// Cleans up the file system when the transport stops without the client sending
+    /// `FUSE_DESTROY`, e.g. because the device was reset. Messages that are still blocked, like a
+    /// `FUSE_SETLKW`, stop waiting so that the transport can wait for them to complete.
+    pub fn reset(&self) {
+        self.fs.destroy();
+    }
+
     pub fn handle_message<R: Reader + ZeroCopyReader, W: Writer + ZeroCopyWriter, M: Mapper>(
         &self,
         mut r: R,
@@ -1227,27 +1318,69 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn getlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.getlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn getlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = LkIn::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.getlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(lk) => reply_ok(Some(LkOut { lk }), None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.setlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn setlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = LkIn::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.setlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlkw<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.setlkw() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn setlkw<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = LkIn::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.setlkw(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
@@ -1373,25 +1506,64 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn poll<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.poll() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn poll<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let PollIn {
+            fh,
+            kh,
+            flags,
+            events,
+        } = PollIn::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.poll(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            kh,
+            flags,
+            events,
+        ) {
+            Ok(revents) => {
+                let out = PollOut {
+                    revents,
+                    padding: 0,
+                };
+                reply_ok(Some(out), None, in_header.unique, w)
+            }
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
     fn notify_reply<R: Reader, W: Writer>(
         &self,
         in_header: InHeader,
-        mut _r: R,
-        w: W,
+        mut r: R,
+        _w: W,
     ) -> Result<usize> {
-        if let Err(e) = self.fs.notify_reply() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+        let NotifyRetrieveIn { offset, size, .. } =
+            NotifyRetrieveIn::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        if size > self.fs.max_buffer_size() {
+            error!("notify reply is too large: {} bytes", size);
+            return Ok(0);
+        }
+
+        let mut data = vec![0; size as usize];
+        r.read_exact(&mut data).map_err(Error::DecodeMessage)?;
+
+        // The `unique` field of a notify reply holds the `notify_unique` of the retrieve
+        // notification that it answers.
+        if let Err(e) = self.fs.notify_reply(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            in_header.unique,
+            offset,
+            &data,
+        ) {
+            error!("failed to handle notify reply: {}", e);
         }
+
+        // The kernel doesn't expect a reply to a notify reply.
+        Ok(0)
     }
 
     fn batch_forget<R: Reader, W: Writer>(
@@ -1461,11 +1633,20 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn lseek<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.lseek() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn lseek<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LseekIn {
+            fh, offset, whence, ..
+        } = LseekIn::from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.lseek(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            offset,
+            whence,
+        ) {
+            Ok(offset) => reply_ok(Some(LseekOut { offset }), None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
@@ -376,6 +376,10 @@ pub const GETATTR_FH: u32 = 1;
 // Lock flags.
 pub const LK_FLOCK: u32 = 1;
 
+/// The largest offset that a lock range can end at. A lock with this `end` offset extends to the
+/// end of the file, however large it grows.
+pub const OFFSET_MAX: u64 = i64::MAX as u64;
+
 // Write flags.
 
 /// Delayed write from page cache, file handle is guessed.
@@ -615,6 +619,12 @@ pub enum NotifyOpcode {
     Retrieve = 5,
     Delete = 6,
     CodeMax = 7,
+
+    // Not part of the upstream protocol. Asks the driver to remove DAX mappings so that the device
+    // can reclaim that part of the window. Followed by a `RemoveMappingIn` and `count`
+    // `RemoveMappingOne`s, just like a `RemoveMapping` request. Only sent to drivers that opt in
+    // to it, e.g. by acking the virtio-fs device's crosvm-specific DAX reclaim feature.
+    RemoveMapping = 0x7fff_ffff,
 }
 
 #[repr(C)]
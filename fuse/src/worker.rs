@@ -7,9 +7,10 @@ use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
 use std::mem::size_of;
 use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 
 use crate::filesystem::{FileSystem, ZeroCopyReader, ZeroCopyWriter};
-use crate::server::{Mapper, Reader, Server, Writer};
+use crate::server::{encode_poll_wakeup, Mapper, Notifier, Reader, Server, Writer};
 use crate::sys;
 use crate::{Error, Result};
 
@@ -143,13 +144,33 @@ impl Mapper for DevFuseMapper {
     }
 }
 
-/// Start the FUSE message handling loop. Returns when an error happens.
+struct DevFuseNotifier {
+    // File representing /dev/fuse for writing notifications. Each write to /dev/fuse is a complete
+    // message so notifications don't interleave with replies written by the message loop.
+    dev_fuse: File,
+}
+
+impl Notifier for DevFuseNotifier {
+    fn poll_wakeup(&self, khandle: u64) -> io::Result<()> {
+        let mut buf = Vec::new();
+        encode_poll_wakeup(khandle, &mut buf)?;
+        (&self.dev_fuse).write_all(&buf)
+    }
+}
+
+/// Start the FUSE message handling loop. Returns when an error happens or, successfully, when the
+/// filesystem is unmounted.
 pub fn start_message_loop<F: FileSystem + Sync>(
     dev_fuse: File,
     max_write: u32,
     max_read: u32,
     fs: F,
 ) -> Result<()> {
+    let notifier = DevFuseNotifier {
+        dev_fuse: dev_fuse.try_clone().map_err(Error::EndpointSetup)?,
+    };
+    fs.set_notifier(Arc::new(notifier));
+
     let server = Server::new(fs);
     let mut dev_fuse_reader = {
         let rfile = dev_fuse.try_clone().map_err(Error::EndpointSetup)?;
@@ -166,7 +187,12 @@ pub fn start_message_loop<F: FileSystem + Sync>(
     };
     let dev_fuse_mapper = DevFuseMapper::new();
     loop {
-        server.handle_message(&mut dev_fuse_reader, &mut dev_fuse_writer, &dev_fuse_mapper)?;
+        match server.handle_message(&mut dev_fuse_reader, &mut dev_fuse_writer, &dev_fuse_mapper) {
+            Ok(_) => {}
+            // Reading from /dev/fuse fails with ENODEV once the filesystem has been unmounted.
+            Err(Error::DecodeMessage(e)) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+            Err(e) => return Err(e),
+        }
 
         // Since we're reusing the buffer to avoid repeated allocation, drain the possible
         // residual from the buffer.
@@ -1270,7 +1270,7 @@ bool dwl_surface_close_requested(const struct dwl_surface *self)
 	return self->close_requested;
 }
 
-void dwl_surface_set_position(struct dwl_surface *self, uint32_t x, uint32_t y)
+void dwl_surface_set_position(struct dwl_surface *self, int32_t x, int32_t y)
 {
 	if (self->subsurface) {
 		wl_subsurface_set_position(self->subsurface, x / self->scale,
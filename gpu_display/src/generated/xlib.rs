@@ -10,6 +10,9 @@ extern "C" {}
 #[link(name = "Xext")]
 extern "C" {}
 
+#[link(name = "Xcursor")]
+extern "C" {}
+
 /* automatically generated by rust-bindgen */
 
 pub const KeyPressMask: u32 = 1;
@@ -1937,6 +1940,7 @@ pub type Window = XID;
 pub type Drawable = XID;
 pub type Font = XID;
 pub type Pixmap = XID;
+pub type Cursor = XID;
 pub type Colormap = XID;
 pub type KeySym = XID;
 pub type KeyCode = ::std::os::raw::c_uchar;
@@ -2658,6 +2662,9 @@ extern "C" {
 extern "C" {
     pub fn XDefaultDepthOfScreen(arg1: *mut Screen) -> ::std::os::raw::c_int;
 }
+extern "C" {
+    pub fn XDefineCursor(arg1: *mut Display, arg2: Window, arg3: Cursor) -> ::std::os::raw::c_int;
+}
 extern "C" {
     pub fn XDestroyWindow(arg1: *mut Display, arg2: Window) -> ::std::os::raw::c_int;
 }
@@ -2667,6 +2674,9 @@ extern "C" {
 extern "C" {
     pub fn XFree(arg1: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int;
 }
+extern "C" {
+    pub fn XFreeCursor(arg1: *mut Display, arg2: Cursor) -> ::std::os::raw::c_int;
+}
 extern "C" {
     pub fn XFreeGC(arg1: *mut Display, arg2: GC) -> ::std::os::raw::c_int;
 }
@@ -2712,6 +2722,9 @@ pub struct XSizeHints__bindgen_ty_1 {
     pub x: ::std::os::raw::c_int,
     pub y: ::std::os::raw::c_int,
 }
+extern "C" {
+    pub fn XUndefineCursor(arg1: *mut Display, arg2: Window) -> ::std::os::raw::c_int;
+}
 extern "C" {
     pub fn XDestroyImage(ximage: *mut XImage) -> ::std::os::raw::c_int;
 }
@@ -2804,3 +2817,31 @@ extern "C" {
         arg8: ::std::os::raw::c_uint,
     ) -> *mut XImage;
 }
+pub type XcursorUInt = ::std::os::raw::c_uint;
+pub type XcursorDim = XcursorUInt;
+pub type XcursorPixel = XcursorUInt;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct _XcursorImage {
+    pub version: XcursorUInt,
+    pub size: XcursorDim,
+    pub width: XcursorDim,
+    pub height: XcursorDim,
+    pub xhot: XcursorDim,
+    pub yhot: XcursorDim,
+    pub delay: XcursorUInt,
+    pub pixels: *mut XcursorPixel,
+}
+pub type XcursorImage = _XcursorImage;
+extern "C" {
+    pub fn XcursorImageCreate(
+        width: ::std::os::raw::c_int,
+        height: ::std::os::raw::c_int,
+    ) -> *mut XcursorImage;
+}
+extern "C" {
+    pub fn XcursorImageDestroy(image: *mut XcursorImage);
+}
+extern "C" {
+    pub fn XcursorImageLoadCursor(dpy: *mut Display, image: *const XcursorImage) -> Cursor;
+}
@@ -18,6 +18,9 @@ extern "C" {}
 #[link(name = "Xext")]
 extern "C" {}
 
+#[link(name = "Xcursor")]
+extern "C" {}
+
 EOF
 
 bindgen --no-layout-tests --no-derive-debug \
@@ -28,13 +31,18 @@ bindgen --no-layout-tests --no-derive-debug \
   --allowlist-function XConnectionNumber \
   --allowlist-function XCreateGC \
   --allowlist-function XCreateSimpleWindow \
+  --allowlist-function XcursorImageCreate \
+  --allowlist-function XcursorImageDestroy \
+  --allowlist-function XcursorImageLoadCursor \
   --allowlist-function XDefaultDepthOfScreen \
   --allowlist-function XDefaultScreenOfDisplay \
   --allowlist-function XDefaultVisualOfScreen \
+  --allowlist-function XDefineCursor \
   --allowlist-function XDestroyImage \
   --allowlist-function XDestroyWindow \
   --allowlist-function XFlush \
   --allowlist-function XFree \
+  --allowlist-function XFreeCursor \
   --allowlist-function XFreeGC \
   --allowlist-function XGetVisualInfo \
   --allowlist-function XInternAtom \
@@ -54,6 +62,7 @@ bindgen --no-layout-tests --no-derive-debug \
   --allowlist-function XShmGetEventBase \
   --allowlist-function XShmPutImage \
   --allowlist-function XShmQueryExtension \
+  --allowlist-function XUndefineCursor \
   --allowlist-var 'XK_.*' \
   --allowlist-var ButtonPress \
   --allowlist-var ButtonPressMask \
@@ -80,6 +89,7 @@ bindgen --no-layout-tests --no-derive-debug \
   --allowlist-var VisualScreenMask \
   --allowlist-var ZPixmap \
   --allowlist-type Display \
+  --allowlist-type Cursor \
   --allowlist-type GC \
   --allowlist-type Screen \
   --allowlist-type XShmCompletionEvent \
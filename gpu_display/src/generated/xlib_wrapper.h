@@ -5,4 +5,5 @@
 #include <X11/Xlib.h>
 #include <X11/Xutil.h>
 #include <X11/extensions/XShm.h>
+#include <X11/Xcursor/Xcursor.h>
 #include <X11/keysymdef.h>
\ No newline at end of file
@@ -111,6 +111,21 @@ impl DisplayT for DisplayStub {
             buffer: None,
         }))
     }
+
+    fn create_cursor_surface(
+        &mut self,
+        _parent_surface_id: u32,
+        _parent: &dyn GpuDisplaySurface,
+        _surface_id: u32,
+        width: u32,
+        height: u32,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        Ok(Box::new(StubSurface {
+            width,
+            height,
+            buffer: None,
+        }))
+    }
 }
 
 impl AsRawDescriptor for DisplayStub {
@@ -0,0 +1,1309 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A display backend that serves scanouts to VNC clients over the remote framebuffer (RFB)
+//! protocol described in RFC 6143.
+//!
+//! Each scanout that is served has a listener of its own. Surfaces are shown on the scanout they
+//! are assigned with `set_scanout_id` and every flip is compared against the last one in square
+//! tiles, so only the tiles that changed are sent to clients. Keyboard and pointer input from any
+//! client is forwarded to the display's event devices the same way the X backend forwards it.
+//!
+//! There is no authentication, so the listeners should only be reachable by trusted clients.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::time::Duration;
+
+use base::{error, info, warn, AsRawDescriptor, EventType, PollToken, RawDescriptor, WaitContext};
+use data_model::VolatileSlice;
+use flate2::{Compress, Compression, FlushCompress};
+use linux_input_sys::virtio_input_event;
+
+use crate::keysym::keysym_to_linux_keycode;
+use crate::{
+    DisplayT, EventDeviceKind, GpuDisplayError, GpuDisplayEvents, GpuDisplayFramebuffer,
+    GpuDisplayResult, GpuDisplaySurface, SurfaceType,
+};
+
+// Surfaces are XRGB8888.
+const BYTES_PER_PIXEL: u32 = 4;
+
+// Changes are tracked, and sent to clients, in square tiles with sides of this many pixels.
+const TILE_SIZE: u32 = 64;
+
+// A client is disconnected once this many bytes are waiting to be sent to it, e.g. because it
+// stopped reading. This is enough for a full update of a 4K frame in any encoding.
+const MAX_CLIENT_BACKLOG: usize = 64 << 20;
+
+// The longest cut text message accepted from a client.
+const MAX_CUT_TEXT_LEN: usize = 1 << 20;
+
+const DESKTOP_NAME: &[u8] = b"crosvm";
+
+const SECURITY_TYPE_NONE: u8 = 1;
+
+const ENCODING_RAW: i32 = 0;
+const ENCODING_ZLIB: i32 = 6;
+const ENCODING_TIGHT: i32 = 7;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const MSG_SET_PIXEL_FORMAT: u8 = 0;
+const MSG_SET_ENCODINGS: u8 = 2;
+const MSG_FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const MSG_KEY_EVENT: u8 = 4;
+const MSG_POINTER_EVENT: u8 = 5;
+const MSG_CLIENT_CUT_TEXT: u8 = 6;
+
+const MSG_FRAMEBUFFER_UPDATE: u8 = 0;
+
+// Tight sends data shorter than this uncompressed.
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+const TIGHT_FILL: u8 = 0x80;
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_color: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    // The format of the surfaces, which is what clients are offered.
+    const XRGB8888: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_color: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn from_bytes(b: &[u8; 16]) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            true_color: b[3] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let [red_max_hi, red_max_lo] = self.red_max.to_be_bytes();
+        let [green_max_hi, green_max_lo] = self.green_max.to_be_bytes();
+        let [blue_max_hi, blue_max_lo] = self.blue_max.to_be_bytes();
+        [
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian as u8,
+            self.true_color as u8,
+            red_max_hi,
+            red_max_lo,
+            green_max_hi,
+            green_max_lo,
+            blue_max_hi,
+            blue_max_lo,
+            self.red_shift,
+            self.green_shift,
+            self.blue_shift,
+            0,
+            0,
+            0,
+        ]
+    }
+
+    // Color maps aren't supported, only true color formats.
+    fn is_supported(&self) -> bool {
+        self.true_color
+            && matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && self.red_shift < self.bits_per_pixel
+            && self.green_shift < self.bits_per_pixel
+            && self.blue_shift < self.bits_per_pixel
+    }
+
+    // Appends the XRGB8888 pixel `xrgb` to `out` in this format.
+    fn write_pixel(&self, xrgb: u32, out: &mut Vec<u8>) {
+        let channel = |value: u32, max: u16, shift: u8| (value * max as u32 / 255) << shift;
+        let value = channel((xrgb >> 16) & 0xff, self.red_max, self.red_shift)
+            | channel((xrgb >> 8) & 0xff, self.green_max, self.green_shift)
+            | channel(xrgb & 0xff, self.blue_max, self.blue_shift);
+
+        match self.bits_per_pixel {
+            8 => out.push(value as u8),
+            16 if self.big_endian => out.extend_from_slice(&(value as u16).to_be_bytes()),
+            16 => out.extend_from_slice(&(value as u16).to_le_bytes()),
+            _ if self.big_endian => out.extend_from_slice(&value.to_be_bytes()),
+            _ => out.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    // Appends the XRGB8888 pixel `xrgb` to `out` as a tight TPIXEL, which packs 24 bit colors into
+    // 3 bytes.
+    fn write_tight_pixel(&self, xrgb: u32, out: &mut Vec<u8>) {
+        if self.true_color
+            && self.bits_per_pixel == 32
+            && self.depth == 24
+            && self.red_max == 255
+            && self.green_max == 255
+            && self.blue_max == 255
+        {
+            out.extend_from_slice(&[(xrgb >> 16) as u8, (xrgb >> 8) as u8, xrgb as u8]);
+        } else {
+            self.write_pixel(xrgb, out);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    // Appends the header of a framebuffer update rectangle to `out`.
+    fn write_header(&self, encoding: i32, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.x as u16).to_be_bytes());
+        out.extend_from_slice(&(self.y as u16).to_be_bytes());
+        out.extend_from_slice(&(self.width as u16).to_be_bytes());
+        out.extend_from_slice(&(self.height as u16).to_be_bytes());
+        out.extend_from_slice(&encoding.to_be_bytes());
+    }
+}
+
+/// The contents last flipped onto a scanout, which is what its clients are shown.
+struct Frame {
+    width: u32,
+    height: u32,
+    bytes: Vec<u8>,
+}
+
+impl Frame {
+    fn new(width: u32, height: u32) -> Frame {
+        Frame {
+            width,
+            height,
+            bytes: vec![0; (width * height * BYTES_PER_PIXEL) as usize],
+        }
+    }
+
+    fn stride(&self) -> usize {
+        (self.width * BYTES_PER_PIXEL) as usize
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> u32 {
+        let offset = y as usize * self.stride() + (x * BYTES_PER_PIXEL) as usize;
+        let b = &self.bytes[offset..offset + BYTES_PER_PIXEL as usize];
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]]) & 0xffffff
+    }
+
+    fn pixels(&self, rect: Rect) -> impl Iterator<Item = u32> + '_ {
+        (rect.y..rect.y + rect.height)
+            .flat_map(move |y| (rect.x..rect.x + rect.width).map(move |x| self.pixel(x, y)))
+    }
+
+    fn tiles_across(&self) -> u32 {
+        (self.width + TILE_SIZE - 1) / TILE_SIZE
+    }
+
+    fn tile_count(&self) -> usize {
+        (self.tiles_across() * ((self.height + TILE_SIZE - 1) / TILE_SIZE)) as usize
+    }
+
+    fn tile(&self, index: usize) -> Rect {
+        let tiles_across = self.tiles_across() as usize;
+        let x = (index % tiles_across) as u32 * TILE_SIZE;
+        let y = (index / tiles_across) as u32 * TILE_SIZE;
+        Rect {
+            x,
+            y,
+            width: TILE_SIZE.min(self.width - x),
+            height: TILE_SIZE.min(self.height - y),
+        }
+    }
+
+    /// Replaces the contents of the frame with `bytes`, which must have the same size and layout,
+    /// and returns the indices of the tiles that changed.
+    fn update(&mut self, bytes: &[u8]) -> Vec<usize> {
+        let mut changed = Vec::new();
+        if bytes.len() != self.bytes.len() {
+            return changed;
+        }
+
+        let stride = self.stride();
+        for index in 0..self.tile_count() {
+            let tile = self.tile(index);
+            let rows = (tile.y..tile.y + tile.height).map(move |y| {
+                let start = y as usize * stride + (tile.x * BYTES_PER_PIXEL) as usize;
+                start..start + (tile.width * BYTES_PER_PIXEL) as usize
+            });
+
+            if rows
+                .clone()
+                .any(|row| self.bytes[row.clone()] != bytes[row])
+            {
+                for row in rows {
+                    self.bytes[row.clone()].copy_from_slice(&bytes[row]);
+                }
+                changed.push(index);
+            }
+        }
+
+        changed
+    }
+}
+
+// Compresses `input` with `stream`, flushing it so that the client can decompress all of `input`
+// from the returned bytes alone.
+fn deflate(stream: &mut Compress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let start = stream.total_in();
+    let mut out = Vec::with_capacity(input.len() + input.len() / 8 + 64);
+    loop {
+        let consumed = (stream.total_in() - start) as usize;
+        stream
+            .compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // The flush is only complete if it didn't run out of room for the output.
+        if (stream.total_in() - start) as usize == input.len() && out.len() < out.capacity() {
+            return Ok(out);
+        }
+        out.reserve(out.capacity());
+    }
+}
+
+// Appends `len` to `out` in the variable length format used by tight.
+fn write_compact_len(mut len: usize, out: &mut Vec<u8>) {
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ClientState {
+    // Waiting for the client to pick a protocol version.
+    Version,
+    // Waiting for the client to pick a security type.
+    Security,
+    // Waiting for the ClientInit message.
+    Init,
+    // Waiting for a surface to be shown on the scanout before sending ServerInit.
+    Waiting,
+    // The handshake is complete.
+    Normal,
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Version { minor: u32 },
+    SecurityType(u8),
+    ClientInit,
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest { incremental: bool },
+    Key { down: bool, keysym: u32 },
+    Pointer { buttons: u8, x: u16, y: u16 },
+    CutText,
+}
+
+/// Parses the message at the start of `buf`, returning it and its length if `buf` holds all of it.
+fn parse_message(state: ClientState, buf: &[u8]) -> io::Result<Option<(Message, usize)>> {
+    let be16 = |offset: usize| u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+    let be32 = |offset: usize| {
+        u32::from_be_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ])
+    };
+
+    let (message, len) = match state {
+        ClientState::Version => {
+            // "RFB xxx.yyy\n"
+            if buf.len() < 12 {
+                return Ok(None);
+            }
+            let version = std::str::from_utf8(&buf[..12])
+                .ok()
+                .filter(|v| v.is_ascii() && v.starts_with("RFB ") && &v[7..8] == ".")
+                .ok_or_else(|| invalid_data("invalid protocol version"))?;
+            let major: u32 = version[4..7].parse().map_err(invalid_data)?;
+            let minor: u32 = version[8..11].parse().map_err(invalid_data)?;
+            // Later versions get the latest one supported and unknown earlier ones get 3.3.
+            let minor = match (major, minor) {
+                (3, minor) if minor < 7 => 3,
+                (3, 7) => 7,
+                (major, _) if major < 3 => {
+                    return Err(invalid_data("unsupported protocol version"))
+                }
+                _ => 8,
+            };
+            (Message::Version { minor }, 12)
+        }
+        ClientState::Security => match buf.first() {
+            Some(&security_type) => (Message::SecurityType(security_type), 1),
+            None => return Ok(None),
+        },
+        ClientState::Init => match buf.first() {
+            Some(_) => (Message::ClientInit, 1),
+            None => return Ok(None),
+        },
+        ClientState::Waiting | ClientState::Normal => {
+            let msg_type = match buf.first() {
+                Some(&msg_type) => msg_type,
+                None => return Ok(None),
+            };
+            let min_len = match msg_type {
+                MSG_SET_PIXEL_FORMAT => 20,
+                MSG_SET_ENCODINGS => 4,
+                MSG_FRAMEBUFFER_UPDATE_REQUEST => 10,
+                MSG_KEY_EVENT => 8,
+                MSG_POINTER_EVENT => 6,
+                MSG_CLIENT_CUT_TEXT => 8,
+                _ => return Err(invalid_data(format!("unknown message type {}", msg_type))),
+            };
+            if buf.len() < min_len {
+                return Ok(None);
+            }
+
+            match msg_type {
+                MSG_SET_PIXEL_FORMAT => {
+                    let mut format = [0; 16];
+                    format.copy_from_slice(&buf[4..20]);
+                    (
+                        Message::SetPixelFormat(PixelFormat::from_bytes(&format)),
+                        20,
+                    )
+                }
+                MSG_SET_ENCODINGS => {
+                    let len = 4 + be16(2) as usize * 4;
+                    if buf.len() < len {
+                        return Ok(None);
+                    }
+                    let encodings = (4..len).step_by(4).map(|i| be32(i) as i32).collect();
+                    (Message::SetEncodings(encodings), len)
+                }
+                MSG_FRAMEBUFFER_UPDATE_REQUEST => (
+                    Message::FramebufferUpdateRequest {
+                        incremental: buf[1] != 0,
+                    },
+                    10,
+                ),
+                MSG_KEY_EVENT => (
+                    Message::Key {
+                        down: buf[1] != 0,
+                        keysym: be32(4),
+                    },
+                    8,
+                ),
+                MSG_POINTER_EVENT => (
+                    Message::Pointer {
+                        buttons: buf[1],
+                        x: be16(2),
+                        y: be16(4),
+                    },
+                    6,
+                ),
+                _ => {
+                    let text_len = be32(4) as usize;
+                    if text_len > MAX_CUT_TEXT_LEN {
+                        return Err(invalid_data("cut text is too long"));
+                    }
+                    if buf.len() < 8 + text_len {
+                        return Ok(None);
+                    }
+                    (Message::CutText, 8 + text_len)
+                }
+            }
+        }
+    };
+
+    Ok(Some((message, len)))
+}
+
+struct Client {
+    stream: TcpStream,
+    // Bytes that the socket didn't accept yet, which are sent once it becomes writable.
+    output: Vec<u8>,
+    // Set while the client's socket is waited on for becoming writable.
+    wait_writable: bool,
+    state: ClientState,
+    minor_version: u32,
+    // Bytes received from the client that don't form a complete message yet.
+    pending: Vec<u8>,
+    pixel_format: PixelFormat,
+    encoding: i32,
+    supports_desktop_size: bool,
+    // Set when the client asked for an update that hasn't been sent yet.
+    update_requested: bool,
+    // Set when the frame changed size since the last update sent to the client.
+    resized: bool,
+    // The tiles of the frame that changed since they were last sent to the client.
+    dirty: Vec<bool>,
+    // The buttons the client last reported as pressed.
+    buttons: u8,
+    zlib_stream: Compress,
+    tight_stream: Compress,
+}
+
+impl Client {
+    fn new(stream: TcpStream, frame: &Frame) -> io::Result<Client> {
+        // The socket never blocks so that a slow client can't stall the GPU device.
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        let mut client = Client {
+            stream,
+            output: Vec::new(),
+            wait_writable: false,
+            state: ClientState::Version,
+            minor_version: 8,
+            pending: Vec::new(),
+            pixel_format: PixelFormat::XRGB8888,
+            encoding: ENCODING_RAW,
+            supports_desktop_size: false,
+            update_requested: false,
+            resized: false,
+            dirty: vec![true; frame.tile_count()],
+            buttons: 0,
+            zlib_stream: Compress::new(Compression::default(), true),
+            tight_stream: Compress::new(Compression::default(), true),
+        };
+        client.send(b"RFB 003.008\n")?;
+        Ok(client)
+    }
+
+    /// Sends `bytes` to the client after anything that is still waiting to be sent.
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.output.len() + bytes.len() > MAX_CLIENT_BACKLOG {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "client isn't reading what is sent to it",
+            ));
+        }
+        self.output.extend_from_slice(bytes);
+        self.flush()
+    }
+
+    /// Sends as much of the output waiting to be sent as the socket accepts without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut sent = 0;
+        while sent < self.output.len() {
+            match self.stream.write(&self.output[sent..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => sent += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.output.drain(..sent);
+        Ok(())
+    }
+
+    /// Waits for the client's socket to become writable in `wait_ctx` only while there is output
+    /// waiting to be sent.
+    fn update_wait(
+        &mut self,
+        wait_ctx: &WaitContext<VncPollToken>,
+        client_id: u32,
+    ) -> io::Result<()> {
+        let wait_writable = !self.output.is_empty();
+        if wait_writable != self.wait_writable {
+            let event_type = if wait_writable {
+                EventType::ReadWrite
+            } else {
+                EventType::Read
+            };
+            wait_ctx.modify(&self.stream, event_type, VncPollToken::Client { client_id })?;
+            self.wait_writable = wait_writable;
+        }
+        Ok(())
+    }
+
+    fn take_message(&mut self) -> io::Result<Option<Message>> {
+        Ok(match parse_message(self.state, &self.pending)? {
+            Some((message, len)) => {
+                self.pending.drain(..len);
+                Some(message)
+            }
+            None => None,
+        })
+    }
+
+    /// Returns true if a message was received from the client but not handled yet.
+    fn has_message(&self) -> bool {
+        !matches!(parse_message(self.state, &self.pending), Ok(None))
+    }
+
+    /// Returns the next message from the client, reading from its socket at most once.
+    fn read_message(&mut self) -> io::Result<Option<Message>> {
+        if let Some(message) = self.take_message()? {
+            return Ok(Some(message));
+        }
+
+        let mut buf = [0; 4096];
+        let len = match self.stream.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ));
+        }
+        self.pending.extend_from_slice(&buf[..len]);
+
+        self.take_message()
+    }
+
+    fn send_server_init(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(frame.width as u16).to_be_bytes());
+        msg.extend_from_slice(&(frame.height as u16).to_be_bytes());
+        msg.extend_from_slice(&PixelFormat::XRGB8888.to_bytes());
+        msg.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+        msg.extend_from_slice(DESKTOP_NAME);
+        self.send(&msg)?;
+
+        self.state = ClientState::Normal;
+        self.resized = false;
+        self.dirty = vec![true; frame.tile_count()];
+        Ok(())
+    }
+
+    /// Handles `message` from the client, returning any input it carried.
+    fn handle(&mut self, message: Message, frame: &Frame) -> io::Result<Option<GpuDisplayEvents>> {
+        match message {
+            Message::Version { minor } => {
+                self.minor_version = minor;
+                if minor == 3 {
+                    // The server picks the security type in version 3.3.
+                    self.send(&(SECURITY_TYPE_NONE as u32).to_be_bytes())?;
+                    self.state = ClientState::Init;
+                } else {
+                    self.send(&[1, SECURITY_TYPE_NONE])?;
+                    self.state = ClientState::Security;
+                }
+            }
+            Message::SecurityType(security_type) => {
+                if security_type != SECURITY_TYPE_NONE {
+                    if self.minor_version == 8 {
+                        let reason = b"unsupported security type";
+                        let mut msg = 1u32.to_be_bytes().to_vec();
+                        msg.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+                        msg.extend_from_slice(reason);
+                        self.send(&msg)?;
+                    }
+                    return Err(invalid_data(format!(
+                        "unsupported security type {}",
+                        security_type
+                    )));
+                }
+                if self.minor_version == 8 {
+                    self.send(&0u32.to_be_bytes())?;
+                }
+                self.state = ClientState::Init;
+            }
+            Message::ClientInit => {
+                if frame.tile_count() == 0 {
+                    self.state = ClientState::Waiting;
+                } else {
+                    self.send_server_init(frame)?;
+                }
+            }
+            Message::SetPixelFormat(pixel_format) => {
+                if !pixel_format.is_supported() {
+                    return Err(invalid_data(format!(
+                        "unsupported pixel format {:?}",
+                        pixel_format
+                    )));
+                }
+                self.pixel_format = pixel_format;
+                self.dirty.iter_mut().for_each(|d| *d = true);
+            }
+            Message::SetEncodings(encodings) => {
+                // Encodings are listed in the client's order of preference.
+                self.encoding = encodings
+                    .iter()
+                    .copied()
+                    .find(|e| matches!(*e, ENCODING_RAW | ENCODING_ZLIB | ENCODING_TIGHT))
+                    .unwrap_or(ENCODING_RAW);
+                self.supports_desktop_size = encodings.contains(&ENCODING_DESKTOP_SIZE);
+            }
+            Message::FramebufferUpdateRequest { incremental } => {
+                if !incremental {
+                    self.dirty.iter_mut().for_each(|d| *d = true);
+                }
+                self.update_requested = true;
+                self.send_update(frame)?;
+            }
+            Message::Key { down, keysym } => {
+                if let Some(linux_keycode) = keysym_to_linux_keycode(keysym) {
+                    return Ok(Some(GpuDisplayEvents {
+                        events: vec![virtio_input_event::key(linux_keycode, down)],
+                        device_type: EventDeviceKind::Keyboard,
+                    }));
+                }
+            }
+            Message::Pointer { buttons, x, y } => {
+                let was_pressed = self.buttons & 1 != 0;
+                let pressed = buttons & 1 != 0;
+                self.buttons = buttons;
+
+                // We only support a single touch from the left mouse button, which is reported
+                // when it's pressed or released and while it's held down.
+                if pressed || was_pressed {
+                    // The touch event *must* be first per the Linux input subsystem's guidance.
+                    return Ok(Some(GpuDisplayEvents {
+                        events: vec![
+                            virtio_input_event::touch(pressed),
+                            virtio_input_event::absolute_x(x as i32),
+                            virtio_input_event::absolute_y(y as i32),
+                        ],
+                        device_type: EventDeviceKind::Touchscreen,
+                    }));
+                }
+            }
+            Message::CutText => {}
+        }
+
+        Ok(None)
+    }
+
+    /// Called when the frame was replaced by one with a different size.
+    fn resize(&mut self, frame: &Frame) -> io::Result<()> {
+        self.dirty = vec![true; frame.tile_count()];
+        match self.state {
+            ClientState::Waiting => self.send_server_init(frame),
+            ClientState::Normal if self.supports_desktop_size => {
+                self.resized = true;
+                Ok(())
+            }
+            ClientState::Normal => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "client doesn't support resizing",
+            )),
+            // The client is told the new size when the handshake completes.
+            _ => Ok(()),
+        }
+    }
+
+    fn encode_rect(&mut self, frame: &Frame, rect: Rect, out: &mut Vec<u8>) -> io::Result<()> {
+        rect.write_header(self.encoding, out);
+
+        if self.encoding == ENCODING_TIGHT {
+            let first = frame.pixel(rect.x, rect.y);
+            if frame.pixels(rect).all(|p| p == first) {
+                out.push(TIGHT_FILL);
+                self.pixel_format.write_tight_pixel(first, out);
+                return Ok(());
+            }
+
+            let mut data = Vec::new();
+            for pixel in frame.pixels(rect) {
+                self.pixel_format.write_tight_pixel(pixel, &mut data);
+            }
+
+            // Basic compression with zlib stream 0 and no filter.
+            out.push(0);
+            if data.len() < TIGHT_MIN_TO_COMPRESS {
+                out.extend_from_slice(&data);
+            } else {
+                let compressed = deflate(&mut self.tight_stream, &data)?;
+                write_compact_len(compressed.len(), out);
+                out.extend_from_slice(&compressed);
+            }
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        for pixel in frame.pixels(rect) {
+            self.pixel_format.write_pixel(pixel, &mut data);
+        }
+
+        if self.encoding == ENCODING_ZLIB {
+            let compressed = deflate(&mut self.zlib_stream, &data)?;
+            out.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+            out.extend_from_slice(&compressed);
+        } else {
+            out.extend_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    /// Sends the parts of `frame` that changed since the last update if the client asked for one.
+    /// Nothing is sent until the previous output was sent, so that the changes of every flip don't
+    /// pile up while the client is slow. The tiles stay dirty until then.
+    fn send_update(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.update_requested || self.state != ClientState::Normal || !self.output.is_empty() {
+            return Ok(());
+        }
+
+        let dirty: Vec<usize> = self
+            .dirty
+            .iter()
+            .enumerate()
+            .filter(|(_, &dirty)| dirty)
+            .map(|(index, _)| index)
+            .take(u16::MAX as usize - 1)
+            .collect();
+        if dirty.is_empty() && !self.resized {
+            return Ok(());
+        }
+
+        let rect_count = dirty.len() + self.resized as usize;
+        let mut msg = vec![MSG_FRAMEBUFFER_UPDATE, 0];
+        msg.extend_from_slice(&(rect_count as u16).to_be_bytes());
+        if self.resized {
+            let rect = Rect {
+                x: 0,
+                y: 0,
+                width: frame.width,
+                height: frame.height,
+            };
+            rect.write_header(ENCODING_DESKTOP_SIZE, &mut msg);
+            self.resized = false;
+        }
+        for index in dirty {
+            self.encode_rect(frame, frame.tile(index), &mut msg)?;
+            self.dirty[index] = false;
+        }
+
+        self.send(&msg)?;
+        self.update_requested = false;
+        Ok(())
+    }
+}
+
+/// The listener and clients of a scanout.
+struct VncServer {
+    listener: TcpListener,
+    // Waits for the clients' sockets, shared with `DisplayVnc`.
+    wait_ctx: Rc<WaitContext<VncPollToken>>,
+    frame: Frame,
+    clients: BTreeMap<u32, Client>,
+    // The surface shown on the scanout.
+    surface_id: Option<u32>,
+}
+
+impl VncServer {
+    fn new(listener: TcpListener, wait_ctx: Rc<WaitContext<VncPollToken>>) -> VncServer {
+        VncServer {
+            listener,
+            wait_ctx,
+            frame: Frame::new(0, 0),
+            clients: Default::default(),
+            surface_id: None,
+        }
+    }
+
+    fn retain_clients<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Client, &Frame) -> io::Result<()>,
+    {
+        let frame = &self.frame;
+        let wait_ctx = &*self.wait_ctx;
+        self.clients.retain(|&client_id, client| {
+            match f(client, frame).and_then(|()| client.update_wait(wait_ctx, client_id)) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("disconnecting VNC client {}: {}", client_id, e);
+                    false
+                }
+            }
+        });
+    }
+
+    fn attach(&mut self, surface_id: u32, width: u32, height: u32) {
+        self.surface_id = Some(surface_id);
+        if self.frame.width != width || self.frame.height != height {
+            self.frame = Frame::new(width, height);
+            self.retain_clients(|client, frame| {
+                client.resize(frame)?;
+                client.send_update(frame)
+            });
+        }
+    }
+
+    fn detach(&mut self, surface_id: u32) {
+        if self.surface_id == Some(surface_id) {
+            self.surface_id = None;
+        }
+    }
+
+    fn flip(&mut self, surface_id: u32, bytes: &[u8]) {
+        if self.surface_id != Some(surface_id) {
+            return;
+        }
+
+        let changed = self.frame.update(bytes);
+        if changed.is_empty() {
+            return;
+        }
+        self.retain_clients(|client, frame| {
+            for &index in &changed {
+                client.dirty[index] = true;
+            }
+            client.send_update(frame)
+        });
+    }
+
+    fn handle_client(&mut self, client_id: u32) -> io::Result<Option<GpuDisplayEvents>> {
+        let frame = &self.frame;
+        let client = match self.clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => return Ok(None),
+        };
+
+        // Send what is waiting for the socket to become writable, including any update that was
+        // held back because of it.
+        client.flush()?;
+        client.send_update(frame)?;
+
+        let events = match client.read_message()? {
+            Some(message) => client.handle(message, frame)?,
+            None => None,
+        };
+        client.update_wait(&self.wait_ctx, client_id)?;
+        Ok(events)
+    }
+}
+
+type VncServers = Rc<RefCell<BTreeMap<u32, VncServer>>>;
+
+struct VncSurface {
+    surface_id: u32,
+    width: u32,
+    height: u32,
+    buffer: Vec<u8>,
+    servers: VncServers,
+    scanout_id: Option<u32>,
+}
+
+impl VncSurface {
+    fn detach(&mut self) {
+        if let Some(scanout_id) = self.scanout_id.take() {
+            if let Some(server) = self.servers.borrow_mut().get_mut(&scanout_id) {
+                server.detach(self.surface_id);
+            }
+        }
+    }
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id as u64
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(self.buffer.as_mut_slice()),
+            self.width * BYTES_PER_PIXEL,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn flip(&mut self) {
+        if let Some(scanout_id) = self.scanout_id {
+            if let Some(server) = self.servers.borrow_mut().get_mut(&scanout_id) {
+                server.flip(self.surface_id, &self.buffer);
+            }
+        }
+    }
+
+    fn set_scanout_id(&mut self, scanout_id: u32) {
+        self.detach();
+        self.scanout_id = Some(scanout_id);
+        if let Some(server) = self.servers.borrow_mut().get_mut(&scanout_id) {
+            server.attach(self.surface_id, self.width, self.height);
+        }
+    }
+}
+
+impl Drop for VncSurface {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
+#[derive(Clone, Copy, PollToken)]
+enum VncPollToken {
+    Listener { scanout_id: u32 },
+    Client { client_id: u32 },
+}
+
+pub struct DisplayVnc {
+    servers: VncServers,
+    wait_ctx: Rc<WaitContext<VncPollToken>>,
+    // Tokens reported by `wait_ctx` that haven't been handled yet.
+    ready: RefCell<VecDeque<VncPollToken>>,
+    next_client_id: u32,
+    current_event: Option<GpuDisplayEvents>,
+}
+
+impl DisplayVnc {
+    /// Serves each scanout to the clients of the listener at its index in `listeners`, if any.
+    pub fn new(listeners: Vec<Option<TcpListener>>) -> GpuDisplayResult<DisplayVnc> {
+        let wait_ctx = Rc::new(WaitContext::new()?);
+        let mut servers = BTreeMap::new();
+        for (scanout_id, listener) in listeners.into_iter().enumerate() {
+            let listener = match listener {
+                Some(listener) => listener,
+                None => continue,
+            };
+            let scanout_id = scanout_id as u32;
+            wait_ctx.add(&listener, VncPollToken::Listener { scanout_id })?;
+            info!("serving scanout {} over VNC", scanout_id);
+            servers.insert(scanout_id, VncServer::new(listener, Rc::clone(&wait_ctx)));
+        }
+
+        Ok(DisplayVnc {
+            servers: Rc::new(RefCell::new(servers)),
+            wait_ctx,
+            ready: Default::default(),
+            next_client_id: 1,
+            current_event: None,
+        })
+    }
+
+    fn accept(&mut self, scanout_id: u32) -> GpuDisplayResult<()> {
+        let mut servers = self.servers.borrow_mut();
+        let server = match servers.get_mut(&scanout_id) {
+            Some(server) => server,
+            None => return Ok(()),
+        };
+
+        let (stream, addr) = server.listener.accept()?;
+        let mut client = Client::new(stream, &server.frame)?;
+        let client_id = self.next_client_id;
+        self.wait_ctx
+            .add(&client.stream, VncPollToken::Client { client_id })?;
+        client.update_wait(&self.wait_ctx, client_id)?;
+        self.next_client_id += 1;
+        server.clients.insert(client_id, client);
+        info!(
+            "VNC client {} connected to scanout {} from {}",
+            client_id, scanout_id, addr
+        );
+        Ok(())
+    }
+
+    // Handles a message from the client, returning the descriptor of the surface any input in it
+    // is meant for.
+    fn handle_client(&mut self, client_id: u32) -> u64 {
+        let mut servers = self.servers.borrow_mut();
+        let server = match servers
+            .values_mut()
+            .find(|server| server.clients.contains_key(&client_id))
+        {
+            Some(server) => server,
+            None => return 0,
+        };
+
+        match server.handle_client(client_id) {
+            Ok(events) => self.current_event = events,
+            Err(e) => {
+                warn!("disconnecting VNC client {}: {}", client_id, e);
+                // Closing the socket also removes it from `wait_ctx`.
+                server.clients.remove(&client_id);
+                return 0;
+            }
+        }
+
+        // Handle the rest of what the client sent before waiting on its socket again.
+        if server.clients[&client_id].has_message() {
+            self.ready
+                .get_mut()
+                .push_front(VncPollToken::Client { client_id });
+        }
+
+        match (&self.current_event, server.surface_id) {
+            (Some(_), Some(surface_id)) => surface_id as u64,
+            _ => 0,
+        }
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        let mut ready = self.ready.borrow_mut();
+        if ready.is_empty() {
+            match self.wait_ctx.wait_timeout(Duration::default()) {
+                Ok(events) => ready.extend(
+                    events
+                        .iter()
+                        .filter(|e| e.is_readable || e.is_writable || e.is_hungup)
+                        .map(|e| e.token),
+                ),
+                Err(e) => error!("failed to wait for VNC clients: {}", e),
+            }
+        }
+
+        !ready.is_empty()
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        self.current_event = None;
+        let token = match self.ready.get_mut().pop_front() {
+            Some(token) => token,
+            None => return Ok(0),
+        };
+
+        match token {
+            VncPollToken::Listener { scanout_id } => {
+                if let Err(e) = self.accept(scanout_id) {
+                    warn!("failed to accept VNC client: {}", e);
+                }
+                Ok(0)
+            }
+            VncPollToken::Client { client_id } => Ok(self.handle_client(client_id)),
+        }
+    }
+
+    fn handle_next_event(
+        &mut self,
+        _surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        self.current_event.take()
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+        _surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+
+        Ok(Box::new(VncSurface {
+            surface_id,
+            width,
+            height,
+            buffer: vec![0; (width * height * BYTES_PER_PIXEL) as usize],
+            servers: self.servers.clone(),
+            scanout_id: None,
+        }))
+    }
+}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.wait_ctx.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::{Decompress, FlushDecompress};
+
+    #[test]
+    fn parse_handshake() {
+        let parse = |state, buf: &[u8]| parse_message(state, buf).unwrap();
+
+        assert_eq!(parse(ClientState::Version, b"RFB 003.0"), None);
+        assert_eq!(
+            parse(ClientState::Version, b"RFB 003.008\n"),
+            Some((Message::Version { minor: 8 }, 12))
+        );
+        assert_eq!(
+            parse(ClientState::Version, b"RFB 003.005\n"),
+            Some((Message::Version { minor: 3 }, 12))
+        );
+        assert_eq!(
+            parse(ClientState::Version, b"RFB 004.001\n"),
+            Some((Message::Version { minor: 8 }, 12))
+        );
+        assert!(parse_message(ClientState::Version, b"HTTP/1.1 200").is_err());
+
+        assert_eq!(
+            parse(ClientState::Security, &[1]),
+            Some((Message::SecurityType(1), 1))
+        );
+        assert_eq!(parse(ClientState::Init, &[]), None);
+    }
+
+    #[test]
+    fn parse_client_messages() {
+        let parse = |buf: &[u8]| parse_message(ClientState::Normal, buf).unwrap();
+
+        let set_encodings = [2, 0, 0, 2, 0, 0, 0, 7, 0xff, 0xff, 0xff, 0x21];
+        assert_eq!(parse(&set_encodings[..8]), None);
+        assert_eq!(
+            parse(&set_encodings),
+            Some((Message::SetEncodings(vec![7, -223]), 12))
+        );
+
+        assert_eq!(
+            parse(&[4, 1, 0, 0, 0, 0, 0xff, 0x0d, 5]),
+            Some((
+                Message::Key {
+                    down: true,
+                    keysym: 0xff0d
+                },
+                8
+            ))
+        );
+        assert_eq!(
+            parse(&[5, 1, 0, 10, 1, 0]),
+            Some((
+                Message::Pointer {
+                    buttons: 1,
+                    x: 10,
+                    y: 256
+                },
+                6
+            ))
+        );
+        assert_eq!(parse(&[6, 0, 0, 0, 0, 0, 0, 2, b'h']), None);
+        assert!(parse_message(ClientState::Normal, &[42]).is_err());
+    }
+
+    #[test]
+    fn slow_client_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut client = Client::new(stream, &Frame::new(0, 0)).unwrap();
+
+        // The peer never reads, so the output piles up without blocking until it's too much.
+        let chunk = vec![0; 1 << 20];
+        let err = (0..2 * MAX_CLIENT_BACKLOG / chunk.len())
+            .find_map(|_| client.send(&chunk).err())
+            .expect("backlog isn't limited");
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(client.output.len() <= MAX_CLIENT_BACKLOG);
+    }
+
+    #[test]
+    fn frame_update_dirty_tiles() {
+        let mut frame = Frame::new(100, 70);
+        assert_eq!(frame.tile_count(), 4);
+        assert_eq!(
+            frame.tile(3),
+            Rect {
+                x: 64,
+                y: 64,
+                width: 36,
+                height: 6
+            }
+        );
+
+        let mut bytes = vec![0; frame.bytes.len()];
+        assert!(frame.update(&bytes).is_empty());
+
+        // Change a pixel in the bottom right tile and one in the top right tile.
+        let stride = frame.stride();
+        bytes[69 * stride + 99 * 4] = 0xff;
+        bytes[70 * 4 + 1] = 0x12;
+        assert_eq!(frame.update(&bytes), vec![1, 3]);
+        assert_eq!(frame.pixel(99, 69), 0xff);
+        assert_eq!(frame.pixel(70, 0), 0x1200);
+        assert!(frame.update(&bytes).is_empty());
+    }
+
+    #[test]
+    fn pixel_formats() {
+        let mut out = Vec::new();
+        PixelFormat::XRGB8888.write_pixel(0x123456, &mut out);
+        assert_eq!(out, [0x56, 0x34, 0x12, 0x00]);
+
+        // RGB565, big endian.
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_color: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert!(rgb565.is_supported());
+        out.clear();
+        rgb565.write_pixel(0xff00ff, &mut out);
+        assert_eq!(out, [0xf8, 0x1f]);
+
+        out.clear();
+        PixelFormat::XRGB8888.write_tight_pixel(0x123456, &mut out);
+        assert_eq!(out, [0x12, 0x34, 0x56]);
+
+        assert_eq!(
+            PixelFormat::from_bytes(&PixelFormat::XRGB8888.to_bytes()),
+            PixelFormat::XRGB8888
+        );
+    }
+
+    #[test]
+    fn compact_len() {
+        let mut out = Vec::new();
+        write_compact_len(90, &mut out);
+        write_compact_len(0x1234, &mut out);
+        write_compact_len(0x12345, &mut out);
+        assert_eq!(out, [90, 0xb4, 0x24, 0xc5, 0xc6, 0x04]);
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let mut stream = Compress::new(Compression::default(), true);
+        let mut inflate = Decompress::new(true);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 / 13) as u8).collect();
+
+        // Each chunk must be decompressible on its own from a shared stream.
+        for chunk in data.chunks(30_000) {
+            let compressed = deflate(&mut stream, chunk).unwrap();
+            let mut out = Vec::with_capacity(chunk.len());
+            inflate
+                .decompress_vec(&compressed, &mut out, FlushDecompress::Sync)
+                .unwrap();
+            assert_eq!(out, chunk);
+        }
+    }
+}
@@ -89,6 +89,8 @@ struct WaylandSurface {
     buffer_size: usize,
     buffer_index: Cell<usize>,
     buffer_mem: MemoryMapping,
+    // The point of a cursor surface that is placed at its position.
+    hotspot: (u32, u32),
 }
 
 impl WaylandSurface {
@@ -154,6 +156,21 @@ impl GpuDisplaySurface for WaylandSurface {
     }
 
     fn set_position(&mut self, x: u32, y: u32) {
+        // Safe because only a valid surface is used.
+        unsafe {
+            dwl_surface_set_position(self.surface(), x as i32, y as i32);
+        }
+    }
+
+    fn set_hotspot(&mut self, hot_x: u32, hot_y: u32) {
+        self.hotspot = (hot_x, hot_y);
+    }
+
+    fn move_cursor(&mut self, x: u32, y: u32) {
+        // The subsurface is positioned by its top left corner, which is outside of the parent
+        // when the cursor is closer to the parent's top or left edge than its hotspot is.
+        let x = x as i32 - self.hotspot.0 as i32;
+        let y = y as i32 - self.hotspot.1 as i32;
         // Safe because only a valid surface is used.
         unsafe {
             dwl_surface_set_position(self.surface(), x, y);
@@ -350,9 +367,29 @@ impl DisplayT for DisplayWl {
             buffer_size: fb_size as usize,
             buffer_index: Cell::new(0),
             buffer_mem,
+            hotspot: (0, 0),
         }))
     }
 
+    fn create_cursor_surface(
+        &mut self,
+        parent_surface_id: u32,
+        _parent: &dyn GpuDisplaySurface,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        // Cursors are subsurfaces with alpha blending that the compositor can put on a cursor
+        // plane.
+        self.create_surface(
+            Some(parent_surface_id),
+            surface_id,
+            width,
+            height,
+            SurfaceType::Cursor,
+        )
+    }
+
     fn import_memory(
         &mut self,
         import_id: u32,
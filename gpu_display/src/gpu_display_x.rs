@@ -12,7 +12,7 @@
 mod xlib;
 
 use linux_input_sys::virtio_input_event;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::ffi::{c_void, CStr, CString};
 use std::mem::{transmute_copy, zeroed};
 use std::os::raw::c_ulong;
@@ -31,6 +31,7 @@ use base::{AsRawDescriptor, RawDescriptor};
 use data_model::VolatileSlice;
 
 const BUFFER_COUNT: usize = 2;
+const CURSOR_BYTES_PER_PIXEL: u32 = 4;
 
 /// A wrapper for XFree that takes any type.
 unsafe fn x_free<T>(t: *mut T) {
@@ -395,6 +396,78 @@ impl Drop for XSurface {
     }
 }
 
+// Cursor surfaces are the cursor of their parent's window, which the X server draws at the host
+// pointer. The guest's cursor position follows the host pointer through input events, so moves are
+// ignored.
+struct XCursorSurface {
+    display: XDisplay,
+    // The window of the parent surface.
+    window: xlib::Window,
+    width: u32,
+    height: u32,
+    hotspot: (u32, u32),
+    // B8G8R8A8 pixels, as for the cursor resources of virtio-gpu.
+    buffer: Vec<u8>,
+    cursor: Option<xlib::Cursor>,
+}
+
+impl GpuDisplaySurface for XCursorSurface {
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(&mut self.buffer),
+            self.width * CURSOR_BYTES_PER_PIXEL,
+            CURSOR_BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn flip(&mut self) {
+        unsafe {
+            let image = xlib::XcursorImageCreate(self.width as i32, self.height as i32);
+            if image.is_null() {
+                return;
+            }
+            // The X server rejects cursors whose hotspot is outside of their image.
+            (*image).xhot = min(self.hotspot.0, self.width - 1);
+            (*image).yhot = min(self.hotspot.1, self.height - 1);
+            let pixels = std::slice::from_raw_parts_mut(
+                (*image).pixels,
+                self.width as usize * self.height as usize,
+            );
+            for (pixel, bytes) in pixels
+                .iter_mut()
+                .zip(self.buffer.chunks_exact(CURSOR_BYTES_PER_PIXEL as usize))
+            {
+                *pixel = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+
+            let cursor = xlib::XcursorImageLoadCursor(self.display.as_ptr(), image);
+            xlib::XcursorImageDestroy(image);
+            xlib::XDefineCursor(self.display.as_ptr(), self.window, cursor);
+            if let Some(old_cursor) = self.cursor.replace(cursor) {
+                xlib::XFreeCursor(self.display.as_ptr(), old_cursor);
+            }
+            self.display.flush();
+        }
+    }
+
+    fn set_hotspot(&mut self, hot_x: u32, hot_y: u32) {
+        self.hotspot = (hot_x, hot_y);
+    }
+}
+
+impl Drop for XCursorSurface {
+    fn drop(&mut self) {
+        // Safe because the parent's window outlives its cursor surface.
+        if let Some(cursor) = self.cursor.take() {
+            unsafe {
+                xlib::XUndefineCursor(self.display.as_ptr(), self.window);
+                xlib::XFreeCursor(self.display.as_ptr(), cursor);
+            }
+            self.display.flush();
+        }
+    }
+}
+
 pub struct DisplayX {
     display: XDisplay,
     screen: XScreen,
@@ -649,6 +722,30 @@ impl DisplayT for DisplayX {
             }))
         }
     }
+
+    fn create_cursor_surface(
+        &mut self,
+        _parent_surface_id: u32,
+        parent: &dyn GpuDisplaySurface,
+        _surface_id: u32,
+        width: u32,
+        height: u32,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        if width == 0 || height == 0 {
+            return Err(GpuDisplayError::CreateSurface);
+        }
+
+        Ok(Box::new(XCursorSurface {
+            display: self.display.clone(),
+            // The descriptor of top level surfaces is their window.
+            window: parent.surface_descriptor() as xlib::Window,
+            width,
+            height,
+            hotspot: (0, 0),
+            buffer: vec![0; (width * height * CURSOR_BYTES_PER_PIXEL) as usize],
+            cursor: None,
+        }))
+    }
 }
 
 impl AsRawDescriptor for DisplayX {
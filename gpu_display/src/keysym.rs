@@ -0,0 +1,143 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Translation of X11 keysyms, which is how VNC clients identify keys, into Linux keycodes.
+//!
+//! Keysyms name the symbol a key produced rather than the key itself, so symbols that need a
+//! modifier (e.g. `!` or `A`) are mapped to the key that produces them on a US layout. The client
+//! also reports the modifier keys it pressed, which lets the guest reconstruct the same symbol.
+
+/// Returns the Linux keycode of the key producing `keysym` on a US layout, if there is one.
+pub fn keysym_to_linux_keycode(keysym: u32) -> Option<u16> {
+    let keycode = match keysym {
+        // Letters, in either case.
+        0x41..=0x5a => return letter_keycode(keysym - 0x41),
+        0x61..=0x7a => return letter_keycode(keysym - 0x61),
+
+        // Digits and their shifted symbols.
+        0x31..=0x39 => keysym as u16 - 0x31 + 2,
+        0x30 | 0x29 => 11, // 0 )
+        0x21 => 2,         // !
+        0x40 => 3,         // @
+        0x23 => 4,         // #
+        0x24 => 5,         // $
+        0x25 => 6,         // %
+        0x5e => 7,         // ^
+        0x26 => 8,         // &
+        0x2a => 9,         // *
+        0x28 => 10,        // (
+
+        // Punctuation and its shifted symbols.
+        0x2d | 0x5f => 12, // - _
+        0x3d | 0x2b => 13, // = +
+        0x5b | 0x7b => 26, // [ {
+        0x5d | 0x7d => 27, // ] }
+        0x3b | 0x3a => 39, // ; :
+        0x27 | 0x22 => 40, // ' "
+        0x60 | 0x7e => 41, // ` ~
+        0x5c | 0x7c => 43, // \ |
+        0x2c | 0x3c => 51, // , <
+        0x2e | 0x3e => 52, // . >
+        0x2f | 0x3f => 53, // / ?
+        0x20 => 57,        // space
+
+        // Editing and navigation.
+        0xff08 => 14,  // BackSpace
+        0xff09 => 15,  // Tab
+        0xff0d => 28,  // Return
+        0xff13 => 119, // Pause
+        0xff14 => 70,  // Scroll_Lock
+        0xff15 => 99,  // Sys_Req
+        0xff1b => 1,   // Escape
+        0xff50 => 102, // Home
+        0xff51 => 105, // Left
+        0xff52 => 103, // Up
+        0xff53 => 106, // Right
+        0xff54 => 108, // Down
+        0xff55 => 104, // Page_Up
+        0xff56 => 109, // Page_Down
+        0xff57 => 107, // End
+        0xff61 => 99,  // Print
+        0xff63 => 110, // Insert
+        0xff67 => 127, // Menu
+        0xffff => 111, // Delete
+
+        // Keypad.
+        0xff7f => 69, // Num_Lock
+        0xff8d => 96, // KP_Enter
+        0xffaa => 55, // KP_Multiply
+        0xffab => 78, // KP_Add
+        0xffad => 74, // KP_Subtract
+        0xffae => 83, // KP_Decimal
+        0xffaf => 98, // KP_Divide
+        0xffb0 => 82, // KP_0
+        0xffb1..=0xffb3 => keysym as u16 - 0xffb1 + 79,
+        0xffb4..=0xffb6 => keysym as u16 - 0xffb4 + 75,
+        0xffb7..=0xffb9 => keysym as u16 - 0xffb7 + 71,
+
+        // Function keys.
+        0xffbe..=0xffc7 => keysym as u16 - 0xffbe + 59, // F1 to F10
+        0xffc8 => 87,                                   // F11
+        0xffc9 => 88,                                   // F12
+
+        // Modifiers.
+        0xfe03 => 100, // ISO_Level3_Shift (AltGr)
+        0xffe1 => 42,  // Shift_L
+        0xffe2 => 54,  // Shift_R
+        0xffe3 => 29,  // Control_L
+        0xffe4 => 97,  // Control_R
+        0xffe5 => 58,  // Caps_Lock
+        0xffe7 => 125, // Meta_L
+        0xffe8 => 126, // Meta_R
+        0xffe9 => 56,  // Alt_L
+        0xffea => 100, // Alt_R
+        0xffeb => 125, // Super_L
+        0xffec => 126, // Super_R
+
+        _ => return None,
+    };
+
+    Some(keycode)
+}
+
+// Returns the keycode of the `index`th letter of the alphabet.
+fn letter_keycode(index: u32) -> Option<u16> {
+    const LETTERS: [u16; 26] = [
+        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
+        45, 21, 44,
+    ];
+
+    LETTERS.get(index as usize).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letters_ignore_case() {
+        assert_eq!(keysym_to_linux_keycode('a' as u32), Some(30));
+        assert_eq!(keysym_to_linux_keycode('A' as u32), Some(30));
+        assert_eq!(keysym_to_linux_keycode('q' as u32), Some(16));
+        assert_eq!(keysym_to_linux_keycode('Z' as u32), Some(44));
+    }
+
+    #[test]
+    fn shifted_symbols_use_unshifted_key() {
+        assert_eq!(keysym_to_linux_keycode('1' as u32), Some(2));
+        assert_eq!(keysym_to_linux_keycode('!' as u32), Some(2));
+        assert_eq!(keysym_to_linux_keycode('0' as u32), Some(11));
+        assert_eq!(keysym_to_linux_keycode(')' as u32), Some(11));
+        assert_eq!(keysym_to_linux_keycode('?' as u32), Some(53));
+    }
+
+    #[test]
+    fn special_keys() {
+        assert_eq!(keysym_to_linux_keycode(0xff0d), Some(28));
+        assert_eq!(keysym_to_linux_keycode(0xffbe), Some(59));
+        assert_eq!(keysym_to_linux_keycode(0xffc7), Some(68));
+        assert_eq!(keysym_to_linux_keycode(0xffb5), Some(76));
+        assert_eq!(keysym_to_linux_keycode(0x1234), None);
+    }
+}
@@ -3,10 +3,11 @@
 // found in the LICENSE file.
 
 //! Crate for displaying simple surfaces and GPU buffers over a low-level display backend such as
-//! Wayland or X.
+//! Wayland, X or VNC.
 
 use std::collections::BTreeMap;
 use std::io::Error as IoError;
+use std::net::TcpListener;
 use std::path::Path;
 use std::time::Duration;
 
@@ -17,11 +18,15 @@ use thiserror::Error;
 
 mod event_device;
 mod gpu_display_stub;
+#[cfg(feature = "vnc")]
+mod gpu_display_vnc;
 mod gpu_display_wl;
 #[cfg(feature = "x")]
 mod gpu_display_x;
 #[cfg(feature = "x")]
 mod keycode_converter;
+#[cfg(feature = "vnc")]
+mod keysym;
 
 pub use event_device::{EventDevice, EventDeviceKind};
 use linux_input_sys::virtio_input_event;
@@ -196,6 +201,16 @@ trait GpuDisplaySurface {
         // no-op
     }
 
+    /// Sets the point of a cursor surface that is placed at the position given to `move_cursor`.
+    fn set_hotspot(&mut self, _hot_x: u32, _hot_y: u32) {
+        // no-op
+    }
+
+    /// Moves a cursor surface so that its hotspot is at the given position of its parent.
+    fn move_cursor(&mut self, _x: u32, _y: u32) {
+        // no-op
+    }
+
     /// Returns the type of the completed buffer.
     fn buffer_completion_type(&self) -> u32 {
         0
@@ -262,6 +277,20 @@ trait DisplayT: AsRawDescriptor {
         surf_type: SurfaceType,
     ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>>;
 
+    /// Creates a cursor surface shown over the top level surface `parent`, whose handle is
+    /// `parent_surface_id`. The display backend is given a non-zero `surface_id` as a handle for
+    /// subsequent operations.
+    fn create_cursor_surface(
+        &mut self,
+        _parent_surface_id: u32,
+        _parent: &dyn GpuDisplaySurface,
+        _surface_id: u32,
+        _width: u32,
+        _height: u32,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
     /// Imports memory into the display backend.  The display backend is given a non-zero
     /// `import_id` as a handle for subsequent operations.
     fn import_memory(
@@ -287,6 +316,8 @@ pub struct GpuDisplay {
     next_id: u32,
     event_devices: BTreeMap<u32, EventDevice>,
     surfaces: BTreeMap<u32, Box<dyn GpuDisplaySurface>>,
+    // Maps the surfaces that have a parent to the ID of their parent.
+    parent_surface_ids: BTreeMap<u32, u32>,
     imports: BTreeMap<u32, Box<dyn GpuDisplayImport>>,
     // `inner` must be after `imports` and `surfaces` to ensure those objects are dropped before
     // the display context. The drop order for fields inside a struct is the order in which they
@@ -315,6 +346,7 @@ impl GpuDisplay {
                 next_id: 1,
                 event_devices: Default::default(),
                 surfaces: Default::default(),
+                parent_surface_ids: Default::default(),
                 imports: Default::default(),
                 wait_ctx,
                 is_x: true,
@@ -339,12 +371,39 @@ impl GpuDisplay {
             next_id: 1,
             event_devices: Default::default(),
             surfaces: Default::default(),
+            parent_surface_ids: Default::default(),
             imports: Default::default(),
             wait_ctx,
             is_x: false,
         })
     }
 
+    /// Serves the display to VNC clients. Top level surfaces shown on a scanout are served to the
+    /// clients of the listener at the scanout's index in `listeners`, if there is one.
+    #[cfg_attr(not(feature = "vnc"), allow(unused_variables))]
+    pub fn open_vnc(listeners: Vec<Option<TcpListener>>) -> GpuDisplayResult<GpuDisplay> {
+        #[cfg(feature = "vnc")]
+        {
+            let display = gpu_display_vnc::DisplayVnc::new(listeners)?;
+
+            let wait_ctx = WaitContext::new()?;
+            wait_ctx.add(&display, DisplayPollToken::Display)?;
+
+            Ok(GpuDisplay {
+                inner: Box::new(display),
+                next_id: 1,
+                event_devices: Default::default(),
+                surfaces: Default::default(),
+                parent_surface_ids: Default::default(),
+                imports: Default::default(),
+                wait_ctx,
+                is_x: false,
+            })
+        }
+        #[cfg(not(feature = "vnc"))]
+        Err(GpuDisplayError::Unsupported)
+    }
+
     pub fn open_stub() -> GpuDisplayResult<GpuDisplay> {
         let display = gpu_display_stub::DisplayStub::new()?;
         let wait_ctx = WaitContext::new()?;
@@ -355,6 +414,7 @@ impl GpuDisplay {
             next_id: 1,
             event_devices: Default::default(),
             surfaces: Default::default(),
+            parent_surface_ids: Default::default(),
             imports: Default::default(),
             wait_ctx,
             is_x: false,
@@ -431,6 +491,8 @@ impl GpuDisplay {
 
     /// Creates a surface on the the compositor as either a top level window, or child of another
     /// surface, returning a handle to the new surface.
+    ///
+    /// Cursor surfaces are shown over their parent using the cursor plane of the backend.
     pub fn create_surface(
         &mut self,
         parent_surface_id: Option<u32>,
@@ -438,28 +500,58 @@ impl GpuDisplay {
         height: u32,
         surf_type: SurfaceType,
     ) -> GpuDisplayResult<u32> {
-        if let Some(parent_id) = parent_surface_id {
-            if !self.surfaces.contains_key(&parent_id) {
-                return Err(GpuDisplayError::InvalidSurfaceId);
-            }
-        }
+        let parent = match parent_surface_id {
+            Some(parent_id) => Some((
+                parent_id,
+                self.surfaces
+                    .get(&parent_id)
+                    .ok_or(GpuDisplayError::InvalidSurfaceId)?,
+            )),
+            None => None,
+        };
 
         let new_surface_id = self.next_id;
-        let new_surface = self.inner.create_surface(
-            parent_surface_id,
-            new_surface_id,
-            width,
-            height,
-            surf_type,
-        )?;
+        let new_surface = match (surf_type, parent) {
+            (SurfaceType::Cursor, Some((parent_id, parent))) => self.inner.create_cursor_surface(
+                parent_id,
+                parent.as_ref(),
+                new_surface_id,
+                width,
+                height,
+            )?,
+            _ => self.inner.create_surface(
+                parent_surface_id,
+                new_surface_id,
+                width,
+                height,
+                surf_type,
+            )?,
+        };
 
         self.next_id += 1;
         self.surfaces.insert(new_surface_id, new_surface);
+        if let Some(parent_id) = parent_surface_id {
+            self.parent_surface_ids.insert(new_surface_id, parent_id);
+        }
         Ok(new_surface_id)
     }
 
-    /// Releases a previously created surface identified by the given handle.
+    /// Releases a previously created surface identified by the given handle, along with the
+    /// surfaces that are its children.
     pub fn release_surface(&mut self, surface_id: u32) {
+        // Children are released first because some backends can't release them after their
+        // parent.
+        let children: Vec<u32> = self
+            .parent_surface_ids
+            .iter()
+            .filter(|&(_, &parent_id)| parent_id == surface_id)
+            .map(|(&child_id, _)| child_id)
+            .collect();
+        for child_id in children {
+            self.release_surface(child_id);
+        }
+
+        self.parent_surface_ids.remove(&surface_id);
         self.surfaces.remove(&surface_id);
     }
 
@@ -600,6 +692,35 @@ impl GpuDisplay {
         Ok(())
     }
 
+    /// Sets the point of the identified cursor surface that is placed at the position given to
+    /// `move_cursor`.
+    pub fn set_cursor_hotspot(
+        &mut self,
+        surface_id: u32,
+        hot_x: u32,
+        hot_y: u32,
+    ) -> GpuDisplayResult<()> {
+        let surface = self
+            .surfaces
+            .get_mut(&surface_id)
+            .ok_or(GpuDisplayError::InvalidSurfaceId)?;
+
+        surface.set_hotspot(hot_x, hot_y);
+        Ok(())
+    }
+
+    /// Moves the identified cursor surface so that its hotspot is at the given position of its
+    /// parent.
+    pub fn move_cursor(&mut self, surface_id: u32, x: u32, y: u32) -> GpuDisplayResult<()> {
+        let surface = self
+            .surfaces
+            .get_mut(&surface_id)
+            .ok_or(GpuDisplayError::InvalidSurfaceId)?;
+
+        surface.move_cursor(x, y);
+        Ok(())
+    }
+
     /// Associates the scanout id with the given surface.
     pub fn set_scanout_id(&mut self, surface_id: u32, scanout_id: u32) -> GpuDisplayResult<()> {
         let surface = self
@@ -617,3 +738,26 @@ impl AsRawDescriptor for GpuDisplay {
         self.wait_ctx.as_raw_descriptor()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_released_with_parent() {
+        let mut display = GpuDisplay::open_stub().unwrap();
+        let parent = display
+            .create_surface(None, 64, 64, SurfaceType::Scanout)
+            .unwrap();
+        let cursor = display
+            .create_surface(Some(parent), 16, 16, SurfaceType::Cursor)
+            .unwrap();
+        display.set_cursor_hotspot(cursor, 2, 3).unwrap();
+        display.move_cursor(cursor, 10, 10).unwrap();
+        assert!(display.framebuffer(cursor).is_some());
+
+        display.release_surface(parent);
+        assert!(display.framebuffer(cursor).is_none());
+        assert!(display.move_cursor(cursor, 0, 0).is_err());
+    }
+}
@@ -0,0 +1,14 @@
+#!/bin/bash
+# Copyright 2021 The Chromium OS Authors. All rights reserved.
+# Use of this source code is governed by a BSD-style license that can be
+# found in the LICENSE file.
+
+# Regenerates rutabaga_gfx_ffi.h from the rutabaga_gfx_ffi crate.  Pass --verify to only check
+# that the checked-in header is up to date.
+
+set -e
+
+cd "${0%/*}/../.."
+
+cbindgen --config cbindgen.toml --crate rutabaga_gfx_ffi \
+  --output src/include/rutabaga_gfx_ffi.h --quiet "$@"
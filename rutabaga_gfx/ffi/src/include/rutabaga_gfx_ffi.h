@@ -4,153 +4,193 @@
  * found in the LICENSE file.
  */
 
+#ifndef RUTABAGA_GFX_FFI_H
+#define RUTABAGA_GFX_FFI_H
+
+/* Generated using ./generate_header.sh. Do not edit. */
+
 #include <stdarg.h>
 #include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 #include <stdlib.h>
 #include <sys/uio.h>
 
-#ifndef RUTABAGA_GFX_FFI_H
-#define RUTABAGA_GFX_FFI_H
-
-#ifdef __cplusplus
-extern "C" {
-#endif
-
 /**
  * Rutabaga component types
  */
 #define RUTABAGA_COMPONENT_2D 1
+
 #define RUTABAGA_COMPONENT_VIRGL_RENDERER 2
+
 #define RUTABAGA_COMPONENT_GFXSTREAM 3
+
 #define RUTABAGA_COMPONENT_CROSS_DOMAIN 4
 
 /**
  * Blob resource creation parameters.
  */
 #define RUTABAGA_BLOB_MEM_GUEST 1
+
 #define RUTABAGA_BLOB_MEM_HOST3D 2
+
 #define RUTABAGA_BLOB_MEM_HOST3D_GUEST 3
 
 #define RUTABAGA_BLOB_FLAG_USE_MAPPABLE 1
+
 #define RUTABAGA_BLOB_FLAG_USE_SHAREABLE 2
+
 #define RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE 4
 
 /**
  * Rutabaga capsets.
  */
 #define RUTABAGA_CAPSET_VIRGL 1
+
 #define RUTABAGA_CAPSET_VIRGL2 2
+
 #define RUTABAGA_CAPSET_GFXSTREAM 3
+
 #define RUTABAGA_CAPSET_VENUS 4
+
 #define RUTABAGA_CAPSET_CROSS_DOMAIN 5
 
 /**
  * Mapped memory caching flags (see virtio_gpu spec)
  */
 #define RUTABAGA_MAP_CACHE_CACHED 1
+
 #define RUTABAGA_MAP_CACHE_UNCACHED 2
+
 #define RUTABAGA_MAP_CACHE_WC 3
 
 /**
  * Rutabaga flags for creating fences.
  */
 #define RUTABAGA_FLAG_FENCE (1 << 0)
+
 #define RUTABAGA_FLAG_INFO_RING_IDX (1 << 1)
 
 /**
  * Rutabaga channel types
  */
 #define RUTABAGA_CHANNEL_TYPE_WAYLAND 1
+
 #define RUTABAGA_CHANNEL_TYPE_CAMERA 2
 
 /**
  * Rutabaga handle types
  */
 #define RUTABAGA_MEM_HANDLE_TYPE_OPAQUE_FD 1
+
 #define RUTABAGA_MEM_HANDLE_TYPE_DMABUF 2
+
 #define RUTABAGE_MEM_HANDLE_TYPE_OPAQUE_WIN32 3
+
 #define RUTABAGA_FENCE_HANDLE_TYPE_OPAQUE_FD 4
+
 #define RUTABAGA_FENCE_HANDLE_TYPE_SYNC_FD 5
+
 #define RUTABAGE_FENCE_HANDLE_TYPE_OPAQUE_WIN32 6
 
+/**
+ * A rutabaga instance created by `rutabaga_init`.
+ */
 struct rutabaga;
 
+/**
+ * Convenience struct for Rutabaga fences
+ */
 struct rutabaga_fence {
-	uint32_t flags;
-	uint64_t fence_id;
-	uint32_t ctx_id;
-	uint32_t ring_idx;
+  uint32_t flags;
+  uint64_t fence_id;
+  uint32_t ctx_id;
+  uint8_t ring_idx;
 };
 
-struct rutabaga_create_blob {
-	uint32_t blob_mem;
-	uint32_t blob_flags;
-	uint64_t blob_id;
-	uint64_t size;
+typedef struct rutabaga_fence rutabaga_fence;
+
+/**
+ * Throwing an exception inside this callback is not allowed.
+ */
+typedef void (*write_fence_cb)(uint64_t user_data, rutabaga_fence fence_data);
+
+/**
+ * Assumes null-terminated C-string.
+ */
+struct rutabaga_channel {
+  const char *channel_name;
+  uint32_t channel_type;
 };
 
-struct rutabaga_create_3d {
-	uint32_t target;
-	uint32_t format;
-	uint32_t bind;
-	uint32_t width;
-	uint32_t height;
-	uint32_t depth;
-	uint32_t array_size;
-	uint32_t last_level;
-	uint32_t nr_samples;
-	uint32_t flags;
+struct rutabaga_channels {
+  const struct rutabaga_channel *channels;
+  size_t num_channels;
 };
 
-struct rutabaga_transfer {
-	uint32_t x;
-	uint32_t y;
-	uint32_t z;
-	uint32_t w;
-	uint32_t h;
-	uint32_t d;
-	uint32_t level;
-	uint32_t stride;
-	uint32_t layer_stride;
-	uint64_t offset;
+struct rutabaga_builder {
+  uint64_t user_data;
+  uint32_t default_component;
+  write_fence_cb fence_cb;
+  const struct rutabaga_channels *channels;
 };
 
-struct rutabaga_iovecs {
-	struct iovec *iovecs;
-	size_t num_iovecs;
+struct rutabaga_create_3d {
+  uint32_t target;
+  uint32_t format;
+  uint32_t bind;
+  uint32_t width;
+  uint32_t height;
+  uint32_t depth;
+  uint32_t array_size;
+  uint32_t last_level;
+  uint32_t nr_samples;
+  uint32_t flags;
 };
 
-struct rutabaga_handle {
-	int32_t os_handle;
-	uint32_t handle_type;
+typedef struct rutabaga_create_3d rutabaga_create_3d;
+
+struct rutabaga_iovecs {
+  struct iovec *iovecs;
+  size_t num_iovecs;
 };
 
 /**
- * Assumes null-terminated C-string.
+ * Transfers {to, from} 1D buffers, 2D textures, 3D textures, and cubemaps.
  */
-struct rutabaga_channel {
-	char *channel_name;
-	uint32_t channel_type;
+struct rutabaga_transfer {
+  uint32_t x;
+  uint32_t y;
+  uint32_t z;
+  uint32_t w;
+  uint32_t h;
+  uint32_t d;
+  uint32_t level;
+  uint32_t stride;
+  uint32_t layer_stride;
+  uint64_t offset;
 };
 
-struct rutabaga_channels {
-	struct rutabaga_channel *channels;
-	size_t num_channels;
+typedef struct rutabaga_transfer rutabaga_transfer;
+
+struct rutabaga_create_blob {
+  uint32_t blob_mem;
+  uint32_t blob_flags;
+  uint64_t blob_id;
+  uint64_t size;
 };
 
-/**
- * Throwing an exception inside this callback is not allowed.
- */
-typedef void (*write_fence_cb)(uint64_t user_data, struct rutabaga_fence fence_data);
+typedef struct rutabaga_create_blob rutabaga_create_blob;
 
-struct rutabaga_builder {
-	uint64_t user_data;
-	uint32_t rutabaga_component;
-	write_fence_cb fence_cb;
-	struct rutabaga_channels *channels;
+struct rutabaga_handle {
+  int32_t os_handle;
+  uint32_t handle_type;
 };
 
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
 /**
  * # Safety
  * - If `(*builder).channels` is not null, the caller must ensure `(*channels).channels` points to
@@ -167,28 +207,37 @@ int32_t rutabaga_finish(struct rutabaga **ptr);
 
 uint32_t rutabaga_get_num_capsets(void);
 
-int32_t rutabaga_get_capset_info(struct rutabaga *ptr, uint32_t capset_index, uint32_t *capset_id,
-				 uint32_t *capset_version, uint32_t *capset_size);
+int32_t rutabaga_get_capset_info(struct rutabaga *ptr,
+                                 uint32_t capset_index,
+                                 uint32_t *capset_id,
+                                 uint32_t *capset_version,
+                                 uint32_t *capset_size);
 
 /**
  * # Safety
  * - `capset` must point an array of bytes of size `capset_size`.
  */
-int32_t rutabaga_get_capset(struct rutabaga *ptr, uint32_t capset_id, uint32_t version,
-			    uint8_t *capset, uint32_t capset_size);
+int32_t rutabaga_get_capset(struct rutabaga *ptr,
+                            uint32_t capset_id,
+                            uint32_t version,
+                            uint8_t *capset,
+                            uint32_t capset_size);
 
 int32_t rutabaga_context_create(struct rutabaga *ptr, uint32_t ctx_id, uint32_t context_init);
 
 int32_t rutabaga_context_destroy(struct rutabaga *ptr, uint32_t ctx_id);
 
-int32_t rutabaga_context_attach_resource(struct rutabaga *ptr, uint32_t ctx_id,
-					 uint32_t resource_id);
+int32_t rutabaga_context_attach_resource(struct rutabaga *ptr,
+                                         uint32_t ctx_id,
+                                         uint32_t resource_id);
 
-int32_t rutabaga_context_detach_resource(struct rutabaga *ptr, uint32_t ctx_id,
-					 uint32_t resource_id);
+int32_t rutabaga_context_detach_resource(struct rutabaga *ptr,
+                                         uint32_t ctx_id,
+                                         uint32_t resource_id);
 
-int32_t rutabaga_resource_create_3d(struct rutabaga *ptr, uint32_t resource_id,
-				    const struct rutabaga_create_3d *create_3d);
+int32_t rutabaga_resource_create_3d(struct rutabaga *ptr,
+                                    uint32_t resource_id,
+                                    const rutabaga_create_3d *create_3d);
 
 /**
  * # Safety
@@ -198,8 +247,9 @@ int32_t rutabaga_resource_create_3d(struct rutabaga *ptr, uint32_t resource_id,
  * - Each iovec must valid until the resource's backing is explictly detached or the resource is
  *   is unreferenced.
  */
-int32_t rutabaga_resource_attach_backing(struct rutabaga *ptr, uint32_t resource_id,
-					 const struct rutabaga_iovecs *iovecs);
+int32_t rutabaga_resource_attach_backing(struct rutabaga *ptr,
+                                         uint32_t resource_id,
+                                         const struct rutabaga_iovecs *iovecs);
 
 int32_t rutabaga_resource_detach_backing(struct rutabaga *ptr, uint32_t resource_id);
 
@@ -208,13 +258,16 @@ int32_t rutabaga_resource_detach_backing(struct rutabaga *ptr, uint32_t resource
  * - If `iovecs` is not null, the caller must ensure `(*iovecs).iovecs` points to a valid array of
  *   iovecs of size `(*iovecs).num_iovecs`.
  */
-int32_t rutabaga_resource_transfer_read(struct rutabaga *ptr, uint32_t ctx_id, uint32_t resource_id,
-					const struct rutabaga_transfer *transfer,
-					const struct iovec *iovec);
+int32_t rutabaga_resource_transfer_read(struct rutabaga *ptr,
+                                        uint32_t ctx_id,
+                                        uint32_t resource_id,
+                                        const rutabaga_transfer *transfer,
+                                        const struct iovec *buf);
 
-int32_t rutabaga_resource_transfer_write(struct rutabaga *ptr, uint32_t ctx_id,
-					 uint32_t resource_id,
-					 const struct rutabaga_transfer *transfer);
+int32_t rutabaga_resource_transfer_write(struct rutabaga *ptr,
+                                         uint32_t ctx_id,
+                                         uint32_t resource_id,
+                                         const rutabaga_transfer *transfer);
 
 /**
  * # Safety
@@ -225,10 +278,12 @@ int32_t rutabaga_resource_transfer_write(struct rutabaga *ptr, uint32_t ctx_id,
  * - Each iovec must valid until the resource's backing is explictly detached or the resource is
  *   is unreferenced.
  */
-int32_t rutabaga_resource_create_blob(struct rutabaga *ptr, uint32_t ctx_id, uint32_t resource_id,
-				      const struct rutabaga_create_blob *rutabaga_create_blob,
-				      const struct rutabaga_iovecs *iovecs,
-				      const struct rutabaga_handle *handle);
+int32_t rutabaga_resource_create_blob(struct rutabaga *ptr,
+                                      uint32_t ctx_id,
+                                      uint32_t resource_id,
+                                      const rutabaga_create_blob *create_blob,
+                                      const struct rutabaga_iovecs *iovecs,
+                                      const struct rutabaga_handle *handle);
 
 int32_t rutabaga_resource_unref(struct rutabaga *ptr, uint32_t resource_id);
 
@@ -236,8 +291,9 @@ int32_t rutabaga_resource_unref(struct rutabaga *ptr, uint32_t resource_id);
  * # Safety
  * Caller owns raw descriptor on success and is responsible for closing it.
  */
-int32_t rutabaga_resource_export_blob(struct rutabaga *ptr, uint32_t resource_id,
-				      struct rutabaga_handle *handle);
+int32_t rutabaga_resource_export_blob(struct rutabaga *ptr,
+                                      uint32_t resource_id,
+                                      struct rutabaga_handle *handle);
 
 int32_t rutabaga_resource_map_info(struct rutabaga *ptr, uint32_t resource_id, uint32_t *map_info);
 
@@ -245,13 +301,21 @@ int32_t rutabaga_resource_map_info(struct rutabaga *ptr, uint32_t resource_id, u
  * # Safety
  * - `commands` must point to a contiguous memory region of `size` bytes.
  */
-int32_t rutabaga_submit_command(struct rutabaga *ptr, uint32_t ctx_id, uint8_t *commands,
-				size_t size);
+int32_t rutabaga_submit_command(struct rutabaga *ptr,
+                                uint32_t ctx_id,
+                                uint8_t *commands,
+                                size_t size);
+
+int32_t rutabaga_create_fence(struct rutabaga *ptr, const rutabaga_fence *fence);
 
-int32_t rutabaga_create_fence(struct rutabaga *ptr, const struct rutabaga_fence *fence);
+/**
+ * Polls the default component and any contexts for completed fences.  Completed fences are
+ * reported through the `fence_cb` registered in `rutabaga_init`, once per fence.
+ */
+int32_t rutabaga_poll(struct rutabaga *ptr);
 
 #ifdef __cplusplus
-}
-#endif
+} // extern "C"
+#endif // __cplusplus
 
-#endif
+#endif /* RUTABAGA_GFX_FFI_H */
@@ -5,13 +5,16 @@
 ///! C-bindings for the rutabaga_gfx crate
 extern crate rutabaga_gfx;
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi::CStr;
+use std::ops::{Deref, DerefMut};
 use std::os::raw::c_char;
 use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::PathBuf;
 use std::ptr::{copy_nonoverlapping, null_mut};
 use std::slice::{from_raw_parts, from_raw_parts_mut};
+use std::sync::{Arc, Mutex};
 
 use base::{error, FromRawDescriptor, IntoRawDescriptor, SafeDescriptor};
 use data_model::VolatileSlice;
@@ -43,13 +46,72 @@ macro_rules! return_on_error {
     };
 }
 
-const RUTABAGA_COMPONENT_2D: u32 = 1;
-const RUTABAGA_COMPONENT_VIRGL_RENDERER: u32 = 2;
-const RUTABAGA_COMPONENT_GFXSTREAM: u32 = 3;
-const RUTABAGA_COMPONENT_CROSS_DOMAIN: u32 = 4;
-
+// The constants below mirror rutabaga_gfx so cbindgen can emit them into rutabaga_gfx_ffi.h.
+
+/// Rutabaga component types
+pub const RUTABAGA_COMPONENT_2D: u32 = 1;
+pub const RUTABAGA_COMPONENT_VIRGL_RENDERER: u32 = 2;
+pub const RUTABAGA_COMPONENT_GFXSTREAM: u32 = 3;
+pub const RUTABAGA_COMPONENT_CROSS_DOMAIN: u32 = 4;
+
+/// Blob resource creation parameters.
+pub const RUTABAGA_BLOB_MEM_GUEST: u32 = 0x0001;
+pub const RUTABAGA_BLOB_MEM_HOST3D: u32 = 0x0002;
+pub const RUTABAGA_BLOB_MEM_HOST3D_GUEST: u32 = 0x0003;
+
+pub const RUTABAGA_BLOB_FLAG_USE_MAPPABLE: u32 = 0x0001;
+pub const RUTABAGA_BLOB_FLAG_USE_SHAREABLE: u32 = 0x0002;
+pub const RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE: u32 = 0x0004;
+
+/// Rutabaga capsets.
+pub const RUTABAGA_CAPSET_VIRGL: u32 = 1;
+pub const RUTABAGA_CAPSET_VIRGL2: u32 = 2;
+pub const RUTABAGA_CAPSET_GFXSTREAM: u32 = 3;
+pub const RUTABAGA_CAPSET_VENUS: u32 = 4;
+pub const RUTABAGA_CAPSET_CROSS_DOMAIN: u32 = 5;
+
+/// Mapped memory caching flags (see virtio_gpu spec)
+pub const RUTABAGA_MAP_CACHE_CACHED: u32 = 0x01;
+pub const RUTABAGA_MAP_CACHE_UNCACHED: u32 = 0x02;
+pub const RUTABAGA_MAP_CACHE_WC: u32 = 0x03;
+
+/// Rutabaga flags for creating fences.
+pub const RUTABAGA_FLAG_FENCE: u32 = 1 << 0;
+pub const RUTABAGA_FLAG_INFO_RING_IDX: u32 = 1 << 1;
+
+/// Rutabaga channel types
+pub const RUTABAGA_CHANNEL_TYPE_WAYLAND: u32 = 0x0001;
+pub const RUTABAGA_CHANNEL_TYPE_CAMERA: u32 = 0x0002;
+
+/// Rutabaga handle types
+pub const RUTABAGA_MEM_HANDLE_TYPE_OPAQUE_FD: u32 = 0x0001;
+pub const RUTABAGA_MEM_HANDLE_TYPE_DMABUF: u32 = 0x0002;
+pub const RUTABAGE_MEM_HANDLE_TYPE_OPAQUE_WIN32: u32 = 0x0003;
+pub const RUTABAGA_FENCE_HANDLE_TYPE_OPAQUE_FD: u32 = 0x0004;
+pub const RUTABAGA_FENCE_HANDLE_TYPE_SYNC_FD: u32 = 0x0005;
+pub const RUTABAGE_FENCE_HANDLE_TYPE_OPAQUE_WIN32: u32 = 0x0006;
+
+/// A rutabaga instance created by `rutabaga_init`.
 #[allow(non_camel_case_types)]
-type rutabaga = Rutabaga;
+pub struct rutabaga {
+    rutabaga: Rutabaga,
+    // Reports the fences that `rutabaga_poll` finds completed.
+    fence_handler: RutabagaFenceHandler,
+}
+
+impl Deref for rutabaga {
+    type Target = Rutabaga;
+
+    fn deref(&self) -> &Rutabaga {
+        &self.rutabaga
+    }
+}
+
+impl DerefMut for rutabaga {
+    fn deref_mut(&mut self) -> &mut Rutabaga {
+        &mut self.rutabaga
+    }
+}
 
 #[allow(non_camel_case_types)]
 type rutabaga_create_blob = ResourceCreateBlob;
@@ -77,6 +139,7 @@ pub struct rutabaga_handle {
     pub handle_type: u32,
 }
 
+/// Assumes null-terminated C-string.
 #[repr(C)]
 pub struct rutabaga_channel {
     pub channel_name: *const c_char,
@@ -89,6 +152,7 @@ pub struct rutabaga_channels {
     pub num_channels: usize,
 }
 
+/// Throwing an exception inside this callback is not allowed.
 #[allow(non_camel_case_types)]
 pub type write_fence_cb = extern "C" fn(user_data: u64, fence_data: rutabaga_fence);
 
@@ -100,8 +164,29 @@ pub struct rutabaga_builder<'a> {
     pub channels: Option<&'a rutabaga_channels>,
 }
 
+// Components report a fence when it completes and `rutabaga_poll` reports the latest completed
+// fence again, so only call `fence_cb` for fences newer than the last one on their timeline.
 fn create_ffi_fence_handler(user_data: u64, fence_cb: write_fence_cb) -> RutabagaFenceHandler {
-    RutabagaFenceClosure::new(move |completed_fence| fence_cb(user_data, completed_fence))
+    // The global timeline of the default component has no ring index.
+    let reported = Arc::new(Mutex::new(BTreeMap::new()));
+    RutabagaFenceClosure::new(move |completed_fence: RutabagaFence| {
+        let timeline = if completed_fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0 {
+            Some((completed_fence.ctx_id, completed_fence.ring_idx))
+        } else {
+            None
+        };
+
+        {
+            let mut reported = reported.lock().unwrap();
+            let latest = reported.entry(timeline).or_insert(0);
+            if completed_fence.fence_id <= *latest {
+                return;
+            }
+            *latest = completed_fence.fence_id;
+        }
+
+        fence_cb(user_data, completed_fence)
+    })
 }
 
 /// # Safety
@@ -160,10 +245,13 @@ pub unsafe extern "C" fn rutabaga_init(builder: &rutabaga_builder, ptr: &mut *mu
             .set_virglrenderer_flags(virglrenderer_flags)
             .set_gfxstream_flags(gfxstream_flags)
             .set_rutabaga_channels(rutabaga_channels_opt)
-            .build(fence_handler);
+            .build(fence_handler.clone());
 
-        let rtbg = return_on_error!(result);
-        *ptr = Box::into_raw(Box::new(rtbg)) as _;
+        let rtbg = rutabaga {
+            rutabaga: return_on_error!(result),
+            fence_handler,
+        };
+        *ptr = Box::into_raw(Box::new(rtbg));
         NO_ERROR
     }))
     .unwrap_or(-ESRCH)
@@ -493,3 +581,206 @@ pub extern "C" fn rutabaga_create_fence(ptr: &mut rutabaga, fence: &rutabaga_fen
     }))
     .unwrap_or(-ESRCH)
 }
+
+/// Polls the default component and any contexts for completed fences.  Completed fences are
+/// reported through the `fence_cb` registered in `rutabaga_init`, once per fence.
+#[no_mangle]
+pub extern "C" fn rutabaga_poll(ptr: &mut rutabaga) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        for fence in ptr.rutabaga.poll() {
+            ptr.fence_handler.call(fence);
+        }
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const WIDTH: u32 = 4;
+    const HEIGHT: u32 = 4;
+    const BYTES_PER_PIXEL: u32 = 4;
+
+    extern "C" fn record_fence(user_data: u64, fence_data: rutabaga_fence) {
+        // Safe because the tests pass a pointer to an `AtomicU64` that outlives the rutabaga
+        // instance as `user_data`.
+        let latest_fence = unsafe { &*(user_data as *const AtomicU64) };
+        latest_fence.store(fence_data.fence_id, Ordering::SeqCst);
+    }
+
+    fn init(component: u32, latest_fence: &AtomicU64) -> Result<*mut rutabaga, i32> {
+        let builder = rutabaga_builder {
+            user_data: latest_fence as *const AtomicU64 as u64,
+            default_component: component,
+            fence_cb: record_fence,
+            channels: None,
+        };
+        let mut ptr = null_mut();
+        // Safe because the builder has no channels.
+        match unsafe { rutabaga_init(&builder, &mut ptr) } {
+            NO_ERROR => Ok(ptr),
+            e => Err(e),
+        }
+    }
+
+    #[test]
+    fn init_unknown_component() {
+        let latest_fence = AtomicU64::new(0);
+        assert_eq!(init(0, &latest_fence), Err(-EINVAL));
+    }
+
+    #[test]
+    fn fence_callback() {
+        let latest_fence = AtomicU64::new(0);
+        let mut ptr = init(RUTABAGA_COMPONENT_2D, &latest_fence).unwrap();
+        // Safe because `ptr` was just returned by a successful `rutabaga_init`.
+        let rtbg = unsafe { &mut *ptr };
+
+        let fence = rutabaga_fence {
+            flags: RUTABAGA_FLAG_FENCE,
+            fence_id: 7,
+            ctx_id: 0,
+            ring_idx: 0,
+        };
+        assert_eq!(rutabaga_create_fence(rtbg, &fence), NO_ERROR);
+        assert_eq!(rutabaga_poll(rtbg), NO_ERROR);
+        assert_eq!(latest_fence.load(Ordering::SeqCst), 7);
+
+        assert_eq!(rutabaga_finish(&mut ptr), NO_ERROR);
+        assert!(ptr.is_null());
+    }
+
+    #[test]
+    fn poll_reports_fences_once() {
+        let latest_fence = AtomicU64::new(0);
+        let mut ptr = init(RUTABAGA_COMPONENT_2D, &latest_fence).unwrap();
+        // Safe because `ptr` was just returned by a successful `rutabaga_init`.
+        let rtbg = unsafe { &mut *ptr };
+
+        let mut fence = rutabaga_fence {
+            flags: RUTABAGA_FLAG_FENCE,
+            fence_id: 9,
+            ctx_id: 0,
+            ring_idx: 0,
+        };
+        assert_eq!(rutabaga_create_fence(rtbg, &fence), NO_ERROR);
+        assert_eq!(latest_fence.load(Ordering::SeqCst), 9);
+
+        // The 2D component already reported the fence when it was created.
+        latest_fence.store(0, Ordering::SeqCst);
+        assert_eq!(rutabaga_poll(rtbg), NO_ERROR);
+        assert_eq!(latest_fence.load(Ordering::SeqCst), 0);
+
+        fence.fence_id = 10;
+        assert_eq!(rutabaga_create_fence(rtbg, &fence), NO_ERROR);
+        assert_eq!(rutabaga_poll(rtbg), NO_ERROR);
+        assert_eq!(latest_fence.load(Ordering::SeqCst), 10);
+
+        assert_eq!(rutabaga_finish(&mut ptr), NO_ERROR);
+    }
+
+    #[test]
+    fn resource_2d_round_trip() {
+        let latest_fence = AtomicU64::new(0);
+        let mut ptr = init(RUTABAGA_COMPONENT_2D, &latest_fence).unwrap();
+        // Safe because `ptr` was just returned by a successful `rutabaga_init`.
+        let rtbg = unsafe { &mut *ptr };
+
+        let create_3d = rutabaga_create_3d {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: WIDTH,
+            height: HEIGHT,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        assert_eq!(rutabaga_resource_create_3d(rtbg, 1, &create_3d), NO_ERROR);
+        assert_eq!(
+            rutabaga_resource_create_3d(rtbg, 1, &create_3d),
+            -EINVAL,
+            "resource ids must be unique"
+        );
+
+        let size = (WIDTH * HEIGHT * BYTES_PER_PIXEL) as usize;
+        let mut backing: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let mut backing_iovec = iovec {
+            iov_base: backing.as_mut_ptr() as *mut _,
+            iov_len: backing.len(),
+        };
+        let iovecs = rutabaga_iovecs {
+            iovecs: &mut backing_iovec,
+            num_iovecs: 1,
+        };
+        // Safe because `backing` outlives the resource.
+        let ret = unsafe { rutabaga_resource_attach_backing(rtbg, 1, &iovecs) };
+        assert_eq!(ret, NO_ERROR);
+
+        let mut transfer = Transfer3D::new_2d(0, 0, WIDTH, HEIGHT);
+        transfer.stride = WIDTH * BYTES_PER_PIXEL;
+        assert_eq!(
+            rutabaga_resource_transfer_write(rtbg, 0, 1, &transfer),
+            NO_ERROR
+        );
+
+        let mut readback = vec![0u8; size];
+        let readback_iovec = iovec {
+            iov_base: readback.as_mut_ptr() as *mut _,
+            iov_len: readback.len(),
+        };
+        // Safe because `readback_iovec` describes `readback`.
+        let ret = unsafe {
+            rutabaga_resource_transfer_read(rtbg, 0, 1, &transfer, Some(&readback_iovec))
+        };
+        assert_eq!(ret, NO_ERROR);
+        assert_eq!(readback, backing);
+
+        assert_eq!(rutabaga_resource_detach_backing(rtbg, 1), NO_ERROR);
+        assert_eq!(rutabaga_resource_unref(rtbg, 1), NO_ERROR);
+        assert_eq!(rutabaga_resource_unref(rtbg, 1), -EINVAL);
+        assert_eq!(rutabaga_finish(&mut ptr), NO_ERROR);
+    }
+
+    #[test]
+    fn constants_match_rutabaga_gfx() {
+        macro_rules! assert_mirrors {
+            ($($name:ident),*) => {
+                $(assert_eq!($name, rutabaga_gfx::$name, stringify!($name));)*
+            };
+        }
+
+        assert_mirrors!(
+            RUTABAGA_BLOB_MEM_GUEST,
+            RUTABAGA_BLOB_MEM_HOST3D,
+            RUTABAGA_BLOB_MEM_HOST3D_GUEST,
+            RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+            RUTABAGA_BLOB_FLAG_USE_SHAREABLE,
+            RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE,
+            RUTABAGA_CAPSET_VIRGL,
+            RUTABAGA_CAPSET_VIRGL2,
+            RUTABAGA_CAPSET_GFXSTREAM,
+            RUTABAGA_CAPSET_VENUS,
+            RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RUTABAGA_MAP_CACHE_CACHED,
+            RUTABAGA_MAP_CACHE_UNCACHED,
+            RUTABAGA_MAP_CACHE_WC,
+            RUTABAGA_FLAG_FENCE,
+            RUTABAGA_FLAG_INFO_RING_IDX,
+            RUTABAGA_CHANNEL_TYPE_WAYLAND,
+            RUTABAGA_CHANNEL_TYPE_CAMERA,
+            RUTABAGA_MEM_HANDLE_TYPE_OPAQUE_FD,
+            RUTABAGA_MEM_HANDLE_TYPE_DMABUF,
+            RUTABAGE_MEM_HANDLE_TYPE_OPAQUE_WIN32,
+            RUTABAGA_FENCE_HANDLE_TYPE_OPAQUE_FD,
+            RUTABAGA_FENCE_HANDLE_TYPE_SYNC_FD,
+            RUTABAGE_FENCE_HANDLE_TYPE_OPAQUE_WIN32
+        );
+    }
+}
@@ -74,26 +74,22 @@ static int test_rutabaga_init(struct rutabaga_test *test, uint32_t component)
 	int result;
 	struct rutabaga_builder builder = { 0 };
 	struct rutabaga_channels channels = { 0 };
+	struct rutabaga_channel channel = { 0 };
 
 	builder.fence_cb = rutabaga_test_write_fence;
-	builder.rutabaga_component = component;
+	builder.default_component = component;
 	if (component == RUTABAGA_COMPONENT_CROSS_DOMAIN) {
 		builder.user_data = (uint64_t)(uintptr_t *)(void *)test;
-		channels.channels =
-		    (struct rutabaga_channel *)calloc(1, sizeof(struct rutabaga_channel));
-		channels.num_channels = 1;
+		channel.channel_name = s_wayland_path;
+		channel.channel_type = RUTABAGA_CHANNEL_TYPE_WAYLAND;
 
-		channels.channels[0].channel_name = s_wayland_path;
-		channels.channels[0].channel_type = RUTABAGA_CHANNEL_TYPE_WAYLAND;
+		channels.channels = &channel;
+		channels.num_channels = 1;
 
 		builder.channels = &channels;
 	}
 
 	result = rutabaga_init(&builder, &test->rutabaga);
-
-	if (component == RUTABAGA_COMPONENT_CROSS_DOMAIN)
-		free(channels.channels);
-
 	CHECK_RESULT(result);
 	return 0;
 }
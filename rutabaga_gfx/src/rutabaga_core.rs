@@ -657,6 +657,7 @@ pub struct RutabagaBuilder {
     virglrenderer_flags: Option<VirglRendererFlags>,
     gfxstream_flags: Option<GfxstreamFlags>,
     channels: Option<Vec<RutabagaChannel>>,
+    software_renderer: bool,
 }
 
 impl RutabagaBuilder {
@@ -669,6 +670,7 @@ impl RutabagaBuilder {
             virglrenderer_flags: None,
             gfxstream_flags: None,
             channels: None,
+            software_renderer: false,
         }
     }
 
@@ -708,6 +710,14 @@ impl RutabagaBuilder {
         self
     }
 
+    /// Render with a software rasterizer for the RutabagaBuilder, so that hosts without a GPU can
+    /// still offer 3D to the guest.  Only virglrenderer supports this, and it then always uses a
+    /// surfaceless EGL context.  `set_software_renderer_env` must be called at process start.
+    pub fn set_software_renderer(mut self, software_renderer: bool) -> RutabagaBuilder {
+        self.software_renderer = software_renderer;
+        self
+    }
+
     /// Builds Rutabaga and returns a handle to it.
     ///
     /// This should be only called once per every virtual machine instance.  Rutabaga tries to
@@ -725,10 +735,21 @@ impl RutabagaBuilder {
         } else {
             #[cfg(feature = "virgl_renderer")]
             if self.default_component == RutabagaComponentType::VirglRenderer {
-                let virglrenderer_flags = self
+                let mut virglrenderer_flags = self
                     .virglrenderer_flags
                     .ok_or(RutabagaError::InvalidRutabagaBuild)?;
 
+                if self.software_renderer {
+                    if !software_renderer_env_is_set() {
+                        return Err(RutabagaError::InvalidRutabagaBuild);
+                    }
+
+                    virglrenderer_flags = virglrenderer_flags
+                        .use_egl(true)
+                        .use_glx(false)
+                        .use_surfaceless(true);
+                }
+
                 let virgl = VirglRenderer::init(virglrenderer_flags, fence_handler.clone())?;
                 rutabaga_components.insert(RutabagaComponentType::VirglRenderer, virgl);
 
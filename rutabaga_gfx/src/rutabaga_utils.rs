@@ -4,6 +4,7 @@
 
 //! rutabaga_utils: Utility enums, structs, and implementations needed by the rest of the crate.
 
+use std::env;
 use std::io::Error as IoError;
 use std::num::TryFromIntError;
 use std::os::raw::c_void;
@@ -369,6 +370,32 @@ impl VirglRendererFlags {
     }
 }
 
+/// Environment that makes Mesa render with a software rasterizer (e.g, llvmpipe) on a surfaceless
+/// EGL display, which doesn't need a render node.
+const SOFTWARE_RENDERER_ENV: [(&str, &str); 2] = [
+    ("EGL_PLATFORM", "surfaceless"),
+    ("LIBGL_ALWAYS_SOFTWARE", "1"),
+];
+
+/// Sets up the environment needed by `RutabagaBuilder::set_software_renderer`.  Mesa reads it
+/// when virglrenderer creates its EGL display.
+///
+/// Changing the environment is not thread safe, so this must be called at process start before
+/// any other threads exist.
+pub fn set_software_renderer_env() {
+    for (key, value) in SOFTWARE_RENDERER_ENV.iter() {
+        env::set_var(key, value);
+    }
+}
+
+/// Returns true if `set_software_renderer_env` was called.
+#[cfg(feature = "virgl_renderer")]
+pub(crate) fn software_renderer_env_is_set() -> bool {
+    SOFTWARE_RENDERER_ENV
+        .iter()
+        .all(|(key, value)| env::var_os(key).map_or(false, |v| v == *value))
+}
+
 /// Flags for the gfxstream renderer.
 const GFXSTREAM_RENDERER_FLAGS_USE_EGL: u32 = 1 << 0;
 #[allow(dead_code)]
@@ -0,0 +1,118 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Exercises the virglrenderer component with a software rasterizer, so it runs on hosts
+//! without a GPU.  virglrenderer can only be initialized once per process, so everything is
+//! driven from a single test.
+
+#![cfg(feature = "virgl_renderer")]
+
+use data_model::VolatileSlice;
+use rutabaga_gfx::{
+    set_software_renderer_env, ResourceCreate3D, RutabagaBuilder, RutabagaComponentType,
+    RutabagaFenceClosure, Transfer3D, VirglRendererFlags, RUTABAGA_PIPE_BIND_RENDER_TARGET,
+    RUTABAGA_PIPE_TEXTURE_2D,
+};
+
+// Subset of the virgl protocol (virgl_protocol.h) needed to clear a render target.
+const VIRGL_CCMD_CREATE_OBJECT: u32 = 1;
+const VIRGL_CCMD_SET_FRAMEBUFFER_STATE: u32 = 5;
+const VIRGL_CCMD_CLEAR: u32 = 7;
+const VIRGL_OBJECT_SURFACE: u32 = 8;
+const VIRGL_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const PIPE_CLEAR_COLOR0: u32 = 1 << 2;
+
+const CTX_ID: u32 = 1;
+const RESOURCE_ID: u32 = 1;
+const SURFACE_HANDLE: u32 = 1;
+const WIDTH: u32 = 16;
+const HEIGHT: u32 = 16;
+
+fn virgl_cmd0(cmd: u32, obj: u32, len: u32) -> u32 {
+    cmd | obj << 8 | len << 16
+}
+
+fn clear_commands(color: [f32; 4]) -> Vec<u8> {
+    let mut cmds = vec![
+        virgl_cmd0(VIRGL_CCMD_CREATE_OBJECT, VIRGL_OBJECT_SURFACE, 5),
+        SURFACE_HANDLE,
+        RESOURCE_ID,
+        VIRGL_FORMAT_B8G8R8A8_UNORM,
+        0, // level
+        0, // first and last layer
+        virgl_cmd0(VIRGL_CCMD_SET_FRAMEBUFFER_STATE, 0, 3),
+        1, // nr_cbufs
+        0, // zsurf handle
+        SURFACE_HANDLE,
+        virgl_cmd0(VIRGL_CCMD_CLEAR, 0, 8),
+        PIPE_CLEAR_COLOR0,
+    ];
+    cmds.extend(color.iter().map(|c| c.to_bits()));
+    let depth = 0f64.to_bits();
+    cmds.push(depth as u32);
+    cmds.push((depth >> 32) as u32);
+    cmds.push(0); // stencil
+
+    cmds.iter().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+}
+
+#[test]
+fn software_clear_and_read_back() {
+    // This is the only test in the binary, so nothing else reads the environment concurrently.
+    set_software_renderer_env();
+
+    let virglrenderer_flags = VirglRendererFlags::new().use_virgl(true).use_gles(true);
+    let mut rutabaga = RutabagaBuilder::new(RutabagaComponentType::VirglRenderer)
+        .set_virglrenderer_flags(virglrenderer_flags)
+        .set_software_renderer(true)
+        .build(RutabagaFenceClosure::new(|_| {}))
+        .expect("failed to initialize virglrenderer with a software rasterizer");
+
+    rutabaga.create_context(CTX_ID, 0).unwrap();
+    rutabaga
+        .resource_create_3d(
+            RESOURCE_ID,
+            ResourceCreate3D {
+                target: RUTABAGA_PIPE_TEXTURE_2D,
+                format: VIRGL_FORMAT_B8G8R8A8_UNORM,
+                bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+                width: WIDTH,
+                height: HEIGHT,
+                depth: 1,
+                array_size: 1,
+                last_level: 0,
+                nr_samples: 0,
+                flags: 0,
+            },
+        )
+        .unwrap();
+    rutabaga
+        .context_attach_resource(CTX_ID, RESOURCE_ID)
+        .unwrap();
+
+    let mut cmds = clear_commands([1.0, 0.0, 0.0, 1.0]);
+    rutabaga.submit_command(CTX_ID, &mut cmds).unwrap();
+
+    let mut pixels = vec![0u8; (WIDTH * HEIGHT * 4) as usize];
+    let mut transfer = Transfer3D::new_2d(0, 0, WIDTH, HEIGHT);
+    transfer.stride = WIDTH * 4;
+    rutabaga
+        .transfer_read(
+            CTX_ID,
+            RESOURCE_ID,
+            transfer,
+            Some(VolatileSlice::new(&mut pixels)),
+        )
+        .unwrap();
+
+    for pixel in pixels.chunks(4) {
+        assert_eq!(pixel, [0x00, 0x00, 0xff, 0xff]);
+    }
+
+    rutabaga
+        .context_detach_resource(CTX_ID, RESOURCE_ID)
+        .unwrap();
+    rutabaga.unref_resource(RESOURCE_ID).unwrap();
+    rutabaga.destroy_context(CTX_ID).unwrap();
+}
@@ -24,6 +24,8 @@ use arch::{Pstore, VcpuAffinity};
 use devices::serial_device::{SerialHardware, SerialParameters};
 #[cfg(feature = "audio_cras")]
 use devices::virtio::cras_backend::Parameters as CrasSndParameters;
+#[cfg(feature = "audio")]
+use devices::virtio::cras_backend::Parameters as StreamSndParameters;
 use devices::virtio::fs::passthrough;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
@@ -73,6 +75,14 @@ pub struct VhostUserFsOption {
     pub tag: String,
 }
 
+/// A vhost-user device of a type crosvm has no dedicated frontend for.
+pub struct VhostUserGenericOption {
+    pub socket: PathBuf,
+    pub device_type: u32,
+    pub queue_sizes: Vec<u16>,
+    pub config_size: u64,
+}
+
 pub struct VhostUserWlOption {
     pub socket: PathBuf,
     pub vm_tube: PathBuf,
@@ -157,6 +167,11 @@ impl TouchDeviceOption {
 pub enum SharedDirKind {
     FS,
     P9,
+    /// A virtio-fs device serving a writable upper directory layered over the read-only source.
+    Overlay,
+    /// A writable virtio-fs device whose contents live in memory, optionally seeded from the tar
+    /// archive at the source path.
+    Tmpfs,
 }
 
 impl FromStr for SharedDirKind {
@@ -167,6 +182,8 @@ impl FromStr for SharedDirKind {
         match s {
             "fs" | "FS" => Ok(FS),
             "9p" | "9P" | "p9" | "P9" => Ok(P9),
+            "overlay" => Ok(Overlay),
+            "tmpfs" => Ok(Tmpfs),
             _ => Err("invalid file system type"),
         }
     }
@@ -186,6 +203,12 @@ pub struct SharedDir {
     pub gid_map: String,
     pub fs_cfg: passthrough::Config,
     pub p9_cfg: p9::Config,
+    /// The writable directory of an overlay.
+    pub overlay_upper: Option<PathBuf>,
+    /// Additional read-only layers stacked on top of `src` in an overlay, from the bottom up.
+    pub overlay_lowers: Vec<PathBuf>,
+    /// A file to which the virtio-fs server appends a record of every mutating request.
+    pub audit_log: Option<PathBuf>,
 }
 
 impl Default for SharedDir {
@@ -198,6 +221,9 @@ impl Default for SharedDir {
             gid_map: format!("0 {} 1", unsafe { getegid() }),
             fs_cfg: Default::default(),
             p9_cfg: Default::default(),
+            overlay_upper: None,
+            overlay_lowers: Vec::new(),
+            audit_log: None,
         }
     }
 }
@@ -330,6 +356,7 @@ pub struct Config {
     pub vhost_net: bool,
     pub tap_fd: Vec<RawFd>,
     pub cid: Option<u64>,
+    pub vsock_uds_path: Option<PathBuf>,
     pub wayland_socket_paths: BTreeMap<String, PathBuf>,
     pub x_display: Option<String>,
     pub shared_dirs: Vec<SharedDir>,
@@ -345,12 +372,16 @@ pub struct Config {
     pub ac97_parameters: Vec<Ac97Parameters>,
     #[cfg(feature = "audio")]
     pub sound: Option<PathBuf>,
+    #[cfg(feature = "audio")]
+    pub stream_sound: Option<StreamSndParameters>,
     pub serial_parameters: BTreeMap<(SerialHardware, u8), SerialParameters>,
     pub syslog_tag: Option<String>,
     pub virtio_single_touch: Vec<TouchDeviceOption>,
     pub virtio_multi_touch: Vec<TouchDeviceOption>,
     pub virtio_trackpad: Vec<TouchDeviceOption>,
     pub virtio_mice: Vec<PathBuf>,
+    pub virtio_tablets: Vec<TouchDeviceOption>,
+    pub virtio_gamepads: Vec<PathBuf>,
     pub virtio_keyboard: Vec<PathBuf>,
     pub virtio_switches: Vec<PathBuf>,
     pub virtio_input_evdevs: Vec<PathBuf>,
@@ -367,6 +398,7 @@ pub struct Config {
     pub vhost_user_blk: Vec<VhostUserOption>,
     pub vhost_user_console: Vec<VhostUserOption>,
     pub vhost_user_fs: Vec<VhostUserFsOption>,
+    pub vhost_user_generic: Vec<VhostUserGenericOption>,
     pub vhost_user_gpu: Vec<VhostUserOption>,
     pub vhost_user_mac80211_hwsim: Option<VhostUserOption>,
     pub vhost_user_net: Vec<VhostUserOption>,
@@ -374,6 +406,7 @@ pub struct Config {
     pub vhost_user_snd: Vec<VhostUserOption>,
     pub vhost_user_vsock: Vec<VhostUserOption>,
     pub vhost_user_wl: Vec<VhostUserWlOption>,
+    pub vvu_proxy: Vec<VhostUserOption>,
     #[cfg(feature = "direct")]
     pub direct_pmio: Option<DirectIoOption>,
     #[cfg(feature = "direct")]
@@ -426,6 +459,7 @@ impl Default for Config {
             vhost_net: false,
             tap_fd: Vec::new(),
             cid: None,
+            vsock_uds_path: None,
             #[cfg(feature = "gpu")]
             gpu_parameters: None,
             software_tpm: false,
@@ -441,12 +475,16 @@ impl Default for Config {
             ac97_parameters: Vec::new(),
             #[cfg(feature = "audio")]
             sound: None,
+            #[cfg(feature = "audio")]
+            stream_sound: None,
             serial_parameters: BTreeMap::new(),
             syslog_tag: None,
             virtio_single_touch: Vec::new(),
             virtio_multi_touch: Vec::new(),
             virtio_trackpad: Vec::new(),
             virtio_mice: Vec::new(),
+            virtio_tablets: Vec::new(),
+            virtio_gamepads: Vec::new(),
             virtio_keyboard: Vec::new(),
             virtio_switches: Vec::new(),
             virtio_input_evdevs: Vec::new(),
@@ -464,12 +502,14 @@ impl Default for Config {
             vhost_user_console: Vec::new(),
             vhost_user_gpu: Vec::new(),
             vhost_user_fs: Vec::new(),
+            vhost_user_generic: Vec::new(),
             vhost_user_mac80211_hwsim: None,
             vhost_user_net: Vec::new(),
             #[cfg(feature = "audio")]
             vhost_user_snd: Vec::new(),
             vhost_user_vsock: Vec::new(),
             vhost_user_wl: Vec::new(),
+            vvu_proxy: Vec::new(),
             #[cfg(feature = "direct")]
             direct_pmio: None,
             #[cfg(feature = "direct")]
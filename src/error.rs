@@ -5,10 +5,12 @@
 use arch::{self, LinuxArch};
 use base::TubeError;
 use devices::virtio;
+use devices::virtio::vhost::user::proxy::Error as VirtioVhostUserError;
 use devices::virtio::vhost::user::vmm::Error as VhostUserVmmError;
 use std::error::Error as StdError;
 use std::fmt::{self, Display};
 use std::io;
+use std::net::SocketAddr;
 use std::num::ParseIntError;
 use std::path::PathBuf;
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
@@ -36,6 +38,7 @@ pub enum Error {
     AllocateGpuDeviceAddress,
     AllocatePmemDeviceAddress(resources::Error),
     BalloonDeviceNew(virtio::BalloonError),
+    BindVncListener(SocketAddr, io::Error),
     BlockDeviceNew(base::Error),
     BlockSignal(base::signal::Error),
     BuildVm(<Arch as LinuxArch>::Error),
@@ -93,6 +96,7 @@ pub enum Error {
     InputEventsOpen(io::Error),
     InvalidHotPlugKey,
     InvalidVfioPath,
+    InvalidVsockPath,
     InvalidWaylandPath,
     IoJail(minijail::Error),
     LoadKernel(Box<dyn StdError>),
@@ -102,6 +106,8 @@ pub enum Error {
     OpenAcpiTable(PathBuf, io::Error),
     OpenAndroidFstab(PathBuf, io::Error),
     OpenBios(PathBuf, io::Error),
+    OpenFsArchive(PathBuf, io::Error),
+    OpenFsAuditLog(PathBuf, io::Error),
     OpenInitrd(PathBuf, io::Error),
     OpenKernel(PathBuf, io::Error),
     OpenVinput(PathBuf, io::Error),
@@ -139,6 +145,8 @@ pub enum Error {
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     SpawnGdbServer(io::Error),
     SpawnVcpu(io::Error),
+    #[cfg(feature = "audio")]
+    StreamSoundDeviceNew(virtio::snd::cras_backend::Error),
     SwiotlbTooLarge,
     Timer(base::Error),
     ValidateRawDescriptor(base::Error),
@@ -146,6 +154,7 @@ pub enum Error {
     VhostUserBlockDeviceNew(VhostUserVmmError),
     VhostUserConsoleDeviceNew(VhostUserVmmError),
     VhostUserFsDeviceNew(VhostUserVmmError),
+    VhostUserGenericDeviceNew(VhostUserVmmError),
     VhostUserGpuDeviceNew(VhostUserVmmError),
     VhostUserMac80211HwsimNew(VhostUserVmmError),
     VhostUserNetDeviceNew(VhostUserVmmError),
@@ -155,7 +164,8 @@ pub enum Error {
     VhostUserWlDeviceNew(VhostUserVmmError),
     VhostVsockDeviceNew(virtio::vhost::Error),
     VirtioPciDev(base::Error),
-    VirtioVhostUserDeviceNew(VhostUserVmmError),
+    VirtioVhostUserDeviceNew(VirtioVhostUserError),
+    VsockDeviceNew(virtio::VsockError),
     WaitContextAdd(base::Error),
     WaitContextDelete(base::Error),
     WaylandDeviceNew(base::Error),
@@ -176,6 +186,9 @@ impl Display for Error {
                 write!(f, "failed to allocate memory for pmem device: {}", e)
             }
             BalloonDeviceNew(e) => write!(f, "failed to create balloon: {}", e),
+            BindVncListener(addr, e) => {
+                write!(f, "failed to listen for VNC clients on {}: {}", addr, e)
+            }
             BlockDeviceNew(e) => write!(f, "failed to create block device: {}", e),
             BlockSignal(e) => write!(f, "failed to block signal: {}", e),
             BuildVm(e) => write!(f, "The architecture failed to build the vm: {}", e),
@@ -237,6 +250,7 @@ impl Display for Error {
             InputEventsOpen(e) => write!(f, "failed to open event device: {}", e),
             InvalidHotPlugKey => write!(f, "failed to find hotplug key in hotplug bus"),
             InvalidVfioPath => write!(f, "failed to parse or find vfio path"),
+            InvalidVsockPath => write!(f, "vsock socket path has no parent directory"),
             InvalidWaylandPath => write!(f, "wayland socket path has no parent or file name"),
             IoJail(e) => write!(f, "{}", e),
             LoadKernel(e) => write!(f, "failed to load kernel: {}", e),
@@ -251,6 +265,12 @@ impl Display for Error {
                 e
             ),
             OpenBios(p, e) => write!(f, "failed to open bios {}: {}", p.display(), e),
+            OpenFsArchive(p, e) => {
+                write!(f, "failed to open fs archive {}: {}", p.display(), e)
+            }
+            OpenFsAuditLog(p, e) => {
+                write!(f, "failed to open fs audit log {}: {}", p.display(), e)
+            }
             OpenInitrd(p, e) => write!(f, "failed to open initrd {}: {}", p.display(), e),
             OpenKernel(p, e) => write!(f, "failed to open kernel image {}: {}", p.display(), e),
             OpenVinput(p, e) => write!(f, "failed to open vinput device {}: {}", p.display(), e),
@@ -294,6 +314,8 @@ impl Display for Error {
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             SpawnGdbServer(e) => write!(f, "failed to spawn GDB thread: {}", e),
             SpawnVcpu(e) => write!(f, "failed to spawn VCPU thread: {}", e),
+            #[cfg(feature = "audio")]
+            StreamSoundDeviceNew(e) => write!(f, "failed to create sound device: {}", e),
             SwiotlbTooLarge => write!(f, "requested swiotlb size too large"),
             Timer(e) => write!(f, "failed to read timer fd: {}", e),
             ValidateRawDescriptor(e) => write!(f, "failed to validate raw descriptor: {}", e),
@@ -305,6 +327,9 @@ impl Display for Error {
                 write!(f, "failed to set up vhost-user console device: {}", e)
             }
             VhostUserFsDeviceNew(e) => write!(f, "failed to set up vhost-user fs device: {}", e),
+            VhostUserGenericDeviceNew(e) => {
+                write!(f, "failed to set up vhost-user generic device: {}", e)
+            }
             VhostUserGpuDeviceNew(e) => write!(f, "failed to set up vhost-user gpu device: {}", e),
             VhostUserMac80211HwsimNew(e) => {
                 write!(f, "failed to set up vhost-user mac80211_hwsim device {}", e)
@@ -324,8 +349,9 @@ impl Display for Error {
             VhostVsockDeviceNew(e) => write!(f, "failed to set up virtual socket device: {}", e),
             VirtioPciDev(e) => write!(f, "failed to create virtio pci dev: {}", e),
             VirtioVhostUserDeviceNew(e) => {
-                write!(f, "failed to set up virtio-vhost-user net device: {}", e)
+                write!(f, "failed to set up virtio-vhost-user device: {}", e)
             }
+            VsockDeviceNew(e) => write!(f, "failed to set up userspace vsock device: {}", e),
             WaitContextAdd(e) => write!(f, "failed to add descriptor to wait context: {}", e),
             WaitContextDelete(e) => {
                 write!(f, "failed to remove descriptor from wait context: {}", e)
@@ -32,12 +32,16 @@ use devices::serial_device::{SerialHardware, SerialParameters};
 use devices::vfio::{VfioCommonSetup, VfioCommonTrait};
 #[cfg(feature = "audio_cras")]
 use devices::virtio::snd::cras_backend::Parameters as CrasSndParameters;
+#[cfg(any(feature = "audio_cras", feature = "audio_pulse"))]
+use devices::virtio::snd::cras_backend::StreamSourceBackend;
+#[cfg(feature = "audio")]
+use devices::virtio::snd::cras_backend::{Parameters as StreamSndParameters, VirtioSndCras};
 #[cfg(feature = "audio")]
 use devices::virtio::vhost::user::vmm::Snd as VhostUserSnd;
 use devices::virtio::vhost::user::vmm::{
     Block as VhostUserBlock, Console as VhostUserConsole, Fs as VhostUserFs,
-    Mac80211Hwsim as VhostUserMac80211Hwsim, Net as VhostUserNet, Vsock as VhostUserVsock,
-    Wl as VhostUserWl,
+    Generic as VhostUserGeneric, Mac80211Hwsim as VhostUserMac80211Hwsim, Net as VhostUserNet,
+    Vsock as VhostUserVsock, Wl as VhostUserWl,
 };
 use devices::virtio::{self, Console, VirtioDevice};
 #[cfg(feature = "gpu")]
@@ -70,7 +74,7 @@ use vm_memory::{GuestAddress, GuestMemory, MemoryPolicy};
 use crate::gdb::{gdb_thread, GdbStub};
 use crate::{
     Config, DiskOption, Executable, SharedDir, SharedDirKind, TouchDeviceOption, VfioType,
-    VhostUserFsOption, VhostUserOption, VhostUserWlOption,
+    VhostUserFsOption, VhostUserGenericOption, VhostUserOption, VhostUserWlOption,
 };
 use arch::{
     self, LinuxArch, RunnableLinuxVm, VcpuAffinity, VirtioDeviceStub, VmComponents, VmImage,
@@ -305,6 +309,23 @@ fn create_vhost_user_fs_device(cfg: &Config, option: &VhostUserFsOption) -> Devi
     })
 }
 
+fn create_vhost_user_generic_device(cfg: &Config, opt: &VhostUserGenericOption) -> DeviceResult {
+    let dev = VhostUserGeneric::new(
+        virtio::base_features(cfg.protected_vm),
+        &opt.socket,
+        opt.device_type,
+        opt.queue_sizes.clone(),
+        opt.config_size,
+    )
+    .map_err(Error::VhostUserGenericDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        // no sandbox here because virtqueue handling is exported to a different process.
+        jail: None,
+    })
+}
+
 fn create_vhost_user_mac80211_hwsim_device(cfg: &Config, opt: &VhostUserOption) -> DeviceResult {
     let dev = VhostUserMac80211Hwsim::new(virtio::base_features(cfg.protected_vm), &opt.socket)
         .map_err(Error::VhostUserMac80211HwsimNew)?;
@@ -339,10 +360,15 @@ fn create_rng_device(cfg: &Config) -> DeviceResult {
 }
 
 #[cfg(feature = "audio_cras")]
-fn create_cras_snd_device(cfg: &Config, cras_snd: CrasSndParameters) -> DeviceResult {
+fn create_cras_snd_device(
+    cfg: &Config,
+    cras_snd: CrasSndParameters,
+    snd_control_tube: Option<Tube>,
+) -> DeviceResult {
     let dev = virtio::snd::cras_backend::VirtioSndCras::new(
         virtio::base_features(cfg.protected_vm),
         cras_snd,
+        snd_control_tube,
     )
     .map_err(Error::CrasSoundDeviceNew)?;
 
@@ -454,6 +480,7 @@ fn create_multi_touch_device(
     cfg: &Config,
     multi_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Option<Tube>,
 ) -> DeviceResult {
     let socket = multi_touch_spec
         .get_path()
@@ -470,6 +497,7 @@ fn create_multi_touch_device(
         width,
         height,
         virtio::base_features(cfg.protected_vm),
+        control_tube,
     )
     .map_err(Error::InputDeviceNew)?;
 
@@ -505,14 +533,24 @@ fn create_trackpad_device(
     })
 }
 
-fn create_mouse_device<T: IntoUnixStream>(cfg: &Config, mouse_socket: T, idx: u32) -> DeviceResult {
+fn create_mouse_device<T: IntoUnixStream>(
+    cfg: &Config,
+    mouse_socket: T,
+    idx: u32,
+    control_tube: Option<Tube>,
+) -> DeviceResult {
     let socket = mouse_socket.into_unix_stream().map_err(|e| {
         error!("failed configuring virtio mouse: {}", e);
         e
     })?;
 
-    let dev = virtio::new_mouse(idx, socket, virtio::base_features(cfg.protected_vm))
-        .map_err(Error::InputDeviceNew)?;
+    let dev = virtio::new_mouse(
+        idx,
+        socket,
+        virtio::base_features(cfg.protected_vm),
+        control_tube,
+    )
+    .map_err(Error::InputDeviceNew)?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -524,13 +562,60 @@ fn create_keyboard_device<T: IntoUnixStream>(
     cfg: &Config,
     keyboard_socket: T,
     idx: u32,
+    control_tube: Option<Tube>,
 ) -> DeviceResult {
     let socket = keyboard_socket.into_unix_stream().map_err(|e| {
         error!("failed configuring virtio keyboard: {}", e);
         e
     })?;
 
-    let dev = virtio::new_keyboard(idx, socket, virtio::base_features(cfg.protected_vm))
+    let dev = virtio::new_keyboard(
+        idx,
+        socket,
+        virtio::base_features(cfg.protected_vm),
+        control_tube,
+    )
+    .map_err(Error::InputDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(cfg, "input_device")?,
+    })
+}
+
+fn create_tablet_device(cfg: &Config, tablet_spec: &TouchDeviceOption, idx: u32) -> DeviceResult {
+    let socket = tablet_spec.get_path().into_unix_stream().map_err(|e| {
+        error!("failed configuring virtio tablet: {:?}", e);
+        e
+    })?;
+
+    let (width, height) = tablet_spec.get_size();
+    let dev = virtio::new_tablet(
+        idx,
+        socket,
+        width,
+        height,
+        virtio::base_features(cfg.protected_vm),
+    )
+    .map_err(Error::InputDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(cfg, "input_device")?,
+    })
+}
+
+fn create_gamepad_device<T: IntoUnixStream>(
+    cfg: &Config,
+    gamepad_socket: T,
+    idx: u32,
+) -> DeviceResult {
+    let socket = gamepad_socket.into_unix_stream().map_err(|e| {
+        error!("failed configuring virtio gamepad: {}", e);
+        e
+    })?;
+
+    let dev = virtio::new_gamepad(idx, socket, virtio::base_features(cfg.protected_vm))
         .map_err(Error::InputDeviceNew)?;
 
     Ok(VirtioDeviceStub {
@@ -714,6 +799,7 @@ fn create_gpu_device(
     cfg: &Config,
     exit_evt: &Event,
     gpu_device_tube: Tube,
+    gpu_control_tube: Option<Tube>,
     resource_bridges: Vec<Tube>,
     wayland_socket_path: Option<&PathBuf>,
     x_display: Option<String>,
@@ -739,12 +825,32 @@ fn create_gpu_device(
         );
     }
 
+    let gpu_parameters = cfg.gpu_parameters.as_ref().unwrap();
+    if gpu_parameters.displays.iter().any(|d| d.vnc.is_some()) {
+        // The listeners are bound here because the device can't reach the network from its jail.
+        let listeners = gpu_parameters
+            .displays
+            .iter()
+            .map(|d| {
+                d.vnc
+                    .map(|addr| {
+                        std::net::TcpListener::bind(addr)
+                            .map(Arc::new)
+                            .map_err(|e| Error::BindVncListener(addr, e))
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(listeners));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt.try_clone().map_err(Error::CloneEvent)?,
         Some(gpu_device_tube),
+        gpu_control_tube,
         resource_bridges,
         display_backends,
-        cfg.gpu_parameters.as_ref().unwrap(),
+        gpu_parameters,
         event_devices,
         map_request,
         cfg.sandbox,
@@ -1004,15 +1110,76 @@ fn create_vhost_vsock_device(cfg: &Config, cid: u64) -> DeviceResult {
     })
 }
 
+fn create_vsock_device(cfg: &Config, cid: u64, uds_path: &Path) -> DeviceResult {
+    let uds_dir = uds_path.parent().ok_or(Error::InvalidVsockPath)?;
+
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::Vsock::new(features, cid, uds_path).map_err(Error::VsockDeviceNew)?;
+
+    let jail = match simple_jail(cfg, "vsock_device")? {
+        Some(mut jail) => {
+            // Create a tmpfs in the device's root directory so that we can bind mount the socket
+            // directory into it. The size=67108864 is size=64*1024*1024 or size=64MB.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                "size=67108864",
+            )?;
+
+            // Guest connections are forwarded by connecting to sockets created next to `uds_path`
+            // by host applications, possibly after the VM started.
+            jail.mount_bind(uds_dir, uds_dir, true)?;
+            add_current_user_to_jail(&mut jail)?;
+
+            Some(jail)
+        }
+        None => None,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
+fn create_vvu_proxy_device(cfg: &Config, opt: &VhostUserOption, tube: Tube) -> DeviceResult {
+    let dev = virtio::vhost::user::VirtioVhostUser::new(
+        virtio::base_features(cfg.protected_vm),
+        &opt.socket,
+        tube,
+    )
+    .map_err(Error::VirtioVhostUserDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        // The listening socket is bound before the device is jailed, so the jail does not need
+        // access to the socket's directory.
+        jail: simple_jail(cfg, "vvu_proxy_device")?,
+    })
+}
+
 fn create_fs_device(
     cfg: &Config,
     uid_map: &str,
     gid_map: &str,
     src: &Path,
     tag: &str,
-    fs_cfg: virtio::fs::passthrough::Config,
+    mut fs_cfg: virtio::fs::passthrough::Config,
+    audit_log: Option<&Path>,
     device_tube: Tube,
 ) -> DeviceResult {
+    // The audit log is opened before entering the jail since it usually lives outside of `src`.
+    if let Some(path) = audit_log {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::OpenFsAuditLog(path.to_path_buf(), e))?;
+        fs_cfg.audit_log = Some(Arc::new(file));
+    }
+
     let max_open_files = base::get_max_open_files().map_err(Error::GetMaxOpenFiles)?;
     let j = if cfg.sandbox {
         let seccomp_policy = cfg.seccomp_policy_dir.join("fs_device");
@@ -1045,6 +1212,101 @@ fn create_fs_device(
     })
 }
 
+fn create_overlay_fs_device(
+    cfg: &Config,
+    uid_map: &str,
+    gid_map: &str,
+    upper: &Path,
+    lowers: &[PathBuf],
+    tag: &str,
+    fs_cfg: virtio::fs::passthrough::Config,
+    device_tube: Tube,
+) -> DeviceResult {
+    let max_open_files = base::get_max_open_files().map_err(Error::GetMaxOpenFiles)?;
+    let j = if cfg.sandbox {
+        let pivot_root: &str = option_env!("DEFAULT_PIVOT_ROOT").unwrap_or("/var/empty");
+        let root_path = Path::new(pivot_root);
+        if !root_path.exists() {
+            return Err(Error::PivotRootDoesntExist(pivot_root));
+        }
+        let seccomp_policy = cfg.seccomp_policy_dir.join("fs_device");
+        let config = SandboxConfig {
+            limit_caps: false,
+            uid_map: Some(uid_map),
+            gid_map: Some(gid_map),
+            log_failures: cfg.seccomp_log_failures,
+            seccomp_policy: &seccomp_policy,
+        };
+        let mut jail = create_base_minijail(root_path, Some(max_open_files), Some(&config))?;
+        // We want bind mounts from the parent namespaces to propagate into the fs device's
+        // namespace.
+        jail.set_remount_mode(libc::MS_SLAVE);
+
+        // Every layer keeps its path inside the jail. Only the upper layer is writable.
+        jail.mount_bind(upper, upper, true)?;
+        for lower in lowers {
+            jail.mount_bind(lower, lower, false)?;
+        }
+
+        jail
+    } else {
+        create_base_minijail(Path::new("/"), Some(max_open_files), None)?
+    };
+
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::fs::Fs::new_overlay(features, tag, 1, fs_cfg, upper, lowers, device_tube)
+        .map_err(Error::FsDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: Some(j),
+    })
+}
+
+fn create_memfs_device(
+    cfg: &Config,
+    uid_map: &str,
+    gid_map: &str,
+    archive: Option<&Path>,
+    writable: bool,
+    tag: &str,
+    fs_cfg: virtio::fs::passthrough::Config,
+    device_tube: Tube,
+) -> DeviceResult {
+    // The archive is opened before entering the jail, which doesn't need access to any host paths.
+    let archive = archive
+        .map(|path| File::open(path).map_err(|e| Error::OpenFsArchive(path.to_path_buf(), e)))
+        .transpose()?;
+
+    let j = if cfg.sandbox {
+        let pivot_root: &str = option_env!("DEFAULT_PIVOT_ROOT").unwrap_or("/var/empty");
+        let root_path = Path::new(pivot_root);
+        if !root_path.exists() {
+            return Err(Error::PivotRootDoesntExist(pivot_root));
+        }
+        let seccomp_policy = cfg.seccomp_policy_dir.join("fs_device");
+        let config = SandboxConfig {
+            limit_caps: true,
+            uid_map: Some(uid_map),
+            gid_map: Some(gid_map),
+            log_failures: cfg.seccomp_log_failures,
+            seccomp_policy: &seccomp_policy,
+        };
+        create_base_minijail(root_path, None, Some(&config))?
+    } else {
+        create_base_minijail(Path::new("/"), None, None)?
+    };
+
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::fs::Fs::new_memfs(features, tag, 1, fs_cfg, archive, writable, device_tube)
+        .map_err(Error::FsDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: Some(j),
+    })
+}
+
 fn create_9p_device(
     cfg: &Config,
     uid_map: &str,
@@ -1255,6 +1517,36 @@ fn create_sound_device(path: &Path, cfg: &Config) -> DeviceResult {
     })
 }
 
+#[cfg(feature = "audio")]
+fn create_stream_sound_device(cfg: &Config, params: &StreamSndParameters) -> DeviceResult {
+    #[cfg(feature = "audio_cras")]
+    {
+        if params.backend == StreamSourceBackend::CRAS {
+            return create_cras_snd_device(cfg, params.clone(), None);
+        }
+    }
+
+    let dev = VirtioSndCras::new(
+        virtio::base_features(cfg.protected_vm),
+        params.clone(),
+        None,
+    )
+    .map_err(Error::StreamSoundDeviceNew)?;
+    #[allow(unused_mut)]
+    let mut jail = simple_jail(cfg, dev.minijail_policy())?;
+    #[cfg(feature = "audio_pulse")]
+    {
+        if let (StreamSourceBackend::PULSE, Some(jail)) = (params.backend, &mut jail) {
+            add_pulse_server_to_jail(jail)?;
+        }
+    }
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 // gpu_device_tube is not used when GPU support is disabled.
 #[cfg_attr(not(feature = "gpu"), allow(unused_variables))]
 fn create_virtio_devices(
@@ -1264,6 +1556,9 @@ fn create_virtio_devices(
     _exit_evt: &Event,
     wayland_device_tube: Tube,
     gpu_device_tube: Tube,
+    #[cfg(feature = "gpu")] gpu_control_tube: Option<Tube>,
+    #[cfg(feature = "audio_cras")] snd_control_tube: Option<Tube>,
+    input_control_tubes: &mut BTreeMap<(InputDeviceKind, usize), Tube>,
     vhost_user_gpu_tubes: Vec<(Tube, Tube)>,
     balloon_device_tube: Tube,
     disk_device_tubes: &mut Vec<Tube>,
@@ -1312,7 +1607,11 @@ fn create_virtio_devices(
     #[cfg(feature = "audio_cras")]
     {
         if let Some(cras_snd) = &cfg.cras_snd {
-            devs.push(create_cras_snd_device(cfg, cras_snd.clone())?);
+            devs.push(create_cras_snd_device(
+                cfg,
+                cras_snd.clone(),
+                snd_control_tube,
+            )?);
         }
     }
 
@@ -1336,6 +1635,7 @@ fn create_virtio_devices(
             cfg,
             multi_touch_spec,
             idx as u32,
+            input_control_tubes.remove(&(InputDeviceKind::MultiTouch, idx)),
         )?);
     }
 
@@ -1344,11 +1644,29 @@ fn create_virtio_devices(
     }
 
     for (idx, mouse_socket) in cfg.virtio_mice.iter().enumerate() {
-        devs.push(create_mouse_device(cfg, mouse_socket, idx as u32)?);
+        devs.push(create_mouse_device(
+            cfg,
+            mouse_socket,
+            idx as u32,
+            input_control_tubes.remove(&(InputDeviceKind::Mouse, idx)),
+        )?);
     }
 
     for (idx, keyboard_socket) in cfg.virtio_keyboard.iter().enumerate() {
-        devs.push(create_keyboard_device(cfg, keyboard_socket, idx as u32)?);
+        devs.push(create_keyboard_device(
+            cfg,
+            keyboard_socket,
+            idx as u32,
+            input_control_tubes.remove(&(InputDeviceKind::Keyboard, idx)),
+        )?);
+    }
+
+    for (idx, tablet_spec) in cfg.virtio_tablets.iter().enumerate() {
+        devs.push(create_tablet_device(cfg, tablet_spec, idx as u32)?);
+    }
+
+    for (idx, gamepad_socket) in cfg.virtio_gamepads.iter().enumerate() {
+        devs.push(create_gamepad_device(cfg, gamepad_socket, idx as u32)?);
     }
 
     for (idx, switches_socket) in cfg.virtio_switches.iter().enumerate() {
@@ -1387,6 +1705,10 @@ fn create_virtio_devices(
         devs.push(create_vhost_user_wl_device(cfg, opt)?);
     }
 
+    for opt in &cfg.vhost_user_generic {
+        devs.push(create_vhost_user_generic_device(cfg, opt)?);
+    }
+
     #[cfg(feature = "gpu")]
     for (opt, (host_tube, device_tube)) in cfg.vhost_user_gpu.iter().zip(vhost_user_gpu_tubes) {
         devs.push(create_vhost_user_gpu_device(
@@ -1466,6 +1788,7 @@ fn create_virtio_devices(
                     multi_touch_width,
                     multi_touch_height,
                     virtio::base_features(cfg.protected_vm),
+                    None,
                 )
                 .map_err(Error::InputDeviceNew)?;
                 devs.push(VirtioDeviceStub {
@@ -1483,6 +1806,7 @@ fn create_virtio_devices(
                     u32::MAX,
                     virtio_dev_socket,
                     virtio::base_features(cfg.protected_vm),
+                    None,
                 )
                 .map_err(Error::InputDeviceNew)?;
                 devs.push(VirtioDeviceStub {
@@ -1495,6 +1819,7 @@ fn create_virtio_devices(
                 cfg,
                 _exit_evt,
                 gpu_device_tube,
+                gpu_control_tube,
                 resource_bridges,
                 // Use the unnamed socket for GPU display screens.
                 cfg.wayland_socket_paths.get(""),
@@ -1530,7 +1855,10 @@ fn create_virtio_devices(
     }
 
     if let Some(cid) = cfg.cid {
-        devs.push(create_vhost_vsock_device(cfg, cid)?);
+        match &cfg.vsock_uds_path {
+            Some(uds_path) => devs.push(create_vsock_device(cfg, cid, uds_path)?),
+            None => devs.push(create_vhost_vsock_device(cfg, cid)?),
+        }
     }
 
     for vhost_user_fs in &cfg.vhost_user_fs {
@@ -1551,18 +1879,85 @@ fn create_virtio_devices(
             gid_map,
             fs_cfg,
             p9_cfg,
+            overlay_upper,
+            overlay_lowers,
+            audit_log,
         } = shared_dir;
 
         let dev = match kind {
+            SharedDirKind::FS if src.is_file() => {
+                let device_tube = fs_device_tubes.remove(0);
+                create_memfs_device(
+                    cfg,
+                    uid_map,
+                    gid_map,
+                    Some(src),
+                    false,
+                    tag,
+                    fs_cfg.clone(),
+                    device_tube,
+                )?
+            }
             SharedDirKind::FS => {
                 let device_tube = fs_device_tubes.remove(0);
-                create_fs_device(cfg, uid_map, gid_map, src, tag, fs_cfg.clone(), device_tube)?
+                create_fs_device(
+                    cfg,
+                    uid_map,
+                    gid_map,
+                    src,
+                    tag,
+                    fs_cfg.clone(),
+                    audit_log.as_deref(),
+                    device_tube,
+                )?
             }
             SharedDirKind::P9 => create_9p_device(cfg, uid_map, gid_map, src, tag, p9_cfg.clone())?,
+            SharedDirKind::Overlay => {
+                let device_tube = fs_device_tubes.remove(0);
+                // The lower layers are ordered from the top-most one down.
+                let lowers: Vec<PathBuf> = overlay_lowers
+                    .iter()
+                    .rev()
+                    .chain(iter::once(src))
+                    .cloned()
+                    .collect();
+                create_overlay_fs_device(
+                    cfg,
+                    uid_map,
+                    gid_map,
+                    overlay_upper
+                        .as_deref()
+                        .expect("overlay shared directory without an upper directory"),
+                    &lowers,
+                    tag,
+                    fs_cfg.clone(),
+                    device_tube,
+                )?
+            }
+            SharedDirKind::Tmpfs => {
+                let device_tube = fs_device_tubes.remove(0);
+                // An empty source path means that the file system starts out empty.
+                let archive = Some(src.as_path()).filter(|src| !src.as_os_str().is_empty());
+                create_memfs_device(
+                    cfg,
+                    uid_map,
+                    gid_map,
+                    archive,
+                    true,
+                    tag,
+                    fs_cfg.clone(),
+                    device_tube,
+                )?
+            }
         };
         devs.push(dev);
     }
 
+    for opt in &cfg.vvu_proxy {
+        let device_tube = fs_device_tubes.remove(0);
+        devs.push(create_vvu_proxy_device(cfg, opt, device_tube)?);
+    }
+
     if let Some(vhost_user_mac80211_hwsim) = &cfg.vhost_user_mac80211_hwsim {
         devs.push(create_vhost_user_mac80211_hwsim_device(
             cfg,
@@ -1575,6 +1970,11 @@ fn create_virtio_devices(
         devs.push(create_sound_device(path, cfg)?);
     }
 
+    #[cfg(feature = "audio")]
+    if let Some(params) = &cfg.stream_sound {
+        devs.push(create_stream_sound_device(cfg, params)?);
+    }
+
     Ok(devs)
 }
 
@@ -1659,6 +2059,9 @@ fn create_devices(
     control_tubes: &mut Vec<TaggedControlTube>,
     wayland_device_tube: Tube,
     gpu_device_tube: Tube,
+    #[cfg(feature = "gpu")] gpu_control_tube: Option<Tube>,
+    #[cfg(feature = "audio_cras")] snd_control_tube: Option<Tube>,
+    input_control_tubes: &mut BTreeMap<(InputDeviceKind, usize), Tube>,
     vhost_user_gpu_tubes: Vec<(Tube, Tube)>,
     balloon_device_tube: Tube,
     disk_device_tubes: &mut Vec<Tube>,
@@ -1674,6 +2077,11 @@ fn create_devices(
         exit_evt,
         wayland_device_tube,
         gpu_device_tube,
+        #[cfg(feature = "gpu")]
+        gpu_control_tube,
+        #[cfg(feature = "audio_cras")]
+        snd_control_tube,
+        input_control_tubes,
         vhost_user_gpu_tubes,
         balloon_device_tube,
         disk_device_tubes,
@@ -1697,7 +2105,14 @@ fn create_devices(
     for ac97_param in &cfg.ac97_parameters {
         let dev = Ac97Dev::try_new(vm.get_memory().clone(), ac97_param.clone())
             .map_err(Error::CreateAc97)?;
-        let jail = simple_jail(cfg, dev.minijail_policy())?;
+        #[allow(unused_mut)]
+        let mut jail = simple_jail(cfg, dev.minijail_policy())?;
+        #[cfg(feature = "audio_pulse")]
+        {
+            if let (devices::Ac97Backend::PULSE, Some(jail)) = (&ac97_param.backend, &mut jail) {
+                add_pulse_server_to_jail(jail)?;
+            }
+        }
         devices.push((Box::new(dev), jail));
     }
 
@@ -1782,6 +2197,32 @@ struct Ids {
     gid: gid_t,
 }
 
+// The PulseAudio client library connects to the server socket from inside the jail, so the
+// server's runtime directory is bind mounted in and the jail runs as the current user.
+#[cfg(feature = "audio_pulse")]
+fn add_pulse_server_to_jail(jail: &mut Minijail) -> Result<()> {
+    let runtime_dir = match env::var_os("PULSE_RUNTIME_PATH") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => Path::new(&dir).join("pulse"),
+            None => PathBuf::from(format!("/run/user/{}/pulse", geteuid())),
+        },
+    };
+
+    // Create a tmpfs in the device's root directory to hold the mount point.
+    // The size is 20*1024, or 20 KB.
+    jail.mount_with_data(
+        Path::new("none"),
+        Path::new("/"),
+        "tmpfs",
+        (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+        "size=20480",
+    )?;
+    jail.mount_bind(&runtime_dir, &runtime_dir, true)?;
+    add_current_user_to_jail(jail)?;
+    Ok(())
+}
+
 // Set the uid/gid for the jailed process and give a basic id map. This is
 // required for bind mounts to work.
 fn add_current_user_to_jail(jail: &mut Minijail) -> Result<Ids> {
@@ -2444,6 +2885,17 @@ pub fn run_config(cfg: Config) -> Result<()> {
     run_vm::<KvmVcpu, KvmVm>(cfg, components, vm, irq_chip.as_mut(), ioapic_host_tube)
 }
 
+// Sets the timeouts of the host end of a tube that forwards commands from the control socket to a
+// device, which only answers them while it is active.
+fn set_device_control_timeouts(host_tube: &Tube) -> Result<()> {
+    host_tube
+        .set_send_timeout(Some(DEVICE_CONTROL_TIMEOUT))
+        .map_err(Error::CreateTube)?;
+    host_tube
+        .set_recv_timeout(Some(DEVICE_CONTROL_TIMEOUT))
+        .map_err(Error::CreateTube)
+}
+
 fn run_vm<Vcpu, V>(
     cfg: Config,
     #[allow(unused_mut)] mut components: VmComponents,
@@ -2535,6 +2987,43 @@ where
     let (gpu_host_tube, gpu_device_tube) = Tube::pair().map_err(Error::CreateTube)?;
     control_tubes.push(TaggedControlTube::VmMemory(gpu_host_tube));
 
+    // Screenshot and recording requests are forwarded to the GPU device over this tube.
+    #[cfg(feature = "gpu")]
+    let (gpu_control_host_tube, gpu_control_device_tube) = if cfg.gpu_parameters.is_some() {
+        let (host_tube, device_tube) = Tube::pair().map_err(Error::CreateTube)?;
+        set_device_control_timeouts(&host_tube)?;
+        (Some(host_tube), Some(device_tube))
+    } else {
+        (None, None)
+    };
+
+    // Jack hotplug requests are forwarded to the sound device over this tube.
+    #[cfg(feature = "audio_cras")]
+    let (snd_control_host_tube, snd_control_device_tube) = if cfg.cras_snd.is_some() {
+        let (host_tube, device_tube) = Tube::pair().map_err(Error::CreateTube)?;
+        set_device_control_timeouts(&host_tube)?;
+        (Some(host_tube), Some(device_tube))
+    } else {
+        (None, None)
+    };
+
+    // Event injection and recording requests are forwarded to the socket-backed input devices
+    // over these tubes.
+    let mut input_control_host_tubes = BTreeMap::new();
+    let mut input_control_device_tubes = BTreeMap::new();
+    for &(kind, count) in &[
+        (InputDeviceKind::Keyboard, cfg.virtio_keyboard.len()),
+        (InputDeviceKind::Mouse, cfg.virtio_mice.len()),
+        (InputDeviceKind::MultiTouch, cfg.virtio_multi_touch.len()),
+    ] {
+        for index in 0..count {
+            let (host_tube, device_tube) = Tube::pair().map_err(Error::CreateTube)?;
+            set_device_control_timeouts(&host_tube)?;
+            input_control_host_tubes.insert((kind, index), host_tube);
+            input_control_device_tubes.insert((kind, index), device_tube);
+        }
+    }
+
     if let Some(ioapic_host_tube) = ioapic_host_tube {
         control_tubes.push(TaggedControlTube::VmIrq(ioapic_host_tube));
     }
@@ -2572,11 +3061,14 @@ where
 
     let map_request: Arc<Mutex<Option<ExternalMapping>>> = Arc::new(Mutex::new(None));
 
+    // virtio-vhost-user devices map the frontend's memory into their BAR the same way virtio-fs
+    // devices map files for DAX, so both use a `TaggedControlTube::Fs`.
     let fs_count = cfg
         .shared_dirs
         .iter()
-        .filter(|sd| sd.kind == SharedDirKind::FS)
-        .count();
+        .filter(|sd| sd.kind != SharedDirKind::P9)
+        .count()
+        + cfg.vvu_proxy.len();
     let mut fs_device_tubes = Vec::with_capacity(fs_count);
     for _ in 0..fs_count {
         let (fs_host_tube, fs_device_tube) = Tube::pair().map_err(Error::CreateTube)?;
@@ -2606,6 +3098,11 @@ where
         &mut control_tubes,
         wayland_device_tube,
         gpu_device_tube,
+        #[cfg(feature = "gpu")]
+        gpu_control_device_tube,
+        #[cfg(feature = "audio_cras")]
+        snd_control_device_tube,
+        &mut input_control_device_tubes,
         vhost_user_gpu_tubes,
         balloon_device_tube,
         &mut disk_device_tubes,
@@ -2719,6 +3216,11 @@ where
         &disk_host_tubes,
         #[cfg(feature = "usb")]
         usb_control_tube,
+        #[cfg(feature = "gpu")]
+        gpu_control_host_tube,
+        #[cfg(feature = "audio_cras")]
+        snd_control_host_tube,
+        input_control_host_tubes,
         exit_evt,
         sigchld_fd,
         cfg.sandbox,
@@ -2814,6 +3316,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     balloon_host_tube: Tube,
     disk_host_tubes: &[Tube],
     #[cfg(feature = "usb")] usb_control_tube: Tube,
+    #[cfg(feature = "gpu")] gpu_control_tube: Option<Tube>,
+    #[cfg(feature = "audio_cras")] snd_control_tube: Option<Tube>,
+    input_control_tubes: BTreeMap<(InputDeviceKind, usize), Tube>,
     exit_evt: Event,
     sigchld_fd: SignalFd,
     sandbox: bool,
@@ -3041,6 +3546,15 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                         Some(&usb_control_tube),
                                         #[cfg(not(feature = "usb"))]
                                         None,
+                                        #[cfg(feature = "gpu")]
+                                        gpu_control_tube.as_ref(),
+                                        #[cfg(not(feature = "gpu"))]
+                                        None,
+                                        #[cfg(feature = "audio_cras")]
+                                        snd_control_tube.as_ref(),
+                                        #[cfg(not(feature = "audio_cras"))]
+                                        None,
+                                        &input_control_tubes,
                                         &mut linux.bat_control,
                                         &vcpu_handles,
                                     );
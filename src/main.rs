@@ -11,11 +11,12 @@ use std::convert::TryFrom;
 use std::default::Default;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::String;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use arch::{set_default_serial_parameters, Pstore, VcpuAffinity};
 use base::{debug, error, getpid, info, kill_process_group, reap_child, syslog, warn};
@@ -23,12 +24,16 @@ use base::{debug, error, getpid, info, kill_process_group, reap_child, syslog, w
 use crosvm::DirectIoOption;
 use crosvm::{
     argument::{self, print_help, set_arguments, Argument},
-    platform, BindMount, Config, DiskOption, Executable, GidMap, SharedDir, TouchDeviceOption,
-    VfioCommand, VhostUserFsOption, VhostUserOption, VhostUserWlOption, DISK_ID_LEN,
+    platform, BindMount, Config, DiskOption, Executable, GidMap, SharedDir, SharedDirKind,
+    TouchDeviceOption, VfioCommand, VhostUserFsOption, VhostUserGenericOption, VhostUserOption,
+    VhostUserWlOption, DISK_ID_LEN,
 };
 use devices::serial_device::{SerialHardware, SerialParameters, SerialType};
+use devices::virtio::fs::{diskfs::DiskFs, memfs::MemFs, passthrough, passthrough::PassthroughFs};
 #[cfg(feature = "audio_cras")]
 use devices::virtio::snd::cras_backend::Error as CrasSndError;
+#[cfg(feature = "audio")]
+use devices::virtio::snd::cras_backend::Error as StreamSndError;
 use devices::virtio::vhost::user::device::{
     run_block_device, run_console_device, run_fs_device, run_net_device, run_vsock_device,
     run_wl_device,
@@ -36,7 +41,8 @@ use devices::virtio::vhost::user::device::{
 #[cfg(feature = "gpu")]
 use devices::virtio::{
     gpu::{
-        GpuDisplayParameters, GpuMode, GpuParameters, DEFAULT_DISPLAY_HEIGHT, DEFAULT_DISPLAY_WIDTH,
+        GpuDisplayParameters, GpuMode, GpuParameters, DEFAULT_DISPLAY_HEIGHT,
+        DEFAULT_DISPLAY_WIDTH, VIRTIO_GPU_MAX_SCANOUTS,
     },
     vhost::user::device::run_gpu_device,
 };
@@ -48,13 +54,19 @@ use disk::{self, QcowFile};
 use disk::{
     create_composite_disk, create_disk_file, create_zero_filler, ImagePartitionType, PartitionInfo,
 };
+use fuse::filesystem::FileSystem;
+use fuse::mount::MountOption;
+use linux_input_sys::virtio_input_event;
 use vm_control::{
     client::{
-        do_modify_battery, do_usb_attach, do_usb_detach, do_usb_list, handle_request, vms_request,
-        ModifyUsbError, ModifyUsbResult,
+        do_gpu_command, do_input_command, do_modify_battery, do_snd_command, do_usb_attach,
+        do_usb_attach_emulated, do_usb_attach_usbip, do_usb_detach, do_usb_list, handle_request,
+        vms_request, ModifyUsbError, ModifyUsbResult,
     },
-    BalloonControlCommand, BatteryType, DiskControlCommand, UsbControlResult, VmRequest,
-    VmResponse,
+    BalloonControlCommand, BatteryType, DiskControlCommand, GpuControlCommand, GpuControlResult,
+    GpuRecordFormat, InputControlCommand, InputControlResult, InputDeviceKind, InputEvent,
+    InputRecordEntry, SndControlCommand, SndControlResult, UsbControlResult, UsbEmulatedDeviceKind,
+    VmRequest, VmResponse,
 };
 
 fn executable_is_plugin(executable: &Option<Executable>) -> bool {
@@ -310,6 +322,20 @@ fn parse_gpu_options(s: Option<&str>, gpu_params: &mut GpuParameters) -> argumen
                         });
                     }
                 },
+                "software" => match v {
+                    "true" | "" => {
+                        gpu_params.renderer_use_software = true;
+                    }
+                    "false" => {
+                        gpu_params.renderer_use_software = false;
+                    }
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: v.to_string(),
+                            expected: String::from("gpu parameter 'software' should be a boolean"),
+                        });
+                    }
+                },
                 #[cfg(feature = "gfxstream")]
                 "syncfd" => {
                     syncfd_specified = true;
@@ -390,6 +416,20 @@ fn parse_gpu_options(s: Option<&str>, gpu_params: &mut GpuParameters) -> argumen
                         })?;
                     display_h = Some(height);
                 }
+                "max-displays" => {
+                    let max_displays = v
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|n| (1..=VIRTIO_GPU_MAX_SCANOUTS as u32).contains(n))
+                        .ok_or_else(|| argument::Error::InvalidValue {
+                            value: v.to_string(),
+                            expected: format!(
+                                "gpu parameter 'max-displays' must be between 1 and {}",
+                                VIRTIO_GPU_MAX_SCANOUTS
+                            ),
+                        })?;
+                    gpu_params.max_displays = Some(max_displays);
+                }
                 "cache-path" => gpu_params.cache_path = Some(v.to_string()),
                 "cache-size" => gpu_params.cache_size = Some(v.to_string()),
                 "udmabuf" => match v {
@@ -430,6 +470,7 @@ fn parse_gpu_options(s: Option<&str>, gpu_params: &mut GpuParameters) -> argumen
         gpu_params.displays.push(GpuDisplayParameters {
             width: display_w.unwrap(),
             height: display_h.unwrap(),
+            vnc: None,
         });
     }
 
@@ -462,6 +503,7 @@ fn parse_gpu_display_options(
 ) -> argument::Result<()> {
     let mut display_w: Option<u32> = None;
     let mut display_h: Option<u32> = None;
+    let mut vnc: Option<std::net::SocketAddr> = None;
 
     if let Some(s) = s {
         let opts = s
@@ -491,6 +533,13 @@ fn parse_gpu_display_options(
                         })?;
                     display_h = Some(height);
                 }
+                "vnc" => {
+                    let addr = v.parse().map_err(|_| argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: String::from("gpu-display parameter 'vnc' must be ADDR:PORT"),
+                    })?;
+                    vnc = Some(addr);
+                }
                 "" => {}
                 _ => {
                     return Err(argument::Error::UnknownArgument(format!(
@@ -512,6 +561,7 @@ fn parse_gpu_display_options(
     gpu_params.displays.push(GpuDisplayParameters {
         width: display_w.unwrap(),
         height: display_h.unwrap(),
+        vnc,
     });
 
     Ok(())
@@ -569,6 +619,12 @@ fn parse_ac97_options(s: &str) -> argument::Result<Ac97Parameters> {
                         })?,
                     );
             }
+            "playback_file" => {
+                ac97_params.playback_file = Some(PathBuf::from(v));
+            }
+            "capture_file" => {
+                ac97_params.capture_file = Some(PathBuf::from(v));
+            }
             _ => {
                 return Err(argument::Error::UnknownArgument(format!(
                     "unknown ac97 parameter {}",
@@ -597,9 +653,77 @@ fn parse_ac97_options(s: &str) -> argument::Result<Ac97Parameters> {
         }
     }
 
+    // playback_file is required for and, like capture_file, exclusive to wav backend
+    match ac97_params.backend {
+        Ac97Backend::WAV => {
+            if ac97_params.playback_file.is_none() {
+                return Err(argument::Error::ExpectedArgument(String::from(
+                    "playback_file argument is required for WAV backend",
+                )));
+            }
+        }
+        _ => {
+            if ac97_params.playback_file.is_some() || ac97_params.capture_file.is_some() {
+                return Err(argument::Error::UnexpectedValue(String::from(
+                    "playback_file and capture_file arguments are exclusive to the WAV backend",
+                )));
+            }
+        }
+    }
+
     Ok(ac97_params)
 }
 
+#[cfg(feature = "audio")]
+fn parse_sound_options(s: &str, cfg: &mut Config) -> argument::Result<()> {
+    // A plain path is the socket of a VioS server.
+    if !s.contains('=') {
+        cfg.sound = Some(PathBuf::from(s));
+        cfg.stream_sound = None;
+        return Ok(());
+    }
+
+    let opts: Vec<(&str, &str)> = s
+        .split(',')
+        .map(|frag| frag.split('='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")))
+        .collect();
+
+    // server is required for and exclusive to vios backend, the other backends create their
+    // streams from a StreamSource.
+    if opts.contains(&("backend", "vios")) {
+        let mut server = None;
+        for (k, v) in opts {
+            match k {
+                "backend" => {}
+                "server" => server = Some(PathBuf::from(v)),
+                _ => {
+                    return Err(argument::Error::UnknownArgument(format!(
+                        "unknown vios sound parameter {}",
+                        k
+                    )))
+                }
+            }
+        }
+        cfg.sound = Some(server.ok_or_else(|| {
+            argument::Error::ExpectedArgument(String::from(
+                "server argument is required for VIOS backend",
+            ))
+        })?);
+        cfg.stream_sound = None;
+    } else {
+        let params = s
+            .parse()
+            .map_err(|e: StreamSndError| argument::Error::InvalidValue {
+                value: s.to_string(),
+                expected: e.to_string(),
+            })?;
+        cfg.stream_sound = Some(params);
+        cfg.sound = None;
+    }
+    Ok(())
+}
+
 fn parse_serial_options(s: &str) -> argument::Result<SerialParameters> {
     let mut serial_setting = SerialParameters {
         type_: SerialType::Sink,
@@ -923,6 +1047,94 @@ fn parse_stub_pci_parameters(s: Option<&str>) -> argument::Result<StubPciParamet
     Ok(params)
 }
 
+fn parse_vhost_user_generic_options(s: Option<&str>) -> argument::Result<VhostUserGenericOption> {
+    let s = s.ok_or(argument::Error::ExpectedValue(String::from(
+        "vhost-user-generic configuration expected",
+    )))?;
+
+    let mut options = argument::parse_key_value_options("vhost-user-generic", s, ',');
+    let socket = options
+        .next()
+        .map(|opt| PathBuf::from(opt.key()))
+        .filter(|socket| !socket.as_os_str().is_empty())
+        .ok_or(argument::Error::ExpectedValue(String::from(
+            "vhost-user-generic: expected socket path",
+        )))?;
+
+    let mut device_type = None;
+    let mut queues = None;
+    let mut queue_size = 256;
+    let mut queue_sizes = None;
+    let mut config_size = 0;
+    for opt in options {
+        match opt.key() {
+            "type" => {
+                let t = opt.parse_numeric::<u32>()?;
+                if t == 0 {
+                    return Err(opt.invalid_value_err(String::from("device ID must be non-zero")));
+                }
+                device_type = Some(t);
+            }
+            "queues" => {
+                let n = opt.parse_numeric::<usize>()?;
+                if n == 0 {
+                    return Err(opt.invalid_value_err(String::from("at least one queue required")));
+                }
+                queues = Some(n);
+            }
+            "queue_size" => queue_size = parse_queue_size(&opt, opt.value()?)?,
+            "queue_sizes" => {
+                queue_sizes = Some(
+                    opt.value()?
+                        .split(':')
+                        .map(|size| parse_queue_size(&opt, size))
+                        .collect::<argument::Result<Vec<u16>>>()?,
+                );
+            }
+            "config_size" => config_size = opt.parse_numeric::<u64>()?,
+            _ => return Err(opt.invalid_key_err()),
+        }
+    }
+
+    let device_type = device_type.ok_or(argument::Error::ExpectedValue(String::from(
+        "vhost-user-generic: `type` is required",
+    )))?;
+    let queue_sizes = match (queue_sizes, queues) {
+        (Some(sizes), None) => sizes,
+        (Some(sizes), Some(n)) if sizes.len() == n => sizes,
+        (Some(_), Some(_)) => {
+            return Err(argument::Error::InvalidValue {
+                value: s.to_owned(),
+                expected: String::from(
+                    "vhost-user-generic: `queues` does not match the length of `queue_sizes`",
+                ),
+            })
+        }
+        (None, Some(n)) => vec![queue_size; n],
+        (None, None) => {
+            return Err(argument::Error::ExpectedValue(String::from(
+                "vhost-user-generic: `queues` or `queue_sizes` is required",
+            )))
+        }
+    };
+
+    Ok(VhostUserGenericOption {
+        socket,
+        device_type,
+        queue_sizes,
+        config_size,
+    })
+}
+
+fn parse_queue_size(opt: &argument::KeyValuePair, size: &str) -> argument::Result<u16> {
+    match size.parse::<u16>() {
+        Ok(size) if size.is_power_of_two() => Ok(size),
+        _ => Err(opt.invalid_value_err(String::from(
+            "queue size must be a power of two between 1 and 32768",
+        ))),
+    }
+}
+
 fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
     match name {
         "" => {
@@ -1104,8 +1316,7 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         }
         #[cfg(feature = "audio")]
         "sound" => {
-            let client_path = PathBuf::from(value.unwrap());
-            cfg.sound = Some(client_path);
+            parse_sound_options(value.unwrap(), cfg)?;
         }
         "serial" => {
             let serial_params = parse_serial_options(value.unwrap())?;
@@ -1501,15 +1712,27 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     })?,
             );
         }
+        "vsock-uds" => {
+            if cfg.vsock_uds_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`vsock-uds` already given".to_owned(),
+                ));
+            }
+            cfg.vsock_uds_path = Some(PathBuf::from(value.unwrap()));
+        }
         "shared-dir" => {
             // This is formatted as multiple fields, each separated by ":". The first 2 fields are
             // fixed (src:tag).  The rest may appear in any order:
             //
-            // * type=TYPE - must be one of "p9" or "fs" (default: p9)
+            // * type=TYPE - must be one of "p9", "fs", "overlay" or "tmpfs" (default: p9)
             // * uidmap=UIDMAP - a uid map in the format "inner outer count[,inner outer count]"
             //   (default: "0 <current euid> 1")
             // * gidmap=GIDMAP - a gid map in the same format as uidmap
             //   (default: "0 <current egid> 1")
+            // * fs_uidmap=UIDMAP - a uid map in the format "guest host count[,guest host count]"
+            //   that the virtio-fs server applies to file ownership and guest credentials
+            // * fs_gidmap=GIDMAP - a gid map in the same format as fs_uidmap
+            // * audit_log=PATH - a file to which every mutating virtio-fs request is appended
             // * privileged_quota_uids=UIDS - Space-separated list of privileged uid values. When
             //   performing quota-related operations, these UIDs are treated as if they have
             //   CAP_FOWNER.
@@ -1517,6 +1740,9 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             //   and directory contents should be considered valid (default: 5)
             // * cache=CACHE - one of "never", "always", or "auto" (default: auto)
             // * writeback=BOOL - indicates whether writeback caching should be enabled (default: false)
+            // * upper=DIR - the writable directory layered over `src` when the type is "overlay"
+            // * lower=DIR - an additional read-only directory stacked on top of `src` and any
+            //   previous `lower` directories when the type is "overlay"
             let param = value.unwrap();
             let mut components = param.split(':');
             let src =
@@ -1536,13 +1762,6 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 })?
                 .to_owned();
 
-            if !src.is_dir() {
-                return Err(argument::Error::InvalidValue {
-                    value: param.to_owned(),
-                    expected: String::from("source path for `shared-dir` must be a directory"),
-                });
-            }
-
             let mut shared_dir = SharedDir {
                 src,
                 tag,
@@ -1564,11 +1783,25 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                         shared_dir.kind =
                             value.parse().map_err(|_| argument::Error::InvalidValue {
                                 value: value.to_owned(),
-                                expected: String::from("`type` must be one of `fs` or `9p`"),
+                                expected: String::from(
+                                    "`type` must be one of `fs`, `9p`, `overlay` or `tmpfs`",
+                                ),
                             })?
                     }
                     "uidmap" => shared_dir.uid_map = value.into(),
                     "gidmap" => shared_dir.gid_map = value.into(),
+                    "fs_uidmap" | "fs_gidmap" => {
+                        let map = value.parse().map_err(|e| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: format!("`{}` is invalid: {}", kind, e),
+                        })?;
+                        if kind == "fs_uidmap" {
+                            shared_dir.fs_cfg.uid_map = map;
+                        } else {
+                            shared_dir.fs_cfg.gid_map = map;
+                        }
+                    }
+                    "audit_log" => shared_dir.audit_log = Some(PathBuf::from(value)),
                     #[cfg(feature = "chromeos")]
                     "privileged_quota_uids" => {
                         shared_dir.fs_cfg.privileged_quota_uids =
@@ -1627,6 +1860,32 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                         })?;
                         shared_dir.fs_cfg.use_dax = use_dax;
                     }
+                    "dax_window_size" => {
+                        let size: u64 =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`dax_window_size` must be an integer"),
+                            })?;
+                        // Each DAX mapping covers a 2 MiB range and PCI BARs must be a power of
+                        // two in size.
+                        if size < 2 << 20 || !size.is_power_of_two() {
+                            return Err(argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from(
+                                    "`dax_window_size` must be a power of two of at least 2 MiB",
+                                ),
+                            });
+                        }
+                        shared_dir.fs_cfg.dax_window_size = size;
+                    }
+                    "dax_reclaim" => {
+                        let dax_reclaim =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`dax_reclaim` must be a boolean"),
+                            })?;
+                        shared_dir.fs_cfg.dax_reclaim = dax_reclaim;
+                    }
                     "posix_acl" => {
                         let posix_acl =
                             value.parse().map_err(|_| argument::Error::InvalidValue {
@@ -1635,6 +1894,20 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                             })?;
                         shared_dir.fs_cfg.posix_acl = posix_acl;
                     }
+                    "upper" | "lower" => {
+                        let dir = PathBuf::from(value);
+                        if !dir.is_dir() {
+                            return Err(argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: format!("`{}` must be a directory", kind),
+                            });
+                        }
+                        if kind == "upper" {
+                            shared_dir.overlay_upper = Some(dir);
+                        } else {
+                            shared_dir.overlay_lowers.push(dir);
+                        }
+                    }
                     _ => {
                         return Err(argument::Error::InvalidValue {
                             value: kind.to_owned(),
@@ -1643,6 +1916,64 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     }
                 }
             }
+            // A virtio-fs device can also serve a tar archive, and a tmpfs starts out either empty
+            // or with the contents of an archive.
+            let src = &shared_dir.src;
+            let src_valid = match shared_dir.kind {
+                SharedDirKind::FS => src.is_dir() || src.is_file(),
+                SharedDirKind::Tmpfs => src.as_os_str().is_empty() || src.is_file(),
+                _ => src.is_dir(),
+            };
+            if !src_valid {
+                let expected = match shared_dir.kind {
+                    SharedDirKind::FS => "source path for `shared-dir` must be a directory or file",
+                    SharedDirKind::Tmpfs => "source path for `type=tmpfs` must be empty or a file",
+                    _ => "source path for `shared-dir` must be a directory",
+                };
+                return Err(argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from(expected),
+                });
+            }
+            if shared_dir.fs_cfg.use_dax
+                && (shared_dir.kind == SharedDirKind::Tmpfs || src.is_file())
+            {
+                return Err(argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from("`dax` is not supported with archives or `type=tmpfs`"),
+                });
+            }
+            if shared_dir.kind == SharedDirKind::Overlay {
+                if shared_dir.overlay_upper.is_none() {
+                    return Err(argument::Error::InvalidValue {
+                        value: param.to_owned(),
+                        expected: String::from("`type=overlay` requires an `upper` directory"),
+                    });
+                }
+                if shared_dir.fs_cfg.use_dax {
+                    return Err(argument::Error::InvalidValue {
+                        value: param.to_owned(),
+                        expected: String::from("`dax` is not supported with `type=overlay`"),
+                    });
+                }
+            } else if shared_dir.overlay_upper.is_some() || !shared_dir.overlay_lowers.is_empty() {
+                return Err(argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from("`upper` and `lower` require `type=overlay`"),
+                });
+            }
+            if (!shared_dir.fs_cfg.uid_map.is_empty()
+                || !shared_dir.fs_cfg.gid_map.is_empty()
+                || shared_dir.audit_log.is_some())
+                && (shared_dir.kind != SharedDirKind::FS || !src.is_dir())
+            {
+                return Err(argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from(
+                        "`fs_uidmap`, `fs_gidmap` and `audit_log` require `type=fs` and a directory",
+                    ),
+                });
+            }
             cfg.shared_dirs.push(shared_dir);
         }
         "seccomp-policy-dir" => {
@@ -1800,6 +2131,23 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             cfg.virtio_mice
                 .push(PathBuf::from(value.unwrap().to_owned()));
         }
+        "tablet" => {
+            let mut it = value.unwrap().split(':');
+
+            let mut tablet_spec =
+                TouchDeviceOption::new(PathBuf::from(it.next().unwrap().to_owned()));
+            if let Some(width) = it.next() {
+                tablet_spec.set_width(width.trim().parse().unwrap());
+            }
+            if let Some(height) = it.next() {
+                tablet_spec.set_height(height.trim().parse().unwrap());
+            }
+            cfg.virtio_tablets.push(tablet_spec);
+        }
+        "gamepad" => {
+            cfg.virtio_gamepads
+                .push(PathBuf::from(value.unwrap().to_owned()));
+        }
         "keyboard" => {
             cfg.virtio_keyboard
                 .push(PathBuf::from(value.unwrap().to_owned()));
@@ -1932,6 +2280,12 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             cfg.vhost_user_wl
                 .push(VhostUserWlOption { socket, vm_tube });
         }
+        "vvu-proxy" => cfg.vvu_proxy.push(VhostUserOption {
+            socket: PathBuf::from(value.unwrap()),
+        }),
+        "vhost-user-generic" => cfg
+            .vhost_user_generic
+            .push(parse_vhost_user_generic_options(value)?),
         "vhost-user-fs" => {
             // (socket:tag)
             let param = value.unwrap();
@@ -2060,6 +2414,11 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
             ));
         }
     }
+    if cfg.vsock_uds_path.is_some() && cfg.cid.is_none() {
+        return Err(argument::Error::ExpectedArgument(
+            "`vsock-uds` requires `cid`".to_owned(),
+        ));
+    }
     if cfg.plugin_root.is_some() && !executable_is_plugin(&cfg.executable_path) {
         return Err(argument::Error::ExpectedArgument(
             "`plugin-root` requires `plugin`".to_owned(),
@@ -2072,9 +2431,22 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
                 gpu_parameters.displays.push(GpuDisplayParameters {
                     width: DEFAULT_DISPLAY_WIDTH,
                     height: DEFAULT_DISPLAY_HEIGHT,
+                    vnc: None,
                 });
             }
 
+            if let Some(max_displays) = gpu_parameters.max_displays {
+                if (max_displays as usize) < gpu_parameters.displays.len() {
+                    return Err(argument::Error::InvalidValue {
+                        value: max_displays.to_string(),
+                        expected: format!(
+                            "gpu parameter 'max-displays' is less than the {} displays",
+                            gpu_parameters.displays.len()
+                        ),
+                    });
+                }
+            }
+
             let width = gpu_parameters.displays[0].width;
             let height = gpu_parameters.displays[0].height;
 
@@ -2084,6 +2456,9 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
             if let Some(virtio_single_touch) = cfg.virtio_single_touch.first_mut() {
                 virtio_single_touch.set_default_size(width, height);
             }
+            if let Some(virtio_tablet) = cfg.virtio_tablets.first_mut() {
+                virtio_tablet.set_default_size(width, height);
+            }
         }
     }
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
@@ -2193,18 +2568,31 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
           Argument::value("net-vq-pairs", "N", "virtio net virtual queue paris. (default: 1)"),
           #[cfg(feature = "audio")]
           Argument::value("ac97",
-                          "[backend=BACKEND,capture=true,capture_effect=EFFECT,client_type=TYPE,shm-fd=FD,client-fd=FD,server-fd=FD]",
+                          "[backend=BACKEND,capture=true,capture_effect=EFFECT,client_type=TYPE,shm-fd=FD,client-fd=FD,server-fd=FD,playback_file=PATH,capture_file=PATH]",
                           "Comma separated key=value pairs for setting up Ac97 devices. Can be given more than once .
                               Possible key values:
-                              backend=(null, cras, vios) - Where to route the audio device. If not provided, backend will default to null.
-                              `null` for /dev/null, cras for CRAS server and vios for VioS server.
+                              backend=(null, cras, vios, wav, pulse) - Where to route the audio device. If not provided, backend will default to null.
+                              `null` for /dev/null, cras for CRAS server, vios for VioS server, wav for WAV files and pulse for a PulseAudio (or pipewire-pulse) server.
                               capture - Enable audio capture
                               capture_effects - | separated effects to be enabled for recording. The only supported effect value now is EchoCancellation or aec.
                               client_type - Set specific client type for cras backend.
                               socket_type - Set specific socket type for cras backend.
-                              server - The to the VIOS server (unix socket)."),
+                              server - The to the VIOS server (unix socket).
+                              playback_file - WAV file to record playback into for wav backend.
+                              capture_file - WAV file to play as capture for wav backend. Its format must match the captured stream."),
           #[cfg(feature = "audio")]
-          Argument::value("sound", "[PATH]", "Path to the VioS server socket for setting up virtio-snd devices."),
+          Argument::value("sound",
+                          "[PATH|backend=BACKEND,capture=true,server=PATH,playback_file=PATH,capture_file=PATH,client_type=TYPE,socket_type=TYPE]",
+                          "Path to the VioS server socket, or comma separated key=value pairs, for setting up a virtio-snd device.
+                              Possible key values:
+                              backend=(vios, null, cras, wav, pulse) - Where to route the audio device. If not provided, backend will default to cras when built with it and to null otherwise.
+                              `null` for /dev/null, cras for CRAS server, vios for VioS server, wav for WAV files and pulse for a PulseAudio (or pipewire-pulse) server.
+                              capture - Enable audio capture. Default is true.
+                              server - The path to the VIOS server (unix socket) for vios backend.
+                              playback_file - WAV file to record playback into for wav backend.
+                              capture_file - WAV file to play as capture for wav backend, which has no capture without one. Its format must match the captured stream.
+                              client_type - Set specific client type for cras backend.
+                              socket_type - Set specific socket type for cras backend."),
           Argument::value("serial",
                           "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin]",
                           "Comma separated key=value pairs for setting up serial devices. Can be given more than once.
@@ -2231,18 +2619,26 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                                 "Path to put the control socket. If PATH is a directory, a name will be generated."),
           Argument::flag("disable-sandbox", "Run all devices in one, non-sandboxed process."),
           Argument::value("cid", "CID", "Context ID for virtual sockets."),
-          Argument::value("shared-dir", "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE:dax=BOOL,posix_acl=BOOL]",
+          Argument::value("vsock-uds", "PATH", "Use a userspace virtio-vsock device instead of vhost-vsock. Host applications connect to the guest through the Unix domain socket at PATH by sending \"CONNECT <port>\\n\", and guest connections to host port P are forwarded to PATH_P. Requires `cid`."),
+          Argument::value("shared-dir", "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:fs_uidmap=UIDMAP:fs_gidmap=GIDMAP:audit_log=PATH:cache=CACHE:dax=BOOL:dax_window_size=BYTES:dax_reclaim=BOOL:posix_acl=BOOL:upper=DIR:lower=DIR]",
                           "Colon-separated options for configuring a directory to be shared with the VM.
                               The first field is the directory to be shared and the second field is the tag that the VM can use to identify the device.
                               The remaining fields are key=value pairs that may appear in any order.  Valid keys are:
-                              type=(p9, fs) - Indicates whether the directory should be shared via virtio-9p or virtio-fs (default: p9).
+                              type=(p9, fs, overlay, tmpfs) - Indicates whether the directory should be shared via virtio-9p or virtio-fs (default: p9).  \"overlay\" shares it via virtio-fs as the read-only bottom layer of a copy-on-write overlay.  \"tmpfs\" shares a writable in-memory file system whose changes are discarded when the VM exits; PATH is either empty or a tar archive with its initial contents.  When the type is \"fs\", PATH may also be a tar archive, which is shared read-only without unpacking it.
                               uidmap=UIDMAP - The uid map to use for the device's jail in the format \"inner outer count[,inner outer count]\" (default: 0 <current euid> 1).
                               gidmap=GIDMAP - The gid map to use for the device's jail in the format \"inner outer count[,inner outer count]\" (default: 0 <current egid> 1).
+                              fs_uidmap=UIDMAP - A uid map applied by the virtio-fs server itself in the format \"guest host count[,guest host count]\".  Files created by a mapped guest uid are owned by the corresponding host uid, and host owners are reported back to the VM as guest uids.  Unmapped uids are translated to the overflow uid 65534 in both directions.  Only valid when the type is \"fs\" and the shared path is a directory.
+                              fs_gidmap=GIDMAP - A gid map applied by the virtio-fs server in the same format as fs_uidmap.
+                              audit_log=PATH - Append a line to PATH for every request that modifies the shared directory, recording the operation, the guest credentials, the affected paths, and the result.  Only valid when the type is \"fs\" and the shared path is a directory.
                               cache=(never, auto, always) - Indicates whether the VM can cache the contents of the shared directory (default: auto).  When set to \"auto\" and the type is \"fs\", the VM will use close-to-open consistency for file contents.
                               timeout=SECONDS - How long the VM should consider file attributes and directory entries to be valid (default: 5).  If the VM has exclusive access to the directory, then this should be a large value.  If the directory can be modified by other processes, then this should be 0.
                               writeback=BOOL - Indicates whether the VM can use writeback caching (default: false).  This is only safe to do when the VM has exclusive access to the files in a directory.  Additionally, the server should have read permission for all files as the VM may issue read requests even for files that are opened write-only.
                               dax=BOOL - Indicates whether DAX support should be enabled.  Enabling DAX can improve performance for frequently accessed files by mapping regions of the file directory into the VM's memory, allowing direct access at the cost of slightly increased latency the first time the file is accessed.  Since the mapping is shared directly from the host kernel's file cache, enabling DAX can improve performance even when the cache policy is \"Never\".  The default value for this option is \"false\".
+                              dax_window_size=BYTES - The size of the DAX window, which limits how much of the shared files the VM can map at the same time.  Must be a power of two of at least 2 MiB (default: 8 GiB).
+                              dax_reclaim=BOOL - Indicates whether the device should ask the VM to remove the least recently mapped ranges once the DAX window is 90% full.  This uses a crosvm-specific virtio-fs feature, so it only takes effect when the VM's driver supports it (default: false).
                               posix_acl=BOOL - Indicates whether the shared directory supports POSIX ACLs.  This should only be enabled when the underlying file system supports POSIX ACLs.  The default value for this option is \"true\".
+                              upper=DIR - The writable directory of an overlay.  Modified files are copied into it and deleted files are hidden by \".wh.\" whiteout files.  Required when the type is \"overlay\".
+                              lower=DIR - An additional read-only directory stacked on top of the shared directory and any previous `lower` directories of an overlay.  May be given more than once.
 "),
           Argument::value("seccomp-policy-dir", "PATH", "Path to seccomp .policy files."),
           Argument::flag("seccomp-log-failures", "Instead of seccomp filter failures being fatal, they will be logged instead."),
@@ -2270,19 +2666,22 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                               backend=(2d|virglrenderer|gfxstream) - Which backend to use for virtio-gpu (determining rendering protocol)
                               width=INT - The width of the virtual display connected to the virtio-gpu.
                               height=INT - The height of the virtual display connected to the virtio-gpu.
+                              max-displays=INT - The most displays that can be connected at once, including those added with `crosvm display add`. Defaults to the number of displays.
                               egl[=true|=false] - If the backend should use a EGL context for rendering.
                               glx[=true|=false] - If the backend should use a GLX context for rendering.
                               surfaceless[=true|=false] - If the backend should use a surfaceless context for rendering.
+                              software[=true|=false] - If the virglrenderer backend should render with a software rasterizer, for hosts without a GPU.
                               angle[=true|=false] - If the gfxstream backend should use ANGLE (OpenGL on Vulkan) as its native OpenGL driver.
                               syncfd[=true|=false] - If the gfxstream backend should support EGL_ANDROID_native_fence_sync
                               vulkan[=true|=false] - If the backend should support vulkan"),
           #[cfg(feature = "gpu")]
           Argument::flag_or_value("gpu-display",
-                                  "[width=INT,height=INT]",
+                                  "[width=INT,height=INT,vnc=ADDR:PORT]",
                                   "(EXPERIMENTAL) Comma separated key=value pairs for setting up a display on the virtio-gpu device
                               Possible key values:
                               width=INT - The width of the virtual display connected to the virtio-gpu.
-                              height=INT - The height of the virtual display connected to the virtio-gpu."),
+                              height=INT - The height of the virtual display connected to the virtio-gpu.
+                              vnc=ADDR:PORT - Serve the display to VNC clients on this address instead of showing it in a window. There is no authentication, so the address should only be reachable by trusted clients."),
           #[cfg(feature = "tpm")]
           Argument::flag("software-tpm", "enable a software emulated trusted platform module device"),
           Argument::value("evdev", "PATH", "Path to an event device node. The device will be grabbed (unusable from the host) and made available to the guest with the same configuration it shows on the host"),
@@ -2290,6 +2689,8 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
           Argument::value("multi-touch", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read multi touch input events (such as those from a touchscreen) and write status updates to, optionally followed by width and height (defaults to 800x1280)."),
           Argument::value("trackpad", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read trackpad input events and write status updates to, optionally followed by screen width and height (defaults to 800x1280)."),
           Argument::value("mouse", "PATH", "Path to a socket from where to read mouse input events and write status updates to."),
+          Argument::value("tablet", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read absolute pointer input events (such as those from a VNC client) and write status updates to, optionally followed by width and height (defaults to the display size, or 1280x1024 without a display)."),
+          Argument::value("gamepad", "PATH", "Path to a socket from where to read gamepad input events and write status updates to."),
           Argument::value("keyboard", "PATH", "Path to a socket from where to read keyboard input events and write status updates to."),
           Argument::value("switches", "PATH", "Path to a socket from where to read switch input events and write status updates to."),
           #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -2322,8 +2723,17 @@ iommu=on|off - indicates whether to enable virtio IOMMU for this device"),
           Argument::value("vhost-user-snd", "SOCKET_PATH", "Path to a socket for vhost-user snd"),
           Argument::value("vhost-user-vsock", "SOCKET_PATH", "Path to a socket for vhost-user vsock"),
           Argument::value("vhost-user-wl", "SOCKET_PATH:TUBE_PATH", "Paths to a vhost-user socket for wayland and a Tube socket for additional wayland-specific messages"),
+          Argument::value("vvu-proxy", "SOCKET_PATH", "Socket path for a virtio-vhost-user device that forwards vhost-user messages from a frontend to a backend running in the guest"),
           Argument::value("vhost-user-fs", "SOCKET_PATH:TAG",
                           "Path to a socket path for vhost-user fs, and tag for the shared dir"),
+          Argument::value("vhost-user-generic", "SOCKET_PATH[,key=value[,key=value[,...]]]",
+                          "Comma separated key=value pairs for attaching a vhost-user backend of a device type crosvm has no dedicated frontend for
+                              Possible key values:
+                              type=NUM - virtio device ID of the device (required)
+                              queues=NUM - number of virtqueues
+                              queue_size=NUM - size of each virtqueue (default: 256)
+                              queue_sizes=A:B:... - colon separated sizes of each virtqueue, instead of queues and queue_size
+                              config_size=NUM - size in bytes of the device configuration space (default: 0)"),
           #[cfg(feature = "direct")]
           Argument::value("direct-pmio", "PATH@RANGE[,RANGE[,...]]", "Path and ranges for direct port mapped I/O access"),
           #[cfg(feature = "direct")]
@@ -2367,16 +2777,29 @@ iommu=on|off - indicates whether to enable virtio IOMMU for this device"),
                 }
             }
         }
-        Ok(()) => match platform::run_config(cfg) {
-            Ok(_) => {
-                info!("crosvm has exited normally");
-                Ok(())
+        Ok(()) => {
+            // Mesa is configured through the environment, which can only be changed safely before
+            // any threads are started.
+            #[cfg(feature = "gpu")]
+            if cfg
+                .gpu_parameters
+                .as_ref()
+                .map_or(false, |p| p.renderer_use_software)
+            {
+                rutabaga_gfx::set_software_renderer_env();
             }
-            Err(e) => {
-                error!("crosvm has exited with error: {}", e);
-                Err(())
+
+            match platform::run_config(cfg) {
+                Ok(_) => {
+                    info!("crosvm has exited normally");
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("crosvm has exited with error: {}", e);
+                    Err(())
+                }
             }
-        },
+        }
         Err(argument::Error::PrintHelp) => {
             print_help("crosvm run", "KERNEL", &arguments[..]);
             Ok(())
@@ -2674,6 +3097,128 @@ with a '--backing_file'."
     Ok(())
 }
 
+// Mounts `fs` at `mountpoint` and serves its requests until it is unmounted.
+fn serve_fuse<F: FileSystem + Sync>(
+    fs: F,
+    mountpoint: &str,
+    writable: bool,
+    allow_other: bool,
+) -> std::result::Result<(), ()> {
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    if !writable {
+        flags |= libc::MS_RDONLY;
+    }
+    let max_size = fs.max_buffer_size();
+    let mut options = vec![MountOption::MaxRead(max_size)];
+    if allow_other {
+        options.push(MountOption::AllowOther);
+    }
+
+    let dev_fuse = fuse::mount::mount_dev_fuse(mountpoint, "crosvm", flags, &options)
+        .map_err(|e| error!("Failed to mount '{}': {}", mountpoint, e))?;
+    fuse::worker::start_message_loop(dev_fuse, max_size, max_size, fs)
+        .map_err(|e| error!("FUSE file system at '{}' failed: {}", mountpoint, e))
+}
+
+fn fuse_mount(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::positional("SOURCE", "the directory, archive or disk image to mount"),
+        Argument::positional("MOUNTPOINT", "where to mount it"),
+        Argument::value(
+            "type",
+            "TYPE",
+            "one of `passthrough`, `archive`, `tmpfs` or `disk` (default: passthrough)",
+        ),
+        Argument::flag("writable", "allow changes to the mounted file system"),
+        Argument::flag(
+            "allow_other",
+            "allow users other than the caller to access the mount",
+        ),
+    ];
+    let mut positional_index = 0;
+    let mut source = String::new();
+    let mut mountpoint = String::new();
+    let mut fs_type = String::from("passthrough");
+    let mut writable = false;
+    let mut allow_other = false;
+    set_arguments(args, &arguments[..], |name, value| {
+        match (name, positional_index) {
+            ("", 0) => {
+                // SOURCE
+                positional_index += 1;
+                source = value.unwrap().to_owned();
+            }
+            ("", 1) => {
+                // MOUNTPOINT
+                positional_index += 1;
+                mountpoint = value.unwrap().to_owned();
+            }
+            ("", _) => {
+                return Err(argument::Error::TooManyArguments(
+                    "Expected exactly 2 positional arguments".to_owned(),
+                ));
+            }
+            ("type", _) => fs_type = value.unwrap().to_owned(),
+            ("writable", _) => writable = true,
+            ("allow_other", _) => allow_other = true,
+            _ => unreachable!(),
+        };
+        Ok(())
+    })
+    .map_err(|e| {
+        error!("Unable to parse command line arguments: {}", e);
+    })?;
+    if positional_index != 2 {
+        print_help("crosvm fuse_mount", "SOURCE MOUNTPOINT", &arguments);
+        println!(
+            "Mounts SOURCE at MOUNTPOINT on the host using the same file system implementations as
+virtio-fs and serves it until MOUNTPOINT is unmounted. SOURCE may be empty for `tmpfs`."
+        );
+        return Err(());
+    }
+
+    let fs_cfg: passthrough::Config = Default::default();
+    let open_source = || {
+        OpenOptions::new()
+            .read(true)
+            .write(writable && fs_type == "disk")
+            .open(&source)
+            .map_err(|e| error!("Failed to open '{}': {}", source, e))
+    };
+    match fs_type.as_str() {
+        "passthrough" => {
+            let fs = PassthroughFs::new_at(fs_cfg, Path::new(&source))
+                .map_err(|e| error!("Failed to create passthrough file system: {}", e))?;
+            serve_fuse(fs, &mountpoint, writable, allow_other)
+        }
+        "archive" => {
+            let fs = MemFs::from_archive(fs_cfg, open_source()?, writable)
+                .map_err(|e| error!("Failed to read archive '{}': {}", source, e))?;
+            serve_fuse(fs, &mountpoint, writable, allow_other)
+        }
+        "tmpfs" => {
+            let fs = if source.is_empty() {
+                MemFs::new(fs_cfg)
+            } else {
+                MemFs::from_archive(fs_cfg, open_source()?, true)
+            }
+            .map_err(|e| error!("Failed to create tmpfs: {}", e))?;
+            serve_fuse(fs, &mountpoint, true, allow_other)
+        }
+        "disk" => {
+            let disk = disk::create_disk_file(open_source()?, disk::MAX_NESTING_DEPTH)
+                .map_err(|e| error!("Failed to open disk image '{}': {}", source, e))?;
+            let fs = DiskFs::new(fs_cfg, disk, writable)
+                .map_err(|e| error!("Failed to read disk image '{}': {}", source, e))?;
+            serve_fuse(fs, &mountpoint, writable, allow_other)
+        }
+        _ => {
+            error!("Unknown file system type: {}", fs_type);
+            Err(())
+        }
+    }
+}
+
 fn start_device(mut args: std::env::Args) -> std::result::Result<(), ()> {
     let print_usage = || {
         print_help(
@@ -2767,26 +3312,438 @@ fn make_rt(mut args: std::env::Args) -> std::result::Result<(), ()> {
     vms_request(&VmRequest::MakeRT, socket_path)
 }
 
-fn parse_bus_id_addr(v: &str) -> ModifyUsbResult<(u8, u8, u16, u16)> {
-    debug!("parse_bus_id_addr: {}", v);
-    let mut ids = v.split(':');
-    match (ids.next(), ids.next(), ids.next(), ids.next()) {
-        (Some(bus_id), Some(addr), Some(vid), Some(pid)) => {
-            let bus_id = bus_id
-                .parse::<u8>()
-                .map_err(|e| ModifyUsbError::ArgParseInt("bus_id", bus_id.to_owned(), e))?;
-            let addr = addr
-                .parse::<u8>()
-                .map_err(|e| ModifyUsbError::ArgParseInt("addr", addr.to_owned(), e))?;
-            let vid = u16::from_str_radix(vid, 16)
-                .map_err(|e| ModifyUsbError::ArgParseInt("vid", vid.to_owned(), e))?;
-            let pid = u16::from_str_radix(pid, 16)
-                .map_err(|e| ModifyUsbError::ArgParseInt("pid", pid.to_owned(), e))?;
-            Ok((bus_id, addr, vid, pid))
+fn gpu_command(command: GpuControlCommand, socket_path: &str) -> std::result::Result<(), ()> {
+    match do_gpu_command(Path::new(socket_path), command)? {
+        GpuControlResult::Ok => Ok(()),
+        result => {
+            println!("error {}", result);
+            Err(())
         }
-        _ => Err(ModifyUsbError::ArgParse(
-            "BUS_ID_ADDR_BUS_NUM_DEV_NUM",
-            v.to_owned(),
+    }
+}
+
+fn parse_scanout_id(value: &str) -> std::result::Result<u32, ()> {
+    value
+        .parse::<u32>()
+        .map_err(|_| error!("Failed to parse scanout id '{}'", value))
+}
+
+// Files are created here rather than by the GPU device, which can't open host paths.
+fn create_capture_file(path: &str) -> std::result::Result<File, ()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| error!("Failed to create '{}': {}", path, e))
+}
+
+fn screenshot(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm screenshot", "SCANOUT_ID PNG_PATH VM_SOCKET", &[]);
+        println!("Writes the current contents of a GPU scanout to a PNG image.");
+        return Err(());
+    }
+
+    let scanout_id = parse_scanout_id(&args.next().unwrap())?;
+    let file = create_capture_file(&args.next().unwrap())?;
+    gpu_command(
+        GpuControlCommand::Screenshot { scanout_id, file },
+        &args.next().unwrap(),
+    )
+}
+
+fn record(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm record", "SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Records the frames flushed to a GPU scanout.");
+        println!("Subcommands:");
+        println!("  start SCANOUT_ID (raw|y4m) PATH VM_SOCKET");
+        println!("  stop SCANOUT_ID VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let scanout_id = parse_scanout_id(&args.next().unwrap())?;
+
+    let command = match subcommand {
+        "start" => {
+            if args.len() < 3 {
+                error!("Expected a format, a path and a socket to start recording");
+                return Err(());
+            }
+            let format = args
+                .next()
+                .unwrap()
+                .parse::<GpuRecordFormat>()
+                .map_err(|e| error!("{}", e))?;
+            let file = create_capture_file(&args.next().unwrap())?;
+            GpuControlCommand::RecordStart {
+                scanout_id,
+                format,
+                file,
+            }
+        }
+        "stop" => GpuControlCommand::RecordStop { scanout_id },
+        _ => {
+            error!("Unknown record subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    gpu_command(command, &args.next().unwrap())
+}
+
+fn parse_display_size(width: &str, height: &str) -> std::result::Result<(u32, u32), ()> {
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => {
+            error!("Failed to parse display size '{}x{}'", width, height);
+            Err(())
+        }
+    }
+}
+
+fn display_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm display", "SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Manage the displays of the GPU.");
+        println!("Subcommands:");
+        println!("  list VM_SOCKET");
+        println!("  add WIDTH HEIGHT VM_SOCKET");
+        println!("  remove SCANOUT_ID VM_SOCKET");
+        println!("  resize SCANOUT_ID WIDTH HEIGHT VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+
+    let command = match subcommand {
+        "list" => GpuControlCommand::ListDisplays,
+        "add" => {
+            if args.len() < 3 {
+                error!("Expected a width, a height and a socket to add a display");
+                return Err(());
+            }
+            let (width, height) = parse_display_size(&args.next().unwrap(), &args.next().unwrap())?;
+            GpuControlCommand::AddDisplay { width, height }
+        }
+        "remove" => {
+            let scanout_id = parse_scanout_id(&args.next().unwrap())?;
+            GpuControlCommand::RemoveDisplay { scanout_id }
+        }
+        "resize" => {
+            if args.len() < 4 {
+                error!("Expected a scanout id, a width, a height and a socket to resize a display");
+                return Err(());
+            }
+            let scanout_id = parse_scanout_id(&args.next().unwrap())?;
+            let (width, height) = parse_display_size(&args.next().unwrap(), &args.next().unwrap())?;
+            GpuControlCommand::ResizeDisplay {
+                scanout_id,
+                width,
+                height,
+            }
+        }
+        _ => {
+            error!("Unknown display subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    let socket_path = match args.next() {
+        Some(socket_path) => socket_path,
+        None => {
+            error!("Expected a socket");
+            return Err(());
+        }
+    };
+    match do_gpu_command(Path::new(&socket_path), command)? {
+        result @ GpuControlResult::Ok
+        | result @ GpuControlResult::Displays(_)
+        | result @ GpuControlResult::DisplayAdded { .. } => {
+            println!("{}", result);
+            Ok(())
+        }
+        result => {
+            println!("error {}", result);
+            Err(())
+        }
+    }
+}
+
+fn snd_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm snd", "SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Manage the jacks of the sound device.");
+        println!("Subcommands:");
+        println!("  jacks VM_SOCKET");
+        println!("  connect JACK_ID VM_SOCKET");
+        println!("  disconnect JACK_ID VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+
+    let command = match subcommand {
+        "jacks" => SndControlCommand::ListJacks,
+        "connect" | "disconnect" => {
+            let value = args.next().unwrap();
+            let jack_id = value
+                .parse::<u32>()
+                .map_err(|_| error!("Failed to parse jack id '{}'", value))?;
+            SndControlCommand::SetJackConnected {
+                jack_id,
+                connected: subcommand == "connect",
+            }
+        }
+        _ => {
+            error!("Unknown snd subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    let socket_path = match args.next() {
+        Some(socket_path) => socket_path,
+        None => {
+            error!("Expected a socket");
+            return Err(());
+        }
+    };
+    match do_snd_command(Path::new(&socket_path), command)? {
+        result @ SndControlResult::Ok | result @ SndControlResult::Jacks(_) => {
+            println!("{}", result);
+            Ok(())
+        }
+        result => {
+            println!("error {}", result);
+            Err(())
+        }
+    }
+}
+
+// Parses `KIND[:INDEX]`, where the index defaults to the first device of that kind.
+fn parse_input_device(value: &str) -> std::result::Result<(InputDeviceKind, usize), ()> {
+    let mut parts = value.splitn(2, ':');
+    let kind = parts
+        .next()
+        .unwrap()
+        .parse::<InputDeviceKind>()
+        .map_err(|e| error!("{}", e))?;
+    let index = match parts.next() {
+        Some(index) => index
+            .parse::<usize>()
+            .map_err(|_| error!("Failed to parse input device index '{}'", index))?,
+        None => 0,
+    };
+    Ok((kind, index))
+}
+
+// Parses a comma separated list of `TYPE:CODE:VALUE` events.
+fn parse_input_events(value: &str) -> std::result::Result<Vec<InputEvent>, ()> {
+    value
+        .split(',')
+        .map(|event| {
+            let fields: Vec<&str> = event.split(':').collect();
+            if let [type_, code, value] = fields[..] {
+                if let (Ok(type_), Ok(code), Ok(value)) =
+                    (type_.parse(), code.parse(), value.parse())
+                {
+                    return Ok(InputEvent { type_, code, value });
+                }
+            }
+            error!("Failed to parse input event '{}'", event);
+            Err(())
+        })
+        .collect()
+}
+
+fn read_input_recording(path: &str) -> std::result::Result<Vec<InputRecordEntry>, ()> {
+    let file = File::open(path).map_err(|e| error!("Failed to open '{}': {}", path, e))?;
+    let mut entries = Vec::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| error!("Failed to read '{}': {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = line
+            .parse::<InputRecordEntry>()
+            .map_err(|e| error!("{}:{}: {}", path, line_number + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn input_command(
+    socket_path: &Path,
+    kind: InputDeviceKind,
+    index: usize,
+    command: InputControlCommand,
+) -> std::result::Result<(), ()> {
+    match do_input_command(socket_path, kind, index, command)? {
+        InputControlResult::Ok => Ok(()),
+        result => {
+            println!("error {}", result);
+            Err(())
+        }
+    }
+}
+
+// Injects the recorded events one report at a time, keeping the original spacing between reports.
+fn replay_input_events(
+    socket_path: &Path,
+    kind: InputDeviceKind,
+    index: usize,
+    entries: Vec<InputRecordEntry>,
+) -> std::result::Result<(), ()> {
+    let syn = InputEvent::from(virtio_input_event::syn());
+    let start = Instant::now();
+    let mut events = Vec::new();
+    for entry in entries {
+        events.push(entry.event);
+        if entry.event == syn {
+            let elapsed = start.elapsed();
+            let time = Duration::from_micros(entry.time_us);
+            if time > elapsed {
+                sleep(time - elapsed);
+            }
+            let command = InputControlCommand::Inject(mem::take(&mut events));
+            input_command(socket_path, kind, index, command)?;
+        }
+    }
+    if !events.is_empty() {
+        input_command(
+            socket_path,
+            kind,
+            index,
+            InputControlCommand::Inject(events),
+        )?;
+    }
+    Ok(())
+}
+
+fn input_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm input", "SUBCOMMAND DEVICE VM_SOCKET...", &[]);
+        println!("Inject and record the events of a --keyboard, --mouse or --multi-touch device.");
+        println!("DEVICE is keyboard, mouse or multi-touch, optionally followed by :INDEX.");
+        println!("Subcommands:");
+        println!("  send DEVICE TYPE:CODE:VALUE[,TYPE:CODE:VALUE...] VM_SOCKET");
+        println!("  key DEVICE KEYCODE VM_SOCKET");
+        println!("  move DEVICE DX DY VM_SOCKET");
+        println!("  touch DEVICE X Y VM_SOCKET");
+        println!("  record-start DEVICE PATH VM_SOCKET");
+        println!("  record-stop DEVICE VM_SOCKET");
+        println!("  replay DEVICE PATH VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let (kind, index) = parse_input_device(&args.next().unwrap())?;
+
+    let operands: Vec<String> = args.collect();
+    let operand_count = match subcommand {
+        "record-stop" => 1,
+        "send" | "key" | "record-start" | "replay" => 2,
+        "move" | "touch" => 3,
+        _ => {
+            error!("Unknown input subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+    if operands.len() != operand_count {
+        error!(
+            "Expected {} arguments after the device for input subcommand '{}'",
+            operand_count, subcommand
+        );
+        return Err(());
+    }
+    let socket_path = Path::new(&operands[operand_count - 1]);
+    let parse_i32 = |value: &str| {
+        value
+            .parse::<i32>()
+            .map_err(|_| error!("Failed to parse '{}'", value))
+    };
+
+    let command = match subcommand {
+        "send" => {
+            let mut events = parse_input_events(&operands[0])?;
+            events.push(virtio_input_event::syn().into());
+            InputControlCommand::Inject(events)
+        }
+        "key" => {
+            let code = operands[0]
+                .parse::<u16>()
+                .map_err(|_| error!("Failed to parse key code '{}'", operands[0]))?;
+            let events = vec![
+                virtio_input_event::key(code, true),
+                virtio_input_event::syn(),
+                virtio_input_event::key(code, false),
+                virtio_input_event::syn(),
+            ];
+            InputControlCommand::Inject(events.into_iter().map(InputEvent::from).collect())
+        }
+        "move" => {
+            let events = vec![
+                virtio_input_event::relative_x(parse_i32(&operands[0])?),
+                virtio_input_event::relative_y(parse_i32(&operands[1])?),
+                virtio_input_event::syn(),
+            ];
+            InputControlCommand::Inject(events.into_iter().map(InputEvent::from).collect())
+        }
+        "touch" => {
+            let x = parse_i32(&operands[0])?;
+            let y = parse_i32(&operands[1])?;
+            let events = vec![
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(0),
+                virtio_input_event::multitouch_absolute_x(x),
+                virtio_input_event::multitouch_absolute_y(y),
+                virtio_input_event::touch(true),
+                virtio_input_event::absolute_x(x),
+                virtio_input_event::absolute_y(y),
+                virtio_input_event::syn(),
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(-1),
+                virtio_input_event::touch(false),
+                virtio_input_event::syn(),
+            ];
+            InputControlCommand::Inject(events.into_iter().map(InputEvent::from).collect())
+        }
+        "record-start" => InputControlCommand::RecordStart {
+            file: create_capture_file(&operands[0])?,
+        },
+        "record-stop" => InputControlCommand::RecordStop,
+        "replay" => {
+            let entries = read_input_recording(&operands[0])?;
+            replay_input_events(socket_path, kind, index, entries)?;
+            println!("ok");
+            return Ok(());
+        }
+        _ => unreachable!(),
+    };
+
+    input_command(socket_path, kind, index, command)?;
+    println!("ok");
+    Ok(())
+}
+
+fn parse_bus_id_addr(v: &str) -> ModifyUsbResult<(u8, u8, u16, u16)> {
+    debug!("parse_bus_id_addr: {}", v);
+    let mut ids = v.split(':');
+    match (ids.next(), ids.next(), ids.next(), ids.next()) {
+        (Some(bus_id), Some(addr), Some(vid), Some(pid)) => {
+            let bus_id = bus_id
+                .parse::<u8>()
+                .map_err(|e| ModifyUsbError::ArgParseInt("bus_id", bus_id.to_owned(), e))?;
+            let addr = addr
+                .parse::<u8>()
+                .map_err(|e| ModifyUsbError::ArgParseInt("addr", addr.to_owned(), e))?;
+            let vid = u16::from_str_radix(vid, 16)
+                .map_err(|e| ModifyUsbError::ArgParseInt("vid", vid.to_owned(), e))?;
+            let pid = u16::from_str_radix(pid, 16)
+                .map_err(|e| ModifyUsbError::ArgParseInt("pid", pid.to_owned(), e))?;
+            Ok((bus_id, addr, vid, pid))
+        }
+        _ => Err(ModifyUsbError::ArgParse(
+            "BUS_ID_ADDR_BUS_NUM_DEV_NUM",
+            v.to_owned(),
         )),
     }
 }
@@ -2795,18 +3752,29 @@ fn usb_attach(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
     let val = args
         .next()
         .ok_or(ModifyUsbError::ArgMissing("BUS_ID_ADDR_BUS_NUM_DEV_NUM"))?;
-    let (bus, addr, vid, pid) = parse_bus_id_addr(&val)?;
-    let dev_path = PathBuf::from(
-        args.next()
-            .ok_or(ModifyUsbError::ArgMissing("usb device path"))?,
-    );
+    // Emulated devices are named by kind instead of a host bus address.
+    let emulated_kind = val.parse::<UsbEmulatedDeviceKind>().ok();
+    let dev_path = args
+        .next()
+        .ok_or(ModifyUsbError::ArgMissing("usb device path"))?;
 
     let socket_path = args
         .next()
         .ok_or(ModifyUsbError::ArgMissing("control socket path"))?;
     let socket_path = Path::new(&socket_path);
 
-    do_usb_attach(socket_path, bus, addr, vid, pid, &dev_path)
+    // USB/IP devices are named by their bus id on the server, which takes the place of the path.
+    if let Some(busid) = val.strip_prefix("usbip:") {
+        return do_usb_attach_usbip(socket_path, busid, &dev_path);
+    }
+    let dev_path = PathBuf::from(dev_path);
+    match emulated_kind {
+        Some(kind) => do_usb_attach_emulated(socket_path, kind, &dev_path),
+        None => {
+            let (bus, addr, vid, pid) = parse_bus_id_addr(&val)?;
+            do_usb_attach(socket_path, bus, addr, vid, pid, &dev_path)
+        }
+    }
 }
 
 fn usb_detach(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
@@ -2834,7 +3802,19 @@ fn usb_list(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
 fn modify_usb(mut args: std::env::Args) -> std::result::Result<(), ()> {
     if args.len() < 2 {
         print_help("crosvm usb",
-                   "[attach BUS_ID:ADDR:VENDOR_ID:PRODUCT_ID [USB_DEVICE_PATH|-] | detach PORT | list] VM_SOCKET...", &[]);
+                   "[attach BUS_ID:ADDR:VENDOR_ID:PRODUCT_ID [USB_DEVICE_PATH|-] | attach EMULATED_DEVICE PATH | attach usbip:BUS_ID SERVER | detach PORT | list] VM_SOCKET...", &[]);
+        println!("EMULATED_DEVICE is one of:");
+        println!("    mass-storage[:ro] - A USB stick backed by the disk image at PATH.");
+        println!("    keyboard - A keyboard fed with virtio input events read from PATH.");
+        println!(
+            "    tablet[:WIDTHxHEIGHT] - A tablet fed with virtio input events read from PATH."
+        );
+        println!(
+            "    serial - A CDC-ACM serial port connected to the file, socket or pty at PATH."
+        );
+        println!(
+            "SERVER is the HOST[:PORT] or Unix socket path of a USB/IP server exporting BUS_ID."
+        );
         return Err(());
     }
 
@@ -2882,12 +3862,18 @@ fn print_usage() {
     println!("    create_qcow2  - Create a new qcow2 disk image file.");
     println!("    device - Start a device process.");
     println!("    disk - Manage attached virtual disk devices.");
+    println!("    display - Manage the displays of the GPU.");
+    println!("    fuse_mount - Mount a directory, archive or disk image on the host via FUSE.");
+    println!("    input - Inject and record the events of an input device.");
     println!(
         "    make_rt - Enables real-time vcpu priority for crosvm instances started with \
          `--delay-rt`."
     );
+    println!("    record - Record the frames shown on a GPU scanout.");
     println!("    resume - Resumes the crosvm instance.");
     println!("    run - Start a new crosvm instance.");
+    println!("    screenshot - Write the contents of a GPU scanout to a PNG image.");
+    println!("    snd - Manage the jacks of the sound device.");
     println!("    stop - Stops crosvm instances via their control sockets.");
     println!("    suspend - Suspends the crosvm instance.");
     println!("    usb - Manage attached virtual USB devices.");
@@ -2922,9 +3908,15 @@ fn crosvm_main() -> std::result::Result<(), ()> {
         Some("create_qcow2") => create_qcow2(args),
         Some("device") => start_device(args),
         Some("disk") => disk_cmd(args),
+        Some("display") => display_cmd(args),
+        Some("fuse_mount") => fuse_mount(args),
+        Some("input") => input_cmd(args),
         Some("make_rt") => make_rt(args),
+        Some("record") => record(args),
         Some("resume") => resume_vms(args),
         Some("run") => run_vm(args),
+        Some("screenshot") => screenshot(args),
+        Some("snd") => snd_cmd(args),
         Some("stop") => stop_vms(args),
         Some("suspend") => suspend_vms(args),
         Some("usb") => modify_usb(args),
@@ -3095,6 +4087,211 @@ mod tests {
             .expect("parse should have succeded");
     }
 
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_ac97_wav_valid() {
+        let params = parse_ac97_options(
+            "backend=wav,playback_file=/tmp/playback.wav,capture_file=/tmp/capture.wav",
+        )
+        .expect("parse should have succeded");
+        assert_eq!(
+            params.playback_file,
+            Some(PathBuf::from("/tmp/playback.wav"))
+        );
+        assert_eq!(params.capture_file, Some(PathBuf::from("/tmp/capture.wav")));
+        parse_ac97_options("backend=wav,playback_file=/tmp/playback.wav")
+            .expect("parse should have succeded");
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_ac97_wav_invalid() {
+        parse_ac97_options("backend=wav").expect_err("parse should have failed");
+        parse_ac97_options("backend=wav,capture_file=/tmp/capture.wav")
+            .expect_err("parse should have failed");
+        parse_ac97_options("backend=null,playback_file=/tmp/playback.wav")
+            .expect_err("parse should have failed");
+    }
+
+    #[cfg(feature = "audio_pulse")]
+    #[test]
+    fn parse_ac97_pulse_valid() {
+        parse_ac97_options("backend=pulse,capture=true").expect("parse should have succeded");
+    }
+
+    #[test]
+    fn parse_input_device_valid() {
+        assert_eq!(
+            parse_input_device("keyboard").unwrap(),
+            (InputDeviceKind::Keyboard, 0)
+        );
+        assert_eq!(
+            parse_input_device("multi-touch:2").unwrap(),
+            (InputDeviceKind::MultiTouch, 2)
+        );
+    }
+
+    #[test]
+    fn parse_input_device_invalid() {
+        parse_input_device("trackpad").expect_err("parse should have failed");
+        parse_input_device("mouse:").expect_err("parse should have failed");
+        parse_input_device("mouse:-1").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_input_events_valid() {
+        let events = parse_input_events("1:30:1,0:0:0,3:53:-1").unwrap();
+        assert_eq!(
+            events,
+            vec![
+                InputEvent {
+                    type_: 1,
+                    code: 30,
+                    value: 1
+                },
+                InputEvent {
+                    type_: 0,
+                    code: 0,
+                    value: 0
+                },
+                InputEvent {
+                    type_: 3,
+                    code: 53,
+                    value: -1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_input_events_invalid() {
+        parse_input_events("").expect_err("parse should have failed");
+        parse_input_events("1:30").expect_err("parse should have failed");
+        parse_input_events("1:30:1:0").expect_err("parse should have failed");
+        parse_input_events("1:30:1,").expect_err("parse should have failed");
+        parse_input_events("1:key:1").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn input_record_entry_round_trip() {
+        let entry = InputRecordEntry {
+            time_us: 1500,
+            event: InputEvent {
+                type_: 2,
+                code: 1,
+                value: -7,
+            },
+        };
+        assert_eq!(entry.to_string(), "1500 2 1 -7");
+        assert_eq!(entry.to_string().parse::<InputRecordEntry>(), Ok(entry));
+        "1500 2 1"
+            .parse::<InputRecordEntry>()
+            .expect_err("parse should have failed");
+        "1500 2 1 x"
+            .parse::<InputRecordEntry>()
+            .expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn usb_emulated_device_kind_valid() {
+        assert_eq!(
+            "mass-storage:ro".parse::<UsbEmulatedDeviceKind>(),
+            Ok(UsbEmulatedDeviceKind::MassStorage { read_only: true })
+        );
+        assert_eq!(
+            "tablet".parse::<UsbEmulatedDeviceKind>(),
+            Ok(UsbEmulatedDeviceKind::Tablet {
+                width: 1280,
+                height: 1024
+            })
+        );
+        let kind = UsbEmulatedDeviceKind::Tablet {
+            width: 800,
+            height: 600,
+        };
+        assert_eq!(kind.to_string(), "tablet:800x600");
+        assert_eq!(kind.to_string().parse::<UsbEmulatedDeviceKind>(), Ok(kind));
+    }
+
+    #[test]
+    fn usb_emulated_device_kind_invalid() {
+        // Host devices are given by address and must not be mistaken for emulated ones.
+        "1:2:18d1:4ee7"
+            .parse::<UsbEmulatedDeviceKind>()
+            .expect_err("parse should have failed");
+        "mass-storage:rw"
+            .parse::<UsbEmulatedDeviceKind>()
+            .expect_err("parse should have failed");
+        "tablet:800"
+            .parse::<UsbEmulatedDeviceKind>()
+            .expect_err("parse should have failed");
+        "serial:1"
+            .parse::<UsbEmulatedDeviceKind>()
+            .expect_err("parse should have failed");
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_sound_vios() {
+        let mut config = Config::default();
+        set_argument(&mut config, "sound", Some("/run/vios.sock")).expect("parse should succeed");
+        assert_eq!(config.sound, Some(PathBuf::from("/run/vios.sock")));
+        set_argument(
+            &mut config,
+            "sound",
+            Some("backend=vios,server=/run/other.sock"),
+        )
+        .expect("parse should succeed");
+        assert_eq!(config.sound, Some(PathBuf::from("/run/other.sock")));
+        assert!(config.stream_sound.is_none());
+
+        set_argument(&mut config, "sound", Some("backend=vios"))
+            .expect_err("parse should have failed");
+        set_argument(
+            &mut config,
+            "sound",
+            Some("backend=vios,server=/run/vios.sock,capture=true"),
+        )
+        .expect_err("parse should have failed");
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_sound_wav() {
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "sound",
+            Some("backend=wav,playback_file=/tmp/playback.wav,capture_file=/tmp/capture.wav"),
+        )
+        .expect("parse should succeed");
+        assert!(config.sound.is_none());
+        let params = config.stream_sound.take().expect("no stream sound device");
+        assert_eq!(
+            params.playback_file,
+            Some(PathBuf::from("/tmp/playback.wav"))
+        );
+        assert_eq!(params.capture_file, Some(PathBuf::from("/tmp/capture.wav")));
+
+        set_argument(&mut config, "sound", Some("backend=wav"))
+            .expect_err("parse should have failed");
+        set_argument(
+            &mut config,
+            "sound",
+            Some("backend=null,server=/run/vios.sock"),
+        )
+        .expect_err("parse should have failed");
+    }
+
+    #[cfg(feature = "audio_pulse")]
+    #[test]
+    fn parse_sound_pulse() {
+        let mut config = Config::default();
+        set_argument(&mut config, "sound", Some("backend=pulse,capture=false"))
+            .expect("parse should succeed");
+        assert!(!config.stream_sound.expect("no stream sound device").capture);
+    }
+
     #[test]
     fn parse_serial_vaild() {
         parse_serial_options("type=syslog,num=1,console=true,stdin=true")
@@ -3370,6 +4567,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn virtio_tablet_and_gamepad() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "tablet", Some("/dev/tablet-test")).unwrap();
+        set_argument(&mut config, "tablet", Some("/dev/tablet-test2:1920:1080")).unwrap();
+        set_argument(&mut config, "gamepad", Some("/dev/gamepad-test")).unwrap();
+        validate_arguments(&mut config).unwrap();
+        assert_eq!(config.virtio_tablets.len(), 2);
+        assert_eq!(
+            config.virtio_tablets[0].get_path(),
+            Path::new("/dev/tablet-test")
+        );
+        assert_eq!(config.virtio_tablets[1].get_size(), (1920, 1080));
+        assert_eq!(
+            config.virtio_gamepads,
+            vec![PathBuf::from("/dev/gamepad-test")]
+        );
+    }
+
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn tablet_spec_default_size_from_gpu() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "tablet", Some("/dev/tablet-test")).unwrap();
+        set_argument(&mut config, "gpu", Some("width=1920,height=1080")).unwrap();
+        validate_arguments(&mut config).unwrap();
+        assert_eq!(config.virtio_tablets[0].get_size(), (1920, 1080));
+    }
+
     #[cfg(feature = "gpu")]
     #[test]
     fn parse_gpu_options_default_vulkan_support() {
@@ -3450,6 +4682,32 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn parse_gpu_options_software() {
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_options(Some("backend=virglrenderer"), &mut gpu_params).is_ok());
+            assert!(!gpu_params.renderer_use_software);
+        }
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(
+                parse_gpu_options(Some("backend=virglrenderer,software"), &mut gpu_params).is_ok()
+            );
+            assert!(gpu_params.renderer_use_software);
+        }
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_options(Some("software=false"), &mut gpu_params).is_ok());
+            assert!(!gpu_params.renderer_use_software);
+        }
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_options(Some("software=invalid_value"), &mut gpu_params).is_err());
+        }
+    }
+
     #[cfg(all(feature = "gpu", feature = "gfxstream"))]
     #[test]
     fn parse_gpu_options_gfxstream_with_syncfd_specified() {
@@ -3529,6 +4787,19 @@ mod tests {
             assert_eq!(gpu_params.displays.len(), 1);
             assert_eq!(gpu_params.displays[0].width, 500);
             assert_eq!(gpu_params.displays[0].height, 600);
+            assert_eq!(gpu_params.displays[0].vnc, None);
+        }
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_display_options(
+                Some("width=500,height=600,vnc=127.0.0.1:5900"),
+                &mut gpu_params
+            )
+            .is_ok());
+            assert_eq!(
+                gpu_params.displays[0].vnc,
+                Some("127.0.0.1:5900".parse().unwrap())
+            );
         }
     }
 
@@ -3551,6 +4822,14 @@ mod tests {
             let mut gpu_params: GpuParameters = Default::default();
             assert!(parse_gpu_display_options(Some("blah"), &mut gpu_params).is_err());
         }
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_display_options(
+                Some("width=500,height=600,vnc=5900"),
+                &mut gpu_params
+            )
+            .is_err());
+        }
     }
 
     #[cfg(feature = "gpu")]
@@ -3580,6 +4859,51 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn parse_gpu_options_max_displays() {
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_options(Some("max-displays=4"), &mut gpu_params).is_ok());
+            assert_eq!(gpu_params.max_displays, Some(4));
+        }
+        {
+            let mut gpu_params: GpuParameters = Default::default();
+            assert!(parse_gpu_options(Some("max-displays=0"), &mut gpu_params).is_err());
+            assert!(parse_gpu_options(Some("max-displays=17"), &mut gpu_params).is_err());
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn max_displays_defaults_to_displays() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "gpu", Some("width=1920,height=1080")).unwrap();
+        set_argument(&mut config, "gpu-display", Some("width=640,height=480")).unwrap();
+        validate_arguments(&mut config).unwrap();
+        assert_eq!(config.gpu_parameters.unwrap().num_scanouts(), 2);
+    }
+
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn max_displays_less_than_displays() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(
+            &mut config,
+            "gpu",
+            Some("width=1920,height=1080,max-displays=1"),
+        )
+        .unwrap();
+        set_argument(&mut config, "gpu-display", Some("width=640,height=480")).unwrap();
+        assert!(validate_arguments(&mut config).is_err());
+    }
+
     #[test]
     fn parse_battery_vaild() {
         parse_battery_options(Some("type=goldfish")).expect("parse should have succeded");
@@ -3616,4 +4940,38 @@ mod tests {
         assert_eq!(params.subsystem_device_id, 0xfffb);
         assert_eq!(params.revision_id, 0xa);
     }
+
+    #[test]
+    fn parse_vhost_user_generic() {
+        let opt =
+            parse_vhost_user_generic_options(Some("/tmp/scmi.sock,type=32,queues=2,config_size=8"))
+                .unwrap();
+        assert_eq!(opt.socket, PathBuf::from("/tmp/scmi.sock"));
+        assert_eq!(opt.device_type, 32);
+        assert_eq!(opt.queue_sizes, vec![256, 256]);
+        assert_eq!(opt.config_size, 8);
+
+        let opt =
+            parse_vhost_user_generic_options(Some("/tmp/gpio.sock,type=0x29,queue_sizes=64:128"))
+                .unwrap();
+        assert_eq!(opt.device_type, 41);
+        assert_eq!(opt.queue_sizes, vec![64, 128]);
+        assert_eq!(opt.config_size, 0);
+    }
+
+    #[test]
+    fn parse_vhost_user_generic_invalid() {
+        // Missing device ID.
+        parse_vhost_user_generic_options(Some("/tmp/i2c.sock,queues=1"))
+            .expect_err("parse should have failed");
+        // Missing queue layout.
+        parse_vhost_user_generic_options(Some("/tmp/i2c.sock,type=34"))
+            .expect_err("parse should have failed");
+        // Queue size is not a power of two.
+        parse_vhost_user_generic_options(Some("/tmp/i2c.sock,type=34,queues=1,queue_size=100"))
+            .expect_err("parse should have failed");
+        // Queue count disagrees with the list of sizes.
+        parse_vhost_user_generic_options(Some("/tmp/i2c.sock,type=34,queues=3,queue_sizes=64:64"))
+            .expect_err("parse should have failed");
+    }
 }
@@ -6,8 +6,12 @@ use base::{info, net::UnixSeqpacket, validate_raw_descriptor, RawDescriptor, Tub
 use remain::sorted;
 use thiserror::Error;
 
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
+use std::net::TcpStream;
 use std::num::ParseIntError;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 
 #[sorted]
@@ -106,6 +110,84 @@ pub fn do_usb_attach(
     }
 }
 
+pub fn do_usb_attach_emulated(
+    socket_path: &Path,
+    kind: UsbEmulatedDeviceKind,
+    dev_path: &Path,
+) -> ModifyUsbResult<UsbControlResult> {
+    let read_only = kind == UsbEmulatedDeviceKind::MassStorage { read_only: true };
+    let is_socket = fs::metadata(dev_path)
+        .map(|m| m.file_type().is_socket())
+        .unwrap_or(false);
+    let file: File = if dev_path.parent() == Some(Path::new("/proc/self/fd")) {
+        // Special case '/proc/self/fd/*' paths. The FD is already open, just use it.
+        // Safe because we will validate |raw_fd|.
+        unsafe { File::from_raw_descriptor(raw_descriptor_from_path(dev_path)?) }
+    } else if is_socket {
+        // Input events and serial data can come from a listening socket, which can't be opened.
+        let stream = UnixStream::connect(dev_path)
+            .map_err(|_| ModifyUsbError::UsbControl(UsbControlResult::FailedToOpenDevice))?;
+        // Safe because the descriptor was just released by `stream`, so `file` owns it.
+        unsafe { File::from_raw_descriptor(stream.into_raw_fd()) }
+    } else {
+        OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(dev_path)
+            .map_err(|_| ModifyUsbError::UsbControl(UsbControlResult::FailedToOpenDevice))?
+    };
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachEmulatedDevice { kind, file });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
+/// Port of USB/IP servers when none is given.
+pub const USBIP_DEFAULT_PORT: u16 = 3240;
+
+/// Attaches the device `busid` exported by the USB/IP server at `server`, which is either the path
+/// of a Unix socket or a TCP address with an optional port.
+pub fn do_usb_attach_usbip(
+    socket_path: &Path,
+    busid: &str,
+    server: &str,
+) -> ModifyUsbResult<UsbControlResult> {
+    let socket = if server.contains('/') {
+        let stream = UnixStream::connect(server)
+            .map_err(|_| ModifyUsbError::UsbControl(UsbControlResult::FailedToOpenDevice))?;
+        stream.into_raw_fd()
+    } else {
+        let stream = if server.contains(':') {
+            TcpStream::connect(server)
+        } else {
+            TcpStream::connect((server, USBIP_DEFAULT_PORT))
+        }
+        .map_err(|_| ModifyUsbError::UsbControl(UsbControlResult::FailedToOpenDevice))?;
+        // URBs are small and latency sensitive.
+        stream
+            .set_nodelay(true)
+            .map_err(|_| ModifyUsbError::UsbControl(UsbControlResult::FailedToOpenDevice))?;
+        stream.into_raw_fd()
+    };
+    // Safe because the descriptor was just released by the stream, so `socket` owns it.
+    let socket = unsafe { File::from_raw_descriptor(socket) };
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachUsbipDevice {
+        busid: busid.to_owned(),
+        socket,
+    });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 pub fn do_usb_detach(socket_path: &Path, port: u8) -> ModifyUsbResult<UsbControlResult> {
     let request = VmRequest::UsbCommand(UsbControlCommand::DetachDevice { port });
     let response =
@@ -161,6 +243,54 @@ pub fn do_modify_battery(
     }
 }
 
+pub type DoGpuCommandResult = std::result::Result<GpuControlResult, ()>;
+
+pub fn do_gpu_command(socket_path: &Path, command: GpuControlCommand) -> DoGpuCommandResult {
+    let request = VmRequest::GpuCommand(command);
+    match handle_request(&request, socket_path)? {
+        VmResponse::GpuResponse(result) => Ok(result),
+        r => {
+            error!("unexpected response to gpu command: {}", r);
+            Err(())
+        }
+    }
+}
+
+pub type DoSndCommandResult = std::result::Result<SndControlResult, ()>;
+
+pub fn do_snd_command(socket_path: &Path, command: SndControlCommand) -> DoSndCommandResult {
+    let request = VmRequest::SndCommand(command);
+    match handle_request(&request, socket_path)? {
+        VmResponse::SndResponse(result) => Ok(result),
+        r => {
+            error!("unexpected response to snd command: {}", r);
+            Err(())
+        }
+    }
+}
+
+pub type DoInputCommandResult = std::result::Result<InputControlResult, ()>;
+
+pub fn do_input_command(
+    socket_path: &Path,
+    kind: InputDeviceKind,
+    index: usize,
+    command: InputControlCommand,
+) -> DoInputCommandResult {
+    let request = VmRequest::InputCommand {
+        kind,
+        index,
+        command,
+    };
+    match handle_request(&request, socket_path)? {
+        VmResponse::InputResponse(result) => Ok(result),
+        r => {
+            error!("unexpected response to input command: {}", r);
+            Err(())
+        }
+    }
+}
+
 pub type HandleRequestResult = std::result::Result<VmResponse, ()>;
 
 pub fn handle_request(request: &VmRequest, socket_path: &Path) -> HandleRequestResult {
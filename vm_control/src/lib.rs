@@ -15,25 +15,31 @@ pub mod gdb;
 
 pub mod client;
 
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::fs::File;
+use std::io;
 use std::os::raw::c_int;
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc};
+use std::time::Duration;
 
 use std::thread::JoinHandle;
 
-use libc::{EINVAL, EIO, ENODEV};
+use libc::{EINVAL, EIO, ENODEV, ETIMEDOUT};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use base::{
     error, with_as_descriptor, AsRawDescriptor, Error as SysError, Event, ExternalMapping, Fd,
     FromRawDescriptor, IntoRawDescriptor, Killable, MappedRegion, MemoryMappingArena,
     MemoryMappingBuilder, MemoryMappingBuilderUnix, MmapError, Protection, Result, SafeDescriptor,
-    SharedMemory, Tube, SIGRTMIN,
+    SharedMemory, Tube, TubeError, SIGRTMIN,
 };
 use hypervisor::{IrqRoute, IrqSource, Vm};
+use linux_input_sys::virtio_input_event;
 use resources::{Alloc, MmioType, SystemAllocator};
 use rutabaga_gfx::{
     DrmFormat, ImageAllocationInfo, RutabagaGralloc, RutabagaGrallocFlags, RutabagaHandle,
@@ -198,6 +204,17 @@ pub enum UsbControlCommand {
         #[serde(with = "with_as_descriptor")]
         file: File,
     },
+    AttachEmulatedDevice {
+        kind: UsbEmulatedDeviceKind,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Import the device `busid` from the USB/IP server at the other end of `socket`.
+    AttachUsbipDevice {
+        busid: String,
+        #[serde(with = "with_as_descriptor")]
+        socket: File,
+    },
     DetachDevice {
         port: u8,
     },
@@ -206,6 +223,72 @@ pub enum UsbControlCommand {
     },
 }
 
+/// Width of the coordinate range of an emulated USB tablet when none is given.
+pub const DEFAULT_USB_TABLET_WIDTH: u32 = 1280;
+/// Height of the coordinate range of an emulated USB tablet when none is given.
+pub const DEFAULT_USB_TABLET_HEIGHT: u32 = 1024;
+
+/// A USB device emulated by the xHCI controller instead of being passed through from the host.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum UsbEmulatedDeviceKind {
+    /// A bulk-only mass-storage device backed by a disk image.
+    MassStorage { read_only: bool },
+    /// A boot protocol keyboard fed with `virtio_input_event`s.
+    Keyboard,
+    /// A tablet fed with `virtio_input_event`s whose absolute axes range over `width` x `height`.
+    Tablet { width: u32, height: u32 },
+    /// A CDC-ACM serial port connected to a file, socket or pty.
+    Serial,
+}
+
+impl Display for UsbEmulatedDeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::UsbEmulatedDeviceKind::*;
+
+        match self {
+            MassStorage { read_only: false } => write!(f, "mass-storage"),
+            MassStorage { read_only: true } => write!(f, "mass-storage:ro"),
+            Keyboard => write!(f, "keyboard"),
+            Tablet { width, height } => write!(f, "tablet:{}x{}", width, height),
+            Serial => write!(f, "serial"),
+        }
+    }
+}
+
+impl FromStr for UsbEmulatedDeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let mut components = s.splitn(2, ':');
+        let kind = components.next().unwrap_or("");
+        let option = components.next();
+        match (kind, option) {
+            ("mass-storage", None) => Ok(UsbEmulatedDeviceKind::MassStorage { read_only: false }),
+            ("mass-storage", Some("ro")) => {
+                Ok(UsbEmulatedDeviceKind::MassStorage { read_only: true })
+            }
+            ("keyboard", None) => Ok(UsbEmulatedDeviceKind::Keyboard),
+            ("tablet", None) => Ok(UsbEmulatedDeviceKind::Tablet {
+                width: DEFAULT_USB_TABLET_WIDTH,
+                height: DEFAULT_USB_TABLET_HEIGHT,
+            }),
+            ("tablet", Some(size)) => {
+                let parse = |v: Option<&str>| {
+                    v.and_then(|v| v.parse::<u32>().ok())
+                        .filter(|&v| v > 0)
+                        .ok_or_else(|| format!("invalid tablet size `{}`", size))
+                };
+                let mut dimensions = size.splitn(2, 'x');
+                let width = parse(dimensions.next())?;
+                let height = parse(dimensions.next())?;
+                Ok(UsbEmulatedDeviceKind::Tablet { width, height })
+            }
+            ("serial", None) => Ok(UsbEmulatedDeviceKind::Serial),
+            _ => Err(format!("unknown emulated USB device `{}`", s)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
 pub struct UsbControlAttachedDevice {
     pub port: u8,
@@ -228,6 +311,8 @@ pub enum UsbControlResult {
     FailedToOpenDevice,
     Devices([UsbControlAttachedDevice; USB_CONTROL_MAX_PORTS]),
     FailedToInitHostDevice,
+    FailedToInitEmulatedDevice,
+    FailedToImportUsbipDevice,
 }
 
 impl Display for UsbControlResult {
@@ -248,6 +333,282 @@ impl Display for UsbControlResult {
                 std::result::Result::Ok(())
             }
             FailedToInitHostDevice => write!(f, "failed_to_init_host_device"),
+            FailedToInitEmulatedDevice => write!(f, "failed_to_init_emulated_device"),
+            FailedToImportUsbipDevice => write!(f, "failed_to_import_usbip_device"),
+        }
+    }
+}
+
+/// Layout of the frames written by a scanout recording.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum GpuRecordFormat {
+    /// Each frame's B8G8R8X8 pixels, back to back, without any header.
+    Raw,
+    /// A YUV4MPEG2 stream with 4:2:0 chroma subsampling.
+    Y4m,
+}
+
+impl FromStr for GpuRecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "raw" => Ok(GpuRecordFormat::Raw),
+            "y4m" => Ok(GpuRecordFormat::Y4m),
+            _ => Err(format!("unknown record format `{}`", s)),
+        }
+    }
+}
+
+// GPU commands that are sent on the crosvm control socket. The files are opened by the sender
+// because the GPU device can't open host paths from within its jail.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GpuControlCommand {
+    /// Write the current contents of a scanout to `file` as a PNG image.
+    Screenshot {
+        scanout_id: u32,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Start appending each frame flushed to a scanout to `file`.
+    RecordStart {
+        scanout_id: u32,
+        format: GpuRecordFormat,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Stop a recording started with `RecordStart`.
+    RecordStop { scanout_id: u32 },
+    /// List the size of each enabled scanout.
+    ListDisplays,
+    /// Plug a display of the given size into the first unused scanout.
+    AddDisplay { width: u32, height: u32 },
+    /// Unplug the display of a scanout.
+    RemoveDisplay { scanout_id: u32 },
+    /// Change the size of the display of a scanout.
+    ResizeDisplay {
+        scanout_id: u32,
+        width: u32,
+        height: u32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum GpuControlResult {
+    Ok,
+    NoSuchScanout,
+    NoScanoutResource,
+    AlreadyRecording,
+    NotRecording,
+    CaptureFailed,
+    WriteFailed,
+    /// The `(width, height)` of each enabled scanout, by scanout id.
+    Displays(BTreeMap<u32, (u32, u32)>),
+    DisplayAdded {
+        scanout_id: u32,
+    },
+    TooManyDisplays,
+    InvalidDisplaySize,
+}
+
+impl Display for GpuControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GpuControlResult::*;
+
+        match self {
+            Ok => write!(f, "ok"),
+            NoSuchScanout => write!(f, "no_such_scanout"),
+            NoScanoutResource => write!(f, "no_scanout_resource"),
+            AlreadyRecording => write!(f, "already_recording"),
+            NotRecording => write!(f, "not_recording"),
+            CaptureFailed => write!(f, "capture_failed"),
+            WriteFailed => write!(f, "write_failed"),
+            Displays(displays) => {
+                write!(f, "displays")?;
+                for (scanout_id, (width, height)) in displays {
+                    write!(f, " {} {}x{}", scanout_id, width, height)?;
+                }
+                std::result::Result::Ok(())
+            }
+            DisplayAdded { scanout_id } => write!(f, "display_added {}", scanout_id),
+            TooManyDisplays => write!(f, "too_many_displays"),
+            InvalidDisplaySize => write!(f, "invalid_display_size"),
+        }
+    }
+}
+
+// Sound device commands that are sent on the crosvm control socket.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SndControlCommand {
+    /// List the jacks of the sound device and whether each one is connected.
+    ListJacks,
+    /// Plug or unplug a jack.  The guest is notified with a jack event.
+    SetJackConnected { jack_id: u32, connected: bool },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SndControlResult {
+    Ok,
+    NoSuchJack,
+    /// Whether each jack is connected, by jack id.
+    Jacks(BTreeMap<u32, bool>),
+}
+
+impl Display for SndControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SndControlResult::*;
+
+        match self {
+            Ok => write!(f, "ok"),
+            NoSuchJack => write!(f, "no_such_jack"),
+            Jacks(jacks) => {
+                write!(f, "jacks")?;
+                for (jack_id, connected) in jacks {
+                    let state = if *connected {
+                        "connected"
+                    } else {
+                        "disconnected"
+                    };
+                    write!(f, " {} {}", jack_id, state)?;
+                }
+                std::result::Result::Ok(())
+            }
+        }
+    }
+}
+
+/// The kinds of socket-backed input devices that accept commands on the crosvm control socket.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InputDeviceKind {
+    Keyboard,
+    Mouse,
+    MultiTouch,
+}
+
+impl Display for InputDeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InputDeviceKind::*;
+
+        match self {
+            Keyboard => write!(f, "keyboard"),
+            Mouse => write!(f, "mouse"),
+            MultiTouch => write!(f, "multi-touch"),
+        }
+    }
+}
+
+impl FromStr for InputDeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "keyboard" => Ok(InputDeviceKind::Keyboard),
+            "mouse" => Ok(InputDeviceKind::Mouse),
+            "multi-touch" => Ok(InputDeviceKind::MultiTouch),
+            _ => Err(format!("unknown input device `{}`", s)),
+        }
+    }
+}
+
+/// An input event with the same fields as a `virtio_input_event`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl From<virtio_input_event> for InputEvent {
+    fn from(evt: virtio_input_event) -> Self {
+        InputEvent {
+            type_: evt.type_.into(),
+            code: evt.code.into(),
+            value: evt.value.into(),
+        }
+    }
+}
+
+impl From<InputEvent> for virtio_input_event {
+    fn from(evt: InputEvent) -> Self {
+        virtio_input_event {
+            type_: evt.type_.into(),
+            code: evt.code.into(),
+            value: evt.value.into(),
+        }
+    }
+}
+
+/// An event in an input recording, stored as one `TIME_US TYPE CODE VALUE` line where `TIME_US`
+/// is the number of microseconds between the start of the recording and the event being
+/// delivered to the guest.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InputRecordEntry {
+    pub time_us: u64,
+    pub event: InputEvent,
+}
+
+impl Display for InputRecordEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.time_us, self.event.type_, self.event.code, self.event.value
+        )
+    }
+}
+
+impl FromStr for InputRecordEntry {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(format!("expected 4 fields in input record `{}`", s));
+        }
+        let invalid = |name: &str, value: &str| format!("invalid {} `{}`", name, value);
+        Ok(InputRecordEntry {
+            time_us: fields[0]
+                .parse()
+                .map_err(|_| invalid("timestamp", fields[0]))?,
+            event: InputEvent {
+                type_: fields[1].parse().map_err(|_| invalid("type", fields[1]))?,
+                code: fields[2].parse().map_err(|_| invalid("code", fields[2]))?,
+                value: fields[3].parse().map_err(|_| invalid("value", fields[3]))?,
+            },
+        })
+    }
+}
+
+// Input device commands that are sent on the crosvm control socket. The recording file is opened
+// by the sender because the input device can't open host paths from within its jail.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InputControlCommand {
+    /// Deliver the events to the guest as if they were read from the device's socket.
+    Inject(Vec<InputEvent>),
+    /// Start appending each event delivered to the guest to `file` as an `InputRecordEntry`.
+    RecordStart {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Stop a recording started with `RecordStart`.
+    RecordStop,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum InputControlResult {
+    Ok,
+    AlreadyRecording,
+    NotRecording,
+}
+
+impl Display for InputControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InputControlResult::*;
+
+        match self {
+            Ok => write!(f, "ok"),
+            AlreadyRecording => write!(f, "already_recording"),
+            NotRecording => write!(f, "not_recording"),
         }
     }
 }
@@ -915,6 +1276,17 @@ pub enum VmRequest {
     UsbCommand(UsbControlCommand),
     /// Command to set battery.
     BatCommand(BatteryType, BatControlCommand),
+    /// Command to capture the GPU's scanouts.
+    GpuCommand(GpuControlCommand),
+    /// Command for the sound device's jacks.
+    SndCommand(SndControlCommand),
+    /// Send a command to an input device chosen by `kind` and `index`.
+    /// `index` is a 0-based count of the command-line options that create devices of that kind.
+    InputCommand {
+        kind: InputDeviceKind,
+        index: usize,
+        command: InputControlCommand,
+    },
 }
 
 fn register_memory(
@@ -967,6 +1339,62 @@ fn register_host_pointer(
     Ok((addr >> 12, slot))
 }
 
+/// How long the control loop waits for a device to answer a command forwarded over its control
+/// tube. Devices only read their control tube while they are active, so a command sent before the
+/// guest drives the device, or after it resets it, is never answered.
+pub const DEVICE_CONTROL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A command forwarded to a device over its control tube. The device replies with a
+/// `DeviceControlResponse` carrying the same `id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceControlRequest<T> {
+    pub id: u64,
+    pub command: T,
+}
+
+/// The result of the `DeviceControlRequest` with the same `id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceControlResponse<T> {
+    pub id: u64,
+    pub result: T,
+}
+
+static NEXT_DEVICE_CONTROL_ID: AtomicU64 = AtomicU64::new(0);
+
+// Sends `command` to the `device` at the other end of `tube` and waits for its result.
+//
+// A command which timed out may still be executed once the device becomes active, and its result
+// then waits in the tube. The id of each command tells those stale results apart from the result
+// of the current command, like the id of balloon stats requests.
+fn forward_device_command<C: Serialize, R: DeserializeOwned>(
+    tube: &Tube,
+    device: &str,
+    command: &C,
+) -> StdResult<R, SysError> {
+    let id = NEXT_DEVICE_CONTROL_ID.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = tube.send(&DeviceControlRequest { id, command }) {
+        error!("fail to send command to {} control socket: {}", device, e);
+        return Err(SysError::new(EIO));
+    }
+    loop {
+        match tube.recv::<DeviceControlResponse<R>>() {
+            Ok(response) if response.id == id => return Ok(response.result),
+            // The result of an earlier command which timed out.
+            Ok(_) => continue,
+            Err(TubeError::Recv(e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                error!("{} device didn't answer the command in time", device);
+                return Err(SysError::new(ETIMEDOUT));
+            }
+            Err(e) => {
+                error!("fail to recv command from {} control socket: {}", device, e);
+                return Err(SysError::new(EIO));
+            }
+        }
+    }
+}
+
 impl VmRequest {
     /// Executes this request on the given Vm and other mutable state.
     ///
@@ -980,6 +1408,9 @@ impl VmRequest {
         balloon_stats_id: &mut u64,
         disk_host_tubes: &[Tube],
         usb_control_tube: Option<&Tube>,
+        gpu_control_tube: Option<&Tube>,
+        snd_control_tube: Option<&Tube>,
+        input_control_tubes: &BTreeMap<(InputDeviceKind, usize), Tube>,
         bat_control: &mut Option<BatControl>,
         vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
     ) -> VmResponse {
@@ -1122,6 +1553,49 @@ impl VmRequest {
                     None => VmResponse::BatResponse(BatControlResult::NoBatDevice),
                 }
             }
+            VmRequest::GpuCommand(ref cmd) => {
+                let gpu_control_tube = match gpu_control_tube {
+                    Some(t) => t,
+                    None => {
+                        error!("attempted to execute GPU request without control tube");
+                        return VmResponse::Err(SysError::new(ENODEV));
+                    }
+                };
+                match forward_device_command(gpu_control_tube, "gpu", cmd) {
+                    Ok(response) => VmResponse::GpuResponse(response),
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            VmRequest::SndCommand(ref cmd) => {
+                let snd_control_tube = match snd_control_tube {
+                    Some(t) => t,
+                    None => {
+                        error!("attempted to execute sound request without control tube");
+                        return VmResponse::Err(SysError::new(ENODEV));
+                    }
+                };
+                match forward_device_command(snd_control_tube, "snd", cmd) {
+                    Ok(response) => VmResponse::SndResponse(response),
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            VmRequest::InputCommand {
+                kind,
+                index,
+                ref command,
+            } => {
+                let input_control_tube = match input_control_tubes.get(&(kind, index)) {
+                    Some(t) => t,
+                    None => {
+                        error!("no {} input device with index {}", kind, index);
+                        return VmResponse::Err(SysError::new(ENODEV));
+                    }
+                };
+                match forward_device_command(input_control_tube, "input", command) {
+                    Ok(response) => VmResponse::InputResponse(response),
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
         }
     }
 }
@@ -1155,6 +1629,12 @@ pub enum VmResponse {
     UsbResponse(UsbControlResult),
     /// Results of battery control commands.
     BatResponse(BatControlResult),
+    /// Results of GPU control commands.
+    GpuResponse(GpuControlResult),
+    /// Results of sound control commands.
+    SndResponse(SndControlResult),
+    /// Results of input control commands.
+    InputResponse(InputControlResult),
 }
 
 impl Display for VmResponse {
@@ -1188,6 +1668,9 @@ impl Display for VmResponse {
             }
             UsbResponse(result) => write!(f, "usb control request get result {:?}", result),
             BatResponse(result) => write!(f, "{}", result),
+            GpuResponse(result) => write!(f, "gpu control request get result {}", result),
+            SndResponse(result) => write!(f, "snd control request get result {}", result),
+            InputResponse(result) => write!(f, "input control request get result {}", result),
         }
     }
 }